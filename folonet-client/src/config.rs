@@ -1,24 +1,301 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct GlobalConfig {
+    #[serde(default)]
     pub services: Vec<ServiceConfig>,
+    // files of one more service each, e.g. a conf.d directory: files, whole
+    // directories of them or patterns like `conf.d/*.yaml`, relative to the
+    // file including them. Any of them may be yaml, json or toml.
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
     pub interfaces: Vec<InterfaceConfig>,
+    // every interface but the loopback, with its own addresses unless an
+    // entry of `interfaces` matches it
+    #[serde(default)]
+    pub attach_all: bool,
+    #[serde(default)]
     pub ip_mac_list: Vec<IpMac>,
+    #[serde(default)]
+    pub syn_flood: Option<SynFloodConfig>,
+    #[serde(default)]
+    pub acl: Vec<AclConfig>,
+    #[serde(default)]
+    pub attach: AttachConfig,
+    #[serde(default)]
+    pub flow_log: Option<FlowLogConfig>,
+    #[serde(default)]
+    pub manager: ManagerConfig,
+    // tag connections with their application protocol, from the first bytes
+    // the client sends
+    #[serde(default)]
+    pub classify_protocols: bool,
+    #[serde(default)]
+    pub stuck: Option<StuckConfig>,
+    // ip fragments after the first one
+    #[serde(default)]
+    pub fragments: FragmentPolicy,
+    #[serde(default)]
+    pub sharding: ShardingConfig,
+    // tasks handling the packet events, the events of a connection stay on one
+    #[serde(default = "default_event_workers")]
+    pub event_workers: usize,
+    #[serde(default)]
+    pub queues: QueueConfig,
+    #[serde(default)]
+    pub auto_block: Option<AutoBlockConfig>,
+    // tcp packets reported to the state machines besides fins
+    #[serde(default)]
+    pub notify: NotifyConfig,
+    // sample how long the xdp program takes per packet
+    #[serde(default)]
+    pub latency: Option<LatencyConfig>,
+    // tcp packets other than a syn of connections folonet has no state of,
+    // e.g. after a restart
+    #[serde(default)]
+    pub unknown_connections: UnknownConnAction,
+    // cross check the kernel nat entries with the state machines
+    #[serde(default)]
+    pub reconcile: Option<ReconcileConfig>,
+    // capacity of the kernel tables
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    // where the datapath is pinned for the next process to take over
+    #[serde(default)]
+    pub pinning: PinConfig,
+    // serve the stats of this node and a merged view of its peers
+    #[serde(default)]
+    pub federation: Option<FederationConfig>,
+    // how the local ports are shared among the services
+    #[serde(default)]
+    pub ports: PortsConfig,
+    // close the idle connections of a service being removed
+    #[serde(default)]
+    pub fin_sweep: Option<FinSweepConfig>,
+    // how an idle service winds down before its backend is stopped
+    #[serde(default)]
+    pub scale_down: ScaleDownConfig,
+    // where the admin commands reach the running daemon
+    #[serde(default)]
+    pub admin: AdminConfig,
+    // closed connections as csv files, for billing
+    #[serde(default)]
+    pub usage_export: Option<UsageExportConfig>,
+    // connections a backend opens itself, toward clients folonet never saw
+    #[serde(default)]
+    pub backend_egress: BackendEgressAction,
+    #[serde(default)]
+    pub log: LogConfig,
+    // liveness and readiness probes over http
+    #[serde(default)]
+    pub health: Option<HealthConfig>,
+    // answer what the xdp program turns away with a reset, or a port
+    // unreachable for udp, instead of dropping it silently
+    #[serde(default)]
+    pub reject: Option<RejectConfig>,
+    // how the services with http routes are proxied
+    #[serde(default)]
+    pub http_routing: HttpRoutingConfig,
+    // proxy the flows the xdp program cannot rewrite in userspace, instead of
+    // leaving them to the host stack or the fragment policy
+    #[serde(default)]
+    pub fallback: Option<FallbackConfig>,
+    // rewrite the packets of one interface in userspace, over af_xdp sockets
+    #[serde(default)]
+    pub af_xdp: Option<AfXdpConfig>,
+    // a span per tcp connection, exported over otlp
+    #[serde(default)]
+    pub tracing: Option<TracingConfig>,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct ServiceConfig {
     pub name: String,
     pub local_endpoint: String,
     pub servers: Vec<String>,
+    #[serde(default)]
     pub is_tcp: bool,
+    // Serve the service over every one of these on the same endpoint, e.g.
+    // dns over tcp and udp, instead of over `is_tcp` only. A cold start
+    // brings it up on all of them, with one backend listening on both.
+    #[serde(default)]
+    pub protocols: Vec<ServiceProtocol>,
+    #[serde(default)]
+    pub syn_grace: Option<SynGraceConfig>,
+    // answer the client handshake while the service cold starts
+    #[serde(default)]
+    pub hold_handshake: Option<HoldHandshakeConfig>,
+    // wait for a cold started backend to answer before routing the service
+    // to it, tcp services only
+    #[serde(default)]
+    pub startup_probe: Option<StartupProbeConfig>,
+    #[serde(default)]
+    pub cleanup: CleanupConfig,
+    // backends started up front and kept running even when idle
+    #[serde(default)]
+    pub min_warm: u32,
+    // let an idle service stop its warm backends anyway
+    #[serde(default)]
+    pub scale_below_min_warm: bool,
+    // the local ip connections to the backends leave from, instead of the
+    // one picked by the subnet of the backend
+    #[serde(default)]
+    pub egress_ip: Option<String>,
+    // passed on to the manager with every start of a backend
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    // what starts and stops the backends of the service, the manager without
+    #[serde(default)]
+    pub provider: Option<ProviderConfig>,
+    // local ports the service may hold at once, instead of ports.default_quota
+    #[serde(default)]
+    pub port_quota: Option<u64>,
+    // connections the service may have open at once, none for no limit
+    #[serde(default)]
+    pub max_connections: Option<u64>,
+    // what the xdp program does with new connections past max_connections
+    #[serde(default)]
+    pub conn_limit_action: ConnLimitAction,
+    // Put a PROXY protocol v2 header in front of the first bytes of every
+    // tcp connection, so the backends learn the address of the client
+    // instead of the local ip connections leave from.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    // Pass the address of the client in a TOA option of the syn toward the
+    // backend instead, for backends with a kernel module reading it.
+    #[serde(default)]
+    pub toa: bool,
+    // Route every connection by the host of its first http request, to the
+    // servers of the route of that host or to `servers` otherwise. The
+    // service is proxied in userspace, so its ip must be one of this host.
+    #[serde(default)]
+    pub http_routes: Vec<HttpRoute>,
+    // Route every tls connection by the server name of its client hello,
+    // without terminating it, like http_routes do by the http host.
+    #[serde(default)]
+    pub sni_routes: Vec<SniRoute>,
+    // Backends sharing the new connections with `servers`, e.g. the green
+    // of a blue/green deployment or a canary. Each pool gets its weight in
+    // percent of them, `servers` the rest.
+    #[serde(default)]
+    pub pools: Vec<PoolConfig>,
+    // A backend of the pools coming up, added to the service or cold started
+    // again, gets its share of the new connections bit by bit over this
+    // long instead of all at once. 0 sends it the whole share right away,
+    // which is all a service without pools can do.
+    #[serde(default)]
+    pub slow_start_secs: u64,
+    // keep the new connections of a client on the backend of its last one
+    #[serde(default)]
+    pub affinity: Affinity,
+    // how long since its last connection a client keeps to its backend
+    #[serde(default = "default_affinity_timeout_secs")]
+    pub affinity_timeout_secs: u64,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+fn default_affinity_timeout_secs() -> u64 {
+    3 * 3600
+}
+
+impl ServiceConfig {
+    // `protocols`, or the one `is_tcp` picks without them
+    pub fn served_protocols(&self) -> Vec<ServiceProtocol> {
+        if self.protocols.is_empty() {
+            return vec![ServiceProtocol::from_is_tcp(self.is_tcp)];
+        }
+        let mut protocols = vec![];
+        for protocol in self.protocols.iter() {
+            if !protocols.contains(protocol) {
+                protocols.push(*protocol);
+            }
+        }
+        protocols
+    }
+
+    // the service as served over each of its protocols, every copy with
+    // `is_tcp` set and no `protocols`
+    pub fn per_protocol(&self) -> Vec<ServiceConfig> {
+        self.served_protocols()
+            .into_iter()
+            .map(|protocol| ServiceConfig {
+                is_tcp: protocol.is_tcp(),
+                protocols: vec![],
+                ..self.clone()
+            })
+            .collect()
+    }
+
+    pub fn keeps_warm(&self) -> bool {
+        self.min_warm > 0 && !self.scale_below_min_warm
+    }
+
+    // proxied in userspace, see http_routes and sni_routes
+    pub fn routes_by_name(&self) -> bool {
+        !self.http_routes.is_empty() || !self.sni_routes.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceProtocol {
+    Tcp,
+    Udp,
+}
+
+impl ServiceProtocol {
+    pub fn from_is_tcp(is_tcp: bool) -> Self {
+        if is_tcp {
+            ServiceProtocol::Tcp
+        } else {
+            ServiceProtocol::Udp
+        }
+    }
+
+    pub fn is_tcp(self) -> bool {
+        self == ServiceProtocol::Tcp
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HttpRoute {
+    // matched without its port and case
+    pub host: String,
+    pub servers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PoolConfig {
+    pub name: String,
+    pub servers: Vec<String>,
+    // percent of the new connections, adjustable while running
+    #[serde(default)]
+    pub weight: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SniRoute {
+    // matched without its case
+    pub server_name: String,
+    pub servers: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct InterfaceConfig {
+    // or a pattern, `*` matching any run of characters and `?` any one,
+    // every interface matching it is attached to as it shows up
     pub name: String,
+    // `ip` or `ip/prefix_len`, a backend on the subnet of an ip is reached
+    // from that ip, any other from the first one. Without any, the addresses
+    // of the interface, followed as they change.
+    #[serde(default)]
     pub local_ips: Vec<String>,
+    // `ip` or `ip/prefix_len`, addresses of the interface that are not
+    // local ips, e.g. a floating ip another node may take over
+    #[serde(default)]
+    pub exclude_ips: Vec<String>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -26,3 +303,949 @@ pub struct IpMac {
     pub ip: String,
     pub mac: String,
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SynFloodAction {
+    Drop,
+    Cookie,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SynFloodConfig {
+    // half-open connections allowed per source address before the action kicks in
+    pub half_open_threshold: u32,
+    pub action: SynFloodAction,
+    #[serde(default = "default_syn_window_secs")]
+    pub window_secs: u64,
+}
+
+fn default_event_workers() -> usize {
+    4
+}
+
+fn default_syn_window_secs() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FragmentPolicy {
+    // hand them to the host stack untouched
+    #[default]
+    Pass,
+    Drop,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownConnAction {
+    // to the host stack, which answers with a reset of its own
+    #[default]
+    Pass,
+    Drop,
+    // answer with a reset from the xdp program
+    Rst,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Affinity {
+    // every new connection picks its backend anew
+    #[default]
+    None,
+    // by the ip of the client, whatever its port
+    ClientIp,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnLimitAction {
+    // the client retransmits its syn and may find room then
+    #[default]
+    Drop,
+    // answer a syn with a reset, the client gives up at once
+    Rst,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendEgressAction {
+    // to the host stack, as if folonet was not there
+    #[default]
+    Pass,
+    // from the ip of the interface toward the peer, the replies find their
+    // way back to the backend
+    Masquerade,
+    // drop the syns and udp packets the backend opens connections with,
+    // replies to connections the host opened go on
+    Block,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AclAction {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct AclConfig {
+    pub local_endpoint: String,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    // when omitted, a service with an allow list denies everyone else
+    #[serde(default)]
+    pub default: Option<AclAction>,
+}
+
+impl AclConfig {
+    pub fn default_action(&self) -> AclAction {
+        match self.default {
+            Some(action) => action,
+            None if self.allow.is_empty() => AclAction::Allow,
+            None => AclAction::Deny,
+        }
+    }
+}
+
+// how patient the clients of a service are while it cold starts,
+// defaults match the linux tcp_syn_retries behaviour
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SynGraceConfig {
+    #[serde(default = "default_syn_retries")]
+    pub retries: u32,
+    #[serde(default = "default_initial_rto_ms")]
+    pub initial_rto_ms: u64,
+    // extra time granted after the last retransmission
+    #[serde(default)]
+    pub grace_ms: u64,
+}
+
+impl Default for SynGraceConfig {
+    fn default() -> Self {
+        SynGraceConfig {
+            retries: default_syn_retries(),
+            initial_rto_ms: default_initial_rto_ms(),
+            grace_ms: 0,
+        }
+    }
+}
+
+fn default_syn_retries() -> u32 {
+    6
+}
+
+fn default_initial_rto_ms() -> u64 {
+    1000
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CleanupStrategy {
+    // follow every connection through the tcp state machine
+    #[default]
+    Fsm,
+    // no state machine, a connection is dropped once it was idle for too long
+    IdleTimeout,
+    // the state machine, with idle connections dropped as well
+    Hybrid,
+}
+
+impl CleanupStrategy {
+    pub fn uses_fsm(&self) -> bool {
+        *self != CleanupStrategy::IdleTimeout
+    }
+
+    pub fn uses_idle_timeout(&self) -> bool {
+        *self != CleanupStrategy::Fsm
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CleanupConfig {
+    pub strategy: CleanupStrategy,
+    pub idle_timeout_secs: u64,
+    // udp flows always end this way, whatever the strategy
+    pub udp_idle_timeout_secs: u64,
+    // Whatever the strategy, a tracked connection whose flow was idle this
+    // long, or is gone from the kernel, is closed so a missed close does not
+    // hold its port and state forever. 0 never reaps.
+    pub reap_after_secs: u64,
+    // tracked connections of the service at once, the one opened first is
+    // closed to make room; 0 has no limit
+    pub max_tracked: usize,
+    // how long a closed tcp connection keeps its nat entries in TIME_WAIT,
+    // for late retransmits of its last fin and ack; 0 lets go right away
+    pub time_wait_ms: u64,
+    // a tcp connection whose client sent a syn and never got established,
+    // e.g. a client gone mid handshake, is closed by then and its port goes
+    // back to the pool; 0 waits for the reaper
+    pub handshake_timeout_ms: u64,
+}
+
+impl CleanupConfig {
+    pub fn idle_timeout_secs_for(&self, is_tcp: bool) -> u64 {
+        if is_tcp {
+            self.idle_timeout_secs
+        } else {
+            self.udp_idle_timeout_secs
+        }
+    }
+}
+
+impl Default for CleanupConfig {
+    fn default() -> Self {
+        CleanupConfig {
+            strategy: CleanupStrategy::default(),
+            idle_timeout_secs: 300,
+            udp_idle_timeout_secs: 30,
+            reap_after_secs: 0,
+            max_tracked: 0,
+            time_wait_ms: 2000,
+            handshake_timeout_ms: 75000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct HoldHandshakeConfig {
+    // the client is reset when its service is not ready in time
+    #[serde(default = "default_hold_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_hold_timeout_ms() -> u64 {
+    30_000
+}
+
+// The manager may answer start_server before the backend listens, and a syn
+// forwarded to it then is reset. The backend is probed every `interval_ms`
+// instead, each attempt given `timeout_ms`, by a tcp connect or a GET of
+// `http_path` that must answer below 400. The cold start fails unless a probe
+// passed within `deadline_ms`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StartupProbeConfig {
+    pub http_path: Option<String>,
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+    pub deadline_ms: u64,
+}
+
+impl Default for StartupProbeConfig {
+    fn default() -> Self {
+        StartupProbeConfig {
+            http_path: None,
+            interval_ms: 100,
+            timeout_ms: 1000,
+            deadline_ms: 30_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AttachConfig {
+    // per interface, including the first try
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for AttachConfig {
+    fn default() -> Self {
+        AttachConfig {
+            max_attempts: 5,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 10_000,
+        }
+    }
+}
+
+// the tasks running the tcp state machines, grown while their queues are slow
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShardingConfig {
+    pub min_shards: usize,
+    pub max_shards: usize,
+    // queue latency of the slowest shard that adds a shard
+    pub split_above_ms: u64,
+    // and the one below which a shard is taken away
+    pub merge_below_ms: u64,
+    pub interval_secs: u64,
+    // packets a shard holds before the service handing them over waits
+    pub queue: usize,
+}
+
+impl Default for ShardingConfig {
+    fn default() -> Self {
+        ShardingConfig {
+            min_shards: 4,
+            max_shards: 64,
+            split_above_ms: 5,
+            merge_below_ms: 1,
+            interval_secs: 5,
+            queue: 10240,
+        }
+    }
+}
+
+// How many packet events wait for a service. A full queue sheds the events
+// opening connections, a syn or a udp packet, and counts them. The nat entries
+// and port of a shed syn are closed as if the syn was dropped, a udp flow
+// whose first packet was shed is left to the idle sweep; the events closing a
+// connection wait for room instead.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueueConfig {
+    pub service: usize,
+    pub shed: bool,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            service: 102400,
+            shed: true,
+        }
+    }
+}
+
+// Which tcp packets besides fins the xdp program reports, each as one in `n`
+// connections: 0 reports none, 1 all of them. Connections are picked by their
+// client, so equal rates report the whole handshake of the same connections.
+// The state machine of a connection whose syn was not reported starts out
+// established.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotifyConfig {
+    pub syn: u8,
+    pub syn_ack: u8,
+    pub rst: u8,
+    // the ack finishing the handshake
+    pub first_ack: u8,
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        NotifyConfig {
+            syn: 1,
+            syn_ack: 1,
+            rst: 1,
+            first_ack: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LatencyConfig {
+    // time one in this many packets
+    pub sample_one_in: u32,
+}
+
+impl Default for LatencyConfig {
+    fn default() -> Self {
+        LatencyConfig {
+            sample_one_in: 1000,
+        }
+    }
+}
+
+// block sources that only ever open handshakes, e.g. port scanners
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutoBlockConfig {
+    pub interval_secs: u64,
+    // half open connections of a source without an established one
+    pub min_half_open: usize,
+    pub ttl_secs: u64,
+}
+
+impl Default for AutoBlockConfig {
+    fn default() -> Self {
+        AutoBlockConfig {
+            interval_secs: 10,
+            min_half_open: 20,
+            ttl_secs: 600,
+        }
+    }
+}
+
+// alert when connections pile up in a handshake or close state
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StuckConfig {
+    pub interval_secs: u64,
+    // how long a connection may sit in such a state
+    pub min_age_secs: u64,
+    // stuck connection sides tolerated before alerting
+    pub max_stuck: usize,
+    // close the stuck connections once alerted
+    pub sweep: bool,
+}
+
+impl Default for StuckConfig {
+    fn default() -> Self {
+        StuckConfig {
+            interval_secs: 30,
+            min_age_secs: 120,
+            max_stuck: 100,
+            sweep: false,
+        }
+    }
+}
+
+// Every service takes its local ports from the same pool. A service with a
+// quota cannot hold more than that many at once, so a busy one leaves ports
+// to the others.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PortsConfig {
+    // the quota of services without their own, none for no limit
+    pub default_quota: Option<u64>,
+    // warn once the free ports of a quota, or of the whole pool, drop below
+    // this share of it
+    pub low_free_ratio: f64,
+    // how long the port of a closed tcp connection waits before it is used
+    // again, 2*MSL so the backend is done with TIME_WAIT of the old one. 0 to
+    // use it again right away.
+    pub quarantine_secs: u64,
+    // `first-last` or a single port each, the ports of the pool. Without
+    // any, from 10000 on as many as the kernel queue holds.
+    pub ranges: Vec<String>,
+}
+
+impl Default for PortsConfig {
+    fn default() -> Self {
+        PortsConfig {
+            default_quota: None,
+            low_free_ratio: 0.1,
+            quarantine_secs: 60,
+            ranges: vec![],
+        }
+    }
+}
+
+impl PortsConfig {
+    pub fn quota_of(&self, service: &ServiceConfig) -> Option<u64> {
+        service.port_quota.or(self.default_quota)
+    }
+}
+
+// entries of the kernel maps, set when the program is loaded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    // nat entries, two per connection, the least recently used are evicted
+    // once full
+    pub connections: u32,
+    // per connection accounting, keyed by the client side
+    pub flows: u32,
+    // services, with their backends, epochs, load and handshake settings
+    pub services: u32,
+    pub local_ips: u32,
+    pub ip_macs: u32,
+    // half open handshakes tracked by the syn flood protection and clients
+    // held while their service cold starts
+    pub half_open: u32,
+    pub blocked_sources: u32,
+    pub acl_rules: u32,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            connections: 131072,
+            flows: 65536,
+            services: 1024,
+            local_ips: 10,
+            ip_macs: 1024,
+            half_open: 65536,
+            blocked_sources: 65536,
+            acl_rules: 4096,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PinConfig {
+    // a directory on a bpf filesystem
+    pub path: String,
+}
+
+impl Default for PinConfig {
+    fn default() -> Self {
+        PinConfig {
+            path: String::from("/sys/fs/bpf/folonet"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    // a unix socket, replaced when the daemon starts
+    pub socket: String,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig {
+            socket: String::from("/run/folonet.sock"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    // like RUST_LOG, which wins when set, `folonet log-level` changes it at
+    // runtime
+    pub filter: String,
+    // the messages of the xdp program, off leaves out their per packet cost
+    pub ebpf: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            filter: String::from("info"),
+            ebpf: true,
+        }
+    }
+}
+
+// /healthz answers as long as the daemon runs, /readyz once the xdp program
+// is attached, the configured services are in its maps, the manager answers
+// within `manager_timeout_ms` and the ring buffer consumers keep up: they
+// polled within `stall_secs` and no event was lost since the last probe.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    pub listen: String,
+    pub manager_timeout_ms: u64,
+    pub stall_secs: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            listen: String::from("0.0.0.0:7071"),
+            manager_timeout_ms: 1000,
+            stall_secs: 5,
+        }
+    }
+}
+
+// The request head of the first request of a connection, or the client hello
+// of a tls one, must fit in `max_head_bytes` and arrive within
+// `head_timeout_ms` to be routed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpRoutingConfig {
+    pub max_head_bytes: usize,
+    pub head_timeout_ms: u64,
+    pub connect_timeout_ms: u64,
+}
+
+impl Default for HttpRoutingConfig {
+    fn default() -> Self {
+        HttpRoutingConfig {
+            max_head_bytes: 8192,
+            head_timeout_ms: 5000,
+            connect_timeout_ms: 2000,
+        }
+    }
+}
+
+// The new flows of a service the xdp program cannot rewrite, those opened by
+// a packet with ip options or a fragmented datagram, are handed to the host
+// stack whole and proxied to the servers of the service in userspace. The ip
+// of such a service must be one of this host. A udp client is forgotten once
+// idle for `udp_idle_secs`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FallbackConfig {
+    pub connect_timeout_ms: u64,
+    pub udp_idle_secs: u64,
+}
+
+impl Default for FallbackConfig {
+    fn default() -> Self {
+        FallbackConfig {
+            connect_timeout_ms: 2000,
+            udp_idle_secs: 60,
+        }
+    }
+}
+
+// The xdp program hands the packets of connections it would rewrite on
+// `interface` to an af_xdp socket on each of the first `queues` rx queues,
+// and a worker thread per socket rewrites and sends them. Every socket has
+// `frames` frames of `frame_size` bytes, both powers of two. Without a driver
+// that supports zero copy, or unless `zero_copy` is set, the packets are
+// copied between the kernel and the worker.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AfXdpConfig {
+    pub interface: String,
+    pub queues: u32,
+    pub frames: u32,
+    pub frame_size: u32,
+    pub zero_copy: bool,
+}
+
+impl Default for AfXdpConfig {
+    fn default() -> Self {
+        AfXdpConfig {
+            interface: String::new(),
+            queues: 1,
+            frames: 4096,
+            frame_size: 4096,
+            zero_copy: true,
+        }
+    }
+}
+
+// Spans of the tcp connections POSTed as otlp/http json to `endpoint`, e.g.
+// the collector in front of jaeger or tempo. They go out every `flush_secs`,
+// or once `batch_size` of them closed, as `service_name`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TracingConfig {
+    pub endpoint: String,
+    pub service_name: String,
+    pub flush_secs: u64,
+    pub batch_size: usize,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        TracingConfig {
+            endpoint: String::from("http://127.0.0.1:4318/v1/traces"),
+            service_name: String::from("folonet"),
+            flush_secs: 5,
+            batch_size: 512,
+        }
+    }
+}
+
+// Connections denied by the acl, or turned away for want of a local port, are
+// rejected at once. So are the new ones of a service whose cold start failed,
+// for `cold_start_failed_secs`, instead of starting it again on every syn.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RejectConfig {
+    pub cold_start_failed_secs: u64,
+}
+
+impl Default for RejectConfig {
+    fn default() -> Self {
+        RejectConfig {
+            cold_start_failed_secs: 5,
+        }
+    }
+}
+
+// A service being stopped keeps its open connections, as seen by the kernel
+// and by its connection trackers, for up to `drain_deadline_secs` before its
+// backend is stopped anyway. With `drain_rpc` the manager is asked to drain
+// the backend first, so it can finish and close them itself.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScaleDownConfig {
+    pub drain_deadline_secs: u64,
+    pub drain_rpc: bool,
+}
+
+impl Default for ScaleDownConfig {
+    fn default() -> Self {
+        ScaleDownConfig {
+            drain_deadline_secs: 30,
+            drain_rpc: false,
+        }
+    }
+}
+
+// When a service is removed, its tcp connections idle for `min_idle_secs` get
+// a fin from the service before the backend goes away, so their clients see a
+// close instead of timing out later.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FinSweepConfig {
+    pub min_idle_secs: u64,
+}
+
+impl Default for FinSweepConfig {
+    fn default() -> Self {
+        FinSweepConfig { min_idle_secs: 10 }
+    }
+}
+
+// Every node serves its own stats as json on `listen`, at /stats. A node with
+// peers also scrapes theirs and serves the stats of all of them merged per
+// service, at /federated.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FederationConfig {
+    pub listen: String,
+    // `host:port` of the other nodes
+    pub peers: Vec<String>,
+    // how this node shows up in the merged view, its hostname by default
+    pub node: Option<String>,
+    pub interval_secs: u64,
+    pub timeout_ms: u64,
+}
+
+impl Default for FederationConfig {
+    fn default() -> Self {
+        FederationConfig {
+            listen: String::from("0.0.0.0:7070"),
+            peers: vec![],
+            node: None,
+            interval_secs: 15,
+            timeout_ms: 2000,
+        }
+    }
+}
+
+// find connections whose packet events were lost, the kernel has nat entries
+// of them without a state machine or the other way round
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReconcileConfig {
+    pub interval_secs: u64,
+    // nat entries without a state machine are only repaired once idle this long
+    pub min_idle_secs: u64,
+    // close the inconsistent connections, only log them otherwise
+    pub repair: bool,
+}
+
+impl Default for ReconcileConfig {
+    fn default() -> Self {
+        ReconcileConfig {
+            interval_secs: 60,
+            min_idle_secs: 300,
+            repair: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FlowLogSink {
+    Stdout,
+    File { path: String },
+    // udp syslog, e.g. 127.0.0.1:514
+    Syslog { addr: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FlowLogConfig {
+    pub sink: FlowLogSink,
+}
+
+// One csv file of closed connections per `rotate_secs` in `dir`, csv only. A
+// file is written as `.csv.part` and renamed once complete, then
+// `upload_command` runs with its path appended, e.g. a script copying it to
+// s3; folonet does not upload anything itself.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UsageExportConfig {
+    pub dir: String,
+    pub rotate_secs: u64,
+    // the metadata key of a service naming its tenant
+    pub tenant_key: String,
+    pub upload_command: Option<Vec<String>>,
+}
+
+impl Default for UsageExportConfig {
+    fn default() -> Self {
+        UsageExportConfig {
+            dir: String::from("/var/lib/folonet/usage"),
+            rotate_secs: 3600,
+            tenant_key: String::from("tenant"),
+            upload_command: None,
+        }
+    }
+}
+
+// A cold start provider other than the manager, see provider. Each starts
+// one backend, listening on `server_endpoint`, unless a webhook answers with
+// another. The metadata of the service is passed to an exec command as
+// FOLONET_META_<KEY> environment variables and to a webhook in its body.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProviderConfig {
+    // argv of the commands starting, stopping and draining the backend
+    Exec {
+        start: Vec<String>,
+        #[serde(default)]
+        stop: Vec<String>,
+        #[serde(default)]
+        drain: Vec<String>,
+        server_endpoint: String,
+    },
+    // a container started and stopped through the docker engine api
+    Docker {
+        container: String,
+        server_endpoint: String,
+        #[serde(default = "default_docker_socket")]
+        socket: String,
+    },
+    // a container of containerd, started and stopped by running nerdctl,
+    // which has to be on the PATH
+    Containerd {
+        container: String,
+        server_endpoint: String,
+        #[serde(default = "default_containerd_namespace")]
+        namespace: String,
+    },
+    // POSTed {"action": "start" | "stop" | "drain", "name", "local_endpoint"},
+    // http or https
+    Webhook {
+        url: String,
+        #[serde(default)]
+        server_endpoint: Option<String>,
+    },
+}
+
+fn default_docker_socket() -> String {
+    "/var/run/docker.sock".to_string()
+}
+
+fn default_containerd_namespace() -> String {
+    "default".to_string()
+}
+
+// where the server manager lives and how hard to try reaching it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ManagerConfig {
+    pub addr: String,
+    pub connect_timeout_ms: u64,
+    pub request_timeout_ms: u64,
+    // on top of the first try
+    pub max_retries: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    // sent as a bearer token with every call
+    pub token: Option<String>,
+    pub tls: Option<ManagerTlsConfig>,
+}
+
+// pem files; with a client cert and key the channel is mutual tls
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ManagerTlsConfig {
+    pub ca_cert: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    // defaults to the host of `addr`
+    pub domain: Option<String>,
+}
+
+impl ManagerConfig {
+    // FOLONET_MANAGER_* environment variables win over the config file
+    pub fn with_env(self) -> Self {
+        self.apply_env(|key| std::env::var(key).ok())
+    }
+
+    fn apply_env(mut self, get: impl Fn(&str) -> Option<String>) -> Self {
+        if let Some(addr) = get("FOLONET_MANAGER_ADDR") {
+            self.addr = addr;
+        }
+        if let Some(token) = get("FOLONET_MANAGER_TOKEN") {
+            self.token = Some(token);
+        }
+        let tls_vars = [
+            "FOLONET_MANAGER_CA_CERT",
+            "FOLONET_MANAGER_CLIENT_CERT",
+            "FOLONET_MANAGER_CLIENT_KEY",
+            "FOLONET_MANAGER_TLS_DOMAIN",
+        ]
+        .map(|key| get(key));
+        if tls_vars.iter().any(|v| v.is_some()) {
+            let [ca_cert, client_cert, client_key, domain] = tls_vars;
+            let tls = self.tls.get_or_insert_with(Default::default);
+            tls.ca_cert = ca_cert.or(tls.ca_cert.take());
+            tls.client_cert = client_cert.or(tls.client_cert.take());
+            tls.client_key = client_key.or(tls.client_key.take());
+            tls.domain = domain.or(tls.domain.take());
+        }
+        self
+    }
+}
+
+impl Default for ManagerConfig {
+    fn default() -> Self {
+        ManagerConfig {
+            addr: "http://[::1]:7788".to_string(),
+            connect_timeout_ms: 1000,
+            request_timeout_ms: 5000,
+            max_retries: 3,
+            initial_backoff_ms: 200,
+            max_backoff_ms: 5000,
+            token: None,
+            tls: None,
+        }
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_manager_env_overrides() {
+        use std::collections::HashMap;
+
+        use super::ManagerConfig;
+
+        let env = HashMap::from([
+            ("FOLONET_MANAGER_ADDR", "https://manager:7788"),
+            ("FOLONET_MANAGER_CA_CERT", "/etc/folonet/ca.pem"),
+        ]);
+        let cfg = ManagerConfig {
+            token: Some("secret".to_string()),
+            ..Default::default()
+        }
+        .apply_env(|key| env.get(key).map(|v| v.to_string()));
+
+        assert_eq!(cfg.addr, "https://manager:7788");
+        assert_eq!(cfg.token.as_deref(), Some("secret"));
+        let tls = cfg.tls.unwrap();
+        assert_eq!(tls.ca_cert.as_deref(), Some("/etc/folonet/ca.pem"));
+        assert_eq!(tls.client_cert, None);
+    }
+
+    #[test]
+    fn test_per_protocol() {
+        use super::{ServiceConfig, ServiceProtocol};
+
+        let cfg: ServiceConfig = serde_yaml::from_str(
+            "name: dns\nlocal_endpoint: 10.0.0.1:53\nservers: []\nprotocols: [udp, tcp, udp]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            cfg.served_protocols(),
+            vec![ServiceProtocol::Udp, ServiceProtocol::Tcp]
+        );
+        let copies = cfg.per_protocol();
+        assert_eq!(
+            copies.iter().map(|c| c.is_tcp).collect::<Vec<_>>(),
+            vec![false, true]
+        );
+        assert!(copies.iter().all(|c| c.protocols.is_empty()));
+
+        let cfg = ServiceConfig {
+            is_tcp: true,
+            ..Default::default()
+        };
+        assert_eq!(cfg.served_protocols(), vec![ServiceProtocol::Tcp]);
+    }
+}
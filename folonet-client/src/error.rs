@@ -0,0 +1,70 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ClientError {
+    // the manager address does not parse
+    InvalidAddr(String),
+    // a cert or key could not be loaded
+    Tls(String),
+    // the token is not a valid header value
+    InvalidToken,
+    Connect(tonic::transport::Error),
+    Rpc(tonic::Status),
+    Timeout,
+    // a cold start provider other than the manager failed
+    Provider(String),
+    // a request to a webhook, collector or the docker engine failed
+    Http(String),
+}
+
+impl ClientError {
+    // worth another try once the manager had some time
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ClientError::InvalidAddr(_)
+            | ClientError::Tls(_)
+            | ClientError::InvalidToken
+            | ClientError::Provider(_)
+            | ClientError::Http(_) => false,
+            ClientError::Connect(_) | ClientError::Timeout => true,
+            ClientError::Rpc(status) => matches!(
+                status.code(),
+                tonic::Code::Unavailable
+                    | tonic::Code::DeadlineExceeded
+                    | tonic::Code::ResourceExhausted
+                    | tonic::Code::Aborted
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::InvalidAddr(addr) => write!(f, "invalid manager address {}", addr),
+            ClientError::Tls(e) => write!(f, "invalid manager tls config: {}", e),
+            ClientError::InvalidToken => write!(f, "invalid manager token"),
+            ClientError::Connect(e) => write!(f, "failed to connect to the manager: {}", e),
+            ClientError::Rpc(status) => write!(f, "manager call failed: {}", status),
+            ClientError::Timeout => write!(f, "manager call timed out"),
+            ClientError::Provider(msg) => write!(f, "cold start provider: {}", msg),
+            ClientError::Http(msg) => write!(f, "http request failed: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Connect(e) => Some(e),
+            ClientError::Rpc(status) => Some(status),
+            _ => None,
+        }
+    }
+}
+
+impl From<tonic::Status> for ClientError {
+    fn from(status: tonic::Status) -> Self {
+        ClientError::Rpc(status)
+    }
+}
@@ -0,0 +1,156 @@
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use tokio::net::UnixStream;
+
+use crate::error::ClientError;
+
+// a response body past this is given up on
+const MAX_RESPONSE: usize = 1 << 20;
+
+pub type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+// A client for http and https urls, trusting the webpki roots. Cheap to
+// clone, the clones share their connections.
+pub fn https_client() -> HttpsClient {
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder().build(connector)
+}
+
+// an absolute http or https url, with a host
+pub fn check_url(url: &str) -> Result<(), ClientError> {
+    let uri: Uri = url
+        .parse()
+        .map_err(|e| ClientError::Http(format!("invalid url {}: {}", url, e)))?;
+    match (uri.scheme_str(), uri.host()) {
+        (Some("http" | "https"), Some(_)) => Ok(()),
+        _ => Err(ClientError::Http(format!(
+            "invalid url {}: not an http or https url",
+            url
+        ))),
+    }
+}
+
+async fn read_body(mut body: Body, what: &str) -> Result<Vec<u8>, ClientError> {
+    let mut read = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| ClientError::Http(format!("{}: {}", what, e)))?;
+        if read.len() + chunk.len() > MAX_RESPONSE {
+            return Err(ClientError::Http(format!(
+                "{}: response over {} bytes",
+                what, MAX_RESPONSE
+            )));
+        }
+        read.extend_from_slice(&chunk);
+    }
+    Ok(read)
+}
+
+// POST `body` as json to `url`, returns the status and body of the response
+pub async fn post_json(
+    client: &HttpsClient,
+    url: &str,
+    body: Vec<u8>,
+) -> Result<(u16, Vec<u8>), ClientError> {
+    let what = format!("POST {}", url);
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header("content-type", "application/json")
+        .body(Body::from(body))
+        .map_err(|e| ClientError::Http(format!("{}: {}", what, e)))?;
+    let response = client
+        .request(request)
+        .await
+        .map_err(|e| ClientError::Http(format!("{}: {}", what, e)))?;
+    let status = response.status().as_u16();
+    Ok((status, read_body(response.into_body(), &what).await?))
+}
+
+// GET `url`, returns the status and body of the response
+pub async fn get(client: &HttpsClient, url: &str) -> Result<(u16, Vec<u8>), ClientError> {
+    let what = format!("GET {}", url);
+    let uri: Uri = url
+        .parse()
+        .map_err(|e| ClientError::Http(format!("{}: {}", what, e)))?;
+    let response = client
+        .get(uri)
+        .await
+        .map_err(|e| ClientError::Http(format!("{}: {}", what, e)))?;
+    let status = response.status().as_u16();
+    Ok((status, read_body(response.into_body(), &what).await?))
+}
+
+// POST to `path` of the http server listening on the unix socket `socket`,
+// e.g. the docker engine, returns the status and body of the response
+pub async fn post_unix(socket: &str, path: &str) -> Result<(u16, Vec<u8>), ClientError> {
+    let what = format!("POST {} on {}", path, socket);
+    let failed = |e: &dyn std::fmt::Display| ClientError::Http(format!("{}: {}", what, e));
+    let stream = UnixStream::connect(socket).await.map_err(|e| failed(&e))?;
+    let (mut sender, conn) = hyper::client::conn::handshake(stream)
+        .await
+        .map_err(|e| failed(&e))?;
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(path)
+        .header("host", "localhost")
+        .body(Body::empty())
+        .map_err(|e| failed(&e))?;
+    let response = sender.send_request(request).await.map_err(|e| failed(&e))?;
+    let status = response.status().as_u16();
+    Ok((status, read_body(response.into_body(), &what).await?))
+}
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn test_check_url() {
+        use super::check_url;
+
+        assert!(check_url("http://127.0.0.1:9000/hook").is_ok());
+        assert!(check_url("https://hooks.local").is_ok());
+        assert!(check_url("hooks.local/start").is_err());
+        assert!(check_url("ftp://hooks.local/start").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_post_json() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        use super::{https_client, post_json, MAX_RESPONSE};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for body in [b"{}".to_vec(), vec![b' '; MAX_RESPONSE + 1]] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await.unwrap();
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).await.unwrap();
+                let _ = stream.write_all(&body).await;
+            }
+        });
+
+        let client = https_client();
+        let url = format!("http://{}/hook", addr);
+        let (status, body) = post_json(&client, &url, b"{}".to_vec()).await.unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, b"{}");
+        // a body too large is not read to its end
+        assert!(post_json(&client, &url, b"{}".to_vec()).await.is_err());
+    }
+}
@@ -1,57 +1,235 @@
-use tonic::{transport::Channel, Request};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::Duration;
+
+use tonic::{
+    metadata::MetadataValue,
+    transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity},
+    Request,
+};
 
 pub mod folonetrpc {
     tonic::include_proto!("folonetrpc");
 }
 
 use folonetrpc::{
-    server_manager_client::ServerManagerClient, StartServerRequest, StopServerRequest,
+    server_manager_client::ServerManagerClient, DrainServerRequest, ListServersRequest, ServerInfo,
+    StartServerRequest, StopServerRequest,
 };
 
 pub mod config;
+pub mod error;
+pub mod http;
+pub mod provider;
 
-async fn get_server_manager_client() -> ServerManagerClient<Channel> {
-    // let channel = Channel::from_static("http://[::1]:50051")
-    //     .connect()
-    //     .await
-    //     .unwrap();
-    ServerManagerClient::connect("http://[::1]:7788")
-        .await
-        .unwrap()
+use config::{ManagerConfig, ManagerTlsConfig};
+use error::ClientError;
+
+// Talks to the server manager. Every call gets its own connection and is
+// retried with exponential backoff while the manager looks temporarily gone.
+#[derive(Debug, Clone, Default)]
+pub struct ManagerClient {
+    cfg: ManagerConfig,
 }
 
-pub async fn start_server(local_endpoint: String) -> Option<config::ServiceConfig> {
-    let mut client = get_server_manager_client().await;
-    let server = client
-        .start_server(Request::new(StartServerRequest {
+impl ManagerClient {
+    pub fn new(cfg: ManagerConfig) -> Self {
+        ManagerClient { cfg }
+    }
+
+    async fn connect(&self) -> Result<ServerManagerClient<Channel>, ClientError> {
+        let endpoint = Endpoint::from_shared(self.cfg.addr.clone())
+            .map_err(|_| ClientError::InvalidAddr(self.cfg.addr.clone()))?
+            .connect_timeout(Duration::from_millis(self.cfg.connect_timeout_ms))
+            .timeout(Duration::from_millis(self.cfg.request_timeout_ms));
+        let endpoint = match &self.cfg.tls {
+            Some(tls) => endpoint
+                .tls_config(load_tls(tls)?)
+                .map_err(ClientError::Connect)?,
+            None => endpoint,
+        };
+        let channel = endpoint.connect().await.map_err(ClientError::Connect)?;
+        Ok(ServerManagerClient::new(channel))
+    }
+
+    fn request<T>(&self, msg: T) -> Result<Request<T>, ClientError> {
+        let mut request = Request::new(msg);
+        if let Some(token) = &self.cfg.token {
+            let value = MetadataValue::try_from(format!("Bearer {}", token))
+                .map_err(|_| ClientError::InvalidToken)?;
+            request.metadata_mut().insert("authorization", value);
+        }
+        Ok(request)
+    }
+
+    async fn call<T, F, Fut>(&self, f: F) -> Result<T, ClientError>
+    where
+        F: Fn(ServerManagerClient<Channel>) -> Fut,
+        Fut: Future<Output = Result<T, ClientError>>,
+    {
+        // connect and request both have their own timeout, this one bounds
+        // the pair
+        let attempt_timeout =
+            Duration::from_millis(self.cfg.connect_timeout_ms + self.cfg.request_timeout_ms);
+        let mut attempt = 1;
+        loop {
+            let result = tokio::time::timeout(attempt_timeout, async {
+                let client = self.connect().await?;
+                f(client).await
+            })
+            .await
+            .unwrap_or(Err(ClientError::Timeout));
+
+            match result {
+                Err(e) if e.is_transient() && attempt <= self.cfg.max_retries => {
+                    attempt += 1;
+                    tokio::time::sleep(backoff(&self.cfg, attempt)).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Ok(None) when the manager has no active server for `local_endpoint`
+    pub async fn start_server(
+        &self,
+        local_endpoint: String,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<Option<config::ServiceConfig>, ClientError> {
+        let server = self
+            .call(|mut client| {
+                let request = self.request(StartServerRequest {
+                    local_endpoint: local_endpoint.clone(),
+                    metadata: metadata.clone().into_iter().collect(),
+                });
+                async move {
+                    let response = client.start_server(request?).await?;
+                    Ok::<_, ClientError>(response.into_inner())
+                }
+            })
+            .await?;
+
+        if !server.active {
+            return Ok(None);
+        }
+
+        Ok(Some(config::ServiceConfig {
+            name: server.name.clone(),
             local_endpoint: local_endpoint.clone(),
+            servers: vec![server.server_endpoint.clone()],
+            is_tcp: true,
+            metadata: metadata.clone(),
+            ..Default::default()
         }))
+    }
+
+    pub async fn stop_server(&self, local_endpoint: String) -> Result<(), ClientError> {
+        self.call(|mut client| {
+            let request = self.request(StopServerRequest {
+                local_endpoint: local_endpoint.clone(),
+            });
+            async move {
+                client.stop_server(request?).await?;
+                Ok::<_, ClientError>(())
+            }
+        })
         .await
-        .unwrap()
-        .into_inner();
+    }
 
-    if !server.active {
-        return None;
+    // ask the backend of `local_endpoint` to wind down within `deadline`
+    pub async fn drain_server(
+        &self,
+        local_endpoint: String,
+        deadline: Duration,
+    ) -> Result<(), ClientError> {
+        self.call(|mut client| {
+            let request = self.request(DrainServerRequest {
+                local_endpoint: local_endpoint.clone(),
+                deadline_ms: deadline.as_millis() as u64,
+            });
+            async move {
+                client.drain_server(request?).await?;
+                Ok::<_, ClientError>(())
+            }
+        })
+        .await
     }
 
-    Some(config::ServiceConfig {
-        name: server.name.clone(),
-        local_endpoint: local_endpoint.clone(),
-        servers: vec![server.server_endpoint.clone()],
-        is_tcp: true,
-    })
+    pub async fn list_servers(&self) -> Result<Vec<ServerInfo>, ClientError> {
+        self.call(|mut client| {
+            let request = self.request(ListServersRequest {});
+            async move {
+                let response = client.list_servers(request?).await?;
+                Ok::<_, ClientError>(response.into_inner().servers)
+            }
+        })
+        .await
+    }
 }
 
-pub async fn stop_server(local_endpoint: String) {
-    let mut client = get_server_manager_client().await;
-    let _ = client
-        .stop_server(Request::new(StopServerRequest {
-            local_endpoint: local_endpoint.clone(),
-        }))
-        .await
-        .unwrap()
-        .into_inner();
+fn read_pem(path: &str) -> Result<Vec<u8>, ClientError> {
+    std::fs::read(path).map_err(|e| ClientError::Tls(format!("failed to read {}: {}", path, e)))
+}
+
+fn load_tls(cfg: &ManagerTlsConfig) -> Result<ClientTlsConfig, ClientError> {
+    let mut tls = ClientTlsConfig::new();
+    if let Some(ca_cert) = &cfg.ca_cert {
+        tls = tls.ca_certificate(Certificate::from_pem(read_pem(ca_cert)?));
+    }
+    match (&cfg.client_cert, &cfg.client_key) {
+        (Some(cert), Some(key)) => {
+            tls = tls.identity(Identity::from_pem(read_pem(cert)?, read_pem(key)?));
+        }
+        (None, None) => {}
+        _ => {
+            return Err(ClientError::Tls(
+                "client_cert and client_key go together".to_string(),
+            ))
+        }
+    }
+    if let Some(domain) = &cfg.domain {
+        tls = tls.domain_name(domain.clone());
+    }
+    Ok(tls)
+}
+
+fn backoff(cfg: &ManagerConfig, attempt: u32) -> Duration {
+    if attempt <= 1 {
+        return Duration::ZERO;
+    }
+    let delay = Duration::from_millis(cfg.initial_backoff_ms) * 2u32.pow((attempt - 2).min(16));
+    delay.min(Duration::from_millis(cfg.max_backoff_ms))
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+
+    #[test]
+    fn test_backoff() {
+        use std::time::Duration;
+
+        use super::backoff;
+        use crate::config::ManagerConfig;
+
+        let cfg = ManagerConfig {
+            initial_backoff_ms: 200,
+            max_backoff_ms: 1000,
+            ..Default::default()
+        };
+
+        assert_eq!(backoff(&cfg, 1), Duration::ZERO);
+        assert_eq!(backoff(&cfg, 2), Duration::from_millis(200));
+        assert_eq!(backoff(&cfg, 4), Duration::from_millis(800));
+        assert_eq!(backoff(&cfg, 6), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_transient_errors() {
+        use crate::error::ClientError;
+
+        assert!(ClientError::Timeout.is_transient());
+        assert!(ClientError::Rpc(tonic::Status::unavailable("down")).is_transient());
+        assert!(!ClientError::Rpc(tonic::Status::not_found("no such service")).is_transient());
+        assert!(!ClientError::InvalidAddr("::".to_string()).is_transient());
+    }
+}
@@ -0,0 +1,518 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::config::{ProviderConfig, ServiceConfig};
+use crate::error::ClientError;
+use crate::http::{https_client, post_json, post_unix, HttpsClient};
+use crate::ManagerClient;
+
+// a docker or webhook call, the start of a container included
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+// what docker gives a container to stop before it kills it
+const DOCKER_STOP_SECS: u64 = 10;
+
+pub type ProviderFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, ClientError>> + Send + 'a>>;
+
+// Starts, stops and drains the backends of cold started services. The server
+// manager is one, a service may pick another with its `provider`.
+pub trait ColdStartProvider: Send + Sync {
+    // Ok(None) when there is no backend to start for `local_endpoint`
+    fn start_server<'a>(
+        &'a self,
+        local_endpoint: &'a str,
+        metadata: &'a BTreeMap<String, String>,
+    ) -> ProviderFuture<'a, Option<ServiceConfig>>;
+
+    fn stop_server<'a>(&'a self, local_endpoint: &'a str) -> ProviderFuture<'a, ()>;
+
+    // ask the backend to wind down within `deadline`
+    fn drain_server<'a>(
+        &'a self,
+        local_endpoint: &'a str,
+        deadline: Duration,
+    ) -> ProviderFuture<'a, ()>;
+}
+
+impl ColdStartProvider for ManagerClient {
+    fn start_server<'a>(
+        &'a self,
+        local_endpoint: &'a str,
+        metadata: &'a BTreeMap<String, String>,
+    ) -> ProviderFuture<'a, Option<ServiceConfig>> {
+        Box::pin(ManagerClient::start_server(
+            self,
+            local_endpoint.to_string(),
+            metadata,
+        ))
+    }
+
+    fn stop_server<'a>(&'a self, local_endpoint: &'a str) -> ProviderFuture<'a, ()> {
+        Box::pin(ManagerClient::stop_server(self, local_endpoint.to_string()))
+    }
+
+    fn drain_server<'a>(
+        &'a self,
+        local_endpoint: &'a str,
+        deadline: Duration,
+    ) -> ProviderFuture<'a, ()> {
+        Box::pin(ManagerClient::drain_server(
+            self,
+            local_endpoint.to_string(),
+            deadline,
+        ))
+    }
+}
+
+// what a provider reports once the backend of the service `name` started,
+// like the manager does
+fn started(
+    name: &str,
+    local_endpoint: &str,
+    server_endpoint: String,
+    metadata: &BTreeMap<String, String>,
+) -> ServiceConfig {
+    ServiceConfig {
+        name: name.to_string(),
+        local_endpoint: local_endpoint.to_string(),
+        servers: vec![server_endpoint],
+        is_tcp: true,
+        metadata: metadata.clone(),
+        ..Default::default()
+    }
+}
+
+// FOLONET_META_<KEY> for every metadata entry, the key upper cased with
+// anything but letters and digits turned into underscores
+fn metadata_env(metadata: &BTreeMap<String, String>) -> Vec<(String, String)> {
+    metadata
+        .iter()
+        .map(|(key, value)| {
+            let key: String = key
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() {
+                        c.to_ascii_uppercase()
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            (format!("FOLONET_META_{}", key), value.clone())
+        })
+        .collect()
+}
+
+async fn run(argv: &[String], env: &[(String, String)]) -> Result<(), ClientError> {
+    let joined = argv.join(" ");
+    let (program, args) = argv
+        .split_first()
+        .ok_or_else(|| ClientError::Provider("empty command".to_string()))?;
+    let status = Command::new(program)
+        .args(args)
+        .envs(env.iter().cloned())
+        .status()
+        .await
+        .map_err(|e| ClientError::Provider(format!("failed to run {}: {}", joined, e)))?;
+    if !status.success() {
+        return Err(ClientError::Provider(format!(
+            "{} exited with {}",
+            joined, status
+        )));
+    }
+    Ok(())
+}
+
+// runs commands on this host, the way the manager does for its exec backends
+struct ExecProvider {
+    name: String,
+    start: Vec<String>,
+    stop: Vec<String>,
+    drain: Vec<String>,
+    server_endpoint: String,
+}
+
+impl ColdStartProvider for ExecProvider {
+    fn start_server<'a>(
+        &'a self,
+        local_endpoint: &'a str,
+        metadata: &'a BTreeMap<String, String>,
+    ) -> ProviderFuture<'a, Option<ServiceConfig>> {
+        Box::pin(async move {
+            run(&self.start, &metadata_env(metadata)).await?;
+            Ok(Some(started(
+                &self.name,
+                local_endpoint,
+                self.server_endpoint.clone(),
+                metadata,
+            )))
+        })
+    }
+
+    fn stop_server<'a>(&'a self, _local_endpoint: &'a str) -> ProviderFuture<'a, ()> {
+        Box::pin(async move {
+            if self.stop.is_empty() {
+                return Ok(());
+            }
+            run(&self.stop, &[]).await
+        })
+    }
+
+    fn drain_server<'a>(
+        &'a self,
+        _local_endpoint: &'a str,
+        deadline: Duration,
+    ) -> ProviderFuture<'a, ()> {
+        Box::pin(async move {
+            if self.drain.is_empty() {
+                return Ok(());
+            }
+            let env = [(
+                "FOLONET_DRAIN_DEADLINE_MS".to_string(),
+                deadline.as_millis().to_string(),
+            )];
+            run(&self.drain, &env).await
+        })
+    }
+}
+
+async fn with_timeout<T>(
+    what: &str,
+    call: impl Future<Output = Result<T, ClientError>>,
+) -> Result<T, ClientError> {
+    tokio::time::timeout(REQUEST_TIMEOUT, call)
+        .await
+        .unwrap_or_else(|_| Err(ClientError::Provider(format!("{} timed out", what))))
+}
+
+// a container started and stopped through the docker engine api on its unix
+// socket, draining is left to its stop
+struct DockerProvider {
+    name: String,
+    container: String,
+    server_endpoint: String,
+    socket: String,
+}
+
+impl DockerProvider {
+    async fn call(&self, path: String) -> Result<(), ClientError> {
+        with_timeout(&path, async {
+            let (status, body) = post_unix(&self.socket, &path).await?;
+            // 304 when the container already is where it should go
+            if !(200..300).contains(&status) && status != 304 {
+                return Err(ClientError::Provider(format!(
+                    "docker answered {} to {}: {}",
+                    status,
+                    path,
+                    String::from_utf8_lossy(&body).trim()
+                )));
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+impl ColdStartProvider for DockerProvider {
+    fn start_server<'a>(
+        &'a self,
+        local_endpoint: &'a str,
+        metadata: &'a BTreeMap<String, String>,
+    ) -> ProviderFuture<'a, Option<ServiceConfig>> {
+        Box::pin(async move {
+            self.call(format!("/containers/{}/start", self.container))
+                .await?;
+            Ok(Some(started(
+                &self.name,
+                local_endpoint,
+                self.server_endpoint.clone(),
+                metadata,
+            )))
+        })
+    }
+
+    fn stop_server<'a>(&'a self, _local_endpoint: &'a str) -> ProviderFuture<'a, ()> {
+        Box::pin(self.call(format!(
+            "/containers/{}/stop?t={}",
+            self.container, DOCKER_STOP_SECS
+        )))
+    }
+
+    fn drain_server<'a>(
+        &'a self,
+        _local_endpoint: &'a str,
+        _deadline: Duration,
+    ) -> ProviderFuture<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+// A container of containerd, started and stopped by running nerdctl, which
+// has to be on the PATH of folonet. Draining is left to its stop.
+struct ContainerdProvider {
+    name: String,
+    container: String,
+    server_endpoint: String,
+    namespace: String,
+}
+
+impl ContainerdProvider {
+    fn nerdctl(&self, action: &str) -> Vec<String> {
+        vec![
+            "nerdctl".to_string(),
+            "--namespace".to_string(),
+            self.namespace.clone(),
+            action.to_string(),
+            self.container.clone(),
+        ]
+    }
+}
+
+impl ColdStartProvider for ContainerdProvider {
+    fn start_server<'a>(
+        &'a self,
+        local_endpoint: &'a str,
+        metadata: &'a BTreeMap<String, String>,
+    ) -> ProviderFuture<'a, Option<ServiceConfig>> {
+        Box::pin(async move {
+            run(&self.nerdctl("start"), &[]).await?;
+            Ok(Some(started(
+                &self.name,
+                local_endpoint,
+                self.server_endpoint.clone(),
+                metadata,
+            )))
+        })
+    }
+
+    fn stop_server<'a>(&'a self, _local_endpoint: &'a str) -> ProviderFuture<'a, ()> {
+        Box::pin(async move { run(&self.nerdctl("stop"), &[]).await })
+    }
+
+    fn drain_server<'a>(
+        &'a self,
+        _local_endpoint: &'a str,
+        _deadline: Duration,
+    ) -> ProviderFuture<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[derive(Serialize)]
+struct HookRequest<'a> {
+    action: &'a str,
+    name: &'a str,
+    local_endpoint: &'a str,
+    // only sent with a start
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: &'a BTreeMap<String, String>,
+    // only sent with a drain
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deadline_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct HookResponse {
+    #[serde(default)]
+    server_endpoint: Option<String>,
+}
+
+// POSTs every start, stop and drain to `url`, http or https, with the same
+// body the manager sends its webhooks
+struct WebhookProvider {
+    name: String,
+    url: String,
+    server_endpoint: Option<String>,
+    client: HttpsClient,
+}
+
+impl WebhookProvider {
+    async fn hook(
+        &self,
+        action: &str,
+        local_endpoint: &str,
+        metadata: &BTreeMap<String, String>,
+        deadline_ms: Option<u64>,
+    ) -> Result<HookResponse, ClientError> {
+        let body = serde_json::to_vec(&HookRequest {
+            action,
+            name: &self.name,
+            local_endpoint,
+            metadata,
+            deadline_ms,
+        })
+        .map_err(|e| ClientError::Provider(e.to_string()))?;
+
+        let (status, body) =
+            with_timeout(&self.url, post_json(&self.client, &self.url, body)).await?;
+        if !(200..300).contains(&status) {
+            return Err(ClientError::Provider(format!(
+                "{} answered {}",
+                self.url, status
+            )));
+        }
+        if body.is_empty() {
+            return Ok(HookResponse::default());
+        }
+        serde_json::from_slice(&body).map_err(|e| ClientError::Provider(e.to_string()))
+    }
+}
+
+impl ColdStartProvider for WebhookProvider {
+    fn start_server<'a>(
+        &'a self,
+        local_endpoint: &'a str,
+        metadata: &'a BTreeMap<String, String>,
+    ) -> ProviderFuture<'a, Option<ServiceConfig>> {
+        Box::pin(async move {
+            let reported = self
+                .hook("start", local_endpoint, metadata, None)
+                .await?
+                .server_endpoint;
+            let server_endpoint = reported
+                .or_else(|| self.server_endpoint.clone())
+                .ok_or_else(|| {
+                    ClientError::Provider(format!("no server endpoint known for {}", self.name))
+                })?;
+            Ok(Some(started(
+                &self.name,
+                local_endpoint,
+                server_endpoint,
+                metadata,
+            )))
+        })
+    }
+
+    fn stop_server<'a>(&'a self, local_endpoint: &'a str) -> ProviderFuture<'a, ()> {
+        Box::pin(async move {
+            self.hook("stop", local_endpoint, &BTreeMap::new(), None)
+                .await
+                .map(|_| ())
+        })
+    }
+
+    fn drain_server<'a>(
+        &'a self,
+        local_endpoint: &'a str,
+        deadline: Duration,
+    ) -> ProviderFuture<'a, ()> {
+        Box::pin(async move {
+            let deadline_ms = Some(deadline.as_millis() as u64);
+            self.hook("drain", local_endpoint, &BTreeMap::new(), deadline_ms)
+                .await
+                .map(|_| ())
+        })
+    }
+}
+
+fn provider_of(name: &str, cfg: &ProviderConfig) -> Arc<dyn ColdStartProvider> {
+    let name = name.to_string();
+    match cfg.clone() {
+        ProviderConfig::Exec {
+            start,
+            stop,
+            drain,
+            server_endpoint,
+        } => Arc::new(ExecProvider {
+            name,
+            start,
+            stop,
+            drain,
+            server_endpoint,
+        }),
+        ProviderConfig::Docker {
+            container,
+            server_endpoint,
+            socket,
+        } => Arc::new(DockerProvider {
+            name,
+            container,
+            server_endpoint,
+            socket,
+        }),
+        ProviderConfig::Containerd {
+            container,
+            server_endpoint,
+            namespace,
+        } => Arc::new(ContainerdProvider {
+            name,
+            container,
+            server_endpoint,
+            namespace,
+        }),
+        ProviderConfig::Webhook {
+            url,
+            server_endpoint,
+        } => Arc::new(WebhookProvider {
+            name,
+            url,
+            server_endpoint,
+            client: https_client(),
+        }),
+    }
+}
+
+// The provider of every service by its local endpoint, the manager for the
+// services without one. Cheap to clone.
+#[derive(Clone)]
+pub struct Providers {
+    manager: ManagerClient,
+    providers: Arc<HashMap<SocketAddr, Arc<dyn ColdStartProvider>>>,
+}
+
+impl Providers {
+    pub fn new(manager: ManagerClient, services: &[ServiceConfig]) -> Self {
+        let providers = services
+            .iter()
+            .filter_map(|service| {
+                let cfg = service.provider.as_ref()?;
+                let local_endpoint = service.local_endpoint.parse::<SocketAddr>().ok()?;
+                Some((local_endpoint, provider_of(&service.name, cfg)))
+            })
+            .collect();
+        Providers {
+            manager,
+            providers: Arc::new(providers),
+        }
+    }
+
+    fn of(&self, local_endpoint: &str) -> &dyn ColdStartProvider {
+        local_endpoint
+            .parse::<SocketAddr>()
+            .ok()
+            .and_then(|local_endpoint| self.providers.get(&local_endpoint))
+            .map(|provider| provider.as_ref() as &dyn ColdStartProvider)
+            .unwrap_or(&self.manager)
+    }
+
+    // Ok(None) when there is no backend to start for `local_endpoint`
+    pub async fn start_server(
+        &self,
+        local_endpoint: String,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<Option<ServiceConfig>, ClientError> {
+        self.of(&local_endpoint)
+            .start_server(&local_endpoint, metadata)
+            .await
+    }
+
+    pub async fn stop_server(&self, local_endpoint: String) -> Result<(), ClientError> {
+        self.of(&local_endpoint).stop_server(&local_endpoint).await
+    }
+
+    pub async fn drain_server(
+        &self,
+        local_endpoint: String,
+        deadline: Duration,
+    ) -> Result<(), ClientError> {
+        self.of(&local_endpoint)
+            .drain_server(&local_endpoint, deadline)
+            .await
+    }
+}
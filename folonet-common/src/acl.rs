@@ -0,0 +1,29 @@
+use crate::KEndpoint;
+
+pub const ACL_ALLOW: u8 = 0;
+pub const ACL_DENY: u8 = 1;
+
+// the service endpoint is always matched as a whole, only the client ip is a prefix
+pub const ACL_SERVICE_PREFIX_LEN: u32 = 64;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KAclKey {
+    pub service: KEndpoint,
+    // network byte order, so that prefixes match from the most significant bit
+    pub ip: u32,
+    pub _pad: u32,
+}
+
+impl KAclKey {
+    pub fn new(service: KEndpoint, ip: u32) -> Self {
+        KAclKey {
+            service,
+            ip,
+            _pad: 0,
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KAclKey {}
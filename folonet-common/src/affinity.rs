@@ -0,0 +1,49 @@
+use crate::KEndpoint;
+
+// key of AFFINITY_MAP, a client of a service
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KAffinityKey {
+    pub service: KEndpoint,
+    pub client_ip: u32,
+    pub _pad: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KAffinityKey {}
+
+// the backend of the last connection of a client and when it opened
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KAffinity {
+    pub backend: KEndpoint,
+    pub last_ns: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KAffinity {}
+
+impl KAffinity {
+    // whether the client still keeps to its backend at `now`
+    #[inline(always)]
+    pub fn is_fresh(&self, now: u64, timeout_ns: u64) -> bool {
+        now.saturating_sub(self.last_ns) < timeout_ns
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_is_fresh() {
+        use super::KAffinity;
+
+        let affinity = KAffinity {
+            last_ns: 1_000,
+            ..Default::default()
+        };
+        assert!(affinity.is_fresh(1_500, 1_000));
+        assert!(!affinity.is_fresh(2_000, 1_000));
+        // a clock read on another cpu just before
+        assert!(affinity.is_fresh(900, 1_000));
+    }
+}
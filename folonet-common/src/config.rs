@@ -0,0 +1,64 @@
+pub const SYN_FLOOD_ACTION_DROP: u8 = 0;
+pub const SYN_FLOOD_ACTION_COOKIE: u8 = 1;
+
+// what happens to ip fragments after the first, which carry no port to nat by
+pub const FRAG_POLICY_PASS: u8 = 0;
+pub const FRAG_POLICY_DROP: u8 = 1;
+
+// what happens to tcp packets other than a syn of connections without state
+pub const UNKNOWN_CONN_PASS: u8 = 0;
+pub const UNKNOWN_CONN_DROP: u8 = 1;
+pub const UNKNOWN_CONN_RST: u8 = 2;
+
+// what happens to connections a backend starts to anything but a service
+pub const BACKEND_EGRESS_PASS: u8 = 0;
+pub const BACKEND_EGRESS_MASQUERADE: u8 = 1;
+pub const BACKEND_EGRESS_BLOCK: u8 = 2;
+
+// runtime knobs of the xdp program, written by userspace into the single slot of the CONFIG map
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KConfig {
+    pub syn_window_ns: u64,
+    // 0 means syn flood protection is disabled
+    pub syn_half_open_threshold: u32,
+    pub syn_cookie_secret: u32,
+    pub syn_flood_action: u8,
+    // report the first data bytes of every connection on FIRST_DATA
+    pub sample_first_data: u8,
+    pub frag_policy: u8,
+    // report tcp packets with these flags on PACKET_EVENT for one in n
+    // connections, 0 for none
+    pub notify_syn: u8,
+    pub notify_syn_ack: u8,
+    pub notify_rst: u8,
+    pub notify_first_ack: u8,
+    pub unknown_conn_action: u8,
+    // time the processing of one in n packets into LATENCY, 0 for none
+    pub latency_sample: u32,
+    pub backend_egress_action: u8,
+    // leave out the log messages of the xdp program
+    pub quiet: u8,
+    // answer turned away connections instead of dropping their packets
+    pub reject: u8,
+    // hand the flows the xdp program cannot rewrite to the userspace proxy
+    pub fallback: u8,
+    // packets of this interface go to the af_xdp worker of their rx queue to
+    // be rewritten, 0 for none
+    pub af_xdp_ifindex: u32,
+    pub _pad3: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KConfig {}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KHalfOpen {
+    pub count: u32,
+    pub _pad: u32,
+    pub window_start_ns: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KHalfOpen {}
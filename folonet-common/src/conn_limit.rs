@@ -0,0 +1,20 @@
+// what happens to a new connection of a service with all the connections it
+// may have open
+pub const CONN_LIMIT_DROP: u8 = 0;
+pub const CONN_LIMIT_RST: u8 = 1;
+
+// the connections one service may have open at once, keyed by the service
+// endpoint and written by userspace only
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KConnLimit {
+    pub max: u64,
+    // connections of the service userspace saw closed so far, the xdp program
+    // counts the ones it opened in CONN_OPENED_MAP
+    pub closed: u64,
+    pub action: u8,
+    pub _pad: [u8; 7],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KConnLimit {}
@@ -0,0 +1,20 @@
+// the interface is always matched as a whole, only the backend ip is a prefix
+pub const SOURCE_IFINDEX_PREFIX_LEN: u32 = 32;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KSourceKey {
+    pub ifindex: u32,
+    // of the backend, network byte order so that prefixes match from the most
+    // significant bit
+    pub ip: u32,
+}
+
+impl KSourceKey {
+    pub fn new(ifindex: u32, ip: u32) -> Self {
+        KSourceKey { ifindex, ip }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KSourceKey {}
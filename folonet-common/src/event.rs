@@ -3,49 +3,157 @@ use network_types::tcp::TcpHdr;
 
 use crate::L4Hdr;
 
+// a u8 tag first, so a frame can be checked for a valid one before it is read
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-
+#[cfg_attr(feature = "std", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
 pub enum Event {
     TcpPacket(Packet),
     UdpPacket(Packet),
 }
 
+// The canonical encoding of an event, little endian:
+//
+//   version: u8, EVENT_VERSION
+//   type: u8, EVENT_TYPE_*
+//   len: u16, of the payload
+//   payload: len bytes, per type
+//
+// A decoder skips the payload of a type it does not know by its length, so
+// new types can be added without a new version. The version is only bumped
+// when the payload of an existing type changes.
+pub const EVENT_VERSION: u8 = 1;
+pub const EVENT_HEADER_SIZE: usize = 4;
+
+pub const EVENT_TYPE_TCP_PACKET: u8 = 1;
+pub const EVENT_TYPE_UDP_PACKET: u8 = 2;
+// reserved for events to come
+pub const EVENT_TYPE_STATS: u8 = 3;
+pub const EVENT_TYPE_ERROR: u8 = 4;
+pub const EVENT_TYPE_CONNTRACK: u8 = 5;
+
+// flag, seq and ack_seq
+const PACKET_PAYLOAD_SIZE: usize = 12;
+// the largest encoding of any event known to this version
+pub const EVENT_MAX_SIZE: usize = EVENT_HEADER_SIZE + PACKET_PAYLOAD_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventError {
+    // the buffer has fewer bytes than the event needs
+    Short { need: usize, got: usize },
+    Version(u8),
+    // a type this version does not know, `size` bytes to skip in all
+    UnknownType { event_type: u8, size: usize },
+    // a known type with a payload of the wrong length
+    Payload { event_type: u8, len: usize },
+}
+
+impl core::fmt::Display for EventError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            EventError::Short { need, got } => {
+                write!(f, "event needs {} bytes, got {}", need, got)
+            }
+            EventError::Version(v) => {
+                write!(f, "event version {}, expected {}", v, EVENT_VERSION)
+            }
+            EventError::UnknownType { event_type, .. } => {
+                write!(f, "unknown event type {}", event_type)
+            }
+            EventError::Payload { event_type, len } => {
+                write!(f, "payload of {} bytes for event type {}", len, event_type)
+            }
+        }
+    }
+}
+
 impl Event {
+    // of the in memory repr the kernel writes, unrelated to EVENT_TYPE_*
+    pub const MAX_TAG: u8 = 1;
+
     pub fn type_id(&self) -> u8 {
         match self {
-            Event::TcpPacket(_) => 1,
-            Event::UdpPacket(_) => 2,
+            Event::TcpPacket(_) => EVENT_TYPE_TCP_PACKET,
+            Event::UdpPacket(_) => EVENT_TYPE_UDP_PACKET,
         }
     }
 
     pub fn new_packet_event(hdr: &L4Hdr) -> Self {
-        match hdr {
-            L4Hdr::TcpHdr(tcphdr) => Event::TcpPacket(Packet::new(&unsafe { **tcphdr })),
-            L4Hdr::UdpHdr(_) => Event::UdpPacket(Packet::default()),
+        match hdr.tcp_hdr() {
+            Some(tcphdr) => Event::TcpPacket(Packet::new(&tcphdr)),
+            None => Event::UdpPacket(Packet::default()),
         }
     }
-}
 
-impl From<&Event> for u128 {
-    fn from(e: &Event) -> u128 {
-        match e {
-            Event::TcpPacket(ref p) => (e.type_id() as u128) << 120 | u128::from(p),
-            Event::UdpPacket(_) => 0,
+    // write the event to the start of `buf`, returns the bytes written
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, EventError> {
+        let (Event::TcpPacket(packet) | Event::UdpPacket(packet)) = self;
+        let size = EVENT_HEADER_SIZE + PACKET_PAYLOAD_SIZE;
+        if buf.len() < size {
+            return Err(EventError::Short {
+                need: size,
+                got: buf.len(),
+            });
         }
+        buf[0] = EVENT_VERSION;
+        buf[1] = self.type_id();
+        buf[2..4].copy_from_slice(&(PACKET_PAYLOAD_SIZE as u16).to_le_bytes());
+        buf[4..8].copy_from_slice(&packet.flag.bits().to_le_bytes());
+        buf[8..12].copy_from_slice(&packet.seq.to_le_bytes());
+        buf[12..16].copy_from_slice(&packet.ack_seq.to_le_bytes());
+        Ok(size)
     }
-}
 
-impl From<u128> for Event {
-    fn from(v: u128) -> Self {
-        let type_id = (v >> 120) as u8;
-        match type_id {
-            1 => Event::TcpPacket(Packet::from(v)),
-            _ => panic!("unknown event type id: {}", type_id),
+    // the event at the start of `buf` and the bytes it took
+    pub fn decode(buf: &[u8]) -> Result<(Self, usize), EventError> {
+        if buf.len() < EVENT_HEADER_SIZE {
+            return Err(EventError::Short {
+                need: EVENT_HEADER_SIZE,
+                got: buf.len(),
+            });
+        }
+        if buf[0] != EVENT_VERSION {
+            return Err(EventError::Version(buf[0]));
+        }
+        let event_type = buf[1];
+        let len = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+        let size = EVENT_HEADER_SIZE + len;
+        if buf.len() < size {
+            return Err(EventError::Short {
+                need: size,
+                got: buf.len(),
+            });
         }
+        let payload = &buf[EVENT_HEADER_SIZE..size];
+
+        let event = match event_type {
+            EVENT_TYPE_TCP_PACKET | EVENT_TYPE_UDP_PACKET => {
+                if len != PACKET_PAYLOAD_SIZE {
+                    return Err(EventError::Payload { event_type, len });
+                }
+                let word = |i: usize| {
+                    u32::from_le_bytes([payload[i], payload[i + 1], payload[i + 2], payload[i + 3]])
+                };
+                let packet = Packet {
+                    flag: PacketFlag::from_bits_truncate(word(0)),
+                    seq: word(4),
+                    ack_seq: word(8),
+                };
+                if event_type == EVENT_TYPE_TCP_PACKET {
+                    Event::TcpPacket(packet)
+                } else {
+                    Event::UdpPacket(packet)
+                }
+            }
+            _ => return Err(EventError::UnknownType { event_type, size }),
+        };
+        Ok((event, size))
     }
 }
 
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "std", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
 pub struct Packet {
     pub flag: PacketFlag,
     pub ack_seq: u32,
@@ -64,6 +172,9 @@ impl Packet {
         if tcphdr.ack() != 0 {
             flag.insert(PacketFlag::ACK);
         }
+        if tcphdr.rst() != 0 {
+            flag.insert(PacketFlag::RST);
+        }
         Packet {
             flag,
             ack_seq: u32::from_be(tcphdr.ack_seq),
@@ -82,47 +193,72 @@ impl Packet {
     pub fn is_ack(&self) -> bool {
         return self.flag.contains(PacketFlag::ACK);
     }
+
+    pub fn is_rst(&self) -> bool {
+        return self.flag.contains(PacketFlag::RST);
+    }
 }
 
 bitflags! {
     #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    #[cfg_attr(feature = "std", derive(serde::Serialize, serde::Deserialize))]
     pub struct PacketFlag: u32 {
          const SYN = 0b0000_0001;
          const FIN = 0b0000_0010;
          const ACK = 0b0000_0100;
-    }
-}
-
-impl From<u128> for Packet {
-    fn from(value: u128) -> Self {
-        let ack_seq = ((value as u64) >> 32) as u32;
-        let seq = value as u32;
-        let flag = PacketFlag::from_bits_truncate((value >> 64) as u32);
-        Packet { flag, ack_seq, seq }
-    }
-}
-
-impl From<&Packet> for u128 {
-    fn from(value: &Packet) -> Self {
-        value.into()
+         const RST = 0b0000_1000;
     }
 }
 
 mod test {
 
     #[test]
-    fn test_packet() {
-        use super::{Packet, PacketFlag};
-        let p = Packet {
-            flag: PacketFlag::ACK | PacketFlag::SYN,
-            ack_seq: 128,
-            seq: 129,
-        };
+    fn test_event_round_trip() {
+        use super::{Event, Packet, PacketFlag, EVENT_MAX_SIZE};
 
-        let v: u128 = (&p).into();
+        let events = [
+            Event::TcpPacket(Packet {
+                flag: PacketFlag::ACK | PacketFlag::SYN,
+                ack_seq: 128,
+                seq: 129,
+            }),
+            Event::UdpPacket(Packet::default()),
+        ];
+        let mut buf = [0u8; EVENT_MAX_SIZE * 2];
+        let mut len = 0;
+        for event in events.iter() {
+            len += event.encode(&mut buf[len..]).unwrap();
+        }
+
+        let (first, size) = Event::decode(&buf[..len]).unwrap();
+        assert_eq!(first, events[0]);
+        let (second, _) = Event::decode(&buf[size..len]).unwrap();
+        assert_eq!(second, events[1]);
+    }
 
-        let got_p: Packet = v.into();
+    #[test]
+    fn test_event_decode_errors() {
+        use super::{Event, EventError, EVENT_TYPE_CONNTRACK, EVENT_VERSION};
 
-        assert_eq!(p, got_p);
+        assert_eq!(
+            Event::decode(&[EVENT_VERSION, 1]),
+            Err(EventError::Short { need: 4, got: 2 })
+        );
+        assert_eq!(Event::decode(&[9, 1, 0, 0]), Err(EventError::Version(9)));
+        // a newer type is skipped by its length
+        assert_eq!(
+            Event::decode(&[EVENT_VERSION, EVENT_TYPE_CONNTRACK, 2, 0, 0xaa, 0xbb]),
+            Err(EventError::UnknownType {
+                event_type: EVENT_TYPE_CONNTRACK,
+                size: 6
+            })
+        );
+        assert_eq!(
+            Event::decode(&[EVENT_VERSION, 1, 1, 0, 0]),
+            Err(EventError::Payload {
+                event_type: 1,
+                len: 1
+            })
+        );
     }
 }
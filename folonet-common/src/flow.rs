@@ -0,0 +1,48 @@
+use crate::KConnection;
+
+// per connection accounting, keyed by the client side way (client -> local_in)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KFlow {
+    pub start_ns: u64,
+    pub last_ns: u64,
+    // ip bytes from the client towards the backend
+    pub bytes_in: u64,
+    // ip bytes from the backend back to the client
+    pub bytes_out: u64,
+    // set once the first data of the client was sampled
+    pub sampled: u32,
+    // FLOW_NOTIFIED_* of the packets reported once per connection
+    pub notified: u32,
+    // backend -> local_out, what is left of the connection once its nat
+    // entries are evicted
+    pub backend_way: KConnection,
+    // SERVICE_EPOCH when the connection opened, which is bumped with every
+    // backend installed for the service
+    pub generation: u32,
+    pub _pad: u32,
+    // when the backend answered the syn, 0 before
+    pub syn_ack_ns: u64,
+}
+
+pub const FLOW_NOTIFIED_FIRST_ACK: u32 = 1;
+pub const FLOW_NOTIFIED_EVICTED: u32 = 2;
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KFlow {}
+
+// a connection whose nat entries were evicted from the lru CONNECTION map
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KEviction {
+    // client -> local_in
+    pub client_way: KConnection,
+    // backend -> local_out
+    pub backend_way: KConnection,
+}
+
+impl KEviction {
+    pub fn from_bytes(bs: &[u8]) -> Self {
+        unsafe { *core::mem::transmute::<*const u8, *const KEviction>(bs.as_ptr()) }.clone()
+    }
+}
@@ -0,0 +1,220 @@
+use core::{
+    marker::PhantomData,
+    mem,
+    ptr::{addr_of_mut, NonNull},
+};
+
+use network_types::{tcp::TcpHdr, udp::UdpHdr};
+
+use crate::BiPort;
+
+// The bytes of one packet, e.g. between data and data_end of an xdp context.
+// Headers are only handed out from within them and cannot outlive them. A
+// packet changing its length, see rewrite_tcp, needs new bounds.
+#[derive(Clone, Copy)]
+pub struct PacketBounds<'a> {
+    start: usize,
+    end: usize,
+    _packet: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> PacketBounds<'a> {
+    /// # Safety
+    ///
+    /// `start..end` must be writable memory valid for `'a`.
+    #[inline(always)]
+    pub unsafe fn new(start: usize, end: usize) -> Self {
+        PacketBounds {
+            start,
+            end,
+            _packet: PhantomData,
+        }
+    }
+
+    pub fn from_slice(bytes: &'a mut [u8]) -> Self {
+        let start = bytes.as_mut_ptr() as usize;
+        unsafe { PacketBounds::new(start, start + bytes.len()) }
+    }
+
+    // a `T` at `offset`, none when it does not fit in the packet. It may not
+    // be aligned for `T`.
+    #[inline(always)]
+    pub fn ptr_at<T>(&self, offset: usize) -> Option<NonNull<T>> {
+        if self.start + offset + mem::size_of::<T>() > self.end {
+            return None;
+        }
+        NonNull::new((self.start + offset) as *mut T)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Hdr {
+    Tcp(NonNull<TcpHdr>),
+    Udp(NonNull<UdpHdr>),
+}
+
+// The tcp or udp header of a packet, checked to be within its bounds when
+// taken. Headers are read and written unaligned, they follow the 14 bytes of
+// the ethernet header.
+pub struct L4Hdr<'a> {
+    hdr: Hdr,
+    _packet: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> L4Hdr<'a> {
+    #[inline(always)]
+    pub fn tcp_at(packet: &PacketBounds<'a>, offset: usize) -> Option<Self> {
+        Some(L4Hdr {
+            hdr: Hdr::Tcp(packet.ptr_at(offset)?),
+            _packet: PhantomData,
+        })
+    }
+
+    #[inline(always)]
+    pub fn udp_at(packet: &PacketBounds<'a>, offset: usize) -> Option<Self> {
+        Some(L4Hdr {
+            hdr: Hdr::Udp(packet.ptr_at(offset)?),
+            _packet: PhantomData,
+        })
+    }
+
+    pub fn is_tcp(&self) -> bool {
+        matches!(self.hdr, Hdr::Tcp(_))
+    }
+
+    // a copy of the tcp header, none for udp
+    #[inline(always)]
+    pub fn tcp_hdr(&self) -> Option<TcpHdr> {
+        match self.hdr {
+            Hdr::Tcp(hdr) => Some(unsafe { hdr.as_ptr().read_unaligned() }),
+            Hdr::Udp(_) => None,
+        }
+    }
+
+    // up to the payload, with the tcp options
+    pub fn header_len(&self) -> usize {
+        match self.tcp_hdr() {
+            Some(hdr) => hdr.doff() as usize * 4,
+            None => UdpHdr::LEN,
+        }
+    }
+
+    pub fn get_check(&self) -> u16 {
+        match self.hdr {
+            Hdr::Tcp(hdr) => unsafe { hdr.as_ptr().read_unaligned().check },
+            Hdr::Udp(hdr) => unsafe { hdr.as_ptr().read_unaligned().check },
+        }
+    }
+
+    pub fn set_check(&mut self, new_csum: u16) {
+        match self.hdr {
+            Hdr::Tcp(hdr) => unsafe {
+                addr_of_mut!((*hdr.as_ptr()).check).write_unaligned(new_csum)
+            },
+            Hdr::Udp(hdr) => unsafe {
+                addr_of_mut!((*hdr.as_ptr()).check).write_unaligned(new_csum)
+            },
+        }
+    }
+
+    pub fn get_source(&self) -> u16 {
+        match self.hdr {
+            Hdr::Tcp(hdr) => unsafe { hdr.as_ptr().read_unaligned().source },
+            Hdr::Udp(hdr) => unsafe { hdr.as_ptr().read_unaligned().source },
+        }
+    }
+
+    pub fn get_dest(&self) -> u16 {
+        match self.hdr {
+            Hdr::Tcp(hdr) => unsafe { hdr.as_ptr().read_unaligned().dest },
+            Hdr::Udp(hdr) => unsafe { hdr.as_ptr().read_unaligned().dest },
+        }
+    }
+
+    pub fn set_bi_port(&mut self, bi_port: &BiPort) {
+        let (src, dst) = bi_port.split_net();
+        match self.hdr {
+            Hdr::Tcp(hdr) => unsafe {
+                addr_of_mut!((*hdr.as_ptr()).source).write_unaligned(src);
+                addr_of_mut!((*hdr.as_ptr()).dest).write_unaligned(dst);
+            },
+            Hdr::Udp(hdr) => unsafe {
+                addr_of_mut!((*hdr.as_ptr()).source).write_unaligned(src);
+                addr_of_mut!((*hdr.as_ptr()).dest).write_unaligned(dst);
+            },
+        }
+    }
+
+    pub fn is_fin(&self) -> bool {
+        self.tcp_hdr().is_some_and(|hdr| hdr.fin() != 0)
+    }
+
+    pub fn is_syn(&self) -> bool {
+        self.tcp_hdr().is_some_and(|hdr| hdr.syn() != 0)
+    }
+
+    pub fn is_ack(&self) -> bool {
+        self.tcp_hdr().is_some_and(|hdr| hdr.ack() != 0)
+    }
+
+    pub fn is_rst(&self) -> bool {
+        self.tcp_hdr().is_some_and(|hdr| hdr.rst() != 0)
+    }
+
+    // seq and ack_seq in host byte order, 0 for udp
+    pub fn get_seq(&self) -> u32 {
+        self.tcp_hdr().map_or(0, |hdr| u32::from_be(hdr.seq))
+    }
+
+    pub fn get_ack_seq(&self) -> u32 {
+        self.tcp_hdr().map_or(0, |hdr| u32::from_be(hdr.ack_seq))
+    }
+
+    pub fn set_seq(&mut self, seq: u32) {
+        if let Hdr::Tcp(hdr) = self.hdr {
+            unsafe { addr_of_mut!((*hdr.as_ptr()).seq).write_unaligned(seq.to_be()) }
+        }
+    }
+
+    pub fn set_ack_seq(&mut self, ack_seq: u32) {
+        if let Hdr::Tcp(hdr) = self.hdr {
+            unsafe { addr_of_mut!((*hdr.as_ptr()).ack_seq).write_unaligned(ack_seq.to_be()) }
+        }
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_tcp_hdr() {
+        use super::{L4Hdr, PacketBounds};
+        use crate::BiPort;
+
+        // a byte in front, so the header is not aligned
+        let mut bytes = [0u8; 21];
+        bytes[1..3].copy_from_slice(&1000u16.to_be_bytes());
+        bytes[3..5].copy_from_slice(&80u16.to_be_bytes());
+        bytes[5..9].copy_from_slice(&7u32.to_be_bytes());
+        // data offset of 5 words, syn and ack
+        bytes[13] = 0x50;
+        bytes[14] = 0x12;
+
+        let packet = PacketBounds::from_slice(&mut bytes);
+        let mut hdr = L4Hdr::tcp_at(&packet, 1).unwrap();
+        assert!(hdr.is_tcp());
+        assert!(hdr.is_syn() && hdr.is_ack() && !hdr.is_fin() && !hdr.is_rst());
+        assert_eq!(hdr.get_seq(), 7);
+        assert_eq!(hdr.header_len(), 20);
+        assert_eq!(u16::from_be(hdr.get_source()), 1000);
+
+        hdr.set_seq(8);
+        hdr.set_bi_port(&BiPort::new(2000u16.to_be(), 443u16.to_be()));
+        assert_eq!(hdr.get_seq(), 8);
+        assert_eq!(u16::from_be(hdr.get_dest()), 443);
+
+        // past the end of the packet
+        assert!(L4Hdr::tcp_at(&packet, 2).is_none());
+        assert!(L4Hdr::udp_at(&packet, 13).is_some());
+        assert!(L4Hdr::udp_at(&packet, 14).is_none());
+    }
+}
@@ -0,0 +1,51 @@
+use crate::KEndpoint;
+
+// xdp processing time is kept in power of two buckets, bucket i holding the
+// samples of [2^i, 2^(i+1)) ns
+pub const LATENCY_BUCKETS: u32 = 32;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct KLatencyKey {
+    pub ifindex: u32,
+    pub bucket: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KLatencyKey {}
+
+// the time from the syn of a client to the syn-ack of its backend, in the
+// same buckets
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct KHandshakeKey {
+    pub backend: KEndpoint,
+    pub bucket: u32,
+    pub _pad: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KHandshakeKey {}
+
+#[inline(always)]
+pub fn latency_bucket(ns: u64) -> u32 {
+    if ns == 0 {
+        return 0;
+    }
+    (63 - ns.leading_zeros()).min(LATENCY_BUCKETS - 1)
+}
+
+mod test {
+
+    #[test]
+    fn test_latency_bucket() {
+        use super::{latency_bucket, LATENCY_BUCKETS};
+
+        assert_eq!(latency_bucket(0), 0);
+        assert_eq!(latency_bucket(1), 0);
+        assert_eq!(latency_bucket(2), 1);
+        assert_eq!(latency_bucket(1023), 9);
+        assert_eq!(latency_bucket(1024), 10);
+        assert_eq!(latency_bucket(u64::MAX), LATENCY_BUCKETS - 1);
+    }
+}
@@ -1,89 +1,41 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 use byteorder::{BigEndian, ByteOrder};
 use event::Event;
-use network_types::{tcp::TcpHdr, udp::UdpHdr};
 
+pub mod acl;
+pub mod affinity;
+pub mod config;
+pub mod conn_limit;
+pub mod egress;
 pub mod event;
+pub mod flow;
+pub mod l4;
+pub mod latency;
+pub mod load;
 pub mod maps;
+pub mod nat;
+pub mod pcap;
+pub mod ports;
+pub mod proxy_v2;
 pub mod queue;
+pub mod sample;
+pub mod service;
+pub mod split;
+pub mod stats;
+pub mod syncookie;
+#[cfg(feature = "std")]
+pub mod text;
+pub mod toa;
+pub mod xsk;
+
+pub use l4::{L4Hdr, PacketBounds};
 
 pub const PORTS_QUEUE_SIZE: u32 = 50000;
 
-pub enum L4Hdr {
-    TcpHdr(*mut TcpHdr),
-    UdpHdr(*mut UdpHdr),
-}
-
-impl From<*mut TcpHdr> for L4Hdr {
-    fn from(value: *mut TcpHdr) -> Self {
-        Self::TcpHdr(value)
-    }
-}
-
-impl From<*mut UdpHdr> for L4Hdr {
-    fn from(value: *mut UdpHdr) -> Self {
-        Self::UdpHdr(value)
-    }
-}
-
-impl L4Hdr {
-    pub fn get_check(&self) -> u16 {
-        match self {
-            L4Hdr::TcpHdr(hdr) => unsafe { (**hdr).check },
-            L4Hdr::UdpHdr(hdr) => unsafe { (**hdr).check },
-        }
-    }
-
-    pub fn set_check(&self, new_csum: u16) {
-        match self {
-            L4Hdr::TcpHdr(hdr) => unsafe { (**hdr).check = new_csum },
-            L4Hdr::UdpHdr(hdr) => unsafe { (**hdr).check = new_csum },
-        }
-    }
-
-    pub fn get_source(&self) -> u16 {
-        match self {
-            L4Hdr::TcpHdr(hdr) => unsafe { (**hdr).source },
-            L4Hdr::UdpHdr(hdr) => unsafe { (**hdr).source },
-        }
-    }
-
-    pub fn get_dest(&self) -> u16 {
-        match self {
-            L4Hdr::TcpHdr(hdr) => unsafe { (**hdr).dest },
-            L4Hdr::UdpHdr(hdr) => unsafe { (**hdr).dest },
-        }
-    }
-
-    pub fn set_bi_port(&self, bi_port: &BiPort) {
-        let (src, dst) = bi_port.split_net();
-        match self {
-            L4Hdr::TcpHdr(hdr) => unsafe {
-                (**hdr).source = src;
-                (**hdr).dest = dst;
-            },
-            L4Hdr::UdpHdr(hdr) => unsafe {
-                (**hdr).source = src;
-                (**hdr).dest = dst;
-            },
-        }
-    }
-
-    pub fn inner_tcp_ptr(&self) -> Option<*mut TcpHdr> {
-        match self {
-            L4Hdr::TcpHdr(hdr) => Some(*hdr),
-            _ => None,
-        }
-    }
-
-    pub fn is_fin(&self) -> bool {
-        match self {
-            L4Hdr::TcpHdr(hdr) => unsafe { (**hdr).fin() != 0 },
-            _ => false,
-        }
-    }
-}
+// Bumped whenever the key or the value of a pinned map changes. A datapath is
+// only taken over by a folonet pinning the same version in MAP_SCHEMA.
+pub const MAP_SCHEMA_VERSION: u32 = 1;
 
 #[derive(Debug, Clone, Copy)]
 pub struct BiPort(u32);
@@ -105,12 +57,17 @@ impl Into<u32> for BiPort {
 }
 
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "std", derive(serde::Serialize, serde::Deserialize))]
 pub struct KConnection {
     pub from: KEndpoint,
     pub to: KEndpoint,
 }
 
 impl KConnection {
+    pub fn from_bytes(bs: &[u8]) -> Self {
+        unsafe { *core::mem::transmute::<*const u8, *const KConnection>(bs.as_ptr()) }.clone()
+    }
+
     pub fn reverse(&self) -> Self {
         KConnection {
             from: self.to,
@@ -202,18 +159,113 @@ impl Into<[u8; 6]> for Mac {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "std", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
 pub struct Notification {
     pub local_in_endpoint: KEndpoint,
     pub lcoal_out_endpoint: KEndpoint,
     pub connection: KConnection,
+    // of the service when the packet was seen, see SERVICE_EPOCH
+    pub epoch: u32,
     pub event: Event,
 }
 
-pub const NOTIFICATION_SIZE: usize = core::mem::size_of::<Notification>();
+// a client reached a service that has no backend yet
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KColdStart {
+    pub way: KConnection,
+    pub is_tcp: u8,
+    pub _pad: [u8; 7],
+}
 
-impl Notification {
+impl KColdStart {
     pub fn from_bytes(bs: &[u8]) -> Self {
-        unsafe { *core::mem::transmute::<*const u8, *const Notification>(bs.as_ptr()) }.clone()
+        unsafe { *core::mem::transmute::<*const u8, *const KColdStart>(bs.as_ptr()) }.clone()
+    }
+}
+
+// bumped whenever the layout of a notification changes, userspace drops
+// frames of any other version
+pub const NOTIFICATION_VERSION: u8 = 1;
+
+// a notification as it goes through the ring buffer
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct NotificationFrame {
+    pub version: u8,
+    pub _pad: [u8; 7],
+    pub notification: Notification,
+}
+
+impl NotificationFrame {
+    pub fn new(notification: Notification) -> Self {
+        NotificationFrame {
+            version: NOTIFICATION_VERSION,
+            _pad: [0; 7],
+            notification,
+        }
+    }
+}
+
+pub const NOTIFICATION_SIZE: usize = core::mem::size_of::<NotificationFrame>();
+
+// where the tag of the event is, see the repr of Event
+const EVENT_TAG_OFFSET: usize = core::mem::offset_of!(NotificationFrame, notification)
+    + core::mem::offset_of!(Notification, event);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    // fewer bytes than NOTIFICATION_SIZE
+    Short(usize),
+    Version(u8),
+    EventType(u8),
+}
+
+impl core::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FrameError::Short(len) => write!(
+                f,
+                "notification of {} bytes, expected {}",
+                len, NOTIFICATION_SIZE
+            ),
+            FrameError::Version(v) => write!(
+                f,
+                "notification version {}, expected {}",
+                v, NOTIFICATION_VERSION
+            ),
+            FrameError::EventType(t) => write!(f, "unknown event type {}", t),
+        }
+    }
+}
+
+impl Notification {
+    // the bytes of a frame may come at any alignment and be cut short
+    pub fn parse(bs: &[u8]) -> Result<Self, FrameError> {
+        if bs.len() < NOTIFICATION_SIZE {
+            return Err(FrameError::Short(bs.len()));
+        }
+        if bs[0] != NOTIFICATION_VERSION {
+            return Err(FrameError::Version(bs[0]));
+        }
+        // any other tag is no event at all
+        let tag = bs[EVENT_TAG_OFFSET];
+        if tag > Event::MAX_TAG {
+            return Err(FrameError::EventType(tag));
+        }
+        let frame = unsafe { core::ptr::read_unaligned(bs.as_ptr() as *const NotificationFrame) };
+        Ok(frame.notification)
+    }
+
+    // write the frame of this notification to the start of `buf`, returns its size
+    pub fn write(&self, buf: &mut [u8]) -> Result<usize, FrameError> {
+        if buf.len() < NOTIFICATION_SIZE {
+            return Err(FrameError::Short(buf.len()));
+        }
+        let frame = NotificationFrame::new(*self);
+        unsafe { core::ptr::write_unaligned(buf.as_mut_ptr() as *mut NotificationFrame, frame) };
+        Ok(NOTIFICATION_SIZE)
     }
 
     pub fn is_tcp(&self) -> bool {
@@ -278,7 +330,7 @@ mod test {
     fn test_notification_write_read_bytes() {
         use crate::{
             event::{Event, Packet, PacketFlag},
-            KConnection, KEndpoint, Notification,
+            KConnection, KEndpoint, Notification, NOTIFICATION_SIZE,
         };
 
         let ip = build_ip_u32(192, 168, 174, 140);
@@ -299,22 +351,29 @@ mod test {
             local_in_endpoint: endpoint,
             lcoal_out_endpoint: endpoint,
             connection,
+            epoch: 3,
             event: Event::TcpPacket(packet),
         };
 
-        let p = &notification as *const Notification;
+        // one byte more, to read it from an odd address as well
+        let mut buffer = [0; NOTIFICATION_SIZE + 1];
+        assert_eq!(notification.write(&mut buffer), Ok(NOTIFICATION_SIZE));
+        assert_eq!(Notification::parse(&buffer), Ok(notification));
 
-        const SIZE: usize = core::mem::size_of::<Notification>();
-
-        let mut buffer = [0; SIZE];
-        unsafe {
-            core::ptr::copy_nonoverlapping(p as *const u8, buffer.as_mut_ptr(), SIZE);
-        }
+        assert_eq!(notification.write(&mut buffer[1..]), Ok(NOTIFICATION_SIZE));
+        assert_eq!(Notification::parse(&buffer[1..]), Ok(notification));
+    }
 
-        let bs: &[u8] = &buffer[..];
+    #[test]
+    fn test_notification_parse_errors() {
+        use crate::{FrameError, Notification, NOTIFICATION_SIZE, NOTIFICATION_VERSION};
 
-        let got_notification = Notification::from_bytes(bs);
+        let mut buffer = [0; NOTIFICATION_SIZE];
+        assert_eq!(Notification::parse(&buffer[..8]), Err(FrameError::Short(8)));
+        assert_eq!(Notification::parse(&buffer), Err(FrameError::Version(0)));
 
-        assert_eq!(notification, got_notification);
+        buffer[0] = NOTIFICATION_VERSION;
+        buffer[super::EVENT_TAG_OFFSET] = 7;
+        assert_eq!(Notification::parse(&buffer), Err(FrameError::EventType(7)));
     }
 }
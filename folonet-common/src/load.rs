@@ -0,0 +1,12 @@
+// per service load, keyed by the service endpoint and kept per cpu
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KServiceLoad {
+    // connections the xdp program opened towards a backend of the service
+    pub conns_opened: u64,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KServiceLoad {}
@@ -0,0 +1,178 @@
+use crate::KConnection;
+
+// where the mac addresses of a rewritten packet come from
+//
+// our own mac as source, the known mac of the new destination ip
+pub const MAC_POLICY_LOOKUP: u32 = 0;
+// straight back to the mac the packet came from, for hairpinned clients
+pub const MAC_POLICY_BOUNCE: u32 = 1;
+// the ethernet header is left alone, e.g. direct server return
+pub const MAC_POLICY_KEEP: u32 = 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KRewrite {
+    // the addresses and ports the packet leaves with
+    pub way: KConnection,
+    pub mac_policy: u32,
+    pub _pad: u32,
+}
+
+impl KRewrite {
+    pub fn new(way: KConnection, mac_policy: u32) -> Self {
+        KRewrite {
+            way,
+            mac_policy,
+            _pad: 0,
+        }
+    }
+}
+
+// The state the xdp program keeps of a connection, next to the full tcp state
+// machines userspace runs from the packet events. It is enough to drop what
+// comes after a connection closed.
+//
+// only the client sent so far
+pub const CT_NEW: u8 = 0;
+// the backend answered
+pub const CT_ESTABLISHED: u8 = 1;
+// one side sent a fin
+pub const CT_FIN_SEEN: u8 = 2;
+// both sides sent a fin, or one a reset
+pub const CT_CLOSED: u8 = 3;
+
+// the fins of a connection, as seen from the direction of one entry
+pub const CT_FIN_SENT: u8 = 1;
+pub const CT_FIN_RECEIVED: u8 = 1 << 1;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KConnTrack {
+    pub state: u8,
+    pub fins: u8,
+    // set in the entry of the backend -> local direction
+    pub reply: u8,
+    pub _pad: [u8; 5],
+}
+
+impl KConnTrack {
+    // the same state seen from the other direction
+    pub fn mirror(&self) -> Self {
+        KConnTrack {
+            state: self.state,
+            fins: (self.fins & CT_FIN_SENT) << 1 | (self.fins & CT_FIN_RECEIVED) >> 1,
+            reply: 1 - self.reply,
+            _pad: [0; 5],
+        }
+    }
+}
+
+// Value of the CONNECTION map. A connection has one entry per direction, each
+// holding the rewrite of its own packets and the key and rewrite of the other
+// direction. The return path is not always the reverse of the forward
+// rewrite, so it is never derived from it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KNat {
+    pub fwd: KRewrite,
+    pub rev_key: KConnection,
+    pub rev: KRewrite,
+    // the same in both entries, but for the direction
+    pub ct: KConnTrack,
+}
+
+impl KNat {
+    // the classic full nat: the backend answers to where the request left from
+    pub fn full_nat(declare_way: &KConnection, out_way: &KConnection) -> Self {
+        KNat {
+            fwd: KRewrite::new(*out_way, MAC_POLICY_LOOKUP),
+            rev_key: out_way.reverse(),
+            rev: KRewrite::new(declare_way.reverse(), MAC_POLICY_LOOKUP),
+            ct: KConnTrack::default(),
+        }
+    }
+
+    // A client routed to a backend on its own ip, e.g. a backend calling its
+    // own service. Both ways go back to the mac the packet came from, the
+    // client and the backend are one host.
+    pub fn hairpin(declare_way: &KConnection, out_way: &KConnection) -> Self {
+        KNat {
+            fwd: KRewrite::new(*out_way, MAC_POLICY_BOUNCE),
+            rev_key: out_way.reverse(),
+            rev: KRewrite::new(declare_way.reverse(), MAC_POLICY_BOUNCE),
+            ct: KConnTrack::default(),
+        }
+    }
+
+    // the entry of the other direction, `key` being the one of this entry
+    pub fn mirror(&self, key: &KConnection) -> Self {
+        KNat {
+            fwd: self.rev,
+            rev_key: *key,
+            rev: self.fwd,
+            ct: self.ct.mirror(),
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KNat {}
+
+mod test {
+
+    #[test]
+    fn test_full_nat_mirror() {
+        use crate::{
+            nat::{KNat, MAC_POLICY_BOUNCE},
+            KConnection, KEndpoint,
+        };
+
+        let client = KEndpoint::new(1, 40000);
+        let service = KEndpoint::new(2, 80);
+        let local = KEndpoint::new(3, 10000);
+        let backend = KEndpoint::new(4, 8080);
+
+        let declare_way = KConnection {
+            from: client,
+            to: service,
+        };
+        let out_way = KConnection {
+            from: local,
+            to: backend,
+        };
+
+        let nat = KNat::full_nat(&declare_way, &out_way);
+        assert_eq!(nat.rev_key, out_way.reverse());
+
+        let back = nat.mirror(&declare_way);
+        assert_eq!(back.fwd.way, declare_way.reverse());
+        assert_eq!(back.rev_key, declare_way);
+        assert_eq!(back.mirror(&nat.rev_key), nat);
+        assert_eq!(back.ct.reply, 1);
+
+        // the client is the backend
+        let declare_way = KConnection {
+            from: KEndpoint::new(4, 40000),
+            to: service,
+        };
+        let nat = KNat::hairpin(&declare_way, &out_way);
+        assert_eq!(nat.fwd.mac_policy, MAC_POLICY_BOUNCE);
+        assert_eq!(nat.mirror(&declare_way).fwd.mac_policy, MAC_POLICY_BOUNCE);
+    }
+
+    #[test]
+    fn test_conntrack_mirror() {
+        use crate::nat::{KConnTrack, CT_FIN_RECEIVED, CT_FIN_SEEN, CT_FIN_SENT};
+
+        let ct = KConnTrack {
+            state: CT_FIN_SEEN,
+            fins: CT_FIN_SENT,
+            ..Default::default()
+        };
+        let back = ct.mirror();
+        assert_eq!(back.state, CT_FIN_SEEN);
+        assert_eq!(back.fins, CT_FIN_RECEIVED);
+        assert_eq!(back.reply, 1);
+        assert_eq!(back.mirror(), ct);
+    }
+}
@@ -0,0 +1,46 @@
+use crate::KEndpoint;
+
+// bytes of a packet kept, from the ethernet header on
+pub const PCAP_SNAPLEN: usize = 128;
+
+// where in the xdp program a packet was copied
+pub const PCAP_BEFORE: u8 = 0;
+pub const PCAP_AFTER: u8 = 1;
+
+// the packets of `service` copied to PCAP_RING, one in `one_in` of them, 0
+// for none
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KPcapFilter {
+    pub service: KEndpoint,
+    pub one_in: u32,
+    pub _pad: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KPcapFilter {}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KPcapRecord {
+    // bpf_ktime_get_ns
+    pub ts_ns: u64,
+    pub ifindex: u32,
+    // of the whole packet
+    pub len: u32,
+    // bytes of `data` that are set
+    pub cap_len: u32,
+    pub stage: u8,
+    pub _pad: [u8; 3],
+    pub data: [u8; PCAP_SNAPLEN],
+}
+
+impl KPcapRecord {
+    pub fn from_bytes(bs: &[u8]) -> Self {
+        unsafe { *core::mem::transmute::<*const u8, *const KPcapRecord>(bs.as_ptr()) }.clone()
+    }
+
+    pub fn packet(&self) -> &[u8] {
+        &self.data[..(self.cap_len as usize).min(PCAP_SNAPLEN)]
+    }
+}
@@ -0,0 +1,14 @@
+// the share of SERVICE_PORTS one service may hold, keyed by the service
+// endpoint and written by userspace only
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KPortQuota {
+    // ports the service may hold at once
+    pub limit: u64,
+    // ports of the service userspace gave back so far, the xdp program counts
+    // the ones it took in PORT_TAKEN_MAP
+    pub released: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KPortQuota {}
@@ -0,0 +1,125 @@
+use crate::KConnection;
+
+// The PROXY protocol v2 header of an ipv4 tcp connection: the 16 byte
+// preamble, then the addresses and ports of the client and of the service.
+pub const PROXY_V2_LEN: usize = 28;
+
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// version 2, the PROXY command
+const VERSION_COMMAND: u8 = 0x21;
+// AF_INET over SOCK_STREAM
+const FAMILY_TCP4: u8 = 0x11;
+const ADDRESSES_LEN: u16 = 12;
+
+// no data of the client reached the backend yet
+pub const PROXY_V2_PENDING: u8 = 0;
+// the header went in front of the client data starting at `data_seq`
+pub const PROXY_V2_SENT: u8 = 1;
+
+// State of a connection whose backend is told the client address in a PROXY
+// protocol v2 header, keyed by the client -> service way. Every client byte
+// after the header is PROXY_V2_LEN further in the stream of the backend.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KProxyV2 {
+    // the seq of the first byte the client sent, host byte order
+    pub data_seq: u32,
+    pub state: u8,
+    pub _pad: [u8; 3],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KProxyV2 {}
+
+impl KProxyV2 {
+    // whether a client packet starting at `seq` with `payload_len` bytes
+    // takes the header, again for a retransmit of the first data
+    #[inline(always)]
+    pub fn takes_header(&self, seq: u32, payload_len: usize) -> bool {
+        payload_len > 0 && (self.state == PROXY_V2_PENDING || seq == self.data_seq)
+    }
+
+    // the seq of a client packet in the stream of the backend
+    #[inline(always)]
+    pub fn to_backend_seq(&self, seq: u32) -> u32 {
+        if self.state == PROXY_V2_SENT && (seq.wrapping_sub(self.data_seq) as i32) > 0 {
+            seq.wrapping_add(PROXY_V2_LEN as u32)
+        } else {
+            seq
+        }
+    }
+
+    // the ack of a backend packet in the stream of the client, an ack of
+    // part of the header acks none of the client data
+    #[inline(always)]
+    pub fn to_client_ack(&self, ack_seq: u32) -> u32 {
+        if self.state != PROXY_V2_SENT {
+            return ack_seq;
+        }
+        let acked = ack_seq.wrapping_sub(self.data_seq) as i32;
+        if acked >= PROXY_V2_LEN as i32 {
+            ack_seq.wrapping_sub(PROXY_V2_LEN as u32)
+        } else if acked > 0 {
+            self.data_seq
+        } else {
+            ack_seq
+        }
+    }
+}
+
+// the header for a client connected over `way`, client -> service
+#[inline(always)]
+pub fn proxy_v2_header(way: &KConnection) -> [u8; PROXY_V2_LEN] {
+    let mut header = [0u8; PROXY_V2_LEN];
+    header[..12].copy_from_slice(&SIGNATURE);
+    header[12] = VERSION_COMMAND;
+    header[13] = FAMILY_TCP4;
+    header[14..16].copy_from_slice(&ADDRESSES_LEN.to_be_bytes());
+    // the endpoints hold them in network byte order already
+    header[16..20].copy_from_slice(&way.from.ip().to_ne_bytes());
+    header[20..24].copy_from_slice(&way.to.ip().to_ne_bytes());
+    header[24..26].copy_from_slice(&way.from.port().to_ne_bytes());
+    header[26..28].copy_from_slice(&way.to.port().to_ne_bytes());
+    header
+}
+
+mod test {
+
+    #[test]
+    fn test_proxy_v2() {
+        use super::{proxy_v2_header, KProxyV2, PROXY_V2_LEN, PROXY_V2_SENT};
+        use crate::{KConnection, KEndpoint};
+
+        let way = KConnection {
+            from: KEndpoint::new(u32::from_ne_bytes([10, 0, 0, 2]), 40000u16.to_be()),
+            to: KEndpoint::new(u32::from_ne_bytes([10, 0, 0, 1]), 80u16.to_be()),
+        };
+        let header = proxy_v2_header(&way);
+        assert_eq!(&header[..12], b"\r\n\r\n\0\r\nQUIT\n");
+        assert_eq!(&header[12..16], &[0x21, 0x11, 0, 12]);
+        assert_eq!(&header[16..24], &[10, 0, 0, 2, 10, 0, 0, 1]);
+        assert_eq!(&header[24..], &[0x9c, 0x40, 0, 80]);
+
+        let pending = KProxyV2 {
+            data_seq: 0,
+            ..Default::default()
+        };
+        assert!(!pending.takes_header(1000, 0));
+        assert!(pending.takes_header(1000, 10));
+        assert_eq!(pending.to_backend_seq(1010), 1010);
+
+        // across the wrap of the seq numbers
+        let sent = KProxyV2 {
+            data_seq: u32::MAX - 4,
+            state: PROXY_V2_SENT,
+            _pad: [0; 3],
+        };
+        assert!(sent.takes_header(u32::MAX - 4, 10));
+        assert!(!sent.takes_header(5, 10));
+        assert_eq!(sent.to_backend_seq(u32::MAX - 4), u32::MAX - 4);
+        assert_eq!(sent.to_backend_seq(5), 5 + PROXY_V2_LEN as u32);
+        assert_eq!(sent.to_client_ack(u32::MAX - 4), u32::MAX - 4);
+        assert_eq!(sent.to_client_ack(10), u32::MAX - 4);
+        assert_eq!(sent.to_client_ack(33), 33 - PROXY_V2_LEN as u32);
+    }
+}
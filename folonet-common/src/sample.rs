@@ -0,0 +1,26 @@
+use crate::KConnection;
+
+pub const PAYLOAD_SAMPLE_LEN: usize = 16;
+
+// the first bytes of the first client packet carrying data, for telling
+// application protocols apart
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KPayloadSample {
+    // client -> local_in
+    pub way: KConnection,
+    // bytes of `data` that are set
+    pub len: u32,
+    pub _pad: u32,
+    pub data: [u8; PAYLOAD_SAMPLE_LEN],
+}
+
+impl KPayloadSample {
+    pub fn from_bytes(bs: &[u8]) -> Self {
+        unsafe { *core::mem::transmute::<*const u8, *const KPayloadSample>(bs.as_ptr()) }.clone()
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.data[..(self.len as usize).min(PAYLOAD_SAMPLE_LEN)]
+    }
+}
@@ -0,0 +1,32 @@
+use crate::KEndpoint;
+
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+
+// A service may be served on the same endpoint over tcp and udp, each with a
+// SERVER_MAP entry of its own under this key.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct KServiceKey {
+    pub endpoint: KEndpoint,
+    pub proto: u8,
+    pub _pad: [u8; 7],
+}
+
+impl KServiceKey {
+    #[inline(always)]
+    pub fn new(endpoint: KEndpoint, is_tcp: bool) -> Self {
+        KServiceKey {
+            endpoint,
+            proto: if is_tcp { PROTO_TCP } else { PROTO_UDP },
+            _pad: [0; 7],
+        }
+    }
+
+    pub fn is_tcp(&self) -> bool {
+        self.proto == PROTO_TCP
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KServiceKey {}
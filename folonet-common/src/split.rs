@@ -0,0 +1,77 @@
+use crate::{syncookie::mix, KConnection, KEndpoint};
+
+// the servers of a service and the pools next to them
+pub const MAX_POOLS: usize = 4;
+
+// Value of SPLIT_MAP: the backend of every pool of a service, the first the
+// one of its servers, and the share of the new connections each one gets.
+// Unused pools have no weight.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KSplit {
+    pub backends: [KEndpoint; MAX_POOLS],
+    pub weights: [u32; MAX_POOLS],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KSplit {}
+
+impl KSplit {
+    // the pool of a connection hashed to `hash`, none when no pool has weight
+    #[inline(always)]
+    pub fn pick(&self, hash: u32) -> Option<usize> {
+        let mut total = 0u32;
+        for weight in self.weights {
+            total = total.saturating_add(weight);
+        }
+        if total == 0 {
+            return None;
+        }
+        let mut point = hash % total;
+        for (pool, weight) in self.weights.iter().enumerate() {
+            if point < *weight {
+                return Some(pool);
+            }
+            point -= weight;
+        }
+        None
+    }
+}
+
+// the same for every packet of a connection
+#[inline(always)]
+pub fn conn_hash(way: &KConnection) -> u32 {
+    let h = mix(way.from.ip());
+    mix(h ^ ((way.from.port() as u32) << 16 | way.to.port() as u32))
+}
+
+mod test {
+
+    #[test]
+    fn test_pick() {
+        use super::{conn_hash, KSplit};
+        use crate::{KConnection, KEndpoint};
+
+        let split = KSplit {
+            weights: [95, 5, 0, 0],
+            ..Default::default()
+        };
+        assert_eq!(split.pick(0), Some(0));
+        assert_eq!(split.pick(94), Some(0));
+        assert_eq!(split.pick(95), Some(1));
+        assert_eq!(split.pick(199), Some(0));
+        assert_eq!(KSplit::default().pick(7), None);
+
+        // about the weights over many clients
+        let canary = (0..10000u16)
+            .filter(|port| {
+                let way = KConnection {
+                    from: KEndpoint::new(0x0a00_0002, *port),
+                    to: KEndpoint::new(0x0a00_0001, 80),
+                };
+                split.pick(conn_hash(&way)) == Some(1)
+            })
+            .count();
+        assert!((300..700).contains(&canary), "{}", canary);
+    }
+}
@@ -0,0 +1,160 @@
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Counter {
+    SynFloodDropped = 0,
+    SynCookieSent = 1,
+    SynCookieValid = 2,
+    SynCookieInvalid = 3,
+    AclDenied = 4,
+    HandshakeHeld = 5,
+    HandshakeSpliced = 6,
+    HandshakeHoldExpired = 7,
+    PortAllocated = 8,
+    PortExhausted = 9,
+    DrainingDropped = 10,
+    IcmpTranslated = 11,
+    FragFirst = 12,
+    FragPassed = 13,
+    FragDropped = 14,
+    Blocked = 15,
+    UnknownConnection = 16,
+    // the packet event ring buffer was full, the state machines miss the packet
+    PacketEventLost = 17,
+    ColdStartEventLost = 18,
+    // nat entries of an open connection evicted from the lru CONNECTION map
+    ConnectionEvicted = 19,
+    // the service held every port of its quota
+    PortQuotaExceeded = 20,
+    // a packet of a connection the xdp program saw closing
+    ClosedConnDropped = 21,
+    // a backend started a connection to somewhere else than a service
+    BackendEgressBlocked = 22,
+    BackendEgressMasqueraded = 23,
+    // the service had all the connections it may have open
+    ConnLimitExceeded = 24,
+    // a reset or port unreachable sent instead of a drop
+    Rejected = 25,
+    // a packet of a flow handed to the userspace fallback proxy
+    FallenBack = 26,
+    // a packet handed to the af_xdp worker to be rewritten
+    XskRedirected = 27,
+    // a retransmitted syn of a client whose cold start is reported already
+    ColdStartDeduped = 28,
+    // a connection of a backend to its own service, see KNat::hairpin
+    Hairpinned = 29,
+    // the first data of a client, with a PROXY protocol v2 header put in front
+    ProxyHeaderSent = 30,
+    // a syn toward a backend, with a TOA option carrying the client address
+    ToaSent = 31,
+}
+
+pub const COUNTER_NUM: u32 = 32;
+
+impl Counter {
+    pub const ALL: [Counter; COUNTER_NUM as usize] = [
+        Counter::SynFloodDropped,
+        Counter::SynCookieSent,
+        Counter::SynCookieValid,
+        Counter::SynCookieInvalid,
+        Counter::AclDenied,
+        Counter::HandshakeHeld,
+        Counter::HandshakeSpliced,
+        Counter::HandshakeHoldExpired,
+        Counter::PortAllocated,
+        Counter::PortExhausted,
+        Counter::DrainingDropped,
+        Counter::IcmpTranslated,
+        Counter::FragFirst,
+        Counter::FragPassed,
+        Counter::FragDropped,
+        Counter::Blocked,
+        Counter::UnknownConnection,
+        Counter::PacketEventLost,
+        Counter::ColdStartEventLost,
+        Counter::ConnectionEvicted,
+        Counter::PortQuotaExceeded,
+        Counter::ClosedConnDropped,
+        Counter::BackendEgressBlocked,
+        Counter::BackendEgressMasqueraded,
+        Counter::ConnLimitExceeded,
+        Counter::Rejected,
+        Counter::FallenBack,
+        Counter::XskRedirected,
+        Counter::ColdStartDeduped,
+        Counter::Hairpinned,
+        Counter::ProxyHeaderSent,
+        Counter::ToaSent,
+    ];
+
+    // the packet was dropped by the xdp program
+    pub fn is_drop(&self) -> bool {
+        matches!(
+            self,
+            Counter::SynFloodDropped
+                | Counter::SynCookieInvalid
+                | Counter::AclDenied
+                | Counter::PortExhausted
+                | Counter::PortQuotaExceeded
+                | Counter::ConnLimitExceeded
+                | Counter::ClosedConnDropped
+                | Counter::BackendEgressBlocked
+                | Counter::DrainingDropped
+                | Counter::FragDropped
+                | Counter::Blocked
+        )
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Counter::SynFloodDropped => "syn_flood_dropped",
+            Counter::SynCookieSent => "syn_cookie_sent",
+            Counter::SynCookieValid => "syn_cookie_valid",
+            Counter::SynCookieInvalid => "syn_cookie_invalid",
+            Counter::AclDenied => "acl_denied",
+            Counter::HandshakeHeld => "handshake_held",
+            Counter::HandshakeSpliced => "handshake_spliced",
+            Counter::HandshakeHoldExpired => "handshake_hold_expired",
+            Counter::PortAllocated => "port_allocated",
+            Counter::PortExhausted => "port_exhausted",
+            Counter::DrainingDropped => "draining_dropped",
+            Counter::IcmpTranslated => "icmp_translated",
+            Counter::FragFirst => "frag_first",
+            Counter::FragPassed => "frag_passed",
+            Counter::FragDropped => "frag_dropped",
+            Counter::Blocked => "blocked",
+            Counter::UnknownConnection => "unknown_connection",
+            Counter::PacketEventLost => "packet_event_lost",
+            Counter::ColdStartEventLost => "cold_start_event_lost",
+            Counter::ConnectionEvicted => "connection_evicted",
+            Counter::PortQuotaExceeded => "port_quota_exceeded",
+            Counter::ClosedConnDropped => "closed_conn_dropped",
+            Counter::BackendEgressBlocked => "backend_egress_blocked",
+            Counter::BackendEgressMasqueraded => "backend_egress_masqueraded",
+            Counter::ConnLimitExceeded => "conn_limit_exceeded",
+            Counter::Rejected => "rejected",
+            Counter::FallenBack => "fallen_back",
+            Counter::XskRedirected => "xsk_redirected",
+            Counter::ColdStartDeduped => "cold_start_deduped",
+            Counter::Hairpinned => "hairpinned",
+            Counter::ProxyHeaderSent => "proxy_header_sent",
+            Counter::ToaSent => "toa_sent",
+        }
+    }
+}
+
+// what the xdp program did with the packets of one interface, per cpu
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KIfaceStats {
+    pub rx: u64,
+    // of a connection folonet rewrites
+    pub matched: u64,
+    pub tx: u64,
+    pub redirected: u64,
+    pub passed: u64,
+    pub dropped: u64,
+    pub aborted: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KIfaceStats {}
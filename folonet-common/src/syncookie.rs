@@ -0,0 +1,104 @@
+use crate::KConnection;
+
+// a cookie stays valid for the current and the previous bucket
+pub const COOKIE_BUCKET_NS: u64 = 64 * 1_000_000_000;
+
+pub const SYN_PROXY_PENDING: u8 = 1;
+pub const SYN_PROXY_ESTABLISHED: u8 = 2;
+// pending, of a client held with a zero window until the backend answers
+pub const SYN_PROXY_HELD: u8 = 3;
+
+// state of a connection whose handshake was answered by the xdp program,
+// keyed by the backend -> local way of the connection
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KSynProxy {
+    // the isn we announced to the client
+    pub cookie: u32,
+    // added to the backend seq numbers on the way to the client
+    pub delta: u32,
+    pub state: u8,
+    pub _pad: [u8; 7],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KSynProxy {}
+
+// a client whose handshake was answered while its service was cold starting,
+// keyed by the client -> service way
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KHeld {
+    pub client_isn: u32,
+    // the isn we announced to the client
+    pub cookie: u32,
+    pub start_ns: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KHeld {}
+
+#[inline(always)]
+pub(crate) fn mix(mut h: u32) -> u32 {
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    h
+}
+
+#[inline(always)]
+pub fn cookie_bucket(now_ns: u64) -> u32 {
+    (now_ns / COOKIE_BUCKET_NS) as u32
+}
+
+#[inline(always)]
+pub fn syn_cookie(conn: &KConnection, secret: u32, bucket: u32) -> u32 {
+    let mut h = secret ^ bucket.wrapping_mul(0x9e37_79b1);
+    h = mix(h ^ conn.from.ip());
+    h = mix(h ^ conn.to.ip());
+    h = mix(h ^ ((conn.from.port() as u32) << 16 | conn.to.port() as u32));
+    h
+}
+
+#[inline(always)]
+pub fn check_syn_cookie(conn: &KConnection, secret: u32, now_ns: u64, cookie: u32) -> bool {
+    let bucket = cookie_bucket(now_ns);
+    syn_cookie(conn, secret, bucket) == cookie
+        || syn_cookie(conn, secret, bucket.wrapping_sub(1)) == cookie
+}
+
+mod test {
+
+    #[test]
+    fn test_syn_cookie() {
+        use super::{check_syn_cookie, cookie_bucket, syn_cookie, COOKIE_BUCKET_NS};
+        use crate::{KConnection, KEndpoint};
+
+        let conn = KConnection {
+            from: KEndpoint::new(0x0a00_0001, 40000),
+            to: KEndpoint::new(0x0a00_0002, 80),
+        };
+        let secret = 0xdead_beef;
+        let now = 10 * COOKIE_BUCKET_NS + 5;
+
+        let cookie = syn_cookie(&conn, secret, cookie_bucket(now));
+
+        assert!(check_syn_cookie(&conn, secret, now, cookie));
+        assert!(check_syn_cookie(
+            &conn,
+            secret,
+            now + COOKIE_BUCKET_NS,
+            cookie
+        ));
+        assert!(!check_syn_cookie(
+            &conn,
+            secret,
+            now + 2 * COOKIE_BUCKET_NS,
+            cookie
+        ));
+        assert!(!check_syn_cookie(&conn, secret + 1, now, cookie));
+        assert!(!check_syn_cookie(&conn.reverse(), secret, now, cookie));
+    }
+}
@@ -0,0 +1,137 @@
+// Text forms of the kernel types for userspace: endpoints read
+// `1.2.3.4:80`, connections `1.2.3.4:5000 -> 10.0.0.1:80`. Endpoints
+// serialize as that text, so logs, the cli and rpcs all show the same thing.
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{event::Event, KConnection, KEndpoint, Notification};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl KEndpoint {
+    pub fn to_socket_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(
+            Ipv4Addr::from(u32::from_be(self.ip())),
+            u16::from_be(self.port()),
+        )
+    }
+}
+
+impl From<SocketAddrV4> for KEndpoint {
+    fn from(addr: SocketAddrV4) -> Self {
+        KEndpoint::new(u32::from(*addr.ip()).to_be(), addr.port().to_be())
+    }
+}
+
+impl fmt::Display for KEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_socket_addr().fmt(f)
+    }
+}
+
+impl FromStr for KEndpoint {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<SocketAddrV4>()
+            .map(KEndpoint::from)
+            .map_err(|e| ParseError(format!("invalid endpoint {}: {}", s, e)))
+    }
+}
+
+impl Serialize for KEndpoint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for KEndpoint {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for KConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}", self.from, self.to)
+    }
+}
+
+impl FromStr for KConnection {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once("->")
+            .ok_or_else(|| ParseError(format!("invalid connection {}: no ->", s)))?;
+        Ok(KConnection {
+            from: from.trim().parse()?,
+            to: to.trim().parse()?,
+        })
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::TcpPacket(p) => write!(f, "tcp {:?} seq {} ack {}", p.flag, p.seq, p.ack_seq),
+            Event::UdpPacket(_) => f.write_str("udp"),
+        }
+    }
+}
+
+impl fmt::Display for Notification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} via {}/{} at epoch {}",
+            self.event,
+            self.connection,
+            self.local_in_endpoint,
+            self.lcoal_out_endpoint,
+            self.epoch
+        )
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_endpoint_text() {
+        use crate::KEndpoint;
+
+        let endpoint: KEndpoint = "192.168.174.140:80".parse().unwrap();
+        assert_eq!(
+            endpoint.ip(),
+            u32::from_be_bytes([192, 168, 174, 140]).to_be()
+        );
+        assert_eq!(endpoint.port(), 80u16.to_be());
+        assert_eq!(endpoint.to_string(), "192.168.174.140:80");
+        assert!("192.168.174.140".parse::<KEndpoint>().is_err());
+    }
+
+    #[test]
+    fn test_connection_text() {
+        use crate::KConnection;
+
+        let text = "1.2.3.4:5000 -> 10.0.0.1:80";
+        let conn: KConnection = text.parse().unwrap();
+        assert_eq!(conn.to_string(), text);
+        assert_eq!(conn.reverse().from, conn.to);
+        assert!("1.2.3.4:5000 10.0.0.1:80".parse::<KConnection>().is_err());
+    }
+}
@@ -0,0 +1,30 @@
+use crate::KEndpoint;
+
+// the tcp option kind the TOA kernel modules of the backends read
+pub const TCPOPT_TOA: u8 = 254;
+// kind, length, then the port and the ip of the client
+pub const TOA_LEN: usize = 8;
+
+// the TOA option carrying `client`, for the syn toward the backend
+#[inline(always)]
+pub fn toa_option(client: &KEndpoint) -> [u8; TOA_LEN] {
+    let mut option = [0u8; TOA_LEN];
+    option[0] = TCPOPT_TOA;
+    option[1] = TOA_LEN as u8;
+    // the endpoint holds them in network byte order already
+    option[2..4].copy_from_slice(&client.port().to_ne_bytes());
+    option[4..8].copy_from_slice(&client.ip().to_ne_bytes());
+    option
+}
+
+mod test {
+
+    #[test]
+    fn test_toa_option() {
+        use super::toa_option;
+        use crate::KEndpoint;
+
+        let client = KEndpoint::new(u32::from_ne_bytes([10, 0, 0, 2]), 40000u16.to_be());
+        assert_eq!(toa_option(&client), [254, 8, 0x9c, 0x40, 10, 0, 0, 2]);
+    }
+}
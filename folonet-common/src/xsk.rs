@@ -0,0 +1,20 @@
+use core::mem;
+
+use crate::KConnection;
+
+// Written by the xdp program into the metadata in front of a packet it
+// redirects to an af_xdp socket, all userspace needs to rewrite and send the
+// packet. Drivers leave room for at most 32 bytes of metadata.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KXskMeta {
+    // the addresses and ports the packet leaves with
+    pub way: KConnection,
+    pub src_mac: [u8; 6],
+    pub dst_mac: [u8; 6],
+    // leave the ethernet header alone, see MAC_POLICY_KEEP
+    pub keep_macs: u8,
+    pub _pad: [u8; 3],
+}
+
+pub const XSK_META_LEN: usize = mem::size_of::<KXskMeta>();
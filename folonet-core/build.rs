@@ -0,0 +1,20 @@
+use std::process::Command;
+
+fn main() {
+    // a commit given by the packager wins over the one of the checkout
+    println!("cargo:rerun-if-env-changed=FOLONET_GIT_COMMIT");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    if std::env::var("FOLONET_GIT_COMMIT").is_ok() {
+        return;
+    }
+
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=FOLONET_GIT_COMMIT={}", commit);
+    }
+}
@@ -0,0 +1,88 @@
+use std::net::Ipv4Addr;
+
+use anyhow::anyhow;
+use aya::maps::{lpm_trie::Key, HashMap as AyaHashMap, LpmTrie, MapData};
+use folonet_client::config::{AclAction, AclConfig};
+use folonet_common::acl::{KAclKey, ACL_ALLOW, ACL_DENY, ACL_SERVICE_PREFIX_LEN};
+
+use crate::endpoint::{Endpoint, UEndpoint};
+use crate::error::{FolonetError, MapResultExt};
+
+pub fn parse_cidr(s: &str) -> Result<(Ipv4Addr, u8), anyhow::Error> {
+    let (ip, prefix_len) = match s.split_once('/') {
+        Some((ip, prefix_len)) => (ip, prefix_len.parse::<u8>()?),
+        None => (s, 32),
+    };
+    if prefix_len > 32 {
+        return Err(anyhow!("invalid prefix length in {}", s));
+    }
+    Ok((ip.parse::<Ipv4Addr>()?, prefix_len))
+}
+
+fn action_val(action: AclAction) -> u8 {
+    match action {
+        AclAction::Allow => ACL_ALLOW,
+        AclAction::Deny => ACL_DENY,
+    }
+}
+
+pub fn load_acl(
+    acl_cfg: &[AclConfig],
+    acl_map: &mut LpmTrie<MapData, KAclKey, u8>,
+    acl_default_map: &mut AyaHashMap<MapData, UEndpoint, u8>,
+) -> Result<(), FolonetError> {
+    for service_acl in acl_cfg {
+        let service = Endpoint::from(&service_acl.local_endpoint);
+        let rules = service_acl
+            .allow
+            .iter()
+            .map(|cidr| (cidr, AclAction::Allow))
+            .chain(service_acl.deny.iter().map(|cidr| (cidr, AclAction::Deny)));
+
+        for (cidr, action) in rules {
+            let (ip, prefix_len) = parse_cidr(cidr).map_err(|e| {
+                FolonetError::Config(format!(
+                    "invalid acl entry {} for {}: {}",
+                    cidr, service_acl.local_endpoint, e
+                ))
+            })?;
+            let key = Key::new(
+                ACL_SERVICE_PREFIX_LEN + prefix_len as u32,
+                KAclKey::new(service.to_k_endpoint(), u32::from(ip).to_be()),
+            );
+            acl_map
+                .insert(&key, action_val(action), 0)
+                .map_context("ACL_MAP")?;
+        }
+
+        acl_default_map
+            .insert(
+                service.to_u_endpoint(),
+                action_val(service_acl.default_action()),
+                0,
+            )
+            .map_context("ACL_DEFAULT_MAP")?;
+    }
+    Ok(())
+}
+
+mod test {
+
+    #[test]
+    fn test_parse_cidr() {
+        use std::net::Ipv4Addr;
+
+        use super::parse_cidr;
+
+        assert_eq!(
+            parse_cidr("10.0.0.0/8").unwrap(),
+            (Ipv4Addr::new(10, 0, 0, 0), 8)
+        );
+        assert_eq!(
+            parse_cidr("192.168.1.7").unwrap(),
+            (Ipv4Addr::new(192, 168, 1, 7), 32)
+        );
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+        assert!(parse_cidr("10.0.0/8").is_err());
+    }
+}
@@ -0,0 +1,434 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use folonet_client::config::{AdminConfig, ServiceConfig};
+use folonet_common::nat::{KNat, CT_CLOSED, CT_ESTABLISHED, CT_FIN_SEEN, CT_NEW};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::time::Duration;
+
+use crate::control::Control;
+use crate::endpoint::{Endpoint, UConnection};
+use crate::engine::Installer;
+use crate::error::FolonetError;
+use crate::logging::{self, LogFilter};
+use crate::output::{render, ActionReport, MapEntry, MapReport, OutputFormat};
+use crate::pcap::{Capture, CaptureOptions};
+use crate::removal::{BpfDrainingMap, BpfServerMap};
+use crate::sequencer::BpfEpochMap;
+use crate::state::BpfConnectionMap;
+
+// a request is one line, a service config included
+const MAX_REQUEST: u64 = 64 * 1024;
+
+// the kernel maps `maps dump` knows
+pub const DUMPABLE_MAPS: &[&str] = &["connection", "server", "epoch", "draining"];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum AdminRequest {
+    ServicesList,
+    // a service with its backends, cold started ones come from the manager
+    ServicesAdd {
+        service: ServiceConfig,
+    },
+    ServicesRemove {
+        name: String,
+    },
+    // percent of the new connections of a service going to one of its pools
+    ServicesWeight {
+        name: String,
+        pool: String,
+        weight: u32,
+    },
+    ConnectionsList,
+    // the nat entries of every connection, like /proc/net/nf_conntrack
+    ConnectionsNat,
+    // of every service without one
+    ConnectionsFlush {
+        service: Option<String>,
+    },
+    PortsStatus,
+    MapsDump {
+        map: String,
+    },
+    Stats,
+    // packets the xdp program dropped, per reason
+    Drops,
+    // what became of the packets of every interface
+    Interfaces,
+    // syn to syn-ack per backend
+    Handshakes,
+    // attempts, outcomes and latency of the cold starts of every service
+    ColdStarts,
+    // packet events waiting for every service
+    Queues,
+    // a filter like RUST_LOG, e.g. `info,folonet_core::state=debug`, the
+    // current one is reported without
+    LogLevel {
+        level: Option<String>,
+    },
+    // copy the packets of a service before and after their rewrite to a
+    // pcapng file the daemon writes
+    Pcap {
+        service: String,
+        file: String,
+        one_in: u32,
+        max_packets: usize,
+        secs: u64,
+    },
+}
+
+// what an admin command sends the daemon, one json line per connection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdminCall {
+    #[serde(flatten)]
+    pub request: AdminRequest,
+    // the daemon renders the report, the cli needs no schema of its own
+    #[serde(default)]
+    pub output: OutputFormat,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminReply {
+    Output(String),
+    Error(String),
+}
+
+// send `call` to the daemon listening on `socket`, returns what it rendered
+pub async fn send(socket: &str, call: &AdminCall) -> Result<String, FolonetError> {
+    let io = |context: &str| {
+        let context = format!("{} {}", context, socket);
+        move |source| FolonetError::Io { context, source }
+    };
+    let mut stream = UnixStream::connect(socket)
+        .await
+        .map_err(io("failed to connect to the daemon at"))?;
+    let mut line = serde_json::to_string(call).map_err(|e| FolonetError::Encode(e.to_string()))?;
+    line.push('\n');
+    stream
+        .write_all(line.as_bytes())
+        .await
+        .map_err(io("failed to send to"))?;
+    let mut reply = String::new();
+    stream
+        .read_to_string(&mut reply)
+        .await
+        .map_err(io("failed to read from"))?;
+    match serde_json::from_str(&reply)
+        .map_err(|e| FolonetError::Admin(format!("malformed reply: {}", e)))?
+    {
+        AdminReply::Output(out) => Ok(out),
+        AdminReply::Error(e) => Err(FolonetError::Admin(e)),
+    }
+}
+
+pub(crate) fn way_name(way: &UConnection) -> String {
+    format!(
+        "{} -> {}",
+        way.from_endpoint().to_string(),
+        way.to_endpoint().to_string()
+    )
+}
+
+pub(crate) fn ct_name(state: u8) -> &'static str {
+    match state {
+        CT_NEW => "new",
+        CT_ESTABLISHED => "established",
+        CT_FIN_SEEN => "fin_seen",
+        CT_CLOSED => "closed",
+        _ => "unknown",
+    }
+}
+
+// Serves the admin commands on a unix socket: every connection carries one
+// request and gets one reply, both a json line.
+#[derive(Clone)]
+pub struct Admin {
+    control: Control,
+    installer: Installer,
+    connection_map: BpfConnectionMap,
+    server_map: BpfServerMap,
+    epoch_map: BpfEpochMap,
+    draining_map: BpfDrainingMap,
+    capture: Capture,
+}
+
+impl Admin {
+    pub fn new(
+        control: Control,
+        installer: Installer,
+        connection_map: BpfConnectionMap,
+        server_map: BpfServerMap,
+        epoch_map: BpfEpochMap,
+        draining_map: BpfDrainingMap,
+        capture: Capture,
+    ) -> Self {
+        Admin {
+            control,
+            installer,
+            connection_map,
+            server_map,
+            epoch_map,
+            draining_map,
+            capture,
+        }
+    }
+
+    // Listen on the socket, only the owner may connect. A socket answering
+    // is of a running daemon and left alone.
+    pub fn bind(cfg: &AdminConfig) -> Result<UnixListener, FolonetError> {
+        let failed = |source| FolonetError::Io {
+            context: format!("failed to listen on {}", cfg.socket),
+            source,
+        };
+        if std::os::unix::net::UnixStream::connect(&cfg.socket).is_ok() {
+            return Err(failed(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                "another folonet answers on it",
+            )));
+        }
+        // left behind by a daemon that did not exit cleanly
+        let _ = fs::remove_file(&cfg.socket);
+        let listener = UnixListener::bind(&cfg.socket).map_err(failed)?;
+        fs::set_permissions(&cfg.socket, fs::Permissions::from_mode(0o600)).map_err(failed)?;
+        Ok(listener)
+    }
+
+    pub async fn serve_forever(self, listener: UnixListener) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("failed to accept an admin request: {}", e);
+                    continue;
+                }
+            };
+            let admin = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = admin.answer(stream).await {
+                    debug!("failed to answer an admin request: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn answer(&self, stream: UnixStream) -> std::io::Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut line = String::new();
+        BufReader::new(read.take(MAX_REQUEST))
+            .read_line(&mut line)
+            .await?;
+        let reply = match serde_json::from_str::<AdminCall>(&line) {
+            Ok(call) => match self.handle(call.request, call.output).await {
+                Ok(out) => AdminReply::Output(out),
+                Err(e) => AdminReply::Error(e.to_string()),
+            },
+            Err(e) => AdminReply::Error(format!("malformed request: {}", e)),
+        };
+        let mut reply = serde_json::to_string(&reply).map_err(std::io::Error::other)?;
+        reply.push('\n');
+        write.write_all(reply.as_bytes()).await
+    }
+
+    async fn handle(
+        &self,
+        request: AdminRequest,
+        output: OutputFormat,
+    ) -> Result<String, FolonetError> {
+        match request {
+            AdminRequest::ServicesList => render(&self.control.services_report().await, output),
+            AdminRequest::ServicesAdd { service } => {
+                let e = self.installer.add(&service).await?;
+                let message = format!("added {} on {}", service.name, e.to_string());
+                render(&ActionReport { message }, output)
+            }
+            AdminRequest::ServicesRemove { name } => {
+                let e = self.service_endpoint(&name).await?;
+                self.control.remove_service(e).await?;
+                let message = format!("removed {}", name);
+                render(&ActionReport { message }, output)
+            }
+            AdminRequest::ServicesWeight { name, pool, weight } => {
+                let e = self.service_endpoint(&name).await?;
+                let weights = self.control.set_pool_weight(e, &pool, weight)?;
+                let message = format!("{}: {}", name, weights);
+                render(&ActionReport { message }, output)
+            }
+            AdminRequest::ConnectionsList => {
+                render(&self.control.connections_report().await, output)
+            }
+            AdminRequest::ConnectionsNat => render(&self.control.nat_table_report().await, output),
+            AdminRequest::ConnectionsFlush { service } => {
+                let e = match &service {
+                    Some(name) => Some(self.service_endpoint(name).await?),
+                    None => None,
+                };
+                let flushed = self.control.flush_connections(e).await;
+                let message = format!("closed {} connections", flushed);
+                render(&ActionReport { message }, output)
+            }
+            AdminRequest::PortsStatus => render(&self.control.ports_report(), output),
+            AdminRequest::MapsDump { map } => render(&self.dump(&map).await?, output),
+            AdminRequest::Stats => render(&self.control.stats_report(), output),
+            AdminRequest::Drops => render(&self.control.drops_report(), output),
+            AdminRequest::Handshakes => render(&self.control.handshakes_report().await, output),
+            AdminRequest::ColdStarts => render(&self.control.cold_starts_report(), output),
+            AdminRequest::Interfaces => render(&self.control.interfaces_report(), output),
+            AdminRequest::Queues => render(&self.control.queues_report(), output),
+            AdminRequest::LogLevel { level: None } => {
+                let filter = logging::filter()
+                    .ok_or_else(|| FolonetError::Admin("no logger installed".to_string()))?;
+                let message = format!("log level {}", filter);
+                render(&ActionReport { message }, output)
+            }
+            AdminRequest::Pcap {
+                service,
+                file,
+                one_in,
+                max_packets,
+                secs,
+            } => {
+                let e = self.service_endpoint(&service).await?;
+                let opts = CaptureOptions {
+                    one_in,
+                    max_packets,
+                    duration: Duration::from_secs(secs),
+                };
+                let written = self.capture.run(e, &file, &opts).await?;
+                let message = format!("wrote {} packets of {} to {}", written, service, file);
+                render(&ActionReport { message }, output)
+            }
+            AdminRequest::LogLevel { level: Some(level) } => {
+                let filter: LogFilter = level.parse()?;
+                logging::set_filter(filter.clone())?;
+                info!("log level set to {}", filter);
+                let message = format!("log level set to {}", filter);
+                render(&ActionReport { message }, output)
+            }
+        }
+    }
+
+    // the local endpoint of the service called `name`
+    async fn service_endpoint(&self, name: &str) -> Result<Endpoint, FolonetError> {
+        self.control
+            .services_report()
+            .await
+            .services
+            .iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| FolonetError::Admin(format!("no service {}", name)))?
+            .local_endpoint
+            .parse()
+    }
+
+    async fn dump(&self, map: &str) -> Result<MapReport, FolonetError> {
+        let mut entries: Vec<MapEntry> = match map {
+            "connection" => self
+                .connection_map
+                .entries()
+                .into_iter()
+                .map(|(way, nat): (UConnection, KNat)| MapEntry {
+                    key: way_name(&way),
+                    value: format!(
+                        "{} ({})",
+                        way_name(&UConnection::from(nat.fwd.way)),
+                        ct_name(nat.ct.state)
+                    ),
+                })
+                .collect(),
+            "server" => self
+                .server_map
+                .lock()
+                .await
+                .iter()
+                .filter_map(|item| item.ok())
+                .map(|(service, backend)| MapEntry {
+                    key: format!(
+                        "{}/{}",
+                        Endpoint::new(service.endpoint).to_string(),
+                        if service.is_tcp() { "tcp" } else { "udp" }
+                    ),
+                    value: backend.to_endpoint().to_string(),
+                })
+                .collect(),
+            "epoch" => self
+                .epoch_map
+                .lock()
+                .await
+                .iter()
+                .filter_map(|item| item.ok())
+                .map(|(service, epoch)| MapEntry {
+                    key: service.to_endpoint().to_string(),
+                    value: epoch.to_string(),
+                })
+                .collect(),
+            "draining" => self
+                .draining_map
+                .lock()
+                .await
+                .iter()
+                .filter_map(|item| item.ok())
+                .map(|(service, _)| MapEntry {
+                    key: service.to_endpoint().to_string(),
+                    value: "draining".to_string(),
+                })
+                .collect(),
+            _ => {
+                return Err(FolonetError::Admin(format!(
+                    "unknown map {}, expected one of {}",
+                    map,
+                    DUMPABLE_MAPS.join(", ")
+                )))
+            }
+        };
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(MapReport {
+            map: map.to_string(),
+            entries,
+        })
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_admin_call() {
+        use super::{AdminCall, AdminReply, AdminRequest};
+        use crate::output::OutputFormat;
+
+        let call = AdminCall {
+            request: AdminRequest::ConnectionsFlush {
+                service: Some("web".to_string()),
+            },
+            output: OutputFormat::Json,
+        };
+        let line = serde_json::to_string(&call).unwrap();
+        let v: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(v["op"], "connections_flush");
+        assert_eq!(v["service"], "web");
+        assert_eq!(v["output"], "json");
+        assert_eq!(serde_json::from_str::<AdminCall>(&line).unwrap(), call);
+
+        // external tooling may leave the output out
+        let call: AdminCall = serde_json::from_str(r#"{"op":"ports_status"}"#).unwrap();
+        assert_eq!(call.request, AdminRequest::PortsStatus);
+        assert_eq!(call.output, OutputFormat::Human);
+        assert!(serde_json::from_str::<AdminCall>(r#"{"op":"reboot"}"#).is_err());
+
+        let call: AdminCall =
+            serde_json::from_str(r#"{"op":"log_level","level":"debug"}"#).unwrap();
+        assert_eq!(
+            call.request,
+            AdminRequest::LogLevel {
+                level: Some("debug".to_string())
+            }
+        );
+
+        let reply = serde_json::to_string(&AdminReply::Error("no service web".to_string()));
+        assert_eq!(reply.unwrap(), r#"{"error":"no service web"}"#);
+    }
+}
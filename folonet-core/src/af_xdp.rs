@@ -0,0 +1,528 @@
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use aya::maps::{MapData, XskMap};
+use folonet_client::config::AfXdpConfig;
+use folonet_common::xsk::{KXskMeta, XSK_META_LEN};
+use log::{info, warn};
+
+use crate::error::{FolonetError, MapResultExt};
+use crate::net::get_interafce_index;
+
+// from linux/if_xdp.h, their fields are read by the kernel
+const AF_XDP: libc::c_int = 44;
+const SOL_XDP: libc::c_int = 283;
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+const XDP_COPY: u16 = 1 << 1;
+const XDP_ZEROCOPY: u16 = 1 << 2;
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x80000000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x100000000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x180000000;
+
+const ETH_HDR_LEN: usize = 14;
+const IP_HDR_LEN: usize = 20;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+#[allow(dead_code)]
+#[repr(C)]
+struct XdpUmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Default)]
+struct XdpRingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpMmapOffsets {
+    rx: XdpRingOffset,
+    tx: XdpRingOffset,
+    fr: XdpRingOffset,
+    cr: XdpRingOffset,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct SockaddrXdp {
+    family: u16,
+    flags: u16,
+    ifindex: u32,
+    queue_id: u32,
+    shared_umem_fd: u32,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn set_opt<T>(fd: &OwnedFd, name: libc::c_int, val: &T) -> io::Result<()> {
+    check(unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            SOL_XDP,
+            name,
+            val as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    })
+}
+
+// an mmap'd area, unmapped when dropped
+struct Mmap {
+    addr: *mut u8,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: Option<&OwnedFd>, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        let (flags, fd) = match fd {
+            Some(fd) => (libc::MAP_SHARED | libc::MAP_POPULATE, fd.as_raw_fd()),
+            None => (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1),
+        };
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                fd,
+                offset,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap {
+            addr: addr as *mut u8,
+            len,
+        })
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, self.len) };
+    }
+}
+
+// A ring shared with the kernel, of frame addresses for the fill and
+// completion rings and of descriptors for the rx and tx rings. Userspace
+// produces into the fill and tx rings and consumes from the other two.
+struct Ring<T> {
+    // only held to be unmapped with the ring
+    _map: Mmap,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    entries: *mut T,
+    size: u32,
+}
+
+impl<T: Copy> Ring<T> {
+    fn new(fd: &OwnedFd, off: &XdpRingOffset, size: u32, pgoff: libc::off_t) -> io::Result<Self> {
+        let len = off.desc as usize + size as usize * mem::size_of::<T>();
+        let map = Mmap::new(Some(fd), len, pgoff)?;
+        Ok(Ring {
+            producer: unsafe { map.addr.add(off.producer as usize) } as *const AtomicU32,
+            consumer: unsafe { map.addr.add(off.consumer as usize) } as *const AtomicU32,
+            entries: unsafe { map.addr.add(off.desc as usize) } as *mut T,
+            _map: map,
+            size,
+        })
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        unsafe { &*self.consumer }
+    }
+
+    fn entry(&self, index: u32) -> *mut T {
+        unsafe { self.entries.add((index & (self.size - 1)) as usize) }
+    }
+
+    // the entries the kernel produced and userspace did not consume yet
+    fn ready(&self) -> Vec<T> {
+        let consumer = self.consumer().load(Ordering::Relaxed);
+        let producer = self.producer().load(Ordering::Acquire);
+        let ready = (0..producer.wrapping_sub(consumer))
+            .map(|i| unsafe { self.entry(consumer.wrapping_add(i)).read() })
+            .collect();
+        self.consumer().store(producer, Ordering::Release);
+        ready
+    }
+
+    // hands `entries` to the kernel, as many as there is room for, and
+    // returns how many
+    fn produce(&self, entries: &[T]) -> usize {
+        let producer = self.producer().load(Ordering::Relaxed);
+        let consumer = self.consumer().load(Ordering::Acquire);
+        let free = self.size - producer.wrapping_sub(consumer);
+        let n = entries.len().min(free as usize);
+        for (i, entry) in entries[..n].iter().enumerate() {
+            unsafe { self.entry(producer.wrapping_add(i as u32)).write(*entry) };
+        }
+        self.producer()
+            .store(producer.wrapping_add(n as u32), Ordering::Release);
+        n
+    }
+}
+
+// An af_xdp socket bound to one rx queue, with its own umem holding the
+// frames the packets are received in and sent from.
+struct XskSocket {
+    umem: Mmap,
+    frame_size: u32,
+    fill: Ring<u64>,
+    completion: Ring<u64>,
+    rx: Ring<XdpDesc>,
+    tx: Ring<XdpDesc>,
+    // declared last, the rings are unmapped before the socket closes
+    fd: OwnedFd,
+}
+
+// the rings live as long as the socket and are only used by its worker
+unsafe impl Send for XskSocket {}
+
+impl XskSocket {
+    fn open(ifindex: u32, queue: u32, cfg: &AfXdpConfig, zero_copy: bool) -> io::Result<Self> {
+        let raw = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        check(raw)?;
+        let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+
+        let umem = Mmap::new(None, cfg.frames as usize * cfg.frame_size as usize, 0)?;
+        let reg = XdpUmemReg {
+            addr: umem.addr as u64,
+            len: umem.len as u64,
+            chunk_size: cfg.frame_size,
+            headroom: 0,
+        };
+        set_opt(&fd, XDP_UMEM_REG, &reg)?;
+        for ring in [
+            XDP_UMEM_FILL_RING,
+            XDP_UMEM_COMPLETION_RING,
+            XDP_RX_RING,
+            XDP_TX_RING,
+        ] {
+            set_opt(&fd, ring, &cfg.frames)?;
+        }
+
+        let mut off = XdpMmapOffsets::default();
+        let mut len = mem::size_of::<XdpMmapOffsets>() as libc::socklen_t;
+        check(unsafe {
+            libc::getsockopt(
+                fd.as_raw_fd(),
+                SOL_XDP,
+                XDP_MMAP_OFFSETS,
+                &mut off as *mut XdpMmapOffsets as *mut libc::c_void,
+                &mut len,
+            )
+        })?;
+        let socket = XskSocket {
+            fill: Ring::new(&fd, &off.fr, cfg.frames, XDP_UMEM_PGOFF_FILL_RING)?,
+            completion: Ring::new(&fd, &off.cr, cfg.frames, XDP_UMEM_PGOFF_COMPLETION_RING)?,
+            rx: Ring::new(&fd, &off.rx, cfg.frames, XDP_PGOFF_RX_RING)?,
+            tx: Ring::new(&fd, &off.tx, cfg.frames, XDP_PGOFF_TX_RING)?,
+            umem,
+            frame_size: cfg.frame_size,
+            fd,
+        };
+        // every frame starts out waiting for a packet
+        let frames: Vec<u64> = (0..cfg.frames as u64)
+            .map(|frame| frame * cfg.frame_size as u64)
+            .collect();
+        socket.fill.produce(&frames);
+
+        let addr = SockaddrXdp {
+            family: AF_XDP as u16,
+            flags: if zero_copy { XDP_ZEROCOPY } else { XDP_COPY },
+            ifindex,
+            queue_id: queue,
+            shared_umem_fd: 0,
+        };
+        check(unsafe {
+            libc::bind(
+                socket.fd.as_raw_fd(),
+                &addr as *const SockaddrXdp as *const libc::sockaddr,
+                mem::size_of::<SockaddrXdp>() as libc::socklen_t,
+            )
+        })?;
+        Ok(socket)
+    }
+
+    fn frame(&mut self, desc: &XdpDesc) -> Option<(&mut [u8], KXskMeta)> {
+        let start = desc.addr as usize;
+        let in_frame = start % self.frame_size as usize;
+        if in_frame < XSK_META_LEN || start + desc.len as usize > self.umem.len {
+            return None;
+        }
+        let meta = unsafe {
+            (self.umem.addr.add(start - XSK_META_LEN) as *const KXskMeta).read_unaligned()
+        };
+        let frame =
+            unsafe { std::slice::from_raw_parts_mut(self.umem.addr.add(start), desc.len as usize) };
+        Some((frame, meta))
+    }
+
+    // rewrites and sends what was received until the socket fails
+    fn work_forever(mut self) -> io::Result<()> {
+        let mut pfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            // frames sent go back to waiting for a packet
+            let sent = self.completion.ready();
+            self.fill.produce(&sent);
+
+            check(unsafe { libc::poll(&mut pfd, 1, 1000) })?;
+            let received = self.rx.ready();
+            if received.is_empty() {
+                continue;
+            }
+            let mut send = Vec::with_capacity(received.len());
+            let mut unsent = Vec::new();
+            for desc in received {
+                match self.frame(&desc) {
+                    Some((frame, meta)) if rewrite_frame(frame, &meta) => send.push(desc),
+                    _ => unsent.push(desc.addr),
+                }
+            }
+            let queued = self.tx.produce(&send);
+            unsent.extend(send[queued..].iter().map(|desc| desc.addr));
+            self.fill.produce(&unsent);
+            if queued > 0 {
+                // wakes the kernel up to send the tx ring
+                unsafe {
+                    libc::sendto(
+                        self.fd.as_raw_fd(),
+                        ptr::null(),
+                        0,
+                        libc::MSG_DONTWAIT,
+                        ptr::null(),
+                        0,
+                    )
+                };
+            }
+        }
+    }
+}
+
+// ones' complement sum of big endian words, see rfc 1071
+fn sum_words(bytes: &[u8]) -> u32 {
+    bytes
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32)
+        .sum()
+}
+
+// the checksum at `at` after the bytes `old` became `new`, see rfc 1624
+fn update_check(packet: &mut [u8], at: usize, old: &[u8], new: &[u8]) {
+    let check = u16::from_be_bytes([packet[at], packet[at + 1]]);
+    let old_sum: u32 = old
+        .chunks(2)
+        .map(|word| !u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum();
+    let mut sum = !check as u32 + old_sum + sum_words(new);
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    packet[at..at + 2].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+// Rewrites a packet the xdp program handed over with `meta`, the same as the
+// xdp program would have. False for what is not a tcp or udp packet over
+// ipv4 with a plain ip header.
+fn rewrite_frame(frame: &mut [u8], meta: &KXskMeta) -> bool {
+    let ip = ETH_HDR_LEN;
+    let l4 = ip + IP_HDR_LEN;
+    if frame.len() < l4 || frame[ip] & 0x0f != (IP_HDR_LEN / 4) as u8 {
+        return false;
+    }
+    let l4_check = match frame[ip + 9] {
+        IPPROTO_TCP if frame.len() >= l4 + 20 => l4 + 16,
+        IPPROTO_UDP if frame.len() >= l4 + 8 => l4 + 6,
+        _ => return false,
+    };
+    // a zero udp checksum means the sender did not compute one
+    let has_l4_check = frame[ip + 9] == IPPROTO_TCP || frame[l4_check..l4_check + 2] != [0, 0];
+
+    // both kept as they are on the wire
+    let mut ips = [0u8; 8];
+    ips[..4].copy_from_slice(&meta.way.from.ip().to_ne_bytes());
+    ips[4..].copy_from_slice(&meta.way.to.ip().to_ne_bytes());
+    let mut ports = [0u8; 4];
+    ports[..2].copy_from_slice(&meta.way.from.port().to_ne_bytes());
+    ports[2..].copy_from_slice(&meta.way.to.port().to_ne_bytes());
+
+    let old_ips: [u8; 8] = frame[ip + 12..ip + 20].try_into().unwrap();
+    let old_ports: [u8; 4] = frame[l4..l4 + 4].try_into().unwrap();
+    update_check(frame, ip + 10, &old_ips, &ips);
+    if has_l4_check {
+        update_check(frame, l4_check, &old_ips, &ips);
+        update_check(frame, l4_check, &old_ports, &ports);
+        if frame[ip + 9] == IPPROTO_UDP && frame[l4_check..l4_check + 2] == [0, 0] {
+            frame[l4_check..l4_check + 2].copy_from_slice(&[0xff, 0xff]);
+        }
+    }
+    frame[ip + 12..ip + 20].copy_from_slice(&ips);
+    frame[l4..l4 + 4].copy_from_slice(&ports);
+
+    if meta.keep_macs == 0 {
+        frame[..6].copy_from_slice(&meta.dst_mac);
+        frame[6..12].copy_from_slice(&meta.src_mac);
+    }
+    true
+}
+
+// Opens an af_xdp socket on every queue of `cfg`, puts it in XSK_MAP and
+// starts its worker thread. A queue whose socket fails to open keeps being
+// rewritten by the xdp program.
+pub fn start_workers(mut xsk_map: XskMap<MapData>, cfg: &AfXdpConfig) -> Result<(), FolonetError> {
+    if !cfg.frames.is_power_of_two() || !cfg.frame_size.is_power_of_two() {
+        return Err(FolonetError::Config(
+            "the frames and frame size of af_xdp must be powers of two".to_string(),
+        ));
+    }
+    let ifindex = get_interafce_index(cfg.interface.clone()).ok_or_else(|| {
+        FolonetError::Config(format!("no interface {} for af_xdp", cfg.interface))
+    })?;
+
+    for queue in 0..cfg.queues {
+        let socket = match XskSocket::open(ifindex, queue, cfg, cfg.zero_copy) {
+            Err(e) if cfg.zero_copy => {
+                warn!(
+                    "no zero copy af_xdp on {} queue {}, copying instead: {}",
+                    cfg.interface, queue, e
+                );
+                XskSocket::open(ifindex, queue, cfg, false)
+            }
+            opened => opened,
+        };
+        let socket = match socket {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!(
+                    "failed to open an af_xdp socket on {} queue {}: {}",
+                    cfg.interface, queue, e
+                );
+                continue;
+            }
+        };
+        xsk_map
+            .set(queue, socket.fd.as_raw_fd(), 0)
+            .map_context("XSK_MAP")?;
+
+        let name = format!("xsk-{}-{}", cfg.interface, queue);
+        let iface = cfg.interface.clone();
+        std::thread::Builder::new()
+            .name(name)
+            .spawn(move || {
+                if let Err(e) = socket.work_forever() {
+                    warn!(
+                        "the af_xdp worker of {} queue {} failed: {}",
+                        iface, queue, e
+                    );
+                }
+            })
+            .map_err(|source| FolonetError::Io {
+                context: "failed to start an af_xdp worker".to_string(),
+                source,
+            })?;
+        info!("rewriting {} queue {} over af_xdp", cfg.interface, queue);
+    }
+    Ok(())
+}
+
+mod test {
+
+    #[test]
+    fn test_rewrite_frame() {
+        use folonet_common::xsk::KXskMeta;
+        use folonet_common::{KConnection, KEndpoint};
+
+        use super::{rewrite_frame, sum_words};
+
+        fn valid(check: u32) -> bool {
+            let mut sum = check;
+            while sum >> 16 != 0 {
+                sum = (sum & 0xffff) + (sum >> 16);
+            }
+            sum == 0xffff
+        }
+
+        // a tcp syn from 10.0.0.9:40000 to 10.0.0.1:80, with right checksums
+        let mut frame = vec![0u8; 14 + 20 + 20];
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        frame[14..34].copy_from_slice(&[
+            0x45, 0, 0, 40, 0, 1, 0, 0, 64, 6, 0, 0, 10, 0, 0, 9, 10, 0, 0, 1,
+        ]);
+        frame[34..38].copy_from_slice(&[0x9c, 0x40, 0, 80]);
+        frame[46] = 0x50;
+        frame[47] = 0x02;
+        let ip_check = !(sum_words(&frame[14..34]) % 0xffff) as u16;
+        frame[24..26].copy_from_slice(&ip_check.to_be_bytes());
+        let pseudo = sum_words(&frame[26..34]) + 6 + 20;
+        let tcp_check = !((pseudo + sum_words(&frame[34..54])) % 0xffff) as u16;
+        frame[50..52].copy_from_slice(&tcp_check.to_be_bytes());
+
+        // to 10.0.1.2:8080 from 10.0.0.1:30000, as the xdp program reads them
+        let ip = |a: [u8; 4]| u32::from_ne_bytes(a);
+        let port = |p: u16| u16::from_ne_bytes(p.to_be_bytes());
+        let meta = KXskMeta {
+            way: KConnection {
+                from: KEndpoint::new(ip([10, 0, 0, 1]), port(30000)),
+                to: KEndpoint::new(ip([10, 0, 1, 2]), port(8080)),
+            },
+            src_mac: [2, 0, 0, 0, 0, 1],
+            dst_mac: [2, 0, 0, 0, 0, 2],
+            ..Default::default()
+        };
+        assert!(rewrite_frame(&mut frame, &meta));
+
+        assert_eq!(&frame[26..34], &[10, 0, 0, 1, 10, 0, 1, 2]);
+        assert_eq!(&frame[34..38], &[0x75, 0x30, 0x1f, 0x90]);
+        assert_eq!(&frame[..12], &[2, 0, 0, 0, 0, 2, 2, 0, 0, 0, 0, 1]);
+        assert!(valid(sum_words(&frame[14..34])));
+        let pseudo = sum_words(&frame[26..34]) + 6 + 20;
+        assert!(valid(pseudo + sum_words(&frame[34..54])));
+
+        // not ipv4 with a plain header
+        frame[14] = 0x46;
+        assert!(!rewrite_frame(&mut frame, &meta));
+    }
+}
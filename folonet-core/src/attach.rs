@@ -0,0 +1,154 @@
+use std::fmt;
+use std::sync::Mutex;
+
+use aya::programs::{xdp::XdpLinkId, Xdp, XdpFlags};
+use folonet_client::config::AttachConfig;
+use futures::future::join_all;
+use log::{info, warn};
+use tokio::time::{sleep, Duration};
+
+use crate::net::interface_is_up;
+
+#[derive(Debug, Clone)]
+pub struct IfaceReport {
+    pub iface: String,
+    pub attempts: u32,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AttachReport {
+    pub ifaces: Vec<IfaceReport>,
+}
+
+impl AttachReport {
+    pub fn attached(&self) -> impl Iterator<Item = &IfaceReport> {
+        self.ifaces.iter().filter(|i| i.error.is_none())
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &IfaceReport> {
+        self.ifaces.iter().filter(|i| i.error.is_some())
+    }
+
+    pub fn log(&self) {
+        self.attached()
+            .for_each(|i| info!("attached to {} after {} attempt(s)", i.iface, i.attempts));
+        self.failed().for_each(|i| {
+            warn!(
+                "failed to attach to {} after {} attempt(s): {}",
+                i.iface,
+                i.attempts,
+                i.error.as_deref().unwrap_or_default()
+            )
+        });
+        info!("{}", self);
+    }
+}
+
+impl fmt::Display for AttachReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "xdp attach report: {} attached, {} failed",
+            self.attached().count(),
+            self.failed().count()
+        )
+    }
+}
+
+fn backoff(cfg: &AttachConfig, attempt: u32) -> Duration {
+    if attempt <= 1 {
+        return Duration::ZERO;
+    }
+    let delay = Duration::from_millis(cfg.initial_backoff_ms) * 2u32.pow((attempt - 2).min(16));
+    delay.min(Duration::from_millis(cfg.max_backoff_ms))
+}
+
+// wait for `iface` to be up and attach to it, with a backoff between attempts
+async fn attach_one(
+    program: &Mutex<&mut Xdp>,
+    iface: &str,
+    flags: XdpFlags,
+    cfg: &AttachConfig,
+) -> (IfaceReport, Option<XdpLinkId>) {
+    let mut attempt = 1;
+    loop {
+        sleep(backoff(cfg, attempt)).await;
+        let result = if interface_is_up(iface).unwrap_or(false) {
+            program
+                .lock()
+                .unwrap()
+                .attach(iface, flags)
+                .map_err(|e| format!("{:#}", e))
+        } else {
+            Err(String::from("interface is missing or down"))
+        };
+
+        let report = |error| IfaceReport {
+            iface: iface.to_string(),
+            attempts: attempt,
+            error,
+        };
+        match result {
+            Ok(link_id) => return (report(None), Some(link_id)),
+            Err(e) if attempt < cfg.max_attempts => {
+                info!(
+                    "attach to {} failed ({}), retrying in {:?}",
+                    iface,
+                    e,
+                    backoff(cfg, attempt + 1)
+                );
+                attempt += 1;
+            }
+            Err(e) => return (report(Some(e)), None),
+        }
+    }
+}
+
+// Every interface waits and backs off on its own, so one that is still
+// coming up does not hold back the others. The attach syscalls themselves
+// run one after the other, each takes the program for its own. Every failure
+// is in the report.
+pub async fn attach_all(
+    program: &mut Xdp,
+    ifaces: &[String],
+    flags: XdpFlags,
+    cfg: &AttachConfig,
+) -> (AttachReport, Vec<(String, XdpLinkId)>) {
+    let program = Mutex::new(program);
+    let attaching = ifaces
+        .iter()
+        .map(|iface| attach_one(&program, iface, flags, cfg));
+
+    let mut report = AttachReport::default();
+    let mut links = vec![];
+    for (iface, link_id) in join_all(attaching).await {
+        if let Some(link_id) = link_id {
+            links.push((iface.iface.clone(), link_id));
+        }
+        report.ifaces.push(iface);
+    }
+    (report, links)
+}
+
+mod test {
+
+    #[test]
+    fn test_backoff() {
+        use folonet_client::config::AttachConfig;
+        use tokio::time::Duration;
+
+        use super::backoff;
+
+        let cfg = AttachConfig {
+            max_attempts: 10,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1000,
+        };
+
+        assert_eq!(backoff(&cfg, 1), Duration::ZERO);
+        assert_eq!(backoff(&cfg, 2), Duration::from_millis(100));
+        assert_eq!(backoff(&cfg, 4), Duration::from_millis(400));
+        assert_eq!(backoff(&cfg, 9), Duration::from_millis(1000));
+    }
+}
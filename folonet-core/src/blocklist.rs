@@ -0,0 +1,204 @@
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use aya::maps::{HashMap as AyaHashMap, MapData, PerCpuArray};
+use folonet_client::config::AutoBlockConfig;
+use folonet_common::stats::Counter;
+use log::warn;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration};
+
+use crate::control::ServiceMap;
+use crate::endpoint::Endpoint;
+use crate::error::{FolonetError, MapResultExt};
+use crate::flow_log::ktime_now_ns;
+use crate::state::tcp::TCPState;
+use crate::stats::read_counter;
+
+pub type BpfBlocklistMap = AyaHashMap<MapData, u32, u64>;
+
+const NEVER: u64 = u64::MAX;
+
+// client side states of a handshake that never completed
+const HALF_OPEN_STATES: [TCPState; 3] =
+    [TCPState::SynSent, TCPState::Listen, TCPState::SynReceived];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlockedSource {
+    pub ip: Ipv4Addr,
+    // None when the block does not expire
+    pub expires_in_secs: Option<u64>,
+    // blocked by the anomaly detection rather than an operator
+    pub auto: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct BlocklistStats {
+    pub blocked: usize,
+    pub auto_blocked: usize,
+    // sources blocked automatically since startup
+    pub auto_blocked_total: u64,
+    // packets dropped by the xdp program for a blocked source
+    pub dropped: u64,
+}
+
+// Sources the xdp program drops all packets of. Cheap to clone, every clone
+// manages the same kernel map.
+#[derive(Clone)]
+pub struct Blocklist {
+    map: Arc<Mutex<BpfBlocklistMap>>,
+    counters: Arc<PerCpuArray<MapData, u64>>,
+    auto: Arc<std::sync::Mutex<HashSet<Ipv4Addr>>>,
+    auto_total: Arc<AtomicU64>,
+}
+
+impl Blocklist {
+    pub fn new(map: BpfBlocklistMap, counters: Arc<PerCpuArray<MapData, u64>>) -> Self {
+        Blocklist {
+            map: Arc::new(Mutex::new(map)),
+            counters,
+            auto: Default::default(),
+            auto_total: Default::default(),
+        }
+    }
+
+    // block `ip` for `ttl`, or for good without one
+    pub async fn add(&self, ip: Ipv4Addr, ttl: Option<Duration>) -> Result<(), FolonetError> {
+        let expires_ns = ttl
+            .map(|ttl| ktime_now_ns().saturating_add(ttl.as_nanos() as u64))
+            .unwrap_or(NEVER);
+        self.map
+            .lock()
+            .await
+            .insert(&u32::from(ip).to_be(), &expires_ns, 0)
+            .map_context("BLOCKLIST")?;
+        self.auto.lock().unwrap().remove(&ip);
+        Ok(())
+    }
+
+    // returns whether `ip` was blocked
+    pub async fn remove(&self, ip: Ipv4Addr) -> Result<bool, FolonetError> {
+        let key = u32::from(ip).to_be();
+        let mut map = self.map.lock().await;
+        if map.get(&key, 0).is_err() {
+            return Ok(false);
+        }
+        map.remove(&key).map_context("BLOCKLIST")?;
+        self.auto.lock().unwrap().remove(&ip);
+        Ok(true)
+    }
+
+    pub async fn list(&self) -> Vec<BlockedSource> {
+        let now = ktime_now_ns();
+        let entries: Vec<(u32, u64)> = self
+            .map
+            .lock()
+            .await
+            .iter()
+            .filter_map(|item| item.ok())
+            .collect();
+        let auto = self.auto.lock().unwrap();
+        let mut sources: Vec<BlockedSource> = entries
+            .into_iter()
+            .filter(|(_, expires_ns)| *expires_ns > now)
+            .map(|(key, expires_ns)| {
+                let ip = Ipv4Addr::from(u32::from_be(key));
+                BlockedSource {
+                    ip,
+                    expires_in_secs: (expires_ns != NEVER)
+                        .then(|| (expires_ns - now) / 1_000_000_000),
+                    auto: auto.contains(&ip),
+                }
+            })
+            .collect();
+        sources.sort_by_key(|source| source.ip);
+        sources
+    }
+
+    pub async fn stats(&self) -> BlocklistStats {
+        let sources = self.list().await;
+        BlocklistStats {
+            blocked: sources.len(),
+            auto_blocked: sources.iter().filter(|source| source.auto).count(),
+            auto_blocked_total: self.auto_total.load(Ordering::Relaxed),
+            dropped: read_counter(&self.counters, Counter::Blocked),
+        }
+    }
+
+    // block the sources whose connections pile up in the handshake without
+    // ever getting established, port scanners and syn flooders
+    pub async fn watch_forever(self, services: ServiceMap, cfg: AutoBlockConfig) {
+        let ttl = Duration::from_secs(cfg.ttl_secs);
+        loop {
+            sleep(Duration::from_secs(cfg.interval_secs)).await;
+
+            let mut clients = vec![];
+            for service in services.values() {
+                clients.extend(service.handler.lock().await.client_states().await);
+            }
+
+            let blocked: HashSet<Ipv4Addr> =
+                self.list().await.iter().map(|source| source.ip).collect();
+            for ip in suspects(&clients, cfg.min_half_open) {
+                if blocked.contains(&ip) {
+                    continue;
+                }
+                warn!("blocking {} for {:?}: half open connections only", ip, ttl);
+                if let Err(e) = self.add(ip, Some(ttl)).await {
+                    warn!("failed to block {}: {}", ip, e);
+                    continue;
+                }
+                self.auto.lock().unwrap().insert(ip);
+                self.auto_total.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// sources with at least `min_half_open` half open connections and none established
+fn suspects(clients: &[(Endpoint, TCPState)], min_half_open: usize) -> Vec<Ipv4Addr> {
+    let mut per_source: HashMap<Ipv4Addr, (usize, usize)> = HashMap::new();
+    for (client, state) in clients {
+        let (half_open, established) = per_source.entry(client.ip).or_default();
+        if HALF_OPEN_STATES.contains(state) {
+            *half_open += 1;
+        } else if *state == TCPState::Established {
+            *established += 1;
+        }
+    }
+    let mut suspects: Vec<Ipv4Addr> = per_source
+        .into_iter()
+        .filter(|(_, (half_open, established))| *half_open >= min_half_open && *established == 0)
+        .map(|(ip, _)| ip)
+        .collect();
+    suspects.sort();
+    suspects
+}
+
+mod test {
+
+    #[test]
+    fn test_suspects() {
+        use crate::endpoint::Endpoint;
+        use crate::state::tcp::TCPState;
+
+        use super::suspects;
+
+        let client = |ip: &str, port: u16| format!("{}:{}", ip, port).parse::<Endpoint>().unwrap();
+        let mut clients = vec![];
+        for port in 0..5 {
+            // a scanner, nothing but handshakes
+            clients.push((client("10.0.0.1", 40000 + port), TCPState::SynSent));
+            // a busy but healthy client
+            clients.push((client("10.0.0.2", 40000 + port), TCPState::SynReceived));
+        }
+        clients.push((client("10.0.0.2", 50000), TCPState::Established));
+        clients.push((client("10.0.0.3", 40000), TCPState::SynSent));
+
+        assert_eq!(suspects(&clients, 5), vec!["10.0.0.1".parse().unwrap()]);
+        assert!(suspects(&clients, 6).is_empty());
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+use aya::maps::{MapData, RingBuf};
+use folonet_common::sample::KPayloadSample;
+use serde::Serialize;
+
+use crate::endpoint::{Endpoint, UConnection};
+use crate::poll::PollBackoff;
+
+const HTTP_METHODS: [&[u8]; 9] = [
+    b"GET ",
+    b"POST ",
+    b"PUT ",
+    b"HEAD ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+];
+
+// protocol version 3.0 and the SSLRequest code of a postgres startup message
+const PG_STARTUP: u32 = 0x0003_0000;
+const PG_SSL_REQUEST: u32 = 80877103;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AppProto {
+    Http,
+    // cleartext http/2, which in practice is grpc
+    Grpc,
+    Tls,
+    Redis,
+    Postgres,
+    Dns,
+    Unknown,
+}
+
+// the first bytes a client sends say more than the port it connects to
+pub fn classify(port: u16, payload: &[u8]) -> AppProto {
+    by_payload(payload)
+        .or_else(|| by_port(port))
+        .unwrap_or(AppProto::Unknown)
+}
+
+fn by_payload(p: &[u8]) -> Option<AppProto> {
+    if p.starts_with(b"PRI * HTTP/2") {
+        return Some(AppProto::Grpc);
+    }
+    if HTTP_METHODS.iter().any(|m| p.starts_with(m)) {
+        return Some(AppProto::Http);
+    }
+    // a handshake record of any tls version
+    if p.len() >= 3 && p[0] == 0x16 && p[1] == 0x03 {
+        return Some(AppProto::Tls);
+    }
+    // a resp array, e.g. *1\r\n$4\r\nPING
+    if p.len() >= 2 && p[0] == b'*' && p[1].is_ascii_digit() {
+        return Some(AppProto::Redis);
+    }
+    if p.len() >= 8 {
+        let code = u32::from_be_bytes([p[4], p[5], p[6], p[7]]);
+        if code == PG_STARTUP || code == PG_SSL_REQUEST {
+            return Some(AppProto::Postgres);
+        }
+    }
+    None
+}
+
+fn by_port(port: u16) -> Option<AppProto> {
+    match port {
+        80 | 8080 => Some(AppProto::Http),
+        443 | 8443 => Some(AppProto::Tls),
+        6379 => Some(AppProto::Redis),
+        5432 => Some(AppProto::Postgres),
+        53 => Some(AppProto::Dns),
+        50051 => Some(AppProto::Grpc),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct TagState {
+    // keyed by client and service endpoint
+    conns: HashMap<(Endpoint, Endpoint), AppProto>,
+    per_service: HashMap<Endpoint, HashMap<AppProto, u64>>,
+}
+
+// The protocol of every open connection, from the samples the xdp program
+// takes of their first data. Cheap to clone, every clone shares the tags.
+#[derive(Clone, Default)]
+pub struct ProtoTags {
+    state: Arc<Mutex<TagState>>,
+}
+
+impl ProtoTags {
+    pub fn tag(&self, sample: &KPayloadSample) -> AppProto {
+        let way = UConnection::from(sample.way);
+        let service = way.to_endpoint();
+        let proto = classify(service.port, sample.payload());
+
+        let mut state = self.state.lock().unwrap();
+        state.conns.insert((way.from_endpoint(), service), proto);
+        *state
+            .per_service
+            .entry(service)
+            .or_default()
+            .entry(proto)
+            .or_default() += 1;
+        proto
+    }
+
+    // the tag of a closed connection, forgotten afterwards
+    pub fn take(&self, client_way: &UConnection) -> Option<AppProto> {
+        self.state
+            .lock()
+            .unwrap()
+            .conns
+            .remove(&(client_way.from_endpoint(), client_way.to_endpoint()))
+    }
+
+    // connections seen per protocol, for every service
+    pub fn stats(&self) -> HashMap<Endpoint, HashMap<AppProto, u64>> {
+        self.state.lock().unwrap().per_service.clone()
+    }
+
+    pub async fn follow(self, mut first_data: RingBuf<MapData>) {
+        let mut backoff = PollBackoff::default();
+        loop {
+            let sample = first_data
+                .next()
+                .map(|item| KPayloadSample::from_bytes(item.deref()));
+            match sample {
+                Some(sample) => {
+                    backoff.found();
+                    self.tag(&sample);
+                }
+                None => backoff.wait().await,
+            }
+        }
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_classify() {
+        use super::{classify, AppProto};
+
+        assert_eq!(classify(8000, b"GET / HTTP/1.1\r\n"), AppProto::Http);
+        assert_eq!(classify(8000, b"PRI * HTTP/2.0\r\n"), AppProto::Grpc);
+        assert_eq!(classify(8000, &[0x16, 0x03, 0x01, 0x02]), AppProto::Tls);
+        assert_eq!(classify(8000, b"*1\r\n$4\r\nPING\r\n"), AppProto::Redis);
+        assert_eq!(
+            classify(8000, &[0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f]),
+            AppProto::Postgres
+        );
+        // the payload wins over the port
+        assert_eq!(classify(6379, b"GET / HTTP/1.1\r\n"), AppProto::Http);
+        assert_eq!(classify(8443, b"\x00\x01"), AppProto::Tls);
+        assert_eq!(classify(8000, b"hello"), AppProto::Unknown);
+    }
+}
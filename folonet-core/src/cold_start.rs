@@ -0,0 +1,335 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+
+use aya::maps::{HashMap as AyaHashMap, MapData};
+use folonet_client::config::{RejectConfig, SynGraceConfig};
+use log::warn;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+use crate::endpoint::{Endpoint, UConnection, UEndpoint};
+use crate::flow_log::ktime_now_ns;
+
+pub type BpfColdStartFailedMap = Arc<Mutex<AyaHashMap<MapData, UEndpoint, u64>>>;
+pub type BpfColdStartPendingMap = Arc<Mutex<AyaHashMap<MapData, UConnection, u64>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColdStartOutcome {
+    // the backend came up while the client was still retransmitting its syn
+    ReadyInTime,
+    // the client sent its last syn before the backend was ready
+    ClientGaveUp,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutcomeStats {
+    pub ready_in_time: u64,
+    pub client_gave_up: u64,
+}
+
+impl OutcomeStats {
+    fn record(&mut self, outcome: ColdStartOutcome) {
+        match outcome {
+            ColdStartOutcome::ReadyInTime => self.ready_in_time += 1,
+            ColdStartOutcome::ClientGaveUp => self.client_gave_up += 1,
+        }
+    }
+}
+
+// offset of the last syn retransmission from the first syn
+pub fn last_syn_offset(cfg: &SynGraceConfig) -> Duration {
+    let retries = cfg.retries.min(16);
+    Duration::from_millis(cfg.initial_rto_ms) * ((1u32 << retries) - 1)
+}
+
+pub fn outcome(cfg: &SynGraceConfig, first_syn: Instant, ready: Instant) -> ColdStartOutcome {
+    let deadline = first_syn + last_syn_offset(cfg) + Duration::from_millis(cfg.grace_ms);
+    if ready <= deadline {
+        ColdStartOutcome::ReadyInTime
+    } else {
+        ColdStartOutcome::ClientGaveUp
+    }
+}
+
+// clients waiting for a service to cold start, and how their waits ended
+pub struct PendingConnTracker {
+    grace_cfg: HashMap<Endpoint, SynGraceConfig>,
+    pending: HashMap<Endpoint, HashMap<Endpoint, Instant>>,
+    stats: HashMap<Endpoint, OutcomeStats>,
+}
+
+impl PendingConnTracker {
+    pub fn new(grace_cfg: HashMap<Endpoint, SynGraceConfig>) -> Self {
+        PendingConnTracker {
+            grace_cfg,
+            pending: HashMap::new(),
+            stats: HashMap::new(),
+        }
+    }
+
+    pub fn record_syn(&mut self, service: Endpoint, client: Endpoint, now: Instant) {
+        self.pending
+            .entry(service)
+            .or_default()
+            .entry(client)
+            .or_insert(now);
+    }
+
+    // the clients waiting for `service` right now
+    pub fn clients(&self, service: &Endpoint) -> Vec<Endpoint> {
+        self.pending
+            .get(service)
+            .map(|clients| clients.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn server_ready(&mut self, service: &Endpoint, now: Instant) -> OutcomeStats {
+        let cfg = self.grace_cfg.get(service).copied().unwrap_or_default();
+        let stats = self.stats.entry(*service).or_default();
+        if let Some(clients) = self.pending.remove(service) {
+            clients
+                .values()
+                .for_each(|first_syn| stats.record(outcome(&cfg, *first_syn, now)));
+        }
+        *stats
+    }
+
+    // the clients that were waiting, see clients
+    pub fn server_failed(&mut self, service: &Endpoint) -> Vec<Endpoint> {
+        self.pending
+            .remove(service)
+            .map(|clients| clients.into_keys().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn stats(&self) -> &HashMap<Endpoint, OutcomeStats> {
+        &self.stats
+    }
+}
+
+// Services whose cold start failed. With rejects on, the xdp program rejects
+// their new connections for a while, instead of reporting every retransmitted
+// syn for another cold start that fails the same way.
+#[derive(Clone)]
+pub struct ColdStartFailures {
+    map: BpfColdStartFailedMap,
+    // none while rejects are off
+    reject_for: Option<Duration>,
+}
+
+impl ColdStartFailures {
+    pub fn new(map: BpfColdStartFailedMap, cfg: Option<&RejectConfig>) -> Self {
+        ColdStartFailures {
+            map,
+            reject_for: cfg.map(|cfg| Duration::from_secs(cfg.cold_start_failed_secs)),
+        }
+    }
+
+    pub async fn failed(&self, service: &Endpoint) {
+        let reject_for = match self.reject_for {
+            Some(reject_for) if !reject_for.is_zero() => reject_for,
+            _ => return,
+        };
+        let until = ktime_now_ns() + reject_for.as_nanos() as u64;
+        let inserted = self
+            .map
+            .lock()
+            .await
+            .insert(&service.to_u_endpoint(), &until, 0);
+        if let Err(e) = inserted {
+            warn!(
+                "failed to reject the connections of {}: {}",
+                service.to_string(),
+                e
+            );
+        }
+    }
+}
+
+// The clients the xdp program reported as waiting for a cold start, each only
+// once while it is in flight. Once the cold start is over, their next syn is
+// reported again, be it for the next cold start of the service.
+#[derive(Clone)]
+pub struct ColdStartReports {
+    map: BpfColdStartPendingMap,
+}
+
+impl ColdStartReports {
+    pub fn new(map: BpfColdStartPendingMap) -> Self {
+        ColdStartReports { map }
+    }
+
+    pub async fn over(&self, service: &Endpoint, clients: &[Endpoint]) {
+        let mut map = self.map.lock().await;
+        for client in clients {
+            // gone already when the lru map needed the room
+            let _ = map.remove(&UConnection::new(*client, *service));
+        }
+    }
+}
+
+// the latencies of this many cold starts are kept per service
+const LATENCIES_KEPT: usize = 256;
+
+// The cold starts of one service since the daemon started. The latencies are
+// from the first syn userspace read to the backend being routed, over the
+// last cold starts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ColdStartStats {
+    pub service: String,
+    pub attempts: u64,
+    pub successes: u64,
+    pub failures: u64,
+    pub p50_ms: u64,
+    pub p90_ms: u64,
+    pub p99_ms: u64,
+    pub max_ms: u64,
+    // of the last cold start that succeeded
+    pub last_ms: Option<u64>,
+}
+
+#[derive(Default)]
+struct ColdStartRecord {
+    attempts: u64,
+    successes: u64,
+    failures: u64,
+    // the last one at the back
+    latencies_ms: VecDeque<u64>,
+}
+
+impl ColdStartRecord {
+    fn stats(&self, service: &Endpoint) -> ColdStartStats {
+        let mut sorted: Vec<u64> = self.latencies_ms.iter().copied().collect();
+        sorted.sort_unstable();
+        ColdStartStats {
+            service: service.to_string(),
+            attempts: self.attempts,
+            successes: self.successes,
+            failures: self.failures,
+            p50_ms: nearest_rank(&sorted, 0.5),
+            p90_ms: nearest_rank(&sorted, 0.9),
+            p99_ms: nearest_rank(&sorted, 0.99),
+            max_ms: sorted.last().copied().unwrap_or(0),
+            last_ms: self.latencies_ms.back().copied(),
+        }
+    }
+}
+
+// the `q` quantile of `sorted`, 0 without samples
+fn nearest_rank(sorted: &[u64], q: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = ((sorted.len() as f64 * q).ceil() as usize).max(1);
+    sorted[rank.min(sorted.len()) - 1]
+}
+
+// How the cold starts of every service went, to tell what the serverless
+// path adds to the first connection of a client. Cheap to clone, every clone
+// counts into the same stats.
+#[derive(Clone, Default)]
+pub struct ColdStartMetrics {
+    records: Arc<std::sync::Mutex<BTreeMap<Endpoint, ColdStartRecord>>>,
+}
+
+impl ColdStartMetrics {
+    pub fn started(&self, service: &Endpoint) {
+        self.records
+            .lock()
+            .unwrap()
+            .entry(*service)
+            .or_default()
+            .attempts += 1;
+    }
+
+    pub fn succeeded(&self, service: &Endpoint, latency: Duration) {
+        let mut records = self.records.lock().unwrap();
+        let record = records.entry(*service).or_default();
+        record.successes += 1;
+        if record.latencies_ms.len() == LATENCIES_KEPT {
+            record.latencies_ms.pop_front();
+        }
+        record.latencies_ms.push_back(latency.as_millis() as u64);
+    }
+
+    pub fn failed(&self, service: &Endpoint) {
+        self.records
+            .lock()
+            .unwrap()
+            .entry(*service)
+            .or_default()
+            .failures += 1;
+    }
+
+    // by local endpoint
+    pub fn stats(&self) -> Vec<ColdStartStats> {
+        self.records
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(service, record)| record.stats(service))
+            .collect()
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_syn_grace_outcome() {
+        use folonet_client::config::SynGraceConfig;
+        use tokio::time::{Duration, Instant};
+
+        use super::{last_syn_offset, outcome, ColdStartOutcome};
+
+        let cfg = SynGraceConfig::default();
+        assert_eq!(last_syn_offset(&cfg), Duration::from_secs(63));
+
+        let first_syn = Instant::now();
+        assert_eq!(
+            outcome(&cfg, first_syn, first_syn + Duration::from_secs(10)),
+            ColdStartOutcome::ReadyInTime
+        );
+        assert_eq!(
+            outcome(&cfg, first_syn, first_syn + Duration::from_secs(64)),
+            ColdStartOutcome::ClientGaveUp
+        );
+
+        let cfg = SynGraceConfig {
+            grace_ms: 2000,
+            ..Default::default()
+        };
+        assert_eq!(
+            outcome(&cfg, first_syn, first_syn + Duration::from_secs(64)),
+            ColdStartOutcome::ReadyInTime
+        );
+    }
+
+    #[test]
+    fn test_cold_start_metrics() {
+        use tokio::time::Duration;
+
+        use super::ColdStartMetrics;
+        use crate::endpoint::Endpoint;
+
+        let metrics = ColdStartMetrics::default();
+        let e: Endpoint = "10.0.0.1:80".parse().unwrap();
+        for ms in 1..=100 {
+            metrics.started(&e);
+            metrics.succeeded(&e, Duration::from_millis(ms));
+        }
+        metrics.started(&e);
+        metrics.failed(&e);
+
+        let stats = metrics.stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].service, "10.0.0.1:80");
+        assert_eq!(stats[0].attempts, 101);
+        assert_eq!(stats[0].successes, 100);
+        assert_eq!(stats[0].failures, 1);
+        assert_eq!(stats[0].p50_ms, 50);
+        assert_eq!(stats[0].p99_ms, 99);
+        assert_eq!(stats[0].max_ms, 100);
+        assert_eq!(stats[0].last_ms, Some(100));
+    }
+}
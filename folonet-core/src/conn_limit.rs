@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use folonet_client::config::{ConnLimitAction, ServiceConfig};
+use folonet_common::conn_limit::{KConnLimit, CONN_LIMIT_DROP, CONN_LIMIT_RST};
+use log::warn;
+
+use crate::endpoint::{Endpoint, UEndpoint};
+use crate::error::{FolonetError, MapResultExt};
+use crate::sharded::ShardedBpfMap;
+
+fn k_action(action: ConnLimitAction) -> u8 {
+    match action {
+        ConnLimitAction::Drop => CONN_LIMIT_DROP,
+        ConnLimitAction::Rst => CONN_LIMIT_RST,
+    }
+}
+
+// The connections each service may have open at once. The xdp program counts
+// the connections it opens and turns new ones away once a service has `max`
+// of them open, userspace counts the ones that closed since.
+#[derive(Clone)]
+pub struct ConnLimits {
+    limit_map: Arc<ShardedBpfMap<UEndpoint, KConnLimit>>,
+    // what was last written for every service with a limit
+    limits: Arc<Mutex<HashMap<Endpoint, KConnLimit>>>,
+}
+
+impl ConnLimits {
+    pub fn new(limit_map: ShardedBpfMap<UEndpoint, KConnLimit>) -> Self {
+        ConnLimits {
+            limit_map: Arc::new(limit_map),
+            limits: Arc::default(),
+        }
+    }
+
+    // Let `service` have at most `max` connections open at once. The
+    // connections of a datapath taken over are not counted against it.
+    pub fn set(
+        &self,
+        service: Endpoint,
+        max: u64,
+        action: ConnLimitAction,
+    ) -> Result<(), FolonetError> {
+        let mut limits = self.limits.lock().unwrap();
+        let limit = limits.entry(service).or_default();
+        limit.max = max;
+        limit.action = k_action(action);
+        self.limit_map
+            .insert(service.to_u_endpoint(), *limit)
+            .map_context("CONN_LIMIT_MAP")
+    }
+
+    pub fn set_from(&self, service: Endpoint, cfg: &ServiceConfig) -> Result<(), FolonetError> {
+        match cfg.max_connections {
+            Some(max) => self.set(service, max, cfg.conn_limit_action),
+            None => Ok(()),
+        }
+    }
+
+    // a connection of `service` closed, which makes room for a new one
+    pub fn closed(&self, service: &Endpoint) {
+        // held across the write, so the kernel never sees the count go back
+        let mut limits = self.limits.lock().unwrap();
+        let limit = match limits.get_mut(service) {
+            Some(limit) => limit,
+            None => return,
+        };
+        limit.closed += 1;
+        if let Err(e) = self.limit_map.insert(service.to_u_endpoint(), *limit) {
+            warn!(
+                "failed to update the connection limit of {}: {}",
+                service.to_string(),
+                e
+            );
+        }
+    }
+
+    pub fn max_of(&self, service: &Endpoint) -> Option<u64> {
+        self.limits
+            .lock()
+            .unwrap()
+            .get(service)
+            .map(|limit| limit.max)
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_k_action() {
+        use folonet_client::config::ConnLimitAction;
+        use folonet_common::conn_limit::{CONN_LIMIT_DROP, CONN_LIMIT_RST};
+
+        use super::k_action;
+
+        assert_eq!(k_action(ConnLimitAction::default()), CONN_LIMIT_DROP);
+        assert_eq!(k_action(ConnLimitAction::Rst), CONN_LIMIT_RST);
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use aya::maps::{MapData, PerCpuArray};
+use tokio::time::Duration;
+
+use crate::blocklist::{BlockedSource, Blocklist, BlocklistStats};
+use crate::classify::{AppProto, ProtoTags};
+use crate::cold_start::{ColdStartMetrics, ColdStartStats};
+use crate::conn_limit::ConnLimits;
+use crate::endpoint::Endpoint;
+use crate::error::FolonetError;
+use crate::flow_log::{GenerationStats, Generations};
+use crate::info::{Info, InfoSource};
+use crate::latency::{BackendHandshake, DatapathLatency, HandshakeLatency, IfaceLatency};
+use crate::output::{
+    ColdStartsReport, ConnectionsReport, CounterRow, DropRow, DropsReport, HandshakesReport,
+    InterfacesReport, NatTableReport, PortsReport, Protocol, QueueRow, QueuesReport, ServiceRow,
+    ServicesReport, StatsReport,
+};
+use crate::ports::{PortPool, PortPoolStats, PortQuotaStats};
+use crate::reconcile::{ReconcileStats, Reconciler};
+use crate::removal::Removal;
+use crate::scaler::Scaler;
+use crate::service::Service;
+use crate::sharded::ShardedMap;
+use crate::split::Splits;
+use crate::state::tcp::TCPState;
+use crate::stats::{read_counters, read_iface_stats, BpfIfaceStatsMap, IfaceStats};
+use crate::stuck::{StateAges, StuckWatch};
+use crate::worker::MsgWorker;
+
+pub type ServiceMap = Arc<ShardedMap<Endpoint, MsgWorker<Service>>>;
+
+/// Runtime control over a running [`Engine`](crate::Engine). Cheap to clone,
+/// every clone drives the same engine.
+#[derive(Clone)]
+pub struct Control {
+    port_pool: PortPool,
+    services: ServiceMap,
+    udp_services: ServiceMap,
+    scaler: Scaler,
+    info: InfoSource,
+    removal: Removal,
+    tags: ProtoTags,
+    stuck: StuckWatch,
+    blocklist: Blocklist,
+    counters: Arc<PerCpuArray<MapData, u64>>,
+    ifaces: Arc<BpfIfaceStatsMap>,
+    latency: DatapathLatency,
+    handshake: HandshakeLatency,
+    reconciler: Reconciler,
+    generations: Generations,
+    conn_limits: ConnLimits,
+    splits: Splits,
+    cold_starts: ColdStartMetrics,
+}
+
+impl Control {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        port_pool: PortPool,
+        services: ServiceMap,
+        udp_services: ServiceMap,
+        scaler: Scaler,
+        info: InfoSource,
+        removal: Removal,
+        tags: ProtoTags,
+        stuck: StuckWatch,
+        blocklist: Blocklist,
+        counters: Arc<PerCpuArray<MapData, u64>>,
+        ifaces: Arc<BpfIfaceStatsMap>,
+        latency: DatapathLatency,
+        handshake: HandshakeLatency,
+        reconciler: Reconciler,
+        generations: Generations,
+        conn_limits: ConnLimits,
+        splits: Splits,
+        cold_starts: ColdStartMetrics,
+    ) -> Self {
+        Control {
+            port_pool,
+            services,
+            udp_services,
+            scaler,
+            info,
+            removal,
+            tags,
+            stuck,
+            blocklist,
+            counters,
+            ifaces,
+            latency,
+            handshake,
+            reconciler,
+            generations,
+            conn_limits,
+            splits,
+            cold_starts,
+        }
+    }
+
+    // drop every packet of `ip` for `ttl`, or until unblocked without one
+    pub async fn block(&self, ip: Ipv4Addr, ttl: Option<Duration>) -> Result<(), FolonetError> {
+        self.blocklist.add(ip, ttl).await
+    }
+
+    pub async fn unblock(&self, ip: Ipv4Addr) -> Result<bool, FolonetError> {
+        self.blocklist.remove(ip).await
+    }
+
+    pub async fn blocked(&self) -> Vec<BlockedSource> {
+        self.blocklist.list().await
+    }
+
+    pub async fn blocklist_stats(&self) -> BlocklistStats {
+        self.blocklist.stats().await
+    }
+
+    // drain a service and take it down, safe to call again when it failed
+    pub async fn remove_service(&self, service: Endpoint) -> Result<(), FolonetError> {
+        self.removal.remove(service).await
+    }
+
+    // Give `pool` of `service` `weight` percent of its new connections, e.g.
+    // step by step for a rollout. Returns the weights of all its pools.
+    pub fn set_pool_weight(
+        &self,
+        service: Endpoint,
+        pool: &str,
+        weight: u32,
+    ) -> Result<String, FolonetError> {
+        self.splits.set_weight(service, pool, weight)
+    }
+
+    // version, build and capabilities, for feature detection and debugging
+    pub fn info(&self) -> Info {
+        self.info.info()
+    }
+
+    // connections per application protocol of every service, empty unless
+    // classify_protocols is set
+    pub fn proto_stats(&self) -> HashMap<Endpoint, HashMap<AppProto, u64>> {
+        self.tags.stats()
+    }
+
+    pub fn port_stats(&self) -> PortPoolStats {
+        self.port_pool.stats()
+    }
+
+    // ports held per service with a quota
+    pub fn port_quotas(&self) -> Vec<PortQuotaStats> {
+        self.port_pool.quotas()
+    }
+
+    pub fn port_ranges(&self) -> Vec<RangeInclusive<u16>> {
+        self.port_pool.ranges()
+    }
+
+    pub async fn add_port_range(&self, range: RangeInclusive<u16>) -> Result<usize, FolonetError> {
+        self.port_pool.add_range(range).await
+    }
+
+    pub async fn remove_port_range(
+        &self,
+        range: RangeInclusive<u16>,
+    ) -> Result<usize, FolonetError> {
+        self.port_pool.remove_range(range).await
+    }
+
+    // close every tcp connection with a side in `state` and give its port
+    // back, e.g. to get rid of connections stuck in FinWait2
+    pub async fn reclaim_ports(&self, state: TCPState) -> usize {
+        let mut reclaimed = 0;
+        for service in self.services.values() {
+            reclaimed += service.handler.lock().await.reclaim(state).await;
+        }
+        reclaimed
+    }
+
+    // close the tcp connections of `service`, of every service without one,
+    // and give their ports back
+    pub async fn flush_connections(&self, service: Option<Endpoint>) -> usize {
+        let mut flushed = 0;
+        for (e, s) in self.services.entries() {
+            if service.is_some_and(|service| service != e) {
+                continue;
+            }
+            flushed += s.handler.lock().await.flush().await;
+        }
+        flushed
+    }
+
+    // xdp processing time percentiles per interface, empty unless `latency`
+    // is configured
+    pub async fn datapath_latency(&self) -> Vec<IfaceLatency> {
+        self.latency.percentiles().await
+    }
+
+    // what became of the packets of every interface the program saw one on
+    pub fn iface_stats(&self) -> Vec<IfaceStats> {
+        read_iface_stats(&self.ifaces)
+    }
+
+    // syn to syn-ack of every backend that answered one since the program
+    // was loaded
+    pub async fn handshake_latency(&self) -> Vec<BackendHandshake> {
+        self.handshake.backends().await
+    }
+
+    // every kernel counter by name, including the packet events lost to a
+    // full ring buffer
+    pub fn counters(&self) -> Vec<(&'static str, u64)> {
+        read_counters(&self.counters)
+            .into_iter()
+            .map(|(counter, v)| (counter.name(), v))
+            .collect()
+    }
+
+    // what the last reconciliation pass found, zero unless `reconcile` is
+    // configured
+    pub fn reconcile_stats(&self) -> ReconcileStats {
+        self.reconciler.stats()
+    }
+
+    // closed connections per service and backend generation, the last few
+    // generations of every service
+    pub fn generation_stats(&self) -> Vec<GenerationStats> {
+        self.generations.stats()
+    }
+
+    // attempts, outcomes and latency of the cold starts of every service
+    // since the daemon started
+    pub fn cold_start_stats(&self) -> Vec<ColdStartStats> {
+        self.cold_starts.stats()
+    }
+
+    // age of the tcp connections per state as of the last check, empty
+    // unless `stuck` is configured
+    pub fn state_ages(&self) -> StateAges {
+        self.stuck.ages()
+    }
+
+    // the reports below are what the inspection commands print, see
+    // `output::render` for their machine readable form
+
+    pub async fn services_report(&self) -> ServicesReport {
+        let mut services = vec![];
+        for (protocol, map) in [
+            (Protocol::Tcp, &self.services),
+            (Protocol::Udp, &self.udp_services),
+        ] {
+            for (endpoint, service) in map.entries() {
+                let service = service.handler.lock().await;
+                services.push(ServiceRow {
+                    name: service.name.clone(),
+                    protocol,
+                    local_endpoint: endpoint.to_string(),
+                    backends: service.servers.iter().map(|s| s.to_string()).collect(),
+                    connections: self.scaler.load(&endpoint).concurrency,
+                    max_connections: self.conn_limits.max_of(&endpoint),
+                });
+            }
+        }
+        services.sort_by(|a, b| a.name.cmp(&b.name));
+        ServicesReport { services }
+    }
+
+    pub async fn connections_report(&self) -> ConnectionsReport {
+        let mut connections = vec![];
+        for map in [&self.services, &self.udp_services] {
+            for service in map.values() {
+                connections.extend(service.handler.lock().await.connections().await);
+            }
+        }
+        connections.sort_by(|a, b| (&a.service, &a.client).cmp(&(&b.service, &b.client)));
+        ConnectionsReport { connections }
+    }
+
+    // the nat table of the tracked connections, like /proc/net/nf_conntrack
+    pub async fn nat_table_report(&self) -> NatTableReport {
+        let mut flows = vec![];
+        for map in [&self.services, &self.udp_services] {
+            for service in map.values() {
+                flows.extend(service.handler.lock().await.nat_flows().await);
+            }
+        }
+        flows.sort_by(|a, b| (&a.service, a.client).cmp(&(&b.service, b.client)));
+        NatTableReport { flows }
+    }
+
+    pub fn ports_report(&self) -> PortsReport {
+        PortsReport {
+            ranges: self
+                .port_ranges()
+                .iter()
+                .map(|r| format!("{}-{}", r.start(), r.end()))
+                .collect(),
+            stats: self.port_stats(),
+            quotas: self.port_quotas(),
+        }
+    }
+
+    pub async fn handshakes_report(&self) -> HandshakesReport {
+        HandshakesReport {
+            backends: self.handshake_latency().await,
+        }
+    }
+
+    pub fn interfaces_report(&self) -> InterfacesReport {
+        InterfacesReport {
+            interfaces: self.iface_stats(),
+        }
+    }
+
+    pub fn cold_starts_report(&self) -> ColdStartsReport {
+        ColdStartsReport {
+            services: self.cold_start_stats(),
+        }
+    }
+
+    // never waits for a busy service, the queue of one is what is looked at
+    pub fn queues_report(&self) -> QueuesReport {
+        let mut queues = vec![];
+        for (protocol, map) in [
+            (Protocol::Tcp, &self.services),
+            (Protocol::Udp, &self.udp_services),
+        ] {
+            for (endpoint, service) in map.entries() {
+                queues.push(QueueRow {
+                    local_endpoint: endpoint.to_string(),
+                    protocol,
+                    depth: service.depth(),
+                    capacity: service.capacity(),
+                    shed: service.shed(),
+                });
+            }
+        }
+        queues.sort_by(|a, b| a.local_endpoint.cmp(&b.local_endpoint));
+        QueuesReport { queues }
+    }
+
+    pub fn stats_report(&self) -> StatsReport {
+        StatsReport {
+            counters: read_counters(&self.counters)
+                .into_iter()
+                .map(|(counter, value)| CounterRow {
+                    name: counter.name().to_string(),
+                    value,
+                    drop: counter.is_drop(),
+                })
+                .collect(),
+        }
+    }
+
+    // packets the xdp program dropped, per reason
+    pub fn drops_report(&self) -> DropsReport {
+        DropsReport {
+            drops: read_counters(&self.counters)
+                .into_iter()
+                .filter(|(counter, _)| counter.is_drop())
+                .map(|(counter, packets)| DropRow {
+                    reason: counter.name().to_string(),
+                    packets,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn scaler(&self) -> Scaler {
+        self.scaler.clone()
+    }
+}
@@ -0,0 +1,375 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use folonet_client::config::{GlobalConfig, InterfaceConfig, IpMac, ManagerConfig, ServiceConfig};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+use crate::error::FolonetError;
+
+// A client and a backend, each in a network namespace of its own, on a
+// bridge in a third one. The host end of the veth to the bridge is where
+// folonet attaches and owns the ip of the service:
+//
+//   client ns --+
+//               +-- bridge (lan ns) -- IFACE (host, folonet)
+//   backend ns -+
+pub const NS_CLIENT: &str = "folonet-demo-client";
+pub const NS_BACKEND: &str = "folonet-demo-backend";
+pub const NS_LAN: &str = "folonet-demo-lan";
+pub const IFACE: &str = "fl-demo0";
+
+pub const SERVICE_IP: &str = "10.77.0.1";
+pub const CLIENT_IP: &str = "10.77.0.2";
+pub const BACKEND_IP: &str = "10.77.0.3";
+const PREFIX_LEN: u8 = 24;
+// outside of the local ports folonet hands out
+pub const SERVICE_PORT: u16 = 80;
+pub const BACKEND_PORT: u16 = 9000;
+const MANAGER_LISTEN: &str = "127.0.0.1:7799";
+
+pub const DEFAULT_DIR: &str = "/tmp/folonet-demo";
+
+// how long folonet gets to come up, and a ping to get its answer
+const UP_TIMEOUT: Duration = Duration::from_secs(15);
+const PING_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn argv(s: &str) -> Vec<String> {
+    s.split_whitespace().map(str::to_string).collect()
+}
+
+fn in_ns(ns: &str, s: &str) -> Vec<String> {
+    argv(&format!("ip netns exec {} {}", ns, s))
+}
+
+// the `ip` commands building the topology, in order
+pub fn topology_commands() -> Vec<Vec<String>> {
+    let mut commands = vec![];
+    for ns in [NS_CLIENT, NS_BACKEND, NS_LAN] {
+        commands.push(argv(&format!("ip netns add {}", ns)));
+    }
+    commands.push(in_ns(NS_LAN, "ip link add br0 type bridge"));
+    commands.push(in_ns(NS_LAN, "ip link set br0 up"));
+    commands.push(argv(&format!(
+        "ip link add {} type veth peer name lan0 netns {}",
+        IFACE, NS_LAN
+    )));
+    for (ns, peer, ip) in [
+        (NS_CLIENT, "lan1", CLIENT_IP),
+        (NS_BACKEND, "lan2", BACKEND_IP),
+    ] {
+        commands.push(argv(&format!(
+            "ip link add eth0 netns {} type veth peer name {} netns {}",
+            ns, peer, NS_LAN
+        )));
+        commands.push(in_ns(
+            ns,
+            &format!("ip addr add {}/{} dev eth0", ip, PREFIX_LEN),
+        ));
+        commands.push(in_ns(ns, "ip link set eth0 up"));
+        commands.push(in_ns(ns, "ip link set lo up"));
+    }
+    for port in ["lan0", "lan1", "lan2"] {
+        commands.push(in_ns(NS_LAN, &format!("ip link set {} master br0", port)));
+        commands.push(in_ns(NS_LAN, &format!("ip link set {} up", port)));
+    }
+    commands.push(argv(&format!(
+        "ip addr add {}/{} dev {}",
+        SERVICE_IP, PREFIX_LEN, IFACE
+    )));
+    commands.push(argv(&format!("ip link set {} up", IFACE)));
+    commands
+}
+
+// the veths go with their namespaces
+pub fn teardown_commands() -> Vec<Vec<String>> {
+    [NS_CLIENT, NS_BACKEND, NS_LAN]
+        .iter()
+        .map(|ns| argv(&format!("ip netns del {}", ns)))
+        .collect()
+}
+
+pub fn run(argv: &[String]) -> Result<String, FolonetError> {
+    let output = Command::new(&argv[0])
+        .args(&argv[1..])
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|source| FolonetError::Io {
+            context: format!("failed to run {}", argv.join(" ")),
+            source,
+        })?;
+    if !output.status.success() {
+        return Err(FolonetError::Demo(format!(
+            "{} failed: {}",
+            argv.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+pub fn mac_of(ns: &str, iface: &str) -> Result<String, FolonetError> {
+    run(&in_ns(ns, &format!("cat /sys/class/net/{}/address", iface)))
+}
+
+pub fn folonet_config(dir: &Path, client_mac: &str, backend_mac: &str) -> GlobalConfig {
+    let mut cfg: GlobalConfig = serde_yaml::from_str("{}").unwrap();
+    cfg.interfaces = vec![InterfaceConfig {
+        name: IFACE.to_string(),
+        local_ips: vec![format!("{}/{}", SERVICE_IP, PREFIX_LEN)],
+        exclude_ips: vec![],
+    }];
+    cfg.ip_mac_list = vec![
+        IpMac {
+            ip: CLIENT_IP.to_string(),
+            mac: client_mac.to_string(),
+        },
+        IpMac {
+            ip: BACKEND_IP.to_string(),
+            mac: backend_mac.to_string(),
+        },
+    ];
+    // no servers, the first connection cold starts the backend
+    cfg.services = vec![ServiceConfig {
+        name: "echo".to_string(),
+        local_endpoint: format!("{}:{}", SERVICE_IP, SERVICE_PORT),
+        is_tcp: true,
+        ..Default::default()
+    }];
+    cfg.manager = ManagerConfig {
+        addr: format!("http://{}", MANAGER_LISTEN),
+        ..Default::default()
+    };
+    cfg.admin.socket = dir.join("folonet.sock").to_string_lossy().to_string();
+    cfg
+}
+
+// for folonet-manager, whose backend is `folonet demo echo` in the backend
+// namespace
+pub fn manager_config(dir: &Path, exe: &Path) -> String {
+    let pid = dir.join("echo.pid");
+    let start = format!(
+        "ip netns exec {} {} demo echo {}:{} >/dev/null 2>&1 & echo $! > {}",
+        NS_BACKEND,
+        exe.display(),
+        BACKEND_IP,
+        BACKEND_PORT,
+        pid.display()
+    );
+    let stop = format!("kill $(cat {})", pid.display());
+    format!(
+        r#"listen: "{}"
+services:
+  - name: echo
+    local_endpoint: "{}:{}"
+    server_endpoint: "{}:{}"
+    backend:
+      type: exec
+      start: [sh, -c, "{}"]
+      stop: [sh, -c, "{}"]
+"#,
+        MANAGER_LISTEN, SERVICE_IP, SERVICE_PORT, BACKEND_IP, BACKEND_PORT, start, stop
+    )
+}
+
+fn io_err(context: String) -> impl FnOnce(std::io::Error) -> FolonetError {
+    move |source| FolonetError::Io { context, source }
+}
+
+fn write(path: &Path, contents: &str) -> Result<(), FolonetError> {
+    fs::write(path, contents).map_err(io_err(format!("failed to write {}", path.display())))
+}
+
+// start `argv` in the background, its output in `<dir>/<name>.log` and its
+// pid in `<dir>/<name>.pid`
+fn spawn(dir: &Path, name: &str, argv: &[String]) -> Result<(), FolonetError> {
+    let log = dir.join(format!("{}.log", name));
+    let log =
+        fs::File::create(&log).map_err(io_err(format!("failed to create {}", log.display())))?;
+    let err = log
+        .try_clone()
+        .map_err(io_err("failed to open the log".to_string()))?;
+    let pid = Command::new(&argv[0])
+        .args(&argv[1..])
+        .stdout(log)
+        .stderr(err)
+        .spawn()
+        .map(|child| child.id())
+        .map_err(io_err(format!("failed to start {}", argv[0])))?;
+    write(&dir.join(format!("{}.pid", name)), &pid.to_string())
+}
+
+// folonet-manager next to the folonet binary, from the path otherwise
+fn manager_exe(exe: &Path) -> PathBuf {
+    let sibling = exe.with_file_name("folonet-manager");
+    if sibling.exists() {
+        sibling
+    } else {
+        PathBuf::from("folonet-manager")
+    }
+}
+
+// Builds the topology, starts folonet and its manager on it and sends two
+// pings through folonet: the first one cold starts the backend. Everything
+// keeps running until `down`.
+pub async fn up(dir: &Path, exe: &Path) -> Result<(), FolonetError> {
+    fs::create_dir_all(dir).map_err(io_err(format!("failed to create {}", dir.display())))?;
+    for command in topology_commands() {
+        run(&command)?;
+    }
+    let client_mac = mac_of(NS_CLIENT, "eth0")?;
+    let backend_mac = mac_of(NS_BACKEND, "eth0")?;
+
+    let cfg = folonet_config(dir, &client_mac, &backend_mac);
+    let cfg_path = dir.join("folonet.yaml");
+    let yaml = serde_yaml::to_string(&cfg).map_err(|e| FolonetError::Encode(e.to_string()))?;
+    write(&cfg_path, &yaml)?;
+    let manager_path = dir.join("manager.yaml");
+    write(&manager_path, &manager_config(dir, exe))?;
+    println!("topology up, configs in {}", dir.display());
+
+    let path = |p: &Path| p.to_string_lossy().to_string();
+    spawn(
+        dir,
+        "manager",
+        &[
+            path(&manager_exe(exe)),
+            "--config".to_string(),
+            path(&manager_path),
+        ],
+    )?;
+    spawn(
+        dir,
+        "folonet",
+        &[
+            path(exe),
+            "--config".to_string(),
+            path(&cfg_path),
+            "run".to_string(),
+        ],
+    )?;
+
+    let deadline = Instant::now() + UP_TIMEOUT;
+    while !Path::new(&cfg.admin.socket).exists() {
+        if Instant::now() >= deadline {
+            return Err(FolonetError::Demo(format!(
+                "folonet did not come up, see {}",
+                dir.join("folonet.log").display()
+            )));
+        }
+        sleep(Duration::from_millis(200)).await;
+    }
+    println!("folonet attached to {}", IFACE);
+
+    let service = format!("{}:{}", SERVICE_IP, SERVICE_PORT);
+    for what in ["cold start", "warm"] {
+        let ping = in_ns(
+            NS_CLIENT,
+            &format!("{} demo ping {}", exe.display(), service),
+        );
+        println!("{}: {}", what, run(&ping)?);
+    }
+    println!(
+        "try `folonet --config {} services list`, `folonet demo down` when done",
+        cfg_path.display()
+    );
+    Ok(())
+}
+
+fn kill(dir: &Path, name: &str) {
+    let pid = dir.join(format!("{}.pid", name));
+    if let Ok(pid) = fs::read_to_string(&pid) {
+        let _ = run(&argv(&format!("kill {}", pid.trim())));
+    }
+    let _ = fs::remove_file(pid);
+}
+
+// stops what `up` started and removes the topology, leaves the configs and
+// logs in `dir`
+pub fn down(dir: &Path) {
+    for name in ["folonet", "manager", "echo"] {
+        kill(dir, name);
+    }
+    for command in teardown_commands() {
+        if let Err(e) = run(&command) {
+            println!("{}", e);
+        }
+    }
+}
+
+// the backend of the demo, echoes what it reads
+pub async fn echo(addr: &str) -> Result<(), FolonetError> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(io_err(format!("failed to listen on {}", addr)))?;
+    loop {
+        let (mut stream, _) = listener
+            .accept()
+            .await
+            .map_err(io_err("failed to accept".to_string()))?;
+        tokio::spawn(async move {
+            let (mut read, mut write) = stream.split();
+            let _ = tokio::io::copy(&mut read, &mut write).await;
+        });
+    }
+}
+
+// connect to `addr`, send a line and wait for it to come back, returns how
+// long it took
+pub async fn ping(addr: &str) -> Result<Duration, FolonetError> {
+    let start = Instant::now();
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        let line = b"hello folonet\n";
+        stream.write_all(line).await?;
+        let mut back = vec![0u8; line.len()];
+        stream.read_exact(&mut back).await?;
+        Ok::<bool, std::io::Error>(back == line)
+    };
+    match timeout(PING_TIMEOUT, exchange).await {
+        Ok(Ok(true)) => Ok(start.elapsed()),
+        Ok(Ok(false)) => Err(FolonetError::Demo(format!("{} sent back garbage", addr))),
+        Ok(Err(source)) => Err(FolonetError::Io {
+            context: format!("failed to ping {}", addr),
+            source,
+        }),
+        Err(_) => Err(FolonetError::Demo(format!("no answer from {}", addr))),
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_demo_config() {
+        use std::path::Path;
+
+        use folonet_client::config::GlobalConfig;
+
+        use super::{folonet_config, manager_config, topology_commands, IFACE};
+
+        let dir = Path::new("/tmp/folonet-demo");
+        let cfg = folonet_config(dir, "02:00:00:00:00:02", "02:00:00:00:00:03");
+        let yaml = serde_yaml::to_string(&cfg).unwrap();
+        let back: GlobalConfig = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(back.services[0].local_endpoint, "10.77.0.1:80");
+        assert!(back.services[0].servers.is_empty());
+        assert_eq!(back.interfaces[0].name, IFACE);
+        assert_eq!(back.admin.socket, "/tmp/folonet-demo/folonet.sock");
+
+        let manager = manager_config(dir, Path::new("/usr/bin/folonet"));
+        let manager: serde_yaml::Value = serde_yaml::from_str(&manager).unwrap();
+        assert_eq!(
+            manager["services"][0]["server_endpoint"].as_str(),
+            Some("10.77.0.3:9000")
+        );
+
+        let commands = topology_commands();
+        assert!(commands.iter().all(|c| c[0] == "ip"));
+        assert_eq!(
+            commands.last().unwrap().join(" "),
+            "ip link set fl-demo0 up"
+        );
+    }
+}
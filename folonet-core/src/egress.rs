@@ -0,0 +1,151 @@
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+use aya::maps::{HashMap as AyaHashMap, MapData};
+use folonet_client::config::{GlobalConfig, InterfaceConfig};
+use log::warn;
+use tokio::sync::Mutex;
+
+use crate::acl::parse_cidr;
+use crate::endpoint::{Endpoint, UEndpoint};
+use crate::error::{FolonetError, MapResultExt};
+
+// the ips of the backends, to tell the connections they start themselves
+pub type BpfBackendIpMap = Arc<Mutex<AyaHashMap<MapData, u32, u8>>>;
+
+pub fn add_backend_ip(
+    map: &mut AyaHashMap<MapData, u32, u8>,
+    ip: Ipv4Addr,
+) -> Result<(), FolonetError> {
+    map.insert(u32::from(ip).to_be(), 1, 0)
+        .map_context("BACKEND_IPS")
+}
+
+// the local ips of an interface as the kernel picks among them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterfaceIps {
+    // for backends on none of the subnets
+    pub first: Option<Ipv4Addr>,
+    // subnet, prefix length and the local ip on it
+    pub subnets: Vec<(Ipv4Addr, u8, Ipv4Addr)>,
+}
+
+impl InterfaceIps {
+    // a bad entry is logged and skipped
+    pub fn new(cfg: &InterfaceConfig) -> Self {
+        let mut ips = InterfaceIps::default();
+        for entry in cfg.local_ips.iter() {
+            let (ip, prefix_len) = match parse_cidr(entry) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    warn!("invalid local ip {} of {}: {}", entry, cfg.name, e);
+                    continue;
+                }
+            };
+            ips.push(ip, entry.contains('/').then_some(prefix_len));
+        }
+        ips
+    }
+
+    // the addresses the kernel has on an interface, with their prefix length
+    pub fn from_kernel(addresses: &[(Ipv4Addr, u8)]) -> Self {
+        let mut ips = InterfaceIps::default();
+        for (ip, prefix_len) in addresses.iter() {
+            ips.push(*ip, Some(*prefix_len));
+        }
+        ips
+    }
+
+    fn push(&mut self, ip: Ipv4Addr, prefix_len: Option<u8>) {
+        self.first.get_or_insert(ip);
+        if let Some(prefix_len) = prefix_len {
+            let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            let subnet = Ipv4Addr::from(u32::from(ip) & mask);
+            self.subnets.push((subnet, prefix_len, ip));
+        }
+    }
+
+    pub fn contains(&self, ip: &Ipv4Addr) -> bool {
+        self.first.as_ref() == Some(ip) || self.subnets.iter().any(|(_, _, local)| local == ip)
+    }
+
+    pub fn all(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        self.first
+            .iter()
+            .chain(self.subnets.iter().map(|(_, _, ip)| ip))
+            .copied()
+    }
+}
+
+// the egress ip of every service that has one, which must be one of
+// `all_ips`, the local ips of the interfaces
+pub fn load_egress_ips(
+    cfg: &GlobalConfig,
+    all_ips: &[InterfaceIps],
+    egress_ip_map: &mut AyaHashMap<MapData, UEndpoint, u32>,
+) -> Result<(), FolonetError> {
+    for service in cfg.services.iter() {
+        let egress_ip = match &service.egress_ip {
+            Some(egress_ip) => egress_ip,
+            None => continue,
+        };
+        let ip = egress_ip.parse::<Ipv4Addr>().map_err(|e| {
+            FolonetError::Config(format!(
+                "invalid egress ip {} of {}: {}",
+                egress_ip, service.name, e
+            ))
+        })?;
+        // replies to any other ip never reach folonet
+        if !all_ips.iter().any(|ips| ips.contains(&ip)) {
+            return Err(FolonetError::Config(format!(
+                "egress ip {} of {} is not a local ip of any interface",
+                egress_ip, service.name
+            )));
+        }
+        let local_endpoint = match service.local_endpoint.parse::<Endpoint>() {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("skip the egress ip of {}: {}", service.name, e);
+                continue;
+            }
+        };
+        egress_ip_map
+            .insert(local_endpoint.to_u_endpoint(), u32::from(ip), 0)
+            .map_context("EGRESS_IP_MAP")?;
+    }
+    Ok(())
+}
+
+mod test {
+
+    #[test]
+    fn test_interface_ips() {
+        use std::net::Ipv4Addr;
+
+        use folonet_client::config::InterfaceConfig;
+
+        use super::InterfaceIps;
+
+        let cfg = InterfaceConfig {
+            name: "eth0".to_string(),
+            local_ips: vec![
+                "192.168.1.10".to_string(),
+                "10.0.1.5/24".to_string(),
+                "bad".to_string(),
+                "10.2.0.7/16".to_string(),
+            ],
+            exclude_ips: vec![],
+        };
+        let ips = InterfaceIps::new(&cfg);
+        assert_eq!(ips.first, Some(Ipv4Addr::new(192, 168, 1, 10)));
+        assert_eq!(
+            ips.subnets,
+            vec![
+                (Ipv4Addr::new(10, 0, 1, 0), 24, Ipv4Addr::new(10, 0, 1, 5)),
+                (Ipv4Addr::new(10, 2, 0, 0), 16, Ipv4Addr::new(10, 2, 0, 7)),
+            ]
+        );
+        assert!(ips.contains(&Ipv4Addr::new(10, 2, 0, 7)));
+        assert!(!ips.contains(&Ipv4Addr::new(10, 2, 0, 8)));
+    }
+}
@@ -1,14 +1,17 @@
 use std::collections::HashSet;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::str::FromStr;
 use std::{hash::Hash, net::Ipv4Addr};
 
 use aya::Pod;
 use folonet_common::Mac;
-use folonet_common::{queue::Queue, KConnection, KEndpoint, Notification};
+use folonet_common::{queue::Queue, service::KServiceKey, KConnection, KEndpoint, Notification};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 
+use crate::error::FolonetError;
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct UEndpoint(KEndpoint);
 
@@ -16,6 +19,10 @@ impl UEndpoint {
     pub fn new(e: KEndpoint) -> Self {
         UEndpoint(e)
     }
+
+    pub fn to_endpoint(&self) -> Endpoint {
+        Endpoint::new(self.0)
+    }
 }
 
 unsafe impl Pod for UEndpoint {}
@@ -35,14 +42,14 @@ pub fn set_server_ip(ip: &String) {
     }
 }
 
-pub fn mac_from_string(mac: &String) -> Mac {
-    let mac: Vec<u8> = mac
+pub fn try_mac_from_string(mac: &str) -> Result<Mac, FolonetError> {
+    let invalid = || FolonetError::Config(format!("invalid mac address {}", mac));
+    let bytes = mac
         .split(":")
-        .into_iter()
-        .map(|s| u8::from_str_radix(s, 16).unwrap())
-        .collect();
-    let mac: [u8; 6] = mac.try_into().unwrap();
-    Mac::from(mac)
+        .map(|s| u8::from_str_radix(s, 16).map_err(|_| invalid()))
+        .collect::<Result<Vec<u8>, FolonetError>>()?;
+    let bytes: [u8; 6] = bytes.try_into().map_err(|_| invalid())?;
+    Ok(Mac::from(bytes))
 }
 
 impl Endpoint {
@@ -52,14 +59,17 @@ impl Endpoint {
     }
 
     pub fn to_k_endpoint(&self) -> KEndpoint {
-        let ip = u32::from(self.ip).to_be();
-        let port = self.port.to_be();
-        KEndpoint::new(ip, port)
+        KEndpoint::from(SocketAddrV4::new(self.ip, self.port))
     }
 
     pub fn to_u_endpoint(&self) -> UEndpoint {
         UEndpoint(self.to_k_endpoint())
     }
+
+    // the SERVER_MAP key of the service on this endpoint over tcp or udp
+    pub fn to_service_key(&self, is_tcp: bool) -> KServiceKey {
+        KServiceKey::new(self.to_k_endpoint(), is_tcp)
+    }
 }
 
 impl From<&String> for Endpoint {
@@ -75,6 +85,27 @@ impl From<&String> for Endpoint {
     }
 }
 
+impl FromStr for Endpoint {
+    type Err = FolonetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<SocketAddr>() {
+            Ok(SocketAddr::V4(addr)) => Ok(Endpoint {
+                ip: *addr.ip(),
+                port: addr.port(),
+            }),
+            Ok(SocketAddr::V6(_)) => Err(FolonetError::Config(format!(
+                "ipv6 endpoint {} is not supported",
+                s
+            ))),
+            Err(e) => Err(FolonetError::Config(format!(
+                "invalid endpoint {}: {}",
+                s, e
+            ))),
+        }
+    }
+}
+
 impl ToString for Endpoint {
     fn to_string(&self) -> String {
         format!("{}:{}", self.ip, self.port)
@@ -83,9 +114,10 @@ impl ToString for Endpoint {
 
 impl Endpoint {
     pub fn new(endpoint: KEndpoint) -> Self {
+        let addr = endpoint.to_socket_addr();
         Endpoint {
-            ip: u32::from_be(endpoint.ip()).into(),
-            port: u16::from_be(endpoint.port()),
+            ip: *addr.ip(),
+            port: addr.port(),
         }
     }
 }
@@ -107,6 +139,24 @@ impl UConnection {
             to: to.to_k_endpoint(),
         })
     }
+
+    pub fn from_endpoint(&self) -> Endpoint {
+        Endpoint::new(self.0.from)
+    }
+
+    pub fn to_endpoint(&self) -> Endpoint {
+        Endpoint::new(self.0.to)
+    }
+
+    pub fn reverse(&self) -> Self {
+        UConnection(self.0.reverse())
+    }
+}
+
+impl From<KConnection> for UConnection {
+    fn from(conn: KConnection) -> Self {
+        UConnection(conn)
+    }
 }
 
 unsafe impl Pod for UConnection {}
@@ -222,4 +272,21 @@ mod test {
 
         assert!(map.get(&other_connection).is_some());
     }
+
+    #[test]
+    fn test_parse_endpoint() {
+        use std::net::Ipv4Addr;
+
+        use super::{try_mac_from_string, Endpoint};
+
+        let e = "10.0.0.1:8080".parse::<Endpoint>().unwrap();
+        assert_eq!(e.ip, Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(e.port, 8080);
+        assert!("10.0.0.1".parse::<Endpoint>().is_err());
+        assert!("[::1]:80".parse::<Endpoint>().is_err());
+
+        assert!(try_mac_from_string("52:55:55:e9:2a:1b").is_ok());
+        assert!(try_mac_from_string("52:55:55:e9:2a").is_err());
+        assert!(try_mac_from_string("52:55:55:e9:2a:zz").is_err());
+    }
 }
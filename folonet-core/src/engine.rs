@@ -0,0 +1,1526 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::net::Ipv4Addr;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use aya::maps::{
+    Array, HashMap as AyaHashMap, MapData, PerCpuArray, PerCpuHashMap, Queue, RingBuf, XskMap,
+};
+use aya::programs::{Program, Xdp, XdpFlags};
+use aya::{Bpf, BpfLoader};
+use folonet_client::config::{
+    Affinity, GlobalConfig, PortsConfig, QueueConfig, ServiceConfig, ServiceProtocol,
+    StartupProbeConfig,
+};
+use folonet_client::provider::Providers;
+use folonet_client::ManagerClient;
+use folonet_common::config::KConfig;
+use folonet_common::flow::{KEviction, KFlow};
+use folonet_common::load::KServiceLoad;
+use folonet_common::nat::KNat;
+use folonet_common::service::KServiceKey;
+use folonet_common::{KColdStart, Notification};
+use log::{debug, error, info, warn};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::acl::load_acl;
+use crate::admin::Admin;
+use crate::af_xdp::start_workers;
+use crate::attach::{attach_all, IfaceReport};
+use crate::blocklist::Blocklist;
+use crate::classify::ProtoTags;
+use crate::cold_start::{
+    BpfColdStartFailedMap, BpfColdStartPendingMap, ColdStartFailures, ColdStartMetrics,
+    ColdStartReports, PendingConnTracker,
+};
+use crate::conn_limit::ConnLimits;
+use crate::control::{Control, ServiceMap};
+use crate::egress::{add_backend_ip, load_egress_ips, BpfBackendIpMap};
+use crate::endpoint::{
+    endpoint_pair_from_notification, set_server_ip, try_mac_from_string, Endpoint, UConnection,
+    UEndpoint,
+};
+use crate::error::{take_map, take_raw_map, FolonetError, MapResultExt};
+use crate::event_workers::{connection_key, EventWorkers};
+use crate::fallback::FallbackProxy;
+use crate::federation::Federation;
+use crate::flow_log::{FlowLogger, FlowTracker, Generations};
+use crate::health::{Health, Readiness};
+use crate::iface_watch::IfaceWatch;
+use crate::info::InfoSource;
+use crate::kconfig::build_k_config;
+use crate::latency::{DatapathLatency, HandshakeLatency};
+use crate::limits::map_sizes;
+use crate::message::Message;
+use crate::pcap::Capture;
+use crate::pin::{write_schema, Pins};
+use crate::poll::PollBackoff;
+use crate::ports::{port_ranges, PortPool};
+use crate::probe::wait_ready;
+use crate::reconcile::Reconciler;
+use crate::removal::{BpfDrainingMap, BpfServerMap, Removal};
+use crate::route::HostRouter;
+use crate::scaler::Scaler;
+use crate::sequencer::{Admit, BpfEpochMap, Sequencer};
+use crate::service::Service;
+use crate::shard::Shards;
+use crate::sharded::ShardedBpfMap;
+use crate::split::Splits;
+use crate::state::tcp::ConnectionState;
+use crate::state::BpfConnectionMap;
+use crate::stats::{self, BpfIfaceStatsMap};
+use crate::stuck::StuckWatch;
+use crate::systemd::Notifier;
+use crate::timer::Timers;
+use crate::trace::Tracer;
+use crate::usage::UsageExporter;
+use crate::warm_pool::warm_up;
+use crate::worker::MsgWorker;
+
+const PROGRAM_NAME: &str = "folonet";
+
+// the maps userspace takes from the object, an object built from another
+// tree may lack some
+const REQUIRED_MAPS: &[&str] = &[
+    "ACL_DEFAULT_MAP",
+    "ACL_MAP",
+    "AFFINITY_TIMEOUT",
+    "BACKEND_IPS",
+    "BLOCKLIST",
+    "COLD_START_FAILED",
+    "COLD_START_MAP",
+    "COLD_START_PENDING",
+    "CONFIG",
+    "CONNECTION",
+    "CONN_LIMIT_MAP",
+    "COUNTERS",
+    "DRAINING_MAP",
+    "EGRESS_IP_MAP",
+    "EVICTED",
+    "FIRST_DATA",
+    "FLOW_MAP",
+    "HANDSHAKE_LATENCY",
+    "HOLD_MAP",
+    "IFACE_STATS",
+    "IP_MAC_MAP",
+    "LATENCY",
+    "LOCAL_IP_MAP",
+    "MAP_SCHEMA",
+    "PACKET_EVENT",
+    "PCAP_FILTER",
+    "PCAP_RING",
+    "PORT_QUOTA_MAP",
+    "PORT_TAKEN_MAP",
+    "PROXIED",
+    "PROXY_V2_MAP",
+    "SERVER_MAP",
+    "SERVICE_EPOCH",
+    "SERVICE_LOAD",
+    "SERVICE_PORTS",
+    "SOURCE_IP_MAP",
+    "SPLIT_MAP",
+    "TOA_MAP",
+    "XSK_MAP",
+];
+
+/// The loaded eBPF object together with the maps userspace keeps using
+/// after startup.
+pub struct BpfHandles {
+    pub bpf: Bpf,
+    pub connection: ShardedBpfMap<UConnection, KNat>,
+    pub server: BpfServerMap,
+    pub backend_ips: BpfBackendIpMap,
+    // services being removed, see Removal
+    pub draining: BpfDrainingMap,
+    // epoch of every cold started service, see Sequencer
+    pub epoch: BpfEpochMap,
+    pub service_ports: PortPool,
+    pub conn_limits: ConnLimits,
+    // the pools of the services splitting their connections
+    pub splits: Splits,
+    pub service_load: PerCpuHashMap<MapData, UEndpoint, KServiceLoad>,
+    pub packet_event: RingBuf<MapData>,
+    pub cold_start: RingBuf<MapData>,
+    // services whose cold start failed, see ColdStartFailures
+    pub cold_start_failed: BpfColdStartFailedMap,
+    // the clients already reported waiting, see ColdStartReports
+    pub cold_start_pending: BpfColdStartPendingMap,
+    pub counters: Arc<PerCpuArray<MapData, u64>>,
+    // what became of the packets of every interface
+    pub ifaces: Arc<BpfIfaceStatsMap>,
+    pub blocklist: Blocklist,
+    pub flow: AyaHashMap<MapData, UConnection, KFlow>,
+    pub first_data: RingBuf<MapData>,
+    // connections whose nat entries the kernel evicted
+    pub evicted: RingBuf<MapData>,
+    pub latency: DatapathLatency,
+    // syn to syn-ack per backend
+    pub handshake: HandshakeLatency,
+    pub pcap: Capture,
+    // the af_xdp sockets by rx queue, see af_xdp
+    pub xsk: XskMap<MapData>,
+    // the local ips and xdp links of the interfaces, as they change
+    pub iface_watch: IfaceWatch,
+    // of the object `bpf` was loaded from, when the caller knows it
+    pub object_hash: Option<String>,
+}
+
+impl BpfHandles {
+    /// Fill the static maps from `cfg` and take ownership of the dynamic ones.
+    /// A bad interface, service or ip-mac entry is logged and skipped.
+    pub fn load(mut bpf: Bpf, cfg: &GlobalConfig) -> Result<Self, FolonetError> {
+        let mut server: AyaHashMap<_, KServiceKey, UEndpoint> = take_map(&mut bpf, "SERVER_MAP")?;
+        let mut backend_ips = take_map(&mut bpf, "BACKEND_IPS")?;
+        let taking_over = Pins::new(&cfg.pinning).taking_over();
+        if taking_over {
+            // services cold started by the process taken over start again
+            // with their next connection, their epochs are not pinned
+            let configured: HashSet<Endpoint> = cfg
+                .services
+                .iter()
+                .filter_map(|service| service.local_endpoint.parse::<Endpoint>().ok())
+                .collect();
+            let stale: Vec<KServiceKey> = server
+                .keys()
+                .filter_map(|key| key.ok())
+                .filter(|key| !configured.contains(&Endpoint::new(key.endpoint)))
+                .collect();
+            for key in stale.iter() {
+                let _ = server.remove(key);
+            }
+        }
+        let mut hold_map: AyaHashMap<_, UEndpoint, u64> = take_map(&mut bpf, "HOLD_MAP")?;
+        let mut proxied: AyaHashMap<_, UEndpoint, u8> = take_map(&mut bpf, "PROXIED")?;
+        let mut proxy_v2: AyaHashMap<_, UEndpoint, u8> = take_map(&mut bpf, "PROXY_V2_MAP")?;
+        let mut toa: AyaHashMap<_, UEndpoint, u8> = take_map(&mut bpf, "TOA_MAP")?;
+        let mut affinity: AyaHashMap<_, UEndpoint, u64> = take_map(&mut bpf, "AFFINITY_TIMEOUT")?;
+        let splits = Splits::new(take_map(&mut bpf, "SPLIT_MAP")?);
+        for service in cfg.services.iter() {
+            let local_endpoint = match service.local_endpoint.parse::<Endpoint>() {
+                Ok(e) => e,
+                Err(e) => {
+                    warn!("skip service {}: {}", service.name, e);
+                    continue;
+                }
+            };
+            if service.routes_by_name() {
+                proxied
+                    .insert(&local_endpoint.to_u_endpoint(), &1, 0)
+                    .map_context("PROXIED")?;
+                continue;
+            }
+            if let Some(hold) = &service.hold_handshake {
+                hold_map
+                    .insert(
+                        &local_endpoint.to_u_endpoint(),
+                        &(hold.timeout_ms * 1_000_000),
+                        0,
+                    )
+                    .map_context("HOLD_MAP")?;
+            }
+            if service.proxy_protocol {
+                proxy_v2
+                    .insert(&local_endpoint.to_u_endpoint(), &1, 0)
+                    .map_context("PROXY_V2_MAP")?;
+            }
+            if service.toa {
+                toa.insert(&local_endpoint.to_u_endpoint(), &1, 0)
+                    .map_context("TOA_MAP")?;
+            }
+            if service.affinity == Affinity::ClientIp {
+                affinity
+                    .insert(
+                        &local_endpoint.to_u_endpoint(),
+                        &service.affinity_timeout_secs.saturating_mul(1_000_000_000),
+                        0,
+                    )
+                    .map_context("AFFINITY_TIMEOUT")?;
+            }
+
+            let servers = parse_servers(service, service.servers.iter());
+
+            if let Some(server_endpoint) = servers.first() {
+                for protocol in service.served_protocols() {
+                    server
+                        .insert(
+                            &local_endpoint.to_service_key(protocol.is_tcp()),
+                            &server_endpoint.to_u_endpoint(),
+                            0,
+                        )
+                        .map_context("SERVER_MAP")?;
+                }
+            }
+
+            let pool_servers = parse_servers(
+                service,
+                service.pools.iter().flat_map(|pool| pool.servers.iter()),
+            );
+            for server in servers.iter().chain(pool_servers.iter()) {
+                set_server_ip(&server.ip.to_string());
+                add_backend_ip(&mut backend_ips, server.ip)?;
+            }
+            if !servers.is_empty() {
+                if let Err(e) = splits.set_from(local_endpoint, service) {
+                    warn!("skip the pools of service {}: {}", service.name, e);
+                }
+            }
+        }
+
+        let mut ip_mac_map: AyaHashMap<_, u32, u64> = take_map(&mut bpf, "IP_MAC_MAP")?;
+        for ip_mac in cfg.ip_mac_list.iter() {
+            let (ip, mac) = match (
+                ip_mac.ip.parse::<Ipv4Addr>(),
+                try_mac_from_string(&ip_mac.mac),
+            ) {
+                (Ok(ip), Ok(mac)) => (u32::from(ip).to_be(), mac.val()),
+                _ => {
+                    warn!("skip invalid ip mac pair {} {}", ip_mac.ip, ip_mac.mac);
+                    continue;
+                }
+            };
+            ip_mac_map.insert(&ip, &mac, 0).map_context("IP_MAC_MAP")?;
+        }
+
+        // the local ips of the interfaces there are yet, a bad entry only
+        // costs that entry
+        let mut iface_watch = IfaceWatch::new(
+            cfg,
+            take_map(&mut bpf, "LOCAL_IP_MAP")?,
+            take_map(&mut bpf, "SOURCE_IP_MAP")?,
+            ip_mac_map,
+        );
+        iface_watch.sync()?;
+        let mut egress_ip_map = take_map(&mut bpf, "EGRESS_IP_MAP")?;
+        load_egress_ips(cfg, &iface_watch.local_ips(), &mut egress_ip_map)?;
+
+        let mut config_map: Array<_, KConfig> = take_map(&mut bpf, "CONFIG")?;
+        config_map
+            .set(0, build_k_config(cfg), 0)
+            .map_context("CONFIG")?;
+
+        let mut acl_map = take_map(&mut bpf, "ACL_MAP")?;
+        let mut acl_default_map = take_map(&mut bpf, "ACL_DEFAULT_MAP")?;
+        load_acl(&cfg.acl, &mut acl_map, &mut acl_default_map)?;
+
+        // the pinned queue of a datapath taken over still holds its free ports
+        let mut service_ports: Queue<_, u16> = take_map(&mut bpf, "SERVICE_PORTS")?;
+        let ranges = port_ranges(&cfg.ports)?;
+        if !taking_over {
+            for port in ranges.iter().flat_map(|range| range.clone()) {
+                service_ports.push(port, 0).map_context("SERVICE_PORTS")?;
+            }
+        }
+
+        let counters = Arc::new(take_map(&mut bpf, "COUNTERS")?);
+        let blocklist = Blocklist::new(take_map(&mut bpf, "BLOCKLIST")?, counters.clone());
+
+        Ok(BpfHandles {
+            connection: ShardedBpfMap::new("CONNECTION", take_raw_map(&mut bpf, "CONNECTION")?)?,
+            server: Arc::new(Mutex::new(server)),
+            backend_ips: Arc::new(Mutex::new(backend_ips)),
+            draining: Arc::new(Mutex::new(take_map(&mut bpf, "DRAINING_MAP")?)),
+            epoch: Arc::new(Mutex::new(take_map(&mut bpf, "SERVICE_EPOCH")?)),
+            service_ports: PortPool::new(
+                service_ports,
+                ranges,
+                ShardedBpfMap::new("PORT_QUOTA_MAP", take_raw_map(&mut bpf, "PORT_QUOTA_MAP")?)?,
+                take_map(&mut bpf, "PORT_TAKEN_MAP")?,
+            ),
+            conn_limits: ConnLimits::new(ShardedBpfMap::new(
+                "CONN_LIMIT_MAP",
+                take_raw_map(&mut bpf, "CONN_LIMIT_MAP")?,
+            )?),
+            splits,
+            service_load: take_map(&mut bpf, "SERVICE_LOAD")?,
+            packet_event: take_map(&mut bpf, "PACKET_EVENT")?,
+            cold_start: take_map(&mut bpf, "COLD_START_MAP")?,
+            cold_start_failed: Arc::new(Mutex::new(take_map(&mut bpf, "COLD_START_FAILED")?)),
+            cold_start_pending: Arc::new(Mutex::new(take_map(&mut bpf, "COLD_START_PENDING")?)),
+            counters,
+            ifaces: Arc::new(take_map(&mut bpf, "IFACE_STATS")?),
+            blocklist,
+            flow: take_map(&mut bpf, "FLOW_MAP")?,
+            first_data: take_map(&mut bpf, "FIRST_DATA")?,
+            evicted: take_map(&mut bpf, "EVICTED")?,
+            latency: DatapathLatency::new(take_map(&mut bpf, "LATENCY")?),
+            handshake: HandshakeLatency::new(take_map(&mut bpf, "HANDSHAKE_LATENCY")?),
+            pcap: Capture::new(
+                take_map(&mut bpf, "PCAP_FILTER")?,
+                take_map(&mut bpf, "PCAP_RING")?,
+            ),
+            xsk: take_map(&mut bpf, "XSK_MAP")?,
+            iface_watch,
+            object_hash: None,
+            bpf,
+        })
+    }
+
+    pub fn program_mut(&mut self) -> Result<&mut Xdp, FolonetError> {
+        xdp_program(&mut self.bpf)
+    }
+}
+
+// the servers of `service` that parse, the others are logged and skipped
+fn parse_servers<'a>(
+    service: &ServiceConfig,
+    servers: impl Iterator<Item = &'a String>,
+) -> Vec<Endpoint> {
+    servers
+        .filter_map(|server| match server.parse::<Endpoint>() {
+            Ok(e) => Some(e),
+            Err(e) => {
+                warn!("skip server of service {}: {}", service.name, e);
+                None
+            }
+        })
+        .collect()
+}
+
+// stop the server once it has neither open connections nor new ones
+async fn stop_when_idle(e: Endpoint, scaler: Scaler, removal: Removal) {
+    const DURATION: Duration = Duration::from_secs(15);
+    loop {
+        sleep(DURATION).await;
+        if !scaler.is_idle(&e) {
+            continue;
+        }
+
+        info!("stop server {}", e.to_string());
+        if let Err(err) = removal.remove(e).await {
+            warn!("failed to stop server {}: {}", e.to_string(), err);
+        }
+        break;
+    }
+}
+
+// the userspace side goes first, so the first packets the kernel routes
+// already find the service, and they carry `epoch`. `services` holds the
+// service once per protocol it is served over, keyed by `is_tcp`.
+#[allow(clippy::too_many_arguments)]
+async fn install_service(
+    e: Endpoint,
+    backend: Endpoint,
+    services: Vec<(bool, Service)>,
+    epoch: u32,
+    server_map: &BpfServerMap,
+    epoch_map: &BpfEpochMap,
+    backend_ips: &BpfBackendIpMap,
+    tcp_services: &ServiceMap,
+    udp_services: &ServiceMap,
+    queue: usize,
+) -> Result<(), FolonetError> {
+    let protocols: Vec<bool> = services.iter().map(|(is_tcp, _)| *is_tcp).collect();
+    for (is_tcp, service) in services {
+        let service_map = if is_tcp { tcp_services } else { udp_services };
+        service_map.insert(e, MsgWorker::with_capacity(service, queue));
+    }
+    add_backend_ip(&mut *backend_ips.lock().await, backend.ip)?;
+    epoch_map
+        .lock()
+        .await
+        .insert(&e.to_u_endpoint(), &epoch, 0)
+        .map_context("SERVICE_EPOCH")?;
+    let mut server_map = server_map.lock().await;
+    for is_tcp in protocols {
+        server_map
+            .insert(&e.to_service_key(is_tcp), &backend.to_u_endpoint(), 0)
+            .map_context("SERVER_MAP")?;
+    }
+    Ok(())
+}
+
+// Adds services to the running engine, as if they were configured with their
+// backends from the start. Built by the engine once it runs.
+#[derive(Clone)]
+pub struct Installer {
+    connection_map: BpfConnectionMap,
+    port_pool: PortPool,
+    flow_tracker: FlowTracker,
+    scaler: Scaler,
+    shards: Shards<ConnectionState>,
+    timers: Timers,
+    sequencer: Arc<Mutex<Sequencer<Notification>>>,
+    server_map: BpfServerMap,
+    epoch_map: BpfEpochMap,
+    backend_ips: BpfBackendIpMap,
+    splits: Splits,
+    tcp_services: ServiceMap,
+    udp_services: ServiceMap,
+    ports: PortsConfig,
+    queues: QueueConfig,
+}
+
+impl Installer {
+    // route `cfg` to its first backend, returns its local endpoint
+    pub async fn add(&self, cfg: &ServiceConfig) -> Result<Endpoint, FolonetError> {
+        let e = cfg.local_endpoint.parse::<Endpoint>()?;
+        let servers = cfg
+            .servers
+            .iter()
+            .map(|server| server.parse::<Endpoint>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let backend = *servers.first().ok_or_else(|| {
+            FolonetError::Config(format!(
+                "service {} has no servers, cold started services come from the manager",
+                cfg.name
+            ))
+        })?;
+        // the endpoint may be served over the other protocol already
+        let protocols: Vec<bool> = cfg.served_protocols().iter().map(|p| p.is_tcp()).collect();
+        let served = {
+            let server_map = self.server_map.lock().await;
+            protocols.iter().any(|is_tcp| {
+                let services = if *is_tcp {
+                    &self.tcp_services
+                } else {
+                    &self.udp_services
+                };
+                services.contains_key(&e) || server_map.get(&e.to_service_key(*is_tcp), 0).is_ok()
+            })
+        };
+        // a cold start in flight installs the service itself
+        if served || !self.sequencer.lock().await.begin(e) {
+            return Err(FolonetError::Config(format!(
+                "{} is already served",
+                e.to_string()
+            )));
+        }
+
+        if let Some(limit) = self.ports.quota_of(cfg) {
+            if let Err(err) = self.port_pool.set_quota(e, &cfg.name, limit).await {
+                self.sequencer.lock().await.abort(&e);
+                return Err(err);
+            }
+        }
+        if let Err(err) = self.flow_tracker.conn_limits().set_from(e, cfg) {
+            self.sequencer.lock().await.abort(&e);
+            return Err(err);
+        }
+        if let Err(err) = self.add_pools(e, cfg).await {
+            self.sequencer.lock().await.abort(&e);
+            return Err(err);
+        }
+        servers
+            .iter()
+            .for_each(|server| set_server_ip(&server.ip.to_string()));
+        let services = cfg
+            .per_protocol()
+            .iter()
+            .map(|cfg| {
+                let service = Service::new(
+                    cfg,
+                    self.connection_map.clone(),
+                    self.port_pool.clone(),
+                    self.flow_tracker.clone(),
+                    self.scaler.clone(),
+                    self.shards.clone(),
+                    self.timers.clone(),
+                );
+                (cfg.is_tcp, service)
+            })
+            .collect();
+        let epoch = self.sequencer.lock().await.next_epoch(&e);
+        if let Err(err) = install_service(
+            e,
+            backend,
+            services,
+            epoch,
+            &self.server_map,
+            &self.epoch_map,
+            &self.backend_ips,
+            &self.tcp_services,
+            &self.udp_services,
+            self.queues.service,
+        )
+        .await
+        {
+            self.sequencer.lock().await.abort(&e);
+            return Err(err);
+        }
+        // nothing was routed to the service before, there is nothing to replay
+        self.sequencer.lock().await.finish(e, epoch);
+        info!("added service {} on {}", cfg.name, e.to_string());
+        Ok(e)
+    }
+
+    // the backends of the pools of `cfg` next to its servers
+    async fn add_pools(&self, e: Endpoint, cfg: &ServiceConfig) -> Result<(), FolonetError> {
+        self.splits.set_from(e, cfg)?;
+        let mut backend_ips = self.backend_ips.lock().await;
+        for pool in cfg.pools.iter() {
+            for server in pool.servers.iter() {
+                let server = server.parse::<Endpoint>()?;
+                set_server_ip(&server.ip.to_string());
+                add_backend_ip(&mut backend_ips, server.ip)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// hand a packet event to the tracker of its service, if it has one
+async fn dispatch(
+    notification: Notification,
+    tcp_services: &ServiceMap,
+    udp_services: &ServiceMap,
+    shed: bool,
+) {
+    let (from_endpoint, to_endpoint) = endpoint_pair_from_notification(&notification);
+    let local_in_endpoint = Endpoint::new(notification.local_in_endpoint);
+    let local_out_endpoint = Endpoint::new(notification.lcoal_out_endpoint);
+
+    info!(
+        "from {} to {}",
+        from_endpoint.to_string(),
+        to_endpoint.to_string()
+    );
+
+    let mut from_client = true;
+
+    let is_tcp = notification.is_tcp();
+    let service_map = if is_tcp { tcp_services } else { udp_services };
+    let service = service_map.get(&local_in_endpoint).or_else(|| {
+        from_client = false;
+        service_map.get(&local_out_endpoint)
+    });
+
+    if let Some(service) = service {
+        let msg = Message::from_notification(notification, from_client);
+        // nothing is left open when it is shed, see QueueConfig
+        if shed && msg.opens_connection() {
+            let (client_way, backend_way) = msg.to_u_connections();
+            if !service.try_send(msg) {
+                debug!("shed a packet event, the queue of its service is full");
+                // the idle sweep closes a udp flow whose first packet was shed
+                if is_tcp {
+                    service
+                        .handler
+                        .lock()
+                        .await
+                        .shed(client_way, backend_way)
+                        .await;
+                }
+            }
+            return;
+        }
+        if let Some(sender) = service.msg_sender() {
+            let result = sender.send(msg.clone()).await;
+            if result.is_err() {
+                error!(
+                    "failed to send message {:?}, error detail: {:?}",
+                    msg,
+                    result.err().unwrap(),
+                );
+            }
+        }
+    }
+}
+
+// close the connections whose nat entries the kernel evicted
+async fn follow_evictions(
+    mut evicted: RingBuf<MapData>,
+    tcp_services: ServiceMap,
+    udp_services: ServiceMap,
+) {
+    let mut backoff = PollBackoff::default();
+    loop {
+        let eviction = match evicted
+            .next()
+            .map(|item| KEviction::from_bytes(item.deref()))
+        {
+            Some(eviction) => eviction,
+            None => {
+                backoff.wait().await;
+                continue;
+            }
+        };
+        backoff.found();
+        let client_way = UConnection::from(eviction.client_way);
+        let backend_way = UConnection::from(eviction.backend_way);
+        for services in [&tcp_services, &udp_services] {
+            if let Some(service) = services.get(&client_way.to_endpoint()) {
+                service
+                    .handler
+                    .lock()
+                    .await
+                    .evicted(client_way, backend_way)
+                    .await;
+            }
+        }
+    }
+}
+
+// the service a packet event belongs to, the way the kernel picked its epoch:
+// the local in endpoint for client packets, the local out one otherwise
+fn sequence_key(sequencer: &Sequencer<Notification>, notification: &Notification) -> Endpoint {
+    let local_in_endpoint = Endpoint::new(notification.local_in_endpoint);
+    let local_out_endpoint = Endpoint::new(notification.lcoal_out_endpoint);
+    if !sequencer.tracks(&local_in_endpoint) && sequencer.tracks(&local_out_endpoint) {
+        local_out_endpoint
+    } else {
+        local_in_endpoint
+    }
+}
+
+/// Load the eBPF object with its maps sized from `cfg.limits`, reusing the
+/// pinned maps of a running datapath to take over.
+pub fn load_bpf(object: &[u8], cfg: &GlobalConfig) -> Result<Bpf, FolonetError> {
+    let sizes = map_sizes(&cfg.limits)?;
+    let pins = Pins::new(&cfg.pinning);
+    pins.prepare()?;
+
+    let mut loader = BpfLoader::new();
+    loader.map_pin_path(pins.maps_dir());
+    for (name, size) in sizes {
+        loader.set_max_entries(name, size);
+    }
+    let mut bpf = loader.load(object)?;
+    check_object(&bpf)?;
+    write_schema(&mut bpf)?;
+    Ok(bpf)
+}
+
+// Whether the object has the program and the maps userspace needs, before
+// anything is attached. One loaded from a file may come from another build.
+fn check_object(bpf: &Bpf) -> Result<(), FolonetError> {
+    match bpf.program(PROGRAM_NAME) {
+        Some(Program::Xdp(_)) => {}
+        Some(_) => {
+            return Err(FolonetError::Config(format!(
+                "program {} of the eBPF object is not an xdp program",
+                PROGRAM_NAME
+            )))
+        }
+        None => return Err(FolonetError::ProgramNotFound(PROGRAM_NAME)),
+    }
+    match REQUIRED_MAPS
+        .iter()
+        .copied()
+        .find(|name| bpf.map(name).is_none())
+    {
+        Some(name) => Err(FolonetError::MapNotFound(name)),
+        None => Ok(()),
+    }
+}
+
+fn xdp_program(bpf: &mut Bpf) -> Result<&mut Xdp, FolonetError> {
+    bpf.program_mut(PROGRAM_NAME)
+        .ok_or(FolonetError::ProgramNotFound(PROGRAM_NAME))?
+        .try_into()
+        .map_err(|source| FolonetError::Program {
+            name: PROGRAM_NAME,
+            source,
+        })
+}
+
+/// Drives folonet: attaches the XDP program, tracks connections reported by
+/// the kernel and cold starts services on demand.
+pub struct Engine {
+    cfg: GlobalConfig,
+    handles: BpfHandles,
+    scaler: Scaler,
+    services: ServiceMap,
+    udp_services: ServiceMap,
+    info: InfoSource,
+    manager: ManagerClient,
+    // what starts the cold started backends, the manager or the provider of
+    // their service
+    providers: Providers,
+    tags: ProtoTags,
+    stuck: StuckWatch,
+    reconciler: Reconciler,
+    generations: Generations,
+    cold_starts: ColdStartMetrics,
+}
+
+impl Engine {
+    pub fn new(cfg: GlobalConfig, handles: BpfHandles) -> Self {
+        let info = InfoSource::new(handles.object_hash.clone());
+        let manager = ManagerClient::new(cfg.manager.clone().with_env());
+        let providers = Providers::new(manager.clone(), &cfg.services);
+        Engine {
+            cfg,
+            handles,
+            scaler: Scaler::new(),
+            services: Arc::default(),
+            udp_services: Arc::default(),
+            info,
+            manager,
+            providers,
+            tags: ProtoTags::default(),
+            stuck: StuckWatch::default(),
+            reconciler: Reconciler::default(),
+            generations: Generations::default(),
+            cold_starts: ColdStartMetrics::default(),
+        }
+    }
+
+    fn removal(&self) -> Removal {
+        Removal::new(
+            self.handles.server.clone(),
+            self.handles.draining.clone(),
+            self.services.clone(),
+            self.udp_services.clone(),
+            self.scaler.clone(),
+            self.providers.clone(),
+            self.cfg.fin_sweep.clone(),
+            self.cfg.scale_down.clone(),
+        )
+    }
+
+    /// Port pool and connection controls that stay usable while running.
+    pub fn control(&self) -> Control {
+        Control::new(
+            self.handles.service_ports.clone(),
+            self.services.clone(),
+            self.udp_services.clone(),
+            self.scaler.clone(),
+            self.info.clone(),
+            self.removal(),
+            self.tags.clone(),
+            self.stuck.clone(),
+            self.handles.blocklist.clone(),
+            self.handles.counters.clone(),
+            self.handles.ifaces.clone(),
+            self.handles.latency.clone(),
+            self.handles.handshake.clone(),
+            self.reconciler.clone(),
+            self.generations.clone(),
+            self.handles.conn_limits.clone(),
+            self.handles.splits.clone(),
+            self.cold_starts.clone(),
+        )
+    }
+
+    /// Per service load, for autoscalers living outside of folonet.
+    pub fn scaler(&self) -> Scaler {
+        self.scaler.clone()
+    }
+
+    /// Run until `shutdown` resolves, then detach from every interface.
+    pub async fn run<F>(self, shutdown: F) -> Result<(), FolonetError>
+    where
+        F: Future<Output = ()>,
+    {
+        let removal = self.removal();
+        let control = self.control();
+        let Engine {
+            cfg,
+            handles,
+            scaler,
+            services: tcp_service_map,
+            udp_services: udp_service_map,
+            info,
+            manager,
+            providers,
+            tags,
+            stuck,
+            reconciler,
+            generations,
+            cold_starts,
+        } = self;
+        let BpfHandles {
+            mut bpf,
+            connection,
+            server: server_map,
+            backend_ips,
+            draining: draining_map,
+            epoch: epoch_map,
+            service_ports,
+            conn_limits,
+            splits,
+            service_load,
+            mut packet_event,
+            mut cold_start,
+            cold_start_failed,
+            cold_start_pending,
+            counters,
+            blocklist,
+            flow,
+            first_data,
+            evicted,
+            pcap,
+            xsk,
+            mut iface_watch,
+            ..
+        } = handles;
+
+        let program = xdp_program(&mut bpf)?;
+        program.load().map_err(|source| FolonetError::Program {
+            name: PROGRAM_NAME,
+            source,
+        })?;
+
+        let iface_list = iface_watch.interfaces();
+        let xdp_flags = XdpFlags::SKB_MODE;
+        let pins = Pins::new(&cfg.pinning);
+        let (taken, fresh) = pins.take_over(program, &iface_list);
+        let (mut attach_report, mut xdp_links) =
+            attach_all(program, &fresh, xdp_flags, &cfg.attach).await;
+        attach_report
+            .ifaces
+            .extend(taken.iter().map(|(iface, _)| IfaceReport {
+                iface: iface.clone(),
+                attempts: 1,
+                error: None,
+            }));
+        xdp_links.extend(taken);
+        let mut xdp_links = pins.pin_links(program, xdp_links);
+        attach_report.log();
+        info.set_interfaces(
+            iface_watch.attached(attach_report.attached().map(|i| &i.iface), xdp_flags),
+        );
+        if attach_report.attached().count() == 0 {
+            return Err(FolonetError::NoInterfaceAttached);
+        }
+        if let Some(af_xdp_cfg) = cfg.af_xdp.as_ref() {
+            start_workers(xsk, af_xdp_cfg)?;
+        }
+
+        service_ports.set_quarantine(Duration::from_secs(cfg.ports.quarantine_secs));
+        for service_cfg in cfg.services.iter() {
+            if let Ok(local_endpoint) = service_cfg.local_endpoint.parse::<Endpoint>() {
+                conn_limits.set_from(local_endpoint, service_cfg)?;
+            }
+            let limit = match cfg.ports.quota_of(service_cfg) {
+                Some(limit) => limit,
+                None => continue,
+            };
+            if let Ok(local_endpoint) = service_cfg.local_endpoint.parse::<Endpoint>() {
+                service_ports
+                    .set_quota(local_endpoint, &service_cfg.name, limit)
+                    .await?;
+            }
+        }
+        tokio::spawn(stats::log_counters(
+            counters.clone(),
+            Duration::from_secs(10),
+        ));
+        tokio::spawn(service_ports.clone().sample_forever(
+            counters,
+            cfg.ports.low_free_ratio,
+            Duration::from_secs(1),
+        ));
+        tokio::spawn(
+            scaler
+                .clone()
+                .sample_forever(service_load, Duration::from_secs(1)),
+        );
+
+        let connection_map = Arc::new(connection);
+
+        let flow_logger =
+            match &cfg.flow_log {
+                Some(flow_log_cfg) => {
+                    Some(FlowLogger::new(flow_log_cfg).await.map_err(|source| {
+                        FolonetError::Io {
+                            context: format!(
+                                "failed to open flow log sink {:?}",
+                                flow_log_cfg.sink
+                            ),
+                            source,
+                        }
+                    })?)
+                }
+                None => None,
+            };
+        let usage = match &cfg.usage_export {
+            Some(usage_cfg) => Some(UsageExporter::new(usage_cfg, &cfg.services).await.map_err(
+                |source| FolonetError::Io {
+                    context: format!("failed to open usage export dir {}", usage_cfg.dir),
+                    source,
+                },
+            )?),
+            None => None,
+        };
+        let tracer = cfg.tracing.as_ref().map(Tracer::new).transpose()?;
+        if cfg.classify_protocols {
+            tokio::spawn(tags.clone().follow(first_data));
+        }
+        let flow_tracker = FlowTracker::new(
+            Arc::new(Mutex::new(flow)),
+            flow_logger,
+            usage,
+            tracer,
+            tags,
+            generations,
+            conn_limits,
+        );
+
+        let shards = Shards::new(cfg.sharding.clone());
+        tokio::spawn(shards.clone().autoscale_forever());
+        let timers = Timers::new();
+
+        cfg.services.iter().for_each(|service_cfg| {
+            let local_endpoint = match service_cfg.local_endpoint.parse::<Endpoint>() {
+                Ok(e) => e,
+                Err(_) => return,
+            };
+            if service_cfg.servers.is_empty() || service_cfg.routes_by_name() {
+                return;
+            }
+            for service_cfg in service_cfg.per_protocol().iter() {
+                let services = if service_cfg.is_tcp {
+                    &tcp_service_map
+                } else {
+                    &udp_service_map
+                };
+                services.insert(
+                    local_endpoint,
+                    MsgWorker::with_capacity(
+                        Service::new(
+                            service_cfg,
+                            connection_map.clone(),
+                            service_ports.clone(),
+                            flow_tracker.clone(),
+                            scaler.clone(),
+                            shards.clone(),
+                            timers.clone(),
+                        ),
+                        cfg.queues.service,
+                    ),
+                );
+            }
+        });
+        let readiness = Readiness::default();
+        readiness.set_populated();
+
+        if let Some(stuck_cfg) = cfg.stuck.clone() {
+            tokio::spawn(stuck.watch_forever(tcp_service_map.clone(), stuck_cfg));
+        }
+        tokio::spawn(follow_evictions(
+            evicted,
+            tcp_service_map.clone(),
+            udp_service_map.clone(),
+        ));
+        if let Some(reconcile_cfg) = cfg.reconcile.clone() {
+            tokio::spawn(reconciler.reconcile_forever(
+                tcp_service_map.clone(),
+                connection_map.clone(),
+                flow_tracker.clone(),
+                reconcile_cfg,
+            ));
+        }
+        if let Some(auto_block_cfg) = cfg.auto_block.clone() {
+            tokio::spawn(blocklist.watch_forever(tcp_service_map.clone(), auto_block_cfg));
+        }
+        if let Some(federation_cfg) = cfg.federation.clone() {
+            let listener = Federation::bind(&federation_cfg).await?;
+            let federation = Federation::new(control.clone(), &federation_cfg);
+            tokio::spawn(federation.clone().serve_forever(listener));
+            if !federation_cfg.peers.is_empty() {
+                tokio::spawn(federation.scrape_forever(federation_cfg));
+            }
+        }
+        if let Some(health_cfg) = cfg.health.clone() {
+            let listener = Health::bind(&health_cfg).await?;
+            let health = Health::new(
+                control.clone(),
+                manager.clone(),
+                readiness.clone(),
+                &health_cfg,
+            );
+            tokio::spawn(health.serve_forever(listener));
+        }
+        for service_cfg in cfg.services.iter().filter(|s| s.routes_by_name()) {
+            let router = HostRouter::new(service_cfg, &cfg.http_routing)?;
+            let listener = router.bind().await?;
+            tokio::spawn(router.serve_forever(listener));
+        }
+        if let Some(fallback_cfg) = cfg.fallback.as_ref() {
+            // the fallback flows of a service whose ip is not one of this
+            // host go nowhere, the host stack turns them away
+            let services = cfg.services.iter().filter(|s| !s.routes_by_name());
+            for service_cfg in services.filter(|s| !s.servers.is_empty()) {
+                for service_cfg in service_cfg.per_protocol().iter() {
+                    if let Err(e) = FallbackProxy::new(service_cfg, fallback_cfg)?.start().await {
+                        warn!("{}", e);
+                    }
+                }
+            }
+        }
+
+        let pending_tracker = Arc::new(Mutex::new(PendingConnTracker::new(
+            cfg.services
+                .iter()
+                .filter_map(|service_cfg| {
+                    let grace = service_cfg.syn_grace?;
+                    let local_endpoint = service_cfg.local_endpoint.parse::<Endpoint>().ok()?;
+                    Some((local_endpoint, grace))
+                })
+                .collect(),
+        )));
+
+        let sequencer: Arc<Mutex<Sequencer<Notification>>> = Arc::default();
+        let service_queue = cfg.queues.service;
+
+        tokio::spawn(splits.clone().ramp_forever());
+        let splits_cold_start = splits.clone();
+        let installer = Installer {
+            connection_map: connection_map.clone(),
+            port_pool: service_ports.clone(),
+            flow_tracker: flow_tracker.clone(),
+            scaler: scaler.clone(),
+            shards: shards.clone(),
+            timers: timers.clone(),
+            sequencer: sequencer.clone(),
+            server_map: server_map.clone(),
+            epoch_map: epoch_map.clone(),
+            backend_ips: backend_ips.clone(),
+            splits,
+            tcp_services: tcp_service_map.clone(),
+            udp_services: udp_service_map.clone(),
+            ports: cfg.ports.clone(),
+            queues: cfg.queues.clone(),
+        };
+        let admin_listener = Admin::bind(&cfg.admin)?;
+        let admin = Admin::new(
+            control.clone(),
+            installer,
+            connection_map.clone(),
+            server_map.clone(),
+            epoch_map.clone(),
+            draining_map,
+            pcap,
+        );
+        tokio::spawn(admin.serve_forever(admin_listener));
+
+        // cold start services asking for warm backends get them before any client
+        let mut warm_handles = vec![];
+        for service_cfg in cfg.services.iter() {
+            if service_cfg.min_warm == 0 || !service_cfg.servers.is_empty() {
+                continue;
+            }
+            let e = match service_cfg.local_endpoint.parse::<Endpoint>() {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            let service_cfg = service_cfg.clone();
+            let server_map = server_map.clone();
+            let epoch_map = epoch_map.clone();
+            let backend_ips = backend_ips.clone();
+            let sequencer = sequencer.clone();
+            let tcp_services = tcp_service_map.clone();
+            let udp_services = udp_service_map.clone();
+            let bpf_connection_map = connection_map.clone();
+            let port_pool = service_ports.clone();
+            let flow_tracker = flow_tracker.clone();
+            let scaler = scaler.clone();
+            let shards = shards.clone();
+            let timers = timers.clone();
+            let providers = providers.clone();
+            let removal = removal.clone();
+            warm_handles.push(tokio::spawn(async move {
+                let backends = warm_up(&providers, &service_cfg).await;
+                if backends.is_empty() {
+                    warn!(
+                        "no warm backend of {} came up, it cold starts on demand",
+                        service_cfg.name
+                    );
+                    return;
+                }
+                info!(
+                    "{} warm backends of {} up, {} asked for",
+                    backends.len(),
+                    service_cfg.name,
+                    service_cfg.min_warm
+                );
+                let warm_cfg = ServiceConfig {
+                    servers: backends.iter().map(|b| b.to_string()).collect(),
+                    ..service_cfg.clone()
+                };
+                let services = warm_cfg
+                    .per_protocol()
+                    .iter()
+                    .map(|cfg| {
+                        let service = Service::new(
+                            cfg,
+                            bpf_connection_map.clone(),
+                            port_pool.clone(),
+                            flow_tracker.clone(),
+                            scaler.clone(),
+                            shards.clone(),
+                            timers.clone(),
+                        );
+                        (cfg.is_tcp, service)
+                    })
+                    .collect();
+                let epoch = sequencer.lock().await.next_epoch(&e);
+                if let Err(err) = install_service(
+                    e,
+                    backends[0],
+                    services,
+                    epoch,
+                    &server_map,
+                    &epoch_map,
+                    &backend_ips,
+                    &tcp_services,
+                    &udp_services,
+                    service_queue,
+                )
+                .await
+                {
+                    warn!(
+                        "failed to route {} to its warm backends: {}",
+                        service_cfg.name, err
+                    );
+                    return;
+                }
+                // nothing was routed to the service before, there is nothing to replay
+                sequencer.lock().await.finish(e, epoch);
+                if !service_cfg.keeps_warm() {
+                    stop_when_idle(e, scaler, removal).await;
+                }
+            }));
+        }
+        let keep_warm: HashSet<Endpoint> = cfg
+            .services
+            .iter()
+            .filter(|service_cfg| service_cfg.keeps_warm())
+            .filter_map(|service_cfg| service_cfg.local_endpoint.parse::<Endpoint>().ok())
+            .collect();
+        let keep_warm = Arc::new(keep_warm);
+        let metadata: HashMap<Endpoint, BTreeMap<String, String>> = cfg
+            .services
+            .iter()
+            .filter_map(|service_cfg| {
+                let e = service_cfg.local_endpoint.parse::<Endpoint>().ok()?;
+                Some((e, service_cfg.metadata.clone()))
+            })
+            .collect();
+        let metadata = Arc::new(metadata);
+        let probes: HashMap<Endpoint, StartupProbeConfig> = cfg
+            .services
+            .iter()
+            .filter(|service_cfg| service_cfg.served_protocols().iter().any(|p| p.is_tcp()))
+            .filter_map(|service_cfg| {
+                let e = service_cfg.local_endpoint.parse::<Endpoint>().ok()?;
+                Some((e, service_cfg.startup_probe.clone()?))
+            })
+            .collect();
+        let probes = Arc::new(probes);
+        // the others are served over the protocol of their first packet
+        let protocols: HashMap<Endpoint, Vec<ServiceProtocol>> = cfg
+            .services
+            .iter()
+            .filter(|service_cfg| !service_cfg.protocols.is_empty())
+            .filter_map(|service_cfg| {
+                let e = service_cfg.local_endpoint.parse::<Endpoint>().ok()?;
+                Some((e, service_cfg.served_protocols()))
+            })
+            .collect();
+        let protocols = Arc::new(protocols);
+
+        let tcp_service_map_clod_start = tcp_service_map.clone();
+        let udp_service_map_clod_start = udp_service_map.clone();
+        let bpf_conn_map_clod_start = connection_map.clone();
+        let port_pool_cold_start = service_ports.clone();
+        let flow_tracker_cold_start = flow_tracker.clone();
+        let scaler_cold_start = scaler.clone();
+        let shards_cold_start = shards.clone();
+        let timers_cold_start = timers.clone();
+        let epoch_map_cold_start = epoch_map.clone();
+        let backend_ips_cold_start = backend_ips.clone();
+        let sequencer_cold_start = sequencer.clone();
+        let failures_cold_start = ColdStartFailures::new(cold_start_failed, cfg.reject.as_ref());
+        let reports_cold_start = ColdStartReports::new(cold_start_pending);
+        let metrics_cold_start = cold_starts.clone();
+        let heartbeat = readiness.cold_starts.clone();
+        let cold_start_handle = tokio::spawn(async move {
+            let mut backoff = PollBackoff::default();
+            loop {
+                heartbeat.beat();
+                if let Some(item) = cold_start.next() {
+                    backoff.found();
+                    let cold = KColdStart::from_bytes(item.deref());
+                    let is_tcp = cold.is_tcp != 0;
+                    let e = Endpoint::new(cold.way.to);
+                    let served = protocols.get(&e).map_or(true, |protocols| {
+                        protocols.contains(&ServiceProtocol::from_is_tcp(is_tcp))
+                    });
+                    if !served {
+                        debug!(
+                            "{} is not served over {}, no cold start",
+                            e.to_string(),
+                            if is_tcp { "tcp" } else { "udp" }
+                        );
+                        continue;
+                    }
+                    if is_tcp {
+                        pending_tracker.lock().await.record_syn(
+                            e,
+                            Endpoint::new(cold.way.from),
+                            Instant::now(),
+                        );
+                    }
+                    // every retransmitted syn reports the cold start again
+                    if !sequencer_cold_start.lock().await.begin(e) {
+                        continue;
+                    }
+                    let server_map = server_map.clone();
+                    let epoch_map = epoch_map_cold_start.clone();
+                    let backend_ips = backend_ips_cold_start.clone();
+                    let sequencer = sequencer_cold_start.clone();
+                    let tcp_services = tcp_service_map_clod_start.clone();
+                    let udp_services = udp_service_map_clod_start.clone();
+                    let protocols = protocols
+                        .get(&e)
+                        .cloned()
+                        .unwrap_or_else(|| vec![ServiceProtocol::from_is_tcp(is_tcp)]);
+                    let bpf_connection_map = bpf_conn_map_clod_start.clone();
+                    let port_pool = port_pool_cold_start.clone();
+                    let scaler = scaler_cold_start.clone();
+                    let shards = shards_cold_start.clone();
+                    let timers = timers_cold_start.clone();
+                    let pending_tracker = pending_tracker.clone();
+                    let flow_tracker = flow_tracker_cold_start.clone();
+                    let keep_warm = keep_warm.clone();
+                    let metadata = metadata.get(&e).cloned().unwrap_or_default();
+                    let probe = probes.get(&e).cloned();
+                    let providers = providers.clone();
+                    let removal = removal.clone();
+                    let failures = failures_cold_start.clone();
+                    let reports = reports_cold_start.clone();
+                    let metrics = metrics_cold_start.clone();
+                    let splits = splits_cold_start.clone();
+                    metrics.started(&e);
+                    let begun = Instant::now();
+                    tokio::spawn(async move {
+                        let started = providers.start_server(e.to_string(), &metadata).await;
+                        let service_cfg = match started {
+                            // the provider does not know, the first packet does
+                            Ok(Some(service_cfg)) => ServiceConfig {
+                                is_tcp,
+                                protocols,
+                                ..service_cfg
+                            },
+                            Ok(None) => {
+                                let clients = pending_tracker.lock().await.server_failed(&e);
+                                reports.over(&e, &clients).await;
+                                failures.failed(&e).await;
+                                metrics.failed(&e);
+                                sequencer.lock().await.abort(&e);
+                                return;
+                            }
+                            Err(err) => {
+                                warn!("failed to start server {}: {}", e.to_string(), err);
+                                let clients = pending_tracker.lock().await.server_failed(&e);
+                                reports.over(&e, &clients).await;
+                                failures.failed(&e).await;
+                                metrics.failed(&e);
+                                sequencer.lock().await.abort(&e);
+                                return;
+                            }
+                        };
+                        let server_endpoint = match service_cfg
+                            .servers
+                            .first()
+                            .map(|server| server.parse::<Endpoint>())
+                        {
+                            Some(Ok(server_endpoint)) => server_endpoint,
+                            _ => {
+                                warn!(
+                                    "no usable backend was started for {}: {:?}",
+                                    e.to_string(),
+                                    service_cfg.servers
+                                );
+                                let clients = pending_tracker.lock().await.server_failed(&e);
+                                reports.over(&e, &clients).await;
+                                failures.failed(&e).await;
+                                metrics.failed(&e);
+                                sequencer.lock().await.abort(&e);
+                                return;
+                            }
+                        };
+                        // the syns of the clients keep being dropped, or held,
+                        // until the backend answers
+                        let unready = match &probe {
+                            Some(probe) if !wait_ready(&server_endpoint, probe, &timers).await => {
+                                Some(probe.deadline_ms)
+                            }
+                            _ => None,
+                        };
+                        if let Some(deadline_ms) = unready {
+                            warn!(
+                                "backend {} of {} not ready within {}ms",
+                                server_endpoint.to_string(),
+                                e.to_string(),
+                                deadline_ms
+                            );
+                            // a backend that never comes up is of no use to the next client
+                            if let Err(err) = providers.stop_server(e.to_string()).await {
+                                warn!("failed to stop server {}: {}", e.to_string(), err);
+                            }
+                            let clients = pending_tracker.lock().await.server_failed(&e);
+                            reports.over(&e, &clients).await;
+                            failures.failed(&e).await;
+                            metrics.failed(&e);
+                            sequencer.lock().await.abort(&e);
+                            return;
+                        }
+                        let services = service_cfg
+                            .per_protocol()
+                            .iter()
+                            .map(|cfg| {
+                                let service = Service::new(
+                                    cfg,
+                                    bpf_connection_map.clone(),
+                                    port_pool.clone(),
+                                    flow_tracker.clone(),
+                                    scaler.clone(),
+                                    shards.clone(),
+                                    timers.clone(),
+                                );
+                                (cfg.is_tcp, service)
+                            })
+                            .collect();
+                        let epoch = sequencer.lock().await.next_epoch(&e);
+                        if let Err(err) = install_service(
+                            e,
+                            server_endpoint,
+                            services,
+                            epoch,
+                            &server_map,
+                            &epoch_map,
+                            &backend_ips,
+                            &tcp_services,
+                            &udp_services,
+                            service_queue,
+                        )
+                        .await
+                        {
+                            warn!("failed to route {}: {}", e.to_string(), err);
+                            let clients = pending_tracker.lock().await.server_failed(&e);
+                            reports.over(&e, &clients).await;
+                            failures.failed(&e).await;
+                            metrics.failed(&e);
+                            sequencer.lock().await.abort(&e);
+                            return;
+                        }
+
+                        {
+                            // new events of the service wait until the replay is done
+                            let mut sequencer = sequencer.lock().await;
+                            let replay = sequencer.finish(e, epoch);
+                            if !replay.is_empty() {
+                                info!(
+                                    "replaying {} packet event(s) of {} read before it was ready",
+                                    replay.len(),
+                                    e.to_string()
+                                );
+                            }
+                            for notification in replay {
+                                dispatch(notification, &tcp_services, &udp_services, false).await;
+                            }
+                        }
+
+                        // a backend next to pools does not get its whole share at once
+                        if let Err(err) = splits.ramp_up(e, server_endpoint) {
+                            warn!("failed to ramp up {}: {}", server_endpoint.to_string(), err);
+                        }
+                        metrics.succeeded(&e, begun.elapsed());
+                        let (clients, outcomes) = {
+                            let mut pending_tracker = pending_tracker.lock().await;
+                            let clients = pending_tracker.clients(&e);
+                            flow_tracker.cold_started(clients.clone());
+                            (clients, pending_tracker.server_ready(&e, Instant::now()))
+                        };
+                        reports.over(&e, &clients).await;
+                        info!(
+                            "server {} ready, clients served in time: {}, clients likely gave up: {}",
+                            e.to_string(),
+                            outcomes.ready_in_time,
+                            outcomes.client_gave_up
+                        );
+
+                        if keep_warm.contains(&e) {
+                            return;
+                        }
+                        stop_when_idle(e, scaler, removal).await;
+                    });
+                } else {
+                    backoff.wait().await;
+                }
+            }
+        });
+
+        // deal with packets to drive state machine
+        let shed = cfg.queues.shed;
+        let workers = EventWorkers::spawn(cfg.event_workers, move |notification: Notification| {
+            let sequencer = sequencer.clone();
+            let tcp_service_map = tcp_service_map.clone();
+            let udp_service_map = udp_service_map.clone();
+            async move {
+                let admitted = {
+                    let mut sequencer = sequencer.lock().await;
+                    let key = sequence_key(&sequencer, &notification);
+                    sequencer.admit(key, notification.epoch, notification)
+                };
+                match admitted {
+                    Admit::Deliver(notification) => {
+                        dispatch(notification, &tcp_service_map, &udp_service_map, shed).await
+                    }
+                    // replayed once the cold start of its service is done
+                    Admit::Deferred => {}
+                    Admit::Stale => {
+                        debug!("drop packet event of an earlier backend: {}", notification)
+                    }
+                }
+            }
+        });
+        let heartbeat = readiness.packet_events.clone();
+        let packet_handle = tokio::spawn(async move {
+            let mut backoff = PollBackoff::default();
+            loop {
+                heartbeat.beat();
+                if let Some(item) = packet_event.next() {
+                    backoff.found();
+                    let notification = match Notification::parse(item.deref()) {
+                        Ok(notification) => notification,
+                        Err(e) => {
+                            warn!("drop malformed packet event: {}", e);
+                            continue;
+                        }
+                    };
+                    workers
+                        .send(&connection_key(&notification), notification)
+                        .await;
+                } else {
+                    backoff.wait().await;
+                }
+            }
+        });
+
+        let notifier = Notifier::from_env();
+        notifier.ready(&format!(
+            "attached to {}, {} services routed",
+            attach_report.attached().count(),
+            control.services_report().await.services.len()
+        ));
+        tokio::spawn(notifier.clone().watchdog_forever(readiness));
+
+        tokio::pin!(shutdown);
+        tokio::select! {
+            _ = &mut shutdown => {}
+            _ = iface_watch.follow(program, xdp_flags, &pins, &mut xdp_links, &info) => {}
+        }
+
+        notifier.stopping();
+
+        warm_handles.iter().for_each(|handle| handle.abort());
+        cold_start_handle.abort();
+        info!("Waiting for cold start to finish...");
+        packet_handle.abort();
+        info!("Waiting for packet handle to finish...");
+
+        // the process taking over would never see them again
+        service_ports.flush_quarantine().await;
+        pins.release(xdp_links).log();
+        let _ = std::fs::remove_file(&cfg.admin.socket);
+        info.set_interfaces(vec![]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+use std::{fmt, io};
+
+use aya::{
+    maps::{Map, MapError},
+    programs::ProgramError,
+    Bpf, BpfError,
+};
+use folonet_client::error::ClientError;
+use folonet_common::MAP_SCHEMA_VERSION;
+
+#[derive(Debug)]
+pub enum FolonetError {
+    Io {
+        context: String,
+        source: io::Error,
+    },
+    Config(String),
+    Bpf(BpfError),
+    MapNotFound(&'static str),
+    Map {
+        name: &'static str,
+        source: MapError,
+    },
+    ProgramNotFound(&'static str),
+    Program {
+        name: &'static str,
+        source: ProgramError,
+    },
+    NoInterfaceAttached,
+    PortBusy(u16),
+    Manager(ClientError),
+    Encode(String),
+    // the daemon refused an admin request, or could not be reached
+    Admin(String),
+    // a step of `folonet demo` failed
+    Demo(String),
+    // the pinned maps are of another MAP_SCHEMA_VERSION, none when they
+    // predate it
+    IncompatibleMaps(Option<u32>),
+}
+
+impl fmt::Display for FolonetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FolonetError::Io { context, source } => write!(f, "{}: {}", context, source),
+            FolonetError::Config(msg) => write!(f, "invalid config: {}", msg),
+            FolonetError::Bpf(e) => write!(f, "failed to load the eBPF object: {}", e),
+            FolonetError::MapNotFound(name) => write!(f, "map {} not found", name),
+            FolonetError::Map { name, source } => write!(f, "map {}: {}", name, source),
+            FolonetError::ProgramNotFound(name) => write!(f, "program {} not found", name),
+            FolonetError::Program { name, source } => write!(f, "program {}: {}", name, source),
+            FolonetError::NoInterfaceAttached => {
+                write!(f, "the XDP program is not attached to any interface")
+            }
+            FolonetError::PortBusy(port) => write!(f, "port {} is not free", port),
+            FolonetError::Manager(e) => write!(f, "server manager: {}", e),
+            FolonetError::Encode(msg) => write!(f, "failed to encode output: {}", msg),
+            FolonetError::Admin(msg) => write!(f, "admin request failed: {}", msg),
+            FolonetError::Demo(msg) => write!(f, "demo: {}", msg),
+            FolonetError::IncompatibleMaps(found) => write!(
+                f,
+                "the pinned maps are of schema version {}, this folonet needs {}, \
+                 stop the datapath using them first",
+                found.map_or("unknown".to_string(), |v| v.to_string()),
+                MAP_SCHEMA_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FolonetError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FolonetError::Io { source, .. } => Some(source),
+            FolonetError::Bpf(e) => Some(e),
+            FolonetError::Map { source, .. } => Some(source),
+            FolonetError::Program { source, .. } => Some(source),
+            FolonetError::Manager(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<BpfError> for FolonetError {
+    fn from(e: BpfError) -> Self {
+        FolonetError::Bpf(e)
+    }
+}
+
+pub fn take_raw_map(bpf: &mut Bpf, name: &'static str) -> Result<Map, FolonetError> {
+    bpf.take_map(name).ok_or(FolonetError::MapNotFound(name))
+}
+
+pub fn take_map<T>(bpf: &mut Bpf, name: &'static str) -> Result<T, FolonetError>
+where
+    T: TryFrom<Map, Error = MapError>,
+{
+    let map = take_raw_map(bpf, name)?;
+    T::try_from(map).map_err(|source| FolonetError::Map { name, source })
+}
+
+pub trait MapResultExt<T> {
+    fn map_context(self, name: &'static str) -> Result<T, FolonetError>;
+}
+
+impl<T> MapResultExt<T> for Result<T, MapError> {
+    fn map_context(self, name: &'static str) -> Result<T, FolonetError> {
+        self.map_err(|source| FolonetError::Map { name, source })
+    }
+}
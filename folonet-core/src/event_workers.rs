@@ -0,0 +1,103 @@
+use std::future::Future;
+use std::hash::Hash;
+
+use folonet_common::Notification;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::endpoint::Endpoint;
+use crate::sharded::shard_of;
+
+const CHANNEL_SIZE: usize = 10240;
+
+// the same for both ways of a connection, client -> backend and back
+pub fn connection_key(notification: &Notification) -> (Endpoint, Endpoint) {
+    let from = Endpoint::new(notification.connection.from);
+    let to = Endpoint::new(notification.connection.to);
+    (from.min(to), from.max(to))
+}
+
+// Handles events on a fixed number of tasks. The events of one key always go
+// to the same task, in the order they were sent, and the events of different
+// keys are handled in parallel.
+pub struct EventWorkers<T> {
+    senders: Vec<mpsc::Sender<T>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> EventWorkers<T> {
+    pub fn spawn<F, Fut>(workers: usize, handle: F) -> Self
+    where
+        F: Fn(T) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (senders, tasks) = (0..workers.max(1))
+            .map(|_| {
+                let (sender, mut receiver) = mpsc::channel::<T>(CHANNEL_SIZE);
+                let handle = handle.clone();
+                let task = tokio::spawn(async move {
+                    while let Some(event) = receiver.recv().await {
+                        handle(event).await;
+                    }
+                });
+                (sender, task)
+            })
+            .unzip();
+        EventWorkers { senders, tasks }
+    }
+
+    // waits while the worker of `key` is behind
+    pub async fn send(&self, key: &impl Hash, event: T) {
+        let sender = &self.senders[shard_of(key, self.senders.len())];
+        // a worker only stops with the pool
+        let _ = sender.send(event).await;
+    }
+}
+
+impl<T> Drop for EventWorkers<T> {
+    fn drop(&mut self) {
+        self.tasks.iter().for_each(|task| task.abort());
+    }
+}
+
+mod test {
+
+    #[tokio::test]
+    async fn test_event_workers() {
+        use std::collections::HashMap;
+        use std::sync::{Arc, Mutex};
+
+        use tokio::time::{sleep, Duration};
+
+        use super::EventWorkers;
+
+        let seen: Arc<Mutex<Vec<(u32, u32)>>> = Arc::default();
+        let workers = {
+            let seen = seen.clone();
+            EventWorkers::spawn(4, move |event: (u32, u32)| {
+                let seen = seen.clone();
+                async move {
+                    // some workers behind the others
+                    sleep(Duration::from_micros(event.0 as u64 * 10)).await;
+                    seen.lock().unwrap().push(event);
+                }
+            })
+        };
+        for seq in 0..20 {
+            for key in 0..8 {
+                workers.send(&key, (key, seq)).await;
+            }
+        }
+        while seen.lock().unwrap().len() < 160 {
+            sleep(Duration::from_millis(1)).await;
+        }
+
+        let mut last: HashMap<u32, u32> = HashMap::new();
+        for (key, seq) in seen.lock().unwrap().iter() {
+            if let Some(before) = last.insert(*key, *seq) {
+                assert_eq!(*seq, before + 1);
+            }
+        }
+        assert_eq!(last.len(), 8);
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use folonet_client::config::{FallbackConfig, ServiceConfig};
+use log::{debug, info, warn};
+use tokio::io::copy_bidirectional;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::time::{timeout, Duration, Instant};
+
+use crate::endpoint::Endpoint;
+use crate::error::FolonetError;
+use crate::route::Pool;
+
+const MAX_DATAGRAM: usize = 65535;
+
+// a udp client of the service, with the socket its datagrams go on from
+struct UdpClient {
+    upstream: Arc<UdpSocket>,
+    last_seen: Instant,
+}
+
+type UdpClients = Arc<Mutex<HashMap<SocketAddr, UdpClient>>>;
+
+// Proxies the flows of a service the xdp program cannot rewrite to the
+// servers of the service. They reach it through the host stack, the others
+// never leave the xdp program, so it listens on the endpoint of the service
+// itself.
+#[derive(Clone)]
+pub struct FallbackProxy {
+    name: String,
+    local_endpoint: Endpoint,
+    is_tcp: bool,
+    servers: Arc<Pool>,
+    cfg: FallbackConfig,
+}
+
+impl FallbackProxy {
+    pub fn new(service: &ServiceConfig, cfg: &FallbackConfig) -> Result<Self, FolonetError> {
+        Ok(FallbackProxy {
+            name: service.name.clone(),
+            local_endpoint: service.local_endpoint.parse::<Endpoint>()?,
+            is_tcp: service.is_tcp,
+            servers: Arc::new(Pool::new(&service.servers)?),
+            cfg: cfg.clone(),
+        })
+    }
+
+    fn bind_error(&self, source: std::io::Error) -> FolonetError {
+        FolonetError::Io {
+            context: format!(
+                "failed to listen on {} for the fallback of {}",
+                self.local_endpoint.to_string(),
+                self.name
+            ),
+            source,
+        }
+    }
+
+    // listen on the endpoint of the service and proxy from then on
+    pub async fn start(self) -> Result<(), FolonetError> {
+        let addr = self.local_endpoint.to_string();
+        if self.is_tcp {
+            let listener = TcpListener::bind(&addr)
+                .await
+                .map_err(|e| self.bind_error(e))?;
+            info!("proxying the fallback flows of {} on {}", self.name, addr);
+            tokio::spawn(self.serve_tcp_forever(listener));
+        } else {
+            let socket = UdpSocket::bind(&addr)
+                .await
+                .map_err(|e| self.bind_error(e))?;
+            info!("proxying the fallback flows of {} on {}", self.name, addr);
+            tokio::spawn(self.serve_udp_forever(Arc::new(socket)));
+        }
+        Ok(())
+    }
+
+    async fn serve_tcp_forever(self, listener: TcpListener) {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("failed to accept a fallback flow of {}: {}", self.name, e);
+                    continue;
+                }
+            };
+            let proxy = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = proxy.proxy_tcp(stream).await {
+                    debug!("failed to proxy {} to {}: {}", addr, proxy.name, e);
+                }
+            });
+        }
+    }
+
+    async fn proxy_tcp(&self, mut client: TcpStream) -> std::io::Result<()> {
+        let backend = match self.servers.pick() {
+            Some(backend) => backend,
+            None => return Ok(()),
+        };
+        let connect_timeout = Duration::from_millis(self.cfg.connect_timeout_ms);
+        let mut upstream =
+            match timeout(connect_timeout, TcpStream::connect(backend.to_string())).await {
+                Ok(upstream) => upstream?,
+                Err(_) => return Ok(()),
+            };
+        copy_bidirectional(&mut client, &mut upstream).await?;
+        Ok(())
+    }
+
+    async fn serve_udp_forever(self, socket: Arc<UdpSocket>) {
+        let clients: UdpClients = Arc::default();
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let (len, client) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("failed to receive a fallback flow of {}: {}", self.name, e);
+                    continue;
+                }
+            };
+            let upstream = match self.upstream_of(&socket, &clients, client).await {
+                Ok(Some(upstream)) => upstream,
+                Ok(None) => continue,
+                Err(e) => {
+                    debug!("failed to proxy {} to {}: {}", client, self.name, e);
+                    continue;
+                }
+            };
+            if let Err(e) = upstream.send(&buf[..len]).await {
+                debug!("failed to proxy {} to {}: {}", client, self.name, e);
+            }
+        }
+    }
+
+    // the socket toward the backend of `client`, a new one for a new client,
+    // none when the service has no servers
+    async fn upstream_of(
+        &self,
+        socket: &Arc<UdpSocket>,
+        clients: &UdpClients,
+        client: SocketAddr,
+    ) -> std::io::Result<Option<Arc<UdpSocket>>> {
+        let known = clients.lock().unwrap().get_mut(&client).map(|known| {
+            known.last_seen = Instant::now();
+            known.upstream.clone()
+        });
+        if known.is_some() {
+            return Ok(known);
+        }
+
+        let backend = match self.servers.pick() {
+            Some(backend) => backend,
+            None => return Ok(None),
+        };
+        let upstream = UdpSocket::bind("0.0.0.0:0").await?;
+        upstream.connect(backend.to_string()).await?;
+        let upstream = Arc::new(upstream);
+        clients.lock().unwrap().insert(
+            client,
+            UdpClient {
+                upstream: upstream.clone(),
+                last_seen: Instant::now(),
+            },
+        );
+        tokio::spawn(relay_replies(
+            socket.clone(),
+            clients.clone(),
+            client,
+            upstream.clone(),
+            Duration::from_secs(self.cfg.udp_idle_secs),
+        ));
+        Ok(Some(upstream))
+    }
+}
+
+// the datagrams of the backend back to `client`, until it has been idle
+// both ways for `idle`
+async fn relay_replies(
+    socket: Arc<UdpSocket>,
+    clients: UdpClients,
+    client: SocketAddr,
+    upstream: Arc<UdpSocket>,
+    idle: Duration,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        match timeout(idle, upstream.recv(&mut buf)).await {
+            Ok(Ok(len)) => {
+                if let Some(known) = clients.lock().unwrap().get_mut(&client) {
+                    known.last_seen = Instant::now();
+                }
+                if let Err(e) = socket.send_to(&buf[..len], client).await {
+                    debug!("failed to send a reply to {}: {}", client, e);
+                }
+            }
+            Ok(Err(e)) => {
+                debug!("failed to receive a reply for {}: {}", client, e);
+                break;
+            }
+            Err(_) => {
+                let seen = clients
+                    .lock()
+                    .unwrap()
+                    .get(&client)
+                    .map(|known| known.last_seen.elapsed() < idle);
+                if seen != Some(true) {
+                    break;
+                }
+            }
+        }
+    }
+    clients.lock().unwrap().remove(&client);
+}
+
+mod test {
+
+    #[tokio::test]
+    async fn test_udp_fallback() {
+        use folonet_client::config::{FallbackConfig, ServiceConfig};
+        use tokio::net::UdpSocket;
+
+        use super::FallbackProxy;
+
+        let backend = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let free = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let local_endpoint = free.local_addr().unwrap().to_string();
+        drop(free);
+
+        let service = ServiceConfig {
+            name: "echo".to_string(),
+            local_endpoint: local_endpoint.clone(),
+            servers: vec![backend.local_addr().unwrap().to_string()],
+            ..Default::default()
+        };
+        FallbackProxy::new(&service, &FallbackConfig::default())
+            .unwrap()
+            .start()
+            .await
+            .unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"ping", &local_endpoint).await.unwrap();
+
+        let mut buf = [0u8; 16];
+        let (len, from) = backend.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        backend.send_to(b"pong", from).await.unwrap();
+
+        let (len, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"pong");
+        assert_eq!(from.to_string(), local_endpoint);
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CStr;
+use std::sync::Arc;
+
+use folonet_client::config::FederationConfig;
+use folonet_client::http::{get, https_client, HttpsClient};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio::sync::RwLock;
+use tokio::time::{sleep, timeout, Duration};
+
+use crate::control::Control;
+use crate::error::FolonetError;
+use crate::http::{self, Response};
+use crate::output::{Protocol, ServiceRow};
+use crate::stats::IfaceStats;
+
+// what one node serves at /stats
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeStats {
+    pub node: String,
+    pub services: Vec<ServiceRow>,
+    pub counters: BTreeMap<String, u64>,
+    // left out by nodes from before they were served
+    #[serde(default)]
+    pub interfaces: Vec<IfaceStats>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FleetService {
+    pub name: String,
+    pub protocol: Protocol,
+    // the nodes running it
+    pub nodes: Vec<String>,
+    pub backends: usize,
+    pub connections: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PeerStatus {
+    pub peer: String,
+    // of the last scrape, the peer is left out of the merged view
+    pub error: Option<String>,
+}
+
+// what a node with peers serves at /federated
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct FleetStats {
+    pub nodes: Vec<String>,
+    pub peers: Vec<PeerStatus>,
+    // by name and protocol, a service has the same name on every node
+    pub services: Vec<FleetService>,
+    // summed over the nodes
+    pub counters: BTreeMap<String, u64>,
+}
+
+impl FleetStats {
+    pub fn merge(nodes: &[NodeStats]) -> Self {
+        let mut fleet = FleetStats::default();
+        let mut services: BTreeMap<(String, &'static str), FleetService> = BTreeMap::new();
+        for node in nodes {
+            fleet.nodes.push(node.node.clone());
+            for row in node.services.iter() {
+                let service = services
+                    .entry((row.name.clone(), row.protocol.as_str()))
+                    .or_insert_with(|| FleetService {
+                        name: row.name.clone(),
+                        protocol: row.protocol,
+                        nodes: vec![],
+                        backends: 0,
+                        connections: 0,
+                    });
+                service.nodes.push(node.node.clone());
+                service.backends += row.backends.len();
+                service.connections += row.connections;
+            }
+            for (name, v) in node.counters.iter() {
+                *fleet.counters.entry(name.clone()).or_default() += v;
+            }
+        }
+        fleet.services = services.into_values().collect();
+        fleet
+    }
+}
+
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return None;
+    }
+    let name = CStr::from_bytes_until_nul(&buf).ok()?;
+    Some(name.to_string_lossy().into_owned())
+}
+
+async fn node_stats(control: &Control, node: &str) -> NodeStats {
+    NodeStats {
+        node: node.to_string(),
+        services: control.services_report().await.services,
+        counters: control
+            .counters()
+            .into_iter()
+            .map(|(name, v)| (name.to_string(), v))
+            .collect(),
+        interfaces: control.iface_stats(),
+    }
+}
+
+async fn fetch(client: &HttpsClient, peer: &str) -> Result<NodeStats, String> {
+    let (status, body) = get(client, &format!("http://{}/stats", peer))
+        .await
+        .map_err(|e| e.to_string())?;
+    if status != 200 {
+        return Err(format!("unexpected status {}", status));
+    }
+    serde_json::from_slice(&body).map_err(|e| e.to_string())
+}
+
+// Serves the stats of this node and, with peers configured, scrapes theirs
+// every `interval_secs`. Read only: nothing served changes the node.
+#[derive(Clone)]
+pub struct Federation {
+    control: Control,
+    node: String,
+    // by peer, the stats or the error of its last scrape
+    peers: Arc<RwLock<HashMap<String, Result<NodeStats, String>>>>,
+}
+
+impl Federation {
+    pub fn new(control: Control, cfg: &FederationConfig) -> Self {
+        let node = cfg
+            .node
+            .clone()
+            .or_else(hostname)
+            .unwrap_or_else(|| cfg.listen.clone());
+        Federation {
+            control,
+            node,
+            peers: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub async fn fleet_stats(&self) -> FleetStats {
+        let mut nodes = vec![node_stats(&self.control, &self.node).await];
+        let mut peers = vec![];
+        for (peer, last) in self.peers.read().await.iter() {
+            if let Ok(stats) = last {
+                nodes.push(stats.clone());
+            }
+            peers.push(PeerStatus {
+                peer: peer.clone(),
+                error: last.as_ref().err().cloned(),
+            });
+        }
+        peers.sort_by(|a, b| a.peer.cmp(&b.peer));
+        FleetStats {
+            peers,
+            ..FleetStats::merge(&nodes)
+        }
+    }
+
+    pub async fn bind(cfg: &FederationConfig) -> Result<TcpListener, FolonetError> {
+        TcpListener::bind(&cfg.listen)
+            .await
+            .map_err(|source| FolonetError::Io {
+                context: format!("failed to listen on {}", cfg.listen),
+                source,
+            })
+    }
+
+    pub async fn serve_forever(self, listener: TcpListener) {
+        http::serve_forever(listener, "stats request", move |path| {
+            let federation = self.clone();
+            async move { federation.answer(&path).await }
+        })
+        .await
+    }
+
+    async fn answer(&self, path: &str) -> Option<Response> {
+        match path {
+            "/stats" => Some(Response::json(
+                "200 OK",
+                &node_stats(&self.control, &self.node).await,
+            )),
+            "/federated" => Some(Response::json("200 OK", &self.fleet_stats().await)),
+            _ => None,
+        }
+    }
+
+    pub async fn scrape_forever(self, cfg: FederationConfig) {
+        let deadline = Duration::from_millis(cfg.timeout_ms);
+        let client = https_client();
+        loop {
+            for peer in cfg.peers.iter() {
+                let stats = match timeout(deadline, fetch(&client, peer)).await {
+                    Ok(stats) => stats,
+                    Err(_) => Err(format!("no answer within {:?}", deadline)),
+                };
+                if let Err(e) = &stats {
+                    debug!("failed to scrape {}: {}", peer, e);
+                }
+                self.peers.write().await.insert(peer.clone(), stats);
+            }
+            sleep(Duration::from_secs(cfg.interval_secs)).await;
+        }
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_merge() {
+        use std::collections::BTreeMap;
+
+        use super::{FleetStats, NodeStats};
+        use crate::output::{Protocol, ServiceRow};
+
+        let row = |backends: usize, connections: u64| ServiceRow {
+            name: "web".to_string(),
+            protocol: Protocol::Tcp,
+            local_endpoint: "10.0.0.1:8080".to_string(),
+            backends: vec!["10.0.1.1:80".to_string(); backends],
+            connections,
+            max_connections: None,
+        };
+        let node = |name: &str, rows: Vec<ServiceRow>, drops: u64| NodeStats {
+            node: name.to_string(),
+            services: rows,
+            counters: BTreeMap::from([("acl_denied".to_string(), drops)]),
+            interfaces: vec![],
+        };
+
+        let fleet = FleetStats::merge(&[
+            node("edge-1", vec![row(1, 10)], 3),
+            node("edge-2", vec![row(2, 5)], 4),
+        ]);
+        assert_eq!(fleet.nodes, vec!["edge-1", "edge-2"]);
+        assert_eq!(fleet.services.len(), 1);
+        assert_eq!(fleet.services[0].nodes, vec!["edge-1", "edge-2"]);
+        assert_eq!(fleet.services[0].backends, 3);
+        assert_eq!(fleet.services[0].connections, 15);
+        assert_eq!(fleet.counters["acl_denied"], 7);
+    }
+}
@@ -0,0 +1,118 @@
+use std::net::IpAddr;
+
+use log::warn;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::tcp::{self, MutableTcpPacket, TcpFlags};
+use pnet::transport::{transport_channel, TransportChannelType};
+
+use crate::endpoint::Endpoint;
+use crate::error::FolonetError;
+
+const IPV4_HEADER_LEN: usize = 20;
+const TCP_HEADER_LEN: usize = 20;
+
+// a fin from `service` to `client` closing their connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FinTarget {
+    pub service: Endpoint,
+    pub client: Endpoint,
+    pub seq: u32,
+    pub ack_seq: u32,
+}
+
+// the ip packet of the fin of `target`
+pub fn build_fin(target: &FinTarget) -> Vec<u8> {
+    let mut buf = vec![0u8; IPV4_HEADER_LEN + TCP_HEADER_LEN];
+    {
+        let mut tcp = MutableTcpPacket::new(&mut buf[IPV4_HEADER_LEN..]).unwrap();
+        tcp.set_source(target.service.port);
+        tcp.set_destination(target.client.port);
+        tcp.set_sequence(target.seq);
+        tcp.set_acknowledgement(target.ack_seq);
+        tcp.set_data_offset((TCP_HEADER_LEN / 4) as u8);
+        tcp.set_flags(TcpFlags::FIN | TcpFlags::ACK);
+        let checksum =
+            tcp::ipv4_checksum(&tcp.to_immutable(), &target.service.ip, &target.client.ip);
+        tcp.set_checksum(checksum);
+    }
+
+    let mut ip = MutableIpv4Packet::new(&mut buf).unwrap();
+    ip.set_version(4);
+    ip.set_header_length((IPV4_HEADER_LEN / 4) as u8);
+    ip.set_total_length((IPV4_HEADER_LEN + TCP_HEADER_LEN) as u16);
+    ip.set_flags(Ipv4Flags::DontFragment);
+    ip.set_ttl(64);
+    ip.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+    ip.set_source(target.service.ip);
+    ip.set_destination(target.client.ip);
+    let checksum = ipv4::checksum(&ip.to_immutable());
+    ip.set_checksum(checksum);
+    buf
+}
+
+// Sends the fins of `targets` through a raw socket, returns how many went
+// out. The clients answer with a fin of their own, which the backend still
+// running resets.
+pub async fn send_fins(targets: Vec<FinTarget>) -> Result<usize, FolonetError> {
+    let sending = tokio::task::spawn_blocking(move || {
+        let (mut tx, _) = transport_channel(
+            4096,
+            TransportChannelType::Layer3(IpNextHeaderProtocols::Tcp),
+        )
+        .map_err(|source| FolonetError::Io {
+            context: "failed to open a raw socket".to_string(),
+            source,
+        })?;
+        let mut sent = 0;
+        for target in targets.iter() {
+            let buf = build_fin(target);
+            let packet = Ipv4Packet::new(&buf).unwrap();
+            match tx.send_to(packet, IpAddr::V4(target.client.ip)) {
+                Ok(_) => sent += 1,
+                Err(e) => warn!("failed to send a fin to {}: {}", target.client, e),
+            }
+        }
+        Ok(sent)
+    });
+    sending.await.map_err(|e| FolonetError::Io {
+        context: "failed to send fins".to_string(),
+        source: std::io::Error::other(e),
+    })?
+}
+
+mod test {
+
+    #[test]
+    fn test_build_fin() {
+        use pnet::packet::ipv4::{self, Ipv4Packet};
+        use pnet::packet::tcp::{self, TcpFlags, TcpPacket};
+        use pnet::packet::Packet;
+
+        use super::{build_fin, FinTarget};
+
+        let target = FinTarget {
+            service: "10.0.0.1:8080".parse().unwrap(),
+            client: "10.0.0.2:40000".parse().unwrap(),
+            seq: 501,
+            ack_seq: 101,
+        };
+        let buf = build_fin(&target);
+        let ip = Ipv4Packet::new(&buf).unwrap();
+        assert_eq!(ip.get_source(), target.service.ip);
+        assert_eq!(ip.get_destination(), target.client.ip);
+        assert_eq!(ip.get_total_length() as usize, buf.len());
+        assert_eq!(ip.get_checksum(), ipv4::checksum(&ip));
+
+        let tcp = TcpPacket::new(ip.payload()).unwrap();
+        assert_eq!(tcp.get_source(), 8080);
+        assert_eq!(tcp.get_destination(), 40000);
+        assert_eq!(tcp.get_sequence(), 501);
+        assert_eq!(tcp.get_acknowledgement(), 101);
+        assert_eq!(tcp.get_flags(), TcpFlags::FIN | TcpFlags::ACK);
+        assert_eq!(
+            tcp.get_checksum(),
+            tcp::ipv4_checksum(&tcp, &target.service.ip, &target.client.ip)
+        );
+    }
+}
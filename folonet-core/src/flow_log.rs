@@ -0,0 +1,467 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use aya::maps::{HashMap as AyaHashMap, MapData as AyaMapData};
+use folonet_client::config::{FlowLogConfig, FlowLogSink};
+use folonet_common::flow::KFlow;
+use log::{error, warn};
+use serde::Serialize;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+use crate::classify::{AppProto, ProtoTags};
+use crate::conn_limit::ConnLimits;
+use crate::endpoint::{Endpoint, UConnection};
+use crate::sink::Sink;
+use crate::trace::Tracer;
+use crate::usage::UsageExporter;
+
+pub type BpfFlowMap = Arc<Mutex<AyaHashMap<AyaMapData, UConnection, KFlow>>>;
+
+// local0.info
+const SYSLOG_PRI: u8 = 134;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    Fin,
+    IdleTimeout,
+    // closed by an operator to free its local port
+    Reclaimed,
+    // closed by the sweep of connections stuck in a handshake or close state
+    Stuck,
+    // its client sent a syn and the connection did not get established in
+    // time
+    HandshakeTimeout,
+    // closed by the reconciliation of the nat entries with the state machines
+    Reconciled,
+    // the kernel evicted its nat entries to make room for new connections
+    Evicted,
+    // its flow was idle or gone for long while the state machine kept it, or
+    // its service tracked too many connections
+    Reaped,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FlowRecord {
+    // unix time in milliseconds when the connection was closed
+    pub ts: u64,
+    pub service: String,
+    pub client: String,
+    pub backend: String,
+    pub duration_ms: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub close_reason: CloseReason,
+    // of the backend the connection went to, see KFlow
+    pub generation: u32,
+    // the client waited for the backend to cold start
+    pub cold_start: bool,
+    // only known when protocol classification is on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_proto: Option<AppProto>,
+    // from the syn of the client to the syn-ack of the backend, tcp only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub handshake_us: Option<u64>,
+}
+
+impl FlowRecord {
+    pub fn new(
+        service: &str,
+        client: String,
+        backend: String,
+        flow: Option<KFlow>,
+        close_reason: CloseReason,
+        cold_start: bool,
+        app_proto: Option<AppProto>,
+    ) -> Self {
+        let flow = flow.unwrap_or_default();
+        FlowRecord {
+            ts: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            service: service.to_string(),
+            client,
+            backend,
+            duration_ms: flow.last_ns.saturating_sub(flow.start_ns) / 1_000_000,
+            bytes_in: flow.bytes_in,
+            bytes_out: flow.bytes_out,
+            close_reason,
+            generation: flow.generation,
+            cold_start,
+            app_proto,
+            handshake_us: (flow.syn_ack_ns != 0)
+                .then(|| flow.syn_ack_ns.saturating_sub(flow.start_ns) / 1_000),
+        }
+    }
+}
+
+// the stats of this many generations are kept per service
+const GENERATIONS_KEPT: usize = 16;
+
+// the connections closed during one generation of the backend of a service
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct GenerationStats {
+    pub service: String,
+    pub generation: u32,
+    pub connections: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // summed over the connections
+    pub duration_ms: u64,
+    pub close_reasons: BTreeMap<CloseReason, u64>,
+}
+
+// Closed connections per service and backend generation, to tell the first
+// connections to a cold started backend from those to a warm one.
+#[derive(Clone, Default)]
+pub struct Generations {
+    stats: Arc<std::sync::Mutex<BTreeMap<(String, u32), GenerationStats>>>,
+}
+
+impl Generations {
+    pub fn record(&self, record: &FlowRecord) {
+        let mut stats = self.stats.lock().unwrap();
+        let generation = stats
+            .entry((record.service.clone(), record.generation))
+            .or_insert_with(|| GenerationStats {
+                service: record.service.clone(),
+                generation: record.generation,
+                ..Default::default()
+            });
+        generation.connections += 1;
+        generation.bytes_in += record.bytes_in;
+        generation.bytes_out += record.bytes_out;
+        generation.duration_ms += record.duration_ms;
+        *generation
+            .close_reasons
+            .entry(record.close_reason)
+            .or_default() += 1;
+
+        // forget the oldest generations of the service
+        let kept: Vec<(String, u32)> = stats
+            .keys()
+            .filter(|(service, _)| *service == record.service)
+            .cloned()
+            .collect();
+        for key in kept
+            .iter()
+            .take(kept.len().saturating_sub(GENERATIONS_KEPT))
+        {
+            stats.remove(key);
+        }
+    }
+
+    // by service, oldest generation first
+    pub fn stats(&self) -> Vec<GenerationStats> {
+        self.stats.lock().unwrap().values().cloned().collect()
+    }
+}
+
+enum Writer {
+    Stdout,
+    File(tokio::fs::File),
+    Syslog(UdpSocket),
+}
+
+impl Writer {
+    async fn open(sink: &FlowLogSink) -> std::io::Result<Self> {
+        match sink {
+            FlowLogSink::Stdout => Ok(Writer::Stdout),
+            FlowLogSink::File { path } => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?;
+                Ok(Writer::File(file))
+            }
+            FlowLogSink::Syslog { addr } => {
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                socket.connect(addr).await?;
+                Ok(Writer::Syslog(socket))
+            }
+        }
+    }
+
+    async fn write(&mut self, line: &str) -> std::io::Result<()> {
+        match self {
+            Writer::Stdout => {
+                println!("{}", line);
+                Ok(())
+            }
+            Writer::File(file) => file.write_all(format!("{}\n", line).as_bytes()).await,
+            Writer::Syslog(socket) => socket
+                .send(format!("<{}>folonet: {}", SYSLOG_PRI, line).as_bytes())
+                .await
+                .map(|_| ()),
+        }
+    }
+}
+
+// writes one json line per closed connection to the configured sink
+#[derive(Clone)]
+pub struct FlowLogger {
+    sink: Sink<FlowRecord>,
+}
+
+impl FlowLogger {
+    pub async fn new(cfg: &FlowLogConfig) -> std::io::Result<Self> {
+        let mut writer = Writer::open(&cfg.sink).await?;
+        let sink = Sink::spawn("flow record", |mut rx| async move {
+            while let Some(record) = rx.recv().await {
+                let line = match serde_json::to_string(&record) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        error!("failed to encode flow record {:?}: {}", record, e);
+                        continue;
+                    }
+                };
+                if let Err(e) = writer.write(&line).await {
+                    warn!("failed to write flow record: {}", e);
+                }
+            }
+        });
+
+        Ok(FlowLogger { sink })
+    }
+
+    pub fn log(&self, record: FlowRecord) {
+        self.sink.send(record);
+    }
+}
+
+// the clock bpf_ktime_get_ns reads
+pub fn ktime_now_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+// a client connecting this long after its service came up is not counted
+// as waiting for the cold start
+const COLD_CLIENT_TTL: Duration = Duration::from_secs(300);
+
+// the kernel side accounting of every connection plus where to report it
+#[derive(Clone)]
+pub struct FlowTracker {
+    flow_map: BpfFlowMap,
+    logger: Option<FlowLogger>,
+    usage: Option<UsageExporter>,
+    tracer: Option<Tracer>,
+    tags: ProtoTags,
+    generations: Generations,
+    conn_limits: ConnLimits,
+    // clients that waited for a cold start, by when their service came up
+    cold_clients: Arc<std::sync::Mutex<HashMap<Endpoint, Instant>>>,
+}
+
+impl FlowTracker {
+    pub fn new(
+        flow_map: BpfFlowMap,
+        logger: Option<FlowLogger>,
+        usage: Option<UsageExporter>,
+        tracer: Option<Tracer>,
+        tags: ProtoTags,
+        generations: Generations,
+        conn_limits: ConnLimits,
+    ) -> Self {
+        FlowTracker {
+            flow_map,
+            logger,
+            usage,
+            tracer,
+            tags,
+            generations,
+            conn_limits,
+            cold_clients: Arc::default(),
+        }
+    }
+
+    pub fn conn_limits(&self) -> &ConnLimits {
+        &self.conn_limits
+    }
+
+    // where the spans of the tcp connections go, if they are traced
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
+
+    // the next connection of each of `clients` is marked as cold started
+    pub fn cold_started(&self, clients: Vec<Endpoint>) {
+        let now = Instant::now();
+        let mut cold_clients = self.cold_clients.lock().unwrap();
+        cold_clients.retain(|_, ready| now.duration_since(*ready) < COLD_CLIENT_TTL);
+        cold_clients.extend(clients.into_iter().map(|client| (client, now)));
+    }
+
+    // client -> service and backend -> local ways of the connections to
+    // `service` without a packet for `idle_ns`
+    pub async fn idle_flows(
+        &self,
+        service: &Endpoint,
+        idle_ns: u64,
+    ) -> Vec<(UConnection, UConnection)> {
+        let now = ktime_now_ns();
+        let flow_map = self.flow_map.lock().await;
+        flow_map
+            .iter()
+            .filter_map(|item| item.ok())
+            .filter(|(way, flow)| {
+                way.to_endpoint() == *service && now.saturating_sub(flow.last_ns) >= idle_ns
+            })
+            .map(|(way, flow)| (way, UConnection::from(flow.backend_way)))
+            .collect()
+    }
+
+    // backend -> local way of the open connection of `client_way`, if any
+    pub async fn backend_way(&self, client_way: &UConnection) -> Option<UConnection> {
+        let flow_map = self.flow_map.lock().await;
+        flow_map
+            .get(client_way, 0)
+            .ok()
+            .map(|flow| UConnection::from(flow.backend_way))
+    }
+
+    // how long the connection of `client_way` went without a packet, none
+    // once its flow is gone
+    pub async fn idle_for(&self, client_way: &UConnection) -> Option<Duration> {
+        let flow_map = self.flow_map.lock().await;
+        let flow = flow_map.get(client_way, 0).ok()?;
+        Some(Duration::from_nanos(
+            ktime_now_ns().saturating_sub(flow.last_ns),
+        ))
+    }
+
+    // Forget the kernel entry of a closed connection and log it. A close that
+    // finds the flow gone lost a race with another close of the connection,
+    // which logged it already.
+    pub async fn close(
+        &self,
+        service: &str,
+        client_way: &UConnection,
+        backend: String,
+        close_reason: CloseReason,
+    ) {
+        let flow = {
+            let mut flow_map = self.flow_map.lock().await;
+            let flow = flow_map.get(client_way, 0).ok();
+            let _ = flow_map.remove(client_way);
+            flow
+        };
+        let app_proto = self.tags.take(client_way);
+        let cold_start = self
+            .cold_clients
+            .lock()
+            .unwrap()
+            .remove(&client_way.from_endpoint())
+            .is_some();
+        if flow.is_none() {
+            return;
+        }
+
+        let record = FlowRecord::new(
+            service,
+            client_way.from_endpoint().to_string(),
+            backend,
+            flow,
+            close_reason,
+            cold_start,
+            app_proto,
+        );
+        self.generations.record(&record);
+        if let Some(usage) = &self.usage {
+            usage.export(&record);
+        }
+        if let Some(logger) = &self.logger {
+            logger.log(record);
+        }
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_flow_record() {
+        use folonet_common::flow::KFlow;
+
+        use super::{CloseReason, FlowRecord};
+        use crate::classify::AppProto;
+
+        let flow = KFlow {
+            start_ns: 1_000_000_000,
+            last_ns: 3_500_000_000,
+            bytes_in: 120,
+            bytes_out: 4096,
+            generation: 2,
+            syn_ack_ns: 1_002_500_000,
+            ..Default::default()
+        };
+        let record = FlowRecord::new(
+            "web",
+            "10.0.0.2:40000".to_string(),
+            "10.0.0.9:80".to_string(),
+            Some(flow),
+            CloseReason::Fin,
+            true,
+            Some(AppProto::Http),
+        );
+        assert_eq!(record.duration_ms, 2500);
+
+        let v: serde_json::Value = serde_json::to_value(&record).unwrap();
+        assert_eq!(v["service"], "web");
+        assert_eq!(v["backend"], "10.0.0.9:80");
+        assert_eq!(v["bytes_out"], 4096);
+        assert_eq!(v["close_reason"], "fin");
+        assert_eq!(v["generation"], 2);
+        assert_eq!(v["cold_start"], true);
+        assert_eq!(v["app_proto"], "http");
+        assert_eq!(v["handshake_us"], 2500);
+    }
+
+    #[test]
+    fn test_generations() {
+        use super::{CloseReason, FlowRecord, Generations, GENERATIONS_KEPT};
+
+        let record = |generation: u32, close_reason: CloseReason| FlowRecord {
+            ts: 0,
+            service: "web".to_string(),
+            client: "10.0.0.2:40000".to_string(),
+            backend: "10.0.0.9:80".to_string(),
+            duration_ms: 10,
+            bytes_in: 100,
+            bytes_out: 1000,
+            close_reason,
+            generation,
+            cold_start: false,
+            app_proto: None,
+            handshake_us: None,
+        };
+
+        let generations = Generations::default();
+        generations.record(&record(1, CloseReason::Fin));
+        generations.record(&record(1, CloseReason::Evicted));
+        generations.record(&record(2, CloseReason::Fin));
+        let stats = generations.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].generation, 1);
+        assert_eq!(stats[0].connections, 2);
+        assert_eq!(stats[0].bytes_out, 2000);
+        assert_eq!(stats[0].close_reasons[&CloseReason::Evicted], 1);
+
+        for generation in 3..20 {
+            generations.record(&record(generation, CloseReason::Fin));
+        }
+        let stats = generations.stats();
+        assert_eq!(stats.len(), GENERATIONS_KEPT);
+        assert_eq!(stats[0].generation, 20 - GENERATIONS_KEPT as u32);
+    }
+}
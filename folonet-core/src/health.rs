@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use folonet_client::config::HealthConfig;
+use folonet_client::ManagerClient;
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tokio::time::{timeout, Duration, Instant};
+
+use crate::control::Control;
+use crate::error::FolonetError;
+use crate::http::{self, Response};
+
+// the lost events of a consumer, by the name of its counter
+const CONSUMERS: [(&str, &str); 2] = [
+    ("packet_events", "packet_event_lost"),
+    ("cold_starts", "cold_start_event_lost"),
+];
+
+// When a ring buffer consumer last polled. It polls at least every few
+// milliseconds while idle, so a heartbeat seconds old means it is stuck.
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    since: Instant,
+    last_ms: Arc<AtomicU64>,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            since: Instant::now(),
+            last_ms: Arc::default(),
+        }
+    }
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        let now = self.since.elapsed().as_millis() as u64;
+        self.last_ms.store(now, Ordering::Relaxed);
+    }
+
+    pub fn age(&self) -> Duration {
+        let last = Duration::from_millis(self.last_ms.load(Ordering::Relaxed));
+        self.since.elapsed().saturating_sub(last)
+    }
+}
+
+// what the engine reports as it comes up, see Health
+#[derive(Debug, Clone, Default)]
+pub struct Readiness {
+    populated: Arc<AtomicBool>,
+    pub packet_events: Heartbeat,
+    pub cold_starts: Heartbeat,
+}
+
+impl Readiness {
+    // the configured services are in the maps of the xdp program
+    pub fn set_populated(&self) {
+        self.populated.store(true, Ordering::Relaxed);
+    }
+
+    fn heartbeat(&self, consumer: &str) -> &Heartbeat {
+        match consumer {
+            "packet_events" => &self.packet_events,
+            _ => &self.cold_starts,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HealthCheck {
+    pub name: String,
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl HealthCheck {
+    fn new(name: &str, ok: bool, detail: String) -> Self {
+        HealthCheck {
+            name: name.to_string(),
+            ok,
+            detail,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HealthReport {
+    pub ok: bool,
+    pub checks: Vec<HealthCheck>,
+}
+
+impl HealthReport {
+    pub fn new(checks: Vec<HealthCheck>) -> Self {
+        HealthReport {
+            ok: checks.iter().all(|check| check.ok),
+            checks,
+        }
+    }
+}
+
+// a consumer keeps up when it polled within `stall` and lost nothing since
+// the last probe, `lost` is none for the liveness probe
+fn consumer_check(name: &str, age: Duration, stall: Duration, lost: Option<u64>) -> HealthCheck {
+    if age > stall {
+        return HealthCheck::new(name, false, format!("no poll for {}ms", age.as_millis()));
+    }
+    match lost {
+        Some(lost) if lost > 0 => HealthCheck::new(
+            name,
+            false,
+            format!("{} events lost to a full ring buffer", lost),
+        ),
+        _ => HealthCheck::new(name, true, format!("polled {}ms ago", age.as_millis())),
+    }
+}
+
+// Serves /healthz and /readyz for systemd watchdogs and kubernetes probes:
+// 200 with the checks as json when they all pass, 503 otherwise. Liveness
+// only asks whether the ring buffer consumers still poll.
+#[derive(Clone)]
+pub struct Health {
+    control: Control,
+    manager: ManagerClient,
+    readiness: Readiness,
+    manager_timeout: Duration,
+    stall: Duration,
+    // the lost counters at the last readiness probe
+    lost: Arc<Mutex<HashMap<&'static str, u64>>>,
+}
+
+impl Health {
+    pub fn new(
+        control: Control,
+        manager: ManagerClient,
+        readiness: Readiness,
+        cfg: &HealthConfig,
+    ) -> Self {
+        let health = Health {
+            control,
+            manager,
+            readiness,
+            manager_timeout: Duration::from_millis(cfg.manager_timeout_ms),
+            stall: Duration::from_secs(cfg.stall_secs),
+            lost: Arc::default(),
+        };
+        // what was lost before the probes started is not held against them
+        health.lost_since_last();
+        health
+    }
+
+    fn lost_since_last(&self) -> HashMap<&'static str, u64> {
+        let counters: HashMap<&'static str, u64> = self.control.counters().into_iter().collect();
+        let mut last = self.lost.lock().unwrap();
+        CONSUMERS
+            .iter()
+            .map(|(consumer, counter)| {
+                let now = counters.get(counter).copied().unwrap_or(0);
+                let before = last.insert(*counter, now).unwrap_or(0);
+                (*consumer, now.saturating_sub(before))
+            })
+            .collect()
+    }
+
+    pub fn liveness(&self) -> HealthReport {
+        HealthReport::new(
+            CONSUMERS
+                .iter()
+                .map(|(consumer, _)| {
+                    let age = self.readiness.heartbeat(consumer).age();
+                    consumer_check(consumer, age, self.stall, None)
+                })
+                .collect(),
+        )
+    }
+
+    pub async fn readiness(&self) -> HealthReport {
+        let mut checks = vec![];
+
+        let interfaces = self.control.info().interfaces;
+        checks.push(HealthCheck::new(
+            "attached",
+            !interfaces.is_empty(),
+            interfaces
+                .iter()
+                .map(|iface| {
+                    format!(
+                        "{} ({}, {})",
+                        iface.name,
+                        iface.mode,
+                        iface.local_ips.join(" ")
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+        ));
+
+        let populated = self.readiness.populated.load(Ordering::Relaxed);
+        let services = self.control.services_report().await.services.len();
+        checks.push(HealthCheck::new(
+            "maps",
+            populated,
+            format!("{} services routed", services),
+        ));
+
+        let manager = match timeout(self.manager_timeout, self.manager.list_servers()).await {
+            Ok(Ok(servers)) => HealthCheck::new(
+                "manager",
+                true,
+                format!("{} servers running", servers.len()),
+            ),
+            Ok(Err(e)) => HealthCheck::new("manager", false, e.to_string()),
+            Err(_) => HealthCheck::new(
+                "manager",
+                false,
+                format!("no answer within {}ms", self.manager_timeout.as_millis()),
+            ),
+        };
+        checks.push(manager);
+
+        let lost = self.lost_since_last();
+        for (consumer, _) in CONSUMERS.iter() {
+            let age = self.readiness.heartbeat(consumer).age();
+            checks.push(consumer_check(
+                consumer,
+                age,
+                self.stall,
+                lost.get(consumer).copied(),
+            ));
+        }
+        HealthReport::new(checks)
+    }
+
+    pub async fn bind(cfg: &HealthConfig) -> Result<TcpListener, FolonetError> {
+        TcpListener::bind(&cfg.listen)
+            .await
+            .map_err(|source| FolonetError::Io {
+                context: format!("failed to listen on {}", cfg.listen),
+                source,
+            })
+    }
+
+    pub async fn serve_forever(self, listener: TcpListener) {
+        http::serve_forever(listener, "health probe", move |path| {
+            let health = self.clone();
+            async move { health.answer(&path).await }
+        })
+        .await
+    }
+
+    async fn answer(&self, path: &str) -> Option<Response> {
+        let report = match path {
+            "/healthz" => self.liveness(),
+            "/readyz" => self.readiness().await,
+            _ => return None,
+        };
+        let status = if report.ok {
+            "200 OK"
+        } else {
+            "503 Service Unavailable"
+        };
+        Some(Response::json(status, &report))
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_consumer_check() {
+        use tokio::time::Duration;
+
+        use super::{consumer_check, HealthCheck, HealthReport};
+
+        let stall = Duration::from_secs(5);
+        let check = consumer_check("packet_events", Duration::from_millis(3), stall, Some(0));
+        assert!(check.ok);
+        assert_eq!(check.detail, "polled 3ms ago");
+
+        let check = consumer_check("packet_events", Duration::from_millis(3), stall, Some(7));
+        assert!(!check.ok);
+        assert_eq!(check.detail, "7 events lost to a full ring buffer");
+
+        let check = consumer_check("cold_starts", Duration::from_secs(6), stall, None);
+        assert!(!check.ok);
+        assert_eq!(check.detail, "no poll for 6000ms");
+
+        let report = HealthReport::new(vec![
+            HealthCheck::new("attached", true, "eth0 (skb)".to_string()),
+            check,
+        ]);
+        assert!(!report.ok);
+        assert!(HealthReport::new(vec![]).ok);
+    }
+}
@@ -0,0 +1,126 @@
+use std::future::Future;
+
+use log::{debug, warn};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// a request line and headers, the server only answers GETs
+const MAX_REQUEST: usize = 4096;
+
+// the status line and json body of an answer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    status: &'static str,
+    body: String,
+}
+
+impl Response {
+    pub fn json(status: &'static str, body: &impl Serialize) -> Self {
+        match serde_json::to_string(body) {
+            Ok(body) => Response { status, body },
+            Err(e) => Response {
+                status: "500 Internal Server Error",
+                body: serde_json::json!({ "error": e.to_string() }).to_string(),
+            },
+        }
+    }
+
+    fn empty(status: &'static str) -> Self {
+        Response {
+            status,
+            body: "{}".to_string(),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        format!(
+            "HTTP/1.0 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            self.body.len(),
+            self.body
+        )
+        .into_bytes()
+    }
+}
+
+// the request line and headers, what a GET is made of
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; MAX_REQUEST];
+    let mut len = 0;
+    while len < MAX_REQUEST && !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf[len..]).await?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    buf.truncate(len);
+    Ok(buf)
+}
+
+// the path of a GET, none for anything else
+fn parse_get(request: &[u8]) -> Option<&str> {
+    let line = std::str::from_utf8(request).ok()?.lines().next()?;
+    let mut parts = line.split(' ');
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => Some(path),
+        _ => None,
+    }
+}
+
+async fn answer<F, Fut>(mut stream: TcpStream, get: F) -> std::io::Result<()>
+where
+    F: FnOnce(String) -> Fut,
+    Fut: Future<Output = Option<Response>>,
+{
+    let request = read_request(&mut stream).await?;
+    let response = match parse_get(&request) {
+        Some(path) => get(path.to_string())
+            .await
+            .unwrap_or_else(|| Response::empty("404 Not Found")),
+        None => Response::empty("405 Method Not Allowed"),
+    };
+    stream.write_all(&response.to_bytes()).await
+}
+
+// Answers the GETs of every connection to `listener` with `get`, one request
+// per connection. `get` is given the path and has no answer for the paths
+// it does not serve. For the json endpoints of health and federation,
+// `what` names their requests in the log.
+pub async fn serve_forever<F, Fut>(listener: TcpListener, what: &'static str, get: F)
+where
+    F: Fn(String) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Option<Response>> + Send,
+{
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("failed to accept a {}: {}", what, e);
+                continue;
+            }
+        };
+        let get = get.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer(stream, get).await {
+                debug!("failed to answer the {} of {}: {}", what, addr, e);
+            }
+        });
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_request() {
+        use super::{parse_get, Response};
+
+        assert_eq!(parse_get(b"GET /stats HTTP/1.0\r\n\r\n"), Some("/stats"));
+        assert_eq!(parse_get(b"POST /stats HTTP/1.0\r\n\r\n"), None);
+
+        let ok = Response::json("200 OK", &serde_json::json!({ "ok": true }));
+        assert!(ok.to_bytes().ends_with(b"\r\n\r\n{\"ok\":true}"));
+        assert!(ok.to_bytes().starts_with(b"HTTP/1.0 200 OK\r\n"));
+    }
+}
@@ -0,0 +1,640 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use aya::maps::{lpm_trie::Key, HashMap as AyaHashMap, LpmTrie, MapData};
+use aya::programs::links::PinnedLink;
+use aya::programs::{Xdp, XdpFlags};
+use folonet_client::config::{GlobalConfig, InterfaceConfig};
+use folonet_common::egress::{KSourceKey, SOURCE_IFINDEX_PREFIX_LEN};
+use folonet_common::Mac;
+use log::{info, warn};
+use tokio::io::unix::AsyncFd;
+use tokio::time::{sleep, Duration};
+
+use crate::acl::parse_cidr;
+use crate::egress::InterfaceIps;
+use crate::endpoint::try_mac_from_string;
+use crate::error::{FolonetError, MapResultExt};
+use crate::info::{xdp_mode_name, AttachedIface, InfoSource};
+use crate::pin::Pins;
+
+pub type BpfLocalIpMap = AyaHashMap<MapData, u32, u32>;
+pub type BpfSourceIpMap = LpmTrie<MapData, KSourceKey, u32>;
+pub type BpfIpMacMap = AyaHashMap<MapData, u32, u64>;
+
+// how often the interfaces are looked up without netlink to tell a change
+const RESCAN: Duration = Duration::from_secs(30);
+// an interface coming up brings its addresses a moment later, one look at
+// the interfaces takes them all
+const SETTLE: Duration = Duration::from_millis(200);
+
+pub(crate) fn is_pattern(name: &str) -> bool {
+    name.contains(['*', '?'])
+}
+
+// `*` matches any run of characters and `?` any one, e.g. `ens*`, `eth?`
+pub(crate) fn matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // the last `*` seen and how much of the name it takes so far
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// an interface as the kernel has it
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct KernelIface {
+    name: String,
+    index: u32,
+    up: bool,
+    loopback: bool,
+    mac: Option<u64>,
+    // ipv4 addresses with their prefix length
+    ips: Vec<(Ipv4Addr, u8)>,
+}
+
+fn kernel_ifaces() -> Vec<KernelIface> {
+    pnet::datalink::interfaces()
+        .into_iter()
+        .map(|iface| KernelIface {
+            up: iface.is_up(),
+            loopback: iface.is_loopback(),
+            mac: iface.mac.map(|mac| Mac::from(mac.octets()).val()),
+            ips: iface
+                .ips
+                .iter()
+                .filter_map(|net| match net.ip() {
+                    IpAddr::V4(ip) => Some((ip, net.prefix())),
+                    IpAddr::V6(_) => None,
+                })
+                .collect(),
+            name: iface.name,
+            index: iface.index,
+        })
+        .collect()
+}
+
+// an entry of `interfaces` with the subnets it excludes parsed
+#[derive(Debug, Clone)]
+struct IfaceEntry {
+    cfg: InterfaceConfig,
+    exclude: Vec<(Ipv4Addr, u8)>,
+}
+
+impl IfaceEntry {
+    // a bad subnet is logged and skipped
+    fn new(cfg: &InterfaceConfig) -> Self {
+        let exclude = cfg
+            .exclude_ips
+            .iter()
+            .filter_map(|entry| match parse_cidr(entry) {
+                Ok(subnet) => Some(subnet),
+                Err(e) => {
+                    warn!("invalid excluded ip {} of {}: {}", entry, cfg.name, e);
+                    None
+                }
+            })
+            .collect();
+        IfaceEntry {
+            cfg: cfg.clone(),
+            exclude,
+        }
+    }
+
+    // the configured local ips, or the addresses of the interface but the
+    // excluded ones
+    fn local_ips(&self, iface: &KernelIface) -> InterfaceIps {
+        if !self.cfg.local_ips.is_empty() {
+            return InterfaceIps::new(&self.cfg);
+        }
+        let addresses: Vec<(Ipv4Addr, u8)> = iface
+            .ips
+            .iter()
+            .filter(|(ip, _)| {
+                !self
+                    .exclude
+                    .iter()
+                    .any(|(subnet, prefix_len)| in_subnet(*ip, *subnet, *prefix_len))
+            })
+            .copied()
+            .collect();
+        InterfaceIps::from_kernel(&addresses)
+    }
+}
+
+fn in_subnet(ip: Ipv4Addr, subnet: Ipv4Addr, prefix_len: u8) -> bool {
+    let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+    u32::from(ip) & mask == u32::from(subnet) & mask
+}
+
+// what the maps hold of one interface matching the config
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct IfaceState {
+    index: u32,
+    up: bool,
+    mac: Option<u64>,
+    ips: InterfaceIps,
+}
+
+impl IfaceState {
+    fn source_keys(&self) -> impl Iterator<Item = (u32, u8, Ipv4Addr, Ipv4Addr)> + '_ {
+        self.ips
+            .subnets
+            .iter()
+            .map(|(subnet, prefix_len, ip)| (self.index, *prefix_len, *subnet, *ip))
+    }
+}
+
+// The interfaces of the kernel the config matches, by name, or all but the
+// loopback with `attach_all`. The first entry matching an interface gives its
+// local ips, the addresses of the interface when it lists none or there is
+// no such entry.
+fn desired(
+    entries: &[IfaceEntry],
+    attach_all: bool,
+    kernel: &[KernelIface],
+) -> BTreeMap<String, IfaceState> {
+    kernel
+        .iter()
+        .filter_map(|iface| {
+            let ips = match entries
+                .iter()
+                .find(|entry| matches(&entry.cfg.name, &iface.name))
+            {
+                Some(entry) => entry.local_ips(iface),
+                None if attach_all && !iface.loopback => InterfaceIps::from_kernel(&iface.ips),
+                None => return None,
+            };
+            Some((
+                iface.name.clone(),
+                IfaceState {
+                    index: iface.index,
+                    up: iface.up,
+                    mac: iface.mac,
+                    ips,
+                },
+            ))
+        })
+        .collect()
+}
+
+fn source_key(index: u32, prefix_len: u8, subnet: Ipv4Addr) -> Key<KSourceKey> {
+    Key::new(
+        SOURCE_IFINDEX_PREFIX_LEN + prefix_len as u32,
+        KSourceKey::new(index, u32::from(subnet).to_be()),
+    )
+}
+
+// The socket the kernel announces the links and ipv4 addresses coming and
+// going on. What it says does not matter, the interfaces are looked up anew.
+struct Netlink {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl Netlink {
+    fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR) as u32;
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Netlink {
+            fd: AsyncFd::new(fd)?,
+        })
+    }
+
+    // the messages queued so far, a full queue means something changed too
+    fn drain(&self) {
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = unsafe {
+                libc::recv(
+                    self.fd.get_ref().as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    libc::MSG_DONTWAIT,
+                )
+            };
+            if n > 0 {
+                continue;
+            }
+            if n < 0 && io::Error::last_os_error().raw_os_error() == Some(libc::ENOBUFS) {
+                continue;
+            }
+            return;
+        }
+    }
+
+    async fn changed(&self) -> io::Result<()> {
+        let mut guard = self.fd.readable().await?;
+        self.drain();
+        guard.clear_ready();
+        Ok(())
+    }
+}
+
+// Keeps LOCAL_IP_MAP, SOURCE_IP_MAP and the xdp links in step with the
+// interfaces of the kernel, as they come and go, go up and down and change
+// their addresses, instead of as they were at startup. The macs IP_MAC_MAP
+// learned for an address that went away, or of an interface that went away
+// or changed its mac, are forgotten, the configured ones stay.
+pub struct IfaceWatch {
+    entries: Vec<IfaceEntry>,
+    attach_all: bool,
+    // the ips of `ip_mac_list`, network byte order
+    static_macs: HashSet<u32>,
+    local_ip_map: BpfLocalIpMap,
+    source_ip_map: BpfSourceIpMap,
+    ip_mac_map: BpfIpMacMap,
+    applied: BTreeMap<String, IfaceState>,
+}
+
+impl IfaceWatch {
+    pub fn new(
+        cfg: &GlobalConfig,
+        local_ip_map: BpfLocalIpMap,
+        source_ip_map: BpfSourceIpMap,
+        ip_mac_map: BpfIpMacMap,
+    ) -> Self {
+        let static_macs = cfg
+            .ip_mac_list
+            .iter()
+            .filter(|ip_mac| try_mac_from_string(&ip_mac.mac).is_ok())
+            .filter_map(|ip_mac| ip_mac.ip.parse::<Ipv4Addr>().ok())
+            .map(|ip| u32::from(ip).to_be())
+            .collect();
+        IfaceWatch {
+            entries: cfg.interfaces.iter().map(IfaceEntry::new).collect(),
+            attach_all: cfg.attach_all,
+            static_macs,
+            local_ip_map,
+            source_ip_map,
+            ip_mac_map,
+            applied: BTreeMap::new(),
+        }
+    }
+
+    // the interfaces to attach to: the ones configured by name, whether the
+    // kernel has them yet or not, and the ones matching a pattern or taken
+    // by `attach_all`
+    pub fn interfaces(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|entry| !is_pattern(&entry.cfg.name))
+            .map(|entry| entry.cfg.name.clone())
+            .collect();
+        names.extend(self.applied.keys().cloned());
+        let mut seen = HashSet::new();
+        names.retain(|name| seen.insert(name.clone()));
+        names
+    }
+
+    // the local ips of every interface, as the maps hold them
+    pub fn local_ips(&self) -> Vec<InterfaceIps> {
+        self.applied
+            .values()
+            .map(|state| state.ips.clone())
+            .collect()
+    }
+
+    // `names` as attached with `flags`, for the info of the daemon
+    pub fn attached<'a>(
+        &self,
+        names: impl Iterator<Item = &'a String>,
+        flags: XdpFlags,
+    ) -> Vec<AttachedIface> {
+        names
+            .map(|name| AttachedIface {
+                name: name.clone(),
+                mode: xdp_mode_name(flags).to_string(),
+                local_ips: self
+                    .applied
+                    .get(name)
+                    .map(|state| state.ips.all().map(|ip| ip.to_string()).collect())
+                    .unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    // Bring the maps to the interfaces the kernel has now. Returns the
+    // interfaces that went away or were replaced by one of the same name,
+    // their links went with them.
+    pub fn sync(&mut self) -> Result<Vec<String>, FolonetError> {
+        let desired = desired(&self.entries, self.attach_all, &kernel_ifaces());
+        if desired == self.applied {
+            return Ok(vec![]);
+        }
+
+        // what no interface holds any longer goes first, an index or subnet
+        // may have moved to another interface
+        let local_indexes: HashSet<u32> = desired
+            .values()
+            .filter(|state| state.ips.first.is_some())
+            .map(|state| state.index)
+            .collect();
+        let source_keys: HashSet<(u32, u8, Ipv4Addr)> = desired
+            .values()
+            .flat_map(|state| state.source_keys())
+            .map(|(index, prefix_len, subnet, _)| (index, prefix_len, subnet))
+            .collect();
+        let local_ips: HashSet<Ipv4Addr> =
+            desired.values().flat_map(|state| state.ips.all()).collect();
+        let macs: HashSet<u64> = desired.values().filter_map(|state| state.mac).collect();
+        let mut gone_ips = BTreeSet::new();
+        let mut gone_macs = BTreeSet::new();
+        for state in self.applied.values() {
+            if state.ips.first.is_some() && !local_indexes.contains(&state.index) {
+                let _ = self.local_ip_map.remove(&state.index);
+            }
+            for (index, prefix_len, subnet, _) in state.source_keys() {
+                if !source_keys.contains(&(index, prefix_len, subnet)) {
+                    let _ = self
+                        .source_ip_map
+                        .remove(&source_key(index, prefix_len, subnet));
+                }
+            }
+            gone_ips.extend(state.ips.all().filter(|ip| !local_ips.contains(ip)));
+            gone_macs.extend(state.mac.filter(|mac| !macs.contains(mac)));
+        }
+        self.forget_macs(&gone_ips, &gone_macs);
+
+        for state in desired.values() {
+            if let Some(first) = state.ips.first {
+                self.local_ip_map
+                    .insert(state.index, u32::from(first), 0)
+                    .map_context("LOCAL_IP_MAP")?;
+            }
+            for (index, prefix_len, subnet, ip) in state.source_keys() {
+                self.source_ip_map
+                    .insert(&source_key(index, prefix_len, subnet), u32::from(ip), 0)
+                    .map_context("SOURCE_IP_MAP")?;
+            }
+        }
+
+        let gone = self
+            .applied
+            .iter()
+            .filter(|(name, state)| desired.get(*name).map(|s| s.index) != Some(state.index))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for (name, state) in desired.iter() {
+            if self.applied.get(name) != Some(state) {
+                info!(
+                    "interface {} (index {}, {}) has local ips {:?}",
+                    name,
+                    state.index,
+                    if state.up { "up" } else { "down" },
+                    state.ips.all().collect::<Vec<_>>()
+                );
+            }
+        }
+        self.applied = desired;
+        Ok(gone)
+    }
+
+    // the learned macs of `ips`, and the entries learned with one of `macs`
+    fn forget_macs(&mut self, ips: &BTreeSet<Ipv4Addr>, macs: &BTreeSet<u64>) {
+        if ips.is_empty() && macs.is_empty() {
+            return;
+        }
+        let ips: HashSet<u32> = ips.iter().map(|ip| u32::from(*ip).to_be()).collect();
+        let stale: Vec<u32> = self
+            .ip_mac_map
+            .iter()
+            .filter_map(|item| item.ok())
+            .filter(|(ip, mac)| ips.contains(ip) || macs.contains(mac))
+            .map(|(ip, _)| ip)
+            .filter(|ip| !self.static_macs.contains(ip))
+            .collect();
+        for ip in stale.iter() {
+            let _ = self.ip_mac_map.remove(ip);
+        }
+    }
+
+    // attach to the interfaces that are up and have no link, after dropping
+    // the links of the ones in `gone`
+    fn attach_new(
+        &self,
+        program: &mut Xdp,
+        flags: XdpFlags,
+        pins: &Pins,
+        links: &mut Vec<(String, PinnedLink)>,
+        gone: &[String],
+    ) {
+        let (dead, alive): (Vec<_>, Vec<_>) =
+            links.drain(..).partition(|(iface, _)| gone.contains(iface));
+        *links = alive;
+        for (iface, link) in dead {
+            info!("{} went away with its xdp link", iface);
+            pins.forget(&iface, link);
+        }
+
+        let fresh: Vec<(String, _)> = self
+            .applied
+            .iter()
+            .filter(|(name, state)| state.up && !links.iter().any(|(iface, _)| iface == *name))
+            .filter_map(|(name, _)| match program.attach(name, flags) {
+                Ok(link_id) => {
+                    info!("attached to {}", name);
+                    Some((name.clone(), link_id))
+                }
+                Err(e) => {
+                    warn!("failed to attach to {}: {:#}", name, e);
+                    None
+                }
+            })
+            .collect();
+        links.extend(pins.pin_links(program, fresh));
+    }
+
+    // follow the interfaces until the daemon stops
+    pub async fn follow(
+        &mut self,
+        program: &mut Xdp,
+        flags: XdpFlags,
+        pins: &Pins,
+        links: &mut Vec<(String, PinnedLink)>,
+        info: &InfoSource,
+    ) {
+        let mut netlink = match Netlink::open() {
+            Ok(netlink) => Some(netlink),
+            Err(e) => {
+                warn!(
+                    "failed to watch the interfaces over netlink, looking them up every {:?}: {}",
+                    RESCAN, e
+                );
+                None
+            }
+        };
+        // what changed since the maps were filled at startup comes first
+        loop {
+            match self.sync() {
+                Ok(gone) => {
+                    self.attach_new(program, flags, pins, links, &gone);
+                    info.set_interfaces(self.attached(links.iter().map(|(iface, _)| iface), flags));
+                }
+                Err(e) => warn!("failed to follow the interfaces: {}", e),
+            }
+
+            let failed = match &netlink {
+                Some(socket) => socket.changed().await.err(),
+                None => {
+                    sleep(RESCAN).await;
+                    None
+                }
+            };
+            if let Some(e) = failed {
+                warn!(
+                    "netlink socket failed, looking up the interfaces every {:?}: {}",
+                    RESCAN, e
+                );
+                netlink = None;
+            }
+            sleep(SETTLE).await;
+            if let Some(socket) = &netlink {
+                socket.drain();
+            }
+        }
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_matches() {
+        use super::matches;
+
+        assert!(matches("eth0", "eth0"));
+        assert!(!matches("eth0", "eth01"));
+        assert!(matches("veth*", "veth1a2b"));
+        assert!(matches("veth*", "veth"));
+        assert!(!matches("veth*", "eth0"));
+        assert!(matches("*-ext", "br-ext"));
+        assert!(matches("en*p*s0", "enp3s0"));
+        assert!(!matches("en*p*s0", "enp3s1"));
+        assert!(matches("eth?", "eth1"));
+        assert!(!matches("eth?", "eth10"));
+        assert!(matches("ens*?", "ens5"));
+        assert!(!matches("ens*?", "ens"));
+    }
+
+    #[test]
+    fn test_desired() {
+        use std::net::Ipv4Addr;
+
+        use folonet_client::config::InterfaceConfig;
+
+        use super::{desired, IfaceEntry, KernelIface};
+
+        let entries: Vec<IfaceEntry> = [
+            InterfaceConfig {
+                name: "eth0".to_string(),
+                local_ips: vec!["192.168.1.10".to_string()],
+                exclude_ips: vec![],
+            },
+            InterfaceConfig {
+                name: "veth*".to_string(),
+                local_ips: vec![],
+                exclude_ips: vec!["10.0.1.200".to_string(), "bad".to_string()],
+            },
+        ]
+        .iter()
+        .map(IfaceEntry::new)
+        .collect();
+        let kernel = vec![
+            KernelIface {
+                name: "eth0".to_string(),
+                index: 2,
+                up: true,
+                loopback: false,
+                mac: Some(1),
+                ips: vec![(Ipv4Addr::new(192, 168, 1, 20), 24)],
+            },
+            KernelIface {
+                name: "veth7".to_string(),
+                index: 9,
+                up: false,
+                loopback: false,
+                mac: Some(2),
+                ips: vec![
+                    (Ipv4Addr::new(10, 0, 1, 200), 24),
+                    (Ipv4Addr::new(10, 0, 1, 5), 24),
+                ],
+            },
+            KernelIface {
+                name: "lo".to_string(),
+                index: 1,
+                up: true,
+                loopback: true,
+                mac: None,
+                ips: vec![(Ipv4Addr::new(127, 0, 0, 1), 8)],
+            },
+        ];
+        let states = desired(&entries, false, &kernel);
+        assert_eq!(states.len(), 2);
+        // the configured ips win over the ones of the interface
+        assert_eq!(
+            states["eth0"].ips.first,
+            Some(Ipv4Addr::new(192, 168, 1, 10))
+        );
+        assert_eq!(states["veth7"].index, 9);
+        // the floating ip is left out
+        assert_eq!(
+            states["veth7"].ips.subnets,
+            vec![(Ipv4Addr::new(10, 0, 1, 0), 24, Ipv4Addr::new(10, 0, 1, 5))]
+        );
+
+        // all but the loopback, each with its own addresses
+        let states = desired(&[], true, &kernel);
+        assert_eq!(states.keys().collect::<Vec<_>>(), vec!["eth0", "veth7"]);
+        assert_eq!(
+            states["eth0"].ips.first,
+            Some(Ipv4Addr::new(192, 168, 1, 20))
+        );
+    }
+}
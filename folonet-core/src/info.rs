@@ -0,0 +1,150 @@
+use std::ffi::CStr;
+use std::sync::{Arc, RwLock};
+
+use aya::programs::XdpFlags;
+use serde::Serialize;
+
+// what this build can do, for clients to check before they use newer calls
+pub const FEATURES: &[&str] = &[
+    "syn_cookie",
+    "hold_handshake",
+    "flow_log",
+    "warm_pool",
+    "port_pool_control",
+    "manager_tls",
+    "protocol_tagging",
+    "icmp_translation",
+    "blocklist",
+    "structured_output",
+    "handshake_notifications",
+    "datapath_latency",
+    "unknown_connection_action",
+    "reconciliation",
+    "lru_connection_table",
+    "datapath_takeover",
+    "source_ip_selection",
+    "federation",
+    "backend_generations",
+    "port_quotas",
+    "fin_sweep",
+    "kernel_conntrack",
+    "admin_socket",
+    "usage_export",
+    "backend_egress",
+    "log_filters",
+    "demo",
+    "pcap",
+    "handshake_latency",
+    "health_probes",
+    "systemd_notify",
+    "conn_limits",
+    "reject",
+    "http_routes",
+    "sni_routes",
+    "fallback",
+    "af_xdp",
+    "iface_stats",
+    "proxy_protocol",
+    "toa",
+    "traffic_split",
+    "client_ip_affinity",
+    "connection_tracing",
+    "interface_watch",
+    "interface_patterns",
+    "handshake_timeout",
+    "config_validation",
+    "config_include",
+    "config_overrides",
+    "nat_table",
+    "slow_start",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AttachedIface {
+    pub name: String,
+    pub mode: String,
+    // as the kernel has them unless configured
+    pub local_ips: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Info {
+    pub version: String,
+    pub git_commit: Option<String>,
+    pub ebpf_object_hash: Option<String>,
+    pub features: Vec<String>,
+    pub interfaces: Vec<AttachedIface>,
+    pub kernel_version: Option<String>,
+}
+
+// the static parts of Info plus the interfaces a running engine is attached to
+#[derive(Clone, Default)]
+pub struct InfoSource {
+    ebpf_object_hash: Option<String>,
+    interfaces: Arc<RwLock<Vec<AttachedIface>>>,
+}
+
+impl InfoSource {
+    pub fn new(ebpf_object_hash: Option<String>) -> Self {
+        InfoSource {
+            ebpf_object_hash,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_interfaces(&self, interfaces: Vec<AttachedIface>) {
+        *self.interfaces.write().unwrap() = interfaces;
+    }
+
+    pub fn info(&self) -> Info {
+        Info {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_commit: option_env!("FOLONET_GIT_COMMIT").map(|c| c.to_string()),
+            ebpf_object_hash: self.ebpf_object_hash.clone(),
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+            interfaces: self.interfaces.read().unwrap().clone(),
+            kernel_version: kernel_version(),
+        }
+    }
+}
+
+// fnv-1a, enough to tell two builds of the xdp program apart
+pub fn object_hash(object: &[u8]) -> String {
+    let hash = object.iter().fold(0xcbf29ce484222325u64, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+pub fn xdp_mode_name(flags: XdpFlags) -> &'static str {
+    if flags.contains(XdpFlags::HW_MODE) {
+        "hw"
+    } else if flags.contains(XdpFlags::DRV_MODE) {
+        "drv"
+    } else if flags.contains(XdpFlags::SKB_MODE) {
+        "skb"
+    } else {
+        "auto"
+    }
+}
+
+pub fn kernel_version() -> Option<String> {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return None;
+    }
+    let release = unsafe { CStr::from_ptr(uts.release.as_ptr()) };
+    Some(release.to_string_lossy().into_owned())
+}
+
+mod test {
+
+    #[test]
+    fn test_object_hash() {
+        use super::object_hash;
+
+        assert_eq!(object_hash(b""), "cbf29ce484222325");
+        assert_eq!(object_hash(b"a"), "af63dc4c8601ec8c");
+        assert_ne!(object_hash(b"folonet"), object_hash(b"folonex"));
+    }
+}
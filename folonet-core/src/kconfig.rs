@@ -0,0 +1,71 @@
+use std::{fs::File, io::Read};
+
+use folonet_client::config::{
+    BackendEgressAction, FragmentPolicy, GlobalConfig, SynFloodAction, UnknownConnAction,
+};
+use folonet_common::config::{
+    KConfig, BACKEND_EGRESS_BLOCK, BACKEND_EGRESS_MASQUERADE, BACKEND_EGRESS_PASS,
+    FRAG_POLICY_DROP, FRAG_POLICY_PASS, SYN_FLOOD_ACTION_COOKIE, SYN_FLOOD_ACTION_DROP,
+    UNKNOWN_CONN_DROP, UNKNOWN_CONN_PASS, UNKNOWN_CONN_RST,
+};
+
+use crate::net::get_interafce_index;
+
+fn random_u32() -> u32 {
+    let mut buf = [0u8; 4];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut buf))
+        .unwrap();
+    u32::from_ne_bytes(buf)
+}
+
+pub fn build_k_config(cfg: &GlobalConfig) -> KConfig {
+    // held handshakes use syn cookies as well, so the secret is always set
+    let mut k_config = KConfig {
+        syn_cookie_secret: random_u32(),
+        sample_first_data: cfg.classify_protocols as u8,
+        frag_policy: match cfg.fragments {
+            FragmentPolicy::Pass => FRAG_POLICY_PASS,
+            FragmentPolicy::Drop => FRAG_POLICY_DROP,
+        },
+        unknown_conn_action: match cfg.unknown_connections {
+            UnknownConnAction::Pass => UNKNOWN_CONN_PASS,
+            UnknownConnAction::Drop => UNKNOWN_CONN_DROP,
+            UnknownConnAction::Rst => UNKNOWN_CONN_RST,
+        },
+        backend_egress_action: match cfg.backend_egress {
+            BackendEgressAction::Pass => BACKEND_EGRESS_PASS,
+            BackendEgressAction::Masquerade => BACKEND_EGRESS_MASQUERADE,
+            BackendEgressAction::Block => BACKEND_EGRESS_BLOCK,
+        },
+        quiet: !cfg.log.ebpf as u8,
+        reject: cfg.reject.is_some() as u8,
+        fallback: cfg.fallback.is_some() as u8,
+        af_xdp_ifindex: cfg
+            .af_xdp
+            .as_ref()
+            .and_then(|af_xdp| get_interafce_index(af_xdp.interface.clone()))
+            .unwrap_or(0),
+        notify_syn: cfg.notify.syn,
+        notify_syn_ack: cfg.notify.syn_ack,
+        notify_rst: cfg.notify.rst,
+        notify_first_ack: cfg.notify.first_ack,
+        latency_sample: cfg
+            .latency
+            .as_ref()
+            .map(|latency| latency.sample_one_in)
+            .unwrap_or(0),
+        ..Default::default()
+    };
+
+    if let Some(syn_flood) = &cfg.syn_flood {
+        k_config.syn_half_open_threshold = syn_flood.half_open_threshold;
+        k_config.syn_window_ns = syn_flood.window_secs * 1_000_000_000;
+        k_config.syn_flood_action = match syn_flood.action {
+            SynFloodAction::Drop => SYN_FLOOD_ACTION_DROP,
+            SynFloodAction::Cookie => SYN_FLOOD_ACTION_COOKIE,
+        };
+    }
+
+    k_config
+}
@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use aya::maps::{MapData, PerCpuHashMap};
+use folonet_common::latency::{KHandshakeKey, KLatencyKey, LATENCY_BUCKETS};
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::endpoint::Endpoint;
+use crate::net::interface_name;
+
+pub type BpfLatencyMap = PerCpuHashMap<MapData, KLatencyKey, u64>;
+pub type BpfHandshakeMap = PerCpuHashMap<MapData, KHandshakeKey, u64>;
+
+const BUCKETS: usize = LATENCY_BUCKETS as usize;
+
+// Percentiles are the upper bound of the bucket they fall in, so they are
+// exact to within a factor of two.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct IfaceLatency {
+    pub iface: String,
+    pub ifindex: u32,
+    pub samples: u64,
+    pub p50_ns: u64,
+    pub p90_ns: u64,
+    pub p99_ns: u64,
+    pub max_ns: u64,
+}
+
+impl IfaceLatency {
+    fn new(iface: String, ifindex: u32, hist: &[u64; BUCKETS]) -> Self {
+        IfaceLatency {
+            iface,
+            ifindex,
+            samples: hist.iter().sum(),
+            p50_ns: percentile(hist, 0.5),
+            p90_ns: percentile(hist, 0.9),
+            p99_ns: percentile(hist, 0.99),
+            max_ns: percentile(hist, 1.0),
+        }
+    }
+}
+
+// upper bound of the bucket holding the `q` quantile, 0 without samples
+fn percentile(hist: &[u64; BUCKETS], q: f64) -> u64 {
+    let total: u64 = hist.iter().sum();
+    if total == 0 {
+        return 0;
+    }
+    let rank = ((total as f64 * q).ceil() as u64).max(1);
+    let mut seen = 0;
+    for (bucket, count) in hist.iter().enumerate() {
+        seen += count;
+        if seen >= rank {
+            return 1u64 << (bucket + 1);
+        }
+    }
+    1u64 << BUCKETS
+}
+
+// The processing time of the packets the xdp program sampled, per interface,
+// since it was loaded. Cheap to clone, every clone reads the same map.
+#[derive(Clone)]
+pub struct DatapathLatency {
+    map: Arc<Mutex<BpfLatencyMap>>,
+}
+
+impl DatapathLatency {
+    pub fn new(map: BpfLatencyMap) -> Self {
+        DatapathLatency {
+            map: Arc::new(Mutex::new(map)),
+        }
+    }
+
+    // empty unless `latency` is configured
+    pub async fn percentiles(&self) -> Vec<IfaceLatency> {
+        let mut hists: BTreeMap<u32, [u64; BUCKETS]> = BTreeMap::new();
+        {
+            let map = self.map.lock().await;
+            for (key, values) in map.iter().filter_map(|item| item.ok()) {
+                let bucket = (key.bucket as usize).min(BUCKETS - 1);
+                hists.entry(key.ifindex).or_insert([0; BUCKETS])[bucket] +=
+                    values.iter().sum::<u64>();
+            }
+        }
+        hists
+            .iter()
+            .map(|(ifindex, hist)| {
+                let iface = interface_name(*ifindex).unwrap_or_else(|| ifindex.to_string());
+                IfaceLatency::new(iface, *ifindex, hist)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HandshakeBucket {
+    // the samples below this, and at least half of it
+    pub le_ns: u64,
+    pub count: u64,
+}
+
+// How long a backend took to answer the syn of a client with its syn-ack,
+// the first sign of a slow cold start or an overloaded backend.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BackendHandshake {
+    pub backend: String,
+    pub samples: u64,
+    pub p50_ns: u64,
+    pub p90_ns: u64,
+    pub p99_ns: u64,
+    pub max_ns: u64,
+    // the buckets holding samples
+    pub histogram: Vec<HandshakeBucket>,
+}
+
+impl BackendHandshake {
+    fn new(backend: String, hist: &[u64; BUCKETS]) -> Self {
+        BackendHandshake {
+            backend,
+            samples: hist.iter().sum(),
+            p50_ns: percentile(hist, 0.5),
+            p90_ns: percentile(hist, 0.9),
+            p99_ns: percentile(hist, 0.99),
+            max_ns: percentile(hist, 1.0),
+            histogram: hist
+                .iter()
+                .enumerate()
+                .filter(|(_, count)| **count > 0)
+                .map(|(bucket, count)| HandshakeBucket {
+                    le_ns: 1u64 << (bucket + 1),
+                    count: *count,
+                })
+                .collect(),
+        }
+    }
+}
+
+// The handshakes of every backend since the xdp program was loaded.
+#[derive(Clone)]
+pub struct HandshakeLatency {
+    map: Arc<Mutex<BpfHandshakeMap>>,
+}
+
+impl HandshakeLatency {
+    pub fn new(map: BpfHandshakeMap) -> Self {
+        HandshakeLatency {
+            map: Arc::new(Mutex::new(map)),
+        }
+    }
+
+    pub async fn backends(&self) -> Vec<BackendHandshake> {
+        let mut hists: BTreeMap<Endpoint, [u64; BUCKETS]> = BTreeMap::new();
+        {
+            let map = self.map.lock().await;
+            for (key, values) in map.iter().filter_map(|item| item.ok()) {
+                let bucket = (key.bucket as usize).min(BUCKETS - 1);
+                hists
+                    .entry(Endpoint::new(key.backend))
+                    .or_insert([0; BUCKETS])[bucket] += values.iter().sum::<u64>();
+            }
+        }
+        hists
+            .iter()
+            .map(|(backend, hist)| BackendHandshake::new(backend.to_string(), hist))
+            .collect()
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_latency_percentiles() {
+        use super::{BackendHandshake, IfaceLatency, BUCKETS};
+
+        let mut hist = [0u64; BUCKETS];
+        // 90 samples in [256, 512) ns, 9 in [1024, 2048), 1 in [8192, 16384)
+        hist[8] = 90;
+        hist[10] = 9;
+        hist[13] = 1;
+
+        let latency = IfaceLatency::new("eth0".to_string(), 2, &hist);
+        assert_eq!(latency.samples, 100);
+        assert_eq!(latency.p50_ns, 512);
+        assert_eq!(latency.p90_ns, 512);
+        assert_eq!(latency.p99_ns, 2048);
+        assert_eq!(latency.max_ns, 16384);
+
+        let empty = IfaceLatency::new("eth0".to_string(), 2, &[0; BUCKETS]);
+        assert_eq!(empty.p99_ns, 0);
+
+        let handshake = BackendHandshake::new("10.0.0.9:80".to_string(), &hist);
+        assert_eq!(handshake.p99_ns, 2048);
+        assert_eq!(handshake.histogram.len(), 3);
+        assert_eq!(handshake.histogram[0].le_ns, 512);
+        assert_eq!(handshake.histogram[0].count, 90);
+    }
+}
@@ -0,0 +1,77 @@
+//! Userspace side of folonet: loads the XDP program's maps, follows the
+//! connections it reports and cold starts services on demand.
+//!
+//! The `folonet` binary is a thin wrapper around [`Engine`]. Public are the
+//! engine, the admin socket, the config loading and checks, the reports and
+//! what the binary and the integration tests use; the rest is internal.
+
+pub(crate) mod acl;
+pub mod admin;
+pub(crate) mod af_xdp;
+pub(crate) mod attach;
+pub(crate) mod blocklist;
+pub(crate) mod classify;
+pub(crate) mod cold_start;
+pub(crate) mod conn_limit;
+pub(crate) mod control;
+pub mod demo;
+pub(crate) mod egress;
+pub(crate) mod endpoint;
+pub(crate) mod engine;
+pub(crate) mod error;
+pub(crate) mod event_workers;
+pub(crate) mod fallback;
+pub(crate) mod federation;
+pub(crate) mod fin_sweep;
+pub(crate) mod flow_log;
+pub(crate) mod health;
+pub(crate) mod http;
+pub(crate) mod iface_watch;
+pub mod info;
+pub(crate) mod kconfig;
+pub(crate) mod latency;
+pub(crate) mod limits;
+pub mod loader;
+pub mod logging;
+pub(crate) mod message;
+pub(crate) mod net;
+pub mod offline;
+pub mod output;
+pub(crate) mod pcap;
+pub(crate) mod pin;
+pub(crate) mod poll;
+pub(crate) mod ports;
+pub(crate) mod probe;
+pub(crate) mod reconcile;
+pub(crate) mod removal;
+pub mod replay;
+pub(crate) mod route;
+pub(crate) mod scaler;
+pub(crate) mod sequencer;
+pub(crate) mod service;
+pub(crate) mod shard;
+pub(crate) mod sharded;
+pub(crate) mod sink;
+pub(crate) mod split;
+pub(crate) mod state;
+pub(crate) mod stats;
+pub(crate) mod stuck;
+pub(crate) mod systemd;
+pub(crate) mod timer;
+pub(crate) mod trace;
+pub(crate) mod usage;
+pub mod validate;
+pub(crate) mod warm_pool;
+pub(crate) mod worker;
+
+pub use cold_start::ColdStartStats;
+pub use control::Control;
+pub use endpoint::Endpoint;
+pub use engine::{load_bpf, BpfHandles, Engine};
+pub use error::FolonetError;
+pub use info::Info;
+pub use latency::{BackendHandshake, HandshakeBucket};
+pub use ports::{PortPoolStats, PortQuotaStats};
+pub use scaler::{Scaler, ServiceLoad};
+pub use service::Service;
+pub use stats::IfaceStats;
@@ -0,0 +1,82 @@
+use folonet_client::config::LimitsConfig;
+use folonet_common::latency::LATENCY_BUCKETS;
+
+use crate::error::FolonetError;
+
+// the max entries of every kernel map sized by `limits`
+pub fn map_sizes(limits: &LimitsConfig) -> Result<Vec<(&'static str, u32)>, FolonetError> {
+    let sizes = vec![
+        ("CONNECTION", limits.connections),
+        ("FLOW_MAP", limits.flows),
+        // a tcp and a udp entry per service at most
+        ("SERVER_MAP", limits.services.saturating_mul(2)),
+        ("SPLIT_MAP", limits.services.saturating_mul(2)),
+        ("DRAINING_MAP", limits.services),
+        ("SERVICE_EPOCH", limits.services),
+        ("SERVICE_LOAD", limits.services),
+        ("HOLD_MAP", limits.services),
+        ("COLD_START_FAILED", limits.services),
+        ("COLD_START_PENDING", limits.half_open),
+        ("PROXIED", limits.services),
+        ("PROXY_V2_MAP", limits.services),
+        ("PROXY_V2_CONN", limits.connections),
+        ("TOA_MAP", limits.services),
+        ("AFFINITY_TIMEOUT", limits.services),
+        // a client per connection at most
+        ("AFFINITY_MAP", limits.connections),
+        ("FALLBACK_FLOWS", limits.connections),
+        ("ACL_DEFAULT_MAP", limits.services),
+        ("EGRESS_IP_MAP", limits.services),
+        ("BACKEND_IPS", limits.services),
+        // a backend per service, every bucket of its histogram
+        (
+            "HANDSHAKE_LATENCY",
+            limits.services.saturating_mul(LATENCY_BUCKETS),
+        ),
+        ("PORT_QUOTA_MAP", limits.services),
+        ("PORT_TAKEN_MAP", limits.services),
+        ("CONN_LIMIT_MAP", limits.services),
+        ("CONN_OPENED_MAP", limits.services),
+        ("LOCAL_IP_MAP", limits.local_ips),
+        ("SOURCE_IP_MAP", limits.local_ips),
+        ("IP_MAC_MAP", limits.ip_macs),
+        ("HALF_OPEN_MAP", limits.half_open),
+        ("HALF_OPEN_CONN", limits.half_open),
+        ("SYN_PROXY_MAP", limits.half_open),
+        ("HELD_CONN", limits.half_open),
+        ("BLOCKLIST", limits.blocked_sources),
+        ("ACL_MAP", limits.acl_rules),
+    ];
+    if let Some((name, _)) = sizes.iter().find(|(_, size)| *size == 0) {
+        return Err(FolonetError::Config(format!(
+            "the limit of {} must not be zero",
+            name
+        )));
+    }
+    Ok(sizes)
+}
+
+mod test {
+
+    #[test]
+    fn test_map_sizes() {
+        use folonet_client::config::LimitsConfig;
+
+        use super::map_sizes;
+
+        let limits = LimitsConfig {
+            services: 64,
+            ..Default::default()
+        };
+        let sizes = map_sizes(&limits).unwrap();
+        assert!(sizes.contains(&("CONNECTION", 131072)));
+        assert!(sizes.contains(&("SERVER_MAP", 128)));
+        assert!(sizes.contains(&("SERVICE_EPOCH", 64)));
+
+        let limits = LimitsConfig {
+            local_ips: 0,
+            ..Default::default()
+        };
+        assert!(map_sizes(&limits).is_err());
+    }
+}
@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use folonet_client::config::{GlobalConfig, InterfaceConfig, ServiceConfig};
+use serde::de::DeserializeOwned;
+
+use crate::error::FolonetError;
+use crate::iface_watch::{self, is_pattern};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl ConfigFormat {
+    // by the extension of `path`, yaml without one of the others
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => ConfigFormat::Json,
+            Some("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+
+    fn is_config(path: &Path) -> bool {
+        matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("yaml" | "yml" | "json" | "toml")
+        )
+    }
+
+    pub fn parse<T: DeserializeOwned>(self, text: &str) -> Result<T, String> {
+        match self {
+            ConfigFormat::Yaml => serde_yaml::from_str(text).map_err(|e| e.to_string()),
+            ConfigFormat::Json => serde_json::from_str(text).map_err(|e| e.to_string()),
+            ConfigFormat::Toml => toml::from_str(text).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+// a file the config was read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigSource {
+    pub path: String,
+    pub text: String,
+}
+
+impl ConfigSource {
+    fn read(path: &Path) -> Result<Self, FolonetError> {
+        let text = fs::read_to_string(path).map_err(|source| FolonetError::Io {
+            context: format!("failed to read {}", path.display()),
+            source,
+        })?;
+        Ok(ConfigSource {
+            path: path.display().to_string(),
+            text,
+        })
+    }
+
+    fn parse<T: DeserializeOwned>(&self) -> Result<T, FolonetError> {
+        ConfigFormat::of(Path::new(&self.path))
+            .parse(&self.text)
+            .map_err(|e| FolonetError::Config(format!("{}: {}", self.path, e)))
+    }
+
+    pub fn format(&self) -> ConfigFormat {
+        ConfigFormat::of(Path::new(&self.path))
+    }
+}
+
+// Config values given on the command line or in the environment, over
+// those of the files.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Overrides {
+    // the interfaces to attach to instead of the configured ones, a
+    // configured entry of the same name keeps its ips
+    pub interfaces: Vec<String>,
+    pub manager_addr: Option<String>,
+    pub port_ranges: Vec<String>,
+    pub log_filter: Option<String>,
+}
+
+impl Overrides {
+    // returns the keys set, they are in no file
+    pub fn apply(&self, cfg: &mut GlobalConfig) -> Vec<&'static str> {
+        let mut set = vec![];
+        if !self.interfaces.is_empty() {
+            let configured = std::mem::take(&mut cfg.interfaces);
+            cfg.interfaces = self
+                .interfaces
+                .iter()
+                .map(|name| {
+                    configured
+                        .iter()
+                        .find(|iface| iface.name == *name)
+                        .cloned()
+                        .unwrap_or(InterfaceConfig {
+                            name: name.clone(),
+                            local_ips: vec![],
+                            exclude_ips: vec![],
+                        })
+                })
+                .collect();
+            set.push("interfaces");
+        }
+        if let Some(addr) = &self.manager_addr {
+            cfg.manager.addr = addr.clone();
+            set.push("manager.addr");
+        }
+        if !self.port_ranges.is_empty() {
+            cfg.ports.ranges = self.port_ranges.clone();
+            set.push("ports.ranges");
+        }
+        if let Some(filter) = &self.log_filter {
+            cfg.log.filter = filter.clone();
+            set.push("log.filter");
+        }
+        set
+    }
+}
+
+// The config with the services of every included file appended to its own,
+// and the files it came from.
+#[derive(Debug)]
+pub struct LoadedConfig {
+    pub cfg: GlobalConfig,
+    pub main: ConfigSource,
+    // the file of every included service, by its index in `cfg.services`
+    pub included: HashMap<usize, ConfigSource>,
+    // keys set by the overrides
+    pub overridden: Vec<&'static str>,
+}
+
+impl LoadedConfig {
+    // The file the key at `path`, e.g. `services[3].servers[0]`, was read
+    // from, and its path in that file. None for a key set by an override.
+    pub fn locate(&self, path: &str) -> Option<(&ConfigSource, String)> {
+        if self.overridden.iter().any(|key| {
+            path.strip_prefix(key).map_or(false, |rest| {
+                rest.is_empty() || rest.starts_with(['.', '['])
+            })
+        }) {
+            return None;
+        }
+        let service = path
+            .strip_prefix("services[")
+            .and_then(|rest| rest.split_once(']'))
+            .and_then(|(index, rest)| Some((index.parse::<usize>().ok()?, rest)));
+        let located = match service {
+            Some((index, rest)) => match self.included.get(&index) {
+                Some(source) => (source, rest.trim_start_matches('.').to_string()),
+                None => (&self.main, path.to_string()),
+            },
+            None => (&self.main, path.to_string()),
+        };
+        Some(located)
+    }
+}
+
+// the files an entry of `include` stands for, in the order of their names
+fn resolve(dir: &Path, include: &str) -> Result<Vec<PathBuf>, FolonetError> {
+    let path = dir.join(include);
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+        .to_string();
+    let (dir, pattern) = if is_pattern(&name) {
+        (path.parent().unwrap_or(dir).to_path_buf(), Some(name))
+    } else if path.is_dir() {
+        (path, None)
+    } else {
+        return Ok(vec![path]);
+    };
+
+    let entries = fs::read_dir(&dir).map_err(|source| FolonetError::Io {
+        context: format!("failed to read the included {}", dir.display()),
+        source,
+    })?;
+    let mut files = vec![];
+    for entry in entries.flatten() {
+        let file = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        let wanted = match &pattern {
+            Some(pattern) => iface_watch::matches(pattern, &name),
+            None => ConfigFormat::is_config(&file),
+        };
+        // editors leave hidden swap and backup files next to the real ones
+        if wanted && !name.starts_with('.') && file.is_file() {
+            files.push(file);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// Read the config at `path`, in the format of its extension, and the service
+// of every file it includes, then apply `overrides`.
+pub fn load(path: &str, overrides: &Overrides) -> Result<LoadedConfig, FolonetError> {
+    let main = ConfigSource::read(Path::new(path))?;
+    let mut cfg: GlobalConfig = main.parse()?;
+    let dir = Path::new(path).parent().unwrap_or(Path::new("."));
+
+    let mut included = HashMap::new();
+    let mut seen = vec![];
+    for include in cfg.include.iter() {
+        for file in resolve(dir, include)? {
+            // a file matched by two entries is still one service
+            if seen.contains(&file) {
+                continue;
+            }
+            let source = ConfigSource::read(&file)?;
+            let service: ServiceConfig = source.parse()?;
+            included.insert(cfg.services.len(), source);
+            cfg.services.push(service);
+            seen.push(file);
+        }
+    }
+    let overridden = overrides.apply(&mut cfg);
+    Ok(LoadedConfig {
+        cfg,
+        main,
+        included,
+        overridden,
+    })
+}
+
+mod test {
+
+    #[test]
+    fn test_load() {
+        use std::fs;
+
+        use folonet_client::config::ServiceProtocol;
+
+        use super::{load, Overrides};
+        use crate::validate::line_of;
+
+        let dir = std::env::temp_dir().join(format!("folonet-loader-{}", std::process::id()));
+        let conf_d = dir.join("conf.d");
+        fs::create_dir_all(&conf_d).unwrap();
+        fs::write(
+            dir.join("folonet.toml"),
+            r#"
+include = ["conf.d", "extra/*.json"]
+
+[[services]]
+name = "web"
+local_endpoint = "10.0.0.1:80"
+servers = ["10.0.1.5:8080"]
+is_tcp = true
+"#,
+        )
+        .unwrap();
+        fs::write(
+            conf_d.join("b-dns.yaml"),
+            "name: dns\nlocal_endpoint: 10.0.0.1:53\nservers: [10.0.1.6:53]\n",
+        )
+        .unwrap();
+        fs::write(
+            conf_d.join("a-api.yaml"),
+            "name: api\nlocal_endpoint: 10.0.0.1:81\nservers:\n  - 10.0.1.7:8080\n  - 10.0.1.8\n",
+        )
+        .unwrap();
+        fs::write(conf_d.join(".a-api.yaml.swp"), "not a config").unwrap();
+        fs::write(conf_d.join("README"), "not a config either").unwrap();
+        fs::create_dir_all(dir.join("extra")).unwrap();
+        fs::write(
+            dir.join("extra").join("grpc.json"),
+            r#"{"name": "grpc", "local_endpoint": "10.0.0.1:82", "servers": [], "is_tcp": true}"#,
+        )
+        .unwrap();
+
+        let loaded = load(
+            dir.join("folonet.toml").to_str().unwrap(),
+            &Overrides::default(),
+        )
+        .unwrap();
+        let names: Vec<&str> = loaded
+            .cfg
+            .services
+            .iter()
+            .map(|service| service.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["web", "api", "dns", "grpc"]);
+        assert_eq!(
+            loaded.cfg.services[2].served_protocols(),
+            vec![ServiceProtocol::Udp]
+        );
+
+        let (source, path) = loaded.locate("services[1].servers[1]").unwrap();
+        assert!(source.path.ends_with("a-api.yaml"));
+        assert_eq!(path, "servers[1]");
+        assert_eq!(line_of(source.format(), &source.text, &path), Some(5));
+        let (source, path) = loaded.locate("services[0].name").unwrap();
+        assert!(source.path.ends_with("folonet.toml"));
+        assert_eq!(path, "services[0].name");
+        assert_eq!(line_of(source.format(), &source.text, &path), Some(5));
+        assert_eq!(
+            line_of(source.format(), &source.text, "services[0].servers[0]"),
+            Some(7)
+        );
+
+        // a broken included file names itself
+        fs::write(conf_d.join("c-bad.yaml"), "name: [\n").unwrap();
+        let err = load(
+            dir.join("folonet.toml").to_str().unwrap(),
+            &Overrides::default(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("c-bad.yaml"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_overrides() {
+        use std::collections::HashMap;
+
+        use folonet_client::config::GlobalConfig;
+
+        use super::{ConfigSource, LoadedConfig, Overrides};
+
+        let text = "interfaces:\n  - name: eth0\n    local_ips: [10.0.0.1]\nmanager:\n  addr: http://10.0.0.5:50051\n";
+        let mut cfg: GlobalConfig = serde_yaml::from_str(text).unwrap();
+        let overrides = Overrides {
+            interfaces: vec!["eth1".to_string(), "eth0".to_string()],
+            manager_addr: Some("http://manager:50051".to_string()),
+            ..Overrides::default()
+        };
+        let overridden = overrides.apply(&mut cfg);
+        assert_eq!(overridden, vec!["interfaces", "manager.addr"]);
+        assert_eq!(cfg.interfaces[0].name, "eth1");
+        assert!(cfg.interfaces[0].local_ips.is_empty());
+        assert_eq!(cfg.interfaces[1].local_ips, vec!["10.0.0.1".to_string()]);
+        assert_eq!(cfg.manager.addr, "http://manager:50051");
+        assert_eq!(cfg.log.filter, "info");
+
+        let loaded = LoadedConfig {
+            cfg,
+            main: ConfigSource {
+                path: "folonet.yaml".to_string(),
+                text: text.to_string(),
+            },
+            included: HashMap::new(),
+            overridden,
+        };
+        assert!(loaded.locate("interfaces[0].name").is_none());
+        assert!(loaded.locate("manager.addr").is_none());
+        assert!(loaded.locate("manager.token").is_some());
+        assert!(loaded.locate("interfaces_extra").is_some());
+    }
+}
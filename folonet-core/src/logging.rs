@@ -0,0 +1,187 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::OnceCell;
+
+use crate::error::FolonetError;
+
+// `info,folonet_core::state=debug` in the syntax of RUST_LOG: a level for
+// every module, and more specific ones for some modules and what is below
+// them. The messages of the xdp program come from the `folonet` module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFilter {
+    default: LevelFilter,
+    // the longest module first
+    modules: Vec<(String, LevelFilter)>,
+}
+
+fn parse_level(s: &str) -> Result<LevelFilter, FolonetError> {
+    LevelFilter::from_str(s).map_err(|_| {
+        FolonetError::Config(format!(
+            "unknown log level {}, expected off, error, warn, info, debug or trace",
+            s
+        ))
+    })
+}
+
+impl FromStr for LogFilter {
+    type Err = FolonetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = LogFilter {
+            default: LevelFilter::Error,
+            modules: vec![],
+        };
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let level = parse_level(level.trim())?;
+                    filter.modules.retain(|(m, _)| m != module.trim());
+                    filter.modules.push((module.trim().to_string(), level));
+                }
+                // a lone module is all of it, like RUST_LOG
+                None => match parse_level(directive) {
+                    Ok(level) => filter.default = level,
+                    Err(_) => filter
+                        .modules
+                        .push((directive.to_string(), LevelFilter::Trace)),
+                },
+            }
+        }
+        filter
+            .modules
+            .sort_by(|a, b| b.0.len().cmp(&a.0.len()).then(a.0.cmp(&b.0)));
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default.as_str().to_lowercase())?;
+        for (module, level) in self.modules.iter().rev() {
+            write!(f, ",{}={}", module, level.as_str().to_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+impl LogFilter {
+    pub fn level_of(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| {
+                target == module
+                    || target
+                        .strip_prefix(module.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    // the most verbose level of any module, what the log macros check first
+    fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, LevelFilter::max)
+    }
+}
+
+// env_logger formats and writes, the filter can change at any time
+struct DynLogger {
+    filter: RwLock<LogFilter>,
+    inner: env_logger::Logger,
+}
+
+impl Log for DynLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.read().unwrap().level_of(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+static LOGGER: OnceCell<&'static DynLogger> = OnceCell::new();
+
+// install the logger of the process, RUST_LOG wins over `spec` when set
+pub fn init(spec: &str) -> Result<(), FolonetError> {
+    match std::env::var("RUST_LOG") {
+        Ok(env) => install(env.parse()?),
+        Err(_) => install(spec.parse()?),
+    }
+}
+
+// install the logger with `filter`, whatever RUST_LOG says
+pub fn install(filter: LogFilter) -> Result<(), FolonetError> {
+    let inner = env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .build();
+    log::set_max_level(filter.max());
+    let logger: &'static DynLogger = Box::leak(Box::new(DynLogger {
+        filter: RwLock::new(filter),
+        inner,
+    }));
+    log::set_logger(logger).map_err(|e| FolonetError::Config(e.to_string()))?;
+    let _ = LOGGER.set(logger);
+    Ok(())
+}
+
+// replace the filter of the running process, see init
+pub fn set_filter(filter: LogFilter) -> Result<(), FolonetError> {
+    let logger = LOGGER
+        .get()
+        .ok_or_else(|| FolonetError::Config("the logger is not installed".to_string()))?;
+    log::set_max_level(filter.max());
+    *logger.filter.write().unwrap() = filter;
+    Ok(())
+}
+
+pub fn filter() -> Option<LogFilter> {
+    LOGGER
+        .get()
+        .map(|logger| logger.filter.read().unwrap().clone())
+}
+
+mod test {
+
+    #[test]
+    fn test_log_filter() {
+        use log::LevelFilter;
+
+        use super::LogFilter;
+
+        let filter: LogFilter = "info,folonet_core::state=debug,folonet_core=warn"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            filter.level_of("folonet_core::state::tcp"),
+            LevelFilter::Debug
+        );
+        assert_eq!(filter.level_of("folonet_core::engine"), LevelFilter::Warn);
+        // a prefix of a name is not its parent
+        assert_eq!(filter.level_of("folonet_core_x"), LevelFilter::Info);
+        assert_eq!(filter.level_of("folonet"), LevelFilter::Info);
+        assert_eq!(filter.max(), LevelFilter::Debug);
+        assert_eq!(
+            filter.to_string(),
+            "info,folonet_core=warn,folonet_core::state=debug"
+        );
+        assert_eq!(filter.to_string().parse::<LogFilter>().unwrap(), filter);
+
+        let filter: LogFilter = "folonet".parse().unwrap();
+        assert_eq!(filter.level_of("folonet"), LevelFilter::Trace);
+        assert_eq!(filter.level_of("aya"), LevelFilter::Error);
+        assert!("info,folonet=loud".parse::<LogFilter>().is_err());
+    }
+}
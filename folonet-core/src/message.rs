@@ -57,6 +57,17 @@ impl Message {
             to: self.server,
         }
     }
+
+    // A syn, or any udp packet. The nat entries of a shed syn are closed
+    // right away, and the idle sweep closes udp flows whether their packets
+    // were seen or not.
+    pub fn opens_connection(&self) -> bool {
+        match &self.msg_type {
+            MessageType::Packet(PacketMsgType::TCP(packet)) => packet.is_syn() && !packet.is_ack(),
+            MessageType::Packet(PacketMsgType::UDP) => true,
+            MessageType::Close => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
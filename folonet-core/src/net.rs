@@ -0,0 +1,20 @@
+pub fn get_interafce_index(ifce: String) -> Option<u32> {
+    pnet::datalink::interfaces()
+        .iter()
+        .find(|i| i.name == ifce)
+        .map(|i| i.index)
+}
+
+pub fn interface_is_up(ifce: &str) -> Option<bool> {
+    pnet::datalink::interfaces()
+        .iter()
+        .find(|i| i.name == ifce)
+        .map(|i| i.is_up())
+}
+
+pub fn interface_name(index: u32) -> Option<String> {
+    pnet::datalink::interfaces()
+        .into_iter()
+        .find(|i| i.index == index)
+        .map(|i| i.name)
+}
@@ -0,0 +1,215 @@
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+
+use aya::maps::{HashMap as AyaHashMap, Map, MapData};
+use aya::Pod;
+use folonet_client::config::PinConfig;
+use folonet_common::nat::KNat;
+use folonet_common::service::KServiceKey;
+
+use crate::admin::{ct_name, way_name};
+use crate::endpoint::{try_mac_from_string, Endpoint, UConnection, UEndpoint};
+use crate::error::{FolonetError, MapResultExt};
+use crate::output::{MapEntry, MapReport};
+use crate::pin::Pins;
+
+pub const OFFLINE_MAPS: &[&str] = &["connection", "server", "ip_mac"];
+
+// the name a map is pinned under
+fn pinned_name(map: &str) -> Result<&'static str, FolonetError> {
+    match map {
+        "connection" => Ok("CONNECTION"),
+        "server" => Ok("SERVER_MAP"),
+        "ip_mac" => Ok("IP_MAC_MAP"),
+        _ => Err(FolonetError::Config(format!(
+            "unknown map {}, expected one of {}",
+            map,
+            OFFLINE_MAPS.join(", ")
+        ))),
+    }
+}
+
+// `10.0.0.1:80/tcp`, as the server map is dumped
+fn parse_service_key(s: &str) -> Result<KServiceKey, FolonetError> {
+    let (endpoint, proto) = s.rsplit_once('/').ok_or_else(|| {
+        FolonetError::Config(format!("{} is not an ip:port/tcp or ip:port/udp", s))
+    })?;
+    let is_tcp = match proto {
+        "tcp" => true,
+        "udp" => false,
+        _ => {
+            return Err(FolonetError::Config(format!(
+                "unknown protocol {}, expected tcp or udp",
+                proto
+            )))
+        }
+    };
+    Ok(endpoint.parse::<Endpoint>()?.to_service_key(is_tcp))
+}
+
+// `10.0.0.2:40000 -> 10.0.0.1:80`, as the connection map is dumped
+fn parse_way(s: &str) -> Result<UConnection, FolonetError> {
+    let (from, to) = s
+        .split_once("->")
+        .ok_or_else(|| FolonetError::Config(format!("{} is not a from -> to way", s)))?;
+    Ok(UConnection::new(
+        from.trim().parse::<Endpoint>()?,
+        to.trim().parse::<Endpoint>()?,
+    ))
+}
+
+// the IP_MAC_MAP key of `ip`, in network order as the xdp program reads it
+fn parse_ip(s: &str) -> Result<u32, FolonetError> {
+    let ip = s
+        .parse::<Ipv4Addr>()
+        .map_err(|_| FolonetError::Config(format!("invalid ip address {}", s)))?;
+    Ok(u32::from(ip).to_be())
+}
+
+fn mac_name(mac: u64) -> String {
+    mac.to_be_bytes()[2..]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// The maps a datapath pinned, opened without the daemon that loaded it, to
+// look into or fix a live system without restarting it. Edits go to the
+// kernel behind the back of a running daemon, which does not learn of them.
+pub struct OfflineMaps {
+    dir: PathBuf,
+}
+
+impl OfflineMaps {
+    pub fn new(cfg: &PinConfig) -> Result<Self, FolonetError> {
+        let pins = Pins::new(cfg);
+        pins.check_schema()?;
+        Ok(OfflineMaps {
+            dir: pins.maps_dir(),
+        })
+    }
+
+    fn open<K: Pod, V: Pod>(
+        &self,
+        name: &'static str,
+    ) -> Result<AyaHashMap<MapData, K, V>, FolonetError> {
+        let data = MapData::from_pin(self.dir.join(name)).map_context(name)?;
+        AyaHashMap::try_from(Map::HashMap(data)).map_context(name)
+    }
+
+    pub fn dump(&self, map: &str) -> Result<MapReport, FolonetError> {
+        let name = pinned_name(map)?;
+        let mut entries: Vec<MapEntry> = match map {
+            "connection" => self
+                .open::<UConnection, KNat>(name)?
+                .iter()
+                .filter_map(|item| item.ok())
+                .map(|(way, nat)| MapEntry {
+                    key: way_name(&way),
+                    value: format!(
+                        "{} ({})",
+                        way_name(&UConnection::from(nat.fwd.way)),
+                        ct_name(nat.ct.state)
+                    ),
+                })
+                .collect(),
+            "server" => self
+                .open::<KServiceKey, UEndpoint>(name)?
+                .iter()
+                .filter_map(|item| item.ok())
+                .map(|(service, backend)| MapEntry {
+                    key: format!(
+                        "{}/{}",
+                        Endpoint::new(service.endpoint).to_string(),
+                        if service.is_tcp() { "tcp" } else { "udp" }
+                    ),
+                    value: backend.to_endpoint().to_string(),
+                })
+                .collect(),
+            _ => self
+                .open::<u32, u64>(name)?
+                .iter()
+                .filter_map(|item| item.ok())
+                .map(|(ip, mac)| MapEntry {
+                    key: Ipv4Addr::from(u32::from_be(ip)).to_string(),
+                    value: mac_name(mac),
+                })
+                .collect(),
+        };
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(MapReport {
+            map: map.to_string(),
+            entries,
+        })
+    }
+
+    // a connection is only ever written by the xdp program, its entries are
+    // too much state to be typed in
+    pub fn insert(&self, map: &str, key: &str, value: &str) -> Result<(), FolonetError> {
+        let name = pinned_name(map)?;
+        match map {
+            "server" => self
+                .open::<KServiceKey, UEndpoint>(name)?
+                .insert(
+                    parse_service_key(key)?,
+                    value.parse::<Endpoint>()?.to_u_endpoint(),
+                    0,
+                )
+                .map_context(name),
+            "ip_mac" => self
+                .open::<u32, u64>(name)?
+                .insert(parse_ip(key)?, try_mac_from_string(value)?.val(), 0)
+                .map_context(name),
+            _ => Err(FolonetError::Config(format!(
+                "entries of {} can only be dumped and deleted",
+                map
+            ))),
+        }
+    }
+
+    pub fn delete(&self, map: &str, key: &str) -> Result<(), FolonetError> {
+        let name = pinned_name(map)?;
+        match map {
+            "connection" => self
+                .open::<UConnection, KNat>(name)?
+                .remove(&parse_way(key)?)
+                .map_context(name),
+            "server" => self
+                .open::<KServiceKey, UEndpoint>(name)?
+                .remove(&parse_service_key(key)?)
+                .map_context(name),
+            _ => self
+                .open::<u32, u64>(name)?
+                .remove(&parse_ip(key)?)
+                .map_context(name),
+        }
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_parse_keys() {
+        use super::{mac_name, parse_ip, parse_service_key, parse_way, pinned_name};
+        use crate::endpoint::try_mac_from_string;
+
+        let key = parse_service_key("10.0.0.1:53/udp").unwrap();
+        assert!(!key.is_tcp());
+        assert!(parse_service_key("10.0.0.1:53").is_err());
+        assert!(parse_service_key("10.0.0.1:53/sctp").is_err());
+
+        let way = parse_way("10.0.0.2:40000 -> 10.0.0.1:80").unwrap();
+        assert_eq!(way.from_endpoint().to_string(), "10.0.0.2:40000");
+        assert_eq!(way.to_endpoint().to_string(), "10.0.0.1:80");
+
+        // the bytes of the ip header, whatever the host order
+        assert_eq!(
+            parse_ip("10.0.0.1").unwrap(),
+            u32::from_ne_bytes([10, 0, 0, 1])
+        );
+        let mac = try_mac_from_string("02:42:ac:11:00:02").unwrap();
+        assert_eq!(mac_name(mac.val()), "02:42:ac:11:00:02");
+        assert!(pinned_name("epoch").is_err());
+    }
+}
@@ -0,0 +1,604 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cold_start::ColdStartStats;
+use crate::endpoint::Endpoint;
+use crate::error::FolonetError;
+use crate::latency::BackendHandshake;
+use crate::ports::{PortPoolStats, PortQuotaStats};
+use crate::stats::IfaceStats;
+use crate::validate::ConfigProblem;
+
+// Bumped whenever a field of a report is renamed, removed or changes meaning,
+// or a report changes its KIND. New fields and new kinds may be added without
+// a bump, consumers must ignore unknown ones.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    #[default]
+    Human,
+    Json,
+    Yaml,
+}
+
+impl FromStr for OutputFormat {
+    type Err = FolonetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(OutputFormat::Human),
+            "json" => Ok(OutputFormat::Json),
+            "yaml" => Ok(OutputFormat::Yaml),
+            _ => Err(FolonetError::Config(format!(
+                "unknown output format {}, expected human, json or yaml",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputFormat::Human => write!(f, "human"),
+            OutputFormat::Json => write!(f, "json"),
+            OutputFormat::Yaml => write!(f, "yaml"),
+        }
+    }
+}
+
+// what an inspection command prints, `KIND` names its schema
+pub trait Report: Serialize {
+    const KIND: &'static str;
+
+    fn human(&self) -> String;
+}
+
+// the envelope of every machine readable report
+#[derive(Serialize)]
+struct Envelope<'a, T: Serialize> {
+    schema_version: u32,
+    kind: &'static str,
+    data: &'a T,
+}
+
+// The human output is for people and may change with any release. The json
+// and yaml output is `{schema_version, kind, data}`, `data` the report with
+// the fields of its struct, and holds to SCHEMA_VERSION: a consumer checks
+// both before it reads `data`. The README lists the kinds.
+pub fn render<T: Report>(report: &T, format: OutputFormat) -> Result<String, FolonetError> {
+    let envelope = Envelope {
+        schema_version: SCHEMA_VERSION,
+        kind: T::KIND,
+        data: report,
+    };
+    match format {
+        OutputFormat::Human => Ok(report.human()),
+        OutputFormat::Json => {
+            serde_json::to_string_pretty(&envelope).map_err(|e| FolonetError::Encode(e.to_string()))
+        }
+        OutputFormat::Yaml => {
+            serde_yaml::to_string(&envelope).map_err(|e| FolonetError::Encode(e.to_string()))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServiceRow {
+    pub name: String,
+    pub protocol: Protocol,
+    pub local_endpoint: String,
+    pub backends: Vec<String>,
+    // connections currently open
+    pub connections: u64,
+    // connections it may have open at once, none for no limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct ServicesReport {
+    pub services: Vec<ServiceRow>,
+}
+
+impl Report for ServicesReport {
+    const KIND: &'static str = "services";
+
+    fn human(&self) -> String {
+        let mut out = format!(
+            "{:<20} {:<5} {:<22} {:>6}  BACKENDS\n",
+            "NAME", "PROTO", "ENDPOINT", "CONNS"
+        );
+        for s in self.services.iter() {
+            let connections = match s.max_connections {
+                Some(max) => format!("{}/{}", s.connections, max),
+                None => s.connections.to_string(),
+            };
+            out.push_str(&format!(
+                "{:<20} {:<5} {:<22} {:>6}  {}\n",
+                s.name,
+                s.protocol.as_str(),
+                s.local_endpoint,
+                connections,
+                s.backends.join(",")
+            ));
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConnectionRow {
+    pub service: String,
+    pub protocol: Protocol,
+    pub client: String,
+    pub backend: String,
+    // the local port the connection to the backend goes out from
+    pub local_port: Option<u16>,
+    // client side state of the tcp state machine, none for udp
+    pub state: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct ConnectionsReport {
+    pub connections: Vec<ConnectionRow>,
+}
+
+impl Report for ConnectionsReport {
+    const KIND: &'static str = "connections";
+
+    fn human(&self) -> String {
+        let mut out = format!(
+            "{:<20} {:<5} {:<22} {:<22} {:>6}  STATE\n",
+            "SERVICE", "PROTO", "CLIENT", "BACKEND", "PORT"
+        );
+        for c in self.connections.iter() {
+            out.push_str(&format!(
+                "{:<20} {:<5} {:<22} {:<22} {:>6}  {}\n",
+                c.service,
+                c.protocol.as_str(),
+                c.client,
+                c.backend,
+                c.local_port.map(|p| p.to_string()).unwrap_or_default(),
+                c.state.as_deref().unwrap_or("-")
+            ));
+        }
+        out
+    }
+}
+
+// the nat entries of one connection, the two ways they translate
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NatFlow {
+    pub service: String,
+    pub protocol: Protocol,
+    // client side state of the tcp state machine, none for udp
+    pub state: Option<String>,
+    // of the xdp program, none once its entries are gone
+    pub kernel_state: Option<String>,
+    // client -> service, as the packets of the client arrive
+    pub client: Endpoint,
+    pub local_in: Endpoint,
+    // backend -> local, as the packets of the backend arrive
+    pub backend: Endpoint,
+    pub local_out: Endpoint,
+    // until the connection is closed without another packet, none when
+    // only a close does
+    pub timeout_secs: Option<u64>,
+    pub age_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct NatTableReport {
+    pub flows: Vec<NatFlow>,
+}
+
+// `SynSent` as conntrack has it, `SYN_SENT`
+fn conntrack_state(state: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in state.chars().enumerate() {
+        if i > 0 && c.is_ascii_uppercase() {
+            out.push('_');
+        }
+        out.push(c.to_ascii_uppercase());
+    }
+    out
+}
+
+impl Report for NatTableReport {
+    const KIND: &'static str = "nat_table";
+
+    // one line per connection like /proc/net/nf_conntrack, for grep and awk
+    fn human(&self) -> String {
+        let mut out = String::new();
+        for f in self.flows.iter() {
+            let number = match f.protocol {
+                Protocol::Tcp => 6,
+                Protocol::Udp => 17,
+            };
+            let timeout = f
+                .timeout_secs
+                .map(|secs| secs.to_string())
+                .unwrap_or_else(|| "-".to_string());
+            out.push_str(&format!(
+                "{:<8} {:<2} {} ",
+                f.protocol.as_str(),
+                number,
+                timeout
+            ));
+            if let Some(state) = &f.state {
+                out.push_str(&format!("{} ", conntrack_state(state)));
+            }
+            out.push_str(&format!(
+                "src={} dst={} sport={} dport={} src={} dst={} sport={} dport={} kernel={} service={} age={}\n",
+                f.client.ip,
+                f.local_in.ip,
+                f.client.port,
+                f.local_in.port,
+                f.backend.ip,
+                f.local_out.ip,
+                f.backend.port,
+                f.local_out.port,
+                f.kernel_state.as_deref().unwrap_or("gone"),
+                f.service,
+                f.age_secs
+            ));
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PortsReport {
+    pub ranges: Vec<String>,
+    #[serde(flatten)]
+    pub stats: PortPoolStats,
+    pub quotas: Vec<PortQuotaStats>,
+}
+
+impl Report for PortsReport {
+    const KIND: &'static str = "ports";
+
+    fn human(&self) -> String {
+        let mut out = format!(
+            "ranges: {}\npool size: {}\nfree: {}\nin use: {}\nquarantined: {}\nalloc/s: {:.1}\nrelease/s: {:.1}\n",
+            self.ranges.join(","),
+            self.stats.pool_size,
+            self.stats.free,
+            self.stats.in_use,
+            self.stats.quarantined,
+            self.stats.alloc_rate,
+            self.stats.release_rate
+        );
+        if let Some(secs) = self.stats.time_to_exhaustion_secs {
+            out.push_str(&format!("exhausted in: {:.0}s\n", secs));
+        }
+        for q in self.quotas.iter() {
+            out.push_str(&format!(
+                "quota {} ({}): {} of {} in use, {} free\n",
+                q.service, q.local_endpoint, q.in_use, q.limit, q.free
+            ));
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DropRow {
+    // the name of the kernel counter
+    pub reason: String,
+    pub packets: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct DropsReport {
+    pub drops: Vec<DropRow>,
+}
+
+impl Report for DropsReport {
+    const KIND: &'static str = "drops";
+
+    fn human(&self) -> String {
+        let mut out = format!("{:<24} {:>12}\n", "REASON", "PACKETS");
+        for d in self.drops.iter() {
+            out.push_str(&format!("{:<24} {:>12}\n", d.reason, d.packets));
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CounterRow {
+    pub name: String,
+    pub value: u64,
+    // a packet the xdp program dropped, see DropsReport
+    pub drop: bool,
+}
+
+// every kernel counter, summed over the cpus
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct StatsReport {
+    pub counters: Vec<CounterRow>,
+}
+
+impl Report for StatsReport {
+    const KIND: &'static str = "stats";
+
+    fn human(&self) -> String {
+        let mut out = format!("{:<32} {:>12}\n", "COUNTER", "VALUE");
+        for c in self.counters.iter() {
+            out.push_str(&format!("{:<32} {:>12}\n", c.name, c.value));
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct InterfacesReport {
+    pub interfaces: Vec<IfaceStats>,
+}
+
+impl Report for InterfacesReport {
+    const KIND: &'static str = "interfaces";
+
+    fn human(&self) -> String {
+        let mut out = format!(
+            "{:<16} {:>12} {:>12} {:>12} {:>12} {:>12} {:>12} {:>12}\n",
+            "IFACE", "RX", "MATCHED", "TX", "REDIRECTED", "PASSED", "DROPPED", "ABORTED"
+        );
+        for i in self.interfaces.iter() {
+            out.push_str(&format!(
+                "{:<16} {:>12} {:>12} {:>12} {:>12} {:>12} {:>12} {:>12}\n",
+                i.iface, i.rx, i.matched, i.tx, i.redirected, i.passed, i.dropped, i.aborted
+            ));
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct HandshakesReport {
+    pub backends: Vec<BackendHandshake>,
+}
+
+impl Report for HandshakesReport {
+    const KIND: &'static str = "handshakes";
+
+    fn human(&self) -> String {
+        let mut out = format!(
+            "{:<24} {:>10} {:>10} {:>10} {:>10} {:>10}\n",
+            "BACKEND", "SAMPLES", "P50_US", "P90_US", "P99_US", "MAX_US"
+        );
+        for b in self.backends.iter() {
+            out.push_str(&format!(
+                "{:<24} {:>10} {:>10} {:>10} {:>10} {:>10}\n",
+                b.backend,
+                b.samples,
+                b.p50_ns / 1000,
+                b.p90_ns / 1000,
+                b.p99_ns / 1000,
+                b.max_ns / 1000
+            ));
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct ColdStartsReport {
+    pub services: Vec<ColdStartStats>,
+}
+
+impl Report for ColdStartsReport {
+    const KIND: &'static str = "cold_starts";
+
+    fn human(&self) -> String {
+        let mut out = format!(
+            "{:<22} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}\n",
+            "SERVICE", "STARTS", "OK", "FAILED", "P50_MS", "P90_MS", "P99_MS", "MAX_MS"
+        );
+        for s in self.services.iter() {
+            out.push_str(&format!(
+                "{:<22} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}\n",
+                s.service,
+                s.attempts,
+                s.successes,
+                s.failures,
+                s.p50_ms,
+                s.p90_ms,
+                s.p99_ms,
+                s.max_ms
+            ));
+        }
+        out
+    }
+}
+
+// the packet events waiting for a service, and those it shed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct QueueRow {
+    pub local_endpoint: String,
+    pub protocol: Protocol,
+    pub depth: usize,
+    pub capacity: usize,
+    pub shed: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct QueuesReport {
+    pub queues: Vec<QueueRow>,
+}
+
+impl Report for QueuesReport {
+    const KIND: &'static str = "queues";
+
+    fn human(&self) -> String {
+        let mut out = format!(
+            "{:<24} {:<6} {:>10} {:>10} {:>12}\n",
+            "SERVICE", "PROTO", "DEPTH", "CAPACITY", "SHED"
+        );
+        for q in self.queues.iter() {
+            out.push_str(&format!(
+                "{:<24} {:<6} {:>10} {:>10} {:>12}\n",
+                q.local_endpoint,
+                q.protocol.as_str(),
+                q.depth,
+                q.capacity,
+                q.shed
+            ));
+        }
+        out
+    }
+}
+
+// what a command changing the daemon did
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ActionReport {
+    pub message: String,
+}
+
+impl Report for ActionReport {
+    const KIND: &'static str = "action";
+
+    fn human(&self) -> String {
+        format!("{}\n", self.message)
+    }
+}
+
+// a config that passed the checks, with what it got warned about
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigCheckReport {
+    pub config: String,
+    pub warnings: Vec<ConfigProblem>,
+}
+
+impl Report for ConfigCheckReport {
+    const KIND: &'static str = "config_check";
+
+    fn human(&self) -> String {
+        let mut out = format!("{} is valid\n", self.config);
+        for warning in self.warnings.iter() {
+            out.push_str(&format!("  {}\n", warning));
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MapEntry {
+    pub key: String,
+    pub value: String,
+}
+
+// the entries of one kernel map, keys and values as text
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct MapReport {
+    pub map: String,
+    pub entries: Vec<MapEntry>,
+}
+
+impl Report for MapReport {
+    const KIND: &'static str = "map";
+
+    fn human(&self) -> String {
+        let mut out = format!("{} ({} entries)\n", self.map, self.entries.len());
+        for e in self.entries.iter() {
+            out.push_str(&format!("{} -> {}\n", e.key, e.value));
+        }
+        out
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_output_format() {
+        use super::OutputFormat;
+
+        assert_eq!("json".parse::<OutputFormat>().unwrap(), OutputFormat::Json);
+        assert_eq!("yaml".parse::<OutputFormat>().unwrap(), OutputFormat::Yaml);
+        assert!("xml".parse::<OutputFormat>().is_err());
+        assert_eq!(OutputFormat::default().to_string(), "human");
+    }
+
+    #[test]
+    fn test_render_schema() {
+        use super::{render, DropRow, DropsReport, OutputFormat, SCHEMA_VERSION};
+
+        let report = DropsReport {
+            drops: vec![DropRow {
+                reason: "acl_denied".to_string(),
+                packets: 3,
+            }],
+        };
+
+        let json = render(&report, OutputFormat::Json).unwrap();
+        let v: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(v["schema_version"], SCHEMA_VERSION);
+        assert_eq!(v["kind"], "drops");
+        assert_eq!(v["data"]["drops"][0]["reason"], "acl_denied");
+        assert_eq!(v["data"]["drops"][0]["packets"], 3);
+
+        let yaml = render(&report, OutputFormat::Yaml).unwrap();
+        let v: serde_json::Value = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(v["kind"], "drops");
+        assert_eq!(v["data"]["drops"][0]["packets"], 3);
+
+        let human = render(&report, OutputFormat::Human).unwrap();
+        assert!(human.contains("acl_denied"));
+    }
+
+    #[test]
+    fn test_nat_table() {
+        use super::{NatFlow, NatTableReport, Protocol, Report};
+
+        let flow = NatFlow {
+            service: "web".to_string(),
+            protocol: Protocol::Tcp,
+            state: Some("SynSent".to_string()),
+            kernel_state: Some("new".to_string()),
+            client: "10.0.0.2:40000".parse().unwrap(),
+            local_in: "10.0.0.1:80".parse().unwrap(),
+            backend: "10.0.1.5:8080".parse().unwrap(),
+            local_out: "10.0.0.3:10001".parse().unwrap(),
+            timeout_secs: Some(74),
+            age_secs: 1,
+        };
+        let udp = NatFlow {
+            service: "dns".to_string(),
+            protocol: Protocol::Udp,
+            state: None,
+            kernel_state: None,
+            timeout_secs: None,
+            ..flow.clone()
+        };
+        let report = NatTableReport {
+            flows: vec![flow, udp],
+        };
+        assert_eq!(
+            report.human(),
+            "tcp      6  74 SYN_SENT src=10.0.0.2 dst=10.0.0.1 sport=40000 dport=80 src=10.0.1.5 dst=10.0.0.3 sport=8080 dport=10001 kernel=new service=web age=1\n\
+             udp      17 - src=10.0.0.2 dst=10.0.0.1 sport=40000 dport=80 src=10.0.1.5 dst=10.0.0.3 sport=8080 dport=10001 kernel=gone service=dns age=1\n"
+        );
+    }
+}
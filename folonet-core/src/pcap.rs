@@ -0,0 +1,245 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use aya::maps::{Array, MapData, RingBuf};
+use folonet_common::pcap::{KPcapFilter, KPcapRecord, PCAP_AFTER, PCAP_SNAPLEN};
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+use crate::endpoint::Endpoint;
+use crate::error::{FolonetError, MapResultExt};
+use crate::flow_log::ktime_now_ns;
+use crate::poll::PollBackoff;
+
+pub type BpfPcapFilterMap = Array<MapData, KPcapFilter>;
+
+const LINKTYPE_ETHERNET: u16 = 1;
+// the interfaces of the file, one per stage of the packets
+const IFACE_NAMES: [&str; 2] = ["before", "after"];
+
+fn pad4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+// one pcapng block, `body` padded and framed by its length
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let total = (12 + pad4(body.len())) as u32;
+    let mut out = Vec::with_capacity(total as usize);
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&total.to_le_bytes());
+    out.extend_from_slice(body);
+    out.resize(total as usize - 4, 0);
+    out.extend_from_slice(&total.to_le_bytes());
+    out
+}
+
+fn option(code: u16, value: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(4 + pad4(value.len()));
+    out.extend_from_slice(&code.to_le_bytes());
+    out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    out.extend_from_slice(value);
+    out.resize(4 + pad4(value.len()), 0);
+    out
+}
+
+// Writes the copied packets as pcapng, which wireshark reads. The packets as
+// the xdp program got them are on interface `before`, as it sent them on
+// `after`, each one right after the other.
+pub struct PcapngWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    pub fn new(mut out: W) -> io::Result<Self> {
+        // section header: byte order magic, version 1.0, unknown length
+        let mut shb = vec![];
+        shb.extend_from_slice(&0x1a2b3c4du32.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        out.write_all(&block(0x0a0d0d0a, &shb))?;
+
+        for name in IFACE_NAMES {
+            let mut idb = vec![];
+            idb.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+            idb.extend_from_slice(&0u16.to_le_bytes());
+            idb.extend_from_slice(&(PCAP_SNAPLEN as u32).to_le_bytes());
+            idb.extend(option(2, name.as_bytes()));
+            // timestamps in nanoseconds
+            idb.extend(option(9, &[9]));
+            idb.extend(option(0, &[]));
+            out.write_all(&block(1, &idb))?;
+        }
+        Ok(PcapngWriter { out })
+    }
+
+    // `wall_ns` is when the packet was copied, unix time in nanoseconds
+    pub fn write(&mut self, record: &KPcapRecord, wall_ns: u64) -> io::Result<()> {
+        let packet = record.packet();
+        let iface = (record.stage == PCAP_AFTER) as u32;
+        let mut epb = vec![];
+        epb.extend_from_slice(&iface.to_le_bytes());
+        epb.extend_from_slice(&((wall_ns >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(wall_ns as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&record.len.to_le_bytes());
+        epb.extend_from_slice(packet);
+        self.out.write_all(&block(6, &epb))
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CaptureOptions {
+    // copy one in this many packets of the service
+    pub one_in: u32,
+    // stop after this many packets, before and after counted apart
+    pub max_packets: usize,
+    pub duration: Duration,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        CaptureOptions {
+            one_in: 1,
+            max_packets: 1000,
+            duration: Duration::from_secs(10),
+        }
+    }
+}
+
+// What `folonet pcap` runs in the daemon: the xdp program copies the packets
+// of one service, before and after their rewrite, to PCAP_RING while a
+// capture runs. One capture at a time.
+#[derive(Clone)]
+pub struct Capture {
+    filter: Arc<Mutex<BpfPcapFilterMap>>,
+    ring: Arc<Mutex<RingBuf<MapData>>>,
+}
+
+impl Capture {
+    pub fn new(filter: BpfPcapFilterMap, ring: RingBuf<MapData>) -> Self {
+        Capture {
+            filter: Arc::new(Mutex::new(filter)),
+            ring: Arc::new(Mutex::new(ring)),
+        }
+    }
+
+    async fn set_filter(&self, filter: KPcapFilter) -> Result<(), FolonetError> {
+        self.filter
+            .lock()
+            .await
+            .set(0, filter, 0)
+            .map_context("PCAP_FILTER")
+    }
+
+    // write the packets of `service` to a pcapng file at `path`, returns how
+    // many were written
+    pub async fn run(
+        &self,
+        service: Endpoint,
+        path: &str,
+        opts: &CaptureOptions,
+    ) -> Result<usize, FolonetError> {
+        let mut ring = self
+            .ring
+            .try_lock()
+            .map_err(|_| FolonetError::Admin("a capture is already running".to_string()))?;
+        // what a capture stopped in the middle of a packet left behind
+        while ring.next().is_some() {}
+
+        let io = |context: String| move |source| FolonetError::Io { context, source };
+        let file = File::create(path).map_err(io(format!("failed to create {}", path)))?;
+        let mut writer = PcapngWriter::new(BufWriter::new(file))
+            .map_err(io(format!("failed to write {}", path)))?;
+
+        let wall_now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        let offset = wall_now.saturating_sub(ktime_now_ns());
+        self.set_filter(KPcapFilter {
+            service: service.to_k_endpoint(),
+            one_in: opts.one_in.max(1),
+            _pad: 0,
+        })
+        .await?;
+
+        let deadline = Instant::now() + opts.duration;
+        let mut written = 0;
+        let mut backoff = PollBackoff::default();
+        let result = loop {
+            if written >= opts.max_packets || Instant::now() >= deadline {
+                break Ok(());
+            }
+            let record = ring
+                .next()
+                .map(|item| KPcapRecord::from_bytes(item.deref()));
+            match record {
+                Some(record) => {
+                    backoff.found();
+                    if let Err(e) = writer.write(&record, record.ts_ns + offset) {
+                        break Err(e);
+                    }
+                    written += 1;
+                }
+                None => backoff.wait().await,
+            }
+        };
+        // off before anything else can fail
+        self.set_filter(KPcapFilter::default()).await?;
+        result
+            .and_then(|_| writer.into_inner().flush())
+            .map_err(io(format!("failed to write {}", path)))?;
+        Ok(written)
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_pcapng_writer() {
+        use folonet_common::pcap::{KPcapRecord, PCAP_AFTER, PCAP_SNAPLEN};
+
+        use super::PcapngWriter;
+
+        let mut writer = PcapngWriter::new(vec![]).unwrap();
+        let header_len = writer.out.len();
+        // section header of 28 bytes and the interfaces, both 4 byte aligned
+        assert_eq!(&writer.out[..4], &0x0a0d0d0au32.to_le_bytes());
+        assert_eq!(&writer.out[24..28], &28u32.to_le_bytes());
+        assert_eq!(header_len % 4, 0);
+
+        let mut record = KPcapRecord {
+            ts_ns: 0,
+            ifindex: 2,
+            len: 60,
+            cap_len: 42,
+            stage: PCAP_AFTER,
+            _pad: [0; 3],
+            data: [0xab; PCAP_SNAPLEN],
+        };
+        record.data[41] = 0xcd;
+        writer.write(&record, (5u64 << 32) | 7).unwrap();
+
+        let epb = &writer.out[header_len..];
+        // 28 bytes of header, 44 of packet data padded and the trailing length
+        assert_eq!(epb.len(), 28 + 44 + 4);
+        assert_eq!(&epb[..4], &6u32.to_le_bytes());
+        assert_eq!(&epb[4..8], &76u32.to_le_bytes());
+        // interface `after`
+        assert_eq!(&epb[8..12], &1u32.to_le_bytes());
+        assert_eq!(&epb[12..16], &5u32.to_le_bytes());
+        assert_eq!(&epb[16..20], &7u32.to_le_bytes());
+        assert_eq!(&epb[20..24], &42u32.to_le_bytes());
+        assert_eq!(&epb[24..28], &60u32.to_le_bytes());
+        assert_eq!(epb[28 + 41], 0xcd);
+        assert_eq!(&epb[72..], &76u32.to_le_bytes());
+    }
+}
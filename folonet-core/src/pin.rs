@@ -0,0 +1,255 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use aya::maps::{Array, Map, MapData};
+use aya::programs::links::{FdLink, PinnedLink};
+use aya::programs::xdp::{XdpLink, XdpLinkId};
+use aya::programs::Xdp;
+use aya::Bpf;
+use folonet_client::config::PinConfig;
+use folonet_common::MAP_SCHEMA_VERSION;
+use log::{info, warn};
+
+use crate::attach::{AttachReport, IfaceReport};
+use crate::error::{FolonetError, MapResultExt};
+
+const LINK_SUFFIX: &str = ".link";
+const SCHEMA_MAP: &str = "MAP_SCHEMA";
+
+// `<iface>.<pid>.link`, interface names may have dots themselves
+fn parse_link_name(name: &str) -> Option<(&str, u32)> {
+    let (iface, pid) = name.strip_suffix(LINK_SUFFIX)?.rsplit_once('.')?;
+    Some((iface, pid.parse().ok()?))
+}
+
+// Keeps the datapath running from one folonet process to the next. The maps
+// holding connection state are pinned below `path`, and so is the xdp link of
+// every interface, named after the process owning it. A process finding
+// pinned links takes them over: it reuses the pinned maps, swaps its program
+// into the links atomically and pins them under its own name. The process it
+// replaced finds its pins gone and leaves the datapath alone when it exits,
+// a process exiting with its pins in place takes the datapath down.
+#[derive(Debug, Clone)]
+pub struct Pins {
+    dir: PathBuf,
+    pid: u32,
+}
+
+impl Pins {
+    pub fn new(cfg: &PinConfig) -> Self {
+        Pins {
+            dir: PathBuf::from(&cfg.path),
+            pid: std::process::id(),
+        }
+    }
+
+    pub fn maps_dir(&self) -> PathBuf {
+        self.dir.join("maps")
+    }
+
+    fn link_path(&self, iface: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.{}{}", iface, self.pid, LINK_SUFFIX))
+    }
+
+    // links pinned by other processes, by interface
+    fn pinned_links(&self) -> Vec<(String, PathBuf)> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+        entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                let (iface, pid) = parse_link_name(&name)?;
+                (pid != self.pid).then(|| (iface.to_string(), entry.path()))
+            })
+            .collect()
+    }
+
+    // whether there is a running datapath to take over
+    pub fn taking_over(&self) -> bool {
+        !self.pinned_links().is_empty()
+    }
+
+    // the MAP_SCHEMA_VERSION of the pinned maps, none when they were pinned
+    // before it was
+    fn pinned_schema(&self) -> Result<Option<u32>, FolonetError> {
+        let path = self.maps_dir().join(SCHEMA_MAP);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = MapData::from_pin(path).map_context(SCHEMA_MAP)?;
+        let schema: Array<_, u32> = Array::try_from(Map::Array(data)).map_context(SCHEMA_MAP)?;
+        Ok(Some(schema.get(&0, 0).map_context(SCHEMA_MAP)?))
+    }
+
+    // Whether the pinned maps are laid out as this folonet expects. Reusing
+    // them otherwise would have the xdp program read garbage.
+    pub fn check_schema(&self) -> Result<(), FolonetError> {
+        let pinned = fs::read_dir(self.maps_dir())
+            .map(|mut entries| entries.next().is_some())
+            .unwrap_or(false);
+        if !pinned {
+            return Ok(());
+        }
+        check_version(self.pinned_schema()?)
+    }
+
+    // make room for the maps, forgetting those of a datapath no longer running
+    pub fn prepare(&self) -> Result<(), FolonetError> {
+        if self.taking_over() {
+            self.check_schema()?;
+        } else {
+            let _ = fs::remove_dir_all(self.maps_dir());
+        }
+        fs::create_dir_all(self.maps_dir()).map_err(|source| FolonetError::Io {
+            context: format!("failed to create {:?}", self.maps_dir()),
+            source,
+        })
+    }
+
+    // swap `program` into the links pinned for `ifaces`, returns the links it
+    // now runs on and the interfaces without one to take over
+    pub fn take_over(
+        &self,
+        program: &mut Xdp,
+        ifaces: &[String],
+    ) -> (Vec<(String, XdpLinkId)>, Vec<String>) {
+        let pinned = self.pinned_links();
+        let mut taken = vec![];
+        let mut rest = vec![];
+        for iface in ifaces {
+            let path = match pinned.iter().find(|(i, _)| i == iface) {
+                Some((_, path)) => path,
+                None => {
+                    rest.push(iface.clone());
+                    continue;
+                }
+            };
+            match replace(program, path) {
+                Ok(link_id) => {
+                    info!("took over the datapath of {}", iface);
+                    taken.push((iface.clone(), link_id));
+                }
+                Err(e) => {
+                    warn!("failed to take over the datapath of {}: {}", iface, e);
+                    rest.push(iface.clone());
+                }
+            }
+        }
+        (taken, rest)
+    }
+
+    // pin the links of `program` under this process, then drop the pins of
+    // the process taken over
+    pub fn pin_links(
+        &self,
+        program: &mut Xdp,
+        links: Vec<(String, XdpLinkId)>,
+    ) -> Vec<(String, PinnedLink)> {
+        let previous = self.pinned_links();
+        let mut pinned = vec![];
+        for (iface, link_id) in links {
+            match pin_link(program, link_id, &self.link_path(&iface)) {
+                Ok(link) => pinned.push((iface.clone(), link)),
+                Err(e) => {
+                    warn!("failed to pin the link of {}: {}", iface, e);
+                    continue;
+                }
+            }
+            for (_, path) in previous.iter().filter(|(i, _)| *i == iface) {
+                let _ = fs::remove_file(path);
+            }
+        }
+        pinned
+    }
+
+    // the link of an interface that went away, the kernel detached it
+    pub fn forget(&self, iface: &str, link: PinnedLink) {
+        if let Err(e) = link.unpin() {
+            warn!("failed to unpin the link of {}: {:#}", iface, e);
+        }
+    }
+
+    // take the datapath down, unless another process took it over
+    pub fn release(&self, links: Vec<(String, PinnedLink)>) -> AttachReport {
+        let mut report = AttachReport::default();
+        let mut owned = true;
+        for (iface, link) in links {
+            let error = if self.link_path(&iface).exists() {
+                // the link goes with its last reference, the fd dropped here
+                link.unpin().map(drop).err().map(|e| format!("{:#}", e))
+            } else {
+                info!("the datapath of {} was taken over", iface);
+                owned = false;
+                None
+            };
+            report.ifaces.push(IfaceReport {
+                iface,
+                attempts: 1,
+                error,
+            });
+        }
+        if owned {
+            let _ = fs::remove_dir_all(self.maps_dir());
+        }
+        report
+    }
+}
+
+fn check_version(found: Option<u32>) -> Result<(), FolonetError> {
+    match found {
+        Some(MAP_SCHEMA_VERSION) => Ok(()),
+        found => Err(FolonetError::IncompatibleMaps(found)),
+    }
+}
+
+// record the version of maps just created, or taken over after the check
+pub fn write_schema(bpf: &mut Bpf) -> Result<(), FolonetError> {
+    let map = bpf
+        .map_mut(SCHEMA_MAP)
+        .ok_or(FolonetError::MapNotFound(SCHEMA_MAP))?;
+    let mut schema: Array<_, u32> = Array::try_from(map).map_context(SCHEMA_MAP)?;
+    schema.set(0, MAP_SCHEMA_VERSION, 0).map_context(SCHEMA_MAP)
+}
+
+fn replace(program: &mut Xdp, path: &Path) -> Result<XdpLinkId, String> {
+    let link = PinnedLink::from_pin(path).map_err(|e| format!("{:#}", e))?;
+    let link = XdpLink::try_from(FdLink::from(link)).map_err(|e| format!("{:#}", e))?;
+    program.attach_to_link(link).map_err(|e| format!("{:#}", e))
+}
+
+fn pin_link(program: &mut Xdp, link_id: XdpLinkId, path: &Path) -> Result<PinnedLink, String> {
+    let link = program.take_link(link_id).map_err(|e| format!("{:#}", e))?;
+    let link = FdLink::try_from(link).map_err(|e| format!("{:#}", e))?;
+    link.pin(path).map_err(|e| format!("{:#}", e))
+}
+
+mod test {
+
+    #[test]
+    fn test_parse_link_name() {
+        use super::parse_link_name;
+
+        assert_eq!(parse_link_name("eth0.4242.link"), Some(("eth0", 4242)));
+        assert_eq!(
+            parse_link_name("eth0.100.4242.link"),
+            Some(("eth0.100", 4242))
+        );
+        assert_eq!(parse_link_name("eth0.link"), None);
+        assert_eq!(parse_link_name("maps"), None);
+    }
+
+    #[test]
+    fn test_check_version() {
+        use folonet_common::MAP_SCHEMA_VERSION;
+
+        use super::check_version;
+
+        assert!(check_version(Some(MAP_SCHEMA_VERSION)).is_ok());
+        assert!(check_version(Some(MAP_SCHEMA_VERSION + 1)).is_err());
+        assert!(check_version(None).is_err());
+    }
+}
@@ -0,0 +1,62 @@
+use tokio::task::yield_now;
+use tokio::time::{sleep, Duration, Instant};
+
+const MIN_WAIT: Duration = Duration::from_micros(50);
+const MAX_WAIT: Duration = Duration::from_millis(10);
+// tokio rounds every sleep up to its timer tick
+const TIMER_RESOLUTION: Duration = Duration::from_millis(1);
+
+// How long a ring buffer consumer waits between polls. It polls again right
+// away while it finds events, and waits twice as long after every empty poll,
+// so a busy host sees its events within microseconds and an idle one costs
+// about a hundred wakeups a second.
+#[derive(Debug, Default)]
+pub struct PollBackoff {
+    next: Option<Duration>,
+}
+
+impl PollBackoff {
+    // the last poll found an event
+    pub fn found(&mut self) {
+        self.next = None;
+    }
+
+    fn next_wait(&mut self) -> Duration {
+        let wait = self.next.unwrap_or(MIN_WAIT);
+        self.next = Some((wait * 2).min(MAX_WAIT));
+        wait
+    }
+
+    // the last poll found nothing
+    pub async fn wait(&mut self) {
+        let wait = self.next_wait();
+        if wait >= TIMER_RESOLUTION {
+            sleep(wait).await;
+            return;
+        }
+        let deadline = Instant::now() + wait;
+        while Instant::now() < deadline {
+            yield_now().await;
+        }
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_poll_backoff() {
+        use tokio::time::Duration;
+
+        use super::PollBackoff;
+
+        let mut backoff = PollBackoff::default();
+        let waits: Vec<Duration> = (0..10).map(|_| backoff.next_wait()).collect();
+        assert_eq!(waits[0], Duration::from_micros(50));
+        assert_eq!(waits[1], Duration::from_micros(100));
+        assert_eq!(waits[8], Duration::from_millis(10));
+        assert_eq!(waits[9], Duration::from_millis(10));
+
+        backoff.found();
+        assert_eq!(backoff.next_wait(), Duration::from_micros(50));
+    }
+}
@@ -0,0 +1,626 @@
+use std::collections::{HashMap, VecDeque};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, RwLock};
+
+use aya::maps::{HashMap as AyaHashMap, MapData, PerCpuArray, Queue};
+use folonet_client::config::PortsConfig;
+use folonet_common::ports::KPortQuota;
+use folonet_common::stats::Counter;
+use folonet_common::PORTS_QUEUE_SIZE;
+use log::{info, warn};
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::endpoint::{Endpoint, UEndpoint};
+use crate::error::{FolonetError, MapResultExt};
+use crate::sharded::ShardedBpfMap;
+use crate::stats::read_counter;
+
+pub const DEFAULT_PORT_RANGE: RangeInclusive<u16> = 10000..=(10000 + PORTS_QUEUE_SIZE as u16 - 1);
+
+// `first-last`, or a single port
+pub fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, FolonetError> {
+    let invalid = || FolonetError::Config(format!("invalid port range {}", s));
+    let (first, last) = s.split_once('-').unwrap_or((s, s));
+    let first = first.trim().parse::<u16>().map_err(|_| invalid())?;
+    let last = last.trim().parse::<u16>().map_err(|_| invalid())?;
+    if first == 0 || first > last {
+        return Err(invalid());
+    }
+    Ok(first..=last)
+}
+
+// The ranges of the pool, the default one without any configured. They must
+// not overlap and fit the kernel queue together.
+pub fn port_ranges(cfg: &PortsConfig) -> Result<Vec<RangeInclusive<u16>>, FolonetError> {
+    if cfg.ranges.is_empty() {
+        return Ok(vec![DEFAULT_PORT_RANGE]);
+    }
+    let mut ranges = cfg
+        .ranges
+        .iter()
+        .map(|range| parse_port_range(range))
+        .collect::<Result<Vec<_>, _>>()?;
+    ranges.sort_by_key(|range| *range.start());
+    for pair in ranges.windows(2) {
+        if pair[1].start() <= pair[0].end() {
+            return Err(FolonetError::Config(format!(
+                "port ranges {}-{} and {}-{} overlap",
+                pair[0].start(),
+                pair[0].end(),
+                pair[1].start(),
+                pair[1].end()
+            )));
+        }
+    }
+    let ports: usize = ranges.iter().map(|range| range.clone().count()).sum();
+    if ports > PORTS_QUEUE_SIZE as usize {
+        return Err(FolonetError::Config(format!(
+            "the port ranges hold {} ports, the kernel queue only {}",
+            ports, PORTS_QUEUE_SIZE
+        )));
+    }
+    Ok(ranges)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct PortPoolStats {
+    pub pool_size: u64,
+    pub free: u64,
+    // with the quarantined ones
+    pub in_use: u64,
+    // of closed tcp connections, waiting for the backend to leave TIME_WAIT
+    pub quarantined: u64,
+    // ports handed out by the xdp program per second
+    pub alloc_rate: f64,
+    // ports given back per second
+    pub release_rate: f64,
+    // only set while the pool is shrinking
+    pub time_to_exhaustion_secs: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PortQuotaStats {
+    pub service: String,
+    pub local_endpoint: String,
+    pub limit: u64,
+    pub in_use: u64,
+    pub free: u64,
+}
+
+struct Quota {
+    service: String,
+    limit: u64,
+    // taken by the xdp program as of the last sample
+    taken: u64,
+    released: u64,
+    low: bool,
+}
+
+impl Quota {
+    fn in_use(&self) -> u64 {
+        self.taken.saturating_sub(self.released)
+    }
+
+    fn k_quota(&self) -> KPortQuota {
+        KPortQuota {
+            limit: self.limit,
+            released: self.released,
+        }
+    }
+}
+
+// Whether the free ports of `size` went below `ratio` of it, or back above,
+// since the last check
+fn crossed_low(low: &mut bool, free: u64, size: u64, ratio: f64) -> bool {
+    let now_low = size > 0 && (free as f64) < size as f64 * ratio;
+    let crossed = now_low != *low;
+    *low = now_low;
+    crossed
+}
+
+// a port of a closed connection and when it may be used again
+struct Quarantined {
+    until: Instant,
+    port: u16,
+    service: Option<Endpoint>,
+}
+
+// the ports whose quarantine is over, the queue is in the order they went in
+fn take_expired(queue: &mut VecDeque<Quarantined>, now: Instant) -> Vec<(u16, Option<Endpoint>)> {
+    let mut expired = vec![];
+    while queue.front().is_some_and(|q| q.until <= now) {
+        let q = queue.pop_front().unwrap();
+        expired.push((q.port, q.service));
+    }
+    expired
+}
+
+#[derive(Default)]
+struct PoolState {
+    ranges: Vec<RangeInclusive<u16>>,
+    allocated: u64,
+    released: u64,
+    last_sample: Option<(Instant, u64, u64)>,
+    stats: PortPoolStats,
+    quotas: HashMap<Endpoint, Quota>,
+    low: bool,
+    quarantine_for: Duration,
+    quarantine: VecDeque<Quarantined>,
+}
+
+impl PoolState {
+    fn contains(&self, port: u16) -> bool {
+        self.ranges.iter().any(|r| r.contains(&port))
+    }
+
+    fn pool_size(&self) -> u64 {
+        self.ranges.iter().map(|r| r.len() as u64).sum()
+    }
+
+    fn update(&mut self, at: Instant) {
+        let in_use = self.allocated.saturating_sub(self.released);
+        let pool_size = self.pool_size();
+        let free = pool_size.saturating_sub(in_use);
+
+        let (alloc_rate, release_rate) = match self.last_sample {
+            Some((last_at, allocated, released)) => {
+                let secs = at.duration_since(last_at).as_secs_f64();
+                if secs > 0.0 {
+                    (
+                        self.allocated.saturating_sub(allocated) as f64 / secs,
+                        self.released.saturating_sub(released) as f64 / secs,
+                    )
+                } else {
+                    (self.stats.alloc_rate, self.stats.release_rate)
+                }
+            }
+            None => (0.0, 0.0),
+        };
+        self.last_sample = Some((at, self.allocated, self.released));
+
+        let drain = alloc_rate - release_rate;
+        self.stats = PortPoolStats {
+            pool_size,
+            free,
+            in_use,
+            quarantined: self.quarantine.len() as u64,
+            alloc_rate,
+            release_rate,
+            time_to_exhaustion_secs: (drain > 0.0).then(|| free as f64 / drain),
+        };
+    }
+
+    fn check_low(&mut self, ratio: f64) {
+        let stats = self.stats;
+        if crossed_low(&mut self.low, stats.free, stats.pool_size, ratio) {
+            if self.low {
+                warn!(
+                    "port pool running low: {} of {} ports free",
+                    stats.free, stats.pool_size
+                );
+            } else {
+                info!(
+                    "port pool recovered: {} of {} ports free",
+                    stats.free, stats.pool_size
+                );
+            }
+        }
+        for quota in self.quotas.values_mut() {
+            let free = quota.limit.saturating_sub(quota.in_use());
+            if crossed_low(&mut quota.low, free, quota.limit, ratio) {
+                if quota.low {
+                    warn!(
+                        "service {} running low on ports: {} of its {} free",
+                        quota.service, free, quota.limit
+                    );
+                } else {
+                    info!(
+                        "service {} recovered: {} of its {} ports free",
+                        quota.service, free, quota.limit
+                    );
+                }
+            }
+        }
+    }
+}
+
+// The local ports the xdp program takes from SERVICE_PORTS for new
+// connections, and the quotas of the services on them. Cheap to clone, every
+// clone manages the same queue.
+#[derive(Clone)]
+pub struct PortPool {
+    queue: Arc<Mutex<Queue<MapData, u16>>>,
+    quota_map: Arc<ShardedBpfMap<UEndpoint, KPortQuota>>,
+    taken_map: Arc<Mutex<AyaHashMap<MapData, UEndpoint, u64>>>,
+    state: Arc<RwLock<PoolState>>,
+}
+
+impl PortPool {
+    // `ranges` are the ports already pushed to `queue`
+    pub fn new(
+        queue: Queue<MapData, u16>,
+        ranges: Vec<RangeInclusive<u16>>,
+        quota_map: ShardedBpfMap<UEndpoint, KPortQuota>,
+        taken_map: AyaHashMap<MapData, UEndpoint, u64>,
+    ) -> Self {
+        PortPool {
+            queue: Arc::new(Mutex::new(queue)),
+            quota_map: Arc::new(quota_map),
+            taken_map: Arc::new(Mutex::new(taken_map)),
+            state: Arc::new(RwLock::new(PoolState {
+                ranges,
+                ..Default::default()
+            })),
+        }
+    }
+
+    pub fn stats(&self) -> PortPoolStats {
+        self.state.read().unwrap().stats
+    }
+
+    // Let `service` hold at most `limit` ports at once. The connections of a
+    // datapath taken over are not counted against it.
+    pub async fn set_quota(
+        &self,
+        service: Endpoint,
+        name: &str,
+        limit: u64,
+    ) -> Result<(), FolonetError> {
+        let k_quota = {
+            let mut state = self.state.write().unwrap();
+            let quota = state.quotas.entry(service).or_insert(Quota {
+                service: name.to_string(),
+                limit,
+                taken: 0,
+                released: 0,
+                low: false,
+            });
+            quota.limit = limit;
+            quota.k_quota()
+        };
+        self.quota_map
+            .insert(service.to_u_endpoint(), k_quota)
+            .map_context("PORT_QUOTA_MAP")
+    }
+
+    pub fn quotas(&self) -> Vec<PortQuotaStats> {
+        let state = self.state.read().unwrap();
+        let mut quotas: Vec<PortQuotaStats> = state
+            .quotas
+            .iter()
+            .map(|(endpoint, quota)| PortQuotaStats {
+                service: quota.service.clone(),
+                local_endpoint: endpoint.to_string(),
+                limit: quota.limit,
+                in_use: quota.in_use(),
+                free: quota.limit.saturating_sub(quota.in_use()),
+            })
+            .collect();
+        quotas.sort_by(|a, b| a.service.cmp(&b.service));
+        quotas
+    }
+
+    pub fn ranges(&self) -> Vec<RangeInclusive<u16>> {
+        self.state.read().unwrap().ranges.clone()
+    }
+
+    // returns how many ports were added, ports already in the pool are skipped
+    pub async fn add_range(&self, range: RangeInclusive<u16>) -> Result<usize, FolonetError> {
+        // the queue lock keeps the ranges as they are until the ports are in
+        let mut queue = self.queue.lock().await;
+        let parts = {
+            let state = self.state.read().unwrap();
+            let parts = uncovered(range, &state.ranges);
+            let added: usize = parts.iter().map(|part| part.len()).sum();
+            if state.pool_size() as usize + added > PORTS_QUEUE_SIZE as usize {
+                return Err(FolonetError::Config(format!(
+                    "the pool would hold {} ports, the kernel queue only {}",
+                    state.pool_size() as usize + added,
+                    PORTS_QUEUE_SIZE
+                )));
+            }
+            parts
+        };
+
+        let mut added = 0;
+        for part in parts {
+            for port in part.clone() {
+                queue.push(port, 0).map_context("SERVICE_PORTS")?;
+            }
+            added += part.len();
+            self.state.write().unwrap().ranges.push(part);
+        }
+        Ok(added)
+    }
+
+    // Take the ports of `range` out of the pool. Ports of the range still held
+    // by connections are not given back once they are released. Returns how
+    // many free ports were taken out.
+    pub async fn remove_range(&self, range: RangeInclusive<u16>) -> Result<usize, FolonetError> {
+        // with the queue locked, a port released meanwhile is not pushed back
+        // once the range is out of the pool
+        let mut queue = self.queue.lock().await;
+        let (in_pool, pool_size) = {
+            let mut state = self.state.write().unwrap();
+            let pool_size = state.pool_size();
+            let in_pool = range.clone().filter(|p| state.contains(*p)).count();
+            let ranges = std::mem::take(&mut state.ranges);
+            state.ranges = ranges
+                .into_iter()
+                .flat_map(|r| split_range(r, &range))
+                .collect();
+            (in_pool, pool_size)
+        };
+
+        // The queue is turned around one port at a time, each kept port goes
+        // back before the next is taken, so the xdp program never finds it
+        // empty. It holds at most the ports of the pool.
+        let mut removed = 0;
+        for _ in 0..pool_size {
+            let port = match queue.pop(0) {
+                Ok(port) => port,
+                Err(_) => break,
+            };
+            if range.contains(&port) {
+                removed += 1;
+            } else {
+                queue.push(port, 0).map_context("SERVICE_PORTS")?;
+            }
+        }
+        // the ports of the range still held leave the pool with it, they are
+        // not counted released again when their connections close
+        self.state.write().unwrap().released += in_pool.saturating_sub(removed) as u64;
+        Ok(removed)
+    }
+
+    pub fn set_quarantine(&self, quarantine_for: Duration) {
+        self.state.write().unwrap().quarantine_for = quarantine_for;
+    }
+
+    // give back `port` of a closed tcp connection once its quarantine is over
+    pub async fn quarantine(&self, port: u16, service: Option<Endpoint>) {
+        {
+            let mut state = self.state.write().unwrap();
+            if !state.quarantine_for.is_zero() {
+                let until = Instant::now() + state.quarantine_for;
+                state.quarantine.push_back(Quarantined {
+                    until,
+                    port,
+                    service,
+                });
+                return;
+            }
+        }
+        self.release(port, service).await
+    }
+
+    // give back every quarantined port right away, e.g. for the process
+    // taking over the datapath
+    pub async fn flush_quarantine(&self) {
+        let ports: Vec<(u16, Option<Endpoint>)> = {
+            let mut state = self.state.write().unwrap();
+            state
+                .quarantine
+                .drain(..)
+                .map(|q| (q.port, q.service))
+                .collect()
+        };
+        for (port, service) in ports {
+            self.release(port, service).await;
+        }
+    }
+
+    // give back `port` of a connection to `service`, when still known
+    pub async fn release(&self, port: u16, service: Option<Endpoint>) {
+        // a range taken out meanwhile waits for the port to be back, or out
+        let mut queue = self.queue.lock().await;
+        let (in_pool, k_quota) = {
+            let mut state = self.state.write().unwrap();
+            let in_pool = state.contains(port);
+            if in_pool {
+                state.released += 1;
+            }
+            let k_quota = service
+                .and_then(|service| state.quotas.get_mut(&service))
+                .map(|quota| {
+                    quota.released += 1;
+                    quota.k_quota()
+                });
+            (in_pool, k_quota)
+        };
+        if let (Some(service), Some(k_quota)) = (service, k_quota) {
+            if let Err(e) = self.quota_map.insert(service.to_u_endpoint(), k_quota) {
+                warn!("failed to update the port quota of {}: {}", service, e);
+            }
+        }
+        if !in_pool {
+            return;
+        }
+        if let Err(e) = queue.push(port, 0) {
+            warn!("failed to give back port {}: {}", port, e);
+        }
+    }
+
+    // warns when the pool, or the quota of a service, has less than
+    // `low_free_ratio` of its ports free
+    pub async fn sample_forever(
+        self,
+        counters: Arc<PerCpuArray<MapData, u64>>,
+        low_free_ratio: f64,
+        interval: Duration,
+    ) {
+        loop {
+            let expired = take_expired(&mut self.state.write().unwrap().quarantine, Instant::now());
+            for (port, service) in expired {
+                self.release(port, service).await;
+            }
+
+            let allocated = read_counter(&counters, Counter::PortAllocated);
+            let taken: Vec<(UEndpoint, u64)> = self
+                .taken_map
+                .lock()
+                .await
+                .iter()
+                .filter_map(|item| item.ok())
+                .collect();
+            {
+                let mut state = self.state.write().unwrap();
+                state.allocated = allocated;
+                for (service, taken) in taken {
+                    if let Some(quota) = state.quotas.get_mut(&service.to_endpoint()) {
+                        quota.taken = taken;
+                    }
+                }
+                state.update(Instant::now());
+                state.check_low(low_free_ratio);
+            }
+            sleep(interval).await;
+        }
+    }
+}
+
+// what is left of `r` once `removed` is taken out of it
+fn split_range(r: RangeInclusive<u16>, removed: &RangeInclusive<u16>) -> Vec<RangeInclusive<u16>> {
+    if removed.end() < r.start() || removed.start() > r.end() {
+        return vec![r];
+    }
+    let mut left = vec![];
+    if removed.start() > r.start() {
+        left.push(*r.start()..=removed.start() - 1);
+    }
+    if removed.end() < r.end() {
+        left.push(removed.end() + 1..=*r.end());
+    }
+    left
+}
+
+// the parts of `range` none of `ranges` covers
+fn uncovered(
+    range: RangeInclusive<u16>,
+    ranges: &[RangeInclusive<u16>],
+) -> Vec<RangeInclusive<u16>> {
+    ranges.iter().fold(vec![range], |parts, r| {
+        parts.into_iter().flat_map(|p| split_range(p, r)).collect()
+    })
+}
+
+mod test {
+
+    #[test]
+    fn test_port_ranges() {
+        use folonet_client::config::PortsConfig;
+
+        use super::{parse_port_range, port_ranges, DEFAULT_PORT_RANGE};
+
+        assert_eq!(parse_port_range("20000-20999").unwrap(), 20000..=20999);
+        assert_eq!(parse_port_range("20000").unwrap(), 20000..=20000);
+        assert!(parse_port_range("20999-20000").is_err());
+        assert!(parse_port_range("0-10").is_err());
+        assert!(parse_port_range("20000-70000").is_err());
+
+        let mut cfg = PortsConfig::default();
+        assert_eq!(port_ranges(&cfg).unwrap(), vec![DEFAULT_PORT_RANGE]);
+        cfg.ranges = vec!["30000-30099".to_string(), "20000-20099".to_string()];
+        assert_eq!(
+            port_ranges(&cfg).unwrap(),
+            vec![20000..=20099, 30000..=30099]
+        );
+        cfg.ranges.push("20050-20060".to_string());
+        assert!(port_ranges(&cfg).is_err());
+        cfg.ranges = vec!["1-65535".to_string()];
+        assert!(port_ranges(&cfg).is_err());
+    }
+
+    #[test]
+    fn test_split_range() {
+        use super::split_range;
+
+        assert_eq!(split_range(10..=20, &(30..=40)), vec![10..=20]);
+        assert_eq!(split_range(10..=20, &(5..=25)), vec![]);
+        assert_eq!(split_range(10..=20, &(10..=14)), vec![15..=20]);
+        assert_eq!(split_range(10..=20, &(12..=14)), vec![10..=11, 15..=20]);
+    }
+
+    #[test]
+    fn test_uncovered() {
+        use super::uncovered;
+
+        assert_eq!(uncovered(10..=20, &[]), vec![10..=20]);
+        assert_eq!(uncovered(10..=20, &[15..=30]), vec![10..=14]);
+        assert_eq!(
+            uncovered(10..=40, &[0..=12, 20..=25, 38..=50]),
+            vec![13..=19, 26..=37]
+        );
+        assert!(uncovered(10..=20, &[0..=15, 16..=30]).is_empty());
+    }
+
+    #[test]
+    fn test_pool_stats() {
+        use tokio::time::{Duration, Instant};
+
+        use super::PoolState;
+
+        let mut state = PoolState {
+            ranges: vec![10000..=10099],
+            ..Default::default()
+        };
+        let at = Instant::now();
+        state.update(at);
+        assert_eq!(state.stats.free, 100);
+        assert_eq!(state.stats.time_to_exhaustion_secs, None);
+
+        state.allocated = 30;
+        state.released = 10;
+        state.update(at + Duration::from_secs(2));
+        assert_eq!(state.stats.in_use, 20);
+        assert_eq!(state.stats.free, 80);
+        assert_eq!(state.stats.alloc_rate, 15.0);
+        assert_eq!(state.stats.release_rate, 5.0);
+        assert_eq!(state.stats.time_to_exhaustion_secs, Some(8.0));
+    }
+
+    #[test]
+    fn test_take_expired() {
+        use std::collections::VecDeque;
+
+        use tokio::time::{Duration, Instant};
+
+        use super::{take_expired, Quarantined};
+
+        let at = Instant::now();
+        let mut queue: VecDeque<Quarantined> = [10000, 10001, 10002]
+            .into_iter()
+            .enumerate()
+            .map(|(i, port)| Quarantined {
+                until: at + Duration::from_secs(i as u64 * 30),
+                port,
+                service: None,
+            })
+            .collect();
+
+        assert_eq!(take_expired(&mut queue, at), vec![(10000, None)]);
+        assert!(take_expired(&mut queue, at + Duration::from_secs(10)).is_empty());
+        assert_eq!(
+            take_expired(&mut queue, at + Duration::from_secs(90)),
+            vec![(10001, None), (10002, None)]
+        );
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_crossed_low() {
+        use super::crossed_low;
+
+        let mut low = false;
+        assert!(!crossed_low(&mut low, 50, 100, 0.1));
+        assert!(crossed_low(&mut low, 9, 100, 0.1));
+        assert!(low);
+        assert!(!crossed_low(&mut low, 5, 100, 0.1));
+        assert!(crossed_low(&mut low, 10, 100, 0.1));
+        assert!(!low);
+        // a quota of nothing is never low
+        assert!(!crossed_low(&mut low, 0, 0, 0.1));
+    }
+}
@@ -0,0 +1,113 @@
+use folonet_client::config::StartupProbeConfig;
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration, Instant};
+
+use crate::endpoint::Endpoint;
+use crate::timer::Timers;
+
+// the status line is all that is read of a response
+const MAX_STATUS_LINE: usize = 512;
+
+// the status of an http response, e.g. 200 for `HTTP/1.1 200 OK`
+fn status_of(response: &[u8]) -> Option<u16> {
+    let line = response.split(|b| *b == b'\n').next()?;
+    let mut parts = std::str::from_utf8(line).ok()?.split_whitespace();
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+async fn probe_once(backend: &Endpoint, http_path: Option<&str>) -> std::io::Result<bool> {
+    let mut stream = TcpStream::connect(backend.to_string()).await?;
+    let path = match http_path {
+        Some(path) => path,
+        None => return Ok(true),
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path,
+        backend.to_string()
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut buf = [0u8; MAX_STATUS_LINE];
+    let mut len = 0;
+    while len < buf.len() && !buf[..len].contains(&b'\n') {
+        let read = stream.read(&mut buf[len..]).await?;
+        if read == 0 {
+            break;
+        }
+        len += read;
+    }
+    Ok(status_of(&buf[..len]).is_some_and(|status| status < 400))
+}
+
+// Probe `backend` until it passes, false once `cfg.deadline_ms` went by
+// without. The probes wait for each other on `timers`.
+pub async fn wait_ready(backend: &Endpoint, cfg: &StartupProbeConfig, timers: &Timers) -> bool {
+    let deadline = Instant::now() + Duration::from_millis(cfg.deadline_ms);
+    let interval = Duration::from_millis(cfg.interval_ms);
+    let attempt = Duration::from_millis(cfg.timeout_ms);
+    loop {
+        match timeout(attempt, probe_once(backend, cfg.http_path.as_deref())).await {
+            Ok(Ok(true)) => return true,
+            Ok(Ok(false)) => debug!("backend {} not ready yet", backend.to_string()),
+            Ok(Err(e)) => debug!("backend {} not ready yet: {}", backend.to_string(), e),
+            Err(_) => debug!("probe of backend {} timed out", backend.to_string()),
+        }
+        if Instant::now() + interval >= deadline {
+            return false;
+        }
+        timers.probe_after(*backend, interval).await;
+    }
+}
+
+mod test {
+
+    #[tokio::test]
+    async fn test_wait_ready() {
+        use folonet_client::config::StartupProbeConfig;
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::TcpListener;
+
+        use super::{status_of, wait_ready};
+        use crate::endpoint::Endpoint;
+        use crate::timer::Timers;
+
+        assert_eq!(status_of(b"HTTP/1.1 204 No Content\r\n"), Some(204));
+        assert_eq!(status_of(b"SSH-2.0-OpenSSH\r\n"), None);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend: Endpoint = listener.local_addr().unwrap().to_string().parse().unwrap();
+        tokio::spawn(async move {
+            // unhealthy for the first probe
+            for status in ["503 Service Unavailable", "200 OK"].iter().cycle() {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        let cfg = StartupProbeConfig {
+            http_path: Some("/healthz".to_string()),
+            interval_ms: 10,
+            deadline_ms: 2000,
+            ..Default::default()
+        };
+        let timers = Timers::new();
+        assert!(wait_ready(&backend, &cfg, &timers).await);
+
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend: Endpoint = closed.local_addr().unwrap().to_string().parse().unwrap();
+        drop(closed);
+        let cfg = StartupProbeConfig {
+            interval_ms: 10,
+            deadline_ms: 50,
+            ..Default::default()
+        };
+        assert!(!wait_ready(&backend, &cfg, &timers).await);
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+use folonet_client::config::ReconcileConfig;
+use folonet_common::nat::KNat;
+use log::{info, warn};
+use serde::Serialize;
+use tokio::time::{sleep, Duration};
+
+use crate::control::ServiceMap;
+use crate::endpoint::{Connection, Endpoint, UConnection};
+use crate::flow_log::FlowTracker;
+use crate::state::BpfConnectionMap;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ReconcileStats {
+    pub passes: u64,
+    // found by the last pass: nat entries without a state machine
+    pub untracked: usize,
+    // and state machines without nat entries
+    pub unmapped: usize,
+    // connections closed by every pass so far
+    pub repaired: u64,
+}
+
+// how the nat entries and the state machines of one backend disagree
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Mismatch {
+    pub untracked: Vec<Connection>,
+    pub unmapped: Vec<Connection>,
+}
+
+impl Mismatch {
+    pub fn new<V>(kernel: &HashMap<Connection, V>, tracked: &HashSet<Connection>) -> Self {
+        Mismatch {
+            untracked: kernel
+                .keys()
+                .filter(|conn| !tracked.contains(conn))
+                .copied()
+                .collect(),
+            unmapped: tracked
+                .iter()
+                .filter(|conn| !kernel.contains_key(conn))
+                .copied()
+                .collect(),
+        }
+    }
+}
+
+// A packet event lost to a full ring buffer leaves the state machine of its
+// connection behind the kernel: a missed fin keeps the nat entries and the
+// local port forever. The reconciler compares both sides periodically and
+// closes what only one of them still has.
+#[derive(Clone, Default)]
+pub struct Reconciler {
+    last: Arc<RwLock<ReconcileStats>>,
+}
+
+impl Reconciler {
+    pub fn stats(&self) -> ReconcileStats {
+        *self.last.read().unwrap()
+    }
+
+    pub async fn reconcile_forever(
+        self,
+        services: ServiceMap,
+        connection_map: BpfConnectionMap,
+        flow_tracker: FlowTracker,
+        cfg: ReconcileConfig,
+    ) {
+        let min_idle_ns = Duration::from_secs(cfg.min_idle_secs).as_nanos() as u64;
+        // state machines without nat entries found by the pass before, one
+        // being closed right now is seen without them for a moment
+        let mut suspects: HashSet<Connection> = HashSet::new();
+        loop {
+            sleep(Duration::from_secs(cfg.interval_secs)).await;
+
+            let nat_entries: Vec<(UConnection, KNat)> = connection_map.entries();
+
+            let mut stats = ReconcileStats {
+                passes: self.stats().passes + 1,
+                repaired: self.stats().repaired,
+                ..Default::default()
+            };
+            let mut next_suspects = HashSet::new();
+            for (local_endpoint, service) in services.entries() {
+                // a nat entry is only closed once its flow is idle, the state
+                // machine of a new connection may still be on its way
+                let idle: HashSet<Endpoint> = flow_tracker
+                    .idle_flows(&local_endpoint, min_idle_ns)
+                    .await
+                    .iter()
+                    .map(|(way, _)| way.from_endpoint())
+                    .collect();
+
+                let service = service.handler.lock().await;
+                for (backend, tracker) in service.server_tracker_map.iter() {
+                    let tracked = match tracker.tracked().await {
+                        Some(tracked) => tracked,
+                        None => continue,
+                    };
+                    // by connection, the client -> service and backend -> local ways
+                    let kernel: HashMap<Connection, (UConnection, UConnection)> = nat_entries
+                        .iter()
+                        .filter(|(way, nat)| {
+                            way.to_endpoint() == local_endpoint
+                                && UConnection::from(nat.fwd.way).to_endpoint() == *backend
+                        })
+                        .map(|(way, nat)| {
+                            (
+                                Connection {
+                                    from: way.from_endpoint(),
+                                    to: *backend,
+                                },
+                                (*way, UConnection::from(nat.rev_key)),
+                            )
+                        })
+                        .collect();
+
+                    let mismatch = Mismatch::new(&kernel, &tracked);
+                    stats.untracked += mismatch.untracked.len();
+                    stats.unmapped += mismatch.unmapped.len();
+                    next_suspects.extend(mismatch.unmapped.iter().copied());
+                    if !cfg.repair {
+                        continue;
+                    }
+
+                    let untracked: Vec<(UConnection, UConnection)> = mismatch
+                        .untracked
+                        .iter()
+                        .filter(|conn| idle.contains(&conn.from))
+                        .map(|conn| kernel[conn])
+                        .collect();
+                    let unmapped: Vec<Connection> = mismatch
+                        .unmapped
+                        .into_iter()
+                        .filter(|conn| suspects.contains(conn))
+                        .collect();
+                    stats.repaired += tracker.repair(&untracked, &unmapped).await as u64;
+                }
+            }
+            suspects = next_suspects;
+
+            if stats.untracked > 0 || stats.unmapped > 0 {
+                warn!(
+                    "{} nat entries without a state machine and {} state machines without nat entries",
+                    stats.untracked, stats.unmapped
+                );
+            }
+            if stats.repaired > self.stats().repaired {
+                info!(
+                    "reconciliation closed {} connections",
+                    stats.repaired - self.stats().repaired
+                );
+            }
+            *self.last.write().unwrap() = stats;
+        }
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_mismatch() {
+        use std::collections::{HashMap, HashSet};
+
+        use super::Mismatch;
+        use crate::endpoint::{Connection, Endpoint};
+
+        let backend: Endpoint = "10.0.0.2:80".parse().unwrap();
+        let conn = |client: &str| Connection {
+            from: client.parse().unwrap(),
+            to: backend,
+        };
+
+        let kernel: HashMap<Connection, ()> =
+            [(conn("1.1.1.1:1000"), ()), (conn("1.1.1.2:1000"), ())]
+                .into_iter()
+                .collect();
+        // the state machine sees the connection from either side
+        let tracked: HashSet<Connection> = [
+            Connection {
+                from: backend,
+                to: "1.1.1.1:1000".parse().unwrap(),
+            },
+            conn("1.1.1.3:1000"),
+        ]
+        .into_iter()
+        .collect();
+
+        let mismatch = Mismatch::new(&kernel, &tracked);
+        assert_eq!(mismatch.untracked, vec![conn("1.1.1.2:1000")]);
+        assert_eq!(mismatch.unmapped, vec![conn("1.1.1.3:1000")]);
+    }
+}
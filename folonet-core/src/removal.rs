@@ -0,0 +1,190 @@
+use std::sync::Arc;
+
+use aya::maps::{HashMap as AyaHashMap, MapData};
+use folonet_client::config::{FinSweepConfig, ScaleDownConfig};
+use folonet_client::provider::Providers;
+use folonet_common::service::KServiceKey;
+use log::{info, warn};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::control::ServiceMap;
+use crate::endpoint::{Endpoint, UEndpoint};
+use crate::error::{FolonetError, MapResultExt};
+use crate::fin_sweep::send_fins;
+use crate::scaler::Scaler;
+
+pub type BpfServerMap = Arc<Mutex<AyaHashMap<MapData, KServiceKey, UEndpoint>>>;
+pub type BpfDrainingMap = Arc<Mutex<AyaHashMap<MapData, UEndpoint, u8>>>;
+
+const DRAIN_POLL: Duration = Duration::from_secs(1);
+const REMOVE_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+// Takes a service down in two phases. The kernel stops routing new clients to
+// it first, then its open connections drain, with the help of the manager
+// when asked to, and the userspace state, the SERVER_MAP entry and the
+// backend go away, in that order. Every step is a
+// no-op when it was done already, so a failed removal is simply run again.
+#[derive(Clone)]
+pub struct Removal {
+    server_map: BpfServerMap,
+    draining_map: BpfDrainingMap,
+    services: ServiceMap,
+    udp_services: ServiceMap,
+    scaler: Scaler,
+    providers: Providers,
+    fin_sweep: Option<FinSweepConfig>,
+    scale_down: ScaleDownConfig,
+}
+
+impl Removal {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        server_map: BpfServerMap,
+        draining_map: BpfDrainingMap,
+        services: ServiceMap,
+        udp_services: ServiceMap,
+        scaler: Scaler,
+        providers: Providers,
+        fin_sweep: Option<FinSweepConfig>,
+        scale_down: ScaleDownConfig,
+    ) -> Self {
+        Removal {
+            server_map,
+            draining_map,
+            services,
+            udp_services,
+            scaler,
+            providers,
+            fin_sweep,
+            scale_down,
+        }
+    }
+
+    pub async fn remove(&self, e: Endpoint) -> Result<(), FolonetError> {
+        let mut attempt = 1;
+        loop {
+            match self.try_remove(e).await {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt < REMOVE_ATTEMPTS => {
+                    warn!(
+                        "removing {} failed ({}), retrying in {:?}",
+                        e.to_string(),
+                        err,
+                        RETRY_DELAY
+                    );
+                    attempt += 1;
+                    sleep(RETRY_DELAY).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn try_remove(&self, e: Endpoint) -> Result<(), FolonetError> {
+        // phase one: new clients are no longer routed to the backend
+        self.draining_map
+            .lock()
+            .await
+            .insert(&e.to_u_endpoint(), &1, 0)
+            .map_context("DRAINING_MAP")?;
+
+        // phase two: wait for the open connections, then tear everything down
+        self.drain(&e).await;
+        self.services.remove(&e);
+        self.udp_services.remove(&e);
+        {
+            let mut server_map = self.server_map.lock().await;
+            for is_tcp in [true, false] {
+                let key = e.to_service_key(is_tcp);
+                if server_map.get(&key, 0).is_ok() {
+                    server_map.remove(&key).map_context("SERVER_MAP")?;
+                }
+            }
+        }
+        {
+            // a client coming now finds no server and cold starts the service again
+            let mut draining_map = self.draining_map.lock().await;
+            if draining_map.get(&e.to_u_endpoint(), 0).is_ok() {
+                draining_map
+                    .remove(&e.to_u_endpoint())
+                    .map_context("DRAINING_MAP")?;
+            }
+        }
+
+        self.providers
+            .stop_server(e.to_string())
+            .await
+            .map_err(FolonetError::Manager)?;
+        info!("removed {}", e.to_string());
+        Ok(())
+    }
+
+    // the connections of `e` still open, as counted by the kernel or by the
+    // connection trackers, whichever saw more
+    async fn open_connections(&self, e: &Endpoint) -> u64 {
+        let tracked = match self.services.get(e) {
+            Some(service) => service.handler.lock().await.tracked() as u64,
+            None => 0,
+        };
+        self.scaler.load(e).concurrency.max(tracked)
+    }
+
+    async fn drain(&self, e: &Endpoint) {
+        let timeout = Duration::from_secs(self.scale_down.drain_deadline_secs);
+        if self.scale_down.drain_rpc && self.open_connections(e).await > 0 {
+            // a manager without the rpc still gets the backend stopped
+            if let Err(err) = self.providers.drain_server(e.to_string(), timeout).await {
+                warn!(
+                    "failed to ask the backend of {} to drain: {}",
+                    e.to_string(),
+                    err
+                );
+            }
+        }
+        if let Some(cfg) = &self.fin_sweep {
+            self.sweep(e, cfg).await;
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            let open = self.open_connections(e).await;
+            if open == 0 {
+                return;
+            }
+            if Instant::now() >= deadline {
+                warn!(
+                    "{} still has {} connection(s) after {:?}, removing it anyway",
+                    e.to_string(),
+                    open,
+                    timeout
+                );
+                return;
+            }
+            sleep(DRAIN_POLL).await;
+        }
+    }
+
+    // the idle connections close rather than wait for the drain to time out
+    async fn sweep(&self, e: &Endpoint, cfg: &FinSweepConfig) {
+        let min_idle_ns = Duration::from_secs(cfg.min_idle_secs).as_nanos() as u64;
+        let mut targets = vec![];
+        if let Some(service) = self.services.get(e) {
+            let service = service.handler.lock().await;
+            for tracker in service.server_tracker_map.values() {
+                targets.extend(tracker.idle_fin_targets(*e, min_idle_ns).await);
+            }
+        }
+        if targets.is_empty() {
+            return;
+        }
+        match send_fins(targets).await {
+            Ok(sent) => info!("sent a fin to {} idle client(s) of {}", sent, e.to_string()),
+            Err(err) => warn!(
+                "failed to close the idle connections of {}: {}",
+                e.to_string(),
+                err
+            ),
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+
+use folonet_client::config::ServiceConfig;
+use folonet_client::provider::Providers;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+
+use crate::error::FolonetError;
+use crate::output::Report;
+use crate::ports::DEFAULT_PORT_RANGE;
+
+// past these the report names a part as the bottleneck
+const SLOW_START_MS: u64 = 1000;
+const BEHIND_MS: u64 = 100;
+
+// the fields of a flow log line a replay needs
+#[derive(Deserialize)]
+struct TraceLine {
+    // when the connection closed, unix ms
+    ts: u64,
+    service: String,
+    duration_ms: u64,
+}
+
+// one connection of a trace, times relative to the first one opened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceConn {
+    pub open_ms: u64,
+    pub duration_ms: u64,
+    pub service: String,
+}
+
+// read a flow log, one json record per line, into connections by the time
+// they opened
+pub fn parse_trace(text: &str) -> Result<Vec<TraceConn>, FolonetError> {
+    let mut conns = vec![];
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line: TraceLine = serde_json::from_str(line)
+            .map_err(|e| FolonetError::Config(format!("trace line {}: {}", i + 1, e)))?;
+        conns.push(TraceConn {
+            open_ms: line.ts.saturating_sub(line.duration_ms),
+            duration_ms: line.duration_ms,
+            service: line.service,
+        });
+    }
+    conns.sort_by_key(|conn| conn.open_ms);
+    let first = conns.first().map(|conn| conn.open_ms).unwrap_or(0);
+    conns.iter_mut().for_each(|conn| conn.open_ms -= first);
+    Ok(conns)
+}
+
+// what starts the backends during a replay
+#[derive(Clone)]
+pub enum ReplayManager {
+    // every start takes this long and succeeds
+    Mock(Duration),
+    Real(Providers),
+}
+
+impl ReplayManager {
+    async fn start(&self, service: &ServiceConfig) -> Result<(), String> {
+        match self {
+            ReplayManager::Mock(delay) => {
+                sleep(*delay).await;
+                Ok(())
+            }
+            ReplayManager::Real(manager) => {
+                match manager
+                    .start_server(service.local_endpoint.clone(), &service.metadata)
+                    .await
+                {
+                    Ok(Some(_)) => Ok(()),
+                    Ok(None) => Err("no active server".to_string()),
+                    Err(e) => Err(e.to_string()),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    // how much faster than recorded the trace is played
+    pub speedup: f64,
+    pub pool_size: usize,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        ReplayOptions {
+            speedup: 1.0,
+            pool_size: DEFAULT_PORT_RANGE.len(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct ReplayReport {
+    pub connections: usize,
+    // of services missing from the config
+    pub skipped: usize,
+    pub cold_starts: usize,
+    pub start_failures: usize,
+    // how long the manager took to start a backend
+    pub start_p50_ms: u64,
+    pub start_p99_ms: u64,
+    pub start_max_ms: u64,
+    // connections waiting for a cold start at the same time
+    pub max_pending: usize,
+    pub pool_size: usize,
+    pub peak_ports: usize,
+    // connections that found the port pool empty
+    pub port_exhausted: usize,
+    // how far the replay fell behind the trace
+    pub max_lag_ms: u64,
+    pub bottlenecks: Vec<String>,
+}
+
+impl ReplayReport {
+    fn find_bottlenecks(&mut self, speedup: f64) {
+        if self.port_exhausted > 0 {
+            self.bottlenecks.push(format!(
+                "port pool of {} ran out for {} connections",
+                self.pool_size, self.port_exhausted
+            ));
+        }
+        if self.start_failures > 0 {
+            self.bottlenecks
+                .push(format!("{} backend starts failed", self.start_failures));
+        }
+        if self.start_p99_ms >= SLOW_START_MS {
+            self.bottlenecks.push(format!(
+                "server manager took {}ms to start a backend (p99), {} connections waited at once",
+                self.start_p99_ms, self.max_pending
+            ));
+        }
+        if self.max_lag_ms >= BEHIND_MS {
+            self.bottlenecks.push(format!(
+                "replay fell {}ms behind the trace at {}x, cold start handling cannot keep up",
+                self.max_lag_ms, speedup
+            ));
+        }
+    }
+}
+
+impl Report for ReplayReport {
+    const KIND: &'static str = "replay";
+
+    fn human(&self) -> String {
+        let mut out = format!(
+            "connections: {} ({} skipped)\ncold starts: {} ({} failed)\nstart latency: p50 {}ms, p99 {}ms, max {}ms\nmax pending: {}\nports: peak {} of {}, exhausted {}\nmax lag: {}ms\n",
+            self.connections,
+            self.skipped,
+            self.cold_starts,
+            self.start_failures,
+            self.start_p50_ms,
+            self.start_p99_ms,
+            self.start_max_ms,
+            self.max_pending,
+            self.peak_ports,
+            self.pool_size,
+            self.port_exhausted,
+            self.max_lag_ms
+        );
+        if self.bottlenecks.is_empty() {
+            out.push_str("bottlenecks: none\n");
+        }
+        for b in self.bottlenecks.iter() {
+            out.push_str(&format!("bottleneck: {}\n", b));
+        }
+        out
+    }
+}
+
+fn percentile(sorted: &[u64], q: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let i = ((sorted.len() - 1) as f64 * q).round() as usize;
+    sorted[i]
+}
+
+enum Backend {
+    Starting(VecDeque<Duration>),
+    Running,
+    Failed,
+}
+
+// local ports of the replayed connections, released when they close
+struct Ports {
+    size: usize,
+    releases: BinaryHeap<Reverse<Instant>>,
+    peak: usize,
+    exhausted: usize,
+}
+
+impl Ports {
+    fn take(&mut self, now: Instant, hold: Duration) {
+        while self.releases.peek().is_some_and(|r| r.0 <= now) {
+            self.releases.pop();
+        }
+        if self.releases.len() >= self.size {
+            self.exhausted += 1;
+            return;
+        }
+        self.releases.push(Reverse(now + hold));
+        self.peak = self.peak.max(self.releases.len());
+    }
+}
+
+struct ReplayState {
+    report: ReplayReport,
+    ports: Ports,
+    backends: HashMap<String, Backend>,
+    // start latencies in ms
+    latencies: Vec<u64>,
+    // connections waiting for a cold start right now
+    pending: usize,
+}
+
+impl ReplayState {
+    fn started(&mut self, name: &str, result: Result<(), String>, took: Duration) {
+        self.latencies.push(took.as_millis() as u64);
+        let pending = match self.backends.remove(name) {
+            Some(Backend::Starting(pending)) => pending,
+            _ => VecDeque::new(),
+        };
+        self.pending -= pending.len();
+        if result.is_err() {
+            self.report.start_failures += 1;
+            self.backends.insert(name.to_string(), Backend::Failed);
+            return;
+        }
+        let now = Instant::now();
+        for hold in pending {
+            self.ports.take(now, hold);
+        }
+        self.backends.insert(name.to_string(), Backend::Running);
+    }
+}
+
+// Plays the connections of `trace` against a model of the cold start path:
+// the first connection of a service starts its backend through `manager`,
+// the following ones wait until it runs, and every connection holds a local
+// port for its recorded duration. A backend keeps running once started.
+pub async fn replay(
+    trace: &[TraceConn],
+    services: &[ServiceConfig],
+    manager: ReplayManager,
+    opts: &ReplayOptions,
+) -> ReplayReport {
+    let scale = |ms: u64| Duration::from_secs_f64(ms as f64 / 1000.0 / opts.speedup);
+    let mut state = ReplayState {
+        report: ReplayReport {
+            pool_size: opts.pool_size,
+            ..Default::default()
+        },
+        ports: Ports {
+            size: opts.pool_size,
+            releases: BinaryHeap::new(),
+            peak: 0,
+            exhausted: 0,
+        },
+        backends: HashMap::new(),
+        latencies: vec![],
+        pending: 0,
+    };
+    let (tx, mut rx) = mpsc::unbounded_channel::<(String, Result<(), String>, Duration)>();
+    let begin = Instant::now();
+
+    for conn in trace {
+        let at = begin + scale(conn.open_ms);
+        sleep_until(at).await;
+        let now = Instant::now();
+        let lag = now.saturating_duration_since(at).as_millis() as u64;
+        state.report.max_lag_ms = state.report.max_lag_ms.max(lag);
+        while let Ok((name, result, took)) = rx.try_recv() {
+            state.started(&name, result, took);
+        }
+
+        let service = match services.iter().find(|s| s.name == conn.service) {
+            Some(service) => service,
+            None => {
+                state.report.skipped += 1;
+                continue;
+            }
+        };
+        state.report.connections += 1;
+        let hold = scale(conn.duration_ms);
+        match state.backends.get_mut(&service.name) {
+            Some(Backend::Running) => state.ports.take(now, hold),
+            Some(Backend::Starting(pending)) => {
+                pending.push_back(hold);
+                state.pending += 1;
+            }
+            // the client of a failed start gives up
+            Some(Backend::Failed) => {}
+            None => {
+                state.report.cold_starts += 1;
+                state.pending += 1;
+                state.backends.insert(
+                    service.name.clone(),
+                    Backend::Starting(VecDeque::from([hold])),
+                );
+                let manager = manager.clone();
+                let service = service.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let start = Instant::now();
+                    let result = manager.start(&service).await;
+                    let _ = tx.send((service.name, result, start.elapsed()));
+                });
+            }
+        }
+        state.report.max_pending = state.report.max_pending.max(state.pending);
+    }
+
+    // the starts still running at the end of the trace
+    drop(tx);
+    while let Some((name, result, took)) = rx.recv().await {
+        state.started(&name, result, took);
+    }
+
+    let ReplayState {
+        mut report,
+        ports,
+        mut latencies,
+        ..
+    } = state;
+    latencies.sort_unstable();
+    report.start_p50_ms = percentile(&latencies, 0.5);
+    report.start_p99_ms = percentile(&latencies, 0.99);
+    report.start_max_ms = percentile(&latencies, 1.0);
+    report.peak_ports = ports.peak;
+    report.port_exhausted = ports.exhausted;
+    report.find_bottlenecks(opts.speedup);
+    report
+}
+
+mod test {
+
+    #[test]
+    fn test_parse_trace() {
+        use super::{parse_trace, TraceConn};
+
+        let text = r#"{"ts":5000,"service":"web","client":"10.0.0.2:40000","backend":"10.0.0.9:80","duration_ms":1000,"bytes_in":1,"bytes_out":2,"close_reason":"fin","generation":1}
+
+{"ts":3500,"service":"api","duration_ms":500}"#;
+        let trace = parse_trace(text).unwrap();
+        assert_eq!(
+            trace,
+            vec![
+                TraceConn {
+                    open_ms: 0,
+                    duration_ms: 500,
+                    service: "api".to_string()
+                },
+                TraceConn {
+                    open_ms: 1000,
+                    duration_ms: 1000,
+                    service: "web".to_string()
+                },
+            ]
+        );
+        assert!(parse_trace("{\"ts\":1}").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        use folonet_client::config::ServiceConfig;
+        use tokio::time::Duration;
+
+        use super::{replay, ReplayManager, ReplayOptions, TraceConn};
+
+        let conn = |open_ms: u64, service: &str| TraceConn {
+            open_ms,
+            duration_ms: 60_000,
+            service: service.to_string(),
+        };
+        let trace = vec![
+            conn(0, "web"),
+            conn(1, "web"),
+            conn(2, "web"),
+            conn(3, "gone"),
+        ];
+        let services = vec![ServiceConfig {
+            name: "web".to_string(),
+            local_endpoint: "10.0.0.1:8080".to_string(),
+            ..Default::default()
+        }];
+        let opts = ReplayOptions {
+            speedup: 1.0,
+            pool_size: 2,
+        };
+
+        let report = replay(
+            &trace,
+            &services,
+            ReplayManager::Mock(Duration::from_millis(20)),
+            &opts,
+        )
+        .await;
+        assert_eq!(report.connections, 3);
+        assert_eq!(report.skipped, 1);
+        assert_eq!(report.cold_starts, 1);
+        assert_eq!(report.max_pending, 3);
+        assert_eq!(report.peak_ports, 2);
+        assert_eq!(report.port_exhausted, 1);
+        assert!(!report.bottlenecks.is_empty());
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use folonet_client::config::{HttpRoutingConfig, ServiceConfig};
+use log::{debug, info, warn};
+use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration};
+
+use crate::endpoint::Endpoint;
+use crate::error::FolonetError;
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_RECORD_HEADER_LEN: usize = 5;
+const TLS_EXT_SERVER_NAME: u16 = 0;
+const SNI_HOST_NAME: u8 = 0;
+
+// what a connection is routed by, read from its first bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RouteBy {
+    HttpHost,
+    TlsServerName,
+}
+
+impl RouteBy {
+    // whether `first` holds all it takes to find the name
+    fn complete(&self, first: &[u8]) -> bool {
+        match self {
+            RouteBy::HttpHost => first.windows(4).any(|w| w == b"\r\n\r\n"),
+            RouteBy::TlsServerName => match first.get(3..TLS_RECORD_HEADER_LEN) {
+                Some(len) => {
+                    let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+                    first.len() >= TLS_RECORD_HEADER_LEN + len
+                }
+                None => false,
+            },
+        }
+    }
+
+    fn name_of(&self, first: &[u8]) -> Option<String> {
+        match self {
+            RouteBy::HttpHost => host_of(first),
+            RouteBy::TlsServerName => server_name_of(first),
+        }
+    }
+}
+
+// the first bytes of a connection, until `by` has all it needs or `max` bytes
+async fn read_first(stream: &mut TcpStream, by: RouteBy, max: usize) -> std::io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; max];
+    let mut len = 0;
+    while len < max && !by.complete(&buf[..len]) {
+        let n = stream.read(&mut buf[len..]).await?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    buf.truncate(len);
+    Ok(buf)
+}
+
+// the host a request head asks for, lowercase and without its port
+fn host_of(head: &[u8]) -> Option<String> {
+    let end = head.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&head[..end]).ok()?;
+    let value = head.split("\r\n").skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("host")
+            .then_some(value.trim())
+    })?;
+    let host = match value.strip_prefix('[') {
+        // an ipv6 literal keeps its colons
+        Some(rest) => rest.split(']').next()?,
+        None => value.split(':').next()?,
+    };
+    (!host.is_empty()).then(|| host.to_ascii_lowercase())
+}
+
+// reads through a tls record, none past its end
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    // a field prefixed by its length in `len_bytes` bytes
+    fn vec(&mut self, len_bytes: usize) -> Option<Reader<'a>> {
+        let len = self
+            .take(len_bytes)?
+            .iter()
+            .fold(0usize, |len, b| len << 8 | *b as usize);
+        self.take(len).map(|bytes| Reader { bytes })
+    }
+}
+
+// the server name of a client hello in the first tls record, lowercase, see
+// rfc 8446 4.1.2 and rfc 6066 3
+fn server_name_of(first: &[u8]) -> Option<String> {
+    let mut record = Reader { bytes: first };
+    if record.u8()? != TLS_HANDSHAKE {
+        return None;
+    }
+    record.take(2)?;
+    let mut handshake = record.vec(2)?;
+    if handshake.u8()? != TLS_CLIENT_HELLO {
+        return None;
+    }
+    let mut hello = handshake.vec(3)?;
+    // version and random
+    hello.take(2 + 32)?;
+    hello.vec(1)?; // session id
+    hello.vec(2)?; // cipher suites
+    hello.vec(1)?; // compression methods
+    let mut extensions = hello.vec(2)?;
+    while let Some(kind) = extensions.u16() {
+        let mut extension = extensions.vec(2)?;
+        if kind != TLS_EXT_SERVER_NAME {
+            continue;
+        }
+        let mut names = extension.vec(2)?;
+        while let Some(name_type) = names.u8() {
+            let name = names.vec(2)?;
+            if name_type == SNI_HOST_NAME {
+                let name = std::str::from_utf8(name.bytes).ok()?;
+                return (!name.is_empty()).then(|| name.to_ascii_lowercase());
+            }
+        }
+        return None;
+    }
+    None
+}
+
+fn reply(status: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    )
+}
+
+// the servers of a route, taken in turns
+#[derive(Debug, Default)]
+pub(crate) struct Pool {
+    servers: Vec<Endpoint>,
+    next: AtomicUsize,
+}
+
+impl Pool {
+    pub(crate) fn new(servers: &[String]) -> Result<Self, FolonetError> {
+        Ok(Pool {
+            servers: servers
+                .iter()
+                .map(|server| server.parse::<Endpoint>())
+                .collect::<Result<Vec<_>, _>>()?,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    pub(crate) fn pick(&self) -> Option<Endpoint> {
+        if self.servers.is_empty() {
+            return None;
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        Some(self.servers[next % self.servers.len()])
+    }
+}
+
+// Proxies a service with http or sni routes: the host of the first request
+// of a connection, or the server name of its tls client hello, picks the pool
+// of servers the whole connection goes to. Tls is not terminated. The xdp
+// program hands the packets of the service to the host stack.
+#[derive(Clone)]
+pub struct HostRouter {
+    name: String,
+    local_endpoint: Endpoint,
+    by: RouteBy,
+    routes: Arc<HashMap<String, Pool>>,
+    // the servers of the service, for the hosts without a route
+    default: Arc<Pool>,
+    cfg: HttpRoutingConfig,
+}
+
+impl HostRouter {
+    pub fn new(service: &ServiceConfig, cfg: &HttpRoutingConfig) -> Result<Self, FolonetError> {
+        if !service.http_routes.is_empty() && !service.sni_routes.is_empty() {
+            return Err(FolonetError::Config(format!(
+                "service {} has both http and sni routes",
+                service.name
+            )));
+        }
+        let mut routes = HashMap::new();
+        for route in service.http_routes.iter() {
+            routes.insert(route.host.to_ascii_lowercase(), Pool::new(&route.servers)?);
+        }
+        for route in service.sni_routes.iter() {
+            routes.insert(
+                route.server_name.to_ascii_lowercase(),
+                Pool::new(&route.servers)?,
+            );
+        }
+        let by = if service.sni_routes.is_empty() {
+            RouteBy::HttpHost
+        } else {
+            RouteBy::TlsServerName
+        };
+        Ok(HostRouter {
+            name: service.name.clone(),
+            local_endpoint: service.local_endpoint.parse::<Endpoint>()?,
+            by,
+            routes: Arc::new(routes),
+            default: Arc::new(Pool::new(&service.servers)?),
+            cfg: cfg.clone(),
+        })
+    }
+
+    // what the client hears when its connection goes nowhere, tls clients
+    // just see it closed
+    async fn refuse(&self, client: &mut TcpStream, status: &str) -> std::io::Result<()> {
+        match self.by {
+            RouteBy::HttpHost => client.write_all(reply(status).as_bytes()).await,
+            RouteBy::TlsServerName => Ok(()),
+        }
+    }
+
+    fn pool(&self, host: Option<&str>) -> &Pool {
+        host.and_then(|host| self.routes.get(host))
+            .unwrap_or(&self.default)
+    }
+
+    pub async fn bind(&self) -> Result<TcpListener, FolonetError> {
+        let addr = self.local_endpoint.to_string();
+        TcpListener::bind(&addr)
+            .await
+            .map_err(|source| FolonetError::Io {
+                context: format!("failed to listen on {} for {}", addr, self.name),
+                source,
+            })
+    }
+
+    pub async fn serve_forever(self, listener: TcpListener) {
+        info!(
+            "routing {} on {} by {:?}",
+            self.name,
+            self.local_endpoint.to_string(),
+            self.by
+        );
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("failed to accept a connection of {}: {}", self.name, e);
+                    continue;
+                }
+            };
+            let router = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = router.proxy(stream).await {
+                    debug!("failed to proxy {} to {}: {}", addr, router.name, e);
+                }
+            });
+        }
+    }
+
+    async fn proxy(&self, mut client: TcpStream) -> std::io::Result<()> {
+        let head_timeout = Duration::from_millis(self.cfg.head_timeout_ms);
+        let read = read_first(&mut client, self.by, self.cfg.max_head_bytes);
+        let head = match timeout(head_timeout, read).await {
+            Ok(head) => head?,
+            Err(_) => return self.refuse(&mut client, "408 Request Timeout").await,
+        };
+        if head.is_empty() {
+            return Ok(());
+        }
+
+        let name = self.by.name_of(&head);
+        let backend = match self.pool(name.as_deref()).pick() {
+            Some(backend) => backend,
+            None => return self.refuse(&mut client, "502 Bad Gateway").await,
+        };
+        let connect_timeout = Duration::from_millis(self.cfg.connect_timeout_ms);
+        let connected = timeout(connect_timeout, TcpStream::connect(backend.to_string())).await;
+        let mut upstream = match connected {
+            Ok(Ok(upstream)) => upstream,
+            Ok(Err(_)) | Err(_) => return self.refuse(&mut client, "502 Bad Gateway").await,
+        };
+        upstream.write_all(&head).await?;
+        copy_bidirectional(&mut client, &mut upstream).await?;
+        Ok(())
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_host_of() {
+        use super::host_of;
+
+        let head = b"GET / HTTP/1.1\r\nUser-Agent: curl\r\nHost: Fn-A.example.com:8080\r\n\r\nbody";
+        assert_eq!(host_of(head), Some("fn-a.example.com".to_string()));
+
+        let head = b"GET / HTTP/1.1\r\nhost:[::1]:8080\r\n\r\n";
+        assert_eq!(host_of(head), Some("::1".to_string()));
+
+        // no host, or a head cut off by the limit
+        assert_eq!(host_of(b"GET / HTTP/1.0\r\n\r\n"), None);
+        assert_eq!(host_of(b"GET / HTTP/1.1\r\nHost: a.example.com"), None);
+        // the request line is not a header
+        assert_eq!(host_of(b"Host: a HTTP/1.1\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn test_server_name_of() {
+        use super::{server_name_of, RouteBy};
+
+        let name = b"Fn-B.example.com";
+        let mut sni = vec![0x00, 0x00];
+        sni.extend(((name.len() + 5) as u16).to_be_bytes());
+        sni.extend(((name.len() + 3) as u16).to_be_bytes());
+        sni.push(0x00);
+        sni.extend((name.len() as u16).to_be_bytes());
+        sni.extend(name);
+        // an extension before it, supported groups
+        let mut extensions = vec![0x00, 0x0a, 0x00, 0x04, 0x00, 0x02, 0x00, 0x1d];
+        extensions.extend(sni);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend([0xab; 32]);
+        hello.extend([0x00]);
+        hello.extend([0x00, 0x02, 0x13, 0x01]);
+        hello.extend([0x01, 0x00]);
+        hello.extend((extensions.len() as u16).to_be_bytes());
+        hello.extend(extensions);
+
+        let mut handshake = vec![0x01, 0x00];
+        handshake.extend((hello.len() as u16).to_be_bytes());
+        handshake.extend(hello);
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+
+        assert!(RouteBy::TlsServerName.complete(&record));
+        assert!(!RouteBy::TlsServerName.complete(&record[..record.len() - 1]));
+        assert_eq!(
+            server_name_of(&record),
+            Some("fn-b.example.com".to_string())
+        );
+        // cut off, or not tls at all
+        assert_eq!(server_name_of(&record[..60]), None);
+        assert_eq!(server_name_of(b"GET / HTTP/1.1\r\n\r\n"), None);
+    }
+}
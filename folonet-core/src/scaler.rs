@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use aya::maps::{MapData, PerCpuHashMap};
+use folonet_common::load::KServiceLoad;
+use serde::Serialize;
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::endpoint::{Endpoint, UEndpoint};
+
+// what an autoscaler needs to know about a service
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct ServiceLoad {
+    // connections currently open: opened as counted by the kernel, less the
+    // ones the connection trackers closed, every close path counts once
+    pub concurrency: u64,
+    // new connections per second over the last sampling window
+    pub request_rate: f64,
+    pub packet_rate: f64,
+    pub byte_rate: f64,
+}
+
+impl ServiceLoad {
+    // no open connection and no new one over the last sampling window
+    pub fn is_idle(&self) -> bool {
+        self.concurrency == 0 && self.request_rate == 0.0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    at: Instant,
+    total: KServiceLoad,
+}
+
+fn per_second(prev: u64, cur: u64, secs: f64) -> f64 {
+    if secs <= 0.0 {
+        return 0.0;
+    }
+    cur.saturating_sub(prev) as f64 / secs
+}
+
+fn aggregate(prev: Option<&Sample>, cur: &Sample, closed: u64) -> ServiceLoad {
+    let concurrency = cur.total.conns_opened.saturating_sub(closed);
+    let prev = match prev {
+        Some(prev) => prev,
+        None => {
+            return ServiceLoad {
+                concurrency,
+                ..Default::default()
+            }
+        }
+    };
+    let secs = cur.at.duration_since(prev.at).as_secs_f64();
+    ServiceLoad {
+        concurrency,
+        request_rate: per_second(prev.total.conns_opened, cur.total.conns_opened, secs),
+        packet_rate: per_second(prev.total.packets, cur.total.packets, secs),
+        byte_rate: per_second(prev.total.bytes, cur.total.bytes, secs),
+    }
+}
+
+#[derive(Default)]
+struct ScalerState {
+    samples: HashMap<Endpoint, Sample>,
+    // connections closed as seen by the connection trackers, whatever
+    // closed them: a fin or rst, a timer, a sweep, an eviction
+    closed: HashMap<Endpoint, u64>,
+    loads: HashMap<Endpoint, ServiceLoad>,
+}
+
+// Per service load sampled from the SERVICE_LOAD map. Cheap to clone, every
+// clone sees the same numbers.
+#[derive(Clone, Default)]
+pub struct Scaler {
+    state: Arc<RwLock<ScalerState>>,
+}
+
+impl Scaler {
+    pub fn new() -> Self {
+        Scaler::default()
+    }
+
+    pub fn load(&self, service: &Endpoint) -> ServiceLoad {
+        let state = self.state.read().unwrap();
+        state.loads.get(service).copied().unwrap_or_default()
+    }
+
+    pub fn loads(&self) -> HashMap<Endpoint, ServiceLoad> {
+        self.state.read().unwrap().loads.clone()
+    }
+
+    pub fn is_idle(&self, service: &Endpoint) -> bool {
+        self.load(service).is_idle()
+    }
+
+    pub fn conn_closed(&self, service: &Endpoint) {
+        let mut state = self.state.write().unwrap();
+        *state.closed.entry(*service).or_default() += 1;
+    }
+
+    fn record(&self, service: Endpoint, total: KServiceLoad, at: Instant) {
+        let mut state = self.state.write().unwrap();
+        let cur = Sample { at, total };
+        let closed = state.closed.get(&service).copied().unwrap_or(0);
+        let load = aggregate(state.samples.get(&service), &cur, closed);
+        state.samples.insert(service, cur);
+        state.loads.insert(service, load);
+    }
+
+    pub async fn sample_forever(
+        self,
+        map: PerCpuHashMap<MapData, UEndpoint, KServiceLoad>,
+        interval: Duration,
+    ) {
+        loop {
+            let now = Instant::now();
+            for (service, values) in map.iter().filter_map(|item| item.ok()) {
+                let total = values
+                    .iter()
+                    .fold(KServiceLoad::default(), |acc, v| KServiceLoad {
+                        conns_opened: acc.conns_opened + v.conns_opened,
+                        packets: acc.packets + v.packets,
+                        bytes: acc.bytes + v.bytes,
+                    });
+                self.record(service.to_endpoint(), total, now);
+            }
+            sleep(interval).await;
+        }
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_aggregate_load() {
+        use folonet_common::load::KServiceLoad;
+        use tokio::time::{Duration, Instant};
+
+        use super::{aggregate, Sample};
+
+        let at = Instant::now();
+        let prev = Sample {
+            at,
+            total: KServiceLoad {
+                conns_opened: 10,
+                packets: 100,
+                bytes: 1000,
+            },
+        };
+        let cur = Sample {
+            at: at + Duration::from_secs(2),
+            total: KServiceLoad {
+                conns_opened: 14,
+                packets: 140,
+                bytes: 3000,
+            },
+        };
+
+        let load = aggregate(None, &prev, 10);
+        assert_eq!(load.concurrency, 0);
+        assert!(load.is_idle());
+
+        let load = aggregate(Some(&prev), &cur, 11);
+        assert_eq!(load.concurrency, 3);
+        assert_eq!(load.request_rate, 2.0);
+        assert_eq!(load.packet_rate, 20.0);
+        assert_eq!(load.byte_rate, 1000.0);
+        assert!(!load.is_idle());
+    }
+}
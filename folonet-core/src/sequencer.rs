@@ -0,0 +1,169 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+use aya::maps::{HashMap as AyaHashMap, MapData};
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::endpoint::{Endpoint, UEndpoint};
+
+pub type BpfEpochMap = Arc<Mutex<AyaHashMap<MapData, UEndpoint, u32>>>;
+
+// packet events kept per service while its cold start is in flight, the
+// oldest are dropped beyond that
+const MAX_DEFERRED: usize = 4096;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SequencerStats {
+    // cold start notifications of a service already starting
+    pub duplicate_cold_starts: u64,
+    pub deferred: u64,
+    pub replayed: u64,
+    // events of a backend installed before the current one
+    pub stale: u64,
+    pub overflowed: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Admit<T> {
+    Deliver(T),
+    Deferred,
+    Stale,
+}
+
+// Cold start and packet notifications come through two ring buffers, so a
+// packet event may be read before the cold start of its service is done.
+// The kernel stamps packet events with the epoch of their service, which
+// userspace bumps on every install. Events of an epoch not installed yet wait
+// here and are replayed once it is, events of an older epoch are dropped.
+pub struct Sequencer<T> {
+    epochs: HashMap<Endpoint, u32>,
+    in_flight: HashSet<Endpoint>,
+    deferred: HashMap<Endpoint, VecDeque<(u32, T)>>,
+    stats: SequencerStats,
+}
+
+impl<T> Default for Sequencer<T> {
+    fn default() -> Self {
+        Sequencer {
+            epochs: HashMap::new(),
+            in_flight: HashSet::new(),
+            deferred: HashMap::new(),
+            stats: SequencerStats::default(),
+        }
+    }
+}
+
+impl<T> Sequencer<T> {
+    // false when `service` is already cold starting
+    pub fn begin(&mut self, service: Endpoint) -> bool {
+        if !self.in_flight.insert(service) {
+            self.stats.duplicate_cold_starts += 1;
+            return false;
+        }
+        true
+    }
+
+    // the epoch to write to SERVICE_EPOCH before routing to a new backend
+    pub fn next_epoch(&self, service: &Endpoint) -> u32 {
+        self.epochs
+            .get(service)
+            .copied()
+            .unwrap_or(0)
+            .wrapping_add(1)
+    }
+
+    pub fn admit(&mut self, service: Endpoint, epoch: u32, event: T) -> Admit<T> {
+        let installed = self.epochs.get(&service).copied().unwrap_or(0);
+        if epoch == installed {
+            return Admit::Deliver(event);
+        }
+        if epoch < installed {
+            self.stats.stale += 1;
+            return Admit::Stale;
+        }
+
+        let deferred = self.deferred.entry(service).or_default();
+        if deferred.len() >= MAX_DEFERRED {
+            deferred.pop_front();
+            self.stats.overflowed += 1;
+        }
+        deferred.push_back((epoch, event));
+        self.stats.deferred += 1;
+        Admit::Deferred
+    }
+
+    // `epoch` of `service` is installed, returns its deferred events in the
+    // order they were read
+    pub fn finish(&mut self, service: Endpoint, epoch: u32) -> Vec<T> {
+        self.in_flight.remove(&service);
+        self.epochs.insert(service, epoch);
+
+        let mut replay = vec![];
+        let mut later = VecDeque::new();
+        for (e, event) in self.deferred.remove(&service).unwrap_or_default() {
+            if e == epoch {
+                replay.push(event);
+            } else if e > epoch {
+                later.push_back((e, event));
+            } else {
+                self.stats.stale += 1;
+            }
+        }
+        if !later.is_empty() {
+            self.deferred.insert(service, later);
+        }
+        self.stats.replayed += replay.len() as u64;
+        replay
+    }
+
+    // the cold start of `service` failed, nothing was installed
+    pub fn abort(&mut self, service: &Endpoint) {
+        self.in_flight.remove(service);
+    }
+
+    // whether `e` is a service that was cold started or is starting
+    pub fn tracks(&self, e: &Endpoint) -> bool {
+        self.epochs.contains_key(e) || self.in_flight.contains(e)
+    }
+
+    pub fn stats(&self) -> SequencerStats {
+        self.stats
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_sequencer() {
+        use crate::endpoint::Endpoint;
+
+        use super::{Admit, Sequencer};
+
+        let e: Endpoint = "10.0.0.1:8080".parse().unwrap();
+        let mut seq: Sequencer<&str> = Sequencer::default();
+
+        // services installed at startup are at epoch 0
+        assert_eq!(seq.admit(e, 0, "static"), Admit::Deliver("static"));
+
+        assert!(seq.begin(e));
+        assert!(!seq.begin(e));
+        assert_eq!(seq.stats().duplicate_cold_starts, 1);
+
+        let epoch = seq.next_epoch(&e);
+        assert_eq!(epoch, 1);
+        assert_eq!(seq.admit(e, epoch, "syn"), Admit::Deferred);
+        assert_eq!(seq.admit(e, epoch, "fin"), Admit::Deferred);
+        assert_eq!(seq.finish(e, epoch), vec!["syn", "fin"]);
+        assert_eq!(seq.admit(e, epoch, "ack"), Admit::Deliver("ack"));
+
+        // a late event of the backend before
+        assert_eq!(seq.admit(e, 0, "old"), Admit::Stale);
+        assert!(seq.begin(e));
+
+        let stats = seq.stats();
+        assert_eq!(stats.deferred, 2);
+        assert_eq!(stats.replayed, 2);
+        assert_eq!(stats.stale, 1);
+    }
+}
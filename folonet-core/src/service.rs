@@ -0,0 +1,329 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use folonet_client::config::ServiceConfig;
+use folonet_common::nat::CT_NEW;
+use log::info;
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{sleep, Duration},
+};
+
+use crate::{
+    endpoint::{Endpoint, UConnection},
+    flow_log::FlowTracker,
+    message::{Message, MessageType},
+    output::{ConnectionRow, NatFlow},
+    ports::PortPool,
+    scaler::Scaler,
+    shard::Shards,
+    state::{
+        tcp::{ConnectionState, TCPState},
+        BpfConnectionMap, CloseMsg, ConnectionStateMgr, PacketMsg,
+    },
+    timer::Timers,
+    worker::{MsgHandler, MsgWorker},
+};
+
+/// A configured service and the connection trackers of its backends.
+pub struct Service {
+    pub name: String,
+    pub local_endpoint: Endpoint,
+    pub servers: Vec<Endpoint>,
+    pub active: AtomicBool,
+    pub server_tracker_map: HashMap<Endpoint, MsgWorker<ConnectionStateMgr>>,
+    // connections its trackers follow, across all backends
+    tracked: Arc<AtomicUsize>,
+    idle_sweep: Option<JoinHandle<()>>,
+    reaper: Option<JoinHandle<()>>,
+}
+
+impl MsgHandler for Service {
+    type MsgType = Message;
+
+    async fn handle_message(&mut self, msg: Self::MsgType) {
+        match msg.msg_type {
+            MessageType::Packet(_) => {
+                if let Some(server_tracker) = self.server_tracker_map.get_mut(&msg.server) {
+                    server_tracker.handle_packet_msg(msg).await;
+                }
+            }
+            MessageType::Close => {}
+        }
+    }
+}
+
+impl Service {
+    pub fn new(
+        cfg: &ServiceConfig,
+        connection_map: BpfConnectionMap,
+        port_pool: PortPool,
+        flow_tracker: FlowTracker,
+        scaler: Scaler,
+        shards: Shards<ConnectionState>,
+        timers: Timers,
+    ) -> Self {
+        let local_endpoint = Endpoint::from(&cfg.local_endpoint);
+        let servers: Vec<Endpoint> = cfg.servers.iter().map(|s| Endpoint::from(s)).collect();
+        let tracked = Arc::new(AtomicUsize::new(0));
+        let server_tracker_map: HashMap<Endpoint, MsgWorker<ConnectionStateMgr>> = servers
+            .iter()
+            .map(|server| {
+                (
+                    server.clone(),
+                    MsgWorker::new(ConnectionStateMgr::new(
+                        cfg.is_tcp,
+                        cfg.name.clone(),
+                        connection_map.clone(),
+                        port_pool.clone(),
+                        flow_tracker.clone(),
+                        scaler.clone(),
+                        cfg.cleanup,
+                        shards.clone(),
+                        timers.clone(),
+                        tracked.clone(),
+                    )),
+                )
+            })
+            .collect();
+
+        // udp flows are closed by their idle timers, the sweep still closes
+        // those whose first packet never reached the state machine
+        let idle_sweep = if !cfg.is_tcp || cfg.cleanup.strategy.uses_idle_timeout() {
+            let senders = server_tracker_map
+                .iter()
+                .filter_map(|(server, tracker)| {
+                    tracker.msg_sender().map(|sender| (*server, sender.clone()))
+                })
+                .collect();
+            Some(tokio::spawn(sweep_idle(
+                local_endpoint,
+                Duration::from_secs(cfg.cleanup.idle_timeout_secs_for(cfg.is_tcp)),
+                Duration::from_millis(cfg.cleanup.handshake_timeout_ms),
+                flow_tracker,
+                connection_map,
+                senders,
+            )))
+        } else {
+            None
+        };
+
+        let reaper = if cfg.cleanup.reap_after_secs > 0 {
+            Some(tokio::spawn(reap_forever(
+                local_endpoint,
+                Duration::from_secs(cfg.cleanup.reap_after_secs),
+                server_tracker_map.values().cloned().collect(),
+            )))
+        } else {
+            None
+        };
+
+        let service = Service {
+            name: cfg.name.clone(),
+            local_endpoint,
+            servers,
+            active: AtomicBool::new(false),
+            server_tracker_map,
+            tracked,
+            idle_sweep,
+            reaper,
+        };
+        service
+    }
+}
+
+impl Service {
+    pub fn tracked(&self) -> usize {
+        self.tracked.load(Ordering::Relaxed)
+    }
+
+    // close the connections of every backend whose state machine is in `state`,
+    // returns how many were closed
+    pub async fn reclaim(&self, state: TCPState) -> usize {
+        let mut reclaimed = 0;
+        for tracker in self.server_tracker_map.values() {
+            reclaimed += tracker.reclaim(state).await;
+        }
+        reclaimed
+    }
+
+    pub async fn flush(&self) -> usize {
+        let mut flushed = 0;
+        for tracker in self.server_tracker_map.values() {
+            flushed += tracker.flush().await;
+        }
+        flushed
+    }
+
+    pub async fn sweep_stuck(&self, states: &[TCPState], min_age: Duration) -> usize {
+        let mut swept = 0;
+        for tracker in self.server_tracker_map.values() {
+            swept += tracker.sweep_stuck(states, min_age).await;
+        }
+        swept
+    }
+
+    pub async fn client_states(&self) -> Vec<(Endpoint, TCPState)> {
+        let mut clients = vec![];
+        for tracker in self.server_tracker_map.values() {
+            clients.extend(tracker.client_states().await);
+        }
+        clients
+    }
+
+    pub async fn connections(&self) -> Vec<ConnectionRow> {
+        let mut rows = vec![];
+        for (server, tracker) in self.server_tracker_map.iter() {
+            rows.extend(tracker.connections(*server).await);
+        }
+        rows
+    }
+
+    pub async fn nat_flows(&self) -> Vec<NatFlow> {
+        let mut flows = vec![];
+        for tracker in self.server_tracker_map.values() {
+            flows.extend(tracker.nat_flows().await);
+        }
+        flows
+    }
+
+    // close a connection whose nat entries the kernel evicted, through the
+    // tracker of its backend
+    pub async fn evicted(&self, client_way: UConnection, backend_way: UConnection) {
+        let tracker = self.server_tracker_map.get(&backend_way.from_endpoint());
+        if let Some(sender) = tracker.and_then(|tracker| tracker.msg_sender()) {
+            let _ = sender
+                .send(CloseMsg::evicted(client_way, backend_way))
+                .await;
+        }
+    }
+
+    // close the connection a shed syn opened, the state machine will not
+    // see it and nothing else closes it under the fsm strategy
+    pub async fn shed(&self, client_way: UConnection, backend_way: UConnection) {
+        let tracker = self.server_tracker_map.get(&backend_way.from_endpoint());
+        if let Some(sender) = tracker.and_then(|tracker| tracker.msg_sender()) {
+            let _ = sender.send(CloseMsg::shed(client_way, backend_way)).await;
+        }
+    }
+
+    pub async fn state_ages(&self) -> Vec<(TCPState, Duration)> {
+        let mut ages = vec![];
+        for tracker in self.server_tracker_map.values() {
+            ages.extend(tracker.state_ages().await);
+        }
+        ages
+    }
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        if let Some(idle_sweep) = self.idle_sweep.take() {
+            idle_sweep.abort();
+        }
+        if let Some(reaper) = self.reaper.take() {
+            reaper.abort();
+        }
+    }
+}
+
+// the xdp program saw no answer of the backend to the syn of `client_way`
+fn never_established(connection_map: &BpfConnectionMap, client_way: &UConnection) -> bool {
+    connection_map
+        .get(client_way)
+        .is_ok_and(|nat| nat.ct.state == CT_NEW)
+}
+
+// Close the connections of a service that were idle for `idle`, through the
+// tracker of their backend. The ones whose backend never answered the syn are
+// closed after `handshake` already, 0 leaves them to `idle`.
+async fn sweep_idle(
+    local_endpoint: Endpoint,
+    idle: Duration,
+    handshake: Duration,
+    flow_tracker: FlowTracker,
+    connection_map: BpfConnectionMap,
+    senders: HashMap<Endpoint, mpsc::Sender<CloseMsg>>,
+) {
+    let handshake = if handshake.is_zero() {
+        idle
+    } else {
+        handshake.min(idle)
+    };
+    let interval = (handshake / 4).clamp(Duration::from_secs(1), Duration::from_secs(10));
+    loop {
+        sleep(interval).await;
+
+        let idle_ways = flow_tracker
+            .idle_flows(&local_endpoint, idle.as_nanos() as u64)
+            .await;
+        let idle_clients: HashSet<Endpoint> = idle_ways
+            .iter()
+            .map(|(client_way, _)| client_way.from_endpoint())
+            .collect();
+        let mut closing: Vec<(Endpoint, CloseMsg)> = idle_ways
+            .into_iter()
+            .map(|(client_way, backend_way)| {
+                (
+                    backend_way.from_endpoint(),
+                    CloseMsg::idle(client_way, backend_way),
+                )
+            })
+            .collect();
+        if handshake < idle {
+            let half_open = flow_tracker
+                .idle_flows(&local_endpoint, handshake.as_nanos() as u64)
+                .await
+                .into_iter()
+                .filter(|(client_way, _)| {
+                    !idle_clients.contains(&client_way.from_endpoint())
+                        && never_established(&connection_map, client_way)
+                })
+                .map(|(client_way, backend_way)| {
+                    (
+                        backend_way.from_endpoint(),
+                        CloseMsg::handshake_expired(client_way, backend_way),
+                    )
+                });
+            closing.extend(half_open);
+        }
+
+        // the flow knows the backend even when the nat entries were evicted
+        for (backend, msg) in closing {
+            if let Some(sender) = senders.get(&backend) {
+                let _ = sender.send(msg).await;
+            }
+        }
+    }
+}
+
+// close the tracked connections of a service whose flow was idle or gone for
+// `after`, the ones whose close the state machine missed
+async fn reap_forever(
+    local_endpoint: Endpoint,
+    after: Duration,
+    trackers: Vec<MsgWorker<ConnectionStateMgr>>,
+) {
+    let interval = (after / 4).clamp(Duration::from_secs(1), Duration::from_secs(60));
+    loop {
+        sleep(interval).await;
+
+        let mut reaped = 0;
+        for tracker in trackers.iter() {
+            reaped += tracker.reap(local_endpoint, after).await;
+        }
+        if reaped > 0 {
+            info!(
+                "reaped {} connections of {}",
+                reaped,
+                local_endpoint.to_string()
+            );
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use folonet_client::config::ShardingConfig;
+use log::info;
+use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::worker::MsgHandler;
+
+// points per shard on the ring, enough to spread keys evenly
+const VNODES: u64 = 64;
+
+fn hash_of(v: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    v.hash(&mut hasher);
+    hasher.finish()
+}
+
+struct Job<T: MsgHandler> {
+    handler: Arc<Mutex<T>>,
+    msg: T::MsgType,
+    queued_at: Instant,
+}
+
+#[derive(Default)]
+struct ShardLoad {
+    // queued and not yet handled
+    pending: AtomicUsize,
+    // queue latency of the jobs handled since the last sample
+    latency_us: AtomicU64,
+    handled: AtomicU64,
+}
+
+impl ShardLoad {
+    // mean queue latency since the last call
+    fn take_mean_latency(&self) -> Duration {
+        let latency_us = self.latency_us.swap(0, Ordering::Relaxed);
+        let handled = self.handled.swap(0, Ordering::Relaxed);
+        if handled == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_micros(latency_us / handled)
+    }
+}
+
+struct Shard<T: MsgHandler> {
+    id: u64,
+    sender: mpsc::Sender<Job<T>>,
+    load: Arc<ShardLoad>,
+    task: JoinHandle<()>,
+}
+
+impl<T: MsgHandler> Shard<T> {
+    fn spawn(id: u64, queue: usize) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<Job<T>>(queue);
+        let load = Arc::new(ShardLoad::default());
+        let task_load = load.clone();
+        let task = tokio::spawn(async move {
+            while let Some(job) = receiver.recv().await {
+                task_load.latency_us.fetch_add(
+                    job.queued_at.elapsed().as_micros() as u64,
+                    Ordering::Relaxed,
+                );
+                task_load.handled.fetch_add(1, Ordering::Relaxed);
+                job.handler.lock().await.handle_message(job.msg).await;
+                task_load.pending.fetch_sub(1, Ordering::Relaxed);
+            }
+        });
+        Shard {
+            id,
+            sender,
+            load,
+            task,
+        }
+    }
+}
+
+// consistent hashing of keys to shard ids
+#[derive(Default)]
+struct Ring {
+    points: Vec<(u64, u64)>,
+}
+
+impl Ring {
+    fn add(&mut self, shard_id: u64) {
+        for vnode in 0..VNODES {
+            self.points.push((hash_of(&(shard_id, vnode)), shard_id));
+        }
+        self.points.sort_unstable();
+    }
+
+    fn remove(&mut self, shard_id: u64) {
+        self.points.retain(|(_, id)| *id != shard_id);
+    }
+
+    fn shard_for(&self, key: u64) -> Option<u64> {
+        let idx = self.points.partition_point(|(point, _)| *point < key);
+        self.points
+            .get(idx)
+            .or_else(|| self.points.first())
+            .map(|(_, id)| *id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resize {
+    Split,
+    Merge,
+    Keep,
+}
+
+fn decide(slowest: Duration, shards: usize, cfg: &ShardingConfig) -> Resize {
+    if slowest > Duration::from_millis(cfg.split_above_ms) && shards < cfg.max_shards {
+        Resize::Split
+    } else if slowest < Duration::from_millis(cfg.merge_below_ms) && shards > cfg.min_shards {
+        Resize::Merge
+    } else {
+        Resize::Keep
+    }
+}
+
+struct ShardSet<T: MsgHandler> {
+    shards: Vec<Shard<T>>,
+    ring: Ring,
+    next_id: u64,
+    // jobs a shard holds before its senders wait
+    queue: usize,
+}
+
+impl<T: MsgHandler> ShardSet<T> {
+    fn split(&mut self) {
+        let shard = Shard::spawn(self.next_id, self.queue);
+        self.next_id += 1;
+        self.ring.add(shard.id);
+        self.shards.push(shard);
+    }
+
+    // the newest shard goes, its keys fall to their neighbours on the ring
+    fn merge(&mut self) {
+        if let Some(shard) = self.shards.pop() {
+            self.ring.remove(shard.id);
+        }
+    }
+
+    fn shard_for(&self, key: u64) -> &Shard<T> {
+        let id = self.ring.shard_for(key).unwrap_or_default();
+        self.shards
+            .iter()
+            .find(|shard| shard.id == id)
+            .unwrap_or(&self.shards[0])
+    }
+
+    fn pending(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.load.pending.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+impl<T: MsgHandler> Drop for ShardSet<T> {
+    fn drop(&mut self) {
+        self.shards.iter().for_each(|shard| shard.task.abort());
+    }
+}
+
+// A pool of tasks running the handlers of many keys, e.g. connections. Every
+// key sticks to one shard so its messages are handled in order, and the pool
+// grows and shrinks with the queue latency. Cheap to clone, every clone
+// dispatches to the same shards.
+pub struct Shards<T: MsgHandler> {
+    set: Arc<RwLock<ShardSet<T>>>,
+    cfg: ShardingConfig,
+}
+
+impl<T: MsgHandler> Clone for Shards<T> {
+    fn clone(&self) -> Self {
+        Shards {
+            set: self.set.clone(),
+            cfg: self.cfg.clone(),
+        }
+    }
+}
+
+impl<T: MsgHandler> Shards<T> {
+    pub fn new(cfg: ShardingConfig) -> Self {
+        let mut set = ShardSet {
+            shards: vec![],
+            ring: Ring::default(),
+            next_id: 0,
+            queue: cfg.queue.max(1),
+        };
+        for _ in 0..cfg.min_shards.max(1) {
+            set.split();
+        }
+        Shards {
+            set: Arc::new(RwLock::new(set)),
+            cfg,
+        }
+    }
+
+    pub fn handle(&self, key: &impl Hash, handler: T) -> Sharded<T> {
+        Sharded {
+            handler: Arc::new(Mutex::new(handler)),
+            key: hash_of(key),
+            shards: self.clone(),
+        }
+    }
+
+    async fn dispatch(&self, key: u64, handler: Arc<Mutex<T>>, msg: T::MsgType) {
+        let set = self.set.read().await;
+        let shard = set.shard_for(key);
+        shard.load.pending.fetch_add(1, Ordering::Relaxed);
+        let job = Job {
+            handler,
+            msg,
+            queued_at: Instant::now(),
+        };
+        if shard.sender.send(job).await.is_err() {
+            shard.load.pending.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    pub async fn autoscale_forever(self) {
+        let interval = Duration::from_secs(self.cfg.interval_secs);
+        loop {
+            sleep(interval).await;
+
+            let (slowest, shards) = {
+                let set = self.set.read().await;
+                let slowest = set
+                    .shards
+                    .iter()
+                    .map(|shard| shard.load.take_mean_latency())
+                    .max()
+                    .unwrap_or_default();
+                (slowest, set.shards.len())
+            };
+            let resize = decide(slowest, shards, &self.cfg);
+            if resize == Resize::Keep {
+                continue;
+            }
+
+            // no new message gets in while the old queues drain, so no key
+            // sees its messages handled out of order on two shards
+            let mut set = self.set.write().await;
+            while set.pending() > 0 {
+                sleep(Duration::from_millis(1)).await;
+            }
+            match resize {
+                Resize::Split => set.split(),
+                Resize::Merge => set.merge(),
+                Resize::Keep => {}
+            }
+            info!(
+                "{:?} shards, queue latency {:?}, now {} shards",
+                resize,
+                slowest,
+                set.shards.len()
+            );
+        }
+    }
+}
+
+// the handler of one key, run by the shard the key hashes to
+pub struct Sharded<T: MsgHandler> {
+    pub handler: Arc<Mutex<T>>,
+    key: u64,
+    shards: Shards<T>,
+}
+
+impl<T: MsgHandler> Sharded<T> {
+    pub async fn send(&self, msg: T::MsgType) {
+        self.shards
+            .dispatch(self.key, self.handler.clone(), msg)
+            .await
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_ring_moves_few_keys() {
+        use super::Ring;
+
+        let mut ring = Ring::default();
+        (0..4).for_each(|id| ring.add(id));
+        let keys: Vec<u64> = (0..1000u64).map(|k| super::hash_of(&k)).collect();
+        let before: Vec<u64> = keys.iter().map(|k| ring.shard_for(*k).unwrap()).collect();
+
+        ring.add(4);
+        let moved = keys
+            .iter()
+            .zip(before.iter())
+            .filter(|(k, id)| ring.shard_for(**k).unwrap() != **id)
+            .count();
+        // only the keys landing on the new shard move, about a fifth of them
+        assert!(moved > 0 && moved < 400, "{} keys moved", moved);
+        assert!(keys.iter().zip(before.iter()).all(|(k, id)| {
+            ring.shard_for(*k).unwrap() == 4 || ring.shard_for(*k).unwrap() == *id
+        }));
+
+        ring.remove(4);
+        assert!(keys
+            .iter()
+            .zip(before.iter())
+            .all(|(k, id)| ring.shard_for(*k).unwrap() == *id));
+    }
+
+    #[test]
+    fn test_decide() {
+        use folonet_client::config::ShardingConfig;
+        use tokio::time::Duration;
+
+        use super::{decide, Resize};
+
+        let cfg = ShardingConfig {
+            min_shards: 2,
+            max_shards: 4,
+            split_above_ms: 5,
+            merge_below_ms: 1,
+            interval_secs: 5,
+            ..Default::default()
+        };
+        assert_eq!(decide(Duration::from_millis(10), 2, &cfg), Resize::Split);
+        assert_eq!(decide(Duration::from_millis(10), 4, &cfg), Resize::Keep);
+        assert_eq!(decide(Duration::from_millis(3), 3, &cfg), Resize::Keep);
+        assert_eq!(decide(Duration::ZERO, 3, &cfg), Resize::Merge);
+        assert_eq!(decide(Duration::ZERO, 2, &cfg), Resize::Keep);
+    }
+}
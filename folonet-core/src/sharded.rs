@@ -0,0 +1,192 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::os::fd::AsFd;
+use std::sync::{Mutex, RwLock};
+
+use aya::maps::{HashMap as AyaHashMap, Map, MapData, MapError};
+use aya::Pod;
+
+use crate::error::{FolonetError, MapResultExt};
+
+// enough that the cores handling events rarely meet on one shard
+pub const SHARDS: usize = 16;
+
+pub(crate) fn shard_of(v: &impl Hash, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    v.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+// A map split into shards by the hash of the key, each behind its own lock.
+// A lock is only held to copy a value in or out, never across an await, so
+// values are cheap clones of handles to what they lock themselves.
+pub struct ShardedMap<K, V> {
+    shards: Vec<RwLock<HashMap<K, V>>>,
+}
+
+impl<K, V> Default for ShardedMap<K, V> {
+    fn default() -> Self {
+        ShardedMap {
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> ShardedMap<K, V> {
+    fn shard(&self, k: &K) -> &RwLock<HashMap<K, V>> {
+        &self.shards[shard_of(k, self.shards.len())]
+    }
+
+    pub fn get(&self, k: &K) -> Option<V> {
+        self.shard(k).read().unwrap().get(k).cloned()
+    }
+
+    pub fn contains_key(&self, k: &K) -> bool {
+        self.shard(k).read().unwrap().contains_key(k)
+    }
+
+    pub fn insert(&self, k: K, v: V) -> Option<V> {
+        self.shard(&k).write().unwrap().insert(k, v)
+    }
+
+    pub fn remove(&self, k: &K) -> Option<V> {
+        self.shard(k).write().unwrap().remove(k)
+    }
+
+    // a snapshot, what changes while the caller goes through it is not in it
+    pub fn entries(&self) -> Vec<(K, V)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    pub fn values(&self) -> Vec<V> {
+        self.entries().into_iter().map(|(_, v)| v).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.read().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// the bytes of a map key, they are what the kernel hashes too
+fn key_bytes<K: Pod>(k: &K) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(k as *const K as *const u8, std::mem::size_of::<K>()) }
+}
+
+// another handle on the same kernel map, through a duplicate of its fd
+fn duplicate(name: &'static str, map: &Map) -> Result<Map, FolonetError> {
+    let (data, lru) = match map {
+        Map::HashMap(data) => (data, false),
+        Map::LruHashMap(data) => (data, true),
+        _ => {
+            return Err(FolonetError::Config(format!(
+                "map {} is not a hash map",
+                name
+            )))
+        }
+    };
+    let fd = data
+        .fd()
+        .as_fd()
+        .try_clone_to_owned()
+        .map_err(|source| FolonetError::Io {
+            context: format!("failed to duplicate the fd of map {}", name),
+            source,
+        })?;
+    let data = MapData::from_fd(fd).map_context(name)?;
+    Ok(if lru {
+        Map::LruHashMap(data)
+    } else {
+        Map::HashMap(data)
+    })
+}
+
+// A kernel hash map updated from many tasks at once. The kernel locks the
+// map per bucket, so userspace only needs a handle per shard of the keys, and
+// a handle is locked just for the syscall.
+pub struct ShardedBpfMap<K, V> {
+    handles: Vec<Mutex<AyaHashMap<MapData, K, V>>>,
+}
+
+impl<K: Pod, V: Pod> ShardedBpfMap<K, V> {
+    pub fn new(name: &'static str, map: Map) -> Result<Self, FolonetError> {
+        let mut handles = Vec::with_capacity(SHARDS);
+        for _ in 1..SHARDS {
+            let handle = AyaHashMap::try_from(duplicate(name, &map)?).map_context(name)?;
+            handles.push(Mutex::new(handle));
+        }
+        handles.push(Mutex::new(AyaHashMap::try_from(map).map_context(name)?));
+        Ok(ShardedBpfMap { handles })
+    }
+
+    fn handle(&self, k: &K) -> &Mutex<AyaHashMap<MapData, K, V>> {
+        &self.handles[shard_of(&key_bytes(k), self.handles.len())]
+    }
+
+    pub fn get(&self, k: &K) -> Result<V, MapError> {
+        self.handle(k).lock().unwrap().get(k, 0)
+    }
+
+    pub fn insert(&self, k: K, v: V) -> Result<(), MapError> {
+        self.handle(&k).lock().unwrap().insert(k, v, 0)
+    }
+
+    pub fn remove(&self, k: &K) -> Result<(), MapError> {
+        self.handle(k).lock().unwrap().remove(k)
+    }
+
+    // every entry, walked through one handle while the others stay usable
+    pub fn entries(&self) -> Vec<(K, V)> {
+        self.handles[0]
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|item| item.ok())
+            .collect()
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_sharded_map() {
+        use super::{shard_of, ShardedMap, SHARDS};
+
+        let map: ShardedMap<u32, String> = ShardedMap::default();
+        assert!(map.is_empty());
+        for i in 0..100 {
+            map.insert(i, i.to_string());
+        }
+        assert_eq!(map.len(), 100);
+        assert_eq!(map.get(&42), Some("42".to_string()));
+        assert!(map.contains_key(&7));
+        assert_eq!(map.remove(&7), Some("7".to_string()));
+        assert!(!map.contains_key(&7));
+        assert_eq!(map.get(&7), None);
+
+        let mut keys: Vec<u32> = map.entries().into_iter().map(|(k, _)| k).collect();
+        keys.sort();
+        assert_eq!(keys.len(), 99);
+        assert_eq!(keys[7], 8);
+        assert_eq!(map.values().len(), 99);
+
+        // the keys spread over the shards
+        let used: std::collections::HashSet<usize> =
+            (0..1000u32).map(|i| shard_of(&i, SHARDS)).collect();
+        assert_eq!(used.len(), SHARDS);
+    }
+}
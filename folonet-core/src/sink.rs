@@ -0,0 +1,76 @@
+use std::future::Future;
+
+use log::warn;
+use tokio::sync::mpsc;
+
+const CHANNEL_SIZE: usize = 10240;
+
+// Hands records to the one task writing them out, for the flow log, the usage
+// export and the tracer. A writer that falls behind loses records, the caller
+// never waits for it.
+pub struct Sink<T> {
+    sender: mpsc::Sender<T>,
+    // what a record is, for the log line of a dropped one
+    what: &'static str,
+}
+
+impl<T> Clone for Sink<T> {
+    fn clone(&self) -> Self {
+        Sink {
+            sender: self.sender.clone(),
+            what: self.what,
+        }
+    }
+}
+
+impl<T: Send + 'static> Sink<T> {
+    // spawns `writer` on the receiving end, it returns once every sink is gone
+    pub fn spawn<F, Fut>(what: &'static str, writer: F) -> Self
+    where
+        F: FnOnce(mpsc::Receiver<T>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel::<T>(CHANNEL_SIZE);
+        tokio::spawn(writer(receiver));
+        Sink { sender, what }
+    }
+
+    pub fn send(&self, record: T) {
+        if let Err(e) = self.sender.try_send(record) {
+            warn!("{} dropped: {}", self.what, e);
+        }
+    }
+}
+
+mod test {
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sink() {
+        use std::sync::{Arc, Mutex};
+
+        use tokio::time::{sleep, Duration};
+
+        use super::{Sink, CHANNEL_SIZE};
+
+        let written: Arc<Mutex<Vec<usize>>> = Arc::default();
+        let sink = {
+            let written = written.clone();
+            Sink::spawn("record", |mut rx| async move {
+                // a writer that is behind, the channel fills up
+                sleep(Duration::from_secs(1)).await;
+                while let Some(record) = rx.recv().await {
+                    written.lock().unwrap().push(record);
+                }
+            })
+        };
+        for record in 0..CHANNEL_SIZE + 10 {
+            sink.send(record);
+        }
+        drop(sink);
+        sleep(Duration::from_secs(2)).await;
+
+        let written = written.lock().unwrap();
+        assert_eq!(written.len(), CHANNEL_SIZE);
+        assert_eq!(written.last(), Some(&(CHANNEL_SIZE - 1)));
+    }
+}
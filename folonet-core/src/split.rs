@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use aya::maps::{HashMap as AyaHashMap, MapData};
+use folonet_client::config::ServiceConfig;
+use folonet_common::service::KServiceKey;
+use folonet_common::split::{KSplit, MAX_POOLS};
+
+use log::warn;
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::endpoint::Endpoint;
+use crate::error::{FolonetError, MapResultExt};
+
+pub type BpfSplitMap = AyaHashMap<MapData, KServiceKey, KSplit>;
+
+// the pool of the servers of a service, the rest of the new connections
+pub const PRIMARY_POOL: &str = "primary";
+
+// how often the weights of ramping backends are raised
+const RAMP_TICK: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SplitPool {
+    name: String,
+    backend: Endpoint,
+    weight: u32,
+    // since its backend came up, while it is still ramping up to its weight
+    ramping_since: Option<Instant>,
+}
+
+// the pools of a service, the primary one first, and its protocols
+#[derive(Debug, Clone)]
+struct Split {
+    protocols: Vec<bool>,
+    pools: Vec<SplitPool>,
+    slow_start: Duration,
+}
+
+impl Split {
+    fn from_cfg(cfg: &ServiceConfig) -> Result<Option<Self>, FolonetError> {
+        if cfg.pools.is_empty() {
+            return Ok(None);
+        }
+        if cfg.pools.len() >= MAX_POOLS {
+            return Err(FolonetError::Config(format!(
+                "service {} has {} pools, at most {} fit next to its servers",
+                cfg.name,
+                cfg.pools.len(),
+                MAX_POOLS - 1
+            )));
+        }
+        let first = |servers: &[String], pool: &str| {
+            servers
+                .first()
+                .ok_or_else(|| {
+                    FolonetError::Config(format!(
+                        "pool {} of service {} has no servers",
+                        pool, cfg.name
+                    ))
+                })?
+                .parse::<Endpoint>()
+        };
+        let mut pools = vec![SplitPool {
+            name: PRIMARY_POOL.to_string(),
+            backend: first(&cfg.servers, PRIMARY_POOL)?,
+            weight: 0,
+            ramping_since: None,
+        }];
+        for pool in cfg.pools.iter() {
+            if pools.iter().any(|known| known.name == pool.name) {
+                return Err(FolonetError::Config(format!(
+                    "service {} has two pools called {}",
+                    cfg.name, pool.name
+                )));
+            }
+            pools.push(SplitPool {
+                name: pool.name.clone(),
+                backend: first(&pool.servers, &pool.name)?,
+                weight: pool.weight,
+                ramping_since: None,
+            });
+        }
+        let mut split = Split {
+            protocols: cfg.served_protocols().iter().map(|p| p.is_tcp()).collect(),
+            pools,
+            slow_start: Duration::from_secs(cfg.slow_start_secs),
+        };
+        split.balance(&cfg.name)?;
+        Ok(Some(split))
+    }
+
+    // the primary pool gets what the others leave
+    fn balance(&mut self, service: &str) -> Result<(), FolonetError> {
+        let others = self.pools[1..]
+            .iter()
+            .fold(0u32, |sum, pool| sum.saturating_add(pool.weight));
+        if others > 100 {
+            return Err(FolonetError::Config(format!(
+                "the pools of service {} get {} percent of its connections",
+                service, others
+            )));
+        }
+        self.pools[0].weight = 100 - others;
+        Ok(())
+    }
+
+    // The weight of the pool `i` at `now`: a ramping one gets the share of
+    // its weight the slow start went through, at least 1 so it is tried at
+    // all. The xdp program splits by the sum of the weights, the others take
+    // up what it does not get yet. The primary pool starts from what the
+    // others get together, so while it ramps they get at most twice their
+    // share rather than about all the connections.
+    fn weight_at(&self, i: usize, now: Instant) -> u32 {
+        let pool = &self.pools[i];
+        let since = match pool.ramping_since {
+            Some(since) if !self.slow_start.is_zero() => since,
+            _ => return pool.weight,
+        };
+        let done =
+            now.saturating_duration_since(since).as_secs_f64() / self.slow_start.as_secs_f64();
+        if done >= 1.0 {
+            return pool.weight;
+        }
+        let least = match i {
+            0 => 100 - pool.weight,
+            _ => 1,
+        };
+        ((pool.weight as f64 * done).ceil() as u32)
+            .max(least)
+            .min(pool.weight)
+    }
+
+    fn k_split(&self, now: Instant) -> KSplit {
+        let mut split = KSplit::default();
+        for (i, pool) in self.pools.iter().take(MAX_POOLS).enumerate() {
+            split.backends[i] = pool.backend.to_k_endpoint();
+            split.weights[i] = self.weight_at(i, now);
+        }
+        split
+    }
+
+    // Start the ramp of the pools of `backend`, which just came up. Returns
+    // whether there was one to ramp.
+    fn ramp_up(&mut self, backend: Endpoint, now: Instant) -> bool {
+        if self.slow_start.is_zero() {
+            return false;
+        }
+        let mut found = false;
+        for pool in self.pools.iter_mut().filter(|pool| pool.backend == backend) {
+            pool.ramping_since = Some(now);
+            found = true;
+        }
+        found
+    }
+
+    // forget the ramps that are over
+    fn end_ramps(&mut self, now: Instant) {
+        let slow_start = self.slow_start;
+        for pool in self.pools.iter_mut() {
+            if pool.ramping_since.map_or(false, |since| {
+                now.saturating_duration_since(since) >= slow_start
+            }) {
+                pool.ramping_since = None;
+            }
+        }
+    }
+
+    fn weights(&self) -> String {
+        let now = Instant::now();
+        self.pools
+            .iter()
+            .enumerate()
+            .map(|(i, pool)| match self.weight_at(i, now) {
+                weight if weight < pool.weight => {
+                    format!(
+                        "{} {}% (ramping, {} so far)",
+                        pool.name, pool.weight, weight
+                    )
+                }
+                _ => format!("{} {}%", pool.name, pool.weight),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+// Splits the new connections of services with pools between their servers
+// and the backends of the pools, by a hash of the client in the xdp program.
+// Open connections stay on the backend they started on when the weights
+// change, so a rollout only moves new ones.
+#[derive(Clone)]
+pub struct Splits {
+    map: Arc<Mutex<BpfSplitMap>>,
+    splits: Arc<Mutex<HashMap<Endpoint, Split>>>,
+}
+
+impl Splits {
+    pub fn new(map: BpfSplitMap) -> Self {
+        Splits {
+            map: Arc::new(Mutex::new(map)),
+            splits: Arc::default(),
+        }
+    }
+
+    // route `service` as `cfg` splits it, all to its servers without pools
+    pub fn set_from(&self, service: Endpoint, cfg: &ServiceConfig) -> Result<(), FolonetError> {
+        let mut splits = self.splits.lock().unwrap();
+        let mut map = self.map.lock().unwrap();
+        for is_tcp in [true, false] {
+            let _ = map.remove(&service.to_service_key(is_tcp));
+        }
+        let before = splits.remove(&service);
+        let mut split = match Split::from_cfg(cfg)? {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        // a backend new to a service already split ramps up, one it had
+        // keeps where its ramp is
+        if let Some(before) = before {
+            let now = Instant::now();
+            for pool in split.pools.iter_mut() {
+                pool.ramping_since = match before.pools.iter().find(|b| b.backend == pool.backend) {
+                    Some(known) => known.ramping_since,
+                    None => Some(now),
+                };
+            }
+        }
+        write(&mut map, service, &split)?;
+        splits.insert(service, split);
+        Ok(())
+    }
+
+    // `backend` of `service` came up again, e.g. cold started, ramp it up
+    // to its share of the new connections
+    pub fn ramp_up(&self, service: Endpoint, backend: Endpoint) -> Result<(), FolonetError> {
+        let mut splits = self.splits.lock().unwrap();
+        let split = match splits.get_mut(&service) {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        if split.ramp_up(backend, Instant::now()) {
+            write(&mut self.map.lock().unwrap(), service, split)?;
+        }
+        Ok(())
+    }
+
+    // raise the weights of the ramping backends step by step
+    pub async fn ramp_forever(self) {
+        loop {
+            sleep(RAMP_TICK).await;
+            let now = Instant::now();
+            let mut splits = self.splits.lock().unwrap();
+            let mut map = self.map.lock().unwrap();
+            for (service, split) in splits.iter_mut() {
+                if split.pools.iter().all(|pool| pool.ramping_since.is_none()) {
+                    continue;
+                }
+                // the last step writes the whole weight
+                split.end_ramps(now);
+                if let Err(e) = write(&mut map, *service, split) {
+                    warn!(
+                        "failed to ramp up the backends of {}: {}",
+                        service.to_string(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    // Give `pool` of `service` `weight` percent of its new connections, its
+    // servers the rest. Returns the weights of all its pools.
+    pub fn set_weight(
+        &self,
+        service: Endpoint,
+        pool: &str,
+        weight: u32,
+    ) -> Result<String, FolonetError> {
+        let mut splits = self.splits.lock().unwrap();
+        let split = splits
+            .get_mut(&service)
+            .ok_or_else(|| FolonetError::Config(format!("{} has no pools", service.to_string())))?;
+        if pool == PRIMARY_POOL {
+            return Err(FolonetError::Config(format!(
+                "the {} pool gets what the others leave",
+                PRIMARY_POOL
+            )));
+        }
+        let mut changed = split.clone();
+        changed
+            .pools
+            .iter_mut()
+            .find(|known| known.name == pool)
+            .ok_or_else(|| {
+                FolonetError::Config(format!("{} has no pool {}", service.to_string(), pool))
+            })?
+            .weight = weight;
+        changed.balance(&service.to_string())?;
+        write(&mut self.map.lock().unwrap(), service, &changed)?;
+        *split = changed;
+        Ok(split.weights())
+    }
+}
+
+fn write(map: &mut BpfSplitMap, service: Endpoint, split: &Split) -> Result<(), FolonetError> {
+    let k_split = split.k_split(Instant::now());
+    for is_tcp in split.protocols.iter() {
+        map.insert(service.to_service_key(*is_tcp), k_split, 0)
+            .map_context("SPLIT_MAP")?;
+    }
+    Ok(())
+}
+
+mod test {
+
+    #[test]
+    fn test_split() {
+        use folonet_client::config::{PoolConfig, ServiceConfig};
+        use tokio::time::Instant;
+
+        use super::Split;
+
+        let mut cfg = ServiceConfig {
+            name: "web".to_string(),
+            local_endpoint: "10.0.0.1:80".to_string(),
+            servers: vec!["10.0.1.5:8080".to_string()],
+            is_tcp: true,
+            pools: vec![PoolConfig {
+                name: "canary".to_string(),
+                servers: vec!["10.0.1.6:8080".to_string()],
+                weight: 5,
+            }],
+            ..Default::default()
+        };
+        let mut split = Split::from_cfg(&cfg).unwrap().unwrap();
+        assert_eq!(split.weights(), "primary 95%, canary 5%");
+        assert_eq!(split.k_split(Instant::now()).weights, [95, 5, 0, 0]);
+
+        split.pools[1].weight = 101;
+        assert!(split.balance("web").is_err());
+
+        cfg.pools.push(cfg.pools[0].clone());
+        assert!(Split::from_cfg(&cfg).is_err());
+        cfg.pools.clear();
+        assert!(Split::from_cfg(&cfg).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_slow_start() {
+        use folonet_client::config::{PoolConfig, ServiceConfig};
+        use tokio::time::{Duration, Instant};
+
+        use super::Split;
+        use crate::endpoint::Endpoint;
+
+        let primary: Endpoint = "10.0.1.5:8080".parse().unwrap();
+        let canary: Endpoint = "10.0.1.6:8080".parse().unwrap();
+        let mut cfg = ServiceConfig {
+            name: "web".to_string(),
+            local_endpoint: "10.0.0.1:80".to_string(),
+            servers: vec![primary.to_string()],
+            is_tcp: true,
+            pools: vec![PoolConfig {
+                name: "canary".to_string(),
+                servers: vec![canary.to_string()],
+                weight: 20,
+            }],
+            slow_start_secs: 10,
+            ..Default::default()
+        };
+        let mut split = Split::from_cfg(&cfg).unwrap().unwrap();
+        let now = Instant::now();
+        let after = |secs: u64| now + Duration::from_secs(secs);
+        assert!(!split.ramp_up("10.0.1.7:8080".parse().unwrap(), now));
+
+        // the new backend is tried at once, and gets its share bit by bit
+        assert!(split.ramp_up(canary, now));
+        assert_eq!(split.k_split(now).weights, [80, 1, 0, 0]);
+        assert_eq!(split.k_split(after(5)).weights, [80, 10, 0, 0]);
+        assert_eq!(split.k_split(after(10)).weights, [80, 20, 0, 0]);
+        split.end_ramps(after(5));
+        assert!(split.pools[1].ramping_since.is_some());
+        split.end_ramps(after(10));
+        assert!(split.pools[1].ramping_since.is_none());
+
+        // a primary backend cold started again leaves the canary more for a
+        // while, at most twice its share
+        assert!(split.ramp_up(primary, now));
+        assert_eq!(split.k_split(now).weights, [20, 20, 0, 0]);
+        assert_eq!(split.k_split(after(5)).weights, [40, 20, 0, 0]);
+        assert_eq!(split.k_split(after(10)).weights, [80, 20, 0, 0]);
+
+        cfg.slow_start_secs = 0;
+        let mut split = Split::from_cfg(&cfg).unwrap().unwrap();
+        assert!(!split.ramp_up(canary, now));
+        assert_eq!(split.k_split(now).weights, [80, 20, 0, 0]);
+    }
+}
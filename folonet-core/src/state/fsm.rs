@@ -0,0 +1,423 @@
+use folonet_common::event::Packet;
+use log::debug;
+use rust_fsm::*;
+use tokio::time::{Duration, Instant};
+
+use crate::endpoint::{Direction, Endpoint};
+
+use super::PacketMsg;
+
+// The state machine of one side of a tcp connection, driven by the packets
+// folonet sees of the connection. It does no io and reads no clock, the time
+// of every packet is passed in, so traces of packets can be replayed against
+// it in tests.
+state_machine! {
+    derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)
+
+    pub TCP(Established)
+
+    Closed => {
+        PassiveOpen => Listen,
+        SendSyn => SynSent,
+    },
+
+    Listen => {
+        ReceiveSyn => ListenReceiveSyn,
+        Reset => Closed,
+    },
+    ListenReceiveSyn => {
+        SendSynAck => SynReceived,
+        Reset => Closed,
+    },
+
+    SynSent => {
+        ReceiveSyn => SynSentReceiveSyn,
+        ReceiveSynAck => ReceiveSynAckReceiveSynAck,
+        Reset => Closed,
+    },
+    SynSentReceiveSyn => {
+        SendAckForSyn => SynReceived,
+        Reset => Closed,
+    },
+    ReceiveSynAckReceiveSynAck => {
+        SendAckForSyn => Established,
+        Reset => Closed,
+    },
+
+    SynReceived => {
+        RecvAckForSyn => Established,
+        Reset => Closed,
+    },
+
+    Established => {
+        SendFin => FinWait1,
+        ReceiveFin => CloseWait,
+        Reset => Closed,
+    },
+
+    CloseWait => {
+        SendFin => TimeWait,
+        Reset => Closed,
+    },
+
+    LastAck => {
+        RecvAckForFin => Closed,
+        Reset => Closed,
+    },
+
+    FinWait1 => {
+        RecvAckForFin => FinWait2,
+        ReceiveFin => FinWait1ReceiveFin,
+        Reset => Closed,
+    },
+    FinWait1ReceiveFin => {
+        SendAckForFin => Closing,
+        RecvAckForFin => FinWait2ReceiveFin,
+        Reset => Closed,
+    },
+
+    FinWait2 => {
+        ReceiveFin => TimeWait,
+        Reset => Closed,
+    },
+    FinWait2ReceiveFin => {
+        SendAckForFin => TimeWait,
+        Reset => Closed,
+    },
+
+    Closing => {
+        RecvAckForFin => TimeWait,
+        Reset => Closed,
+    },
+
+    TimeWait(TimeExpired) => Closed,
+}
+
+// how far a sequence number may run ahead of the last one seen of its
+// direction, data packets are not reported so this is the largest scaled window
+pub(super) const SEQ_WINDOW: u32 = 1 << 30;
+// how far it may lag behind, for retransmissions and reordering
+pub(super) const SEQ_SLACK: u32 = 1 << 16;
+
+// The highest sequence number seen of one direction of a connection. Special
+// packets far from it are spoofed or belong to an earlier connection on the
+// same ports, and must not move the state machine.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct SeqWindow {
+    highest: Option<u32>,
+}
+
+impl SeqWindow {
+    fn accepts(&self, seq: u32) -> bool {
+        match self.highest {
+            Some(highest) => {
+                let ahead = seq.wrapping_sub(highest);
+                ahead <= SEQ_WINDOW || highest.wrapping_sub(seq) <= SEQ_SLACK
+            }
+            None => true,
+        }
+    }
+
+    fn observe(&mut self, seq: u32) {
+        match self.highest {
+            Some(highest) if seq.wrapping_sub(highest) > SEQ_WINDOW => {}
+            _ => self.highest = Some(seq),
+        }
+    }
+
+    // a syn starts a new sequence space
+    fn reset(&mut self, isn: u32) {
+        self.highest = Some(isn);
+    }
+
+    // the sequence number expected next, past the syn when that was the last
+    // packet seen
+    fn next(&self, last_special: &Option<SpecialPacket>) -> Option<u32> {
+        let highest = self.highest?;
+        match last_special {
+            Some(SpecialPacket::SYN(isn)) if *isn == highest => Some(highest.wrapping_add(1)),
+            _ => Some(highest),
+        }
+    }
+}
+
+// the ack finishing a handshake may not be reported, a later packet acking
+// data past the syn finishes it as well
+fn acks_syn(syn_seq: u32, ack_seq: u32) -> bool {
+    ack_seq.wrapping_sub(syn_seq.wrapping_add(1)) <= SEQ_WINDOW
+}
+
+pub enum SpecialPacket {
+    SYN(u32),
+    FIN(u32),
+}
+pub struct TcpFsmState {
+    e: Endpoint,
+    fsm: StateMachine<TCP>,
+    received_special_packet: Option<SpecialPacket>,
+    sent_special_packet: Option<SpecialPacket>,
+    // sequence numbers sent by and sent to `e`
+    sent_seq: SeqWindow,
+    received_seq: SeqWindow,
+    // when the fsm last changed its state
+    changed_at: Instant,
+}
+
+impl TcpFsmState {
+    pub fn new(e: &Endpoint, opening: bool, passive: bool, now: Instant) -> Self {
+        let mut fsm = if opening {
+            StateMachine::<TCP>::from_state(TCPState::Closed)
+        } else {
+            StateMachine::<TCP>::new()
+        };
+        if opening && passive {
+            let _ = fsm.consume(&TCPInput::PassiveOpen);
+        }
+        TcpFsmState {
+            e: *e,
+            fsm,
+            received_special_packet: None,
+            sent_special_packet: None,
+            sent_seq: SeqWindow::default(),
+            received_seq: SeqWindow::default(),
+            changed_at: now,
+        }
+    }
+
+    pub fn endpoint(&self) -> Endpoint {
+        self.e
+    }
+
+    pub fn state(&self) -> TCPState {
+        *self.fsm.state()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.fsm.state() == &TCPState::Closed
+    }
+
+    pub fn state_age(&self, now: Instant) -> (TCPState, Duration) {
+        (
+            *self.fsm.state(),
+            now.saturating_duration_since(self.changed_at),
+        )
+    }
+
+    // move the state machine by one reported packet of the connection, seen
+    // at `now`
+    pub fn handle_packet(&mut self, msg: &PacketMsg, now: Instant) {
+        let packet = match msg.packet {
+            Some(p) => p,
+            _ => return,
+        };
+
+        let direction = msg.direction(&self.e);
+        let state_before = *self.fsm.state();
+
+        if !self.in_window(&packet, &direction) {
+            debug!(
+                "{} ignores out of window packet: {:?}, direction: {:?}",
+                self.e.to_string(),
+                packet,
+                direction
+            );
+            return;
+        }
+        self.track_seq(&packet, &direction);
+
+        for input in self.check_input(&packet, &direction) {
+            let _ = self.fsm.consume(&input);
+        }
+
+        // last, we reord the special packet
+        let special_packet = if packet.is_fin() {
+            Some(SpecialPacket::FIN(packet.seq))
+        } else if packet.is_syn() {
+            Some(SpecialPacket::SYN(packet.seq))
+        } else {
+            None
+        };
+
+        if let Some(special_packet) = special_packet {
+            match direction {
+                Direction::From => {
+                    self.sent_special_packet.replace(special_packet);
+                }
+                Direction::To => {
+                    self.received_special_packet.replace(special_packet);
+                }
+            }
+        }
+
+        if self.fsm.state() == &TCPState::TimeWait && state_before != TCPState::TimeWait {
+            debug!("{} into time wait.", self.e.to_string());
+        }
+
+        if self.fsm.state() == &TCPState::Closed {
+            debug!("{} closed.", self.e.to_string());
+        }
+
+        if self.fsm.state() != &state_before {
+            self.changed_at = now;
+        }
+    }
+
+    // end the TIME_WAIT of `e`, if it is in it
+    pub fn expire_time_wait(&mut self, now: Instant) {
+        if self.fsm.state() != &TCPState::TimeWait {
+            return;
+        }
+        let _ = self.fsm.consume(&TCPInput::TimeExpired);
+        debug!("{} closed.", self.e.to_string());
+        self.changed_at = now;
+    }
+
+    // the seq `e` sends next and the one it expects next, from the packets
+    // seen so far
+    pub fn next_seqs(&self) -> Option<(u32, u32)> {
+        Some((
+            self.sent_seq.next(&self.sent_special_packet)?,
+            self.received_seq.next(&self.received_special_packet)?,
+        ))
+    }
+
+    // the seq of `packet` and, if it acks, its ack_seq are near what was seen
+    // of their direction before
+    fn in_window(&self, packet: &Packet, direction: &Direction) -> bool {
+        let (own, peer) = match direction {
+            Direction::From => (&self.sent_seq, &self.received_seq),
+            Direction::To => (&self.received_seq, &self.sent_seq),
+        };
+        (packet.is_syn() || own.accepts(packet.seq))
+            && (!packet.is_ack() || peer.accepts(packet.ack_seq))
+    }
+
+    fn track_seq(&mut self, packet: &Packet, direction: &Direction) {
+        let (own, peer) = match direction {
+            Direction::From => (&mut self.sent_seq, &mut self.received_seq),
+            Direction::To => (&mut self.received_seq, &mut self.sent_seq),
+        };
+        if packet.is_syn() {
+            own.reset(packet.seq);
+        } else {
+            own.observe(packet.seq);
+        }
+        if packet.is_ack() {
+            peer.observe(packet.ack_seq);
+        }
+    }
+
+    #[inline(always)]
+    fn check_input(&self, packet: &Packet, direction: &Direction) -> Vec<TCPInput> {
+        match direction {
+            Direction::From => self.check_send_input(packet),
+            Direction::To => self.check_receive_input(packet),
+        }
+    }
+
+    #[inline(always)]
+    fn check_receive_input(&self, packet: &Packet) -> Vec<TCPInput> {
+        let mut inputs = vec![];
+
+        if packet.is_ack() {
+            match self.sent_special_packet {
+                Some(SpecialPacket::FIN(seq)) => {
+                    if seq.wrapping_add(1) == packet.ack_seq {
+                        inputs.push(TCPInput::RecvAckForFin);
+                    }
+                }
+                Some(SpecialPacket::SYN(seq)) => {
+                    if acks_syn(seq, packet.ack_seq) {
+                        if packet.is_syn() {
+                            inputs.push(TCPInput::ReceiveSynAck);
+                        } else {
+                            inputs.push(TCPInput::RecvAckForSyn);
+                        }
+                    }
+                }
+                None => {}
+            }
+        }
+
+        if packet.is_fin() {
+            inputs.push(TCPInput::ReceiveFin);
+        }
+
+        if packet.is_syn() {
+            inputs.push(TCPInput::ReceiveSyn);
+        }
+
+        if packet.is_rst() {
+            inputs.push(TCPInput::Reset);
+        }
+
+        inputs
+    }
+
+    #[inline(always)]
+    fn check_send_input(&self, packet: &Packet) -> Vec<TCPInput> {
+        let mut inputs = vec![];
+
+        if packet.is_ack() {
+            match self.received_special_packet {
+                Some(SpecialPacket::FIN(seq)) => {
+                    if seq.wrapping_add(1) == packet.ack_seq {
+                        inputs.push(TCPInput::SendAckForFin);
+                    }
+                }
+                Some(SpecialPacket::SYN(seq)) => {
+                    if acks_syn(seq, packet.ack_seq) {
+                        inputs.push(TCPInput::SendAckForSyn);
+                    }
+                }
+                None => {}
+            }
+        }
+
+        if packet.is_syn() {
+            if packet.is_ack() {
+                inputs.push(TCPInput::SendSynAck);
+            } else {
+                inputs.push(TCPInput::SendSyn);
+            }
+        }
+
+        if packet.is_fin() {
+            inputs.push(TCPInput::SendFin);
+        }
+
+        if packet.is_rst() {
+            inputs.push(TCPInput::Reset);
+        }
+
+        inputs
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_seq_window() {
+        use super::{SeqWindow, SEQ_SLACK, SEQ_WINDOW};
+
+        let mut window = SeqWindow::default();
+        assert!(window.accepts(12345));
+
+        window.observe(u32::MAX - 10);
+        // ahead, across the wrap
+        assert!(window.accepts(100));
+        assert!(window.accepts(SEQ_WINDOW - 20));
+        // a retransmission
+        assert!(window.accepts(u32::MAX - 1000));
+        // too far either way
+        assert!(!window.accepts(u32::MAX - 10 - SEQ_SLACK - 1));
+        assert!(!window.accepts((u32::MAX - 10).wrapping_add(SEQ_WINDOW + 1)));
+
+        // an older seq does not move the window back
+        window.observe(200);
+        window.observe(150);
+        assert_eq!(window.highest, Some(200));
+        window.reset(7);
+        assert_eq!(window.highest, Some(7));
+    }
+}
@@ -0,0 +1,868 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use enum_dispatch::enum_dispatch;
+use folonet_client::config::CleanupConfig;
+use folonet_common::{event::Packet, nat::KNat};
+use log::info;
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+
+use crate::{
+    admin::ct_name,
+    endpoint::{Connection, Direction, Endpoint, UConnection},
+    fin_sweep::FinTarget,
+    flow_log::{CloseReason, FlowTracker},
+    message::{Message, MessageType, PacketMsgType},
+    output::{ConnectionRow, NatFlow, Protocol},
+    ports::PortPool,
+    scaler::Scaler,
+    shard::Shards,
+    sharded::ShardedBpfMap,
+    timer::{TimerKey, Timers},
+    trace::ConnSpan,
+    worker::{MsgHandler, MsgWorker},
+};
+
+use self::{
+    tcp::{TcpConnState, TcpTimeouts},
+    udp::UdpConnState,
+};
+
+pub mod fsm;
+#[cfg(test)]
+mod sim;
+pub mod tcp;
+pub mod udp;
+
+#[enum_dispatch]
+pub trait PacketHandler: Send + Sync + 'static {
+    async fn handle_packet(&mut self, packet: PacketMsg);
+}
+
+#[enum_dispatch(PacketHandler)]
+enum L4ConnState {
+    TcpConnState,
+    UdpConnState,
+}
+
+pub type BpfConnectionMap = Arc<ShardedBpfMap<UConnection, KNat>>;
+
+// everything tracked of one connection, so a packet updates it in one go
+struct TrackedConn {
+    state: L4ConnState,
+    // taken from the pool for the connection, kept from its first packet
+    local_port: u16,
+    // the client -> service and backend -> local ways of its nat entries
+    ways: (UConnection, UConnection),
+    since: Instant,
+    // from its syn, when the connections are traced
+    span: Option<ConnSpan>,
+}
+
+/// Tracks the state of every connection towards one backend and releases its
+/// nat entries and local port once the connection is closed.
+pub struct ConnectionStateMgr {
+    is_tcp: bool,
+    is_active: AtomicBool,
+    conns: HashMap<Connection, TrackedConn>,
+
+    service: String,
+    bpf_conn_map: BpfConnectionMap, // reference the bpf map
+    port_pool: PortPool,
+    flow_tracker: FlowTracker,
+    scaler: Scaler,
+    cleanup: CleanupConfig,
+    // run the tcp state machines
+    shards: Shards<tcp::ConnectionState>,
+    timers: Timers,
+    // of its own worker, to arm the timers that start over
+    close_sender: Option<mpsc::WeakSender<CloseMsg>>,
+    // by the trackers of every backend of the service
+    tracked: Arc<AtomicUsize>,
+}
+
+impl ConnectionStateMgr {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        is_tcp: bool,
+        service: String,
+        bpf_conn_map: BpfConnectionMap,
+        port_pool: PortPool,
+        flow_tracker: FlowTracker,
+        scaler: Scaler,
+        cleanup: CleanupConfig,
+        shards: Shards<tcp::ConnectionState>,
+        timers: Timers,
+        tracked: Arc<AtomicUsize>,
+    ) -> Self {
+        ConnectionStateMgr {
+            is_tcp,
+            is_active: AtomicBool::new(false),
+            conns: HashMap::new(),
+            service,
+            bpf_conn_map,
+            port_pool,
+            flow_tracker,
+            scaler,
+            cleanup,
+            shards,
+            timers,
+            close_sender: None,
+            tracked,
+        }
+    }
+
+    // the connection tracked the longest makes room for a new one, unless
+    // the service is below its limit
+    fn make_room(&self, sender: Option<&tokio::sync::mpsc::Sender<CloseMsg>>) {
+        let max = self.cleanup.max_tracked;
+        if max == 0 || self.tracked.load(Ordering::Relaxed) < max {
+            return;
+        }
+        let oldest = self.conns.iter().min_by_key(|(_, tracked)| tracked.since);
+        if let (Some((conn, oldest)), Some(sender)) = (oldest, sender) {
+            // a full queue closes it with the next new connection
+            let _ = sender.try_send(CloseMsg::reaped(conn, oldest.ways));
+        }
+    }
+
+    fn tcp_timeouts(&self) -> TcpTimeouts {
+        TcpTimeouts {
+            timers: self.timers.clone(),
+            time_wait: Duration::from_millis(self.cleanup.time_wait_ms),
+            half_open: Duration::from_millis(self.cleanup.handshake_timeout_ms),
+        }
+    }
+
+    // look at the udp flow of `ways` again `after` from now, it is closed
+    // then if it went without a packet for the idle timeout
+    fn arm_idle(&self, ways: (UConnection, UConnection), after: Duration) {
+        let sender = match self
+            .close_sender
+            .as_ref()
+            .and_then(|sender| sender.upgrade())
+        {
+            Some(sender) => sender,
+            None => return,
+        };
+        let msg = CloseMsg::idle_over(ways.0, ways.1);
+        self.timers
+            .arm(TimerKey::Idle(msg.connection()), after, sender, msg);
+    }
+}
+
+impl MsgWorker<ConnectionStateMgr> {
+    pub async fn handle_packet_msg(&mut self, msg: Message) {
+        let packet_msg = PacketMsg::try_from(&msg);
+        if packet_msg.is_err() {
+            return;
+        }
+        let packet_msg = packet_msg.unwrap();
+        let conn = packet_msg.connection();
+        let ways = msg.to_u_connections();
+
+        let mut conn_mgr = self.handler.lock().await;
+        if conn_mgr.close_sender.is_none() {
+            conn_mgr.close_sender = self.msg_sender().map(|sender| sender.downgrade());
+        }
+        // udp has no close, a flow ends once idle for long enough and every
+        // packet reported of it starts the wait over
+        if !conn_mgr.is_tcp {
+            let idle = Duration::from_secs(conn_mgr.cleanup.udp_idle_timeout_secs);
+            conn_mgr.arm_idle(ways, idle);
+        }
+        if !conn_mgr.cleanup.strategy.uses_fsm() {
+            // the idle sweep or timers of the service clean these up
+            return;
+        }
+        let is_tcp = conn_mgr.is_tcp;
+        let shards = conn_mgr.shards.clone();
+        let timeouts = conn_mgr.tcp_timeouts();
+        // for the span of a new connection, when they are traced
+        let mut traced_service = None;
+        if !conn_mgr.conns.contains_key(&conn) {
+            conn_mgr.make_room(self.msg_sender());
+            conn_mgr.tracked.fetch_add(1, Ordering::Relaxed);
+            if conn_mgr.flow_tracker.tracer().is_some() {
+                traced_service = Some(conn_mgr.service.clone());
+            }
+        }
+
+        let tracked = conn_mgr.conns.entry(conn).or_insert_with(|| {
+            let mut span = None;
+            let state = if is_tcp {
+                let opening = packet_msg.packet.is_some_and(|p| p.is_syn() && !p.is_ack());
+                let mut conn_state = tcp::ConnectionState::new(
+                    &packet_msg.from,
+                    &packet_msg.to,
+                    opening,
+                    Instant::now(),
+                );
+                if let Some(sender) = self.msg_sender() {
+                    conn_state.set_close_event_sender(sender.clone());
+                    if opening && !timeouts.half_open.is_zero() {
+                        timeouts.timers.arm(
+                            TimerKey::HalfOpen(conn),
+                            timeouts.half_open,
+                            sender.clone(),
+                            CloseMsg::half_open(packet_msg.from, packet_msg.to),
+                        );
+                    }
+                }
+                conn_state.set_timeouts(timeouts);
+                // a connection found later has no syn to start its span
+                if let (Some(service), true) = (&traced_service, opening) {
+                    let conn_span = ConnSpan::start(
+                        service,
+                        packet_msg.from,
+                        packet_msg.to,
+                        packet_msg.local_out_port,
+                    );
+                    conn_state.set_span(conn_span.clone());
+                    span = Some(conn_span);
+                }
+                L4ConnState::from(shards.handle(&conn, conn_state))
+            } else {
+                L4ConnState::from(UdpConnState::new())
+            };
+            TrackedConn {
+                state,
+                local_port: packet_msg.local_out_port,
+                ways,
+                since: Instant::now(),
+                span,
+            }
+        });
+        tracked.ways = ways;
+        tracked.state.handle_packet(packet_msg).await;
+    }
+}
+
+impl MsgWorker<ConnectionStateMgr> {
+    // close the tracked tcp connections in `state` so their port goes back to
+    // the pool, returns how many were closed
+    pub async fn reclaim(&self, state: tcp::TCPState) -> usize {
+        self.close_where(|conn_state| conn_state.in_state(state), CloseMsg::reclaimed)
+            .await
+    }
+
+    // close every tracked tcp connection, returns how many were closed
+    pub async fn flush(&self) -> usize {
+        self.close_where(|_| true, CloseMsg::reclaimed).await
+    }
+
+    // close the tracked tcp connections with a side in one of `states` for at
+    // least `min_age`, returns how many were closed
+    pub async fn sweep_stuck(&self, states: &[tcp::TCPState], min_age: Duration) -> usize {
+        let now = Instant::now();
+        self.close_where(
+            |conn_state| conn_state.stuck_in(states, min_age, now),
+            CloseMsg::stuck,
+        )
+        .await
+    }
+
+    // the state of both sides of every tracked tcp connection, with how long
+    // they have been in it
+    pub async fn state_ages(&self) -> Vec<(tcp::TCPState, Duration)> {
+        let now = Instant::now();
+        let conn_mgr = self.handler.lock().await;
+        let mut ages = vec![];
+        for tracked in conn_mgr.conns.values() {
+            if let L4ConnState::TcpConnState(tcp_state) = &tracked.state {
+                ages.extend(tcp_state.handler.lock().await.sides(now));
+            }
+        }
+        ages
+    }
+
+    // the client endpoint and client side state of every tracked tcp connection
+    pub async fn client_states(&self) -> Vec<(Endpoint, tcp::TCPState)> {
+        let conn_mgr = self.handler.lock().await;
+        let mut clients = vec![];
+        for tracked in conn_mgr.conns.values() {
+            if let L4ConnState::TcpConnState(tcp_state) = &tracked.state {
+                clients.push(tcp_state.handler.lock().await.client());
+            }
+        }
+        clients
+    }
+
+    // the tcp connections of this backend idle for at least `min_idle_ns`,
+    // with what a fin to their client carries
+    pub async fn idle_fin_targets(&self, service: Endpoint, min_idle_ns: u64) -> Vec<FinTarget> {
+        let conn_mgr = self.handler.lock().await;
+        let idle: HashSet<Endpoint> = conn_mgr
+            .flow_tracker
+            .idle_flows(&service, min_idle_ns)
+            .await
+            .iter()
+            .map(|(way, _)| way.from_endpoint())
+            .collect();
+        let mut targets = vec![];
+        for tracked in conn_mgr.conns.values() {
+            if let L4ConnState::TcpConnState(tcp_state) = &tracked.state {
+                let state = tcp_state.handler.lock().await;
+                let (client, _) = state.client();
+                if !idle.contains(&client) {
+                    continue;
+                }
+                if let Some((seq, ack_seq)) = state.fin_to_client() {
+                    targets.push(FinTarget {
+                        service,
+                        client,
+                        seq,
+                        ack_seq,
+                    });
+                }
+            }
+        }
+        targets
+    }
+
+    // every tracked connection towards `backend`, as inspection reports it
+    pub async fn connections(&self, backend: Endpoint) -> Vec<ConnectionRow> {
+        let conn_mgr = self.handler.lock().await;
+        let mut rows = vec![];
+        for (conn, tracked) in conn_mgr.conns.iter() {
+            let (client, protocol, state) = match &tracked.state {
+                L4ConnState::TcpConnState(tcp_state) => {
+                    let (client, state) = tcp_state.handler.lock().await.client();
+                    (client, Protocol::Tcp, Some(format!("{:?}", state)))
+                }
+                // the first packet of a udp flow comes from the client
+                L4ConnState::UdpConnState(_) => (conn.from, Protocol::Udp, None),
+            };
+            rows.push(ConnectionRow {
+                service: conn_mgr.service.clone(),
+                protocol,
+                client: client.to_string(),
+                backend: backend.to_string(),
+                local_port: Some(tracked.local_port),
+                state,
+            });
+        }
+        rows
+    }
+
+    // the nat entries of every tracked connection towards this backend, with
+    // how long until they are let go without another packet
+    pub async fn nat_flows(&self) -> Vec<NatFlow> {
+        let conn_mgr = self.handler.lock().await;
+        let cleanup = conn_mgr.cleanup;
+        let now = Instant::now();
+        let mut flows = vec![];
+        for tracked in conn_mgr.conns.values() {
+            let (client_way, backend_way) = tracked.ways;
+            let idle = conn_mgr.flow_tracker.idle_for(&client_way).await;
+            let idle_left = |limit_secs: u64| {
+                idle.map(|idle| Duration::from_secs(limit_secs).saturating_sub(idle))
+            };
+            let (protocol, state, left) = match &tracked.state {
+                L4ConnState::TcpConnState(tcp_state) => {
+                    let tcp = tcp_state.handler.lock().await;
+                    let time_wait = tcp
+                        .sides(now)
+                        .into_iter()
+                        .find(|(state, _)| *state == tcp::TCPState::TimeWait);
+                    let left = if tcp.half_open() && cleanup.handshake_timeout_ms > 0 {
+                        let handshake = Duration::from_millis(cleanup.handshake_timeout_ms);
+                        Some(handshake.saturating_sub(now.saturating_duration_since(tracked.since)))
+                    } else if let Some((_, age)) = time_wait {
+                        Some(Duration::from_millis(cleanup.time_wait_ms).saturating_sub(age))
+                    } else if cleanup.strategy.uses_idle_timeout() {
+                        idle_left(cleanup.idle_timeout_secs)
+                    } else {
+                        None
+                    };
+                    (Protocol::Tcp, Some(format!("{:?}", tcp.client().1)), left)
+                }
+                L4ConnState::UdpConnState(_) => (
+                    Protocol::Udp,
+                    None,
+                    idle_left(cleanup.udp_idle_timeout_secs),
+                ),
+            };
+            flows.push(NatFlow {
+                service: conn_mgr.service.clone(),
+                protocol,
+                state,
+                kernel_state: conn_mgr
+                    .bpf_conn_map
+                    .get(&client_way)
+                    .ok()
+                    .map(|nat| ct_name(nat.ct.state).to_string()),
+                client: client_way.from_endpoint(),
+                local_in: client_way.to_endpoint(),
+                backend: backend_way.from_endpoint(),
+                local_out: backend_way.to_endpoint(),
+                timeout_secs: left.map(|left| left.as_secs()),
+                age_secs: now.saturating_duration_since(tracked.since).as_secs(),
+            });
+        }
+        flows
+    }
+
+    // the connections the state machines track, none when the idle sweep
+    // cleans up the connections of this backend
+    pub async fn tracked(&self) -> Option<HashSet<Connection>> {
+        let conn_mgr = self.handler.lock().await;
+        if !conn_mgr.cleanup.strategy.uses_fsm() {
+            return None;
+        }
+        Some(conn_mgr.conns.keys().copied().collect())
+    }
+
+    // close connections found inconsistent with the kernel: nat entries
+    // without a state machine, given as client and backend ways, and state
+    // machines without nat entries
+    pub async fn repair(
+        &self,
+        untracked: &[(UConnection, UConnection)],
+        unmapped: &[Connection],
+    ) -> usize {
+        let sender = match self.msg_sender() {
+            Some(sender) => sender,
+            None => return 0,
+        };
+        for (client_way, backend_way) in untracked.iter() {
+            let _ = sender
+                .send(CloseMsg::untracked(*client_way, *backend_way))
+                .await;
+        }
+        for conn in unmapped.iter() {
+            let _ = sender.send(CloseMsg::unmapped(conn.from, conn.to)).await;
+        }
+        untracked.len() + unmapped.len()
+    }
+
+    // close the tracked connections older than `after` whose flow was idle
+    // for as long or is gone, returns how many were closed
+    pub async fn reap(&self, service: Endpoint, after: Duration) -> usize {
+        let targets: Vec<CloseMsg> = {
+            let conn_mgr = self.handler.lock().await;
+            let idle: HashSet<Endpoint> = conn_mgr
+                .flow_tracker
+                .idle_flows(&service, after.as_nanos() as u64)
+                .await
+                .iter()
+                .map(|(way, _)| way.from_endpoint())
+                .collect();
+            let now = Instant::now();
+            let mut targets = vec![];
+            for (conn, tracked) in conn_mgr.conns.iter() {
+                if now.duration_since(tracked.since) < after {
+                    continue;
+                }
+                let (client_way, _) = tracked.ways;
+                if idle.contains(&client_way.from_endpoint())
+                    || conn_mgr
+                        .flow_tracker
+                        .backend_way(&client_way)
+                        .await
+                        .is_none()
+                {
+                    targets.push(CloseMsg::reaped(conn, tracked.ways));
+                }
+            }
+            targets
+        };
+
+        let sender = match self.msg_sender() {
+            Some(sender) => sender,
+            None => return 0,
+        };
+        let reaped = targets.len();
+        for close_msg in targets {
+            let _ = sender.send(close_msg).await;
+        }
+        reaped
+    }
+
+    async fn close_where<F>(
+        &self,
+        matches: F,
+        close_msg: fn(Endpoint, Endpoint) -> CloseMsg,
+    ) -> usize
+    where
+        F: Fn(&tcp::ConnectionState) -> bool,
+    {
+        let conns: Vec<Connection> = {
+            let conn_mgr = self.handler.lock().await;
+            let mut conns = vec![];
+            for (conn, tracked) in conn_mgr.conns.iter() {
+                if let L4ConnState::TcpConnState(tcp_state) = &tracked.state {
+                    if matches(&*tcp_state.handler.lock().await) {
+                        conns.push(conn.clone());
+                    }
+                }
+            }
+            conns
+        };
+
+        let sender = match self.msg_sender() {
+            Some(sender) => sender,
+            None => return 0,
+        };
+        for conn in conns.iter() {
+            let _ = sender.send(close_msg(conn.from, conn.to)).await;
+        }
+        conns.len()
+    }
+}
+
+impl ConnectionStateMgr {
+    // The backend keeps a closed tcp connection in TIME_WAIT, a new one from
+    // the same local port meanwhile would be taken for the old one. Udp has
+    // nothing like it.
+    async fn release_port(&self, port: u16, service: Option<Endpoint>) {
+        if self.is_tcp {
+            self.port_pool.quarantine(port, service).await;
+        } else {
+            self.port_pool.release(port, service).await;
+        }
+    }
+
+    // Whether the connection of a timer that fired is closed now. A side that
+    // has not got to TIME_WAIT yet closes it later with its last packet, and a
+    // udp flow with packets since its timer was armed waits for the rest of
+    // its idle timeout.
+    async fn timer_fired(&self, timer: TimerKey, msg: &CloseMsg) -> bool {
+        if let (TimerKey::Idle(_), Some(ways)) = (timer, msg.ways) {
+            let limit = Duration::from_secs(self.cleanup.udp_idle_timeout_secs);
+            return match self.flow_tracker.idle_for(&ways.0).await {
+                Some(idle) if idle < limit => {
+                    self.arm_idle(ways, limit - idle);
+                    false
+                }
+                _ => true,
+            };
+        }
+        let tcp_state = match self.conns.get(&msg.connection()).map(|t| &t.state) {
+            Some(L4ConnState::TcpConnState(tcp_state)) => tcp_state,
+            _ => return false,
+        };
+        let mut conn_state = tcp_state.handler.lock().await;
+        match timer {
+            TimerKey::TimeWait(_) => {
+                conn_state.expire_time_wait(Instant::now());
+                conn_state.is_closed()
+            }
+            TimerKey::HalfOpen(_) => conn_state.half_open(),
+            _ => false,
+        }
+    }
+
+    // Every connection the kernel opened took a port and was counted open by
+    // its service, so it is counted closed once, with its port, by the close
+    // that removed its nat entries.
+    async fn closed(&self, port: u16, service: Endpoint) {
+        self.release_port(port, Some(service)).await;
+        self.flow_tracker.conn_limits().closed(&service);
+        self.scaler.conn_closed(&service);
+    }
+
+    // Whether the connection of an eviction notice is still open. Its flow is
+    // gone once it was closed meanwhile, and replaced once its client opened
+    // a new connection from the same port, which leaves only the local port
+    // and return entry of the evicted one to clean up.
+    async fn still_evicted(&mut self, msg: &CloseMsg) -> bool {
+        let (client_way, backend_way) = match msg.ways {
+            Some(ways) => ways,
+            None => return false,
+        };
+        match self.flow_tracker.backend_way(&client_way).await {
+            Some(way) if way.to_endpoint() == backend_way.to_endpoint() => true,
+            Some(_) => {
+                // only the one that removed the stale entry frees its port
+                if let (Ok(()), Some(port)) = (self.bpf_conn_map.remove(&backend_way), msg.port) {
+                    self.closed(port, client_way.to_endpoint()).await;
+                }
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+impl MsgHandler for ConnectionStateMgr {
+    type MsgType = CloseMsg;
+
+    async fn handle_message(&mut self, msg: Self::MsgType) {
+        if msg.reason == CloseReason::Evicted && !self.still_evicted(&msg).await {
+            return;
+        }
+
+        let conn = msg.connection();
+        if let Some(timer) = msg.timer {
+            if !self.timer_fired(timer, &msg).await {
+                return;
+            }
+        }
+        if msg.shed && self.conns.contains_key(&conn) {
+            return;
+        }
+        let tracked = self.conns.remove(&conn);
+        match tracked {
+            Some(_) => {
+                self.tracked.fetch_sub(1, Ordering::Relaxed);
+            }
+            // already closed, by a reap the connection made room for before
+            None if msg.reason == CloseReason::Reaped => return,
+            None => {}
+        }
+
+        if let (Some(tracer), Some(span)) = (
+            self.flow_tracker.tracer(),
+            tracked.as_ref().and_then(|t| t.span.as_ref()),
+        ) {
+            tracer.end(span, msg.reason);
+        }
+
+        let port = tracked.as_ref().map(|t| t.local_port).or(msg.port);
+        // a close that took the tracked entry or the nat entries is the one
+        // for the connection, a racing one finds them gone and frees nothing
+        let mut removed = tracked.is_some();
+        let u_connections = tracked.map(|t| t.ways).or(msg.ways);
+        if let Some(u_conns) = u_connections {
+            // the flow goes first: the kernel takes a flow without nat entries
+            // for an evicted connection
+            self.flow_tracker
+                .close(
+                    &self.service,
+                    &u_conns.0,
+                    u_conns.1.from_endpoint().to_string(),
+                    msg.reason,
+                )
+                .await;
+            // the idle sweep and the state machine may race on the same
+            // connection, either one finds the entries gone
+            if let Ok(nat) = self.bpf_conn_map.get(&u_conns.0) {
+                let _ = self.bpf_conn_map.remove(&UConnection::from(nat.rev_key));
+            }
+            removed |= self.bpf_conn_map.remove(&u_conns.0).is_ok();
+            removed |= self.bpf_conn_map.remove(&u_conns.1).is_ok();
+        }
+        if !removed {
+            return;
+        }
+
+        // the client way is addressed to the service, the nat entries are
+        // gone so the kernel hands the port out again only to a new connection
+        let service = u_connections.map(|(client_way, _)| client_way.to_endpoint());
+        if let (Some(port), Some(service)) = (port, service) {
+            self.closed(port, service).await;
+        }
+
+        // info!("connection map size: {:?}", self.conns.len());
+
+        info!("remove connection {:?}", conn);
+    }
+}
+
+#[derive(Debug)]
+pub struct CloseMsg {
+    from: Endpoint,
+    to: Endpoint,
+    reason: CloseReason,
+    // the nat entries and local port, for connections the state machine never saw
+    ways: Option<(UConnection, UConnection)>,
+    port: Option<u16>,
+    // of a timer that fired, closes only if the connection is still due
+    timer: Option<TimerKey>,
+    // of a shed syn, closes only a connection the state machine does not track
+    shed: bool,
+}
+
+impl CloseMsg {
+    pub fn new(from: Endpoint, to: Endpoint) -> Self {
+        CloseMsg {
+            from,
+            to,
+            reason: CloseReason::Fin,
+            ways: None,
+            port: None,
+            timer: None,
+            shed: false,
+        }
+    }
+
+    pub fn reclaimed(from: Endpoint, to: Endpoint) -> Self {
+        CloseMsg {
+            reason: CloseReason::Reclaimed,
+            ..CloseMsg::new(from, to)
+        }
+    }
+
+    pub fn stuck(from: Endpoint, to: Endpoint) -> Self {
+        CloseMsg {
+            reason: CloseReason::Stuck,
+            ..CloseMsg::new(from, to)
+        }
+    }
+
+    // `client_way` is the client -> service way, `backend_way` the backend -> local one
+    pub fn idle(client_way: UConnection, backend_way: UConnection) -> Self {
+        CloseMsg {
+            from: client_way.from_endpoint(),
+            to: backend_way.from_endpoint(),
+            reason: CloseReason::IdleTimeout,
+            ways: Some((client_way, backend_way)),
+            port: Some(backend_way.to_endpoint().port),
+            timer: None,
+            shed: false,
+        }
+    }
+
+    // the flow of a tracked connection was idle or gone for too long, or the
+    // connection made room for a new one
+    pub fn reaped(conn: &Connection, ways: (UConnection, UConnection)) -> Self {
+        CloseMsg {
+            from: conn.from,
+            to: conn.to,
+            reason: CloseReason::Reaped,
+            ..CloseMsg::idle(ways.0, ways.1)
+        }
+    }
+
+    // nat entries of a tcp connection never established, which the state
+    // machines do not follow
+    pub fn handshake_expired(client_way: UConnection, backend_way: UConnection) -> Self {
+        CloseMsg {
+            reason: CloseReason::HandshakeTimeout,
+            ..CloseMsg::idle(client_way, backend_way)
+        }
+    }
+
+    // the kernel evicted the nat entries of the connection, or some of them
+    pub fn evicted(client_way: UConnection, backend_way: UConnection) -> Self {
+        CloseMsg {
+            reason: CloseReason::Evicted,
+            ..CloseMsg::idle(client_way, backend_way)
+        }
+    }
+
+    // a nat entry the state machines never saw an event of
+    pub fn untracked(client_way: UConnection, backend_way: UConnection) -> Self {
+        CloseMsg {
+            reason: CloseReason::Reconciled,
+            ..CloseMsg::idle(client_way, backend_way)
+        }
+    }
+
+    // the nat entries of a syn whose event was shed, a retransmit of the syn
+    // of a tracked connection leaves it be
+    pub fn shed(client_way: UConnection, backend_way: UConnection) -> Self {
+        CloseMsg {
+            shed: true,
+            ..CloseMsg::untracked(client_way, backend_way)
+        }
+    }
+
+    // a state machine whose nat entries are gone, its port is still held
+    pub fn unmapped(from: Endpoint, to: Endpoint) -> Self {
+        CloseMsg {
+            reason: CloseReason::Reconciled,
+            ..CloseMsg::new(from, to)
+        }
+    }
+
+    // the TIME_WAIT a side of the connection got into is over
+    pub fn time_wait_over(from: Endpoint, to: Endpoint) -> Self {
+        let msg = CloseMsg::new(from, to);
+        CloseMsg {
+            timer: Some(TimerKey::TimeWait(msg.connection())),
+            ..msg
+        }
+    }
+
+    // the tcp connection did not get established in time
+    pub fn half_open(from: Endpoint, to: Endpoint) -> Self {
+        let msg = CloseMsg {
+            reason: CloseReason::HandshakeTimeout,
+            ..CloseMsg::new(from, to)
+        };
+        CloseMsg {
+            timer: Some(TimerKey::HalfOpen(msg.connection())),
+            ..msg
+        }
+    }
+
+    // the udp flow may have gone idle
+    pub fn idle_over(client_way: UConnection, backend_way: UConnection) -> Self {
+        let msg = CloseMsg::idle(client_way, backend_way);
+        CloseMsg {
+            timer: Some(TimerKey::Idle(msg.connection())),
+            ..msg
+        }
+    }
+
+    fn connection(&self) -> Connection {
+        Connection {
+            from: self.from,
+            to: self.to,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PacketMsg {
+    from: Endpoint,
+    to: Endpoint,
+    local_out_port: u16,
+    pub packet: Option<Packet>,
+}
+
+impl PacketMsg {
+    pub fn direction(&self, e: &Endpoint) -> Direction {
+        if e == &self.from {
+            Direction::From
+        } else {
+            Direction::To
+        }
+    }
+
+    pub fn connection(&self) -> Connection {
+        Connection {
+            from: self.from,
+            to: self.to,
+        }
+    }
+}
+
+impl TryFrom<&Message> for PacketMsg {
+    type Error = ();
+    fn try_from(msg: &Message) -> Result<Self, Self::Error> {
+        match &msg.msg_type {
+            MessageType::Packet(packet_type) => {
+                let packet = match packet_type {
+                    PacketMsgType::TCP(p) => Some(p.clone()),
+                    PacketMsgType::UDP => None,
+                };
+                let packet_msg = if msg.from_client {
+                    PacketMsg {
+                        from: msg.client,
+                        to: msg.server,
+                        local_out_port: msg.local_out.port,
+                        packet,
+                    }
+                } else {
+                    PacketMsg {
+                        from: msg.server,
+                        to: msg.client,
+                        local_out_port: msg.local_out.port,
+                        packet,
+                    }
+                };
+                Ok(packet_msg)
+            }
+            _ => return Err(()),
+        }
+    }
+}
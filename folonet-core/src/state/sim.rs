@@ -0,0 +1,349 @@
+use folonet_common::event::{Packet, PacketFlag};
+use tokio::time::{Duration, Instant};
+
+use super::tcp::{ConnectionState, TCPState};
+use super::PacketMsg;
+use crate::endpoint::Endpoint;
+
+// what one packet takes on the wire, the clock of a trace moves by it
+const PACKET_GAP: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    pub fn other(self) -> Side {
+        match self {
+            Side::Client => Side::Server,
+            Side::Server => Side::Client,
+        }
+    }
+}
+
+// One end of the simulated connection. It acks what it has received only
+// with a syn-ack or an ack, so its fin may not ack the fin of its peer.
+#[derive(Debug, Clone, Copy)]
+struct Peer {
+    e: Endpoint,
+    isn: u32,
+    next_seq: u32,
+    // the seq of its fin, for retransmitting it
+    fin_seq: Option<u32>,
+    // what it acks, none before the syn of its peer
+    rcv_next: Option<u32>,
+}
+
+impl Peer {
+    fn new(e: &str, isn: u32) -> Self {
+        Peer {
+            e: e.parse().unwrap(),
+            isn,
+            next_seq: isn,
+            fin_seq: None,
+            rcv_next: None,
+        }
+    }
+}
+
+// A tcp connection between a client and a server as folonet sees it in
+// between: every packet the two peers send is reported to the state machines,
+// in the order of the calls, on a clock of its own.
+pub struct Sim {
+    client: Peer,
+    server: Peer,
+    pub conn: ConnectionState,
+    pub now: Instant,
+}
+
+impl Sim {
+    pub fn new(client_isn: u32, server_isn: u32) -> Self {
+        let client = Peer::new("10.0.0.2:40000", client_isn);
+        let server = Peer::new("10.0.0.9:80", server_isn);
+        let now = Instant::now();
+        Sim {
+            conn: ConnectionState::new(&client.e, &server.e, true, now),
+            client,
+            server,
+            now,
+        }
+    }
+
+    fn peers(&mut self, side: Side) -> (&mut Peer, &mut Peer) {
+        match side {
+            Side::Client => (&mut self.client, &mut self.server),
+            Side::Server => (&mut self.server, &mut self.client),
+        }
+    }
+
+    fn send(&mut self, side: Side, flag: PacketFlag, seq: u32, ack_seq: u32) {
+        let (from, to) = self.peers(side);
+        let msg = PacketMsg {
+            from: from.e,
+            to: to.e,
+            local_out_port: 10000,
+            packet: Some(Packet { flag, ack_seq, seq }),
+        };
+        self.now += PACKET_GAP;
+        self.conn.observe(&msg, self.now);
+    }
+
+    // the flag and ack_seq of what `side` sends, acking what it has received
+    // so far when `catch_up`
+    fn ack_of(&mut self, side: Side, catch_up: bool) -> (PacketFlag, u32) {
+        let (from, to) = self.peers(side);
+        if catch_up && from.rcv_next.is_some() {
+            from.rcv_next = Some(to.next_seq);
+        }
+        match from.rcv_next {
+            Some(ack_seq) => (PacketFlag::ACK, ack_seq),
+            None => (PacketFlag::empty(), 0),
+        }
+    }
+
+    // a syn, or a syn-ack once the syn of the peer came in
+    pub fn syn(&mut self, side: Side) {
+        let (from, to) = self.peers(side);
+        to.rcv_next = Some(from.isn.wrapping_add(1));
+        if from.rcv_next.is_some() {
+            from.rcv_next = Some(to.next_seq);
+        }
+        let isn = from.isn;
+        from.next_seq = isn.wrapping_add(1);
+        let (ack, ack_seq) = self.ack_of(side, false);
+        self.send(side, PacketFlag::SYN | ack, isn, ack_seq);
+    }
+
+    pub fn ack(&mut self, side: Side) {
+        let (ack, ack_seq) = self.ack_of(side, true);
+        let seq = self.peers(side).0.next_seq;
+        self.send(side, ack, seq, ack_seq);
+    }
+
+    // payload is not reported, it only moves the seq of `side`
+    pub fn data(&mut self, side: Side, len: u32) {
+        let from = self.peers(side).0;
+        from.next_seq = from.next_seq.wrapping_add(len);
+    }
+
+    pub fn fin(&mut self, side: Side) {
+        let (ack, ack_seq) = self.ack_of(side, false);
+        let from = self.peers(side).0;
+        let seq = from.next_seq;
+        from.fin_seq = Some(seq);
+        from.next_seq = seq.wrapping_add(1);
+        self.send(side, PacketFlag::FIN | ack, seq, ack_seq);
+    }
+
+    pub fn retransmit_fin(&mut self, side: Side) {
+        let (ack, ack_seq) = self.ack_of(side, false);
+        if let Some(seq) = self.peers(side).0.fin_seq {
+            self.send(side, PacketFlag::FIN | ack, seq, ack_seq);
+        }
+    }
+
+    // a reset `offset` from the seq `side` sends next
+    pub fn rst(&mut self, side: Side, offset: u32) {
+        let (ack, ack_seq) = self.ack_of(side, false);
+        let seq = self.peers(side).0.next_seq.wrapping_add(offset);
+        self.send(side, PacketFlag::RST | ack, seq, ack_seq);
+    }
+
+    pub fn handshake(&mut self) {
+        self.syn(Side::Client);
+        self.syn(Side::Server);
+        self.ack(Side::Client);
+    }
+
+    pub fn states(&self) -> [TCPState; 2] {
+        let [client, server] = self.conn.sides(self.now);
+        [client.0, server.0]
+    }
+
+    // the seqs of a fin from the server to the client, as the peers have them
+    pub fn next_seqs(&self) -> (u32, u32) {
+        (self.server.next_seq, self.client.next_seq)
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_clock() {
+        use tokio::time::Duration;
+
+        use super::{Side, Sim, PACKET_GAP};
+        use crate::state::tcp::TCPState;
+
+        let mut sim = Sim::new(100, 500);
+        sim.handshake();
+        // both sides got established with the ack of the client, the third
+        // packet of the trace
+        sim.now += Duration::from_secs(30);
+        let sides = sim.conn.sides(sim.now);
+        assert_eq!(sides[0], (TCPState::Established, Duration::from_secs(30)));
+        assert!(sim
+            .conn
+            .stuck_in(&[TCPState::Established], Duration::from_secs(30), sim.now));
+        assert!(!sim.conn.stuck_in(
+            &[TCPState::Established],
+            Duration::from_secs(30) + PACKET_GAP,
+            sim.now
+        ));
+        sim.fin(Side::Client);
+        assert_eq!(
+            sim.conn.sides(sim.now)[0],
+            (TCPState::FinWait1, Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn test_half_open() {
+        use tokio::time::Instant;
+
+        use super::{Side, Sim};
+        use crate::state::tcp::ConnectionState;
+
+        let mut sim = Sim::new(100, 500);
+        sim.syn(Side::Client);
+        assert!(sim.conn.half_open());
+        // the backend answered, the client did not ack yet
+        sim.syn(Side::Server);
+        assert!(sim.conn.half_open());
+        sim.ack(Side::Client);
+        assert!(!sim.conn.half_open());
+        sim.fin(Side::Client);
+        assert!(!sim.conn.half_open());
+
+        // a reset ends the handshake as well
+        let mut sim = Sim::new(100, 500);
+        sim.syn(Side::Client);
+        sim.rst(Side::Server, 0);
+        assert!(!sim.conn.half_open());
+
+        // a connection found later was established before
+        let client = "10.0.0.2:40000".parse().unwrap();
+        let server = "10.0.0.9:80".parse().unwrap();
+        let found = ConnectionState::new(&client, &server, false, Instant::now());
+        assert!(!found.half_open());
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_handshake(client_isn: u32, server_isn: u32, len in 0u32..1 << 20) {
+            use super::{Side, Sim};
+            use crate::state::tcp::TCPState;
+
+            let mut sim = Sim::new(client_isn, server_isn);
+            sim.syn(Side::Client);
+            proptest::prop_assert_eq!(sim.states(), [TCPState::SynSent, TCPState::ListenReceiveSyn]);
+            sim.syn(Side::Server);
+            sim.ack(Side::Client);
+            proptest::prop_assert_eq!(sim.states(), [TCPState::Established; 2]);
+            proptest::prop_assert_eq!(sim.conn.fin_to_client(), Some(sim.next_seqs()));
+
+            // data is not reported, but the acks of it are
+            sim.data(Side::Client, len);
+            sim.ack(Side::Server);
+            proptest::prop_assert_eq!(sim.states(), [TCPState::Established; 2]);
+            proptest::prop_assert_eq!(sim.conn.fin_to_client(), Some(sim.next_seqs()));
+        }
+
+        #[test]
+        fn prop_close(
+            client_isn: u32,
+            server_isn: u32,
+            server_closes: bool,
+            retransmits in proptest::collection::vec(0usize..4, 0..4),
+        ) {
+            use super::{Side, Sim};
+            use crate::state::tcp::TCPState;
+
+            let mut sim = Sim::new(client_isn, server_isn);
+            sim.handshake();
+            let closer = if server_closes { Side::Server } else { Side::Client };
+            sim.fin(closer);
+            // the fin of the closer again, after any of the packets of the close
+            for step in 0..4 {
+                for _ in retransmits.iter().filter(|at| **at == step) {
+                    sim.retransmit_fin(closer);
+                }
+                match step {
+                    0 => sim.ack(closer.other()),
+                    1 => sim.fin(closer.other()),
+                    2 => sim.ack(closer),
+                    _ => {}
+                }
+            }
+            proptest::prop_assert_eq!(sim.states(), [TCPState::Closed; 2]);
+            proptest::prop_assert!(sim.conn.is_closed());
+        }
+
+        #[test]
+        fn prop_simultaneous_close(
+            client_isn: u32,
+            server_isn: u32,
+            server_first: bool,
+            server_acks_first: bool,
+        ) {
+            use super::{Side, Sim};
+            use crate::state::tcp::TCPState;
+
+            let mut sim = Sim::new(client_isn, server_isn);
+            sim.handshake();
+            // neither fin acks the other
+            let first = if server_first { Side::Server } else { Side::Client };
+            sim.fin(first);
+            sim.fin(first.other());
+            let acker = if server_acks_first { Side::Server } else { Side::Client };
+            sim.ack(acker);
+            sim.ack(acker.other());
+            proptest::prop_assert_eq!(sim.states(), [TCPState::Closed; 2]);
+        }
+
+        #[test]
+        fn prop_reset(
+            client_isn: u32,
+            server_isn: u32,
+            steps in 0usize..=7,
+            server_resets: bool,
+        ) {
+            use super::{Side, Sim};
+            use crate::state::tcp::TCPState;
+
+            let mut sim = Sim::new(client_isn, server_isn);
+            let trace: [fn(&mut Sim); 7] = [
+                |sim: &mut Sim| sim.syn(Side::Client),
+                |sim: &mut Sim| sim.syn(Side::Server),
+                |sim: &mut Sim| sim.ack(Side::Client),
+                |sim: &mut Sim| sim.fin(Side::Client),
+                |sim: &mut Sim| sim.ack(Side::Server),
+                |sim: &mut Sim| sim.fin(Side::Server),
+                |sim: &mut Sim| sim.ack(Side::Client),
+            ];
+            for step in trace.iter().take(steps) {
+                step(&mut sim);
+            }
+            sim.rst(if server_resets { Side::Server } else { Side::Client }, 0);
+            proptest::prop_assert_eq!(sim.states(), [TCPState::Closed; 2]);
+        }
+
+        #[test]
+        fn prop_reset_out_of_window(
+            client_isn: u32,
+            server_isn: u32,
+            offset in (1u32 << 30) + 1..u32::MAX - (1 << 16),
+            server_resets: bool,
+        ) {
+            use super::{Side, Sim};
+            use crate::state::tcp::TCPState;
+
+            let mut sim = Sim::new(client_isn, server_isn);
+            sim.handshake();
+            sim.rst(if server_resets { Side::Server } else { Side::Client }, offset);
+            proptest::prop_assert_eq!(sim.states(), [TCPState::Established; 2]);
+        }
+    }
+}
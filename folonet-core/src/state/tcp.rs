@@ -0,0 +1,258 @@
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+
+use crate::{
+    endpoint::Endpoint,
+    shard::Sharded,
+    timer::{TimerKey, Timers},
+    trace::ConnSpan,
+    worker::MsgHandler,
+};
+
+use super::fsm::TcpFsmState;
+pub use super::fsm::{TCPInput, TCPState};
+use super::{CloseMsg, PacketHandler, PacketMsg};
+
+// the timeouts of the tcp connections of a backend, 0 turns one off
+#[derive(Clone)]
+pub struct TcpTimeouts {
+    pub timers: Timers,
+    pub time_wait: Duration,
+    pub half_open: Duration,
+}
+
+pub struct ConnectionState {
+    client: TcpFsmState,
+    server: TcpFsmState,
+
+    close_event_sender: Option<mpsc::Sender<CloseMsg>>,
+    // without them a side leaves TIME_WAIT right away
+    timeouts: Option<TcpTimeouts>,
+    // both sides got established, or were when the connection was found
+    established: bool,
+    // gets an event whenever a side changes its state
+    span: Option<ConnSpan>,
+}
+
+impl ConnectionState {
+    // `opening` when the first packet seen is the syn of the client, the
+    // state machines of connections found later start out established
+    pub fn new(from: &Endpoint, to: &Endpoint, opening: bool, now: Instant) -> Self {
+        ConnectionState {
+            client: TcpFsmState::new(from, opening, false, now),
+            server: TcpFsmState::new(to, opening, true, now),
+            close_event_sender: None,
+            timeouts: None,
+            established: !opening,
+            span: None,
+        }
+    }
+
+    pub fn set_close_event_sender(&mut self, sender: mpsc::Sender<CloseMsg>) {
+        self.close_event_sender.replace(sender);
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: TcpTimeouts) {
+        self.timeouts.replace(timeouts);
+    }
+
+    pub fn set_span(&mut self, span: ConnSpan) {
+        self.span.replace(span);
+    }
+
+    // either side of the connection is in `state`
+    pub fn in_state(&self, state: TCPState) -> bool {
+        self.client.state() == state || self.server.state() == state
+    }
+
+    // the client side, as the first packet seen was sent by the client
+    pub fn client(&self) -> (Endpoint, TCPState) {
+        (self.client.endpoint(), self.client.state())
+    }
+
+    // the state of both sides and how long they have been in it
+    pub fn sides(&self, now: Instant) -> [(TCPState, Duration); 2] {
+        [self.client.state_age(now), self.server.state_age(now)]
+    }
+
+    // Seq and ack_seq of a fin from the service to the client, from the last
+    // packets reported of the connection. Data packets are not reported, a
+    // client further ahead drops the fin and times out as it would have.
+    pub fn fin_to_client(&self) -> Option<(u32, u32)> {
+        let established = |side: &TcpFsmState| side.state() == TCPState::Established;
+        if !established(&self.client) || !established(&self.server) {
+            return None;
+        }
+        let (sent, received) = self.client.next_seqs()?;
+        Some((received, sent))
+    }
+
+    // either side sat in one of `states` for at least `min_age`
+    pub fn stuck_in(&self, states: &[TCPState], min_age: Duration, now: Instant) -> bool {
+        self.sides(now)
+            .iter()
+            .any(|(state, age)| states.contains(state) && *age >= min_age)
+    }
+
+    // both sides see every packet, the state machines are done once both are
+    // closed
+    pub fn observe(&mut self, msg: &PacketMsg, now: Instant) {
+        self.client.handle_packet(msg, now);
+        self.server.handle_packet(msg, now);
+        if self
+            .timeouts
+            .as_ref()
+            .map_or(true, |timeouts| timeouts.time_wait.is_zero())
+        {
+            self.expire_time_wait(now);
+        }
+        if self.client.state() == TCPState::Established
+            && self.server.state() == TCPState::Established
+        {
+            self.established = true;
+        }
+    }
+
+    // opened and still in the handshake
+    pub fn half_open(&self) -> bool {
+        !self.established && !self.is_closed()
+    }
+
+    // the TIME_WAIT of either side is over
+    pub fn expire_time_wait(&mut self, now: Instant) {
+        self.client.expire_time_wait(now);
+        self.server.expire_time_wait(now);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.client.is_closed() && self.server.is_closed()
+    }
+}
+
+impl MsgHandler for ConnectionState {
+    type MsgType = PacketMsg;
+
+    async fn handle_message(&mut self, msg: PacketMsg) {
+        let before = [self.client.state(), self.server.state()];
+        let was_established = self.established;
+        self.observe(&msg, Instant::now());
+
+        if let Some(span) = &self.span {
+            let after = [self.client.state(), self.server.state()];
+            for (side, (was, is)) in ["client", "backend"].iter().zip(before.iter().zip(after)) {
+                if *was != is {
+                    span.event(format!("{} {:?}", side, is));
+                }
+            }
+        }
+
+        let sender = match &self.close_event_sender {
+            Some(sender) => sender,
+            None => return,
+        };
+        if self.is_closed() {
+            let _ = sender.send(CloseMsg::new(msg.from, msg.to)).await;
+            return;
+        }
+        let timeouts = match &self.timeouts {
+            Some(timeouts) => timeouts,
+            None => return,
+        };
+        let conn = msg.connection();
+        if self.established && !was_established {
+            timeouts.timers.cancel(TimerKey::HalfOpen(conn));
+        }
+        // a side just got into TIME_WAIT, the wait of the connection starts
+        // over
+        let after = [self.client.state(), self.server.state()];
+        if !timeouts.time_wait.is_zero()
+            && before
+                .iter()
+                .zip(after)
+                .any(|(was, is)| *was != TCPState::TimeWait && is == TCPState::TimeWait)
+        {
+            timeouts.timers.arm(
+                TimerKey::TimeWait(conn),
+                timeouts.time_wait,
+                sender.clone(),
+                CloseMsg::time_wait_over(msg.from, msg.to),
+            );
+        }
+    }
+}
+
+pub type TcpConnState = Sharded<ConnectionState>;
+
+impl PacketHandler for TcpConnState {
+    async fn handle_packet(&mut self, packet: PacketMsg) {
+        self.send(packet).await;
+    }
+}
+
+impl TcpConnState {
+    pub async fn in_state(&self, state: TCPState) -> bool {
+        self.handler.lock().await.in_state(state)
+    }
+}
+
+mod test {
+
+    #[tokio::test]
+    async fn test_handshake_and_reset() {
+        use folonet_common::event::{Packet, PacketFlag};
+
+        use super::{ConnectionState, TCPState};
+        use crate::endpoint::Endpoint;
+        use crate::state::PacketMsg;
+        use crate::worker::MsgHandler;
+
+        let client: Endpoint = "10.0.0.2:40000".parse().unwrap();
+        let server: Endpoint = "10.0.0.9:80".parse().unwrap();
+        let packet = |from, to, flag, seq, ack_seq| PacketMsg {
+            from,
+            to,
+            local_out_port: 10000,
+            packet: Some(Packet { flag, ack_seq, seq }),
+        };
+
+        let mut conn = ConnectionState::new(&client, &server, true, tokio::time::Instant::now());
+        conn.handle_message(packet(client, server, PacketFlag::SYN, 100, 0))
+            .await;
+        assert!(conn.in_state(TCPState::SynSent));
+        conn.handle_message(packet(
+            server,
+            client,
+            PacketFlag::SYN | PacketFlag::ACK,
+            500,
+            101,
+        ))
+        .await;
+        assert!(conn.in_state(TCPState::SynReceived));
+        conn.handle_message(packet(client, server, PacketFlag::ACK, 101, 501))
+            .await;
+        assert_eq!(
+            conn.sides(tokio::time::Instant::now())[0].0,
+            TCPState::Established
+        );
+        assert_eq!(
+            conn.sides(tokio::time::Instant::now())[1].0,
+            TCPState::Established
+        );
+        assert_eq!(conn.fin_to_client(), Some((501, 101)));
+
+        // a reset far out of the window is ignored, one in it closes both sides
+        conn.handle_message(packet(server, client, PacketFlag::RST, 500 + (1 << 31), 0))
+            .await;
+        assert!(conn.in_state(TCPState::Established));
+        conn.handle_message(packet(server, client, PacketFlag::RST, 501, 0))
+            .await;
+        assert_eq!(
+            conn.sides(tokio::time::Instant::now())[0].0,
+            TCPState::Closed
+        );
+        assert_eq!(
+            conn.sides(tokio::time::Instant::now())[1].0,
+            TCPState::Closed
+        );
+    }
+}
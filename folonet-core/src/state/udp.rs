@@ -0,0 +1,34 @@
+use tokio::time::{Duration, Instant};
+
+use super::{PacketHandler, PacketMsg};
+
+// udp has no close, the idle timer of a flow ends it; this only
+// follows what the xdp program reports of it
+pub struct UdpConnState {
+    opened: Instant,
+    packets: u64,
+}
+
+impl UdpConnState {
+    pub fn new() -> Self {
+        UdpConnState {
+            opened: Instant::now(),
+            packets: 0,
+        }
+    }
+
+    pub fn age(&self) -> Duration {
+        self.opened.elapsed()
+    }
+
+    // packets reported by the xdp program, not all packets of the flow
+    pub fn packets(&self) -> u64 {
+        self.packets
+    }
+}
+
+impl PacketHandler for UdpConnState {
+    async fn handle_packet(&mut self, _packet: PacketMsg) {
+        self.packets += 1;
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::Arc;
+
+use aya::maps::{MapData, PerCpuArray, PerCpuHashMap};
+use folonet_common::stats::{Counter, KIfaceStats};
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Duration};
+
+use crate::net::interface_name;
+
+pub type BpfIfaceStatsMap = PerCpuHashMap<MapData, u32, KIfaceStats>;
+
+pub fn read_counter(counters: &PerCpuArray<MapData, u64>, counter: Counter) -> u64 {
+    counters
+        .get(&(counter as u32), 0)
+        .map(|values| values.iter().sum())
+        .unwrap_or(0)
+}
+
+pub fn read_counters(counters: &PerCpuArray<MapData, u64>) -> Vec<(Counter, u64)> {
+    Counter::ALL
+        .iter()
+        .map(|counter| (*counter, read_counter(counters, *counter)))
+        .collect()
+}
+
+pub async fn log_counters(counters: Arc<PerCpuArray<MapData, u64>>, interval: Duration) {
+    loop {
+        sleep(interval).await;
+        read_counters(&counters)
+            .iter()
+            .filter(|(_, v)| *v > 0)
+            .for_each(|(counter, v)| info!("counter {}: {}", counter.name(), v));
+    }
+}
+
+// what the xdp program did with the packets of one interface since it was
+// loaded, summed over the cpus
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct IfaceStats {
+    pub iface: String,
+    pub ifindex: u32,
+    pub rx: u64,
+    // of a connection folonet rewrites
+    pub matched: u64,
+    pub tx: u64,
+    // to an af_xdp socket
+    pub redirected: u64,
+    pub passed: u64,
+    pub dropped: u64,
+    pub aborted: u64,
+}
+
+impl IfaceStats {
+    fn new(iface: String, ifindex: u32, per_cpu: &[KIfaceStats]) -> Self {
+        let mut stats = IfaceStats {
+            iface,
+            ifindex,
+            ..Default::default()
+        };
+        for cpu in per_cpu {
+            stats.rx += cpu.rx;
+            stats.matched += cpu.matched;
+            stats.tx += cpu.tx;
+            stats.redirected += cpu.redirected;
+            stats.passed += cpu.passed;
+            stats.dropped += cpu.dropped;
+            stats.aborted += cpu.aborted;
+        }
+        stats
+    }
+}
+
+pub fn read_iface_stats(map: &BpfIfaceStatsMap) -> Vec<IfaceStats> {
+    let mut stats: Vec<IfaceStats> = map
+        .iter()
+        .filter_map(|item| item.ok())
+        .map(|(ifindex, per_cpu)| {
+            let iface = interface_name(ifindex).unwrap_or_else(|| ifindex.to_string());
+            IfaceStats::new(iface, ifindex, &per_cpu)
+        })
+        .collect();
+    stats.sort_by_key(|s| s.ifindex);
+    stats
+}
+
+mod test {
+
+    #[test]
+    fn test_iface_stats() {
+        use folonet_common::stats::KIfaceStats;
+
+        use super::IfaceStats;
+
+        let per_cpu = [
+            KIfaceStats {
+                rx: 3,
+                matched: 2,
+                tx: 2,
+                passed: 1,
+                ..Default::default()
+            },
+            KIfaceStats {
+                rx: 2,
+                dropped: 1,
+                aborted: 1,
+                ..Default::default()
+            },
+        ];
+        let stats = IfaceStats::new("eth0".to_string(), 2, &per_cpu);
+        assert_eq!(stats.rx, 5);
+        assert_eq!(stats.matched, 2);
+        assert_eq!(
+            stats.tx + stats.passed + stats.dropped + stats.aborted,
+            stats.rx
+        );
+    }
+}
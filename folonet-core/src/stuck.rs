@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+use folonet_client::config::StuckConfig;
+use log::{error, info};
+use serde::Serialize;
+use tokio::time::{sleep, Duration};
+
+use crate::control::ServiceMap;
+use crate::state::tcp::TCPState;
+
+// handshake and close states a healthy connection passes through quickly
+pub const STUCK_STATES: [TCPState; 5] = [
+    TCPState::SynSent,
+    TCPState::SynReceived,
+    TCPState::FinWait1,
+    TCPState::Closing,
+    TCPState::LastAck,
+];
+
+// upper bounds of the age buckets, the last bucket holds everything older
+const AGE_BUCKETS_SECS: [u64; 4] = [1, 10, 60, 300];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StateAges {
+    // connection sides per state, by age: <1s, <10s, <60s, <300s, older
+    pub states: BTreeMap<String, [u64; AGE_BUCKETS_SECS.len() + 1]>,
+    // sides in a stuck state for longer than the configured age
+    pub stuck: usize,
+}
+
+impl StateAges {
+    pub fn new(ages: &[(TCPState, Duration)], min_age: Duration) -> Self {
+        let mut state_ages = StateAges::default();
+        for (state, age) in ages {
+            let bucket = AGE_BUCKETS_SECS
+                .iter()
+                .position(|secs| age.as_secs() < *secs)
+                .unwrap_or(AGE_BUCKETS_SECS.len());
+            state_ages.states.entry(format!("{:?}", state)).or_default()[bucket] += 1;
+            if STUCK_STATES.contains(state) && *age >= min_age {
+                state_ages.stuck += 1;
+            }
+        }
+        state_ages
+    }
+}
+
+// Looks for tcp connections piling up in a handshake or close state, which
+// points at a bug in the state machine long before the maps fill up. Cheap
+// to clone, every clone sees the same last sample.
+#[derive(Clone, Default)]
+pub struct StuckWatch {
+    last: Arc<RwLock<StateAges>>,
+}
+
+impl StuckWatch {
+    pub fn ages(&self) -> StateAges {
+        self.last.read().unwrap().clone()
+    }
+
+    pub async fn watch_forever(self, services: ServiceMap, cfg: StuckConfig) {
+        let min_age = Duration::from_secs(cfg.min_age_secs);
+        loop {
+            sleep(Duration::from_secs(cfg.interval_secs)).await;
+
+            let mut ages = vec![];
+            for service in services.values() {
+                ages.extend(service.handler.lock().await.state_ages().await);
+            }
+            let state_ages = StateAges::new(&ages, min_age);
+            let stuck = state_ages.stuck;
+            *self.last.write().unwrap() = state_ages;
+
+            if stuck <= cfg.max_stuck {
+                continue;
+            }
+            error!(
+                "{} connection sides stuck in {:?} for over {:?}, the state machine may be leaking",
+                stuck, STUCK_STATES, min_age
+            );
+            if cfg.sweep {
+                let mut swept = 0;
+                for service in services.values() {
+                    swept += service
+                        .handler
+                        .lock()
+                        .await
+                        .sweep_stuck(&STUCK_STATES, min_age)
+                        .await;
+                }
+                info!("swept {} stuck connections", swept);
+            }
+        }
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_state_ages() {
+        use tokio::time::Duration;
+
+        use super::StateAges;
+        use crate::state::tcp::TCPState;
+
+        let ages = [
+            (TCPState::Established, Duration::from_millis(500)),
+            (TCPState::Established, Duration::from_secs(400)),
+            (TCPState::SynReceived, Duration::from_secs(30)),
+            (TCPState::SynReceived, Duration::from_secs(200)),
+            (TCPState::FinWait2, Duration::from_secs(200)),
+        ];
+        let state_ages = StateAges::new(&ages, Duration::from_secs(120));
+
+        assert_eq!(state_ages.states["Established"], [1, 0, 0, 0, 1]);
+        assert_eq!(state_ages.states["SynReceived"], [0, 0, 1, 1, 0]);
+        // FinWait2 is where a half closed connection waits, not a stuck state
+        assert_eq!(state_ages.stuck, 1);
+    }
+}
@@ -0,0 +1,121 @@
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+use log::{debug, warn};
+use tokio::time::{interval, Duration};
+
+use crate::health::Readiness;
+
+// Tells systemd how the daemon is doing when it runs as a Type=notify unit,
+// nothing happens outside of one. See sd_notify(3).
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    socket: Option<String>,
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        Notifier {
+            socket: std::env::var("NOTIFY_SOCKET").ok(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    fn send(&self, state: &str) -> io::Result<()> {
+        let path = match &self.socket {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        // a leading @ is an abstract socket
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name.as_bytes())?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        Ok(())
+    }
+
+    pub fn notify(&self, state: &str) {
+        if let Err(e) = self.send(state) {
+            warn!("failed to notify systemd of {:?}: {}", state, e);
+        }
+    }
+
+    pub fn ready(&self, status: &str) {
+        self.notify(&format!("READY=1\nSTATUS={}", status));
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1\nSTATUS=detaching");
+    }
+
+    // Pings the watchdog at half of WatchdogSec= as long as the ring buffer
+    // consumers poll, so systemd restarts a daemon whose engine is stuck.
+    pub async fn watchdog_forever(self, readiness: Readiness) {
+        let timeout = match watchdog_timeout(
+            std::env::var("WATCHDOG_USEC").ok().as_deref(),
+            std::env::var("WATCHDOG_PID").ok().as_deref(),
+            std::process::id(),
+        ) {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        let mut ticks = interval(timeout / 2);
+        loop {
+            ticks.tick().await;
+            let age = readiness
+                .packet_events
+                .age()
+                .max(readiness.cold_starts.age());
+            if age < timeout / 2 {
+                self.notify("WATCHDOG=1");
+            } else {
+                debug!("skip the watchdog ping, a consumer polled {:?} ago", age);
+            }
+        }
+    }
+}
+
+// the watchdog timeout systemd set for this process, none when it set none
+// or set it for another one
+fn watchdog_timeout(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != own_pid {
+            return None;
+        }
+    }
+    let usec = usec?.parse::<u64>().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec))
+}
+
+mod test {
+
+    #[test]
+    fn test_watchdog_timeout() {
+        use tokio::time::Duration;
+
+        use super::watchdog_timeout;
+
+        assert_eq!(
+            watchdog_timeout(Some("30000000"), None, 7),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            watchdog_timeout(Some("30000000"), Some("7"), 7),
+            Some(Duration::from_secs(30))
+        );
+        // meant for the process that started this one
+        assert_eq!(watchdog_timeout(Some("30000000"), Some("6"), 7), None);
+        assert_eq!(watchdog_timeout(Some("0"), None, 7), None);
+        assert_eq!(watchdog_timeout(None, None, 7), None);
+        assert_eq!(watchdog_timeout(Some("soon"), None, 7), None);
+    }
+}
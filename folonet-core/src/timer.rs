@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Duration;
+use tokio_util::time::delay_queue::{DelayQueue, Key};
+
+use crate::endpoint::{Connection, Endpoint};
+
+// What a timer is for. Arming a timer that is already armed starts it over
+// with the new message, so every connection has at most one of each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimerKey {
+    // a side of the tcp connection got into TIME_WAIT
+    TimeWait(Connection),
+    // the tcp connection was opened and has not got established yet
+    HalfOpen(Connection),
+    // the udp flow may have gone idle
+    Idle(Connection),
+    // the next startup probe of a backend
+    Probe(Endpoint, u64),
+}
+
+type Deliver = Box<dyn FnOnce() + Send>;
+
+enum Command {
+    Arm(TimerKey, Duration, Deliver),
+    Cancel(TimerKey),
+}
+
+// Every timeout of the daemon: one task keeps the armed timers in a
+// hierarchical wheel, a DelayQueue, and delivers the message of a timer that
+// fires to the worker that armed it. Arming, starting over and cancelling
+// cost the same however many connections are waiting, and no worker waits
+// for a timer itself. Cheap to clone, every clone arms on the same wheel.
+#[derive(Clone)]
+pub struct Timers {
+    sender: mpsc::UnboundedSender<Command>,
+    next_probe: Arc<AtomicU64>,
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(drive_forever(rx));
+        Timers {
+            sender: tx,
+            next_probe: Arc::default(),
+        }
+    }
+
+    // `msg` goes to `to` once `after` went by, unless `key` is armed again or
+    // cancelled meanwhile
+    pub fn arm<M: Send + 'static>(
+        &self,
+        key: TimerKey,
+        after: Duration,
+        to: mpsc::Sender<M>,
+        msg: M,
+    ) {
+        let deliver = Box::new(move || {
+            // a busy worker does not hold up the other timers
+            if let Err(mpsc::error::TrySendError::Full(msg)) = to.try_send(msg) {
+                tokio::spawn(async move {
+                    let _ = to.send(msg).await;
+                });
+            }
+        });
+        let _ = self.sender.send(Command::Arm(key, after, deliver));
+    }
+
+    pub fn cancel(&self, key: TimerKey) {
+        let _ = self.sender.send(Command::Cancel(key));
+    }
+
+    // wait `after` before probing `backend` again
+    pub async fn probe_after(&self, backend: Endpoint, after: Duration) {
+        let key = TimerKey::Probe(backend, self.next_probe.fetch_add(1, Ordering::Relaxed));
+        let (tx, rx) = oneshot::channel();
+        let deliver = Box::new(move || {
+            let _ = tx.send(());
+        });
+        let _ = self.sender.send(Command::Arm(key, after, deliver));
+        let _ = rx.await;
+    }
+}
+
+async fn drive_forever(mut rx: mpsc::UnboundedReceiver<Command>) {
+    let mut wheel: DelayQueue<(TimerKey, Deliver)> = DelayQueue::new();
+    let mut armed: HashMap<TimerKey, Key> = HashMap::new();
+    loop {
+        // what is queued goes first, a timer cancelled in time does not fire
+        tokio::select! {
+            biased;
+            command = rx.recv() => match command {
+                Some(Command::Arm(timer, after, deliver)) => {
+                    if let Some(key) = armed.remove(&timer) {
+                        wheel.remove(&key);
+                    }
+                    armed.insert(timer, wheel.insert((timer, deliver), after));
+                }
+                Some(Command::Cancel(timer)) => {
+                    if let Some(key) = armed.remove(&timer) {
+                        wheel.remove(&key);
+                    }
+                }
+                // every handle is gone, nobody waits for the rest
+                None => return,
+            },
+            Some(fired) = poll_fn(|cx| wheel.poll_expired(cx)) => {
+                let (timer, deliver) = fired.into_inner();
+                armed.remove(&timer);
+                deliver();
+            }
+        }
+    }
+}
+
+mod test {
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timers() {
+        use tokio::sync::mpsc;
+        use tokio::task::yield_now;
+        use tokio::time::{advance, Duration};
+
+        use super::{TimerKey, Timers};
+        use crate::endpoint::{Connection, Endpoint};
+
+        let client: Endpoint = "10.0.0.2:40000".parse().unwrap();
+        let server: Endpoint = "10.0.0.9:80".parse().unwrap();
+        let conn = Connection {
+            from: client,
+            to: server,
+        };
+        let timers = Timers::new();
+        let (tx, mut rx) = mpsc::channel(4);
+
+        timers.arm(
+            TimerKey::TimeWait(conn),
+            Duration::from_secs(2),
+            tx.clone(),
+            1,
+        );
+        timers.arm(
+            TimerKey::HalfOpen(conn),
+            Duration::from_secs(1),
+            tx.clone(),
+            2,
+        );
+        yield_now().await;
+        advance(Duration::from_millis(500)).await;
+        timers.cancel(TimerKey::HalfOpen(conn));
+        advance(Duration::from_secs(1)).await;
+        // the other way of the connection is the same connection, its timer
+        // starts over
+        let back = Connection {
+            from: server,
+            to: client,
+        };
+        timers.arm(TimerKey::TimeWait(back), Duration::from_secs(2), tx, 3);
+        yield_now().await;
+        advance(Duration::from_millis(1500)).await;
+        assert!(rx.try_recv().is_err());
+
+        advance(Duration::from_secs(1)).await;
+        assert_eq!(rx.recv().await, Some(3));
+        assert!(rx.try_recv().is_err());
+
+        // a probe waits on the same wheel
+        let probe = timers.probe_after(server, Duration::from_secs(5));
+        tokio::pin!(probe);
+        tokio::select! {
+            _ = &mut probe => panic!("the probe went early"),
+            _ = advance(Duration::from_secs(4)) => {}
+        }
+        advance(Duration::from_secs(1)).await;
+        probe.await;
+    }
+}
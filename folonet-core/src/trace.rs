@@ -0,0 +1,260 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use folonet_client::config::TracingConfig;
+use folonet_client::error::ClientError;
+use folonet_client::http::{check_url, https_client, post_json, HttpsClient};
+use log::warn;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio::time::{interval, Duration};
+
+use crate::endpoint::Endpoint;
+use crate::error::FolonetError;
+use crate::flow_log::CloseReason;
+use crate::sink::Sink;
+
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+// a connection changes its state a handful of times, unless it is odd
+const MAX_EVENTS: usize = 32;
+// SPAN_KIND_SERVER, folonet accepts the connection of the client
+const SPAN_KIND_SERVER: u8 = 2;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// every RandomState is seeded apart, the counter keeps two of a thread apart
+fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+fn unix_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn string_attr(key: &str, value: impl Into<Value>) -> Value {
+    json!({ "key": key, "value": { "stringValue": value.into() } })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SpanEvent {
+    ns: u64,
+    name: String,
+}
+
+#[derive(Debug, Clone)]
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    start_ns: u64,
+    service: String,
+    client: Endpoint,
+    backend: Endpoint,
+    // the port folonet opened the connection to the backend from, which the
+    // traces of the backend see as the peer
+    local_port: u16,
+    events: Vec<SpanEvent>,
+}
+
+impl SpanData {
+    fn to_otlp(&self, end_ns: u64, reason: CloseReason) -> Value {
+        let events: Vec<Value> = self
+            .events
+            .iter()
+            .map(|event| json!({ "timeUnixNano": event.ns.to_string(), "name": event.name }))
+            .collect();
+        json!({
+            "traceId": hex(&self.trace_id),
+            "spanId": hex(&self.span_id),
+            "name": "connection",
+            "kind": SPAN_KIND_SERVER,
+            "startTimeUnixNano": self.start_ns.to_string(),
+            "endTimeUnixNano": end_ns.to_string(),
+            "attributes": [
+                string_attr("folonet.service", self.service.as_str()),
+                string_attr("folonet.client", self.client.to_string()),
+                string_attr("folonet.backend", self.backend.to_string()),
+                json!({
+                    "key": "folonet.local_port",
+                    "value": { "intValue": self.local_port.to_string() }
+                }),
+                string_attr(
+                    "folonet.close_reason",
+                    serde_json::to_value(reason).unwrap_or_default(),
+                ),
+            ],
+            "events": events,
+        })
+    }
+}
+
+// The span of one tcp connection, from the syn of its client to its close.
+// The state machines add an event whenever a side changes its state, the
+// tracker of the connection ends it.
+#[derive(Debug, Clone)]
+pub struct ConnSpan {
+    data: Arc<Mutex<SpanData>>,
+}
+
+impl ConnSpan {
+    pub fn start(service: &str, client: Endpoint, backend: Endpoint, local_port: u16) -> Self {
+        let mut trace_id = [0u8; 16];
+        trace_id[..8].copy_from_slice(&random_u64().to_be_bytes());
+        trace_id[8..].copy_from_slice(&random_u64().to_be_bytes());
+        ConnSpan {
+            data: Arc::new(Mutex::new(SpanData {
+                trace_id,
+                span_id: random_u64().to_be_bytes(),
+                start_ns: unix_ns(),
+                service: service.to_string(),
+                client,
+                backend,
+                local_port,
+                events: vec![],
+            })),
+        }
+    }
+
+    pub fn event(&self, name: String) {
+        let mut data = self.data.lock().unwrap();
+        if data.events.len() < MAX_EVENTS {
+            data.events.push(SpanEvent {
+                ns: unix_ns(),
+                name,
+            });
+        }
+    }
+}
+
+// Exports the spans of closed connections in batches, over otlp/http json,
+// to an http or https collector. A collector that is down or slow loses the
+// batch, not the datapath.
+#[derive(Clone)]
+pub struct Tracer {
+    sink: Sink<Value>,
+}
+
+impl Tracer {
+    pub fn new(cfg: &TracingConfig) -> Result<Self, FolonetError> {
+        check_url(&cfg.endpoint)
+            .map_err(|e| FolonetError::Config(format!("invalid tracing endpoint: {}", e)))?;
+        let cfg = cfg.clone();
+        let sink = Sink::spawn("connection span", |rx| {
+            export_forever(rx, cfg, https_client())
+        });
+        Ok(Tracer { sink })
+    }
+
+    pub fn end(&self, span: &ConnSpan, reason: CloseReason) {
+        let span = span.data.lock().unwrap().to_otlp(unix_ns(), reason);
+        self.sink.send(span);
+    }
+}
+
+fn export_request(service_name: &str, spans: Vec<Value>) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": { "attributes": [string_attr("service.name", service_name)] },
+            "scopeSpans": [{
+                "scope": { "name": "folonet" },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+async fn export(client: &HttpsClient, endpoint: &str, body: Vec<u8>) -> Result<(), String> {
+    let (status, _) = tokio::time::timeout(EXPORT_TIMEOUT, post_json(client, endpoint, body))
+        .await
+        .unwrap_or_else(|_| Err(ClientError::Http("timed out".to_string())))
+        .map_err(|e| e.to_string())?;
+    if !(200..300).contains(&status) {
+        return Err(format!("the collector answered {}", status));
+    }
+    Ok(())
+}
+
+async fn export_forever(mut rx: mpsc::Receiver<Value>, cfg: TracingConfig, client: HttpsClient) {
+    let mut ticker = interval(Duration::from_secs(cfg.flush_secs.max(1)));
+    let mut batch = vec![];
+    loop {
+        tokio::select! {
+            span = rx.recv() => match span {
+                Some(span) => {
+                    batch.push(span);
+                    if batch.len() < cfg.batch_size {
+                        continue;
+                    }
+                }
+                None => return,
+            },
+            _ = ticker.tick() => {}
+        }
+        if batch.is_empty() {
+            continue;
+        }
+
+        let spans = batch.len();
+        let body = export_request(&cfg.service_name, std::mem::take(&mut batch)).to_string();
+        if let Err(e) = export(&client, &cfg.endpoint, body.into_bytes()).await {
+            warn!(
+                "failed to export {} connection spans to {}: {}",
+                spans, cfg.endpoint, e
+            );
+        }
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_otlp_span() {
+        use super::{export_request, ConnSpan};
+        use crate::endpoint::Endpoint;
+        use crate::flow_log::CloseReason;
+
+        let client: Endpoint = "10.0.0.2:40000".parse().unwrap();
+        let backend: Endpoint = "10.0.1.5:8080".parse().unwrap();
+        let span = ConnSpan::start("web", client, backend, 10000);
+        span.event("client SynSent".to_string());
+        span.event("backend Established".to_string());
+        for _ in 0..100 {
+            span.event("client Closed".to_string());
+        }
+
+        let other = ConnSpan::start("web", client, backend, 10001);
+        let data = span.data.lock().unwrap().clone();
+        assert_ne!(data.trace_id, other.data.lock().unwrap().trace_id);
+        assert_eq!(data.events.len(), super::MAX_EVENTS);
+
+        let otlp = data.to_otlp(data.start_ns + 1, CloseReason::IdleTimeout);
+        assert_eq!(otlp["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(otlp["spanId"].as_str().unwrap().len(), 16);
+        assert_eq!(otlp["events"][1]["name"], "backend Established");
+        assert_eq!(
+            otlp["attributes"][2]["value"]["stringValue"],
+            "10.0.1.5:8080"
+        );
+        assert_eq!(
+            otlp["attributes"][4]["value"]["stringValue"],
+            "idle_timeout"
+        );
+
+        let request = export_request("folonet", vec![otlp]);
+        assert_eq!(
+            request["resourceSpans"][0]["scopeSpans"][0]["spans"][0]["name"],
+            "connection"
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use folonet_client::config::{ServiceConfig, UsageExportConfig};
+use log::{info, warn};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::time::{interval, Duration};
+
+use crate::flow_log::FlowRecord;
+use crate::sink::Sink;
+
+const PART_SUFFIX: &str = ".csv.part";
+
+pub const CSV_HEADER: &str = "ts,service,tenant,duration_ms,bytes_in,bytes_out,cold_start";
+
+// a closed connection as billing sees it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageRecord {
+    // unix time in milliseconds when the connection was closed
+    pub ts: u64,
+    pub service: String,
+    // empty when the service has none
+    pub tenant: String,
+    pub duration_ms: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub cold_start: bool,
+}
+
+// quoted when it holds a separator, a quote or a line break
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+impl UsageRecord {
+    pub fn csv_line(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{}",
+            self.ts,
+            csv_field(&self.service),
+            csv_field(&self.tenant),
+            self.duration_ms,
+            self.bytes_in,
+            self.bytes_out,
+            self.cold_start
+        )
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// `usage-<start>.csv.part`, start being unix seconds
+fn parse_part_name(name: &str) -> Option<u64> {
+    name.strip_prefix("usage-")?
+        .strip_suffix(PART_SUFFIX)?
+        .parse()
+        .ok()
+}
+
+fn part_path(dir: &Path, start: u64) -> PathBuf {
+    dir.join(format!("usage-{}{}", start, PART_SUFFIX))
+}
+
+fn run_upload(command: &[String], path: &Path) {
+    let (program, args) = match command.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    match std::process::Command::new(program)
+        .args(args)
+        .arg(path)
+        .status()
+    {
+        Ok(status) if status.success() => info!("uploaded {:?}", path),
+        Ok(status) => warn!("upload of {:?} failed: {}", path, status),
+        Err(e) => warn!("failed to run the upload of {:?}: {}", path, e),
+    }
+}
+
+// the file of the current period
+struct Period {
+    file: File,
+    start: u64,
+}
+
+struct Rotation {
+    dir: PathBuf,
+    rotate_secs: u64,
+    upload_command: Option<Vec<String>>,
+    current: Option<Period>,
+}
+
+impl Rotation {
+    // periods line up with the clock, so the files of several nodes do too
+    fn period_start(&self, now: u64) -> u64 {
+        now - now % self.rotate_secs
+    }
+
+    async fn write(&mut self, line: &str) -> io::Result<()> {
+        self.rotate(unix_secs()).await;
+        if self.current.is_none() {
+            let start = self.period_start(unix_secs());
+            let path = part_path(&self.dir, start);
+            // a process restarted within the period appends to its file
+            let fresh = fs::metadata(&path).await.is_err();
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await?;
+            if fresh {
+                file.write_all(format!("{}\n", CSV_HEADER).as_bytes())
+                    .await?;
+            }
+            self.current = Some(Period { file, start });
+        }
+        let period = self.current.as_mut().unwrap();
+        period
+            .file
+            .write_all(format!("{}\n", line).as_bytes())
+            .await
+    }
+
+    // finish the file of a period that is over
+    async fn rotate(&mut self, now: u64) {
+        let over = self
+            .current
+            .as_ref()
+            .is_some_and(|period| now >= period.start + self.rotate_secs);
+        if !over {
+            return;
+        }
+        let mut period = self.current.take().unwrap();
+        if let Err(e) = period.file.flush().await {
+            warn!("failed to flush the usage file of {}: {}", period.start, e);
+        }
+        drop(period.file);
+        self.finish(period.start).await;
+    }
+
+    // rename the part file of `start` to its final name and upload it
+    async fn finish(&self, start: u64) {
+        let part = part_path(&self.dir, start);
+        let path = self.dir.join(format!("usage-{}.csv", start));
+        if let Err(e) = fs::rename(&part, &path).await {
+            warn!("failed to finish the usage file {:?}: {}", part, e);
+            return;
+        }
+        info!("usage file {:?} complete", path);
+        if let Some(command) = self.upload_command.clone() {
+            tokio::task::spawn_blocking(move || run_upload(&command, &path));
+        }
+    }
+
+    // the part files of periods a previous process did not get to finish
+    async fn finish_leftovers(&self) -> io::Result<()> {
+        let current = self.period_start(unix_secs());
+        let mut entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let start = entry.file_name().to_str().and_then(parse_part_name);
+            if let Some(start) = start.filter(|start| *start < current) {
+                self.finish(start).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Writes the closed connections as csv files, see UsageExportConfig. Each is
+// exported once, by the close that found its flow.
+#[derive(Clone)]
+pub struct UsageExporter {
+    sink: Sink<UsageRecord>,
+    // by service name
+    tenants: Arc<HashMap<String, String>>,
+}
+
+impl UsageExporter {
+    pub async fn new(cfg: &UsageExportConfig, services: &[ServiceConfig]) -> io::Result<Self> {
+        fs::create_dir_all(&cfg.dir).await?;
+        let tenants = services
+            .iter()
+            .filter_map(|service| {
+                let tenant = service.metadata.get(&cfg.tenant_key)?;
+                Some((service.name.clone(), tenant.clone()))
+            })
+            .collect();
+        let mut rotation = Rotation {
+            dir: PathBuf::from(&cfg.dir),
+            rotate_secs: cfg.rotate_secs.max(1),
+            upload_command: cfg.upload_command.clone(),
+            current: None,
+        };
+        rotation.finish_leftovers().await?;
+
+        let sink = Sink::spawn("usage record", |mut rx| async move {
+            let mut tick = interval(Duration::from_secs(1));
+            loop {
+                tokio::select! {
+                    record = rx.recv() => match record {
+                        Some(record) => {
+                            if let Err(e) = rotation.write(&record.csv_line()).await {
+                                warn!("failed to write usage record: {}", e);
+                            }
+                        }
+                        None => break,
+                    },
+                    _ = tick.tick() => rotation.rotate(unix_secs()).await,
+                }
+            }
+        });
+
+        Ok(UsageExporter {
+            sink,
+            tenants: Arc::new(tenants),
+        })
+    }
+
+    pub fn export(&self, record: &FlowRecord) {
+        let usage = UsageRecord {
+            ts: record.ts,
+            service: record.service.clone(),
+            tenant: self
+                .tenants
+                .get(&record.service)
+                .cloned()
+                .unwrap_or_default(),
+            duration_ms: record.duration_ms,
+            bytes_in: record.bytes_in,
+            bytes_out: record.bytes_out,
+            cold_start: record.cold_start,
+        };
+        self.sink.send(usage);
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_csv_line() {
+        use super::{parse_part_name, UsageRecord};
+
+        let record = UsageRecord {
+            ts: 1700000000000,
+            service: "web".to_string(),
+            tenant: "acme, \"inc\"".to_string(),
+            duration_ms: 2500,
+            bytes_in: 120,
+            bytes_out: 4096,
+            cold_start: true,
+        };
+        assert_eq!(
+            record.csv_line(),
+            "1700000000000,web,\"acme, \"\"inc\"\"\",2500,120,4096,true"
+        );
+
+        assert_eq!(
+            parse_part_name("usage-1700000000.csv.part"),
+            Some(1700000000)
+        );
+        assert_eq!(parse_part_name("usage-1700000000.csv"), None);
+        assert_eq!(parse_part_name("flows.csv.part"), None);
+    }
+}
@@ -0,0 +1,696 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::ops::Range;
+
+use folonet_client::config::{GlobalConfig, ServiceConfig, ServiceProtocol};
+use serde::Serialize;
+use toml_edit::{ImDocument, TableLike};
+use yaml_rust2::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust2::scanner::Marker;
+
+use crate::acl::parse_cidr;
+use crate::endpoint::{try_mac_from_string, Endpoint};
+use crate::error::FolonetError;
+use crate::iface_watch::is_pattern;
+use crate::loader::{ConfigFormat, LoadedConfig};
+use crate::logging::LogFilter;
+use crate::ports::{parse_port_range, port_ranges};
+
+// One thing wrong with the config, under the key it was found at, e.g.
+// `services[2].servers[0]`, and in the file and on the line of that key. A
+// warning does not keep the daemon from starting.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigProblem {
+    pub path: String,
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub message: String,
+    pub warning: bool,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.warning {
+            write!(f, "warning: ")?;
+        }
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, "{}:{}: ", file, line)?,
+            (Some(file), None) => write!(f, "{}: ", file)?,
+            (None, Some(line)) => write!(f, "line {}: ", line)?,
+            (None, None) => {}
+        }
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+#[derive(Default)]
+struct Problems(Vec<ConfigProblem>);
+
+impl Problems {
+    fn error(&mut self, path: String, message: String) {
+        self.0.push(ConfigProblem {
+            path,
+            file: None,
+            line: None,
+            message,
+            warning: false,
+        });
+    }
+
+    fn warning(&mut self, path: String, message: String) {
+        self.0.push(ConfigProblem {
+            path,
+            file: None,
+            line: None,
+            message,
+            warning: true,
+        });
+    }
+
+    fn endpoint(&mut self, path: String, endpoint: &str) -> Option<Endpoint> {
+        match endpoint.parse::<Endpoint>() {
+            Ok(e) if e.port == 0 => {
+                self.error(path, format!("endpoint {} has no port", endpoint));
+                None
+            }
+            Ok(e) => Some(e),
+            Err(FolonetError::Config(msg)) => {
+                self.error(path, msg);
+                None
+            }
+            Err(e) => {
+                self.error(path, e.to_string());
+                None
+            }
+        }
+    }
+
+    fn cidr(&mut self, path: String, cidr: &str) {
+        if let Err(e) = parse_cidr(cidr) {
+            self.error(path, format!("invalid ip or cidr {}: {}", cidr, e));
+        }
+    }
+
+    fn servers(&mut self, path: &str, servers: &[String]) {
+        for (i, server) in servers.iter().enumerate() {
+            self.endpoint(format!("{}[{}]", path, i), server);
+        }
+    }
+}
+
+fn check_service(
+    problems: &mut Problems,
+    path: &str,
+    service: &ServiceConfig,
+    names: &mut HashMap<String, String>,
+    endpoints: &mut HashMap<(Endpoint, ServiceProtocol), String>,
+) {
+    if service.name.is_empty() {
+        problems.error(
+            format!("{}.name", path),
+            "a service needs a name".to_string(),
+        );
+    } else if let Some(taken) = names.insert(service.name.clone(), path.to_string()) {
+        problems.error(
+            format!("{}.name", path),
+            format!(
+                "service {} is configured at {} already",
+                service.name, taken
+            ),
+        );
+    }
+
+    let local = problems.endpoint(format!("{}.local_endpoint", path), &service.local_endpoint);
+    let protocols = match local {
+        Some(local) => service
+            .served_protocols()
+            .into_iter()
+            .map(|protocol| (local, protocol))
+            .collect(),
+        None => vec![],
+    };
+    for (local, protocol) in protocols {
+        if let Some(taken) = endpoints.insert((local, protocol), path.to_string()) {
+            problems.error(
+                format!("{}.local_endpoint", path),
+                format!(
+                    "{} is served over {:?} by {} already",
+                    service.local_endpoint, protocol, taken
+                ),
+            );
+        }
+    }
+
+    problems.servers(&format!("{}.servers", path), &service.servers);
+    let mut pools = HashSet::new();
+    for (i, pool) in service.pools.iter().enumerate() {
+        if !pools.insert(&pool.name) {
+            problems.error(
+                format!("{}.pools[{}].name", path, i),
+                format!("pool {} is configured twice", pool.name),
+            );
+        }
+        problems.servers(&format!("{}.pools[{}].servers", path, i), &pool.servers);
+    }
+    let weights: u32 = service.pools.iter().map(|pool| pool.weight).sum();
+    if weights > 100 {
+        problems.error(
+            format!("{}.pools", path),
+            format!("the pool weights add up to {}%, over 100", weights),
+        );
+    }
+    if service.slow_start_secs > 0 && service.pools.is_empty() {
+        problems.error(
+            format!("{}.slow_start_secs", path),
+            "only services with pools ramp up their backends".to_string(),
+        );
+    }
+    for (i, route) in service.http_routes.iter().enumerate() {
+        problems.servers(
+            &format!("{}.http_routes[{}].servers", path, i),
+            &route.servers,
+        );
+    }
+    for (i, route) in service.sni_routes.iter().enumerate() {
+        problems.servers(
+            &format!("{}.sni_routes[{}].servers", path, i),
+            &route.servers,
+        );
+    }
+
+    let udp = service.served_protocols().contains(&ServiceProtocol::Udp);
+    if udp && service.routes_by_name() {
+        problems.error(
+            path.to_string(),
+            "http and sni routes are proxied over tcp, the service is served over udp".to_string(),
+        );
+    }
+    if udp && service.startup_probe.is_some() {
+        problems.error(
+            format!("{}.startup_probe", path),
+            "a startup probe connects over tcp, the service is served over udp".to_string(),
+        );
+    }
+    if let Some(egress_ip) = &service.egress_ip {
+        if egress_ip.parse::<Ipv4Addr>().is_err() {
+            problems.error(
+                format!("{}.egress_ip", path),
+                format!("invalid ip {}", egress_ip),
+            );
+        }
+    }
+    if service.port_quota == Some(0) {
+        problems.error(
+            format!("{}.port_quota", path),
+            "a quota of 0 ports lets no connection through".to_string(),
+        );
+    }
+}
+
+// Everything wrong with `cfg`, not only the first problem, with the
+// interfaces the host has as `host_ifaces`. Nothing is touched, so it runs
+// before any map is filled.
+pub fn validate(cfg: &GlobalConfig, host_ifaces: &[String]) -> Vec<ConfigProblem> {
+    let mut problems = Problems::default();
+
+    let mut names = HashMap::new();
+    let mut endpoints = HashMap::new();
+    for (i, service) in cfg.services.iter().enumerate() {
+        check_service(
+            &mut problems,
+            &format!("services[{}]", i),
+            service,
+            &mut names,
+            &mut endpoints,
+        );
+    }
+
+    let mut ifaces = HashSet::new();
+    for (i, iface) in cfg.interfaces.iter().enumerate() {
+        let path = format!("interfaces[{}]", i);
+        if iface.name.is_empty() {
+            problems.error(
+                format!("{}.name", path),
+                "an interface needs a name".to_string(),
+            );
+        } else if !ifaces.insert(&iface.name) {
+            problems.error(
+                format!("{}.name", path),
+                format!("interface {} is configured twice", iface.name),
+            );
+        } else if !is_pattern(&iface.name) && !host_ifaces.contains(&iface.name) {
+            problems.warning(
+                format!("{}.name", path),
+                format!(
+                    "no interface {} on this host, it is attached once it shows up",
+                    iface.name
+                ),
+            );
+        }
+        for (j, ip) in iface.local_ips.iter().enumerate() {
+            problems.cidr(format!("{}.local_ips[{}]", path, j), ip);
+        }
+        for (j, ip) in iface.exclude_ips.iter().enumerate() {
+            problems.cidr(format!("{}.exclude_ips[{}]", path, j), ip);
+        }
+    }
+
+    for (i, ip_mac) in cfg.ip_mac_list.iter().enumerate() {
+        let path = format!("ip_mac_list[{}]", i);
+        if ip_mac.ip.parse::<Ipv4Addr>().is_err() {
+            problems.error(format!("{}.ip", path), format!("invalid ip {}", ip_mac.ip));
+        }
+        if try_mac_from_string(&ip_mac.mac).is_err() {
+            problems.error(
+                format!("{}.mac", path),
+                format!(
+                    "invalid mac {}, expected six hex bytes like 0a:1b:2c:3d:4e:5f",
+                    ip_mac.mac
+                ),
+            );
+        }
+    }
+
+    for (i, acl) in cfg.acl.iter().enumerate() {
+        let path = format!("acl[{}]", i);
+        problems.endpoint(format!("{}.local_endpoint", path), &acl.local_endpoint);
+        for (j, cidr) in acl.allow.iter().enumerate() {
+            problems.cidr(format!("{}.allow[{}]", path, j), cidr);
+        }
+        for (j, cidr) in acl.deny.iter().enumerate() {
+            problems.cidr(format!("{}.deny[{}]", path, j), cidr);
+        }
+    }
+
+    if !(0.0..=1.0).contains(&cfg.ports.low_free_ratio) {
+        problems.error(
+            "ports.low_free_ratio".to_string(),
+            format!(
+                "{} is not a share between 0 and 1",
+                cfg.ports.low_free_ratio
+            ),
+        );
+    }
+    if cfg.ports.default_quota == Some(0) {
+        problems.error(
+            "ports.default_quota".to_string(),
+            "a quota of 0 ports lets no connection through".to_string(),
+        );
+    }
+    let mut ranges_parse = true;
+    for (i, range) in cfg.ports.ranges.iter().enumerate() {
+        if let Err(FolonetError::Config(msg)) = parse_port_range(range) {
+            problems.error(format!("ports.ranges[{}]", i), msg);
+            ranges_parse = false;
+        }
+    }
+    // overlapping or too many together
+    if let (true, Err(FolonetError::Config(msg))) = (ranges_parse, port_ranges(&cfg.ports)) {
+        problems.error("ports.ranges".to_string(), msg);
+    }
+
+    if let Err(FolonetError::Config(msg)) = cfg.log.filter.parse::<LogFilter>() {
+        problems.error("log.filter".to_string(), msg);
+    }
+
+    problems.0
+}
+
+// a yaml or json node and the line it starts on, counted from 1
+#[derive(Debug)]
+struct Marked {
+    line: usize,
+    node: MarkedNode,
+}
+
+#[derive(Debug)]
+enum MarkedNode {
+    Scalar(String),
+    Seq(Vec<Marked>),
+    // the entries with the lines of their keys, and a key waiting for its
+    // value
+    Map(Vec<(String, usize, Marked)>, Option<(String, usize)>),
+}
+
+// Builds the nodes of a document from the events of the parser, which carry
+// where every one starts.
+#[derive(Default)]
+struct MarkedTree {
+    open: Vec<Marked>,
+    root: Option<Marked>,
+}
+
+impl MarkedTree {
+    fn close(&mut self, done: Marked) {
+        let parent = match self.open.last_mut() {
+            Some(parent) => parent,
+            None => {
+                self.root.get_or_insert(done);
+                return;
+            }
+        };
+        match &mut parent.node {
+            MarkedNode::Seq(items) => items.push(done),
+            MarkedNode::Map(entries, pending) => match pending.take() {
+                Some((key, line)) => entries.push((key, line, done)),
+                None => {
+                    let key = match done.node {
+                        MarkedNode::Scalar(key) => key,
+                        _ => String::new(),
+                    };
+                    *pending = Some((key, done.line));
+                }
+            },
+            MarkedNode::Scalar(_) => {}
+        }
+    }
+}
+
+impl MarkedEventReceiver for MarkedTree {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        let line = mark.line();
+        match event {
+            Event::Scalar(value, ..) => self.close(Marked {
+                line,
+                node: MarkedNode::Scalar(value),
+            }),
+            Event::Alias(..) => self.close(Marked {
+                line,
+                node: MarkedNode::Scalar(String::new()),
+            }),
+            Event::SequenceStart(..) => self.open.push(Marked {
+                line,
+                node: MarkedNode::Seq(vec![]),
+            }),
+            Event::MappingStart(..) => self.open.push(Marked {
+                line,
+                node: MarkedNode::Map(vec![], None),
+            }),
+            Event::SequenceEnd | Event::MappingEnd => {
+                if let Some(done) = self.open.pop() {
+                    self.close(done);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// `key` of `services[2]`, with its index
+fn split_part(part: &str) -> (&str, Option<usize>) {
+    match part.split_once('[') {
+        Some((key, index)) => (key, index.trim_end_matches(']').parse().ok()),
+        None => (part, None),
+    }
+}
+
+fn yaml_line_of(text: &str, path: &str) -> Option<usize> {
+    let mut tree = MarkedTree::default();
+    Parser::new_from_str(text).load(&mut tree, false).ok()?;
+    let mut node = tree.root.as_ref()?;
+    let mut found = None;
+    for part in path.split('.') {
+        let (key, index) = split_part(part);
+        let entries = match &node.node {
+            MarkedNode::Map(entries, _) => entries,
+            _ => break,
+        };
+        let (line, value) = match entries.iter().find(|(k, ..)| k == key) {
+            Some((_, line, value)) => (*line, value),
+            None => break,
+        };
+        found = Some(line);
+        node = value;
+        if let Some(index) = index {
+            let item = match &node.node {
+                MarkedNode::Seq(items) => items.get(index),
+                _ => None,
+            };
+            match item {
+                Some(item) => {
+                    found = Some(item.line);
+                    node = item;
+                }
+                None => break,
+            }
+        }
+    }
+    found
+}
+
+// where a toml walk is at, tables and arrays come in more than one kind
+#[derive(Clone, Copy)]
+enum TomlAt<'a> {
+    Item(&'a toml_edit::Item),
+    Table(&'a toml_edit::Table),
+    Value(&'a toml_edit::Value),
+}
+
+impl<'a> TomlAt<'a> {
+    fn entry(self, key: &str) -> Option<(Option<Range<usize>>, TomlAt<'a>)> {
+        let table: &dyn TableLike = match self {
+            TomlAt::Item(item) => item.as_table_like()?,
+            TomlAt::Table(table) => table,
+            TomlAt::Value(value) => value.as_inline_table()?,
+        };
+        let (k, v) = table.get_key_value(key)?;
+        Some((k.span().or_else(|| v.span()), TomlAt::Item(v)))
+    }
+
+    fn item(self, index: usize) -> Option<(Option<Range<usize>>, TomlAt<'a>)> {
+        let array = match self {
+            TomlAt::Item(toml_edit::Item::ArrayOfTables(tables)) => {
+                let table = tables.get(index)?;
+                return Some((table.span(), TomlAt::Table(table)));
+            }
+            TomlAt::Item(toml_edit::Item::Value(toml_edit::Value::Array(array))) => array,
+            TomlAt::Value(toml_edit::Value::Array(array)) => array,
+            _ => return None,
+        };
+        let value = array.get(index)?;
+        Some((value.span(), TomlAt::Value(value)))
+    }
+}
+
+fn toml_line_of(text: &str, path: &str) -> Option<usize> {
+    let doc = ImDocument::parse(text).ok()?;
+    let line = |span: Option<Range<usize>>| {
+        span.and_then(|span| text.get(..span.start))
+            .map(|before| before.matches('\n').count() + 1)
+    };
+    let mut at = TomlAt::Table(doc.as_table());
+    let mut found = None;
+    for part in path.split('.') {
+        let (key, index) = split_part(part);
+        let (span, value) = match at.entry(key) {
+            Some(entry) => entry,
+            None => break,
+        };
+        found = line(span).or(found);
+        at = value;
+        if let Some(index) = index {
+            let (span, item) = match at.item(index) {
+                Some(item) => item,
+                None => break,
+            };
+            found = line(span).or(found);
+            at = item;
+        }
+    }
+    found
+}
+
+// The line of `path` in the config `text` of `format`, counted from 1, from
+// where its parser found the key. A path not in the file gets the line of
+// the deepest key of it found. Json is read as the yaml it also is.
+pub fn line_of(format: ConfigFormat, text: &str, path: &str) -> Option<usize> {
+    match format {
+        ConfigFormat::Yaml | ConfigFormat::Json => yaml_line_of(text, path),
+        ConfigFormat::Toml => toml_line_of(text, path),
+    }
+}
+
+// Check the loaded config against the interfaces of the host. Any problem
+// but a warning fails with all of them listed, the warnings are left to the
+// caller.
+pub fn check(loaded: &LoadedConfig) -> Result<Vec<ConfigProblem>, FolonetError> {
+    let host_ifaces: Vec<String> = pnet::datalink::interfaces()
+        .into_iter()
+        .map(|iface| iface.name)
+        .collect();
+    let mut problems = validate(&loaded.cfg, &host_ifaces);
+    for problem in problems.iter_mut() {
+        // a key set by an override is in no file
+        if let Some((source, path)) = loaded.locate(&problem.path) {
+            problem.file = Some(source.path.clone());
+            if !path.is_empty() {
+                problem.line = line_of(source.format(), &source.text, &path);
+            }
+        }
+    }
+    let errors: Vec<String> = problems
+        .iter()
+        .filter(|problem| !problem.warning)
+        .map(|problem| format!("  {}", problem))
+        .collect();
+    if !errors.is_empty() {
+        return Err(FolonetError::Config(format!(
+            "{} problems in {}:\n{}",
+            errors.len(),
+            loaded.main.path,
+            errors.join("\n")
+        )));
+    }
+    Ok(problems)
+}
+
+mod test {
+
+    #[test]
+    fn test_validate() {
+        use folonet_client::config::GlobalConfig;
+
+        use super::{line_of, validate};
+        use crate::loader::ConfigFormat;
+
+        let yaml = r#"
+interfaces:
+  - name: eth0
+  - name: eth9
+    local_ips: [10.0.0.300]
+ip_mac_list:
+  - ip: 10.0.1.5
+    mac: 0a:1b:2c:3d:4e
+services:
+  - name: web
+    local_endpoint: 10.0.0.1:80
+    is_tcp: true
+    servers:
+      - 10.0.1.5:8080
+      - 10.0.1.6
+  - name: web
+    local_endpoint: 10.0.0.1:80
+    is_tcp: true
+    servers: []
+    pools:
+      - name: green
+        servers: [10.0.1.7:8080]
+        weight: 70
+      - name: canary
+        servers: [10.0.1.8:8080]
+        weight: 40
+  - name: dns
+    local_endpoint: 10.0.0.1:80
+    servers: []
+ports:
+  low_free_ratio: 1.5
+"#;
+        let cfg: GlobalConfig = serde_yaml::from_str(yaml).unwrap();
+        let host = vec!["lo".to_string(), "eth0".to_string()];
+        let problems: Vec<(String, bool)> = validate(&cfg, &host)
+            .into_iter()
+            .map(|problem| (problem.path, problem.warning))
+            .collect();
+        let expect = |path: &str| (path.to_string(), false);
+        assert_eq!(
+            problems,
+            vec![
+                expect("services[0].servers[1]"),
+                expect("services[1].name"),
+                expect("services[1].local_endpoint"),
+                expect("services[1].pools"),
+                ("interfaces[1].name".to_string(), true),
+                expect("interfaces[1].local_ips[0]"),
+                expect("ip_mac_list[0].mac"),
+                expect("ports.low_free_ratio"),
+            ]
+        );
+
+        let yaml_line = |path| line_of(ConfigFormat::Yaml, yaml, path);
+        assert_eq!(yaml_line("services[0].servers[1]"), Some(15));
+        assert_eq!(yaml_line("services[1].name"), Some(16));
+        assert_eq!(yaml_line("services[1].local_endpoint"), Some(17));
+        assert_eq!(yaml_line("services[1].pools"), Some(20));
+        assert_eq!(yaml_line("interfaces[1].name"), Some(4));
+        assert_eq!(yaml_line("interfaces[1].local_ips[0]"), Some(5));
+        assert_eq!(yaml_line("ip_mac_list[0].mac"), Some(8));
+        assert_eq!(yaml_line("ports.low_free_ratio"), Some(31));
+        assert_eq!(yaml_line("acl[0]"), None);
+        // a key missing from the file gets the line of the deepest one found
+        assert_eq!(yaml_line("services[1].pools[5].weight"), Some(20));
+    }
+
+    #[test]
+    fn test_line_of() {
+        use super::line_of;
+        use crate::loader::ConfigFormat;
+
+        let flow = "services:\n  - {name: web, servers: [10.0.1.5,\n      10.0.1.6]}\n";
+        assert_eq!(
+            line_of(ConfigFormat::Yaml, flow, "services[0].servers[1]"),
+            Some(3)
+        );
+        assert_eq!(
+            line_of(ConfigFormat::Yaml, flow, "services[0].name"),
+            Some(2)
+        );
+
+        let json = r#"{
+  "services": [
+    {
+      "name": "web",
+      "servers": ["10.0.1.5:8080", "10.0.1.6"]
+    }
+  ],
+  "ports": {"low_free_ratio": 1.5}
+}"#;
+        assert_eq!(
+            line_of(ConfigFormat::Json, json, "services[0].name"),
+            Some(4)
+        );
+        assert_eq!(
+            line_of(ConfigFormat::Json, json, "services[0].servers[1]"),
+            Some(5)
+        );
+        assert_eq!(
+            line_of(ConfigFormat::Json, json, "ports.low_free_ratio"),
+            Some(8)
+        );
+
+        let toml = r#"[ports]
+low_free_ratio = 1.5
+
+[[services]]
+name = "web"
+servers = [
+  "10.0.1.5:8080",
+  "10.0.1.6",
+]
+
+[[services]]
+name = "dns"
+pools = [{ name = "green", weight = 70 }]
+"#;
+        assert_eq!(
+            line_of(ConfigFormat::Toml, toml, "ports.low_free_ratio"),
+            Some(2)
+        );
+        assert_eq!(
+            line_of(ConfigFormat::Toml, toml, "services[0].servers[1]"),
+            Some(8)
+        );
+        assert_eq!(
+            line_of(ConfigFormat::Toml, toml, "services[1].name"),
+            Some(12)
+        );
+        assert_eq!(
+            line_of(ConfigFormat::Toml, toml, "services[1].pools[0].weight"),
+            Some(13)
+        );
+        assert_eq!(
+            line_of(ConfigFormat::Toml, toml, "services[1].local_endpoint"),
+            Some(11)
+        );
+    }
+}
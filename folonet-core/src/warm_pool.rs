@@ -0,0 +1,67 @@
+use folonet_client::config::ServiceConfig;
+use folonet_client::provider::Providers;
+use log::warn;
+
+use crate::endpoint::Endpoint;
+
+// the manager may hand out a backend that is already running, so every wanted
+// instance gets a few start_server calls
+const ATTEMPTS_PER_INSTANCE: u32 = 3;
+
+// Start `cfg.min_warm` backends of a cold start service up front. Returns the
+// distinct backends that came up, possibly fewer than asked for.
+pub async fn warm_up(providers: &Providers, cfg: &ServiceConfig) -> Vec<Endpoint> {
+    let wanted = cfg.min_warm as usize;
+    let mut backends = vec![];
+    for _ in 0..cfg.min_warm * ATTEMPTS_PER_INSTANCE {
+        if backends.len() >= wanted {
+            break;
+        }
+        let started = match providers
+            .start_server(cfg.local_endpoint.clone(), &cfg.metadata)
+            .await
+        {
+            Ok(Some(started)) => started,
+            Ok(None) => continue,
+            Err(e) => {
+                // retries are behind us already, the manager is unlikely to recover soon
+                warn!("failed to warm up {}: {}", cfg.name, e);
+                break;
+            }
+        };
+        for server in started.servers.iter() {
+            match server.parse::<Endpoint>() {
+                Ok(backend) => push_distinct(&mut backends, backend, wanted),
+                Err(e) => warn!("invalid backend {} of {}: {}", server, cfg.name, e),
+            }
+        }
+    }
+    backends
+}
+
+fn push_distinct(backends: &mut Vec<Endpoint>, backend: Endpoint, wanted: usize) {
+    if backends.len() < wanted && !backends.contains(&backend) {
+        backends.push(backend);
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_push_distinct() {
+        use crate::endpoint::Endpoint;
+
+        use super::push_distinct;
+
+        let a: Endpoint = "10.0.0.1:80".parse().unwrap();
+        let b: Endpoint = "10.0.0.2:80".parse().unwrap();
+        let c: Endpoint = "10.0.0.3:80".parse().unwrap();
+
+        let mut backends = vec![];
+        push_distinct(&mut backends, a, 2);
+        push_distinct(&mut backends, a, 2);
+        push_distinct(&mut backends, b, 2);
+        push_distinct(&mut backends, c, 2);
+        assert_eq!(backends, vec![a, b]);
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+use tokio::sync::Mutex;
+
+pub trait MsgHandler: Send + Sync + 'static {
+    type MsgType: Send + Sync + 'static + Debug;
+
+    fn handle_message(
+        &mut self,
+        msg: Self::MsgType,
+    ) -> impl std::future::Future<Output = ()> + Send;
+}
+
+pub struct MsgWorker<T>
+where
+    T: MsgHandler,
+{
+    pub handler: Arc<Mutex<T>>,
+    sender: Option<mpsc::Sender<T::MsgType>>,
+    capacity: usize,
+    // messages given up on because the queue was full
+    shed: Arc<AtomicU64>,
+}
+
+// another handle on the same worker
+impl<T> Clone for MsgWorker<T>
+where
+    T: MsgHandler,
+{
+    fn clone(&self) -> Self {
+        MsgWorker {
+            handler: self.handler.clone(),
+            sender: self.sender.clone(),
+            capacity: self.capacity,
+            shed: self.shed.clone(),
+        }
+    }
+}
+
+impl<T> MsgWorker<T>
+where
+    T: MsgHandler,
+{
+    const CHANNEL_SIZE: usize = 102400;
+    pub fn new(msg_handler: T) -> Self {
+        Self::with_capacity(msg_handler, Self::CHANNEL_SIZE)
+    }
+
+    // at most `capacity` messages wait for the handler
+    pub fn with_capacity(msg_handler: T, capacity: usize) -> Self {
+        let mut worker = MsgWorker {
+            handler: Arc::new(Mutex::new(msg_handler)),
+            sender: None,
+            capacity: capacity.max(1),
+            shed: Arc::default(),
+        };
+        worker.listen_async();
+        worker
+    }
+
+    // queue the message unless the queue is full, what is not queued is
+    // counted as shed
+    pub fn try_send(&self, msg: T::MsgType) -> bool {
+        let sent = self
+            .sender
+            .as_ref()
+            .is_some_and(|sender| sender.try_send(msg).is_ok());
+        if !sent {
+            self.shed.fetch_add(1, Ordering::Relaxed);
+        }
+        sent
+    }
+
+    pub fn depth(&self) -> usize {
+        self.sender
+            .as_ref()
+            .map(|sender| self.capacity.saturating_sub(sender.capacity()))
+            .unwrap_or(0)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn shed(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    pub fn msg_sender(&self) -> Option<&mpsc::Sender<T::MsgType>> {
+        self.sender.as_ref()
+    }
+
+    pub fn listen_async(&mut self) {
+        let (tx, mut rx) = mpsc::channel::<T::MsgType>(self.capacity);
+        let handler = self.handler.clone();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    msg = rx.recv() => {
+                        match msg {
+                            Some(msg) => {
+                                let mut handler = handler.lock().await;
+                                handler.handle_message(msg).await;
+                            }
+                            None => break,
+                        }
+                    }
+                }
+            }
+        });
+
+        self.sender.replace(tx);
+    }
+}
+
+mod test {
+
+    #[tokio::test]
+    async fn test_try_send_sheds() {
+        use super::{MsgHandler, MsgWorker};
+
+        struct Sink(Vec<u32>);
+
+        impl MsgHandler for Sink {
+            type MsgType = u32;
+
+            async fn handle_message(&mut self, msg: u32) {
+                self.0.push(msg);
+            }
+        }
+
+        let worker = MsgWorker::with_capacity(Sink(vec![]), 2);
+        // the handler is busy, the messages wait in the queue
+        let handler = worker.handler.clone();
+        let busy = handler.lock().await;
+        assert!(worker.try_send(1));
+        assert!(worker.try_send(2));
+        assert_eq!(worker.depth(), 2);
+        assert!(!worker.try_send(3));
+        assert_eq!(worker.shed(), 1);
+        assert_eq!(worker.capacity(), 2);
+        drop(busy);
+
+        while worker.handler.lock().await.0.len() < 2 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(worker.handler.lock().await.0, vec![1, 2]);
+        assert_eq!(worker.depth(), 0);
+    }
+}
@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+
+use folonet_client::config::SynGraceConfig;
+use folonet_core::{
+    cold_start::{OutcomeStats, PendingConnTracker},
+    endpoint::Endpoint,
+};
+use tokio::time::{Duration, Instant};
+
+#[test]
+fn test_pending_conn_tracker() {
+    let service: Endpoint = "10.0.0.1:80".parse().unwrap();
+    let client1: Endpoint = "10.0.0.2:40000".parse().unwrap();
+    let client2: Endpoint = "10.0.0.3:40000".parse().unwrap();
+
+    let cfg = SynGraceConfig {
+        retries: 2,
+        initial_rto_ms: 1000,
+        grace_ms: 0,
+    };
+    let mut tracker = PendingConnTracker::new(HashMap::from([(service, cfg)]));
+
+    // the last syn of a client goes out 3s after its first one
+    let start = Instant::now();
+    tracker.record_syn(service, client1, start);
+    tracker.record_syn(service, client2, start + Duration::from_secs(2));
+    // a retransmission does not move the first syn
+    tracker.record_syn(service, client1, start + Duration::from_secs(1));
+
+    let stats = tracker.server_ready(&service, start + Duration::from_secs(4));
+    assert_eq!(
+        stats,
+        OutcomeStats {
+            ready_in_time: 1,
+            client_gave_up: 1,
+        }
+    );
+
+    // nobody is waiting anymore
+    let stats = tracker.server_ready(&service, start + Duration::from_secs(5));
+    assert_eq!(stats.ready_in_time + stats.client_gave_up, 2);
+
+    tracker.record_syn(service, client1, start);
+    tracker.server_failed(&service);
+    assert_eq!(tracker.stats().get(&service), Some(&stats));
+}
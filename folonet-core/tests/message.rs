@@ -0,0 +1,79 @@
+use folonet_common::{
+    event::{Event, Packet, PacketFlag},
+    KConnection, Notification,
+};
+use folonet_core::{
+    endpoint::Endpoint,
+    message::{Message, MessageType, PacketMsgType},
+    state::PacketMsg,
+};
+
+fn endpoint(s: &str) -> Endpoint {
+    s.parse().unwrap()
+}
+
+fn notification(from: &str, to: &str, local_in: &str, local_out: &str) -> Notification {
+    Notification {
+        local_in_endpoint: endpoint(local_in).to_k_endpoint(),
+        lcoal_out_endpoint: endpoint(local_out).to_k_endpoint(),
+        connection: KConnection {
+            from: endpoint(from).to_k_endpoint(),
+            to: endpoint(to).to_k_endpoint(),
+        },
+        epoch: 0,
+        event: Event::TcpPacket(Packet {
+            flag: PacketFlag::SYN,
+            ack_seq: 0,
+            seq: 100,
+        }),
+    }
+}
+
+#[test]
+fn test_endpoint_k_endpoint_round_trip() {
+    let e = endpoint("192.168.1.7:8080");
+    assert_eq!(Endpoint::new(e.to_k_endpoint()), e);
+}
+
+#[test]
+fn test_message_from_client() {
+    let n = notification(
+        "10.0.0.2:40000",
+        "10.0.0.9:80",
+        "10.0.0.1:80",
+        "10.0.0.1:10000",
+    );
+    let msg = Message::from_notification(n, true);
+
+    assert_eq!(msg.client, endpoint("10.0.0.2:40000"));
+    assert_eq!(msg.server, endpoint("10.0.0.9:80"));
+    assert_eq!(msg.local_in, endpoint("10.0.0.1:80"));
+    assert_eq!(msg.local_out, endpoint("10.0.0.1:10000"));
+    assert!(matches!(
+        msg.msg_type,
+        MessageType::Packet(PacketMsgType::TCP(_))
+    ));
+
+    let packet_msg = PacketMsg::try_from(&msg).unwrap();
+    assert_eq!(packet_msg.connection(), msg.connection());
+}
+
+#[test]
+fn test_message_from_server() {
+    let n = notification(
+        "10.0.0.9:80",
+        "10.0.0.2:40000",
+        "10.0.0.1:10000",
+        "10.0.0.1:80",
+    );
+    let msg = Message::from_notification(n, false);
+
+    // the message is always told from the client's point of view
+    assert_eq!(msg.client, endpoint("10.0.0.2:40000"));
+    assert_eq!(msg.server, endpoint("10.0.0.9:80"));
+    assert_eq!(msg.local_in, endpoint("10.0.0.1:80"));
+    assert_eq!(msg.local_out, endpoint("10.0.0.1:10000"));
+
+    let packet_msg = PacketMsg::try_from(&msg).unwrap();
+    assert_eq!(packet_msg.connection(), msg.connection());
+}
@@ -0,0 +1,27 @@
+use aya_ebpf::maps::lpm_trie::Key;
+use folonet_common::{
+    acl::{KAclKey, ACL_DENY, ACL_SERVICE_PREFIX_LEN},
+    KConnection,
+};
+
+use crate::{ACL_DEFAULT_MAP, ACL_MAP};
+
+// only services with an acl section have a default action, everything else is let through
+#[inline(always)]
+pub fn is_denied(way: &KConnection) -> bool {
+    let default_action = match unsafe { ACL_DEFAULT_MAP.get(&way.to) } {
+        Some(action) => *action,
+        None => return false,
+    };
+
+    let key = Key::new(
+        ACL_SERVICE_PREFIX_LEN + 32,
+        KAclKey::new(way.to, way.from.ip()),
+    );
+    let action = match ACL_MAP.get(&key) {
+        Some(action) => *action,
+        None => default_action,
+    };
+
+    action == ACL_DENY
+}
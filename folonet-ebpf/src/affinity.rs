@@ -0,0 +1,57 @@
+use folonet_common::{
+    affinity::{KAffinity, KAffinityKey},
+    service::KServiceKey,
+    split::MAX_POOLS,
+    KConnection, KEndpoint,
+};
+
+use crate::{AFFINITY_MAP, AFFINITY_TIMEOUT, SPLIT_MAP};
+
+#[inline(always)]
+fn key_of(declare_way: &KConnection) -> KAffinityKey {
+    KAffinityKey {
+        service: declare_way.to,
+        client_ip: declare_way.from.ip(),
+        _pad: 0,
+    }
+}
+
+// The backend the client of `declare_way` reached the service on last, while
+// it is recent enough and the service still routes to it, `to` or a pool
+// with weight, none otherwise.
+#[inline(always)]
+pub fn backend_of<'a>(
+    service_key: &KServiceKey,
+    declare_way: &KConnection,
+    to: &'a KEndpoint,
+    now: u64,
+) -> Option<&'a KEndpoint> {
+    let timeout_ns = unsafe { AFFINITY_TIMEOUT.get(&declare_way.to) }?;
+    let affinity = unsafe { AFFINITY_MAP.get(&key_of(declare_way)) }?;
+    if !affinity.is_fresh(now, *timeout_ns) {
+        return None;
+    }
+    if affinity.backend == *to {
+        return Some(to);
+    }
+    let split = unsafe { SPLIT_MAP.get(service_key) }?;
+    for pool in 0..MAX_POOLS {
+        if split.weights[pool] > 0 && split.backends[pool] == affinity.backend {
+            return Some(&split.backends[pool]);
+        }
+    }
+    None
+}
+
+// a new connection of the client of `declare_way` went to `backend`
+#[inline(always)]
+pub fn remember(declare_way: &KConnection, backend: &KEndpoint, now: u64) {
+    if unsafe { AFFINITY_TIMEOUT.get(&declare_way.to) }.is_none() {
+        return;
+    }
+    let affinity = KAffinity {
+        backend: *backend,
+        last_ns: now,
+    };
+    let _ = AFFINITY_MAP.insert(&key_of(declare_way), &affinity, 0);
+}
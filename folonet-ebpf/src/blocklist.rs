@@ -0,0 +1,14 @@
+use crate::BLOCKLIST;
+
+// sources blocked by userspace, until the time stored with them
+#[inline(always)]
+pub fn is_blocked(src_ip: u32, now: u64) -> bool {
+    match unsafe { BLOCKLIST.get(&src_ip) } {
+        Some(expires_ns) if *expires_ns > now => true,
+        Some(_) => {
+            let _ = BLOCKLIST.remove(&src_ip);
+            false
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,35 @@
+use folonet_common::{stats::Counter, KColdStart, KConnection};
+
+use crate::{incr_counter, COLD_START_MAP, COLD_START_PENDING};
+
+// a report userspace never cleared, e.g. as it was restarted, stops holding
+// back the syns of its client after this long
+const REPORT_AGAIN_NS: u64 = 5_000_000_000;
+
+// Asks userspace to cold start the service `way` goes to. The client is
+// reported too, so userspace knows who waits for the service, once until the
+// cold start is over: the retransmitted syns of a client reported already
+// need no report of their own.
+#[inline(always)]
+pub fn report(way: &KConnection, is_tcp: bool, now: u64) {
+    if unsafe { COLD_START_PENDING.get(way) }
+        .is_some_and(|reported| now.wrapping_sub(*reported) < REPORT_AGAIN_NS)
+    {
+        incr_counter(Counter::ColdStartDeduped);
+        return;
+    }
+
+    match COLD_START_MAP.reserve::<KColdStart>(0) {
+        Some(mut e) => {
+            e.write(KColdStart {
+                way: *way,
+                is_tcp: is_tcp as u8,
+                _pad: [0; 7],
+            });
+            e.submit(0);
+            // without the entry the next syn is just reported again
+            let _ = COLD_START_PENDING.insert(way, &now, 0);
+        }
+        None => incr_counter(Counter::ColdStartEventLost),
+    }
+}
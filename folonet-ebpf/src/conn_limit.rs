@@ -0,0 +1,37 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use folonet_common::{conn_limit::KConnLimit, KEndpoint};
+
+use crate::{CONN_LIMIT_MAP, CONN_OPENED_MAP};
+
+// the action for a new connection to `service` once it has all the
+// connections it may have open, none while it has room or no limit
+#[inline(always)]
+pub fn reached(service: &KEndpoint) -> Option<u8> {
+    let limit: &KConnLimit = unsafe { CONN_LIMIT_MAP.get(service) }?;
+    let opened = CONN_OPENED_MAP
+        .get_ptr(service)
+        .map(|opened| unsafe { *opened })
+        .unwrap_or(0);
+    if opened.saturating_sub(limit.closed) >= limit.max {
+        return Some(limit.action);
+    }
+    None
+}
+
+// count a connection opened to `service`, services with a limit only
+#[inline(always)]
+pub fn opened(service: &KEndpoint) {
+    if unsafe { CONN_LIMIT_MAP.get(service) }.is_none() {
+        return;
+    }
+    // connections of the service may open on every cpu at once
+    match CONN_OPENED_MAP.get_ptr_mut(service) {
+        Some(opened) => unsafe {
+            AtomicU64::from_ptr(opened).fetch_add(1, Ordering::Relaxed);
+        },
+        None => {
+            let _ = CONN_OPENED_MAP.insert(service, &1, 0);
+        }
+    }
+}
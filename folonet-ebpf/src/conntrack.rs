@@ -0,0 +1,54 @@
+use folonet_common::{
+    nat::{
+        KConnTrack, KNat, CT_CLOSED, CT_ESTABLISHED, CT_FIN_RECEIVED, CT_FIN_SEEN, CT_FIN_SENT,
+        CT_NEW,
+    },
+    KConnection, L4Hdr,
+};
+
+use crate::{syn_flood::is_pure_ack, CONNECTION};
+
+// Moves the kernel state of a connection along with a packet of `way`, in both
+// of its entries. Returns whether the packet may pass: a closed connection
+// only takes what finishes closing it, and a syn opening it again.
+#[inline(always)]
+pub fn track(way: &KConnection, nat: &KNat, l4_hdr: &L4Hdr) -> bool {
+    let ct = nat.ct;
+    let next = if ct.state == CT_CLOSED {
+        if !(l4_hdr.is_syn() && !l4_hdr.is_ack()) {
+            return l4_hdr.is_fin() || l4_hdr.is_rst() || is_pure_ack(l4_hdr);
+        }
+        KConnTrack {
+            reply: ct.reply,
+            ..Default::default()
+        }
+    } else if l4_hdr.is_rst() {
+        KConnTrack {
+            state: CT_CLOSED,
+            ..ct
+        }
+    } else if l4_hdr.is_fin() {
+        let fins = ct.fins | CT_FIN_SENT;
+        let state = if fins == CT_FIN_SENT | CT_FIN_RECEIVED {
+            CT_CLOSED
+        } else {
+            CT_FIN_SEEN
+        };
+        KConnTrack { state, fins, ..ct }
+    } else if ct.state == CT_NEW && ct.reply != 0 {
+        KConnTrack {
+            state: CT_ESTABLISHED,
+            ..ct
+        }
+    } else {
+        return true;
+    };
+
+    if let Some(entry) = CONNECTION.get_ptr_mut(way) {
+        unsafe { (*entry).ct = next };
+    }
+    if let Some(entry) = CONNECTION.get_ptr_mut(&nat.rev_key) {
+        unsafe { (*entry).ct = next.mirror() };
+    }
+    true
+}
@@ -0,0 +1,32 @@
+use aya_ebpf::maps::lpm_trie::Key;
+use folonet_common::{
+    egress::{KSourceKey, SOURCE_IFINDEX_PREFIX_LEN},
+    KEndpoint,
+};
+
+use crate::{EGRESS_IP_MAP, LOCAL_IP_MAP, SOURCE_IP_MAP};
+
+// the source ip of a new connection to `backend`, host byte order: the egress
+// ip of the service, else the ip of the interface on the subnet of the
+// backend, else the first ip of the interface
+#[inline(always)]
+pub fn source_ip(ifidx: u32, service: &KEndpoint, backend: &KEndpoint) -> Option<u32> {
+    if let Some(ip) = unsafe { EGRESS_IP_MAP.get(service) } {
+        return Some(*ip);
+    }
+    interface_ip(ifidx, backend)
+}
+
+// the ip of the interface on the subnet of `to`, else its first ip
+#[inline(always)]
+pub fn interface_ip(ifidx: u32, to: &KEndpoint) -> Option<u32> {
+    let key = Key::new(
+        SOURCE_IFINDEX_PREFIX_LEN + 32,
+        KSourceKey::new(ifidx, to.ip()),
+    );
+    if let Some(ip) = SOURCE_IP_MAP.get(&key) {
+        return Some(*ip);
+    }
+
+    unsafe { LOCAL_IP_MAP.get(&ifidx) }.copied()
+}
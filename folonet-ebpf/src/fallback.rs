@@ -0,0 +1,51 @@
+use aya_ebpf::bindings::xdp_action;
+use folonet_common::{config::KConfig, service::KServiceKey, stats::Counter, KConnection};
+use network_types::ip::{IpProto, Ipv4Hdr};
+
+use crate::{frag::IP_MF, incr_counter, CONNECTION, FALLBACK_FLOWS, SERVER_MAP};
+
+// the length of the ip header, with its options
+#[inline(always)]
+pub fn ip_hdr_len(iphdr: *const Ipv4Hdr) -> usize {
+    (unsafe { (*iphdr).ihl() } & 0xf) as usize * 4
+}
+
+// the rewrites expect a 20 byte ip header
+#[inline(always)]
+fn has_options(iphdr: *const Ipv4Hdr) -> bool {
+    ip_hdr_len(iphdr) != Ipv4Hdr::LEN
+}
+
+// a packet opening a flow the xdp program cannot rewrite: its ip header has
+// options, or it is the first fragment of a datagram whose later fragments
+// have no ports to be nat'd by
+#[inline(always)]
+fn unhandled(iphdr: *const Ipv4Hdr) -> bool {
+    has_options(iphdr) || u16::from_be(unsafe { (*iphdr).frag_off }) & IP_MF != 0
+}
+
+// Hands the packet to the host stack, where the userspace proxy takes the
+// flow, when its flow fell back before or it opens a new flow of a service
+// that cannot be rewritten. The other packets with ip options go to the host
+// stack as well, none for the packets the xdp program goes on with.
+#[inline(always)]
+pub fn action(cfg: Option<&KConfig>, iphdr: *const Ipv4Hdr, way: &KConnection) -> Option<u32> {
+    let pass = has_options(iphdr).then_some(xdp_action::XDP_PASS);
+    if !cfg.is_some_and(|cfg| cfg.fallback != 0) {
+        return pass;
+    }
+
+    if unsafe { FALLBACK_FLOWS.get(way) }.is_none() {
+        let is_tcp = matches!(unsafe { (*iphdr).proto }, IpProto::Tcp);
+        if !unhandled(iphdr)
+            || unsafe { CONNECTION.get(way) }.is_some()
+            || unsafe { SERVER_MAP.get(&KServiceKey::new(way.to, is_tcp)) }.is_none()
+        {
+            return pass;
+        }
+        // the flow still falls back with this packet if the map is full
+        let _ = FALLBACK_FLOWS.insert(way, &1, 0);
+    }
+    incr_counter(Counter::FallenBack);
+    Some(xdp_action::XDP_PASS)
+}
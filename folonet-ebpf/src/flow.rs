@@ -0,0 +1,100 @@
+use folonet_common::{
+    flow::{KEviction, KFlow, FLOW_NOTIFIED_EVICTED},
+    latency::{latency_bucket, KHandshakeKey},
+    nat::KNat,
+    stats::Counter,
+    KConnection, L4Hdr,
+};
+
+use crate::{incr_counter, EVICTED, FLOW_MAP, HANDSHAKE_LATENCY};
+
+#[inline(always)]
+pub fn start_flow(declare_way: &KConnection, nat: &KNat, generation: u32, now: u64) {
+    let flow = KFlow {
+        start_ns: now,
+        last_ns: now,
+        bytes_in: 0,
+        bytes_out: 0,
+        sampled: 0,
+        notified: 0,
+        backend_way: nat.rev_key,
+        generation,
+        _pad: 0,
+        syn_ack_ns: 0,
+    };
+    let _ = FLOW_MAP.insert(declare_way, &flow, 0);
+}
+
+// the flow is keyed by the client side way, which is where the return entry
+// of a packet from the backend points to
+#[inline(always)]
+pub fn account_flow(declare_way: &KConnection, nat: &KNat, bytes: u64, now: u64) {
+    if let Some(flow) = FLOW_MAP.get_ptr_mut(declare_way) {
+        unsafe {
+            (*flow).bytes_in += bytes;
+            (*flow).last_ns = now;
+        }
+    } else if let Some(flow) = FLOW_MAP.get_ptr_mut(&nat.rev_key) {
+        unsafe {
+            (*flow).bytes_out += bytes;
+            (*flow).last_ns = now;
+        }
+    }
+}
+
+// The first syn-ack of the backend, `declare_way` being backend ->
+// local_out: the time since the syn that opened the flow goes to
+// HANDSHAKE_LATENCY under the backend. A retransmitted one is not counted.
+#[inline(always)]
+pub fn account_syn_ack(declare_way: &KConnection, nat: &KNat, l4_hdr: &L4Hdr, now: u64) {
+    if !(l4_hdr.is_syn() && l4_hdr.is_ack()) {
+        return;
+    }
+    let flow = match FLOW_MAP.get_ptr_mut(&nat.rev_key) {
+        Some(flow) => flow,
+        None => return,
+    };
+    if unsafe { (*flow).syn_ack_ns } != 0 {
+        return;
+    }
+    let start_ns = unsafe {
+        (*flow).syn_ack_ns = now;
+        (*flow).start_ns
+    };
+
+    let key = KHandshakeKey {
+        backend: declare_way.from,
+        bucket: latency_bucket(now.saturating_sub(start_ns)),
+        _pad: 0,
+    };
+    if let Some(count) = HANDSHAKE_LATENCY.get_ptr_mut(&key) {
+        unsafe { *count += 1 };
+    } else {
+        let _ = HANDSHAKE_LATENCY.insert(&key, &1, 0);
+    }
+}
+
+// A client packet without nat entries whose flow is still there belongs to a
+// connection evicted from CONNECTION, userspace only closes a connection
+// after forgetting its flow. Userspace hears of it once, to release the local
+// port; a lost notification is left to the idle sweep or the reconciliation.
+#[inline(always)]
+pub fn notify_evicted(declare_way: &KConnection) {
+    let flow = match FLOW_MAP.get_ptr_mut(declare_way) {
+        Some(flow) => flow,
+        None => return,
+    };
+    if unsafe { (*flow).notified } & FLOW_NOTIFIED_EVICTED != 0 {
+        return;
+    }
+    unsafe { (*flow).notified |= FLOW_NOTIFIED_EVICTED };
+    incr_counter(Counter::ConnectionEvicted);
+
+    if let Some(mut e) = EVICTED.reserve::<KEviction>(0) {
+        e.write(KEviction {
+            client_way: *declare_way,
+            backend_way: unsafe { (*flow).backend_way },
+        });
+        e.submit(0);
+    }
+}
@@ -0,0 +1,35 @@
+use aya_ebpf::bindings::xdp_action;
+use folonet_common::{config::FRAG_POLICY_DROP, stats::Counter};
+use network_types::ip::Ipv4Hdr;
+
+use crate::{incr_counter, CONFIG};
+
+pub const IP_MF: u16 = 0x2000;
+const IP_OFFSET_MASK: u16 = 0x1fff;
+
+// The first fragment carries the l4 header and is nat'd like any packet, the
+// checksum fixups stay right as they only depend on the rewritten fields.
+// Later fragments have no ports to look the connection up by, they get the
+// configured policy instead, or go to the host stack to be reassembled for the
+// fallback proxy.
+#[inline(always)]
+pub fn later_fragment_action(iphdr: *const Ipv4Hdr) -> Option<u32> {
+    let frag_off = u16::from_be(unsafe { (*iphdr).frag_off });
+    if frag_off & IP_OFFSET_MASK == 0 {
+        if frag_off & IP_MF != 0 {
+            incr_counter(Counter::FragFirst);
+        }
+        return None;
+    }
+
+    if CONFIG
+        .get(0)
+        .is_some_and(|cfg| cfg.frag_policy == FRAG_POLICY_DROP && cfg.fallback == 0)
+    {
+        incr_counter(Counter::FragDropped);
+        Some(xdp_action::XDP_DROP)
+    } else {
+        incr_counter(Counter::FragPassed);
+        Some(xdp_action::XDP_PASS)
+    }
+}
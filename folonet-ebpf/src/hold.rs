@@ -0,0 +1,98 @@
+use aya_ebpf::{bindings::xdp_action, programs::XdpContext};
+use folonet_common::{
+    config::KConfig,
+    stats::Counter,
+    syncookie::{cookie_bucket, syn_cookie, KHeld},
+    KConnection, L4Hdr,
+};
+
+use crate::{
+    incr_counter,
+    syn_flood::is_pure_syn,
+    synth::{rewrite_tcp, TcpReply, TCP_FLAG_ACK, TCP_FLAG_RST, TCP_FLAG_SYN},
+    HELD_CONN, HOLD_MAP,
+};
+
+#[inline(always)]
+pub fn held(way: &KConnection) -> Option<KHeld> {
+    unsafe { HELD_CONN.get(way) }.copied()
+}
+
+// answer the syn of a cold starting service ourselves, with a zero window the
+// client waits for the service instead of timing out its connect
+#[inline(always)]
+pub fn hold_syn(
+    ctx: &XdpContext,
+    cfg: &KConfig,
+    way: &KConnection,
+    l4_hdr: &L4Hdr,
+    now: u64,
+) -> Result<Option<u32>, ()> {
+    if !is_pure_syn(l4_hdr) || unsafe { HOLD_MAP.get(&way.to) }.is_none() {
+        return Ok(None);
+    }
+
+    // a retransmitted syn keeps its first isn and start time
+    let h = match held(way) {
+        Some(h) if h.client_isn == l4_hdr.get_seq() => h,
+        _ => {
+            let h = KHeld {
+                client_isn: l4_hdr.get_seq(),
+                cookie: syn_cookie(way, cfg.syn_cookie_secret, cookie_bucket(now)),
+                start_ns: now,
+            };
+            HELD_CONN.insert(way, &h, 0).map_err(|_| ())?;
+            incr_counter(Counter::HandshakeHeld);
+            h
+        }
+    };
+
+    let reply = TcpReply {
+        seq: h.cookie,
+        ack_seq: h.client_isn.wrapping_add(1),
+        flags: TCP_FLAG_SYN | TCP_FLAG_ACK,
+        window: 0,
+    };
+    rewrite_tcp(ctx, &reply, true)?;
+    Ok(Some(xdp_action::XDP_TX))
+}
+
+// the service is still cold starting: keep answering window probes with a
+// zero window, and reset the client once it waited too long or gave up
+#[inline(always)]
+pub fn keep_held(
+    ctx: &XdpContext,
+    way: &KConnection,
+    h: &KHeld,
+    l4_hdr: &L4Hdr,
+    now: u64,
+) -> Result<u32, ()> {
+    if l4_hdr.is_rst() {
+        let _ = HELD_CONN.remove(way);
+        return Ok(xdp_action::XDP_DROP);
+    }
+
+    let timeout_ns = unsafe { HOLD_MAP.get(&way.to) }.copied().unwrap_or(0);
+    let expired = now.saturating_sub(h.start_ns) > timeout_ns;
+
+    let mut reply = TcpReply {
+        seq: h.cookie.wrapping_add(1),
+        ack_seq: h.client_isn.wrapping_add(1),
+        flags: TCP_FLAG_ACK,
+        window: 0,
+    };
+
+    if expired || l4_hdr.is_fin() {
+        let _ = HELD_CONN.remove(way);
+        if expired {
+            incr_counter(Counter::HandshakeHoldExpired);
+        }
+        reply.flags = TCP_FLAG_RST | TCP_FLAG_ACK;
+    } else if l4_hdr.get_seq() == reply.ack_seq {
+        // the ack finishing the handshake needs no answer, only probes do
+        return Ok(xdp_action::XDP_DROP);
+    }
+
+    rewrite_tcp(ctx, &reply, true)?;
+    Ok(xdp_action::XDP_TX)
+}
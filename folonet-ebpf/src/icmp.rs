@@ -0,0 +1,106 @@
+use aya_ebpf::{bindings::xdp_action, helpers::bpf_csum_diff, programs::XdpContext};
+use folonet_common::{csum_fold_helper, stats::Counter, KConnection, KEndpoint};
+use network_types::{
+    eth::EthHdr,
+    ip::{IpProto, Ipv4Hdr},
+};
+
+use crate::{incr_counter, ptr_at, rewrite_macs, CONNECTION};
+
+const ICMP_DEST_UNREACH: u8 = 3;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMP_HDR_LEN: usize = 8;
+const ICMP_CHECK_OFFSET: usize = 2;
+
+const INNER_IP_OFFSET: usize = EthHdr::LEN + Ipv4Hdr::LEN + ICMP_HDR_LEN;
+// the inner header is the one we sent, so it has no options either
+const INNER_L4_OFFSET: usize = INNER_IP_OFFSET + Ipv4Hdr::LEN;
+
+// the checksum `csum` once the 4 bytes `old` became `new`
+#[inline(always)]
+fn csum_replace(csum: u16, old: u32, new: u32) -> u16 {
+    let mut old = old;
+    let mut new = new;
+    let diff = unsafe { bpf_csum_diff(&mut old, 4, &mut new, 4, !(csum) as u32) };
+    csum_fold_helper(diff as u64)
+}
+
+// An icmp error quotes the headers of the packet that caused it, and those
+// carry the nat'd addresses. Rewrite the quote and the outer addresses so
+// the error reaches the other side of the connection as if it came straight
+// from its peer. Works both ways, the quoted packet's reverse way is the key
+// of the nat entry to apply.
+#[inline(always)]
+pub fn translate_error(
+    ctx: &XdpContext,
+    ethhdr: *mut EthHdr,
+    iphdr: *mut Ipv4Hdr,
+) -> Result<u32, ()> {
+    let icmp_type: *const u8 = ptr_at(ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
+    match unsafe { *icmp_type } {
+        ICMP_DEST_UNREACH | ICMP_TIME_EXCEEDED => {}
+        _ => return Ok(xdp_action::XDP_PASS),
+    }
+
+    let inner_ip: *mut Ipv4Hdr = ptr_at(ctx, INNER_IP_OFFSET)?;
+    match unsafe { (*inner_ip).proto } {
+        IpProto::Tcp | IpProto::Udp => {}
+        _ => return Ok(xdp_action::XDP_PASS),
+    }
+    // source and destination port of the quoted tcp or udp header
+    let inner_ports: *mut u32 = ptr_at(ctx, INNER_L4_OFFSET)?;
+    let (src_port, dst_port) = unsafe {
+        let ports = *(inner_ports as *const [u16; 2]);
+        (ports[0], ports[1])
+    };
+
+    let key = KConnection {
+        from: KEndpoint::new(unsafe { (*inner_ip).dst_addr }, dst_port),
+        to: KEndpoint::new(unsafe { (*inner_ip).src_addr }, src_port),
+    };
+    let nat = match unsafe { CONNECTION.get(&key) } {
+        Some(nat) => nat,
+        None => return Ok(xdp_action::XDP_PASS),
+    };
+    // the quoted packet as the peer sent it: the reverse of where ours goes
+    let way = nat.fwd.way;
+    let icmp_check: *mut u16 = ptr_at(ctx, EthHdr::LEN + Ipv4Hdr::LEN + ICMP_CHECK_OFFSET)?;
+
+    unsafe {
+        // ttl, protocol and checksum of the quoted ip header
+        let inner_word: *mut u32 = ptr_at(ctx, INNER_IP_OFFSET + 8)?;
+        let old_inner_word = *inner_word;
+
+        let mut icmp_csum = *icmp_check;
+        let mut inner_csum = (*inner_ip).check;
+
+        inner_csum = csum_replace(inner_csum, (*inner_ip).src_addr, way.to.ip());
+        icmp_csum = csum_replace(icmp_csum, (*inner_ip).src_addr, way.to.ip());
+        (*inner_ip).src_addr = way.to.ip();
+
+        inner_csum = csum_replace(inner_csum, (*inner_ip).dst_addr, way.from.ip());
+        icmp_csum = csum_replace(icmp_csum, (*inner_ip).dst_addr, way.from.ip());
+        (*inner_ip).dst_addr = way.from.ip();
+
+        (*inner_ip).check = inner_csum;
+        icmp_csum = csum_replace(icmp_csum, old_inner_word, *inner_word);
+
+        // laid out as in the packet, source port first
+        let new_ports: [u16; 2] = [way.to.port(), way.from.port()];
+        let new_ports = *(new_ports.as_ptr() as *const u32);
+        icmp_csum = csum_replace(icmp_csum, *inner_ports, new_ports);
+        *inner_ports = new_ports;
+        *icmp_check = icmp_csum;
+
+        let mut ip_csum = (*iphdr).check;
+        ip_csum = csum_replace(ip_csum, (*iphdr).src_addr, way.from.ip());
+        ip_csum = csum_replace(ip_csum, (*iphdr).dst_addr, way.to.ip());
+        (*iphdr).src_addr = way.from.ip();
+        (*iphdr).dst_addr = way.to.ip();
+        (*iphdr).check = ip_csum;
+    }
+
+    rewrite_macs(ethhdr, way.to.ip(), nat.fwd.mac_policy);
+    incr_counter(Counter::IcmpTranslated);
+    Ok(xdp_action::XDP_TX)
+}
@@ -0,0 +1,39 @@
+use aya_ebpf::bindings::xdp_action;
+use folonet_common::stats::KIfaceStats;
+
+use crate::IFACE_STATS;
+
+#[inline(always)]
+fn stats_of(ifindex: u32) -> Option<*mut KIfaceStats> {
+    if let Some(stats) = IFACE_STATS.get_ptr_mut(&ifindex) {
+        return Some(stats);
+    }
+    let _ = IFACE_STATS.insert(&ifindex, &KIfaceStats::default(), 0);
+    IFACE_STATS.get_ptr_mut(&ifindex)
+}
+
+// the packet belongs to a connection with a nat entry
+#[inline(always)]
+pub fn matched(ifindex: u32) {
+    if let Some(stats) = stats_of(ifindex) {
+        unsafe { (*stats).matched += 1 };
+    }
+}
+
+#[inline(always)]
+pub fn record(ifindex: u32, action: u32) {
+    let stats = match stats_of(ifindex) {
+        Some(stats) => stats,
+        None => return,
+    };
+    unsafe {
+        (*stats).rx += 1;
+        match action {
+            xdp_action::XDP_TX => (*stats).tx += 1,
+            xdp_action::XDP_REDIRECT => (*stats).redirected += 1,
+            xdp_action::XDP_PASS => (*stats).passed += 1,
+            xdp_action::XDP_DROP => (*stats).dropped += 1,
+            _ => (*stats).aborted += 1,
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use aya_ebpf::helpers::{bpf_get_prandom_u32, bpf_ktime_get_ns};
+use folonet_common::latency::{latency_bucket, KLatencyKey};
+
+use crate::{CONFIG, LATENCY};
+
+// when the packet is one of the sampled, the time its processing started
+#[inline(always)]
+pub fn sample_start() -> Option<u64> {
+    let one_in = CONFIG.get(0).map(|cfg| cfg.latency_sample).unwrap_or(0);
+    if one_in == 0 || unsafe { bpf_get_prandom_u32() } % one_in != 0 {
+        return None;
+    }
+    Some(unsafe { bpf_ktime_get_ns() })
+}
+
+#[inline(always)]
+pub fn record(ifindex: u32, start_ns: u64) {
+    let elapsed = unsafe { bpf_ktime_get_ns() }.saturating_sub(start_ns);
+    let key = KLatencyKey {
+        ifindex,
+        bucket: latency_bucket(elapsed),
+    };
+    if let Some(count) = LATENCY.get_ptr_mut(&key) {
+        unsafe { *count += 1 };
+    } else {
+        let _ = LATENCY.insert(&key, &1, 0);
+    }
+}
@@ -0,0 +1,34 @@
+use folonet_common::{load::KServiceLoad, KConnection, KEndpoint};
+
+use crate::SERVICE_LOAD;
+
+#[inline(always)]
+pub fn conn_opened(service: &KEndpoint) {
+    if let Some(load) = SERVICE_LOAD.get_ptr_mut(service) {
+        unsafe { (*load).conns_opened += 1 };
+    } else {
+        let load = KServiceLoad {
+            conns_opened: 1,
+            packets: 0,
+            bytes: 0,
+        };
+        let _ = SERVICE_LOAD.insert(service, &load, 0);
+    }
+}
+
+// packets from the client are addressed to the service, the ones from the
+// backend leave from it
+#[inline(always)]
+pub fn account_packet(declare_way: &KConnection, output_way: &KConnection, bytes: u64) {
+    let load = match SERVICE_LOAD.get_ptr_mut(&declare_way.to) {
+        Some(load) => load,
+        None => match SERVICE_LOAD.get_ptr_mut(&output_way.from) {
+            Some(load) => load,
+            None => return,
+        },
+    };
+    unsafe {
+        (*load).packets += 1;
+        (*load).bytes += bytes;
+    }
+}
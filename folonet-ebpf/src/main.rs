@@ -2,31 +2,76 @@
 #![no_main]
 
 use aya_ebpf::{
-    bindings::xdp_action,
-    helpers::bpf_csum_diff,
+    bindings::{xdp_action, BPF_F_NO_PREALLOC},
+    helpers::{bpf_csum_diff, bpf_ktime_get_ns},
     macros::{map, xdp},
-    maps::{HashMap, Queue, RingBuf, Stack},
+    maps::{
+        Array, HashMap, LpmTrie, LruHashMap, PerCpuArray, PerCpuHashMap, Queue, RingBuf, Stack,
+        XskMap,
+    },
     programs::XdpContext,
 };
 
 use aya_log_ebpf::{debug, info, warn};
-use core::{
-    hash::Hash,
-    mem::{self, offset_of},
-    ptr::copy,
-};
+use core::{hash::Hash, mem::offset_of, ptr::copy};
 use folonet_common::{
-    csum_fold_helper, event::Event, BiPort, KConnection, KEndpoint, L4Hdr, Mac, Notification,
+    acl::KAclKey,
+    affinity::{KAffinity, KAffinityKey},
+    config::{KConfig, KHalfOpen, SYN_FLOOD_ACTION_COOKIE},
+    conn_limit::{KConnLimit, CONN_LIMIT_RST},
+    csum_fold_helper,
+    egress::KSourceKey,
+    event::Event,
+    flow::KFlow,
+    latency::{KHandshakeKey, KLatencyKey, LATENCY_BUCKETS},
+    load::KServiceLoad,
+    nat::{KNat, KRewrite, MAC_POLICY_BOUNCE, MAC_POLICY_KEEP},
+    pcap::{KPcapFilter, PCAP_AFTER, PCAP_BEFORE},
+    ports::KPortQuota,
+    proxy_v2::KProxyV2,
+    service::KServiceKey,
+    split::KSplit,
+    stats::{Counter, KIfaceStats, COUNTER_NUM},
+    syncookie::{KHeld, KSynProxy},
+    BiPort, KConnection, KEndpoint, L4Hdr, Mac, Notification, NotificationFrame, PacketBounds,
     PORTS_QUEUE_SIZE,
 };
 use network_types::{
     eth::{EthHdr, EtherType},
     ip::{IpProto, Ipv4Hdr},
     tcp::TcpHdr,
-    udp::UdpHdr,
 };
 
+mod acl;
+mod affinity;
+mod blocklist;
+mod cold_start;
+mod conn_limit;
+mod conntrack;
+mod egress;
+mod fallback;
+mod flow;
+mod frag;
+mod hold;
+mod icmp;
+mod iface;
+mod latency;
+mod load;
 mod maps;
+mod nat;
+mod notify;
+mod outbound;
+mod pcap;
+mod ports;
+mod proxy_v2;
+mod reject;
+mod sample;
+mod split;
+mod syn_flood;
+mod synth;
+mod toa;
+mod unknown;
+mod xsk;
 
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
@@ -35,49 +80,232 @@ fn panic(_info: &core::panic::PanicInfo) -> ! {
 
 #[xdp]
 pub fn folonet(ctx: XdpContext) -> u32 {
-    match try_xdp_firewall(ctx) {
+    let ifindex = unsafe { (*ctx.ctx).ingress_ifindex };
+    let started = latency::sample_start();
+    let action = match try_xdp_firewall(ctx) {
         Ok(ret) => ret,
         Err(_) => xdp_action::XDP_ABORTED,
+    };
+    if let Some(start_ns) = started {
+        latency::record(ifindex, start_ns);
     }
+    iface::record(ifindex, action);
+    action
+}
+
+// the bytes of the packet of `ctx`, as long as its length does not change
+#[inline(always)]
+fn packet(ctx: &XdpContext) -> PacketBounds<'_> {
+    unsafe { PacketBounds::new(ctx.data(), ctx.data_end()) }
 }
 
 #[inline(always)]
 fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*mut T, ()> {
-    let start_addr = ctx.data();
+    packet(ctx).ptr_at(offset).map(|ptr| ptr.as_ptr()).ok_or(())
+}
 
-    if start_addr + offset + mem::size_of::<T>() > ctx.data_end() {
-        return Err(());
-    }
+// The connection state below is pinned, a new folonet process takes it over
+// together with the xdp links, see pin.rs in folonet-core. Userspace sizes
+// most maps from its limits config at load time, the sizes below are only
+// what a loader without one gets.
 
-    Ok((start_addr + offset) as *mut T)
-}
+// two entries per connection
+#[map]
+static CONNECTION: LruHashMap<KConnection, KNat> = LruHashMap::pinned(1024, 0);
+
+#[map]
+static SERVER_MAP: HashMap<KServiceKey, KEndpoint> = HashMap::pinned(1024, 0);
+
+// the pools of the services splitting their new connections
+#[map]
+static SPLIT_MAP: HashMap<KServiceKey, KSplit> = HashMap::with_max_entries(1024, 0);
+
+// how long a client of a service keeps to its backend since its last
+// connection, for the services with affinity
+#[map]
+static AFFINITY_TIMEOUT: HashMap<KEndpoint, u64> = HashMap::with_max_entries(1024, 0);
 
 #[map]
-static CONNECTION: HashMap<KConnection, KConnection> = HashMap::with_max_entries(1024, 0);
+static AFFINITY_MAP: LruHashMap<KAffinityKey, KAffinity> = LruHashMap::pinned(65536, 0);
 
+// services being removed: their open connections go on, new ones are not
+// routed until userspace is done with the removal
 #[map]
-static SERVER_MAP: HashMap<KEndpoint, KEndpoint> = HashMap::with_max_entries(1024, 0);
+static DRAINING_MAP: HashMap<KEndpoint, u8> = HashMap::with_max_entries(1024, 0);
 
+// bumped by userspace every time it installs a backend for a service, packet
+// notifications carry it so userspace can order them against cold starts
 #[map]
-static IP_MAC_MAP: HashMap<u32, Mac> = HashMap::with_max_entries(1024, 0);
+static SERVICE_EPOCH: HashMap<KEndpoint, u32> = HashMap::with_max_entries(1024, 0);
+
+// pinned too, so `folonet maps` can look up the learned macs offline
+#[map]
+static IP_MAC_MAP: HashMap<u32, Mac> = HashMap::pinned(1024, 0);
+
+// the MAP_SCHEMA_VERSION of the pinned maps, only ever written by userspace
+#[map]
+static MAP_SCHEMA: Array<u32> = Array::pinned(1, 0);
 
 #[map]
 static PACKET_EVENT: RingBuf = RingBuf::with_byte_size(256 * 1024 * 10, 0);
 
 #[map]
-static SERVICE_PORTS: Queue<u16> = Queue::with_max_entries(PORTS_QUEUE_SIZE, 0);
+static SERVICE_PORTS: Queue<u16> = Queue::pinned(PORTS_QUEUE_SIZE, 0);
+
+// service -> how many of SERVICE_PORTS it may hold
+#[map]
+static PORT_QUOTA_MAP: HashMap<KEndpoint, KPortQuota> = HashMap::with_max_entries(1024, 0);
+
+// service -> ports taken for it so far, services with a quota only
+#[map]
+static PORT_TAKEN_MAP: HashMap<KEndpoint, u64> = HashMap::with_max_entries(1024, 0);
 
+// service -> how many connections it may have open
+#[map]
+static CONN_LIMIT_MAP: HashMap<KEndpoint, KConnLimit> = HashMap::with_max_entries(1024, 0);
+
+// service -> connections opened to it so far, services with a limit only
+#[map]
+static CONN_OPENED_MAP: HashMap<KEndpoint, u64> = HashMap::with_max_entries(1024, 0);
+
+// ifindex -> the first local ip of the interface
 #[map]
 static LOCAL_IP_MAP: HashMap<u32, u32> = HashMap::with_max_entries(10, 0);
 
+// the local ip of an interface on the subnet of a backend
+#[map]
+static SOURCE_IP_MAP: LpmTrie<KSourceKey, u32> = LpmTrie::with_max_entries(10, BPF_F_NO_PREALLOC);
+
+// service -> the local ip all its connections leave from
+#[map]
+static EGRESS_IP_MAP: HashMap<KEndpoint, u32> = HashMap::with_max_entries(1024, 0);
+
+// the ips of the backends, network byte order, see outbound
+#[map]
+static BACKEND_IPS: HashMap<u32, u8> = HashMap::with_max_entries(1024, 0);
+
+// services proxied by userspace, which routes them by the http host or the
+// tls server name
+#[map]
+static PROXIED: HashMap<KEndpoint, u8> = HashMap::with_max_entries(1024, 0);
+
+// the af_xdp sockets of the workers, by rx queue
+// services whose backends get a PROXY protocol v2 header in front of the
+// data of every connection
+#[map]
+static PROXY_V2_MAP: HashMap<KEndpoint, u8> = HashMap::with_max_entries(1024, 0);
+
+#[map]
+static PROXY_V2_CONN: LruHashMap<KConnection, KProxyV2> = LruHashMap::pinned(65536, 0);
+
+// services whose backends get the client address in a TOA option of the syn
+#[map]
+static TOA_MAP: HashMap<KEndpoint, u8> = HashMap::with_max_entries(1024, 0);
+
+#[map]
+static XSK_MAP: XskMap = XskMap::with_max_entries(64, 0);
+
+// flows handed to the userspace fallback proxy, keyed by their client way
+#[map]
+static FALLBACK_FLOWS: LruHashMap<KConnection, u8> = LruHashMap::with_max_entries(1024, 0);
+
+// services whose cold start failed -> until when their new connections are
+// rejected in ns, written by userspace only when rejects are on
+#[map]
+static COLD_START_FAILED: HashMap<KEndpoint, u64> = HashMap::with_max_entries(1024, 0);
+
+// client ways whose cold start was reported -> when in ns, cleared by
+// userspace once the cold start is over
+#[map]
+static COLD_START_PENDING: LruHashMap<KConnection, u64> = LruHashMap::with_max_entries(1024, 0);
+
 #[map]
 static COLD_START_MAP: RingBuf = RingBuf::with_byte_size(256 * 1024 * 10, 0);
 
 #[map]
-static DOOR_BELL_MAP: HashMap<KEndpoint, u8> = HashMap::with_max_entries(102400, 0);
+static SERVICE_LOAD: PerCpuHashMap<KEndpoint, KServiceLoad> =
+    PerCpuHashMap::with_max_entries(1024, 0);
+
+#[map]
+static CONFIG: Array<KConfig> = Array::with_max_entries(1, 0);
+
+#[map]
+static COUNTERS: PerCpuArray<u64> = PerCpuArray::pinned(COUNTER_NUM, 0);
+
+#[map]
+static HALF_OPEN_MAP: LruHashMap<u32, KHalfOpen> = LruHashMap::pinned(65536, 0);
+
+#[map]
+static HALF_OPEN_CONN: LruHashMap<KConnection, u8> = LruHashMap::pinned(65536, 0);
+
+#[map]
+static SYN_PROXY_MAP: LruHashMap<KConnection, KSynProxy> = LruHashMap::pinned(65536, 0);
 
 #[map]
-static PERFORMANCE_MAP: HashMap<KEndpoint, u8> = HashMap::with_max_entries(102400, 0);
+static ACL_MAP: LpmTrie<KAclKey, u8> = LpmTrie::with_max_entries(4096, BPF_F_NO_PREALLOC);
+
+// source ip -> when its block ends in ns, u64::MAX for never
+#[map]
+static BLOCKLIST: LruHashMap<u32, u64> = LruHashMap::with_max_entries(65536, 0);
+
+#[map]
+static ACL_DEFAULT_MAP: HashMap<KEndpoint, u8> = HashMap::with_max_entries(1024, 0);
+
+// services whose handshake is answered while they cold start, with the
+// longest time a client is held in ns
+#[map]
+static HOLD_MAP: HashMap<KEndpoint, u64> = HashMap::with_max_entries(1024, 0);
+
+#[map]
+static HELD_CONN: LruHashMap<KConnection, KHeld> = LruHashMap::pinned(65536, 0);
+
+#[map]
+static FLOW_MAP: LruHashMap<KConnection, KFlow> = LruHashMap::pinned(65536, 0);
+
+// connections whose nat entries were evicted, see flow::notify_evicted
+#[map]
+static EVICTED: RingBuf = RingBuf::with_byte_size(64 * 1024, 0);
+
+#[map]
+static FIRST_DATA: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
+
+// packets of one service copied before and after their rewrite, see pcap
+#[map]
+static PCAP_FILTER: Array<KPcapFilter> = Array::with_max_entries(1, 0);
+
+#[map]
+static PCAP_RING: RingBuf = RingBuf::with_byte_size(1024 * 1024, 0);
+
+// sampled processing time per interface, see latency::record
+#[map]
+static LATENCY: PerCpuHashMap<KLatencyKey, u64> =
+    PerCpuHashMap::with_max_entries(64 * LATENCY_BUCKETS, 0);
+
+// what became of the packets of every interface, see iface::record
+#[map]
+static IFACE_STATS: PerCpuHashMap<u32, KIfaceStats> = PerCpuHashMap::with_max_entries(64, 0);
+
+// syn to syn-ack per backend, see flow::account_syn_ack
+#[map]
+static HANDSHAKE_LATENCY: PerCpuHashMap<KHandshakeKey, u64> =
+    PerCpuHashMap::with_max_entries(1024 * LATENCY_BUCKETS, 0);
+
+#[inline(always)]
+fn incr_counter(counter: Counter) {
+    if let Some(v) = COUNTERS.get_ptr_mut(counter as u32) {
+        unsafe { *v += 1 };
+    }
+}
+
+// the service is the destination of client packets and the source of the
+// packets going back to the client
+#[inline(always)]
+fn service_epoch(declare_way: &KConnection, output_way: &KConnection) -> u32 {
+    unsafe { SERVICE_EPOCH.get(&declare_way.to) }
+        .or_else(|| unsafe { SERVICE_EPOCH.get(&output_way.from) })
+        .copied()
+        .unwrap_or(0)
+}
 
 #[inline(always)]
 fn extract_way(
@@ -126,8 +354,11 @@ fn update_csum(
     let from_ptr: *mut u32 = ptr_at(&ctx, offset)?;
     let mut new_val = new_val;
     let to_ptr: *mut u32 = &mut new_val as *mut u32;
-    let new_l4_csum = unsafe { bpf_csum_diff(from_ptr, 4, to_ptr, 4, !(old_l4_csum) as u32) };
-    l4_hdr.set_check(csum_fold_helper(new_l4_csum as u64));
+    // a zero udp checksum means the sender did not compute one
+    if l4_hdr.is_tcp() || old_l4_csum != 0 {
+        let new_l4_csum = unsafe { bpf_csum_diff(from_ptr, 4, to_ptr, 4, !(old_l4_csum) as u32) };
+        l4_hdr.set_check(csum_fold_helper(new_l4_csum as u64));
+    }
 
     if update_ip_csum {
         let old_ip_csum = unsafe { (*iphdr).check };
@@ -144,10 +375,10 @@ fn update_packet_by_way(
     ethhdr: *mut EthHdr,
     iphdr: *mut Ipv4Hdr,
     l4_hdr: &mut L4Hdr,
-    way: &KConnection,
+    rewrite: &KRewrite,
 ) -> Result<(), ()> {
-    let dst = way.to;
-    let src = way.from;
+    let dst = rewrite.way.to;
+    let src = rewrite.way.from;
 
     // update dst ip
     update_csum(
@@ -187,17 +418,41 @@ fn update_packet_by_way(
     )?;
     l4_hdr.set_bi_port(&bi_port);
 
-    // set mac
+    rewrite_macs(ethhdr, dst.ip(), rewrite.mac_policy);
+    Ok(())
+}
+
+// the source and destination macs a packet to `dst_ip` leaves with, none when
+// its ethernet header is left alone
+#[inline(always)]
+fn new_macs(ethhdr: *const EthHdr, dst_ip: u32, mac_policy: u32) -> Option<([u8; 6], [u8; 6])> {
+    if mac_policy == MAC_POLICY_KEEP {
+        return None;
+    }
+
     let src_mac: Mac = unsafe { (*ethhdr).dst_addr }.into();
     let src_mac: [u8; 6] = src_mac.into();
-    let src_mac_ptr: *mut [u8; 6] =
-        ((ethhdr as usize) + offset_of!(EthHdr, src_addr)) as *mut [u8; 6];
 
-    let dst_mac: [u8; 6] = if let Some(mac) = unsafe { IP_MAC_MAP.get(&dst.ip()) } {
+    let sender_mac =
+        unsafe { *((ethhdr as usize + offset_of!(EthHdr, src_addr)) as *const [u8; 6]) };
+    let dst_mac: [u8; 6] = if mac_policy == MAC_POLICY_BOUNCE {
+        sender_mac
+    } else if let Some(mac) = unsafe { IP_MAC_MAP.get(&dst_ip) } {
         (*mac).into()
     } else {
-        unsafe { *((ethhdr as usize + offset_of!(EthHdr, src_addr)) as *const [u8; 6]) }
+        sender_mac
     };
+    Some((src_mac, dst_mac))
+}
+
+#[inline(always)]
+fn rewrite_macs(ethhdr: *mut EthHdr, dst_ip: u32, mac_policy: u32) {
+    let (src_mac, dst_mac) = match new_macs(ethhdr, dst_ip, mac_policy) {
+        Some(macs) => macs,
+        None => return,
+    };
+    let src_mac_ptr: *mut [u8; 6] =
+        ((ethhdr as usize) + offset_of!(EthHdr, src_addr)) as *mut [u8; 6];
     let dst_mac_ptr: *mut [u8; 6] =
         ((ethhdr as usize) + offset_of!(EthHdr, dst_addr)) as *mut [u8; 6];
 
@@ -205,8 +460,12 @@ fn update_packet_by_way(
         copy(&src_mac, src_mac_ptr, 6);
         copy(&dst_mac, dst_mac_ptr, 6);
     }
+}
 
-    Ok(())
+// whether the program logs, see KConfig::quiet
+#[inline(always)]
+fn logging(cfg: Option<&KConfig>) -> bool {
+    !cfg.is_some_and(|cfg| cfg.quiet != 0)
 }
 
 #[inline(always)]
@@ -238,25 +497,62 @@ fn try_xdp_firewall(ctx: XdpContext) -> Result<u32, ()> {
 
     let proto: IpProto = unsafe { (*iphdr).proto };
 
-    let mut l4_hdr: L4Hdr = match proto {
-        IpProto::Tcp => {
-            let tcphdr: *mut TcpHdr = ptr_at(&ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
-            L4Hdr::TcpHdr(tcphdr)
-        }
-        IpProto::Udp => {
-            let udphdr: *mut UdpHdr = ptr_at(&ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
-            L4Hdr::UdpHdr(udphdr)
+    if let IpProto::Tcp | IpProto::Udp = proto {
+        if let Some(action) = frag::later_fragment_action(iphdr) {
+            return Ok(action);
         }
+    }
+
+    let packet = packet(&ctx);
+    let l4_offset = EthHdr::LEN + fallback::ip_hdr_len(iphdr);
+    let mut l4_hdr: L4Hdr = match proto {
+        IpProto::Tcp => L4Hdr::tcp_at(&packet, l4_offset).ok_or(())?,
+        IpProto::Udp => L4Hdr::udp_at(&packet, l4_offset).ok_or(())?,
+        IpProto::Icmp => return icmp::translate_error(&ctx, ethhdr, iphdr),
         _ => return Ok(xdp_action::XDP_PASS),
     };
 
     let declare_way = extract_way(ethhdr, iphdr, &l4_hdr)?;
 
-    debug_connection(&ctx, &declare_way, "before check connection map").unwrap();
+    if blocklist::is_blocked(declare_way.from.ip(), unsafe { bpf_ktime_get_ns() }) {
+        incr_counter(Counter::Blocked);
+        return Ok(xdp_action::XDP_DROP);
+    }
+
+    let cfg = CONFIG.get(0);
+    if acl::is_denied(&declare_way) {
+        incr_counter(Counter::AclDenied);
+        return reject::drop_or_reject(&ctx, cfg, iphdr, &l4_hdr);
+    }
+
+    // the host stack hands them to the router
+    if unsafe { PROXIED.get(&declare_way.to) }.is_some() {
+        return Ok(xdp_action::XDP_PASS);
+    }
+
+    if let Some(action) = fallback::action(cfg, iphdr, &declare_way) {
+        return Ok(action);
+    }
+
+    if logging(cfg) {
+        debug_connection(&ctx, &declare_way, "before check connection map").unwrap();
+    }
 
-    if unsafe { CONNECTION.get(&declare_way) }.is_none() {
+    let now = unsafe { bpf_ktime_get_ns() };
+    let mut new_udp_flow = false;
+    let service_key = KServiceKey::new(declare_way.to, l4_hdr.is_tcp());
+
+    // traffic a backend starts itself, to anything but a service
+    let outbound = unsafe { CONNECTION.get(&declare_way) }.is_none()
+        && unsafe { SERVER_MAP.get(&service_key) }.is_none()
+        && outbound::from_backend(declare_way.from.ip());
+    if outbound {
+        if let Some(action) = outbound::handle(ifidx, cfg, &declare_way, &l4_hdr)? {
+            return Ok(action);
+        }
+    } else if unsafe { CONNECTION.get(&declare_way) }.is_none() {
         // debug_connection(&ctx, &declare_way, "cannot find output way").unwrap();
-        let to = match unsafe { SERVER_MAP.get(&declare_way.to) } {
+        let to = match unsafe { SERVER_MAP.get(&service_key) } {
             Some(to) => to,
             None => {
                 let port = declare_way.to.port().to_be();
@@ -265,85 +561,246 @@ fn try_xdp_firewall(ctx: XdpContext) -> Result<u32, ()> {
                     return Ok(xdp_action::XDP_PASS);
                 }
 
-                info!(
-                    &ctx,
-                    "need to cold start: {:i}:{}",
-                    declare_way.to.ip().to_be(),
-                    declare_way.to.port().to_be()
-                );
+                if let Some(h) = hold::held(&declare_way).filter(|_| !l4_hdr.is_syn()) {
+                    return hold::keep_held(&ctx, &declare_way, &h, &l4_hdr, now);
+                }
+
+                // only a syn cold starts a tcp service
+                if l4_hdr.is_tcp() && !l4_hdr.is_syn() {
+                    return unknown::handle(&ctx, cfg, iphdr, &l4_hdr);
+                }
+
+                if reject::cold_start_failed(&declare_way.to, now) {
+                    return reject::drop_or_reject(&ctx, cfg, iphdr, &l4_hdr);
+                }
+
+                if logging(cfg) {
+                    info!(
+                        &ctx,
+                        "need to cold start: {:i}:{}",
+                        declare_way.to.ip().to_be(),
+                        declare_way.to.port().to_be()
+                    );
+                }
+
+                cold_start::report(&declare_way, l4_hdr.is_tcp(), now);
 
-                if let Some(mut e) = COLD_START_MAP.reserve::<KEndpoint>(0) {
-                    let endpoint = declare_way.to.clone();
-                    e.write(endpoint);
-                    e.submit(0);
+                if let Some(cfg) = cfg {
+                    if let Some(action) = hold::hold_syn(&ctx, cfg, &declare_way, &l4_hdr, now)? {
+                        return Ok(action);
+                    }
                 }
 
                 return Ok(xdp_action::XDP_DROP);
             }
         };
-        let from_port = SERVICE_PORTS.pop();
-        if from_port.is_none() {
-            info!(
-                &ctx,
-                "from port is none: {:i}:{}",
-                // SERVICE_PORTS.capacity(),
-                declare_way.to.ip().to_be(),
-                declare_way.to.port().to_be()
-            );
+        let to = match affinity::backend_of(&service_key, &declare_way, to, now) {
+            Some(to) => to,
+            None => split::backend_of(&service_key, &declare_way).unwrap_or(to),
+        };
+
+        flow::notify_evicted(&declare_way);
+
+        // the client retransmits its syn and finds the service again once it is gone
+        if unsafe { DRAINING_MAP.get(&declare_way.to) }.is_some() {
+            incr_counter(Counter::DrainingDropped);
             return Ok(xdp_action::XDP_DROP);
         }
+
+        // a held client gets spliced onto the backend with its next ack
+        let held = hold::held(&declare_way);
+        if held.is_some() {
+            let _ = HELD_CONN.remove(&declare_way);
+        }
+        let held = held.filter(|_| syn_flood::is_pure_ack(&l4_hdr));
+
+        // syn flood protection, before any port is spent on the connection
+        let mut cookie_ack = false;
+        if let Some(cfg) = cfg.filter(|cfg| held.is_none() && cfg.syn_half_open_threshold > 0) {
+            let flooded = syn_flood::half_open_count(cfg, declare_way.from.ip(), now)
+                >= cfg.syn_half_open_threshold;
+            let use_cookie = cfg.syn_flood_action == SYN_FLOOD_ACTION_COOKIE;
+
+            if flooded && syn_flood::is_pure_syn(&l4_hdr) {
+                if use_cookie {
+                    return syn_flood::send_syn_cookie(&ctx, cfg, &declare_way, &l4_hdr, now);
+                }
+                incr_counter(Counter::SynFloodDropped);
+                return Ok(xdp_action::XDP_DROP);
+            }
+
+            if use_cookie && syn_flood::is_pure_ack(&l4_hdr) {
+                if syn_flood::is_cookie_ack(cfg, &declare_way, &l4_hdr, now) {
+                    cookie_ack = true;
+                } else if flooded {
+                    incr_counter(Counter::SynCookieInvalid);
+                    return Ok(xdp_action::XDP_DROP);
+                }
+            }
+        }
+
+        // only a syn, or the ack ending a handshake folonet answered itself,
+        // opens a tcp connection
+        if l4_hdr.is_tcp() && !l4_hdr.is_syn() && held.is_none() && !cookie_ack {
+            return unknown::handle(&ctx, cfg, iphdr, &l4_hdr);
+        }
+
+        if let Some(action) = conn_limit::reached(&declare_way.to) {
+            incr_counter(Counter::ConnLimitExceeded);
+            if action == CONN_LIMIT_RST && l4_hdr.is_tcp() {
+                synth::rewrite_tcp(&ctx, &unknown::reset_for(iphdr, &l4_hdr), true)?;
+                return Ok(xdp_action::XDP_TX);
+            }
+            return Ok(xdp_action::XDP_DROP);
+        }
+
+        let from_port = ports::take(&declare_way.to);
+        if from_port.is_none() {
+            if logging(cfg) {
+                info!(
+                    &ctx,
+                    "from port is none: {:i}:{}",
+                    // SERVICE_PORTS.capacity(),
+                    declare_way.to.ip().to_be(),
+                    declare_way.to.port().to_be()
+                );
+            }
+            return reject::drop_or_reject(&ctx, cfg, iphdr, &l4_hdr);
+        }
         // debug_connection(&ctx, &declare_way, "get from port").unwrap();
         let from_port = from_port.unwrap();
-        let local_ip = unsafe { LOCAL_IP_MAP.get(&ifidx) };
+        let local_ip = egress::source_ip(ifidx, &declare_way.to, to);
         if local_ip.is_none() {
-            info!(
-                &ctx,
-                "local ip is none: {:i}:{}",
-                declare_way.to.ip().to_be(),
-                declare_way.to.port().to_be()
-            );
+            if logging(cfg) {
+                info!(
+                    &ctx,
+                    "local ip is none: {:i}:{}",
+                    declare_way.to.ip().to_be(),
+                    declare_way.to.port().to_be()
+                );
+            }
             return Ok(xdp_action::XDP_DROP);
         }
         // debug_connection(&ctx, &declare_way, "get local ip").unwrap();
-        let local_ip = local_ip.unwrap();
-        let from = KEndpoint::new(local_ip.to_be(), from_port.to_be());
+        let local_ip = local_ip.unwrap().to_be();
+        // the backend would get packets from itself, the service ip is one of
+        // this host too
+        let source_ip = if local_ip == to.ip() {
+            declare_way.to.ip()
+        } else {
+            local_ip
+        };
+        let from = KEndpoint::new(source_ip, from_port.to_be());
 
         // debug_connection(&ctx, &declare_way, "before insert connection map").unwrap();
 
         let out_way = KConnection { from, to: *to };
-        CONNECTION
-            .insert(&declare_way, &out_way, 0)
-            .map_err(|_| ())?;
-
-        // debug_connection(&ctx, &declare_way, "after insert connection map").unwrap();
-
-        // and, we need to record the return way
-        let return_output_way = out_way.reverse();
-        let return_declare_way = &declare_way.reverse();
-        CONNECTION
-            .insert(&return_output_way, &return_declare_way, 0)
-            .map_err(|_| ())?;
+        let nat_entry = if declare_way.from.ip() == to.ip() {
+            incr_counter(Counter::Hairpinned);
+            KNat::hairpin(&declare_way, &out_way)
+        } else {
+            KNat::full_nat(&declare_way, &out_way)
+        };
+        nat::install(&declare_way, &nat_entry)?;
+        affinity::remember(&declare_way, to, now);
+        proxy_v2::start(&declare_way, &l4_hdr);
+
+        flow::start_flow(
+            &declare_way,
+            &nat_entry,
+            service_epoch(&declare_way, &out_way),
+            now,
+        );
+        load::conn_opened(&declare_way.to);
+        conn_limit::opened(&declare_way.to);
+        new_udp_flow = !l4_hdr.is_tcp();
+
+        if let Some(h) = held {
+            incr_counter(Counter::HandshakeSpliced);
+            let action = syn_flood::forward_proxied_syn(
+                &ctx,
+                ethhdr,
+                iphdr,
+                &mut l4_hdr,
+                &nat_entry,
+                h.client_isn,
+                h.cookie,
+                true,
+            )?;
+            if toa::enabled(&declare_way) {
+                toa::insert_option(&ctx, &declare_way)?;
+            }
+            return Ok(action);
+        }
+
+        if cookie_ack {
+            let action =
+                syn_flood::forward_cookie_syn(&ctx, ethhdr, iphdr, &mut l4_hdr, &nat_entry)?;
+            if toa::enabled(&declare_way) {
+                toa::insert_option(&ctx, &declare_way)?;
+            }
+            return Ok(action);
+        }
+
+        if let Some(cfg) = cfg.filter(|cfg| cfg.syn_half_open_threshold > 0) {
+            if syn_flood::is_pure_syn(&l4_hdr) {
+                syn_flood::record_half_open(cfg, &declare_way, now);
+            }
+        }
+    } else if syn_flood::is_pure_ack(&l4_hdr) {
+        syn_flood::complete_half_open(&declare_way);
     }
 
-    let output_way = unsafe { CONNECTION.get(&declare_way) };
+    let nat_entry = unsafe { CONNECTION.get(&declare_way) };
 
-    if output_way.is_none() {
-        info!(
-            &ctx,
-            "output_way is none: {:i}:{}",
-            declare_way.to.ip().to_be(),
-            declare_way.to.port().to_be()
-        );
+    if nat_entry.is_none() {
+        if logging(cfg) {
+            info!(
+                &ctx,
+                "output_way is none: {:i}:{}",
+                declare_way.to.ip().to_be(),
+                declare_way.to.port().to_be()
+            );
+        }
         return Ok(xdp_action::XDP_PASS);
     }
 
-    let output_way = output_way.unwrap();
+    let nat_entry = nat_entry.unwrap();
+    let output_way = &nat_entry.fwd.way;
+    iface::matched(ifidx);
+
+    if !conntrack::track(&declare_way, nat_entry, &l4_hdr) {
+        incr_counter(Counter::ClosedConnDropped);
+        return Ok(xdp_action::XDP_DROP);
+    }
+
+    flow::account_syn_ack(&declare_way, nat_entry, &l4_hdr, now);
+
+    if let Some(action) = syn_flood::handle_backend_syn_ack(
+        &ctx,
+        ethhdr,
+        iphdr,
+        &mut l4_hdr,
+        &declare_way,
+        nat_entry,
+    )? {
+        return Ok(action);
+    }
 
     // debug_connection(&ctx, &output_way, "output:")?;
 
-    // notify to userspace
-    if l4_hdr.is_fin() {
-        if let Some(mut e) = PACKET_EVENT.reserve::<Notification>(0) {
+    let ip_len = u16::from_be(unsafe { (*iphdr).tot_len }) as u64;
+    flow::account_flow(&declare_way, nat_entry, ip_len, now);
+    load::account_packet(&declare_way, output_way, ip_len);
+    if cfg.is_some_and(|cfg| cfg.sample_first_data != 0) {
+        sample::sample_first_data(&ctx, iphdr, &l4_hdr, &declare_way);
+    }
+
+    // notify to userspace: tcp fins and, as configured, the handshake and
+    // resets, and the first packet of a udp flow, which has no last packet
+    // and ends once userspace finds it idle
+    if new_udp_flow || notify::should_notify(cfg, &l4_hdr, &declare_way, output_way) {
+        if let Some(mut e) = PACKET_EVENT.reserve::<NotificationFrame>(0) {
             let notification = Notification {
                 local_in_endpoint: declare_way.to,
                 lcoal_out_endpoint: output_way.from,
@@ -351,9 +808,10 @@ fn try_xdp_firewall(ctx: XdpContext) -> Result<u32, ()> {
                     from: declare_way.from,
                     to: output_way.to,
                 },
+                epoch: service_epoch(&declare_way, output_way),
                 event: Event::new_packet_event(&l4_hdr),
             };
-            e.write(notification);
+            e.write(NotificationFrame::new(notification));
             e.submit(0);
             // info!(
             //     &ctx,
@@ -362,43 +820,46 @@ fn try_xdp_firewall(ctx: XdpContext) -> Result<u32, ()> {
             //     declare_way.to.port().to_be()
             // );
         } else {
-            info!(
-                &ctx,
-                "packet event is full: {:i}:{}",
-                declare_way.to.ip().to_be(),
-                declare_way.to.port().to_be()
-            );
+            incr_counter(Counter::PacketEventLost);
+            if logging(cfg) {
+                info!(
+                    &ctx,
+                    "packet event is full: {:i}:{}",
+                    declare_way.to.ip().to_be(),
+                    declare_way.to.port().to_be()
+                );
+            }
         }
     }
 
-    let target_endpoint = if let Some(v) = unsafe { DOOR_BELL_MAP.get(&declare_way.to) } {
-        if *v == 1 {
-            Some(&declare_way.to)
-        } else {
-            None
-        }
-    } else if let Some(v) = unsafe { DOOR_BELL_MAP.get(&output_way.from) } {
-        if *v == 1 {
-            Some(&output_way.from)
-        } else {
-            None
+    let pcap = pcap::wanted(&declare_way, output_way);
+    if pcap {
+        pcap::capture(&ctx, ifidx, PCAP_BEFORE, now);
+    }
+
+    syn_flood::translate_seq(&ctx, iphdr, &mut l4_hdr, &declare_way, nat_entry)?;
+    let proxy_header = proxy_v2::translate_seq(&ctx, iphdr, &mut l4_hdr, &declare_way, nat_entry)?;
+
+    let toa = syn_flood::is_pure_syn(&l4_hdr) && toa::enabled(&declare_way);
+
+    // the af_xdp worker does not grow packets
+    if !proxy_header && !toa {
+        if let Some(action) = xsk::redirect(&ctx, cfg, ethhdr, &nat_entry.fwd) {
+            return Ok(action);
         }
-    } else {
-        None
-    };
+    }
 
-    if let Some(target_endpoint) = target_endpoint {
-        let v = 1u8;
-        // info!(
-        //     &ctx,
-        //     "record performace: {:i}:{}",
-        //     target_endpoint.ip().to_be(),
-        //     target_endpoint.port().to_be()
-        // );
-        PERFORMANCE_MAP.insert(&target_endpoint, &v, 0).unwrap();
+    update_packet_by_way(&ctx, ethhdr, iphdr, &mut l4_hdr, &nat_entry.fwd)?;
+    if proxy_header {
+        proxy_v2::insert_header(&ctx, &declare_way)?;
+    }
+    if toa {
+        toa::insert_option(&ctx, &declare_way)?;
     }
 
-    update_packet_by_way(&ctx, ethhdr, iphdr, &mut l4_hdr, &output_way)?;
+    if pcap {
+        pcap::capture(&ctx, ifidx, PCAP_AFTER, now);
+    }
 
     Ok(xdp_action::XDP_TX)
 }
@@ -0,0 +1,13 @@
+use folonet_common::{nat::KNat, KConnection};
+
+use crate::CONNECTION;
+
+// install both directions of a connection, the return entry keyed by
+// `nat.rev_key`
+#[inline(always)]
+pub fn install(declare_way: &KConnection, nat: &KNat) -> Result<(), ()> {
+    CONNECTION.insert(declare_way, nat, 0).map_err(|_| ())?;
+    CONNECTION
+        .insert(&nat.rev_key, &nat.mirror(declare_way), 0)
+        .map_err(|_| ())
+}
@@ -0,0 +1,66 @@
+use folonet_common::{
+    config::KConfig, flow::FLOW_NOTIFIED_FIRST_ACK, KConnection, KEndpoint, L4Hdr,
+};
+
+use crate::FLOW_MAP;
+
+// one in `one_in` clients, the same ones for every flag with the same rate
+#[inline(always)]
+fn picked(client: &KEndpoint, one_in: u8) -> bool {
+    if one_in == 0 {
+        return false;
+    }
+    let hash = (client.ip() ^ ((client.port() as u32) << 16)).wrapping_mul(2654435761);
+    (hash >> 16) % one_in as u32 == 0
+}
+
+// Whether userspace hears of this tcp packet. Fins always, as they close the
+// connection; the handshake and resets as configured. `output_way` is where
+// the packet goes after nat.
+#[inline(always)]
+pub fn should_notify(
+    cfg: Option<&KConfig>,
+    l4_hdr: &L4Hdr,
+    declare_way: &KConnection,
+    output_way: &KConnection,
+) -> bool {
+    if l4_hdr.is_fin() {
+        return true;
+    }
+    let cfg = match cfg {
+        Some(cfg) => cfg,
+        None => return false,
+    };
+
+    // only packets of the client find a flow under their own way
+    let client_flow = FLOW_MAP.get_ptr_mut(declare_way);
+    let client = if client_flow.is_some() {
+        &declare_way.from
+    } else {
+        &output_way.to
+    };
+
+    if l4_hdr.is_rst() {
+        return picked(client, cfg.notify_rst);
+    }
+    if l4_hdr.is_syn() {
+        let one_in = if l4_hdr.is_ack() {
+            cfg.notify_syn_ack
+        } else {
+            cfg.notify_syn
+        };
+        return picked(client, one_in);
+    }
+
+    // the first ack of the client finishes the handshake
+    match client_flow {
+        Some(flow) if l4_hdr.is_ack() => {
+            if unsafe { (*flow).notified } & FLOW_NOTIFIED_FIRST_ACK != 0 {
+                return false;
+            }
+            unsafe { (*flow).notified |= FLOW_NOTIFIED_FIRST_ACK };
+            picked(client, cfg.notify_first_ack)
+        }
+        _ => false,
+    }
+}
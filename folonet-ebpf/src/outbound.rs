@@ -0,0 +1,60 @@
+use aya_ebpf::bindings::xdp_action;
+use folonet_common::{
+    config::{KConfig, BACKEND_EGRESS_BLOCK, BACKEND_EGRESS_MASQUERADE},
+    nat::KNat,
+    stats::Counter,
+    KConnection, KEndpoint, L4Hdr,
+};
+
+use crate::{egress, incr_counter, nat, syn_flood::is_pure_syn, BACKEND_IPS};
+
+// whether `ip`, network byte order, is the ip of a backend
+#[inline(always)]
+pub fn from_backend(ip: u32) -> bool {
+    unsafe { BACKEND_IPS.get(&ip) }.is_some()
+}
+
+// A packet a backend sends to anything but a service, e.g. a webhook or a
+// database. Returns what to do with it, none once masqueraded: its nat
+// entries are in place and it goes on like any packet of a connection.
+//
+// A masqueraded connection leaves from the ip of the interface towards its
+// destination and keeps the port of the backend, its nat entries age out of
+// the lru CONNECTION map.
+#[inline(always)]
+pub fn handle(
+    ifidx: u32,
+    cfg: Option<&KConfig>,
+    way: &KConnection,
+    l4_hdr: &L4Hdr,
+) -> Result<Option<u32>, ()> {
+    match cfg.map(|cfg| cfg.backend_egress_action).unwrap_or_default() {
+        BACKEND_EGRESS_BLOCK => {
+            // replies to connections the host opened to the backend, e.g. its
+            // probes and proxies, are no connections the backend opens
+            if l4_hdr.is_tcp() && !is_pure_syn(l4_hdr) {
+                return Ok(Some(xdp_action::XDP_PASS));
+            }
+            incr_counter(Counter::BackendEgressBlocked);
+            Ok(Some(xdp_action::XDP_DROP))
+        }
+        BACKEND_EGRESS_MASQUERADE => {
+            // only a syn opens a tcp connection, one opened before is left alone
+            if l4_hdr.is_tcp() && !is_pure_syn(l4_hdr) {
+                return Ok(Some(xdp_action::XDP_PASS));
+            }
+            let ip = match egress::interface_ip(ifidx, &way.to) {
+                Some(ip) => ip,
+                None => return Ok(Some(xdp_action::XDP_PASS)),
+            };
+            let out_way = KConnection {
+                from: KEndpoint::new(ip.to_be(), way.from.port()),
+                to: way.to,
+            };
+            nat::install(way, &KNat::full_nat(way, &out_way))?;
+            incr_counter(Counter::BackendEgressMasqueraded);
+            Ok(None)
+        }
+        _ => Ok(Some(xdp_action::XDP_PASS)),
+    }
+}
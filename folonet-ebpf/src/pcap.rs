@@ -0,0 +1,54 @@
+use aya_ebpf::{helpers::bpf_get_prandom_u32, programs::XdpContext};
+use folonet_common::{
+    pcap::{KPcapRecord, PCAP_SNAPLEN},
+    KConnection,
+};
+
+use crate::{ptr_at, PCAP_FILTER, PCAP_RING};
+
+// whether to copy this packet, `declare_way` as it came in and `output_way`
+// as it leaves. The same answer goes for its copy before and after the
+// rewrite.
+#[inline(always)]
+pub fn wanted(declare_way: &KConnection, output_way: &KConnection) -> bool {
+    let filter = match PCAP_FILTER.get(0) {
+        Some(filter) => filter,
+        None => return false,
+    };
+    if filter.one_in == 0 {
+        return false;
+    }
+    // a client packet goes to the service, a backend one comes back from it
+    if declare_way.to != filter.service && output_way.from != filter.service {
+        return false;
+    }
+    let sample = unsafe { bpf_get_prandom_u32() };
+    sample % filter.one_in == 0
+}
+
+// copy the first PCAP_SNAPLEN bytes of the packet to PCAP_RING
+#[inline(always)]
+pub fn capture(ctx: &XdpContext, ifindex: u32, stage: u8, now: u64) {
+    let mut entry = match PCAP_RING.reserve::<KPcapRecord>(0) {
+        Some(entry) => entry,
+        None => return,
+    };
+    let record = entry.as_mut_ptr();
+    let mut cap_len = 0;
+    for i in 0..PCAP_SNAPLEN {
+        match ptr_at::<u8>(ctx, i) {
+            Ok(b) => unsafe { (*record).data[i] = *b },
+            Err(_) => break,
+        }
+        cap_len += 1;
+    }
+    unsafe {
+        (*record).ts_ns = now;
+        (*record).ifindex = ifindex;
+        (*record).len = (ctx.data_end() - ctx.data()) as u32;
+        (*record).cap_len = cap_len;
+        (*record).stage = stage;
+        (*record)._pad = [0; 3];
+    }
+    entry.submit(0);
+}
@@ -0,0 +1,44 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use folonet_common::{ports::KPortQuota, stats::Counter, KEndpoint};
+
+use crate::{incr_counter, PORT_QUOTA_MAP, PORT_TAKEN_MAP, SERVICE_PORTS};
+
+// a local port for a new connection to `service`, none once the pool is empty
+// or the service holds all the ports of its quota
+#[inline(always)]
+pub fn take(service: &KEndpoint) -> Option<u16> {
+    let quota: Option<&KPortQuota> = unsafe { PORT_QUOTA_MAP.get(service) };
+    if let Some(quota) = quota {
+        let taken = PORT_TAKEN_MAP
+            .get_ptr(service)
+            .map(|taken| unsafe { *taken })
+            .unwrap_or(0);
+        if taken.saturating_sub(quota.released) >= quota.limit {
+            incr_counter(Counter::PortQuotaExceeded);
+            return None;
+        }
+    }
+
+    let port = match SERVICE_PORTS.pop() {
+        Some(port) => port,
+        None => {
+            incr_counter(Counter::PortExhausted);
+            return None;
+        }
+    };
+    incr_counter(Counter::PortAllocated);
+
+    if quota.is_some() {
+        // connections of the service may open on every cpu at once
+        match PORT_TAKEN_MAP.get_ptr_mut(service) {
+            Some(taken) => unsafe {
+                AtomicU64::from_ptr(taken).fetch_add(1, Ordering::Relaxed);
+            },
+            None => {
+                let _ = PORT_TAKEN_MAP.insert(service, &1, 0);
+            }
+        }
+    }
+    Some(port)
+}
@@ -0,0 +1,103 @@
+use aya_ebpf::programs::XdpContext;
+use core::mem::offset_of;
+use folonet_common::{
+    nat::KNat,
+    proxy_v2::{proxy_v2_header, KProxyV2, PROXY_V2_PENDING, PROXY_V2_SENT},
+    stats::Counter,
+    KConnection, L4Hdr,
+};
+use network_types::{eth::EthHdr, ip::Ipv4Hdr, tcp::TcpHdr};
+
+use crate::{incr_counter, synth::insert_after_tcp_hdr, update_csum, PROXY_V2_CONN, PROXY_V2_MAP};
+
+// a new connection of a service whose backends want the client address
+#[inline(always)]
+pub fn start(declare_way: &KConnection, l4_hdr: &L4Hdr) {
+    if l4_hdr.is_tcp() && unsafe { PROXY_V2_MAP.get(&declare_way.to) }.is_some() {
+        let proxy = KProxyV2 {
+            data_seq: 0,
+            state: PROXY_V2_PENDING,
+            _pad: [0; 3],
+        };
+        let _ = PROXY_V2_CONN.insert(declare_way, &proxy, 0);
+    } else {
+        // of an earlier connection over the same way
+        let _ = PROXY_V2_CONN.remove(declare_way);
+    }
+}
+
+#[inline(always)]
+fn payload_len(iphdr: *const Ipv4Hdr, l4_hdr: &L4Hdr) -> usize {
+    let ip_len = u16::from_be(unsafe { (*iphdr).tot_len }) as usize;
+    ip_len.saturating_sub(Ipv4Hdr::LEN + l4_hdr.header_len())
+}
+
+// Move the seq of the client and the ack of the backend by the header the
+// backend got in its stream. True when the packet is the first data of the
+// client and takes the header, see insert_header.
+#[inline(always)]
+pub fn translate_seq(
+    ctx: &XdpContext,
+    iphdr: *mut Ipv4Hdr,
+    l4_hdr: &mut L4Hdr,
+    declare_way: &KConnection,
+    nat: &KNat,
+) -> Result<bool, ()> {
+    if !l4_hdr.is_tcp() {
+        return Ok(false);
+    }
+
+    if let Some(proxy) = PROXY_V2_CONN.get_ptr_mut(declare_way) {
+        // from client to backend
+        let seq = l4_hdr.get_seq();
+        if unsafe { (*proxy).takes_header(seq, payload_len(iphdr, l4_hdr)) } {
+            unsafe {
+                (*proxy).data_seq = seq;
+                (*proxy).state = PROXY_V2_SENT;
+            }
+            return Ok(true);
+        }
+        let backend_seq = unsafe { (*proxy).to_backend_seq(seq) };
+        if backend_seq != seq {
+            update_csum(
+                ctx,
+                iphdr,
+                l4_hdr,
+                EthHdr::LEN + Ipv4Hdr::LEN + offset_of!(TcpHdr, seq),
+                backend_seq.to_be(),
+                false,
+            )?;
+            l4_hdr.set_seq(backend_seq);
+        }
+    } else if let Some(proxy) = unsafe { PROXY_V2_CONN.get(&nat.rev_key) } {
+        // from backend to client
+        let ack_seq = l4_hdr.get_ack_seq();
+        let client_ack = proxy.to_client_ack(ack_seq);
+        if client_ack != ack_seq {
+            update_csum(
+                ctx,
+                iphdr,
+                l4_hdr,
+                EthHdr::LEN + Ipv4Hdr::LEN + offset_of!(TcpHdr, ack_seq),
+                client_ack.to_be(),
+                false,
+            )?;
+            l4_hdr.set_ack_seq(client_ack);
+        }
+    }
+
+    Ok(false)
+}
+
+// Put the PROXY protocol v2 header of `way` between the tcp header and the
+// payload of the packet in ctx, which is rewritten toward the backend
+// already.
+//
+// All packet pointers taken before calling this are invalid afterwards.
+#[inline(always)]
+pub fn insert_header(ctx: &XdpContext, way: &KConnection) -> Result<(), ()> {
+    let mut header = proxy_v2_header(way);
+    insert_after_tcp_hdr(ctx, &mut header, false)?;
+    incr_counter(Counter::ProxyHeaderSent);
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use aya_ebpf::{bindings::xdp_action, programs::XdpContext};
+use folonet_common::{config::KConfig, stats::Counter, KEndpoint, L4Hdr};
+use network_types::ip::Ipv4Hdr;
+
+use crate::{
+    incr_counter,
+    synth::{rewrite_port_unreachable, rewrite_tcp},
+    unknown::reset_for,
+    COLD_START_FAILED,
+};
+
+// A connection folonet turns away: with rejects on, the client hears of it at
+// once, by a reset for tcp and a port unreachable for udp, instead of
+// retransmitting into the void until it times out.
+#[inline(always)]
+pub fn drop_or_reject(
+    ctx: &XdpContext,
+    cfg: Option<&KConfig>,
+    iphdr: *const Ipv4Hdr,
+    l4_hdr: &L4Hdr,
+) -> Result<u32, ()> {
+    if !cfg.is_some_and(|cfg| cfg.reject != 0) {
+        return Ok(xdp_action::XDP_DROP);
+    }
+    if l4_hdr.is_tcp() {
+        // a reset is never answered
+        if l4_hdr.is_rst() {
+            return Ok(xdp_action::XDP_DROP);
+        }
+        rewrite_tcp(ctx, &reset_for(iphdr, l4_hdr), true)?;
+    } else {
+        rewrite_port_unreachable(ctx)?;
+    }
+    incr_counter(Counter::Rejected);
+    Ok(xdp_action::XDP_TX)
+}
+
+// the last cold start of `service` failed a moment ago, see COLD_START_FAILED
+#[inline(always)]
+pub fn cold_start_failed(service: &KEndpoint, now: u64) -> bool {
+    unsafe { COLD_START_FAILED.get(service) }.is_some_and(|until| now < *until)
+}
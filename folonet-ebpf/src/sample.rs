@@ -0,0 +1,55 @@
+use aya_ebpf::programs::XdpContext;
+use folonet_common::{
+    sample::{KPayloadSample, PAYLOAD_SAMPLE_LEN},
+    KConnection, L4Hdr,
+};
+use network_types::{eth::EthHdr, ip::Ipv4Hdr};
+
+use crate::{ptr_at, FIRST_DATA, FLOW_MAP};
+
+// report the first bytes the client sends on a connection, once per connection
+#[inline(always)]
+pub fn sample_first_data(
+    ctx: &XdpContext,
+    iphdr: *const Ipv4Hdr,
+    l4_hdr: &L4Hdr,
+    declare_way: &KConnection,
+) {
+    // only packets of the client find a flow under their own way
+    let flow = match FLOW_MAP.get_ptr_mut(declare_way) {
+        Some(flow) => flow,
+        None => return,
+    };
+    if unsafe { (*flow).sampled } != 0 {
+        return;
+    }
+
+    let l4_len = l4_hdr.header_len();
+    let ip_len = u16::from_be(unsafe { (*iphdr).tot_len }) as usize;
+    let payload_len = ip_len.saturating_sub(Ipv4Hdr::LEN + l4_len);
+    if payload_len == 0 {
+        // the handshake and pure acks
+        return;
+    }
+
+    let offset = EthHdr::LEN + Ipv4Hdr::LEN + l4_len;
+    let mut sample = KPayloadSample {
+        way: *declare_way,
+        len: 0,
+        _pad: 0,
+        data: [0; PAYLOAD_SAMPLE_LEN],
+    };
+    for i in 0..PAYLOAD_SAMPLE_LEN {
+        if i >= payload_len {
+            break;
+        }
+        match ptr_at::<u8>(ctx, offset + i) {
+            Ok(b) => sample.data[i] = unsafe { *b },
+            Err(_) => break,
+        }
+        sample.len += 1;
+    }
+
+    unsafe { (*flow).sampled = 1 };
+    let _ = FIRST_DATA.output(&sample, 0);
+}
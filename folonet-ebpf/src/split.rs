@@ -0,0 +1,22 @@
+use folonet_common::{
+    service::KServiceKey,
+    split::{conn_hash, MAX_POOLS},
+    KConnection, KEndpoint,
+};
+
+use crate::SPLIT_MAP;
+
+// the backend of the pool a new connection of a service goes to, none for a
+// service without pools
+#[inline(always)]
+pub fn backend_of(
+    service_key: &KServiceKey,
+    declare_way: &KConnection,
+) -> Option<&'static KEndpoint> {
+    let split = unsafe { SPLIT_MAP.get(service_key) }?;
+    let pool = split.pick(conn_hash(declare_way))?;
+    if pool >= MAX_POOLS {
+        return None;
+    }
+    Some(&split.backends[pool])
+}
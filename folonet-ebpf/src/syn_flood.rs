@@ -0,0 +1,278 @@
+use aya_ebpf::{bindings::xdp_action, programs::XdpContext};
+use folonet_common::{
+    config::{KConfig, KHalfOpen},
+    nat::KNat,
+    stats::Counter,
+    syncookie::{
+        check_syn_cookie, cookie_bucket, syn_cookie, KSynProxy, SYN_PROXY_ESTABLISHED,
+        SYN_PROXY_HELD, SYN_PROXY_PENDING,
+    },
+    KConnection, L4Hdr,
+};
+use network_types::{eth::EthHdr, ip::Ipv4Hdr, tcp::TcpHdr};
+
+use crate::{
+    incr_counter,
+    synth::{rewrite_tcp, TcpReply, TCP_FLAG_ACK, TCP_FLAG_SYN},
+    update_csum, update_packet_by_way, HALF_OPEN_CONN, HALF_OPEN_MAP, SYN_PROXY_MAP,
+};
+
+use core::mem::offset_of;
+
+// we strip all options from the handshake we answer, so no window scaling either
+const PROXY_WINDOW: u16 = 65535;
+
+#[inline(always)]
+pub fn is_pure_syn(l4_hdr: &L4Hdr) -> bool {
+    l4_hdr.is_syn() && !l4_hdr.is_ack()
+}
+
+#[inline(always)]
+pub fn is_pure_ack(l4_hdr: &L4Hdr) -> bool {
+    l4_hdr.is_ack() && !l4_hdr.is_syn() && !l4_hdr.is_fin() && !l4_hdr.is_rst()
+}
+
+// number of half-open connections of `src_ip` in the current window
+#[inline(always)]
+pub fn half_open_count(cfg: &KConfig, src_ip: u32, now: u64) -> u32 {
+    match unsafe { HALF_OPEN_MAP.get(&src_ip) } {
+        Some(h) if now.saturating_sub(h.window_start_ns) < cfg.syn_window_ns => h.count,
+        _ => 0,
+    }
+}
+
+#[inline(always)]
+pub fn record_half_open(cfg: &KConfig, way: &KConnection, now: u64) {
+    let src_ip = way.from.ip();
+    if let Some(h) = HALF_OPEN_MAP.get_ptr_mut(&src_ip) {
+        unsafe {
+            if now.saturating_sub((*h).window_start_ns) < cfg.syn_window_ns {
+                (*h).count += 1;
+            } else {
+                (*h).count = 1;
+                (*h).window_start_ns = now;
+            }
+        }
+    } else {
+        let h = KHalfOpen {
+            count: 1,
+            _pad: 0,
+            window_start_ns: now,
+        };
+        let _ = HALF_OPEN_MAP.insert(&src_ip, &h, 0);
+    }
+    let _ = HALF_OPEN_CONN.insert(way, &1, 0);
+}
+
+// the client acked the handshake, so the connection is not half-open anymore
+#[inline(always)]
+pub fn complete_half_open(way: &KConnection) {
+    if unsafe { HALF_OPEN_CONN.get(way) }.is_none() {
+        return;
+    }
+    let _ = HALF_OPEN_CONN.remove(way);
+    if let Some(h) = HALF_OPEN_MAP.get_ptr_mut(&way.from.ip()) {
+        unsafe {
+            if (*h).count > 0 {
+                (*h).count -= 1;
+            }
+        }
+    }
+}
+
+#[inline(always)]
+pub fn is_cookie_ack(cfg: &KConfig, way: &KConnection, l4_hdr: &L4Hdr, now: u64) -> bool {
+    is_pure_ack(l4_hdr)
+        && check_syn_cookie(
+            way,
+            cfg.syn_cookie_secret,
+            now,
+            l4_hdr.get_ack_seq().wrapping_sub(1),
+        )
+}
+
+// answer the syn ourselves, the isn is the cookie so no state is kept
+#[inline(always)]
+pub fn send_syn_cookie(
+    ctx: &XdpContext,
+    cfg: &KConfig,
+    way: &KConnection,
+    l4_hdr: &L4Hdr,
+    now: u64,
+) -> Result<u32, ()> {
+    let reply = TcpReply {
+        seq: syn_cookie(way, cfg.syn_cookie_secret, cookie_bucket(now)),
+        ack_seq: l4_hdr.get_seq().wrapping_add(1),
+        flags: TCP_FLAG_SYN | TCP_FLAG_ACK,
+        window: PROXY_WINDOW,
+    };
+    rewrite_tcp(ctx, &reply, true)?;
+    incr_counter(Counter::SynCookieSent);
+    Ok(xdp_action::XDP_TX)
+}
+
+// the client proved it owns its address, replay its syn towards the backend
+// through the freshly installed nat entry
+#[inline(always)]
+pub fn forward_cookie_syn(
+    ctx: &XdpContext,
+    ethhdr: *mut EthHdr,
+    iphdr: *mut Ipv4Hdr,
+    l4_hdr: &mut L4Hdr,
+    nat: &KNat,
+) -> Result<u32, ()> {
+    let client_isn = l4_hdr.get_seq().wrapping_sub(1);
+    let cookie = l4_hdr.get_ack_seq().wrapping_sub(1);
+    let action = forward_proxied_syn(ctx, ethhdr, iphdr, l4_hdr, nat, client_isn, cookie, false)?;
+    incr_counter(Counter::SynCookieValid);
+    Ok(action)
+}
+
+// turn the packet into the syn the client sent us, so the backend handshake
+// can be spliced onto the one we answered with `cookie`, `held` when we
+// answered it with a zero window
+#[inline(always)]
+#[allow(clippy::too_many_arguments)]
+pub fn forward_proxied_syn(
+    ctx: &XdpContext,
+    ethhdr: *mut EthHdr,
+    iphdr: *mut Ipv4Hdr,
+    l4_hdr: &mut L4Hdr,
+    nat: &KNat,
+    client_isn: u32,
+    cookie: u32,
+    held: bool,
+) -> Result<u32, ()> {
+    let proxy = KSynProxy {
+        cookie,
+        delta: 0,
+        state: if held {
+            SYN_PROXY_HELD
+        } else {
+            SYN_PROXY_PENDING
+        },
+        _pad: [0; 7],
+    };
+    SYN_PROXY_MAP
+        .insert(&nat.rev_key, &proxy, 0)
+        .map_err(|_| ())?;
+
+    let reply = TcpReply {
+        seq: client_isn,
+        ack_seq: 0,
+        flags: TCP_FLAG_SYN,
+        window: PROXY_WINDOW,
+    };
+    update_packet_by_way(ctx, ethhdr, iphdr, l4_hdr, &nat.fwd)?;
+    rewrite_tcp(ctx, &reply, false)?;
+    Ok(xdp_action::XDP_TX)
+}
+
+// The backend answered the replayed syn: remember the distance between its
+// isn and the cookie we gave the client, and finish its handshake.
+//
+// A held client still sees a zero window, so its syn-ack turns into the ack
+// opening the window of the client instead, or the client would wait for its
+// next window probe. The backend handshake then finishes with the first
+// segment of the client, or our ack of the syn-ack it retransmits.
+#[inline(always)]
+pub fn handle_backend_syn_ack(
+    ctx: &XdpContext,
+    ethhdr: *mut EthHdr,
+    iphdr: *mut Ipv4Hdr,
+    l4_hdr: &mut L4Hdr,
+    declare_way: &KConnection,
+    nat: &KNat,
+) -> Result<Option<u32>, ()> {
+    if !(l4_hdr.is_syn() && l4_hdr.is_ack()) {
+        return Ok(None);
+    }
+    let proxy = match SYN_PROXY_MAP.get_ptr_mut(declare_way) {
+        Some(proxy) => proxy,
+        None => return Ok(None),
+    };
+    let KSynProxy {
+        cookie,
+        delta,
+        state,
+        ..
+    } = unsafe { *proxy };
+    let server_isn = l4_hdr.get_seq();
+    match state {
+        SYN_PROXY_PENDING => unsafe {
+            (*proxy).delta = cookie.wrapping_sub(server_isn);
+            (*proxy).state = SYN_PROXY_ESTABLISHED;
+        },
+        SYN_PROXY_HELD => {
+            unsafe {
+                (*proxy).delta = cookie.wrapping_sub(server_isn);
+                (*proxy).state = SYN_PROXY_ESTABLISHED;
+            }
+            let reply = TcpReply {
+                seq: cookie.wrapping_add(1),
+                ack_seq: l4_hdr.get_ack_seq(),
+                flags: TCP_FLAG_ACK,
+                window: PROXY_WINDOW,
+            };
+            update_packet_by_way(ctx, ethhdr, iphdr, l4_hdr, &nat.fwd)?;
+            rewrite_tcp(ctx, &reply, false)?;
+            return Ok(Some(xdp_action::XDP_TX));
+        }
+        // a retransmit, the backend missed our ack
+        SYN_PROXY_ESTABLISHED if server_isn.wrapping_add(delta) == cookie => {}
+        _ => return Ok(None),
+    }
+
+    let reply = TcpReply {
+        seq: l4_hdr.get_ack_seq(),
+        ack_seq: server_isn.wrapping_add(1),
+        flags: TCP_FLAG_ACK,
+        window: PROXY_WINDOW,
+    };
+    rewrite_tcp(ctx, &reply, true)?;
+    Ok(Some(xdp_action::XDP_TX))
+}
+
+// keep both ends of a proxied connection in sync with the isn each of them saw
+#[inline(always)]
+pub fn translate_seq(
+    ctx: &XdpContext,
+    iphdr: *mut Ipv4Hdr,
+    l4_hdr: &mut L4Hdr,
+    declare_way: &KConnection,
+    nat: &KNat,
+) -> Result<(), ()> {
+    if let Some(proxy) = unsafe { SYN_PROXY_MAP.get(declare_way) } {
+        // from backend to client
+        if proxy.state != SYN_PROXY_ESTABLISHED {
+            return Ok(());
+        }
+        let seq = l4_hdr.get_seq().wrapping_add(proxy.delta);
+        update_csum(
+            ctx,
+            iphdr,
+            l4_hdr,
+            EthHdr::LEN + Ipv4Hdr::LEN + offset_of!(TcpHdr, seq),
+            seq.to_be(),
+            false,
+        )?;
+        l4_hdr.set_seq(seq);
+    } else if let Some(proxy) = unsafe { SYN_PROXY_MAP.get(&nat.rev_key) } {
+        // from client to backend
+        if proxy.state != SYN_PROXY_ESTABLISHED {
+            return Ok(());
+        }
+        let ack_seq = l4_hdr.get_ack_seq().wrapping_sub(proxy.delta);
+        update_csum(
+            ctx,
+            iphdr,
+            l4_hdr,
+            EthHdr::LEN + Ipv4Hdr::LEN + offset_of!(TcpHdr, ack_seq),
+            ack_seq.to_be(),
+            false,
+        )?;
+        l4_hdr.set_ack_seq(ack_seq);
+    }
+
+    Ok(())
+}
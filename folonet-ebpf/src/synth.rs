@@ -0,0 +1,297 @@
+use aya_ebpf::{
+    helpers::{bpf_csum_diff, bpf_xdp_adjust_head, bpf_xdp_adjust_tail},
+    programs::XdpContext,
+};
+use core::ptr::{copy_nonoverlapping, null_mut};
+use folonet_common::csum_fold_helper;
+use network_types::{
+    eth::{EthHdr, EtherType},
+    ip::{IpProto, Ipv4Hdr},
+    tcp::TcpHdr,
+};
+
+use crate::ptr_at;
+
+pub const TCP_FLAG_FIN: u8 = 0x01;
+pub const TCP_FLAG_SYN: u8 = 0x02;
+pub const TCP_FLAG_RST: u8 = 0x04;
+pub const TCP_FLAG_ACK: u8 = 0x10;
+
+// the header we want the packet to carry, in host byte order
+pub struct TcpReply {
+    pub seq: u32,
+    pub ack_seq: u32,
+    pub flags: u8,
+    pub window: u16,
+}
+
+const MAX_TRIM: usize = 1500;
+
+// a tcp header with all the options it can have
+const MAX_TCP_HDR_LEN: usize = 60;
+const MAX_HEADERS_LEN: usize = EthHdr::LEN + Ipv4Hdr::LEN + MAX_TCP_HDR_LEN;
+// the word of the tcp header holding doff and the flags
+const TCP_DOFF_WORD: usize = 12;
+const IPPROTO_TCP: u32 = 6;
+
+const ICMP_DEST_UNREACH: u8 = 3;
+const ICMP_PORT_UNREACH: u8 = 3;
+const ICMP_HDR_LEN: usize = 8;
+const UDP_HDR_LEN: usize = 8;
+// what an icmp error puts in front of the headers it quotes
+const ICMP_GROW: usize = Ipv4Hdr::LEN + ICMP_HDR_LEN;
+const ICMP_LEN: usize = ICMP_HDR_LEN + Ipv4Hdr::LEN + UDP_HDR_LEN;
+
+#[inline(always)]
+fn ipv4_csum(iphdr: *mut Ipv4Hdr) -> u16 {
+    let sum = unsafe { bpf_csum_diff(null_mut(), 0, iphdr as *mut u32, Ipv4Hdr::LEN as u32, 0) };
+    csum_fold_helper(sum as u64)
+}
+
+#[inline(always)]
+fn tcp_csum(iphdr: *const Ipv4Hdr, tcphdr: *mut TcpHdr) -> u16 {
+    let src = unsafe { (*iphdr).src_addr };
+    let dst = unsafe { (*iphdr).dst_addr };
+
+    // pseudo header, summed in the same byte order as the packet words
+    let pseudo: u64 = (src >> 16) as u64
+        + (src & 0xffff) as u64
+        + (dst >> 16) as u64
+        + (dst & 0xffff) as u64
+        + 6u16.to_be() as u64
+        + (TcpHdr::LEN as u16).to_be() as u64;
+    let pseudo = csum_fold_helper(pseudo);
+
+    let sum = unsafe {
+        bpf_csum_diff(
+            null_mut(),
+            0,
+            tcphdr as *mut u32,
+            TcpHdr::LEN as u32,
+            !pseudo as u32,
+        )
+    };
+    csum_fold_helper(sum as u64)
+}
+
+// Turn the tcp packet in ctx into a bare 20-byte tcp segment carrying `reply`.
+// Options and payload are cut off, checksums are computed from scratch.
+// When `bounce` is set the packet is sent back to where it came from.
+//
+// All packet pointers taken before calling this are invalid afterwards.
+#[inline(always)]
+pub fn rewrite_tcp(ctx: &XdpContext, reply: &TcpReply, bounce: bool) -> Result<(), ()> {
+    let ethhdr: *mut EthHdr = ptr_at(ctx, 0)?;
+    let iphdr: *mut Ipv4Hdr = ptr_at(ctx, EthHdr::LEN)?;
+    let tcphdr: *mut TcpHdr = ptr_at(ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
+
+    let tot_len = u16::from_be(unsafe { (*iphdr).tot_len }) as usize;
+    if tot_len < Ipv4Hdr::LEN + TcpHdr::LEN {
+        return Err(());
+    }
+    let trim = tot_len - Ipv4Hdr::LEN - TcpHdr::LEN;
+    if trim > MAX_TRIM {
+        return Err(());
+    }
+
+    unsafe {
+        if bounce {
+            let mac = (*ethhdr).src_addr;
+            (*ethhdr).src_addr = (*ethhdr).dst_addr;
+            (*ethhdr).dst_addr = mac;
+
+            let ip = (*iphdr).src_addr;
+            (*iphdr).src_addr = (*iphdr).dst_addr;
+            (*iphdr).dst_addr = ip;
+
+            let port = (*tcphdr).source;
+            (*tcphdr).source = (*tcphdr).dest;
+            (*tcphdr).dest = port;
+        }
+
+        (*tcphdr).seq = reply.seq.to_be();
+        (*tcphdr).ack_seq = reply.ack_seq.to_be();
+        (*tcphdr).window = reply.window.to_be();
+        (*tcphdr).urg_ptr = 0;
+        (*tcphdr).set_doff(5);
+        (*tcphdr).set_fin((reply.flags & TCP_FLAG_FIN != 0) as u16);
+        (*tcphdr).set_syn((reply.flags & TCP_FLAG_SYN != 0) as u16);
+        (*tcphdr).set_rst((reply.flags & TCP_FLAG_RST != 0) as u16);
+        (*tcphdr).set_psh(0);
+        (*tcphdr).set_ack((reply.flags & TCP_FLAG_ACK != 0) as u16);
+        (*tcphdr).set_urg(0);
+        (*tcphdr).set_ece(0);
+        (*tcphdr).set_cwr(0);
+    }
+
+    if trim > 0 && unsafe { bpf_xdp_adjust_tail(ctx.ctx, -(trim as i32)) } != 0 {
+        return Err(());
+    }
+
+    let iphdr: *mut Ipv4Hdr = ptr_at(ctx, EthHdr::LEN)?;
+    let tcphdr: *mut TcpHdr = ptr_at(ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
+
+    unsafe {
+        if bounce {
+            (*iphdr).ttl = 64;
+        }
+        (*iphdr).tot_len = ((Ipv4Hdr::LEN + TcpHdr::LEN) as u16).to_be();
+        (*iphdr).check = 0;
+        (*iphdr).check = ipv4_csum(iphdr);
+
+        (*tcphdr).check = 0;
+        (*tcphdr).check = tcp_csum(iphdr, tcphdr);
+    }
+
+    Ok(())
+}
+
+// Turn the udp packet in ctx into the port unreachable its sender would get
+// from a host without the port, quoting its ip and udp headers. The payload
+// is cut off and the packet goes back to where it came from.
+//
+// All packet pointers taken before calling this are invalid afterwards.
+#[inline(always)]
+pub fn rewrite_port_unreachable(ctx: &XdpContext) -> Result<(), ()> {
+    let iphdr: *const Ipv4Hdr = ptr_at(ctx, EthHdr::LEN)?;
+    let tot_len = u16::from_be(unsafe { (*iphdr).tot_len }) as usize;
+    if tot_len < Ipv4Hdr::LEN + UDP_HDR_LEN {
+        return Err(());
+    }
+    let trim = tot_len - Ipv4Hdr::LEN - UDP_HDR_LEN;
+    if trim > MAX_TRIM {
+        return Err(());
+    }
+    if trim > 0 && unsafe { bpf_xdp_adjust_tail(ctx.ctx, -(trim as i32)) } != 0 {
+        return Err(());
+    }
+    // room for the new ip and icmp headers, the old ethernet header ends
+    // right where the quote starts
+    if unsafe { bpf_xdp_adjust_head(ctx.ctx, -(ICMP_GROW as i32)) } != 0 {
+        return Err(());
+    }
+
+    let ethhdr: *mut EthHdr = ptr_at(ctx, 0)?;
+    let old_ethhdr: *const EthHdr = ptr_at(ctx, ICMP_GROW)?;
+    let iphdr: *mut Ipv4Hdr = ptr_at(ctx, EthHdr::LEN)?;
+    let icmp: *mut [u8; ICMP_LEN] = ptr_at(ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
+    let quoted: *const Ipv4Hdr = ptr_at(ctx, EthHdr::LEN + ICMP_GROW)?;
+
+    unsafe {
+        // the old ethernet header is overwritten by the new ip header
+        let (src_mac, dst_mac) = ((*old_ethhdr).dst_addr, (*old_ethhdr).src_addr);
+        (*ethhdr).src_addr = src_mac;
+        (*ethhdr).dst_addr = dst_mac;
+        (*ethhdr).ether_type = EtherType::Ipv4;
+
+        copy_nonoverlapping(quoted, iphdr, 1);
+        (*iphdr).src_addr = (*quoted).dst_addr;
+        (*iphdr).dst_addr = (*quoted).src_addr;
+        (*iphdr).tos = 0;
+        (*iphdr).id = 0;
+        (*iphdr).frag_off = 0;
+        (*iphdr).ttl = 64;
+        (*iphdr).proto = IpProto::Icmp;
+        (*iphdr).tot_len = ((Ipv4Hdr::LEN + ICMP_LEN) as u16).to_be();
+        (*iphdr).check = 0;
+        (*iphdr).check = ipv4_csum(iphdr);
+
+        // type, code, checksum and 4 unused bytes, then the quote
+        (*icmp)[0] = ICMP_DEST_UNREACH;
+        (*icmp)[1] = ICMP_PORT_UNREACH;
+        (*icmp)[2..ICMP_HDR_LEN].fill(0);
+        let sum = bpf_csum_diff(null_mut(), 0, icmp as *mut u32, ICMP_LEN as u32, 0);
+        let check = csum_fold_helper(sum as u64).to_ne_bytes();
+        (*icmp)[2] = check[0];
+        (*icmp)[3] = check[1];
+    }
+
+    Ok(())
+}
+
+// Put `bytes` between the tcp header and the payload of the packet in ctx,
+// moving the headers to the front to make room, and update the checksums
+// for the longer packet. With `as_option` the bytes are a tcp option and the
+// tcp header grows by them, false when it has no room left for them.
+//
+// All packet pointers taken before calling this are invalid afterwards.
+#[inline(always)]
+pub fn insert_after_tcp_hdr<const N: usize>(
+    ctx: &XdpContext,
+    bytes: &mut [u8; N],
+    as_option: bool,
+) -> Result<bool, ()> {
+    let tcphdr: *const TcpHdr = ptr_at(ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
+    let tcp_len = unsafe { (*tcphdr).doff() } as usize * 4;
+    if !(TcpHdr::LEN..=MAX_TCP_HDR_LEN).contains(&tcp_len) {
+        return Err(());
+    }
+    if as_option && tcp_len + N > MAX_TCP_HDR_LEN {
+        return Ok(false);
+    }
+    let headers_len = EthHdr::LEN + Ipv4Hdr::LEN + tcp_len;
+
+    if unsafe { bpf_xdp_adjust_head(ctx.ctx, -(N as i32)) } != 0 {
+        return Err(());
+    }
+    // front to back, each byte lands before the ones still to be moved
+    for i in 0..MAX_HEADERS_LEN {
+        if i >= headers_len {
+            break;
+        }
+        let from: *const u8 = ptr_at(ctx, N + i)?;
+        let to: *mut u8 = ptr_at(ctx, i)?;
+        unsafe { *to = *from };
+    }
+    let inserted: *mut [u8; N] = ptr_at(ctx, headers_len)?;
+    unsafe { *inserted = *bytes };
+
+    let iphdr: *mut Ipv4Hdr = ptr_at(ctx, EthHdr::LEN)?;
+    let tcphdr: *mut TcpHdr = ptr_at(ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
+    let ip_len = u16::from_be(unsafe { (*iphdr).tot_len });
+    let new_ip_len = ip_len + N as u16;
+
+    // tot_len shares its word with the version and tos
+    let word: *mut u32 = ptr_at(ctx, EthHdr::LEN)?;
+    let mut old_word = unsafe { *word };
+    unsafe { (*iphdr).tot_len = new_ip_len.to_be() };
+    let mut new_word = unsafe { *word };
+    unsafe {
+        let sum = bpf_csum_diff(&mut old_word, 4, &mut new_word, 4, !((*iphdr).check) as u32);
+        (*iphdr).check = csum_fold_helper(sum as u64);
+    }
+
+    // the tcp length of the pseudo header, then doff when it changes, then
+    // the bytes themselves, which start on an even offset of the segment
+    let len_word = |len: u16| (IPPROTO_TCP << 16 | len as u32).to_be();
+    let mut old_word = len_word(ip_len - Ipv4Hdr::LEN as u16);
+    let mut new_word = len_word(new_ip_len - Ipv4Hdr::LEN as u16);
+    let mut sum = unsafe {
+        bpf_csum_diff(
+            &mut old_word,
+            4,
+            &mut new_word,
+            4,
+            !((*tcphdr).check) as u32,
+        )
+    };
+    if as_option {
+        let word: *mut u32 = ptr_at(ctx, EthHdr::LEN + Ipv4Hdr::LEN + TCP_DOFF_WORD)?;
+        let mut old_word = unsafe { *word };
+        unsafe { (*tcphdr).set_doff(((tcp_len + N) / 4) as u16) };
+        let mut new_word = unsafe { *word };
+        sum = unsafe { bpf_csum_diff(&mut old_word, 4, &mut new_word, 4, sum as u32) };
+    }
+    unsafe {
+        let sum = bpf_csum_diff(
+            null_mut(),
+            0,
+            bytes.as_mut_ptr() as *mut u32,
+            N as u32,
+            sum as u32,
+        );
+        (*tcphdr).check = csum_fold_helper(sum as u64);
+    }
+
+    Ok(true)
+}
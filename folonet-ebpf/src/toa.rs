@@ -0,0 +1,25 @@
+use aya_ebpf::programs::XdpContext;
+use folonet_common::{stats::Counter, toa::toa_option, KConnection};
+
+use crate::{incr_counter, synth::insert_after_tcp_hdr, TOA_MAP};
+
+// whether the backends of the service of the client want its address in a
+// TOA option of the syn
+#[inline(always)]
+pub fn enabled(declare_way: &KConnection) -> bool {
+    unsafe { TOA_MAP.get(&declare_way.to) }.is_some()
+}
+
+// Add the TOA option of the client of `declare_way` to the syn in ctx,
+// which is rewritten toward the backend already. A syn whose header is
+// full of options goes on without it.
+//
+// All packet pointers taken before calling this are invalid afterwards.
+#[inline(always)]
+pub fn insert_option(ctx: &XdpContext, declare_way: &KConnection) -> Result<(), ()> {
+    let mut option = toa_option(&declare_way.from);
+    if insert_after_tcp_hdr(ctx, &mut option, true)? {
+        incr_counter(Counter::ToaSent);
+    }
+    Ok(())
+}
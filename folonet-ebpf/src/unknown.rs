@@ -0,0 +1,59 @@
+use aya_ebpf::{bindings::xdp_action, programs::XdpContext};
+use folonet_common::{
+    config::{KConfig, UNKNOWN_CONN_DROP, UNKNOWN_CONN_RST},
+    stats::Counter,
+    L4Hdr,
+};
+use network_types::ip::Ipv4Hdr;
+
+use crate::{
+    incr_counter,
+    synth::{rewrite_tcp, TcpReply, TCP_FLAG_ACK, TCP_FLAG_RST},
+};
+
+// A tcp packet other than a syn for a connection the xdp program has no state
+// of, e.g. one opened before a restart. It gets no port, no nat entry and
+// starts no backend.
+#[inline(always)]
+pub fn handle(
+    ctx: &XdpContext,
+    cfg: Option<&KConfig>,
+    iphdr: *const Ipv4Hdr,
+    l4_hdr: &L4Hdr,
+) -> Result<u32, ()> {
+    incr_counter(Counter::UnknownConnection);
+
+    match cfg.map(|cfg| cfg.unknown_conn_action).unwrap_or_default() {
+        UNKNOWN_CONN_DROP => Ok(xdp_action::XDP_DROP),
+        // a reset is never answered
+        UNKNOWN_CONN_RST if l4_hdr.is_rst() => Ok(xdp_action::XDP_DROP),
+        UNKNOWN_CONN_RST => {
+            rewrite_tcp(ctx, &reset_for(iphdr, l4_hdr), true)?;
+            Ok(xdp_action::XDP_TX)
+        }
+        _ => Ok(xdp_action::XDP_PASS),
+    }
+}
+
+// the reset rfc 793 sends for a segment of a connection that does not exist
+#[inline(always)]
+pub fn reset_for(iphdr: *const Ipv4Hdr, l4_hdr: &L4Hdr) -> TcpReply {
+    if l4_hdr.is_ack() {
+        return TcpReply {
+            seq: l4_hdr.get_ack_seq(),
+            ack_seq: 0,
+            flags: TCP_FLAG_RST,
+            window: 0,
+        };
+    }
+
+    let doff = l4_hdr.header_len() as u32;
+    let ip_len = u16::from_be(unsafe { (*iphdr).tot_len }) as u32;
+    let seg_len = ip_len.saturating_sub(Ipv4Hdr::LEN as u32 + doff) + l4_hdr.is_fin() as u32;
+    TcpReply {
+        seq: 0,
+        ack_seq: l4_hdr.get_seq().wrapping_add(seg_len),
+        flags: TCP_FLAG_RST | TCP_FLAG_ACK,
+        window: 0,
+    }
+}
@@ -0,0 +1,55 @@
+use aya_ebpf::{bindings::xdp_action, helpers::bpf_xdp_adjust_meta, programs::XdpContext};
+use folonet_common::{
+    config::KConfig,
+    nat::KRewrite,
+    stats::Counter,
+    xsk::{KXskMeta, XSK_META_LEN},
+};
+use network_types::eth::EthHdr;
+
+use crate::{incr_counter, new_macs, XSK_MAP};
+
+// Hands the packet, not rewritten yet, to the af_xdp socket of its rx queue
+// with its rewrite in the metadata in front of it. None when the interface
+// has no af_xdp workers or none listens on the queue, the xdp program
+// rewrites the packet itself then. A driver without room for metadata has the
+// packet dropped, as the packet pointers of the caller are no good after
+// trying.
+#[inline(always)]
+pub fn redirect(
+    ctx: &XdpContext,
+    cfg: Option<&KConfig>,
+    ethhdr: *const EthHdr,
+    rewrite: &KRewrite,
+) -> Option<u32> {
+    let md = unsafe { *ctx.ctx };
+    if !cfg.is_some_and(|cfg| cfg.af_xdp_ifindex != 0 && cfg.af_xdp_ifindex == md.ingress_ifindex) {
+        return None;
+    }
+    let queue = md.rx_queue_index;
+    XSK_MAP.get(queue)?;
+
+    let mut meta = KXskMeta {
+        way: rewrite.way,
+        ..Default::default()
+    };
+    match new_macs(ethhdr, rewrite.way.to.ip(), rewrite.mac_policy) {
+        Some((src_mac, dst_mac)) => {
+            meta.src_mac = src_mac;
+            meta.dst_mac = dst_mac;
+        }
+        None => meta.keep_macs = 1,
+    }
+
+    if unsafe { bpf_xdp_adjust_meta(ctx.ctx, -(XSK_META_LEN as i32)) } != 0 {
+        return Some(xdp_action::XDP_DROP);
+    }
+    let start = ctx.metadata();
+    if start + XSK_META_LEN > ctx.data() {
+        return Some(xdp_action::XDP_DROP);
+    }
+    unsafe { (start as *mut KXskMeta).write_unaligned(meta) };
+
+    incr_counter(Counter::XskRedirected);
+    Some(XSK_MAP.redirect(queue, 0).unwrap_or(xdp_action::XDP_DROP))
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::process::ExitStatus;
+
+use hyper::{Body, Client, Method, Request};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::config::{BackendConfig, ManagedService};
+
+const READY_POLL: Duration = Duration::from_millis(200);
+
+#[derive(Debug)]
+pub enum BackendError {
+    Spawn { argv: String, source: io::Error },
+    Exit { argv: String, status: ExitStatus },
+    Webhook(String),
+    NoServerEndpoint(String),
+    NotReady(String),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::Spawn { argv, source } => write!(f, "failed to run {}: {}", argv, source),
+            BackendError::Exit { argv, status } => write!(f, "{} exited with {}", argv, status),
+            BackendError::Webhook(msg) => write!(f, "webhook: {}", msg),
+            BackendError::NoServerEndpoint(name) => {
+                write!(f, "no server endpoint known for {}", name)
+            }
+            BackendError::NotReady(addr) => write!(f, "{} did not come up in time", addr),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+#[derive(Serialize)]
+struct HookRequest<'a> {
+    action: &'a str,
+    name: &'a str,
+    local_endpoint: &'a str,
+    // what folonet knows of the service, only sent with a start
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    metadata: &'a HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct HookResponse {
+    #[serde(default)]
+    server_endpoint: Option<String>,
+}
+
+// FOLONET_META_<KEY> for every metadata entry, the key upper cased with
+// anything but letters and digits turned into underscores
+fn metadata_env(metadata: &HashMap<String, String>) -> Vec<(String, String)> {
+    metadata
+        .iter()
+        .map(|(key, value)| {
+            let key: String = key
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() {
+                        c.to_ascii_uppercase()
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            (format!("FOLONET_META_{}", key), value.clone())
+        })
+        .collect()
+}
+
+// start the backend of `svc` and wait until it accepts connections, returns
+// where it listens. `metadata` comes from the service config of folonet.
+pub async fn start(
+    svc: &ManagedService,
+    metadata: &HashMap<String, String>,
+) -> Result<String, BackendError> {
+    let reported = match &svc.backend {
+        BackendConfig::Exec { start, .. } => {
+            run(start, &metadata_env(metadata)).await?;
+            None
+        }
+        BackendConfig::Docker { container } => {
+            run(&docker("start", container), &[]).await?;
+            None
+        }
+        BackendConfig::Webhook { url } => hook(url, "start", svc, metadata).await?.server_endpoint,
+    };
+
+    let server_endpoint = reported
+        .or_else(|| svc.server_endpoint.clone())
+        .ok_or_else(|| BackendError::NoServerEndpoint(svc.name.clone()))?;
+    wait_ready(
+        &server_endpoint,
+        Duration::from_secs(svc.ready_timeout_secs),
+    )
+    .await?;
+    Ok(server_endpoint)
+}
+
+pub async fn stop(svc: &ManagedService) -> Result<(), BackendError> {
+    match &svc.backend {
+        BackendConfig::Exec { stop, .. } if stop.is_empty() => Ok(()),
+        BackendConfig::Exec { stop, .. } => run(stop, &[]).await,
+        BackendConfig::Docker { container } => run(&docker("stop", container), &[]).await,
+        BackendConfig::Webhook { url } => hook(url, "stop", svc, &HashMap::new()).await.map(|_| ()),
+    }
+}
+
+// tell the backend of `svc` it is about to be stopped, a docker backend just
+// gets stopped when the time comes
+pub async fn drain(svc: &ManagedService, deadline: Duration) -> Result<(), BackendError> {
+    match &svc.backend {
+        BackendConfig::Exec { drain, .. } if drain.is_empty() => Ok(()),
+        BackendConfig::Exec { drain, .. } => {
+            let env = [(
+                "FOLONET_DRAIN_DEADLINE_MS".to_string(),
+                deadline.as_millis().to_string(),
+            )];
+            run(drain, &env).await
+        }
+        BackendConfig::Docker { .. } => Ok(()),
+        BackendConfig::Webhook { url } => {
+            hook(url, "drain", svc, &HashMap::new()).await.map(|_| ())
+        }
+    }
+}
+
+fn docker(action: &str, container: &str) -> Vec<String> {
+    vec![
+        "docker".to_string(),
+        action.to_string(),
+        container.to_string(),
+    ]
+}
+
+async fn run(argv: &[String], env: &[(String, String)]) -> Result<(), BackendError> {
+    let joined = argv.join(" ");
+    let (program, args) = match argv.split_first() {
+        Some(split) => split,
+        None => {
+            return Err(BackendError::Spawn {
+                argv: joined,
+                source: io::Error::new(io::ErrorKind::InvalidInput, "empty command"),
+            })
+        }
+    };
+    let status = Command::new(program)
+        .args(args)
+        .envs(env.iter().cloned())
+        .status()
+        .await
+        .map_err(|source| BackendError::Spawn {
+            argv: joined.clone(),
+            source,
+        })?;
+    if !status.success() {
+        return Err(BackendError::Exit {
+            argv: joined,
+            status,
+        });
+    }
+    Ok(())
+}
+
+async fn hook(
+    url: &str,
+    action: &str,
+    svc: &ManagedService,
+    metadata: &HashMap<String, String>,
+) -> Result<HookResponse, BackendError> {
+    let body = serde_json::to_vec(&HookRequest {
+        action,
+        name: &svc.name,
+        local_endpoint: &svc.local_endpoint,
+        metadata,
+    })
+    .map_err(|e| BackendError::Webhook(e.to_string()))?;
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header("content-type", "application/json")
+        .body(Body::from(body))
+        .map_err(|e| BackendError::Webhook(e.to_string()))?;
+
+    let response = Client::new()
+        .request(request)
+        .await
+        .map_err(|e| BackendError::Webhook(format!("{}: {}", url, e)))?;
+    if !response.status().is_success() {
+        return Err(BackendError::Webhook(format!(
+            "{} answered {}",
+            url,
+            response.status()
+        )));
+    }
+
+    let bytes = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| BackendError::Webhook(e.to_string()))?;
+    if bytes.is_empty() {
+        return Ok(HookResponse::default());
+    }
+    serde_json::from_slice(&bytes).map_err(|e| BackendError::Webhook(e.to_string()))
+}
+
+async fn wait_ready(addr: &str, timeout: Duration) -> Result<(), BackendError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if TcpStream::connect(addr).await.is_ok() {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(BackendError::NotReady(addr.to_string()));
+        }
+        sleep(READY_POLL).await;
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_metadata_env() {
+        use std::collections::HashMap;
+
+        use super::metadata_env;
+
+        let metadata: HashMap<String, String> = [("memory-size".to_string(), "512Mi".to_string())]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            metadata_env(&metadata),
+            vec![("FOLONET_META_MEMORY_SIZE".to_string(), "512Mi".to_string())]
+        );
+    }
+}
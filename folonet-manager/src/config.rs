@@ -0,0 +1,89 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ManagerConfig {
+    #[serde(default = "default_listen")]
+    pub listen: String,
+    #[serde(default)]
+    pub services: Vec<ManagedService>,
+}
+
+fn default_listen() -> String {
+    "[::1]:7788".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManagedService {
+    pub name: String,
+    // the endpoint folonet asks for
+    pub local_endpoint: String,
+    // where the started backend listens, a webhook may answer with another one
+    #[serde(default)]
+    pub server_endpoint: Option<String>,
+    pub backend: BackendConfig,
+    // how long a started backend gets to accept connections
+    #[serde(default = "default_ready_timeout_secs")]
+    pub ready_timeout_secs: u64,
+}
+
+fn default_ready_timeout_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BackendConfig {
+    // argv of the commands starting, stopping and draining the backend
+    Exec {
+        start: Vec<String>,
+        #[serde(default)]
+        stop: Vec<String>,
+        #[serde(default)]
+        drain: Vec<String>,
+    },
+    Docker {
+        container: String,
+    },
+    // POSTed {"action": "start" | "stop" | "drain", "name", "local_endpoint"}
+    Webhook {
+        url: String,
+    },
+}
+
+mod test {
+
+    #[test]
+    fn test_parse_manager_config() {
+        use super::{BackendConfig, ManagerConfig};
+
+        let cfg: ManagerConfig = serde_yaml::from_str(
+            r#"
+services:
+  - name: web
+    local_endpoint: 10.0.0.1:8080
+    server_endpoint: 10.0.0.2:80
+    backend:
+      type: docker
+      container: web
+  - name: api
+    local_endpoint: 10.0.0.1:8081
+    backend:
+      type: webhook
+      url: http://127.0.0.1:9000/hook
+    ready_timeout_secs: 5
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(cfg.listen, "[::1]:7788");
+        assert_eq!(
+            cfg.services[0].backend,
+            BackendConfig::Docker {
+                container: "web".to_string()
+            }
+        );
+        assert_eq!(cfg.services[0].ready_timeout_secs, 30);
+        assert_eq!(cfg.services[1].server_endpoint, None);
+        assert_eq!(cfg.services[1].ready_timeout_secs, 5);
+    }
+}
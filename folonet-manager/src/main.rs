@@ -0,0 +1,56 @@
+use std::fs;
+use std::net::SocketAddr;
+
+use anyhow::Context;
+use clap::Parser;
+use folonet_client::folonetrpc::server_manager_server::ServerManagerServer;
+use log::{info, warn};
+use tokio::signal;
+use tonic::transport::Server;
+
+mod backend;
+mod config;
+mod server;
+
+use config::ManagerConfig;
+use server::Manager;
+
+// A reference server manager: starts and stops the backends of the services
+// in its config when folonet asks for them.
+#[derive(Debug, Parser)]
+struct Opt {
+    #[clap(short, long, default_value = "./manager.yaml")]
+    config: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    env_logger::init();
+    let opt = Opt::parse();
+
+    let cfg_str = fs::read_to_string(&opt.config)
+        .with_context(|| format!("failed to read {}", opt.config))?;
+    let cfg: ManagerConfig = serde_yaml::from_str(&cfg_str)
+        .with_context(|| format!("invalid manager config {}", opt.config))?;
+    let addr: SocketAddr = cfg
+        .listen
+        .parse()
+        .with_context(|| format!("invalid listen address {}", cfg.listen))?;
+
+    info!(
+        "managing {} service(s), listening on {}",
+        cfg.services.len(),
+        addr
+    );
+    Server::builder()
+        .add_service(ServerManagerServer::new(Manager::new(cfg.services)))
+        .serve_with_shutdown(addr, async {
+            if let Err(e) = signal::ctrl_c().await {
+                warn!("failed to listen for Ctrl-C: {}", e);
+            }
+        })
+        .await?;
+
+    info!("Exiting...");
+    Ok(())
+}
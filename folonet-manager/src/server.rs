@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+
+use folonet_client::folonetrpc::{
+    server_manager_server::ServerManager, DrainServerRequest, DrainServerResponse,
+    ListServersRequest, ListServersResponse, ServerInfo, StartServerRequest, StartServerResponse,
+    StopServerRequest, StopServerResponse,
+};
+use log::{info, warn};
+use tokio::sync::Mutex;
+use tokio::time::Duration;
+use tonic::{Request, Response, Status};
+
+use crate::backend;
+use crate::config::ManagedService;
+
+struct Slot {
+    cfg: ManagedService,
+    // where the backend listens while it runs; the lock also keeps two
+    // starts of the same service apart
+    running: Mutex<Option<String>>,
+}
+
+pub struct Manager {
+    slots: HashMap<String, Slot>,
+}
+
+impl Manager {
+    pub fn new(services: Vec<ManagedService>) -> Self {
+        let slots = services
+            .into_iter()
+            .map(|cfg| {
+                (
+                    cfg.local_endpoint.clone(),
+                    Slot {
+                        cfg,
+                        running: Mutex::new(None),
+                    },
+                )
+            })
+            .collect();
+        Manager { slots }
+    }
+}
+
+#[tonic::async_trait]
+impl ServerManager for Manager {
+    async fn start_server(
+        &self,
+        request: Request<StartServerRequest>,
+    ) -> Result<Response<StartServerResponse>, Status> {
+        let StartServerRequest {
+            local_endpoint,
+            metadata,
+        } = request.into_inner();
+        let slot = match self.slots.get(&local_endpoint) {
+            Some(slot) => slot,
+            None => {
+                return Ok(Response::new(StartServerResponse {
+                    active: false,
+                    ..Default::default()
+                }))
+            }
+        };
+
+        let mut running = slot.running.lock().await;
+        let server_endpoint = match running.as_ref() {
+            // already up, e.g. a retried call
+            Some(server_endpoint) => server_endpoint.clone(),
+            None => {
+                info!("start {} for {}", slot.cfg.name, local_endpoint);
+                let server_endpoint = backend::start(&slot.cfg, &metadata)
+                    .await
+                    .map_err(|e| Status::internal(e.to_string()))?;
+                running.replace(server_endpoint.clone());
+                server_endpoint
+            }
+        };
+
+        Ok(Response::new(StartServerResponse {
+            server_endpoint,
+            active: true,
+            name: slot.cfg.name.clone(),
+        }))
+    }
+
+    async fn stop_server(
+        &self,
+        request: Request<StopServerRequest>,
+    ) -> Result<Response<StopServerResponse>, Status> {
+        let local_endpoint = request.into_inner().local_endpoint;
+        if let Some(slot) = self.slots.get(&local_endpoint) {
+            let mut running = slot.running.lock().await;
+            if running.is_some() {
+                info!("stop {} for {}", slot.cfg.name, local_endpoint);
+                if let Err(e) = backend::stop(&slot.cfg).await {
+                    warn!("failed to stop {}: {}", slot.cfg.name, e);
+                    return Err(Status::internal(e.to_string()));
+                }
+                running.take();
+            }
+        }
+        Ok(Response::new(StopServerResponse {}))
+    }
+
+    async fn drain_server(
+        &self,
+        request: Request<DrainServerRequest>,
+    ) -> Result<Response<DrainServerResponse>, Status> {
+        let DrainServerRequest {
+            local_endpoint,
+            deadline_ms,
+        } = request.into_inner();
+        if let Some(slot) = self.slots.get(&local_endpoint) {
+            let running = slot.running.lock().await;
+            if running.is_some() {
+                info!("drain {} for {}", slot.cfg.name, local_endpoint);
+                let deadline = Duration::from_millis(deadline_ms);
+                if let Err(e) = backend::drain(&slot.cfg, deadline).await {
+                    warn!("failed to drain {}: {}", slot.cfg.name, e);
+                    return Err(Status::internal(e.to_string()));
+                }
+            }
+        }
+        Ok(Response::new(DrainServerResponse {}))
+    }
+
+    async fn list_servers(
+        &self,
+        _request: Request<ListServersRequest>,
+    ) -> Result<Response<ListServersResponse>, Status> {
+        let mut servers = vec![];
+        for (local_endpoint, slot) in self.slots.iter() {
+            let running = slot.running.lock().await;
+            servers.push(ServerInfo {
+                local_endpoint: local_endpoint.clone(),
+                server_endpoint: running
+                    .clone()
+                    .or_else(|| slot.cfg.server_endpoint.clone())
+                    .unwrap_or_default(),
+                name: slot.cfg.name.clone(),
+                active: running.is_some(),
+            });
+        }
+        servers.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Response::new(ListServersResponse { servers }))
+    }
+}
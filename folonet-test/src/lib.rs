@@ -0,0 +1,254 @@
+// End to end tests of the datapath. A harness builds the topology of
+// `folonet demo`, starts a folonet daemon on it with the backend as a static
+// server of the service, and opens real tcp connections from the client
+// namespace through the nat of the xdp program. What the daemon holds is read
+// back over its admin socket: the kernel CONNECTION map, the state machines
+// and the local port pool.
+//
+// The tests need root and a built folonet, the one in `FOLONET_BIN` or
+// `target/debug/folonet`, which loads its xdp program as `folonet run` does.
+
+use std::fs;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::{anyhow, Context};
+use folonet_client::config::GlobalConfig;
+use folonet_core::admin::{self, AdminCall, AdminRequest};
+use folonet_core::demo::{
+    self, BACKEND_IP, BACKEND_PORT, NS_BACKEND, NS_CLIENT, SERVICE_IP, SERVICE_PORT,
+};
+use folonet_core::output::OutputFormat;
+use serde_json::Value;
+use tokio::time::{sleep, Duration, Instant};
+
+pub use folonet_core::demo::CLIENT_IP;
+
+// the tests share the namespaces of the demo, one runs at a time
+static TOPOLOGY: Mutex<()> = Mutex::new(());
+
+const DIR: &str = "/tmp/folonet-test";
+// apart from the pins of a daemon running on the host
+const PIN_PATH: &str = "/sys/fs/bpf/folonet-test";
+const UP_TIMEOUT: Duration = Duration::from_secs(15);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL: Duration = Duration::from_millis(100);
+const LINE: &[u8] = b"hello folonet\n";
+
+pub fn service() -> String {
+    format!("{}:{}", SERVICE_IP, SERVICE_PORT)
+}
+
+pub fn backend() -> String {
+    format!("{}:{}", BACKEND_IP, BACKEND_PORT)
+}
+
+fn folonet_exe() -> PathBuf {
+    match std::env::var_os("FOLONET_BIN") {
+        Some(exe) => PathBuf::from(exe),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("../target/debug/folonet"),
+    }
+}
+
+// `argv` in the background, its output in `<dir>/<name>.log`
+fn spawn(dir: &Path, name: &str, argv: &[String]) -> anyhow::Result<Child> {
+    let log = fs::File::create(dir.join(format!("{}.log", name)))?;
+    Command::new(&argv[0])
+        .args(&argv[1..])
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .spawn()
+        .with_context(|| format!("failed to start {}", argv.join(" ")))
+}
+
+// A folonet daemon attached to the demo topology. The daemon, the backend and
+// the namespaces go away with it.
+pub struct Harness {
+    dir: PathBuf,
+    socket: String,
+    children: Vec<Child>,
+    _topology: MutexGuard<'static, ()>,
+}
+
+impl Harness {
+    // `tweak` changes the config of the daemon before it starts, logs and
+    // config end up in `/tmp/folonet-test/<name>`
+    pub async fn up(name: &str, tweak: impl FnOnce(&mut GlobalConfig)) -> anyhow::Result<Self> {
+        let topology = TOPOLOGY.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = Path::new(DIR).join(name);
+        fs::create_dir_all(&dir)?;
+        // of a run that did not get to clean up
+        for command in demo::teardown_commands() {
+            let _ = demo::run(&command);
+        }
+        let _ = fs::remove_dir_all(PIN_PATH);
+
+        let mut harness = Harness {
+            socket: dir.join("folonet.sock").to_string_lossy().to_string(),
+            dir,
+            children: vec![],
+            _topology: topology,
+        };
+        for command in demo::topology_commands() {
+            demo::run(&command)?;
+        }
+
+        let exe = folonet_exe();
+        let exe_arg = exe.to_string_lossy().to_string();
+        let echo = format!(
+            "ip netns exec {} {} demo echo {}",
+            NS_BACKEND,
+            exe_arg,
+            backend()
+        );
+        let echo: Vec<String> = echo.split_whitespace().map(str::to_string).collect();
+        harness.children.push(spawn(&harness.dir, "echo", &echo)?);
+
+        let client_mac = demo::mac_of(NS_CLIENT, "eth0")?;
+        let backend_mac = demo::mac_of(NS_BACKEND, "eth0")?;
+        let mut cfg = demo::folonet_config(&harness.dir, &client_mac, &backend_mac);
+        cfg.services[0].servers = vec![backend()];
+        cfg.pinning.path = PIN_PATH.to_string();
+        cfg.admin.socket = harness.socket.clone();
+        tweak(&mut cfg);
+        let cfg_path = harness.dir.join("folonet.yaml");
+        fs::write(&cfg_path, serde_yaml::to_string(&cfg)?)?;
+
+        let folonet = [
+            exe_arg,
+            "--config".to_string(),
+            cfg_path.to_string_lossy().to_string(),
+            "run".to_string(),
+        ];
+        harness
+            .children
+            .push(spawn(&harness.dir, "folonet", &folonet)?);
+
+        let deadline = Instant::now() + UP_TIMEOUT;
+        while !Path::new(&harness.socket).exists() {
+            if Instant::now() >= deadline {
+                return Err(anyhow!(
+                    "folonet did not come up, see {}",
+                    harness.dir.join("folonet.log").display()
+                ));
+            }
+            sleep(POLL).await;
+        }
+        Ok(harness)
+    }
+
+    // A connection from the client namespace to the service. The socket is
+    // made on a thread that joined the namespace and stays in it.
+    pub fn connect(&self) -> io::Result<TcpStream> {
+        let ns = fs::File::open(Path::new("/var/run/netns").join(NS_CLIENT))?;
+        let service: SocketAddr = service().parse().unwrap();
+        std::thread::spawn(move || {
+            if unsafe { libc::setns(ns.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let stream = TcpStream::connect_timeout(&service, CONNECT_TIMEOUT)?;
+            stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+            Ok(stream)
+        })
+        .join()
+        .map_err(|_| io::Error::other("the connecting thread panicked"))?
+    }
+
+    // the report of `request` as the daemon renders it in json
+    pub async fn admin(&self, request: AdminRequest) -> anyhow::Result<Value> {
+        let call = AdminCall {
+            request,
+            output: OutputFormat::Json,
+        };
+        let out = admin::send(&self.socket, &call).await?;
+        let envelope: Value = serde_json::from_str(&out)?;
+        Ok(envelope["data"].clone())
+    }
+
+    // the connections the state machines track
+    pub async fn connections(&self) -> anyhow::Result<Vec<Value>> {
+        let report = self.admin(AdminRequest::ConnectionsList).await?;
+        Ok(report["connections"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    // the CONNECTION map, every way of a connection to the way it is
+    // rewritten to with its conntrack state, e.g. `10.77.0.2:40000 ->
+    // 10.77.0.1:80` to `10.77.0.1:10000 -> 10.77.0.3:9000 (established)`
+    pub async fn connection_map(&self) -> anyhow::Result<Vec<(String, String)>> {
+        let report = self
+            .admin(AdminRequest::MapsDump {
+                map: "connection".to_string(),
+            })
+            .await?;
+        Ok(report["entries"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|entry| {
+                let text = |key: &str| entry[key].as_str().unwrap_or_default().to_string();
+                (text("key"), text("value"))
+            })
+            .collect())
+    }
+
+    pub async fn ports(&self) -> anyhow::Result<Value> {
+        self.admin(AdminRequest::PortsStatus).await
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        for child in self.children.iter_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        for command in demo::teardown_commands() {
+            let _ = demo::run(&command);
+        }
+        let _ = fs::remove_dir_all(PIN_PATH);
+    }
+}
+
+// send a line over `stream` and read it back from the echoing backend
+pub fn exchange(stream: &mut TcpStream) -> io::Result<()> {
+    stream.write_all(LINE)?;
+    let mut back = vec![0u8; LINE.len()];
+    stream.read_exact(&mut back)?;
+    if back != LINE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the backend sent back garbage",
+        ));
+    }
+    Ok(())
+}
+
+// wait up to `timeout` for `check` to hold
+pub async fn eventually<F, Fut>(what: &str, timeout: Duration, mut check: F) -> anyhow::Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<bool>>,
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        if check().await? {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(anyhow!(
+                "timed out after {:?} waiting for {}",
+                timeout,
+                what
+            ));
+        }
+        sleep(POLL).await;
+    }
+}
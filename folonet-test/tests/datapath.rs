@@ -0,0 +1,96 @@
+// These need root and a built folonet, run them with
+// `sudo -E cargo test -p folonet-test -- --ignored --test-threads 1`.
+
+use folonet_test::{backend, eventually, exchange, Harness, CLIENT_IP};
+use tokio::time::Duration;
+
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs root and a built folonet"]
+async fn test_tcp_through_nat() {
+    let harness = Harness::up("nat", |_| {}).await.unwrap();
+    let mut stream = harness.connect().unwrap();
+    exchange(&mut stream).unwrap();
+    let client = stream.local_addr().unwrap().to_string();
+    assert!(client.starts_with(CLIENT_IP));
+
+    // the way of the client is rewritten toward the backend
+    let map = harness.connection_map().await.unwrap();
+    let (_, rewritten) = map
+        .iter()
+        .find(|(way, _)| way.starts_with(&client))
+        .expect("no CONNECTION entry of the client");
+    assert!(rewritten.ends_with(&format!("{} (established)", backend())));
+    assert!(map.iter().any(|(way, _)| way.starts_with(&backend())));
+
+    let connections = harness.connections().await.unwrap();
+    assert_eq!(connections.len(), 1);
+    assert_eq!(connections[0]["client"], client.as_str());
+    assert_eq!(connections[0]["backend"], backend().as_str());
+    assert_eq!(connections[0]["state"], "Established");
+    let local_port = connections[0]["local_port"].as_u64().unwrap();
+    assert_eq!(
+        harness.ports().await.unwrap()["in_use"].as_u64(),
+        Some(1),
+        "local port {} not taken",
+        local_port
+    );
+
+    drop(stream);
+    eventually("the state machines to let go", CLOSE_TIMEOUT, || async {
+        Ok(harness.connections().await?.is_empty())
+    })
+    .await
+    .unwrap();
+    eventually("the CONNECTION entries to go", CLOSE_TIMEOUT, || async {
+        let map = harness.connection_map().await?;
+        Ok(!map.iter().any(|(way, _)| way.starts_with(&client)))
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs root and a built folonet"]
+async fn test_port_recycling() {
+    // more connections one after the other than the service has ports
+    let harness = Harness::up("ports", |cfg| {
+        cfg.services[0].port_quota = Some(2);
+        cfg.ports.quarantine_secs = 1;
+    })
+    .await
+    .unwrap();
+    let mut local_ports = vec![];
+    for _ in 0..6 {
+        eventually("a free local port", CLOSE_TIMEOUT, || async {
+            Ok(harness.ports().await?["in_use"].as_u64() < Some(2))
+        })
+        .await
+        .unwrap();
+
+        let mut stream = harness.connect().unwrap();
+        exchange(&mut stream).unwrap();
+        let connections = harness.connections().await.unwrap();
+        local_ports.push(connections[0]["local_port"].as_u64().unwrap());
+        drop(stream);
+        eventually("the connection to close", CLOSE_TIMEOUT, || async {
+            Ok(harness.connections().await?.is_empty())
+        })
+        .await
+        .unwrap();
+    }
+
+    local_ports.sort_unstable();
+    local_ports.dedup();
+    assert!(
+        local_ports.len() <= 2,
+        "ports {:?} past the quota",
+        local_ports
+    );
+    eventually("the ports to come back", CLOSE_TIMEOUT, || async {
+        Ok(harness.ports().await?["in_use"].as_u64() == Some(0))
+    })
+    .await
+    .unwrap();
+}
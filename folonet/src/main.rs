@@ -1,71 +1,521 @@
-use anyhow::Ok;
-use aya::maps::{HashMap as AyaHashmap, MapData as AyaMapData, Queue, RingBuf};
-use aya::programs::{Xdp, XdpFlags};
-use aya::{include_bytes_aligned, Bpf};
+use aya::include_bytes_aligned;
 use aya_log::BpfLogger;
-use clap::Parser;
-use folonet_client::config::{GlobalConfig, ServiceConfig};
-use folonet_client::{start_server, stop_server};
-use folonet_common::PORTS_QUEUE_SIZE;
-use folonet_common::{KEndpoint, Notification};
-use log::{debug, error, info, warn};
-use mio::unix::SourceFd;
-use mio::{Events, Interest, Poll, Token};
-use std::borrow::Borrow;
-use std::collections::{HashMap, HashSet};
+use clap::{Args, Parser, Subcommand};
+use folonet_client::config::{ConnLimitAction, GlobalConfig, ServiceConfig};
+use folonet_client::provider::Providers;
+use folonet_client::ManagerClient;
+use folonet_core::admin::{self, AdminCall, AdminRequest};
+use folonet_core::demo;
+use folonet_core::info::object_hash;
+use folonet_core::loader::{self, Overrides};
+use folonet_core::logging;
+use folonet_core::offline::OfflineMaps;
+use folonet_core::output::{render, ActionReport, ConfigCheckReport, OutputFormat};
+use folonet_core::replay::{parse_trace, replay, ReplayManager, ReplayOptions};
+use folonet_core::validate::{self, ConfigProblem};
+use folonet_core::{load_bpf, BpfHandles, Engine, FolonetError};
+use log::{debug, info, warn};
+use std::borrow::Cow;
 use std::fs;
-use std::net::{Ipv4Addr, TcpListener, UdpSocket};
-use std::ops::Deref;
-use std::os::fd::AsRawFd;
-use std::os::fd::RawFd;
-use std::sync::Arc;
-use tokio::io::unix::AsyncFd;
-use tokio::signal;
-use tokio::time::{sleep, Duration};
-
-use crate::endpoint::{
-    endpoint_pair_from_notification, mac_from_string, set_server_ip, Endpoint, UConnection,
-    UEndpoint,
-};
-use crate::message::Message;
-use crate::net::get_interafce_index;
-use crate::service::Service;
-use crate::worker::MsgWorker;
-
-mod endpoint;
-mod message;
-mod net;
-mod service;
-mod state;
-mod worker;
+use std::net::{TcpListener, UdpSocket};
+use std::path::Path;
+use tokio::signal::{self, unix::SignalKind};
+use tokio::time::Duration;
 
 #[derive(Debug, Parser)]
 struct Opt {
-    #[clap(short, long, default_value = "lima0")]
-    iface: String,
+    /// config of the daemon, the admin commands find its socket there
+    #[clap(
+        short,
+        long,
+        env = "FOLONET_CONFIG",
+        default_value = "./config.yaml",
+        global = true
+    )]
+    config: String,
+    /// control socket of the running daemon, instead of the configured one
+    #[clap(long, global = true)]
+    socket: Option<String>,
+    #[clap(long, default_value = "human", global = true)]
+    output: OutputFormat,
+    /// eBPF object to load instead of the one built into folonet, e.g. one
+    /// built for the running kernel
+    #[clap(long, global = true)]
+    bpf_object: Option<String>,
+    #[clap(flatten)]
+    overrides: OverrideOpt,
+    /// `run` when left out
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-fn get_bpf() -> Bpf {
+// config values over those of the config file, for `run` and `check`: a
+// flag wins over its environment variable, which wins over the file
+#[derive(Debug, Args)]
+struct OverrideOpt {
+    /// attach to these interfaces instead of the configured ones
+    #[clap(
+        long = "interface",
+        env = "FOLONET_INTERFACES",
+        value_delimiter = ',',
+        global = true
+    )]
+    interfaces: Vec<String>,
+    /// address of the server manager
+    #[clap(long, env = "FOLONET_MANAGER", global = true)]
+    manager: Option<String>,
+    /// local ports for the connections to backends, `first-last`
+    #[clap(
+        long = "port-range",
+        env = "FOLONET_PORT_RANGES",
+        value_delimiter = ',',
+        global = true
+    )]
+    port_ranges: Vec<String>,
+    /// log filter like RUST_LOG, over it and the configured one
+    #[clap(long, env = "FOLONET_LOG", global = true)]
+    log_level: Option<String>,
+}
+
+impl OverrideOpt {
+    fn overrides(&self) -> Overrides {
+        Overrides {
+            interfaces: self.interfaces.clone(),
+            manager_addr: self.manager.clone(),
+            port_ranges: self.port_ranges.clone(),
+            log_filter: self.log_level.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Attach to the configured interfaces and serve until Ctrl-C
+    Run,
+    /// Check the config and report every problem in it, without loading
+    /// anything
+    Check,
+    /// List, add and remove the services of the running daemon
+    #[clap(subcommand)]
+    Services(ServicesCommand),
+    /// Inspect and close the connections of the running daemon
+    #[clap(subcommand)]
+    Connections(ConnectionsCommand),
+    /// Local ports of the running daemon
+    #[clap(subcommand)]
+    Ports(PortsCommand),
+    /// Kernel maps of the running daemon
+    #[clap(subcommand)]
+    Maps(MapsCommand),
+    /// Kernel counters of the running daemon
+    Stats,
+    /// Packets the xdp program of the running daemon dropped, per reason
+    Drops,
+    /// Packets the running daemon saw on every interface, and what became
+    /// of them
+    Interfaces,
+    /// How long every backend takes to answer a syn, from the histograms of
+    /// the running daemon
+    Handshakes,
+    /// How often the running daemon cold started every service, and how
+    /// long from the first syn until the backend was routed
+    ColdStarts,
+    /// Packet events waiting for every service of the running daemon, and
+    /// those shed while its queue was full
+    Queues,
+    /// Copy the packets of a service before and after their rewrite to a
+    /// pcapng file, for wireshark
+    Pcap(PcapOpt),
+    /// Show or change the log level of the running daemon
+    LogLevel {
+        /// like RUST_LOG, e.g. `info,folonet_core::state=debug`
+        level: Option<String>,
+    },
+    /// Play a recorded flow log against the cold start path and report what
+    /// becomes the bottleneck
+    Replay(ReplayOpt),
+    /// A client and a cold started backend in network namespaces, with
+    /// folonet in between, needs root
+    #[clap(subcommand)]
+    Demo(DemoCommand),
+}
+
+#[derive(Debug, Subcommand)]
+enum DemoCommand {
+    /// Build the topology, start folonet on it and ping the backend through it
+    Up {
+        #[clap(long, default_value = demo::DEFAULT_DIR)]
+        dir: String,
+    },
+    /// Stop what `up` started and remove the topology
+    Down {
+        #[clap(long, default_value = demo::DEFAULT_DIR)]
+        dir: String,
+    },
+    /// The echo backend of the demo
+    #[clap(hide = true)]
+    Echo { addr: String },
+    #[clap(hide = true)]
+    Ping { addr: String },
+}
+
+#[derive(Debug, Subcommand)]
+enum ServicesCommand {
+    List,
+    /// Route a service to its backends, until it is removed or the daemon exits
+    Add(AddServiceOpt),
+    /// Drain a service and stop its backend
+    Remove {
+        name: String,
+    },
+    /// Give a pool of a service a share of its new connections, in percent,
+    /// its servers get the rest
+    Weight {
+        name: String,
+        pool: String,
+        weight: u32,
+    },
+}
+
+#[derive(Debug, Args)]
+struct AddServiceOpt {
+    name: String,
+    /// ip:port the clients connect to
+    local_endpoint: String,
+    /// ip:port of a backend, repeated for more
+    #[clap(long = "server", required = true)]
+    servers: Vec<String>,
+    #[clap(long)]
+    udp: bool,
+    /// local ports the service may hold at once
+    #[clap(long)]
+    port_quota: Option<u64>,
+    /// connections the service may have open at once
+    #[clap(long)]
+    max_connections: Option<u64>,
+    /// answer a syn past --max-connections with a reset instead of dropping it
+    #[clap(long)]
+    reset_over_limit: bool,
+}
+
+#[derive(Debug, Subcommand)]
+enum ConnectionsCommand {
+    List,
+    /// The nat entries of every connection, one line each like
+    /// /proc/net/nf_conntrack
+    Nat,
+    /// Close the tcp connections of a service, of every service without one
+    Flush {
+        service: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum PortsCommand {
+    Status,
+}
+
+#[derive(Debug, Subcommand)]
+enum MapsCommand {
+    /// Print the entries of connection, server, epoch or draining
+    Dump {
+        map: String,
+        /// read connection, server or ip_mac from where the datapath pinned
+        /// them, without the daemon
+        #[clap(long)]
+        pinned: bool,
+    },
+    /// Add or replace an entry of the pinned server or ip_mac map, e.g.
+    /// `server 10.0.0.1:80/tcp 10.0.1.5:8080`
+    Insert {
+        map: String,
+        key: String,
+        value: String,
+    },
+    /// Delete an entry of the pinned connection, server or ip_mac map, keys
+    /// as `dump` prints them
+    Delete { map: String, key: String },
+}
+
+#[derive(Debug, Args)]
+struct PcapOpt {
+    #[clap(long)]
+    service: String,
+    /// written by the daemon, relative to the current directory
+    #[clap(long, default_value = "folonet.pcapng")]
+    out: String,
+    /// copy one in this many packets
+    #[clap(long, default_value_t = 1)]
+    sample: u32,
+    /// stop after this many packets, their rewrites included
+    #[clap(long, default_value_t = 1000)]
+    count: usize,
+    /// stop after this many seconds
+    #[clap(long, default_value_t = 10)]
+    secs: u64,
+}
+
+#[derive(Debug, Args)]
+struct ReplayOpt {
+    /// flow log to replay, one json record per line
+    trace: String,
+    /// how much faster than recorded to play the trace
+    #[clap(long, default_value_t = 1.0)]
+    speedup: f64,
+    /// start backends through the configured server manager, or the provider
+    /// of their service, mocked otherwise
+    #[clap(long)]
+    real_manager: bool,
+    /// how long a mocked backend start takes
+    #[clap(long, default_value_t = 500)]
+    mock_start_ms: u64,
+}
+
+fn bpf_object() -> &'static [u8] {
     // This will include your eBPF object file as raw bytes at compile-time and load it at
     // runtime. This approach is recommended for most real-world use cases. If you would
     // like to specify the eBPF program at runtime rather than at compile-time, you can
     // reach for `Bpf::load_file` instead.
     #[cfg(debug_assertions)]
-    let bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/debug/folonet"
-    ))
-    .unwrap();
+    let object = include_bytes_aligned!("../../target/bpfel-unknown-none/debug/folonet");
     #[cfg(not(debug_assertions))]
-    let bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/release/folonet"
-    ))
-    .unwrap();
-    bpf
+    let object = include_bytes_aligned!("../../target/bpfel-unknown-none/release/folonet");
+    object
+}
+
+fn read_bpf_object(path: Option<&str>) -> Result<Cow<'static, [u8]>, FolonetError> {
+    match path {
+        Some(path) => {
+            let object = fs::read(path).map_err(|source| FolonetError::Io {
+                context: format!("failed to read {}", path),
+                source,
+            })?;
+            info!("loading the eBPF object {}", path);
+            Ok(Cow::Owned(object))
+        }
+        None => Ok(Cow::Borrowed(bpf_object())),
+    }
+}
+
+fn load_config(path: &str) -> Result<GlobalConfig, FolonetError> {
+    Ok(loader::load(path, &Overrides::default())?.cfg)
+}
+
+// the config and its warnings, failing on any other problem in it
+fn load_checked_config(
+    path: &str,
+    overrides: &Overrides,
+) -> Result<(GlobalConfig, Vec<ConfigProblem>), FolonetError> {
+    let loaded = loader::load(path, overrides)?;
+    let warnings = validate::check(&loaded)?;
+    Ok((loaded.cfg, warnings))
+}
+
+fn run_check(
+    config: &str,
+    overrides: &Overrides,
+    output: OutputFormat,
+) -> Result<(), FolonetError> {
+    let (_, warnings) = load_checked_config(config, overrides)?;
+    let report = ConfigCheckReport {
+        config: config.to_string(),
+        warnings,
+    };
+    print!("{}", render(&report, output)?);
+    Ok(())
+}
+
+async fn run_replay(
+    opt: &ReplayOpt,
+    config: &str,
+    output: OutputFormat,
+) -> Result<(), FolonetError> {
+    let global_cfg = load_config(config)?;
+    let trace = fs::read_to_string(&opt.trace).map_err(|source| FolonetError::Io {
+        context: format!("failed to read {}", opt.trace),
+        source,
+    })?;
+    let trace = parse_trace(&trace)?;
+    let manager = if opt.real_manager {
+        let manager = ManagerClient::new(global_cfg.manager.clone().with_env());
+        ReplayManager::Real(Providers::new(manager, &global_cfg.services))
+    } else {
+        ReplayManager::Mock(Duration::from_millis(opt.mock_start_ms))
+    };
+    let opts = ReplayOptions {
+        speedup: opt.speedup,
+        ..Default::default()
+    };
+
+    let report = replay(&trace, &global_cfg.services, manager, &opts).await;
+    println!("{}", render(&report, output)?);
+    Ok(())
+}
+
+// what an admin command asks the daemon, none for the commands run locally
+fn admin_request(command: &Command) -> Option<AdminRequest> {
+    let request = match command {
+        Command::Services(ServicesCommand::List) => AdminRequest::ServicesList,
+        Command::Services(ServicesCommand::Add(add)) => AdminRequest::ServicesAdd {
+            service: ServiceConfig {
+                name: add.name.clone(),
+                local_endpoint: add.local_endpoint.clone(),
+                servers: add.servers.clone(),
+                is_tcp: !add.udp,
+                port_quota: add.port_quota,
+                max_connections: add.max_connections,
+                conn_limit_action: if add.reset_over_limit {
+                    ConnLimitAction::Rst
+                } else {
+                    ConnLimitAction::Drop
+                },
+                ..Default::default()
+            },
+        },
+        Command::Services(ServicesCommand::Remove { name }) => {
+            AdminRequest::ServicesRemove { name: name.clone() }
+        }
+        Command::Services(ServicesCommand::Weight { name, pool, weight }) => {
+            AdminRequest::ServicesWeight {
+                name: name.clone(),
+                pool: pool.clone(),
+                weight: *weight,
+            }
+        }
+        Command::Connections(ConnectionsCommand::List) => AdminRequest::ConnectionsList,
+        Command::Connections(ConnectionsCommand::Nat) => AdminRequest::ConnectionsNat,
+        Command::Connections(ConnectionsCommand::Flush { service }) => {
+            AdminRequest::ConnectionsFlush {
+                service: service.clone(),
+            }
+        }
+        Command::Ports(PortsCommand::Status) => AdminRequest::PortsStatus,
+        Command::Maps(MapsCommand::Dump { map, pinned: false }) => {
+            AdminRequest::MapsDump { map: map.clone() }
+        }
+        Command::Stats => AdminRequest::Stats,
+        Command::Drops => AdminRequest::Drops,
+        Command::Handshakes => AdminRequest::Handshakes,
+        Command::ColdStarts => AdminRequest::ColdStarts,
+        Command::Interfaces => AdminRequest::Interfaces,
+        Command::Queues => AdminRequest::Queues,
+        Command::Pcap(pcap) => AdminRequest::Pcap {
+            service: pcap.service.clone(),
+            file: std::env::current_dir()
+                .map(|dir| dir.join(&pcap.out))
+                .unwrap_or_else(|_| pcap.out.clone().into())
+                .to_string_lossy()
+                .to_string(),
+            one_in: pcap.sample,
+            max_packets: pcap.count,
+            secs: pcap.secs,
+        },
+        Command::LogLevel { level } => AdminRequest::LogLevel {
+            level: level.clone(),
+        },
+        Command::Maps(_)
+        | Command::Run
+        | Command::Check
+        | Command::Replay(_)
+        | Command::Demo(_) => return None,
+    };
+    Some(request)
+}
+
+async fn run_demo(command: &DemoCommand) -> Result<(), FolonetError> {
+    match command {
+        DemoCommand::Up { dir } => {
+            let exe = std::env::current_exe().map_err(|source| FolonetError::Io {
+                context: "failed to find the folonet binary".to_string(),
+                source,
+            })?;
+            demo::up(Path::new(dir), &exe).await
+        }
+        DemoCommand::Down { dir } => {
+            demo::down(Path::new(dir));
+            Ok(())
+        }
+        DemoCommand::Echo { addr } => demo::echo(addr).await,
+        DemoCommand::Ping { addr } => {
+            let took = demo::ping(addr).await?;
+            println!("echoed by {} in {}ms", addr, took.as_millis());
+            Ok(())
+        }
+    }
+}
+
+// the pinned maps edited in place, the daemon may not even run
+fn run_maps(command: &MapsCommand, config: &str, output: OutputFormat) -> Result<(), FolonetError> {
+    let maps = OfflineMaps::new(&load_config(config)?.pinning)?;
+    let out = match command {
+        MapsCommand::Dump { map, .. } => render(&maps.dump(map)?, output)?,
+        MapsCommand::Insert { map, key, value } => {
+            maps.insert(map, key, value)?;
+            let message = format!("{} now maps {} to {}", map, key, value);
+            render(&ActionReport { message }, output)?
+        }
+        MapsCommand::Delete { map, key } => {
+            maps.delete(map, key)?;
+            let message = format!("deleted {} from {}", key, map);
+            render(&ActionReport { message }, output)?
+        }
+    };
+    print!("{}", out);
+    Ok(())
+}
+
+async fn run_admin(opt: &Opt, request: AdminRequest) -> Result<(), FolonetError> {
+    let socket = match &opt.socket {
+        Some(socket) => socket.clone(),
+        None => load_config(&opt.config)?.admin.socket,
+    };
+    let call = AdminCall {
+        request,
+        output: opt.output,
+    };
+    print!("{}", admin::send(&socket, &call).await?);
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    env_logger::init();
+    let opt = Opt::parse();
+    let command = opt.command.as_ref().unwrap_or(&Command::Run);
+    if !matches!(command, Command::Run) {
+        logging::init("error")?;
+    }
+    if let Command::Replay(replay_opt) = command {
+        return Ok(run_replay(replay_opt, &opt.config, opt.output).await?);
+    }
+    if let Command::Demo(demo_command) = command {
+        return Ok(run_demo(demo_command).await?);
+    }
+    if matches!(command, Command::Check) {
+        return Ok(run_check(
+            &opt.config,
+            &opt.overrides.overrides(),
+            opt.output,
+        )?);
+    }
+    if let Command::Maps(maps_command) = command {
+        if let Some(request) = admin_request(command) {
+            return Ok(run_admin(&opt, request).await?);
+        }
+        return Ok(run_maps(maps_command, &opt.config, opt.output)?);
+    }
+    if let Some(request) = admin_request(command) {
+        return Ok(run_admin(&opt, request).await?);
+    }
+
+    // every problem of the config is reported before any map is touched
+    let (global_cfg, warnings) = load_checked_config(&opt.config, &opt.overrides.overrides())?;
+    match &opt.overrides.log_level {
+        Some(_) => logging::install(global_cfg.log.filter.parse()?)?,
+        None => logging::init(&global_cfg.log.filter)?,
+    }
+    for warning in warnings.iter() {
+        warn!("{}: {}", opt.config, warning);
+    }
 
     // Bump the memlock rlimit. This is needed for older kernels that don't use the
     // new memcg based accounting, see https://lwn.net/Articles/837122/
@@ -78,29 +528,16 @@ async fn main() -> Result<(), anyhow::Error> {
         debug!("remove limit on locked memory failed, ret is: {}", ret);
     }
 
-    let mut bpf = get_bpf();
+    let object = read_bpf_object(opt.bpf_object.as_deref())?;
+    let mut bpf = load_bpf(&object, &global_cfg)?;
 
-    if let Err(e) = BpfLogger::init(&mut bpf) {
-        // This can happen if you remove all log statements from your eBPF program.
-        warn!("failed to initialize eBPF logger: {}", e);
-    }
-
-    let cfg_str = fs::read_to_string("./config.yaml").unwrap();
-    let global_cfg: GlobalConfig = serde_yaml::from_str(cfg_str.as_str()).unwrap();
-
-    // parse intreface config
-    let mut local_ip_map: AyaHashmap<_, u32, u32> =
-        AyaHashmap::try_from(bpf.take_map("LOCAL_IP_MAP").unwrap()).unwrap();
-    global_cfg.interfaces.iter().for_each(|i| {
-        if let Some(idx) = get_interafce_index(i.name.clone()) {
-            i.local_ips.iter().for_each(|ip| {
-                let ip: u32 = ip.parse::<Ipv4Addr>().unwrap().into();
-                local_ip_map.insert(&idx, &ip, 0).unwrap();
-            });
+    if global_cfg.log.ebpf {
+        if let Err(e) = BpfLogger::init(&mut bpf) {
+            // This can happen if you remove all log statements from your eBPF program.
+            warn!("failed to initialize eBPF logger: {}", e);
         }
-    });
+    }
 
-    // init maps
     let start_port = 8000u16;
     let end_port = 9999u16;
 
@@ -110,298 +547,44 @@ async fn main() -> Result<(), anyhow::Error> {
 
         // 尝试TCP端口
         if let Result::Err(_) = TcpListener::bind(&tcp_address) {
-            panic!("TCP Port {} is not free.", port);
+            return Err(FolonetError::PortBusy(port).into());
         }
 
         // 尝试UDP端口
         if let Result::Err(_) = UdpSocket::bind(&udp_address) {
-            panic!("UDP Port {} is not free.", port);
+            return Err(FolonetError::PortBusy(port).into());
         }
     }
 
-    let mut server_map: AyaHashmap<_, UEndpoint, UEndpoint> =
-        AyaHashmap::try_from(bpf.take_map("SERVER_MAP").unwrap()).unwrap();
-    global_cfg.services.iter().for_each(|service| {
-        let local_endpoint = Endpoint::from(&service.local_endpoint);
-        if let Some(server) = service.servers.get(0) {
-            let server_endpoint = Endpoint::from(server);
-            server_map
-                .insert(
-                    &local_endpoint.to_u_endpoint(),
-                    &server_endpoint.to_u_endpoint(),
-                    0,
-                )
-                .unwrap();
-        }
-
-        service
-            .servers
-            .iter()
-            .for_each(|server| set_server_ip(&Endpoint::from(server).ip.to_string()));
-    });
-    let server_map = Arc::new(tokio::sync::Mutex::new(server_map));
-
-    let mut ip_mac_map: AyaHashmap<_, u32, u64> =
-        AyaHashmap::try_from(bpf.take_map("IP_MAC_MAP").unwrap()).unwrap();
-    global_cfg.ip_mac_list.iter().for_each(|ip_mac| {
-        let ip: u32 = ip_mac.ip.parse::<Ipv4Addr>().unwrap().into();
-        let ip = ip.to_be();
-        let mac = mac_from_string(&ip_mac.mac).val();
-        ip_mac_map.insert(&ip, &mac, 0).unwrap();
-    });
-
-    let program: &mut Xdp = bpf.program_mut("folonet").unwrap().try_into().unwrap();
-    program.load().unwrap();
-
-    let iface_list: Vec<String> = global_cfg
-        .interfaces
-        .iter()
-        .map(|i| i.name.clone())
-        .collect();
-    iface_list.iter().for_each(|iface| {
-        program.attach(iface, XdpFlags::SKB_MODE).unwrap();
-        // .context("failed to attach the XDP program with default flags - try changing XdpFlags::default() to XdpFlags::SKB_MODE").unwrap();
-    });
-
-    let mut bpf_packet_event_map = bpf.take_map("PACKET_EVENT").unwrap();
-    let mut bpf_cold_start_map = bpf.take_map("COLD_START_MAP").unwrap();
-    let mut bpf_door_bell_map = bpf.take_map("DOOR_BELL_MAP").unwrap();
-    let mut bpf_performance_map = bpf.take_map("PERFORMANCE_MAP").unwrap();
-    let bpf_connection_map = bpf.take_map("CONNECTION").unwrap();
-
-    let bpf_service_ports_map = bpf.take_map("SERVICE_PORTS").unwrap();
-    let mut bpf_service_ports_map: Queue<_, u16> = Queue::try_from(bpf_service_ports_map).unwrap();
-
-    let out_handle = tokio::spawn(async move {
-        let bpf_connection_map: AyaHashmap<AyaMapData, UConnection, UConnection> =
-            AyaHashmap::try_from(bpf_connection_map).unwrap();
-        let connection_map = Arc::new(tokio::sync::Mutex::new(bpf_connection_map));
-
-        let mut tcp_service_map: HashMap<Endpoint, MsgWorker<Service>> = HashMap::new();
-        let mut udp_service_map: HashMap<Endpoint, MsgWorker<Service>> = HashMap::new();
-
-        for i in 10000..(10000 + PORTS_QUEUE_SIZE) {
-            bpf_service_ports_map.push(i as u16, 0).unwrap();
-        }
-
-        let bpf_service_ports_map = Arc::new(tokio::sync::Mutex::new(bpf_service_ports_map));
-        global_cfg.services.iter().for_each(|service_cfg| {
-            if service_cfg.is_tcp && !service_cfg.servers.is_empty() {
-                tcp_service_map.insert(
-                    Endpoint::from(&service_cfg.local_endpoint),
-                    MsgWorker::new(Service::new(
-                        service_cfg,
-                        connection_map.clone(),
-                        bpf_service_ports_map.clone(),
-                    )),
-                );
-            }
-        });
-
-        let tcp_service_map = Arc::new(tokio::sync::Mutex::new(tcp_service_map));
-
-        let tcp_service_map_clod_start = tcp_service_map.clone();
-        let bpf_conn_map_clod_start = connection_map.clone();
-        let bfp_ports_map_cold_start = bpf_service_ports_map.clone();
-        let cold_start_handle = tokio::spawn(async move {
-            let bpf_door_bell_map: AyaHashmap<_, UEndpoint, u8> =
-                AyaHashmap::try_from(bpf_door_bell_map).unwrap();
-            let bpf_performance_map: AyaHashmap<_, UEndpoint, u8> =
-                AyaHashmap::try_from(bpf_performance_map).unwrap();
-
-            let bpf_door_bell_map = Arc::new(tokio::sync::Mutex::new(bpf_door_bell_map));
-            let bpf_performance_map = Arc::new(tokio::sync::Mutex::new(bpf_performance_map));
-
-            let mut cold_start_task_set: HashSet<Endpoint> = HashSet::new();
-
-            let mut cold_start: RingBuf<&mut aya::maps::MapData> =
-                RingBuf::try_from(&mut bpf_cold_start_map).unwrap();
-            // let mut fd = AsyncFd::new(cold_start).unwrap();
-            loop {
-                // let mut guard = fd.readable_mut().await.unwrap();
-                // if let Some(item) = guard.get_inner_mut().next() {
-                if let Some(item) = cold_start.next() {
-                    let e = Endpoint::new(KEndpoint::from_bytes(item.deref()));
-                    if cold_start_task_set.contains(&e) {
-                        continue;
+    let mut handles = BpfHandles::load(bpf, &global_cfg)?;
+    handles.object_hash = Some(object_hash(&object));
+    let engine = Engine::new(global_cfg, handles);
+
+    engine
+        .run(async {
+            info!("Waiting for Ctrl-C or SIGTERM...");
+            let mut sigterm = match signal::unix::signal(SignalKind::terminate()) {
+                Ok(sigterm) => sigterm,
+                Err(e) => {
+                    warn!("failed to listen for SIGTERM: {}", e);
+                    if let Err(e) = signal::ctrl_c().await {
+                        warn!("failed to listen for Ctrl-C: {}", e);
                     }
-                    cold_start_task_set.insert(e.clone());
-                    let server_map = server_map.clone();
-                    let tcp_service_map = tcp_service_map_clod_start.clone();
-                    let bpf_connection_map = bpf_conn_map_clod_start.clone();
-                    let bpf_service_ports_map = bfp_ports_map_cold_start.clone();
-                    let bpf_door_bell_map = bpf_door_bell_map.clone();
-                    let bpf_performance_map = bpf_performance_map.clone();
-                    tokio::spawn(async move {
-                        let service_cfg = start_server(e.to_string()).await;
-                        if service_cfg.is_none() {
-                            return;
-                        }
-
-                        let service_cfg = service_cfg.unwrap();
-                        let server_endpoint = Endpoint::from(service_cfg.servers.get(0).unwrap());
-                        {
-                            let mut server_map = server_map.lock().await;
-                            server_map
-                                .insert(&e.to_u_endpoint(), &server_endpoint.to_u_endpoint(), 0)
-                                .unwrap();
-                            let mut tcp_service_map = tcp_service_map.lock().await;
-                            tcp_service_map.insert(
-                                Endpoint::from(&service_cfg.local_endpoint),
-                                MsgWorker::new(Service::new(
-                                    &service_cfg,
-                                    bpf_connection_map.clone(),
-                                    bpf_service_ports_map.clone(),
-                                )),
-                            );
-                        }
-
-                        // listen to stop
-                        const DURATION: Duration = Duration::from_secs(15);
-                        loop {
-                            let val0 = 0u8;
-                            let val1 = 1u8;
-
-                            // start to record
-                            {
-                                let mut bpf_door_bell_map = bpf_door_bell_map.lock().await;
-                                bpf_door_bell_map
-                                    .insert(&e.to_u_endpoint(), &val1, 0)
-                                    .unwrap();
-                            }
-                            sleep(DURATION).await;
-
-                            {
-                                let mut bpf_door_bell_map = bpf_door_bell_map.lock().await;
-                                let mut bpf_performance_map = bpf_performance_map.lock().await;
-                                // stop to record
-                                bpf_door_bell_map
-                                    .insert(&e.to_u_endpoint(), &val0, 0)
-                                    .unwrap();
-                                // check whether need to stop server
-                                let cnt = bpf_performance_map.get(&e.to_u_endpoint(), 0);
-                                if cnt.is_err() || cnt.unwrap() == 0 {
-                                    // stop server
-                                    info!("stop server {}", e.to_string());
-
-                                    let mut server_map = server_map.lock().await;
-                                    if server_map.get(&e.to_u_endpoint(), 0).is_ok() {
-                                        server_map.remove(&e.to_u_endpoint()).unwrap();
-                                    }
-                                    let mut tcp_service_map = tcp_service_map.lock().await;
-                                    if tcp_service_map.get(&e).is_some() {
-                                        tcp_service_map.remove(&e).unwrap();
-                                    }
-
-                                    stop_server(e.to_string()).await;
-                                    break;
-                                }
-                                // clear performance map
-                                bpf_performance_map
-                                    .insert(&e.to_u_endpoint(), &val0, 0)
-                                    .unwrap();
-                            }
-                            sleep(DURATION).await;
-                        }
-                    });
-
-                    cold_start_task_set.remove(&e);
-                } else {
-                    sleep(Duration::from_millis(100)).await;
+                    return;
                 }
-                // guard.clear_ready();
-            }
-        });
-
-        // deal with packets to drive state machine
-        let packet_handle = tokio::spawn(async move {
-            let mut ring_buf: RingBuf<&mut aya::maps::MapData> =
-                RingBuf::try_from(&mut bpf_packet_event_map).unwrap();
-
-            loop {
-                // let mut guard = fd.readable_mut().await.unwrap();
-
-                // if let Some(item) = guard.get_inner_mut().next() {
-                if let Some(item) = ring_buf.next() {
-                    let notification = Notification::from_bytes(item.deref());
-                    let (from_endpoint, to_endpoint) =
-                        endpoint_pair_from_notification(&notification);
-                    let local_in_endpoint = Endpoint::new(notification.local_in_endpoint);
-                    let local_out_endpoint = Endpoint::new(notification.lcoal_out_endpoint);
-
-                    info!(
-                        "from {} to {}",
-                        from_endpoint.to_string(),
-                        to_endpoint.to_string()
-                    );
-
-                    // info!(
-                    //     "local_in_endpoint {} lcoal_out_endpoint {}",
-                    //     local_in_endpoint.to_string(),
-                    //     local_out_endpoint.to_string(),
-                    // );
-
-                    let mut from_client = true;
-
-                    let tcp_service_map = tcp_service_map.lock().await;
-                    let service = if notification.is_tcp() {
-                        tcp_service_map.get(&local_in_endpoint).or_else(|| {
-                            from_client = false;
-                            tcp_service_map.get(&local_out_endpoint)
-                        })
-                    } else {
-                        udp_service_map.get(&local_in_endpoint).or_else(|| {
-                            from_client = false;
-                            udp_service_map.get(&local_out_endpoint)
-                        })
-                    };
-
-                    if let Some(service) = service {
-                        if let Some(sender) = service.msg_sender() {
-                            let msg = Message::from_notification(notification, from_client);
-                            let result = sender.send(msg.clone()).await;
-                            if result.is_err() {
-                                error!(
-                                    "failed to send message {:?}, error detail: {:?}",
-                                    msg,
-                                    result.err().unwrap(),
-                                );
-                            }
-                        }
+            };
+            tokio::select! {
+                result = signal::ctrl_c() => {
+                    if let Err(e) = result {
+                        warn!("failed to listen for Ctrl-C: {}", e);
                     }
-                } else {
-                    sleep(Duration::from_millis(100)).await;
                 }
+                _ = sigterm.recv() => info!("got SIGTERM"),
             }
-        });
-
-        info!("Waiting for Ctrl-C...");
-        signal::ctrl_c().await.unwrap();
-
-        cold_start_handle.abort();
-        info!("Waiting for cold start to finish...");
-        packet_handle.abort();
-        info!("Waiting for packet handle to finish...");
-    });
-
-    out_handle.await.unwrap();
+        })
+        .await?;
 
     info!("Exiting...");
 
     Ok(())
 }
-
-mod test {
-
-    #[test]
-    fn map_test() {
-        use std::collections::HashMap;
-        let mut map: HashMap<i32, i32> = HashMap::new();
-
-        map.insert(4, 5);
-
-        let v = map.get(&3).or_else(|| map.get(&4)).unwrap();
-
-        assert_eq!(*v, 5);
-    }
-}